@@ -241,6 +241,22 @@ pub trait MouseControllable {
     /// enigo.mouse_scroll_y(2);
     /// ```
     fn mouse_scroll_y(&mut self, length: i32);
+
+    /// Scroll the mouse (wheel) smoothly, e.g. as a touchpad does
+    ///
+    /// The directions are those of `mouse_scroll_x` and `mouse_scroll_y`,
+    /// with `dx` and `dy` in 1/120 of their length, the `WHEEL_DELTA` of
+    /// Windows. Where the operating system has no finer unit than a click,
+    /// the rest is kept for the next call.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use enigo::*;
+    /// let mut enigo = Enigo::new();
+    /// enigo.mouse_scroll_smooth(0, 30);
+    /// ```
+    fn mouse_scroll_smooth(&mut self, dx: i32, dy: i32);
 }
 
 /// A key on the keyboard.
@@ -385,6 +401,14 @@ pub enum Key {
     ///
     Mute,
     ///
+    MediaPlayPause,
+    ///
+    MediaNextTrack,
+    ///
+    MediaPrevTrack,
+    ///
+    MediaStop,
+    ///
     Scroll,
     /// scroll lock
     NumLock,
@@ -64,6 +64,8 @@ fn mousebutton(button: MouseButton) -> c_int {
 pub struct Enigo {
     xdo: Xdo,
     delay: u64,
+    // the rest of the smooth scrolling, less than a click
+    scroll_rest: (i32, i32),
 }
 // This is safe, we have a unique pointer.
 // TODO: use Unique<c_char> once stable.
@@ -75,6 +77,7 @@ impl Default for Enigo {
         Self {
             xdo: unsafe { xdo_new(ptr::null()) },
             delay: DEFAULT_DELAY,
+            scroll_rest: (0, 0),
         }
     }
 }
@@ -179,6 +182,21 @@ impl MouseControllable for Enigo {
             self.mouse_click(button);
         }
     }
+    fn mouse_scroll_smooth(&mut self, dx: i32, dy: i32) {
+        // XTest has only the clicks of the buttons 4 to 7
+        self.scroll_rest.0 += dx;
+        self.scroll_rest.1 += dy;
+        let x = self.scroll_rest.0 / 120;
+        let y = self.scroll_rest.1 / 120;
+        self.scroll_rest.0 -= x * 120;
+        self.scroll_rest.1 -= y * 120;
+        if x != 0 {
+            self.mouse_scroll_x(x);
+        }
+        if y != 0 {
+            self.mouse_scroll_y(y);
+        }
+    }
 }
 fn keysequence<'a>(key: Key) -> Cow<'a, str> {
     if let Key::Layout(c) = key {
@@ -254,9 +272,13 @@ fn keysequence<'a>(key: Key) -> Cow<'a, str> {
         Key::Help => "Help",
         Key::Sleep => "",
         Key::Separator => "KP_Separator",
-        Key::VolumeUp => "",
-        Key::VolumeDown => "",
-        Key::Mute => "",
+        Key::VolumeUp => "XF86AudioRaiseVolume",
+        Key::VolumeDown => "XF86AudioLowerVolume",
+        Key::Mute => "XF86AudioMute",
+        Key::MediaPlayPause => "XF86AudioPlay",
+        Key::MediaNextTrack => "XF86AudioNext",
+        Key::MediaPrevTrack => "XF86AudioPrev",
+        Key::MediaStop => "XF86AudioStop",
         Key::Scroll => "Scroll_Lock",
         Key::NumLock => "Num_Lock",
         Key::RWin => "Super_R",
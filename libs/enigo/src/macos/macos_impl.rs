@@ -8,7 +8,7 @@ use self::core_graphics::event_source::*;
 
 use crate::macos::keycodes::*;
 use crate::{Key, KeyboardControllable, MouseButton, MouseControllable};
-use objc::runtime::Class;
+use objc::runtime::{Class, Object};
 
 struct MyCGEvent;
 
@@ -44,6 +44,9 @@ enum ScrollUnit {
 }
 // hack
 
+// the pixels of a click of the mouse wheel for the smooth scrolling
+const SCROLL_PIXELS: i32 = 10;
+
 /// The main struct for handling the event emitting
 pub struct Enigo {
     event_source: Option<CGEventSource>,
@@ -257,6 +260,21 @@ impl MouseControllable for Enigo {
             }
         }
     }
+
+    fn mouse_scroll_smooth(&mut self, dx: i32, dy: i32) {
+        let x = -dx * SCROLL_PIXELS / 120;
+        let y = -dy * SCROLL_PIXELS / 120;
+        if x == 0 && y == 0 {
+            return;
+        }
+        if let Some(src) = self.event_source.as_ref() {
+            unsafe {
+                let mouse_ev = CGEventCreateScrollWheelEvent(&src, ScrollUnit::Pixel, 2, y, x);
+                CGEventPost(CGEventTapLocation::HID, mouse_ev);
+                CFRelease(mouse_ev as *const std::ffi::c_void);
+            }
+        }
+    }
 }
 
 // https://stackoverflow.
@@ -280,6 +298,11 @@ impl KeyboardControllable for Enigo {
     }
 
     fn key_click(&mut self, key: Key) {
+        if let Some(code) = Self::media_key(key) {
+            Self::post_media_key(code, true);
+            Self::post_media_key(code, false);
+            return;
+        }
         let keycode = self.key_to_keycode(key);
         if keycode == 0 {
             return;
@@ -297,6 +320,10 @@ impl KeyboardControllable for Enigo {
     }
 
     fn key_down(&mut self, key: Key) -> crate::ResultType {
+        if let Some(code) = Self::media_key(key) {
+            Self::post_media_key(code, true);
+            return Ok(());
+        }
         if let Some(src) = self.event_source.as_ref() {
             if let Ok(event) =
                 CGEvent::new_keyboard_event(src.clone(), self.key_to_keycode(key), true)
@@ -308,6 +335,10 @@ impl KeyboardControllable for Enigo {
     }
 
     fn key_up(&mut self, key: Key) {
+        if let Some(code) = Self::media_key(key) {
+            Self::post_media_key(code, false);
+            return;
+        }
         if let Some(src) = self.event_source.as_ref() {
             if let Ok(event) =
                 CGEvent::new_keyboard_event(src.clone(), self.key_to_keycode(key), false)
@@ -324,6 +355,48 @@ impl KeyboardControllable for Enigo {
 }
 
 impl Enigo {
+    // the media keys are not key codes but system defined events, of NX_KEYTYPE_*
+    fn media_key(key: Key) -> Option<i64> {
+        match key {
+            Key::VolumeUp => Some(0),
+            Key::VolumeDown => Some(1),
+            Key::Mute => Some(7),
+            Key::MediaPlayPause => Some(16),
+            Key::MediaNextTrack => Some(17),
+            Key::MediaPrevTrack => Some(18),
+            _ => None,
+        }
+    }
+
+    fn post_media_key(code: i64, down: bool) {
+        let ns_event = match Class::get("NSEvent") {
+            Some(ns_event) => ns_event,
+            None => return,
+        };
+        let flags: u64 = if down { 0xa00 } else { 0xb00 };
+        let data1 = (code << 16) | flags as i64;
+        unsafe {
+            // NSEventTypeSystemDefined, subtype NX_SUBTYPE_AUX_CONTROL_BUTTONS
+            let event: *mut Object = msg_send![ns_event,
+                otherEventWithType: 14u64
+                location: NSPoint { x: 0., y: 0. }
+                modifierFlags: flags
+                timestamp: 0f64
+                windowNumber: 0i64
+                context: std::ptr::null_mut::<Object>()
+                subtype: 8i16
+                data1: data1
+                data2: -1i64];
+            if event.is_null() {
+                return;
+            }
+            let cg_event: *mut MyCGEvent = msg_send![event, CGEvent];
+            if !cg_event.is_null() {
+                CGEventPost(CGEventTapLocation::HID, cg_event);
+            }
+        }
+    }
+
     fn pressed_buttons() -> usize {
         if let Some(ns_event) = Class::get("NSEvent") {
             unsafe { msg_send![ns_event, pressedMouseButtons] }
@@ -405,9 +478,6 @@ impl Enigo {
             Key::Numpad7 => kVK_ANSI_Keypad7,
             Key::Numpad8 => kVK_ANSI_Keypad8,
             Key::Numpad9 => kVK_ANSI_Keypad9,
-            Key::Mute => kVK_Mute,
-            Key::VolumeDown => kVK_VolumeUp,
-            Key::VolumeUp => kVK_VolumeDown,
             Key::Help => kVK_Help,
             Key::Snapshot => kVK_F13,
             Key::Clear => kVK_ANSI_KeypadClear,
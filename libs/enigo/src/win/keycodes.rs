@@ -70,6 +70,10 @@ pub const EVK_SEPARATOR: u16 = 0x6C;
 pub const EVK_VOLUME_MUTE: u16 = 0xAD;
 pub const EVK_VOLUME_DOWN: u16 = 0xAE;
 pub const EVK_VOLUME_UP: u16 = 0xAF;
+pub const EVK_MEDIA_NEXT_TRACK: u16 = 0xB0;
+pub const EVK_MEDIA_PREV_TRACK: u16 = 0xB1;
+pub const EVK_MEDIA_STOP: u16 = 0xB2;
+pub const EVK_MEDIA_PLAY_PAUSE: u16 = 0xB3;
 pub const EVK_NUMLOCK: u16 = 0x90;
 pub const EVK_SCROLL: u16 = 0x91;
 pub const EVK_RWIN: u16 = 0x5C;
@@ -148,6 +148,15 @@ impl MouseControllable for Enigo {
     fn mouse_scroll_y(&mut self, length: i32) {
         mouse_event(MOUSEEVENTF_WHEEL, unsafe { transmute(length * 120) }, 0, 0);
     }
+
+    fn mouse_scroll_smooth(&mut self, dx: i32, dy: i32) {
+        if dx != 0 {
+            mouse_event(MOUSEEVENTF_HWHEEL, unsafe { transmute(dx) }, 0, 0);
+        }
+        if dy != 0 {
+            mouse_event(MOUSEEVENTF_WHEEL, unsafe { transmute(dy) }, 0, 0);
+        }
+    }
 }
 
 impl KeyboardControllable for Enigo {
@@ -322,6 +331,10 @@ impl Enigo {
             Key::Mute => EVK_VOLUME_MUTE,
             Key::VolumeDown => EVK_VOLUME_DOWN,
             Key::VolumeUp => EVK_VOLUME_UP,
+            Key::MediaPlayPause => EVK_MEDIA_PLAY_PAUSE,
+            Key::MediaNextTrack => EVK_MEDIA_NEXT_TRACK,
+            Key::MediaPrevTrack => EVK_MEDIA_PREV_TRACK,
+            Key::MediaStop => EVK_MEDIA_STOP,
             Key::Scroll => EVK_SCROLL,
             Key::NumLock => EVK_NUMLOCK,
             Key::RWin => EVK_RWIN,
@@ -0,0 +1,51 @@
+// The buffers the messages sent are serialized into, given back once copied to
+// the stream by the codec, so that sending the frames of the video does not
+// allocate each time. A buffer grown by a large key frame is kept as long as
+// not above MAX_CAPACITY.
+use std::sync::Mutex;
+
+const MAX_BUFFERS: usize = 32;
+const MAX_CAPACITY: usize = 4 * 1024 * 1024;
+const MIN_CAPACITY: usize = 4 * 1024;
+
+lazy_static::lazy_static! {
+    static ref POOL: Mutex<Vec<Vec<u8>>> = Default::default();
+}
+
+/// An empty buffer, of the pool if any left.
+pub fn get() -> Vec<u8> {
+    POOL.lock()
+        .unwrap()
+        .pop()
+        .unwrap_or_else(|| Vec::with_capacity(MIN_CAPACITY))
+}
+
+/// Gives back a buffer of `get`, dropped if the pool is full.
+pub fn put(mut buf: Vec<u8>) {
+    if buf.capacity() > MAX_CAPACITY {
+        return;
+    }
+    buf.clear();
+    let mut pool = POOL.lock().unwrap();
+    if pool.len() < MAX_BUFFERS {
+        pool.push(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let mut buf = get();
+        buf.extend_from_slice(b"frame");
+        put(buf);
+        put(Vec::with_capacity(MAX_CAPACITY + 1));
+        let pool = POOL.lock().unwrap();
+        assert!(!pool.is_empty() && pool.len() <= MAX_BUFFERS);
+        assert!(pool
+            .iter()
+            .all(|x| x.is_empty() && x.capacity() <= MAX_CAPACITY));
+    }
+}
@@ -2,6 +2,12 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
+/// The largest frame taken from a peer by default, a larger one ending the
+/// stream rather than the memory.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+// reserved ahead for the data of a frame, the rest as it comes
+const MAX_RESERVE: usize = 1024 * 1024;
+
 #[derive(Debug, Clone, Copy)]
 pub struct BytesCodec {
     state: DecodeState,
@@ -20,7 +26,7 @@ impl BytesCodec {
         Self {
             state: DecodeState::Head,
             raw: false,
-            max_packet_length: usize::MAX,
+            max_packet_length: DEFAULT_MAX_FRAME_SIZE,
         }
     }
 
@@ -55,7 +61,7 @@ impl BytesCodec {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Too big packet"));
         }
         src.advance(head_len);
-        src.reserve(n);
+        src.reserve(n.min(MAX_RESERVE));
         return Ok(Some(n));
     }
 
@@ -65,6 +71,36 @@ impl BytesCodec {
         }
         Ok(Some(src.split_to(n)))
     }
+
+    /// As `encode`, of a slice, e.g. a buffer of crate::buffer_pool to be given
+    /// back once copied, not an Encoder, for `encode(x.into(), ..)` to tell
+    /// the item.
+    pub fn encode_slice(&self, data: &[u8], buf: &mut BytesMut) -> io::Result<()> {
+        self.encode_head(data.len(), buf)?;
+        buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn encode_head(&self, n: usize, buf: &mut BytesMut) -> io::Result<()> {
+        buf.reserve(n + 4);
+        if self.raw {
+            return Ok(());
+        }
+        if n <= 0x3F {
+            buf.put_u8((n << 2) as u8);
+        } else if n <= 0x3FFF {
+            buf.put_u16_le((n << 2) as u16 | 0x1);
+        } else if n <= 0x3FFFFF {
+            let h = (n << 2) as u32 | 0x2;
+            buf.put_u16_le((h & 0xFFFF) as u16);
+            buf.put_u8((h >> 16) as u8);
+        } else if n <= 0x3FFFFFFF {
+            buf.put_u32_le((n << 2) as u32 | 0x3);
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Overflow"));
+        }
+        Ok(())
+    }
 }
 
 impl Decoder for BytesCodec {
@@ -105,24 +141,7 @@ impl Encoder<Bytes> for BytesCodec {
     type Error = io::Error;
 
     fn encode(&mut self, data: Bytes, buf: &mut BytesMut) -> Result<(), io::Error> {
-        if self.raw {
-            buf.reserve(data.len());
-            buf.put(data);
-            return Ok(());
-        }
-        if data.len() <= 0x3F {
-            buf.put_u8((data.len() << 2) as u8);
-        } else if data.len() <= 0x3FFF {
-            buf.put_u16_le((data.len() << 2) as u16 | 0x1);
-        } else if data.len() <= 0x3FFFFF {
-            let h = (data.len() << 2) as u32 | 0x2;
-            buf.put_u16_le((h & 0xFFFF) as u16);
-            buf.put_u8((h >> 16) as u8);
-        } else if data.len() <= 0x3FFFFFFF {
-            buf.put_u32_le((data.len() << 2) as u32 | 0x3);
-        } else {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Overflow"));
-        }
+        self.encode_head(data.len(), buf)?;
         buf.extend(data);
         Ok(())
     }
@@ -271,4 +290,35 @@ mod tests {
             assert!(false);
         }
     }
+
+    #[test]
+    fn test_codec_pooled() {
+        let mut codec = BytesCodec::new();
+        let mut buf = BytesMut::new();
+        let mut data = crate::buffer_pool::get();
+        data.resize(0x3F + 1, 7);
+        assert!(codec.encode_slice(&data, &mut buf).is_ok());
+        crate::buffer_pool::put(data);
+        let mut pooled = buf.clone();
+        buf.clear();
+        assert!(codec
+            .encode(Bytes::from(vec![7u8; 0x3F + 1]), &mut buf)
+            .is_ok());
+        assert_eq!(pooled, buf);
+        if let Ok(Some(res)) = codec.decode(&mut pooled) {
+            assert_eq!(res.len(), 0x3F + 1);
+        } else {
+            assert!(false);
+        }
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut codec = BytesCodec::new();
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(Bytes::from(vec![0u8; 0x40]), &mut buf).is_ok());
+        let mut codec2 = BytesCodec::new();
+        codec2.set_max_packet_length(0x3F);
+        assert!(codec2.decode(&mut buf).is_err());
+    }
 }
@@ -0,0 +1,79 @@
+// The features of the protocol which a peer of another build may not have,
+// told at the login, by the controller in LoginRequest and by the host in
+// PeerInfo. A feature is only used once both sides have it, else what was done
+// before it is, the peers of before telling none. A new feature of the protocol
+// adds its name here, to ALL, and checks `Capabilities::has` before sending.
+use std::collections::HashSet;
+
+/// ChatMessage with ids acknowledged by ChatReceipt
+pub const CHAT_RECEIPT: &str = "chat-receipt";
+/// the clipboard in ClipboardChunk, e.g. the images and the files
+pub const CLIPBOARD_CHUNK: &str = "clipboard-chunk";
+/// the video and audio on a ReliableChannel over udp
+pub const MEDIA_CHANNEL: &str = "media-channel";
+pub const TOUCH_PEN: &str = "touch-pen";
+pub const GAMEPAD: &str = "gamepad";
+pub const TERMINAL: &str = "terminal";
+pub const TUNNEL: &str = "tunnel";
+pub const ANNOTATION: &str = "annotation";
+pub const PRINT_JOB: &str = "print-job";
+
+const ALL: &[&str] = &[
+    CHAT_RECEIPT,
+    CLIPBOARD_CHUNK,
+    MEDIA_CHANNEL,
+    TOUCH_PEN,
+    GAMEPAD,
+    TERMINAL,
+    TUNNEL,
+    ANNOTATION,
+    PRINT_JOB,
+];
+
+/// The features of this build, to be sent to the peer.
+pub fn local() -> Vec<String> {
+    ALL.iter().map(|x| x.to_string()).collect()
+}
+
+/// The features both sides have.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Capabilities(HashSet<String>);
+
+impl Capabilities {
+    /// Of the features told by the peer.
+    pub fn new(peer: &[String]) -> Self {
+        Self(
+            peer.iter()
+                .filter(|x| ALL.contains(&x.as_str()))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    #[inline]
+    pub fn has(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    /// Sorted, e.g. for the log.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.0.iter().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let both = Capabilities::new(&local());
+        assert!(ALL.iter().all(|x| both.has(x)));
+        let old = Capabilities::new(&[]);
+        assert!(!old.has(CHAT_RECEIPT));
+        let newer = Capabilities::new(&[PRINT_JOB.to_owned(), "from-the-future".to_owned()]);
+        assert_eq!(newer.names(), vec![PRINT_JOB.to_owned()]);
+    }
+}
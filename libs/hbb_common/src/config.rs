@@ -1,8 +1,8 @@
-use crate::log;
+use crate::{bail, keystore, log, policy, ResultType};
 use directories_next::ProjectDirs;
 use rand::Rng;
 use serde_derive::{Deserialize, Serialize};
-use sodiumoxide::crypto::sign;
+use sodiumoxide::crypto::{hash::sha256, secretbox, sign};
 use std::{
     collections::HashMap,
     fs,
@@ -46,6 +46,9 @@ const CHARS: &'static [char] = &[
     'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
 ];
 
+// m_cost in KiB, t_cost and p_cost of argon2id, the same on both sides of a login
+const ARGON2_PARAMS: (u32, u32, u32) = (19 * 1024, 2, 1);
+
 pub const RENDEZVOUS_SERVERS: &'static [&'static str] = &[
     "rs-ny.rustdesk.com",
     "rs-sg.rustdesk.com",
@@ -58,6 +61,15 @@ pub const RELAY_PORT: i32 = 21117;
 pub enum NetworkType {
     Direct,
     ProxySocks,
+    // tcp only, udp goes direct
+    ProxyHttp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProxyType {
+    Socks5,
+    Http,
+    Https,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -68,12 +80,33 @@ pub struct Config {
     password: String,
     #[serde(default)]
     salt: String,
+    // the verifier of the argon2id of the permanent password and its salt,
+    // base64, instead of the password
+    #[serde(default)]
+    password_hash: String,
+    #[serde(default)]
+    password_salt: String,
+    // if the password is a random one, the others are only kept hashed
+    #[serde(default)]
+    random_password: bool,
     #[serde(default)]
     key_pair: (Vec<u8>, Vec<u8>), // sk, pk
     #[serde(default)]
     key_confirmed: bool,
+    // the TOTP secret of the incoming connections, sealed with the key pair
+    #[serde(default)]
+    totp: String,
+    // the password, the salt, the secret key and the hash of the password,
+    // sealed with the passphrase or the key of the machine, the service
+    // reading the file too
+    #[serde(default)]
+    sealed: String,
+    // the other scalar value must before this
     #[serde(default)]
     keys_confirmed: HashMap<String, bool>,
+    // `sealed` could not be opened, so it is kept as it is
+    #[serde(skip)]
+    locked: bool,
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
@@ -86,6 +119,30 @@ pub struct Socks5Server {
     pub password: String,
 }
 
+impl Socks5Server {
+    /// Selected by the scheme of `proxy`: http://, https:// or socks5://,
+    /// socks5 if there is none.
+    pub fn proxy_type(&self) -> ProxyType {
+        let proxy = self.proxy.trim().to_lowercase();
+        if proxy.starts_with("http://") {
+            ProxyType::Http
+        } else if proxy.starts_with("https://") {
+            ProxyType::Https
+        } else {
+            ProxyType::Socks5
+        }
+    }
+
+    /// `proxy` without the scheme
+    pub fn proxy_addr(&self) -> &str {
+        let proxy = self.proxy.trim();
+        match proxy.find("://") {
+            Some(i) => proxy[i + 3..].trim_end_matches('/'),
+            None => proxy,
+        }
+    }
+}
+
 // more variable configs
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Config2 {
@@ -99,6 +156,13 @@ pub struct Config2 {
     nat_type: i32,
     #[serde(default)]
     serial: i32,
+    // the password of the socks proxy, sealed with the passphrase or the key
+    // of the machine, the service reading the file too
+    #[serde(default)]
+    sealed: String,
+    // of the screen cast portal of wayland, so that its dialog is shown once
+    #[serde(default)]
+    restore_token: String,
 
     #[serde(default)]
     socks: Option<Socks5Server>,
@@ -106,6 +170,10 @@ pub struct Config2 {
     // the other scalar value must before this
     #[serde(default)]
     pub options: HashMap<String, String>,
+
+    // "ip:<addr>" -> (banned until in ms, times banned)
+    #[serde(default)]
+    bans: HashMap<String, (i64, u32)>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -125,6 +193,8 @@ pub struct PeerConfig {
     #[serde(default)]
     pub custom_image_quality: Vec<i32>,
     #[serde(default)]
+    pub quality_preset: String, // low-latency, balanced, best-quality, or none for each setting
+    #[serde(default)]
     pub show_remote_cursor: bool,
     #[serde(default)]
     pub lock_after_session_end: bool,
@@ -138,12 +208,39 @@ pub struct PeerConfig {
     pub disable_audio: bool,
     #[serde(default)]
     pub disable_clipboard: bool,
+    // the sign public key of the peer at the first secured connection
+    #[serde(default)]
+    pub pinned_key: Vec<u8>,
+    // the remembered password, sealed with the keystore
+    #[serde(default)]
+    sealed: String,
 
     // the other scalar value must before this
     #[serde(default)]
     pub options: HashMap<String, String>,
     #[serde(default)]
     pub info: PeerInfoSerde,
+    // tables, after the other values
+    #[serde(default)]
+    pub tunnels: Vec<TunnelProfile>,
+}
+
+/// A port forwarding saved for a peer, started with the main window if `auto_start`.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+pub struct TunnelProfile {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub direction: String, // local (default), udp, dynamic (SOCKS5), reverse
+    #[serde(default)]
+    pub local_port: i32,
+    // the address the peer listens on for reverse
+    #[serde(default)]
+    pub remote_host: String,
+    #[serde(default)]
+    pub remote_port: i32,
+    #[serde(default)]
+    pub auto_start: bool,
 }
 
 #[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
@@ -156,6 +253,100 @@ pub struct PeerInfoSerde {
     pub platform: String,
 }
 
+pub const PROVISIONING_VERSION: i32 = 1;
+
+/// The settings hosts are rolled out with, from `--export-config` of one set up
+/// by hand, or written by the admin: the servers, the hash of the permanent
+/// password, and the options, among them the default permissions, e.g.
+/// `enable-keyboard = "N"`. Never the id or the key pair of a host.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+pub struct Provisioning {
+    #[serde(default)]
+    pub version: i32,
+    #[serde(default)]
+    pub rendezvous_server: String,
+    #[serde(default)]
+    pub relay_server: String,
+    // the public key of the servers
+    #[serde(default)]
+    pub key: String,
+    // the verifier of the argon2id of the permanent password and its salt, base64
+    #[serde(default)]
+    pub password_hash: String,
+    #[serde(default)]
+    pub password_salt: String,
+    // the other scalar value must before this
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+}
+
+impl Provisioning {
+    /// None if `text` is not a provisioning file, e.g. a config file of before.
+    pub fn parse(text: &str) -> Option<Self> {
+        match toml::from_str::<Self>(text) {
+            Ok(x) if x.version > 0 => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// The options of the host once provisioned, merged into `options`.
+    pub fn merge_options(&self, mut options: HashMap<String, String>) -> HashMap<String, String> {
+        let servers = [
+            ("custom-rendezvous-server", &self.rendezvous_server),
+            ("relay-server", &self.relay_server),
+            ("key", &self.key),
+        ];
+        for (k, v) in servers.iter() {
+            if !v.is_empty() {
+                options.insert(k.to_string(), v.to_string());
+            }
+        }
+        for (k, v) in self.options.iter() {
+            if v.is_empty() {
+                options.remove(k);
+            } else {
+                options.insert(k.clone(), v.clone());
+            }
+        }
+        options
+    }
+}
+
+/// Argon2id of the permanent password, by the host when it is set,
+/// and by the client from the salt of the host's Hash message.
+pub fn hash_password(password: &str, salt: &[u8]) -> ResultType<Vec<u8>> {
+    let mut out = vec![0u8; 32];
+    let (m, t, p) = ARGON2_PARAMS;
+    let res = argon2::Params::new(m, t, p, Some(out.len())).and_then(|params| {
+        argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), salt, &mut out)
+    });
+    if let Err(err) = res {
+        bail!("Failed to hash the password: {}", err);
+    }
+    Ok(out)
+}
+
+/// What the host keeps of the argon2id of the permanent password, which is
+/// the key of the login, so that the one who reads it can not log in with it.
+pub fn password_verifier(hash: &[u8]) -> Vec<u8> {
+    sha256::hash(hash).0.to_vec()
+}
+
+/// The key of the login masked by the client with the verifier and the
+/// challenge, and unmasked by the host with the same, to check it against
+/// the verifier.
+pub fn mask_login_key(key: &[u8], verifier: &[u8], challenge: &str) -> Vec<u8> {
+    let mut data = verifier.to_vec();
+    data.extend(challenge.as_bytes());
+    let mask = sha256::hash(&data).0;
+    key.iter().zip(mask.iter()).map(|(a, b)| a ^ b).collect()
+}
+
 fn patch(path: PathBuf) -> PathBuf {
     if let Some(_tmp) = path.to_str() {
         #[cfg(windows)]
@@ -187,11 +378,37 @@ fn patch(path: PathBuf) -> PathBuf {
 
 impl Config2 {
     fn load() -> Config2 {
-        Config::load_::<Config2>("2")
+        let mut config = Config::load_::<Config2>("2");
+        let password = config.socks.as_ref().map(|x| x.password.clone());
+        match keystore::open::<String>(&config.sealed) {
+            Some(x) => {
+                if let Some(socks) = config.socks.as_mut() {
+                    socks.password = x;
+                }
+                if keystore::is_per_user(&config.sealed) {
+                    config.store();
+                }
+            }
+            None if password.unwrap_or_default().is_empty() => {}
+            None if config.sealed.is_empty() && keystore::is_available(true) => {
+                config.store();
+                log::info!("socks password sealed");
+            }
+            None => {}
+        }
+        config
     }
 
     fn store(&self) {
-        Config::store_(self, "2");
+        let mut config = self.clone();
+        config.sealed.clear();
+        if let Some(socks) = config.socks.as_mut() {
+            if let Some(sealed) = keystore::seal(&socks.password, true) {
+                config.sealed = sealed;
+                socks.password.clear();
+            }
+        }
+        Config::store_(&config, "2");
     }
 }
 
@@ -222,39 +439,173 @@ impl Config {
     }
 
     fn load() -> Config {
-        Config::load_::<Config>("")
+        let mut config = Config::load_::<Config>("");
+        let mut store = config.open_secrets();
+        store |= config.migrate_password();
+        if store {
+            config.store();
+        }
+        config
+    }
+
+    // The secrets in clear of before are sealed at the first load if there is
+    // a key, true if so, and the ones sealed with the keystore of the account
+    // or without the hash of the password are sealed again, the service not
+    // having the keystore. The ones sealed with another key are never
+    // overwritten, the key pair of the id being in them.
+    fn open_secrets(&mut self) -> bool {
+        if let Some((password, salt, sk, hash, hash_salt)) = keystore::open(&self.sealed) {
+            self.password = password;
+            self.salt = salt;
+            self.key_pair.0 = sk;
+            self.password_hash = hash;
+            self.password_salt = hash_salt;
+            return keystore::is_per_user(&self.sealed);
+        }
+        if let Some((password, salt, sk)) = keystore::open(&self.sealed) {
+            self.password = password;
+            self.salt = salt;
+            self.key_pair.0 = sk;
+            return true;
+        }
+        if !self.sealed.is_empty() {
+            log::error!("Failed to open the secrets of the config, of another keystore?");
+            self.locked = true;
+            return false;
+        }
+        let in_clear = !self.password.is_empty()
+            || !self.salt.is_empty()
+            || !self.key_pair.0.is_empty()
+            || !self.password_hash.is_empty();
+        if in_clear && keystore::is_available(true) {
+            log::info!("secrets of the config sealed");
+            return true;
+        }
+        false
+    }
+
+    // The passwords set before are kept in clear, the ones not known to be
+    // random are hashed at the first load: a random one of before can not be
+    // told from one of the user, and is then replaced with the next refresh.
+    fn migrate_password(&mut self) -> bool {
+        let password = self.password.clone();
+        if password.is_empty() || !self.password_hash.is_empty() || self.random_password {
+            return false;
+        }
+        if let Err(err) = self.set_password_hash(&password) {
+            log::error!("{}", err);
+            return false;
+        }
+        log::info!("password migrated to argon2id");
+        true
+    }
+
+    // the password before kept if it can not be hashed
+    fn set_password_hash(&mut self, password: &str) -> ResultType<()> {
+        let salt: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+        self.password_hash = base64::encode(password_verifier(&hash_password(password, &salt)?));
+        self.password_salt = base64::encode(salt);
+        self.password = "".to_owned();
+        self.random_password = false;
+        Ok(())
     }
 
     fn store(&self) {
-        Config::store_(self, "");
+        let mut config = self.clone();
+        if !config.locked {
+            let secrets = (
+                &config.password,
+                &config.salt,
+                &config.key_pair.0,
+                &config.password_hash,
+                &config.password_salt,
+            );
+            config.sealed = keystore::seal(&secrets, true).unwrap_or_default();
+        }
+        if !config.sealed.is_empty() {
+            config.password.clear();
+            config.salt.clear();
+            config.key_pair.0.clear();
+            config.password_hash.clear();
+            config.password_salt.clear();
+        }
+        Config::store_(&config, "");
     }
 
     pub fn file() -> PathBuf {
         Self::file_("")
     }
 
+    // Through the structs rather than copied, so that the secrets are sealed
+    // with the key of this side. A config sealed with another key is not
+    // imported, not to lose the key pair here.
     pub fn import(from: &str) {
         log::info!("import {}", from);
         // load first to create path
         Self::load();
-        crate::allow_err!(std::fs::copy(from, Self::file()));
-        crate::allow_err!(std::fs::copy(
-            from.replace(".toml", "2.toml"),
-            Self::file_("2")
-        ));
+        let text = std::fs::read_to_string(from).unwrap_or_default();
+        let mut config = match toml::from_str::<Config>(&text) {
+            Ok(x) => x,
+            Err(err) => {
+                log::error!("Failed to import {}: {}", from, err);
+                return;
+            }
+        };
+        config.open_secrets();
+        if config.locked {
+            log::error!("Failed to import {}, sealed with another key", from);
+            return;
+        }
+        config.store();
+        let text = std::fs::read_to_string(from.replace(".toml", "2.toml")).unwrap_or_default();
+        if let Ok(mut config) = toml::from_str::<Config2>(&text) {
+            if let Some(socks) = config.socks.as_mut() {
+                if let Some(x) = keystore::open::<String>(&config.sealed) {
+                    socks.password = x;
+                }
+            }
+            config.store();
+        }
     }
 
+    // The secrets are written in clear, for another account to seal them with
+    // its own key by import, the tmp files being removed once imported.
     pub fn save_tmp() -> String {
-        let _lock = CONFIG.read().unwrap(); // do not use let _, which will be dropped immediately
+        let config = CONFIG.read().unwrap(); // do not use let _, which will be dropped immediately
+        let mut config2 = CONFIG2.read().unwrap().clone();
+        config2.sealed.clear();
         let path = Self::file_("2").to_str().unwrap_or("").to_owned();
-        let path2 = format!("{}_tmp", path);
-        crate::allow_err!(std::fs::copy(&path, &path2));
+        crate::allow_err!(confy::store_path(format!("{}_tmp", path), &config2));
+        let mut config = config.clone();
+        if !config.locked {
+            config.sealed.clear();
+        }
         let path = Self::file().to_str().unwrap_or("").to_owned();
         let path2 = format!("{}_tmp", path);
-        crate::allow_err!(std::fs::copy(&path, &path2));
+        crate::allow_err!(confy::store_path(&path2, &config));
         path2
     }
 
+    /// The settings of this host to provision others with.
+    pub fn get_provisioning() -> Provisioning {
+        let mut options = Self::get_options();
+        let mut take = |k: &str| options.remove(k).unwrap_or_default();
+        let rendezvous_server = take("custom-rendezvous-server");
+        let relay_server = take("relay-server");
+        let key = take("key");
+        take("provisioned");
+        let (salt, hash) = Self::get_password_hash().unwrap_or_default();
+        Provisioning {
+            version: PROVISIONING_VERSION,
+            rendezvous_server,
+            relay_server,
+            key,
+            password_hash: base64::encode(hash),
+            password_salt: base64::encode(salt),
+            options,
+        }
+    }
+
     fn file_(suffix: &str) -> PathBuf {
         let name = format!("{}{}", APP_NAME, suffix);
         Self::path(name).with_extension("toml")
@@ -292,6 +643,22 @@ impl Config {
         return "".into();
     }
 
+    /// The audit log of the sessions, in the config folder of the service.
+    pub fn audit_log_path() -> PathBuf {
+        Self::path("audit.log")
+    }
+
+    /// The folder of the chat history with the peers.
+    pub fn chat_dir() -> PathBuf {
+        Self::path("chats")
+    }
+
+    /// The folder of the print jobs, "spool" for the ones of the virtual
+    /// printer here and "received" for the ones of the peers.
+    pub fn print_dir(sub: &str) -> PathBuf {
+        Self::path("print").join(sub)
+    }
+
     #[allow(unreachable_code)]
     pub fn log_path() -> PathBuf {
         #[cfg(target_os = "macos")]
@@ -349,7 +716,20 @@ impl Config {
     }
 
     pub fn get_rendezvous_server() -> String {
-        let mut rendezvous_server = Self::get_option("custom-rendezvous-server");
+        let custom = Self::get_custom_rendezvous_servers();
+        let mut rendezvous_server = match custom.len() {
+            0 => "".to_owned(),
+            1 => custom[0].clone(),
+            // the active one of the failover list, updated by latency
+            _ => {
+                let active = CONFIG2.read().unwrap().rendezvous_server.clone();
+                if custom.contains(&active) {
+                    active
+                } else {
+                    custom[0].clone()
+                }
+            }
+        };
         if rendezvous_server.is_empty() {
             rendezvous_server = CONFIG2.write().unwrap().rendezvous_server.clone();
         }
@@ -365,10 +745,21 @@ impl Config {
         rendezvous_server
     }
 
+    /// `custom-rendezvous-server` may list several servers separated by commas,
+    /// in order of preference for failover.
+    fn get_custom_rendezvous_servers() -> Vec<String> {
+        Self::get_option("custom-rendezvous-server")
+            .split(",")
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
     pub fn get_rendezvous_servers() -> Vec<String> {
-        let s = Self::get_option("custom-rendezvous-server");
-        if !s.is_empty() {
-            return vec![s];
+        let custom = Self::get_custom_rendezvous_servers();
+        if !custom.is_empty() {
+            return custom;
         }
         let serial_obsolute = CONFIG2.read().unwrap().serial > SERIAL;
         if serial_obsolute {
@@ -531,12 +922,28 @@ impl Config {
         id
     }
 
+    /// With the ones locked by the policy.
     pub fn get_options() -> HashMap<String, String> {
-        CONFIG2.read().unwrap().options.clone()
+        let mut options = CONFIG2.read().unwrap().options.clone();
+        for (k, v) in policy::get_all() {
+            if v.is_empty() {
+                options.remove(&k);
+            } else {
+                options.insert(k, v);
+            }
+        }
+        options
     }
 
-    pub fn set_options(v: HashMap<String, String>) {
+    /// The options locked by the policy are kept as they are.
+    pub fn set_options(mut v: HashMap<String, String>) {
         let mut config = CONFIG2.write().unwrap();
+        for k in policy::get_all().keys() {
+            match config.options.get(k) {
+                Some(x) => v.insert(k.clone(), x.clone()),
+                None => v.remove(k),
+            };
+        }
         if config.options == v {
             return;
         }
@@ -545,6 +952,9 @@ impl Config {
     }
 
     pub fn get_option(k: &str) -> String {
+        if let Some(v) = policy::get(k) {
+            return v;
+        }
         if let Some(v) = CONFIG2.read().unwrap().options.get(k) {
             v.clone()
         } else {
@@ -552,7 +962,32 @@ impl Config {
         }
     }
 
+    /// If the option is locked by the policy of the admin.
+    #[inline]
+    pub fn is_option_locked(k: &str) -> bool {
+        policy::is_locked(k)
+    }
+
+    /// The permission profile of a peer in "permission-profiles", e.g.
+    /// "123456789=full,987654321=view", else "default-permission-profile":
+    /// "full", "view" (view only), "file" (file transfer only),
+    /// or "" for the global permissions.
+    pub fn get_permission_profile(id: &str) -> String {
+        let profiles = Config::get_option("permission-profiles");
+        for x in profiles.split(",") {
+            let mut it = x.splitn(2, "=");
+            if it.next().map(|x| x.trim()) == Some(id) {
+                return it.next().unwrap_or_default().trim().to_owned();
+            }
+        }
+        Config::get_option("default-permission-profile")
+    }
+
     pub fn set_option(k: String, v: String) {
+        if policy::is_locked(&k) {
+            log::warn!("{} is locked by the policy", k);
+            return;
+        }
         let mut config = CONFIG2.write().unwrap();
         if k == "custom-rendezvous-server" {
             config.rendezvous_server = "".to_owned();
@@ -568,6 +1003,39 @@ impl Config {
         }
     }
 
+    /// Cap on what this side sends, and asks the peer to send, 0 for none.
+    pub fn get_max_bandwidth_kbps() -> u32 {
+        Self::get_option("max-bandwidth-kbps").parse().unwrap_or(0)
+    }
+
+    /// Seconds an interrupted session waits for the peer to resume it, 0 to disable.
+    pub fn get_session_resume_timeout() -> u64 {
+        Self::get_option("session-resume-timeout")
+            .parse()
+            .unwrap_or(30)
+    }
+
+    /// Where the recordings of the sessions go, the videos directory of the user by default.
+    pub fn get_video_save_directory() -> PathBuf {
+        let dir = Self::get_option("video-save-directory");
+        if !dir.is_empty() {
+            return dir.into();
+        }
+        let mut path = dirs_next::video_dir().unwrap_or_else(Self::get_home);
+        path.push(APP_NAME);
+        path
+    }
+
+    /// Where the sessions coming in are recorded with "allow-record-incoming",
+    /// `incoming` in the directory of the recordings by default.
+    pub fn get_incoming_record_directory() -> PathBuf {
+        let dir = Self::get_option("incoming-record-directory");
+        if !dir.is_empty() {
+            return dir.into();
+        }
+        Self::get_video_save_directory().join("incoming")
+    }
+
     pub fn update_id() {
         // to-do: how about if one ip register a lot of ids?
         let id = Self::get_id();
@@ -577,16 +1045,63 @@ impl Config {
         log::info!("id updated from {} to {}", id, new_id);
     }
 
+    /// The random password, shown to be told to the other side.
     pub fn set_password(password: &str) {
         let mut config = CONFIG.write().unwrap();
-        if password == config.password {
+        if password == config.password && config.password_hash.is_empty() {
             return;
         }
         config.password = password.into();
+        config.password_hash.clear();
+        config.password_salt.clear();
+        config.random_password = true;
         config.store();
     }
 
+    /// The password of your own, only its hash is kept.
+    pub fn set_permanent_password(password: &str) {
+        if password.is_empty() {
+            return;
+        }
+        let mut config = CONFIG.write().unwrap();
+        if let Err(err) = config.set_password_hash(password) {
+            log::error!("{}", err);
+            return;
+        }
+        config.store();
+    }
+
+    /// The permanent password by its verifier, e.g. from a provisioning file.
+    pub fn set_permanent_password_hash(salt: &str, hash: &str) {
+        let ok = |x: &str| base64::decode(x).map(|x| !x.is_empty()).unwrap_or(false);
+        if !ok(salt) || !ok(hash) {
+            log::error!("Invalid permanent password hash");
+            return;
+        }
+        let mut config = CONFIG.write().unwrap();
+        config.password_hash = hash.to_owned();
+        config.password_salt = salt.to_owned();
+        config.password = "".to_owned();
+        config.random_password = false;
+        config.store();
+    }
+
+    /// The salt and the verifier of the permanent password, if it is set.
+    pub fn get_password_hash() -> Option<(Vec<u8>, Vec<u8>)> {
+        let config = CONFIG.read().unwrap();
+        if config.password_hash.is_empty() {
+            return None;
+        }
+        let salt = base64::decode(&config.password_salt).unwrap_or_default();
+        let hash = base64::decode(&config.password_hash).unwrap_or_default();
+        Some((salt, hash))
+    }
+
+    /// Empty if only the hash of the permanent password is kept.
     pub fn get_password() -> String {
+        if !CONFIG.read().unwrap().password_hash.is_empty() {
+            return "".to_owned();
+        }
         let mut password = CONFIG.read().unwrap().password.clone();
         if password.is_empty() {
             password = Config::get_auto_password();
@@ -595,6 +1110,12 @@ impl Config {
         password
     }
 
+    /// If the password is a random one, not set by the user.
+    pub fn is_random_password() -> bool {
+        let config = CONFIG.read().unwrap();
+        config.password_hash.is_empty() && config.random_password
+    }
+
     pub fn set_salt(salt: &str) {
         let mut config = CONFIG.write().unwrap();
         if salt == config.salt {
@@ -613,6 +1134,52 @@ impl Config {
         salt
     }
 
+    /// Set the base32 TOTP secret, the second factor is off if it is empty.
+    pub fn set_totp_secret(secret: &str) {
+        let mut totp = "".to_owned();
+        if !secret.is_empty() {
+            let nonce = secretbox::gen_nonce();
+            let mut v = nonce.0.to_vec();
+            v.extend(secretbox::seal(
+                secret.as_bytes(),
+                &nonce,
+                &Self::get_totp_key(),
+            ));
+            totp = base64::encode(v);
+        }
+        let mut config = CONFIG.write().unwrap();
+        if totp.is_empty() && config.totp.is_empty() {
+            return;
+        }
+        config.totp = totp;
+        config.store();
+    }
+
+    /// Empty if not set, or if it can not be opened, e.g. with a new key pair.
+    pub fn get_totp_secret() -> String {
+        let totp = CONFIG.read().unwrap().totp.clone();
+        let v = base64::decode(&totp).unwrap_or_default();
+        if v.len() <= secretbox::NONCEBYTES {
+            return "".to_owned();
+        }
+        let nonce = secretbox::Nonce::from_slice(&v[..secretbox::NONCEBYTES]);
+        nonce
+            .and_then(|nonce| {
+                secretbox::open(&v[secretbox::NONCEBYTES..], &nonce, &Self::get_totp_key()).ok()
+            })
+            .and_then(|x| String::from_utf8(x).ok())
+            .unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn is_totp_enabled() -> bool {
+        !CONFIG.read().unwrap().totp.is_empty()
+    }
+
+    fn get_totp_key() -> secretbox::Key {
+        secretbox::Key(sha256::hash(&Self::get_key_pair().0).0)
+    }
+
     pub fn get_size() -> Size {
         CONFIG2.read().unwrap().size
     }
@@ -653,10 +1220,48 @@ impl Config {
         CONFIG2.read().unwrap().socks.clone()
     }
 
+    /// The bans of the failed logins, the expired ones are kept for the
+    /// next ban to be longer.
+    pub fn get_bans() -> HashMap<String, (i64, u32)> {
+        CONFIG2.read().unwrap().bans.clone()
+    }
+
+    pub fn set_bans(bans: HashMap<String, (i64, u32)>) {
+        let mut config = CONFIG2.write().unwrap();
+        if bans == config.bans {
+            return;
+        }
+        config.bans = bans;
+        config.store();
+    }
+
+    pub fn unban(key: &str) {
+        let mut config = CONFIG2.write().unwrap();
+        if config.bans.remove(key).is_some() {
+            config.store();
+        }
+    }
+
+    pub fn set_restore_token(token: String) {
+        let mut config = CONFIG2.write().unwrap();
+        if token == config.restore_token {
+            return;
+        }
+        config.restore_token = token;
+        config.store();
+    }
+
+    pub fn get_restore_token() -> String {
+        CONFIG2.read().unwrap().restore_token.clone()
+    }
+
     pub fn get_network_type() -> NetworkType {
         match &CONFIG2.read().unwrap().socks {
             None => NetworkType::Direct,
-            Some(_) => NetworkType::ProxySocks,
+            Some(s) => match s.proxy_type() {
+                ProxyType::Socks5 => NetworkType::ProxySocks,
+                ProxyType::Http | ProxyType::Https => NetworkType::ProxyHttp,
+            },
         }
     }
 }
@@ -666,18 +1271,41 @@ const PEERS: &str = "peers";
 impl PeerConfig {
     pub fn load(id: &str) -> PeerConfig {
         let _ = CONFIG.read().unwrap(); // for lock
-        match confy::load_path(&Self::path(id)) {
+        let mut config: PeerConfig = match confy::load_path(&Self::path(id)) {
             Ok(config) => config,
             Err(err) => {
                 log::error!("Failed to load config: {}", err);
                 Default::default()
             }
+        };
+        match keystore::open(&config.sealed) {
+            Some(password) => config.password = password,
+            None if config.password.is_empty() || !config.sealed.is_empty() => {}
+            None if keystore::is_available(false) => {
+                // the modified time is the order of the recent sessions
+                let path = Self::path(id);
+                let modified = fs::metadata(&path).and_then(|m| m.modified());
+                config.store(id);
+                if let Ok(t) = modified {
+                    filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(t)).ok();
+                }
+            }
+            None => {}
         }
+        config
     }
 
     pub fn store(&self, id: &str) {
         let _ = CONFIG.read().unwrap(); // for lock
-        if let Err(err) = confy::store_path(Self::path(id), self) {
+        let mut config = self.clone();
+        config.sealed.clear();
+        if !config.password.is_empty() {
+            if let Some(sealed) = keystore::seal(&config.password, false) {
+                config.sealed = sealed;
+                config.password.clear();
+            }
+        }
+        if let Err(err) = confy::store_path(Self::path(id), &config) {
             log::error!("Failed to store config: {}", err);
         }
     }
@@ -758,6 +1386,9 @@ impl Fav {
 pub struct LanPeers {
     #[serde(default)]
     pub peers: String,
+    // id -> mac address of the peers ever found, to wake them up once they are off
+    #[serde(default)]
+    pub macs: HashMap<String, String>,
 }
 
 impl LanPeers {
@@ -773,13 +1404,25 @@ impl LanPeers {
     }
 
     pub fn store(peers: String) {
-        let f = LanPeers { peers };
+        Self::store_(LanPeers {
+            peers,
+            ..Self::load()
+        });
+    }
+
+    pub fn store_macs(macs: Vec<(String, String)>) {
+        let mut f = Self::load();
+        f.macs.extend(macs.into_iter());
+        Self::store_(f);
+    }
+
+    fn store_(f: LanPeers) {
         if let Err(err) = confy::store_path(Config::file_("_lan_peers"), f) {
             log::error!("Failed to store lan peers: {}", err);
         }
     }
 
-    pub fn modify_time() -> crate::ResultType<u64> {
+    pub fn modify_time() -> ResultType<u64> {
         let p = Config::file_("_lan_peers");
         Ok(fs::metadata(p)?
             .modified()?
@@ -788,6 +1431,191 @@ impl LanPeers {
     }
 }
 
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AbPeer {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub notes: String,
+    // the folder of the peer, e.g. "Office/Floor 1" in "Office"
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub platform: String,
+    // the password of the peer, logged in with if none is remembered
+    #[serde(default)]
+    pub password: String,
+    // in ms, the latest change of a peer winning at the sync,
+    // for which the removed ones are kept as deleted
+    #[serde(default)]
+    pub updated: i64,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// The peers managed by hand, with their tags, notes and passwords, kept
+/// sealed with the keystore, and synced with `sync_url` if it is set.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AddressBook {
+    #[serde(default)]
+    pub sync_url: String,
+    // the bearer token of the url, if any
+    #[serde(default)]
+    pub sync_token: String,
+    // what is synced is sealed with a key of it, the url only seeing that
+    #[serde(default)]
+    pub sync_passphrase: String,
+    // in ms, of the last sync
+    #[serde(default)]
+    pub synced: i64,
+    // all the rest, sealed with the keystore
+    #[serde(default)]
+    sealed: String,
+    // `sealed` could not be opened, so it is kept as it is
+    #[serde(skip)]
+    locked: bool,
+    // the other scalar value must before this
+    #[serde(default)]
+    pub peers: Vec<AbPeer>,
+}
+
+impl AddressBook {
+    pub fn load() -> AddressBook {
+        let _lock = CONFIG.read().unwrap(); // for lock
+        let ab: AddressBook = match confy::load_path(&Config::file_("_ab")) {
+            Ok(ab) => ab,
+            Err(err) => {
+                log::error!("Failed to load the address book: {}", err);
+                return Default::default();
+            }
+        };
+        if ab.sealed.is_empty() {
+            return ab;
+        }
+        match keystore::open::<AddressBook>(&ab.sealed) {
+            Some(ab) => ab,
+            None => {
+                log::error!("Failed to open the address book, of another keystore?");
+                AddressBook {
+                    locked: true,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if self.locked {
+            return;
+        }
+        let mut ab = self.clone();
+        ab.sealed.clear();
+        if let Some(sealed) = keystore::seal(&ab, false) {
+            ab = AddressBook {
+                sealed,
+                ..Default::default()
+            };
+        }
+        let _lock = CONFIG.read().unwrap(); // for lock
+        if let Err(err) = confy::store_path(Config::file_("_ab"), ab) {
+            log::error!("Failed to store the address book: {}", err);
+        }
+    }
+
+    /// The peers, not the removed ones.
+    pub fn get_peers(&self) -> Vec<&AbPeer> {
+        self.peers.iter().filter(|x| !x.deleted).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&AbPeer> {
+        self.peers.iter().find(|x| x.id == id && !x.deleted)
+    }
+
+    /// A peer added or changed.
+    pub fn set(&mut self, mut peer: AbPeer) {
+        peer.updated = get_time_ms();
+        peer.deleted = false;
+        match self.peers.iter_mut().find(|x| x.id == peer.id) {
+            Some(x) => *x = peer,
+            None => self.peers.push(peer),
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        if let Some(x) = self.peers.iter_mut().find(|x| x.id == id) {
+            *x = AbPeer {
+                id: id.to_owned(),
+                updated: get_time_ms(),
+                deleted: true,
+                ..Default::default()
+            };
+        }
+    }
+
+    /// The peers of another copy merged in, the latest change of each winning,
+    /// true if any is taken.
+    pub fn merge(&mut self, peers: Vec<AbPeer>) -> bool {
+        let mut changed = false;
+        for peer in peers {
+            match self.peers.iter_mut().find(|x| x.id == peer.id) {
+                Some(x) if x.updated >= peer.updated => {}
+                Some(x) => {
+                    *x = peer;
+                    changed = true;
+                }
+                None => {
+                    self.peers.push(peer);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// The tags of all the peers, sorted.
+    pub fn get_tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .get_peers()
+            .iter()
+            .flat_map(|x| x.tags.iter().cloned())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    /// The groups of all the peers and the ones they are in, sorted.
+    pub fn get_groups(&self) -> Vec<String> {
+        let mut groups = Vec::new();
+        for p in self.get_peers() {
+            let parts: Vec<&str> = p.group.split('/').map(|x| x.trim()).collect();
+            for i in 1..=parts.len() {
+                if !parts[i - 1].is_empty() {
+                    groups.push(parts[..i].join("/"));
+                }
+            }
+        }
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+}
+
+fn get_time_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_millis() as i64)
+        .unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -800,4 +1628,135 @@ mod tests {
         let res = toml::to_string_pretty(&cfg);
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_proxy_type() {
+        let mut s = Socks5Server {
+            proxy: "127.0.0.1:1080".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(s.proxy_type(), ProxyType::Socks5);
+        assert_eq!(s.proxy_addr(), "127.0.0.1:1080");
+        s.proxy = "socks5://127.0.0.1:1080".to_owned();
+        assert_eq!(s.proxy_type(), ProxyType::Socks5);
+        assert_eq!(s.proxy_addr(), "127.0.0.1:1080");
+        s.proxy = "HTTP://proxy.corp:3128/".to_owned();
+        assert_eq!(s.proxy_type(), ProxyType::Http);
+        assert_eq!(s.proxy_addr(), "proxy.corp:3128");
+        s.proxy = "https://proxy.corp:443".to_owned();
+        assert_eq!(s.proxy_type(), ProxyType::Https);
+    }
+
+    #[test]
+    fn test_config2_roundtrip() {
+        let cfg = Config2 {
+            restore_token: "token".to_owned(),
+            socks: Some(Socks5Server {
+                proxy: "127.0.0.1:1080".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let text = toml::to_string_pretty(&cfg).unwrap();
+        let cfg: Config2 = toml::from_str(&text).unwrap();
+        assert_eq!(cfg.restore_token, "token");
+        assert_eq!(cfg.socks.unwrap().proxy, "127.0.0.1:1080");
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("secret", b"saltsalt").unwrap();
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, hash_password("secret", b"saltsalt").unwrap());
+        assert_ne!(hash, hash_password("secret", b"saltsalu").unwrap());
+        // too short a salt for argon2
+        assert!(hash_password("secret", b"salt").is_err());
+        let mut cfg = Config {
+            password: "my own password".to_owned(),
+            ..Default::default()
+        };
+        assert!(cfg.migrate_password());
+        assert!(cfg.password.is_empty());
+        let salt = base64::decode(&cfg.password_salt).unwrap();
+        let key = hash_password("my own password", &salt).unwrap();
+        let verifier = base64::decode(&cfg.password_hash).unwrap();
+        assert_eq!(verifier, password_verifier(&key));
+        let proof = mask_login_key(&key, &verifier, "challenge");
+        assert_ne!(proof, key);
+        assert_eq!(mask_login_key(&proof, &verifier, "challenge"), key);
+        assert_ne!(mask_login_key(&proof, &verifier, "other"), key);
+        assert!(!cfg.migrate_password());
+        cfg = Config {
+            password: Config::get_auto_password(),
+            random_password: true,
+            ..Default::default()
+        };
+        assert!(!cfg.migrate_password());
+        // of the user, even if it looks like a random one
+        cfg.random_password = false;
+        assert!(cfg.migrate_password());
+        assert!(cfg.password.is_empty());
+    }
+
+    #[test]
+    fn test_address_book() {
+        let peer = |id: &str, alias: &str, updated: i64| AbPeer {
+            id: id.to_owned(),
+            alias: alias.to_owned(),
+            tags: vec![alias.to_owned(), "office".to_owned()],
+            updated,
+            ..Default::default()
+        };
+        let mut ab = AddressBook {
+            peers: vec![peer("1", "a", 10), peer("2", "b", 10), peer("3", "c", 10)],
+            ..Default::default()
+        };
+        ab.remove("3");
+        assert_eq!(ab.get_peers().len(), 2);
+        assert!(ab.get("3").is_none());
+        assert_eq!(ab.get_tags(), vec!["a", "b", "office"]);
+        ab.peers[0].group = "Office/Floor 1".to_owned();
+        ab.peers[1].group = "Office/Floor 2".to_owned();
+        let groups = ab.get_groups();
+        assert_eq!(groups, vec!["Office", "Office/Floor 1", "Office/Floor 2"]);
+        let mut other = vec![peer("1", "x", 5), peer("2", "y", 20), peer("4", "d", 1)];
+        other.push(AbPeer {
+            deleted: true,
+            ..peer("3", "c", 11)
+        });
+        assert!(ab.merge(other.clone()));
+        assert_eq!(ab.get("1").unwrap().alias, "a");
+        assert_eq!(ab.get("2").unwrap().alias, "y");
+        assert_eq!(ab.get("4").unwrap().alias, "d");
+        assert!(ab.get("3").is_none());
+        assert!(!ab.merge(other));
+    }
+
+    #[test]
+    fn test_provisioning() {
+        assert_eq!(
+            Provisioning::parse("[options]\nallow-darktheme = 'Y'\n"),
+            None
+        );
+        let p = Provisioning {
+            version: PROVISIONING_VERSION,
+            rendezvous_server: "rs.corp:21116".to_owned(),
+            key: "pk".to_owned(),
+            options: [("enable-keyboard", "N"), ("direct-server", "")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(Provisioning::parse(&p.to_toml()), Some(p.clone()));
+        let mut options = HashMap::new();
+        options.insert("direct-server".to_owned(), "Y".to_owned());
+        options.insert("relay-server".to_owned(), "relay.corp".to_owned());
+        let options = p.merge_options(options);
+        assert_eq!(options.len(), 4);
+        assert_eq!(options["custom-rendezvous-server"], "rs.corp:21116");
+        assert_eq!(options["relay-server"], "relay.corp");
+        assert_eq!(options["enable-keyboard"], "N");
+        assert!(!options.contains_key("direct-server"));
+    }
 }
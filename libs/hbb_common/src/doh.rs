@@ -0,0 +1,306 @@
+use crate::{bail, config::Config, tcp::tls_connect, timeout, ResultType};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// queried by ip, so that no resolver is needed to reach the resolver
+const DEFAULT_PROVIDER: (&str, &str) = ("cloudflare-dns.com", "1.1.1.1:443");
+const DEFAULT_PATH: &str = "/dns-query";
+const DOH_TIMEOUT: u64 = 3_000;
+const MIN_TTL: u32 = 60;
+const MAX_RESPONSE: usize = 64 * 1024;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+lazy_static::lazy_static! {
+    static ref CACHE: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>> = Default::default();
+}
+
+struct Provider {
+    host: String,
+    addr: String,
+    path: String,
+}
+
+/// `use-doh` is "Y" for the default provider, or the url of another one,
+/// e.g. "https://dns.google/dns-query", whose host is resolved by the system.
+fn get_provider() -> Option<Provider> {
+    let v = Config::get_option("use-doh");
+    if v.is_empty() || v == "N" {
+        return None;
+    }
+    if v == "Y" {
+        return Some(Provider {
+            host: DEFAULT_PROVIDER.0.to_owned(),
+            addr: DEFAULT_PROVIDER.1.to_owned(),
+            path: DEFAULT_PATH.to_owned(),
+        });
+    }
+    let rest = v.trim().trim_start_matches("https://");
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, DEFAULT_PATH),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let (name, addr) = match host.rfind(':') {
+        Some(i) => (&host[..i], host.to_owned()),
+        None => (host, format!("{}:443", host)),
+    };
+    Some(Provider {
+        host: name.to_owned(),
+        addr,
+        path: path.to_owned(),
+    })
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    get_provider().is_some()
+}
+
+/// Resolve `host` with DNS over HTTPS (RFC 8484), A and AAAA queried at once,
+/// the ipv4 addresses first.
+pub async fn resolve(host: &str) -> ResultType<Vec<IpAddr>> {
+    let provider = match get_provider() {
+        Some(provider) => provider,
+        None => bail!("DNS over HTTPS is not enabled"),
+    };
+    if let Some((ips, expires)) = CACHE.lock().unwrap().get(host) {
+        if *expires > Instant::now() {
+            return Ok(ips.clone());
+        }
+    }
+    let (a, aaaa) = futures::join!(
+        resolve_type(&provider, host, TYPE_A),
+        resolve_type(&provider, host, TYPE_AAAA)
+    );
+    let mut ttl = u32::MAX;
+    let mut ips = Vec::new();
+    let mut err = None;
+    for res in [a, aaaa] {
+        match res {
+            Ok((mut res, res_ttl)) if !res.is_empty() => {
+                ttl = ttl.min(res_ttl);
+                ips.append(&mut res);
+            }
+            Ok(_) => {}
+            Err(e) => err = err.or(Some(e)),
+        }
+    }
+    if ips.is_empty() {
+        if let Some(err) = err {
+            return Err(err);
+        }
+        bail!("DNS over HTTPS: no address for {}", host);
+    }
+    log::debug!("DNS over HTTPS: {} = {:?}, ttl: {}", host, ips, ttl);
+    let expires = Instant::now() + Duration::from_secs(ttl.max(MIN_TTL) as _);
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(host.to_owned(), (ips.clone(), expires));
+    Ok(ips)
+}
+
+async fn resolve_type(
+    provider: &Provider,
+    host: &str,
+    qtype: u16,
+) -> ResultType<(Vec<IpAddr>, u32)> {
+    let id = rand::random::<u16>();
+    let response = query(provider, &build_query(id, host, qtype)?).await?;
+    parse_response(&response, id, qtype)
+}
+
+async fn query(provider: &Provider, body: &[u8]) -> ResultType<Vec<u8>> {
+    let stream = timeout(DOH_TIMEOUT, TcpStream::connect(&provider.addr)).await??;
+    let mut stream = timeout(DOH_TIMEOUT, tls_connect(&provider.host, stream)).await??;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        provider.path,
+        provider.host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    timeout(
+        DOH_TIMEOUT,
+        (&mut stream)
+            .take(MAX_RESPONSE as _)
+            .read_to_end(&mut response),
+    )
+    .await??;
+    parse_http_response(&response)
+}
+
+fn parse_http_response(response: &[u8]) -> ResultType<Vec<u8>> {
+    let pos = match response.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => bail!("DNS over HTTPS: incomplete response"),
+    };
+    let head = String::from_utf8_lossy(&response[..pos]).to_lowercase();
+    let body = &response[pos + 4..];
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("DNS over HTTPS: {}", status);
+    }
+    if head.contains("transfer-encoding: chunked") {
+        return dechunk(body);
+    }
+    Ok(body.to_vec())
+}
+
+fn dechunk(mut data: &[u8]) -> ResultType<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let pos = match data.windows(2).position(|x| x == b"\r\n") {
+            Some(pos) => pos,
+            None => bail!("DNS over HTTPS: bad chunk"),
+        };
+        let size = String::from_utf8_lossy(&data[..pos]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        data = &data[pos + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            bail!("DNS over HTTPS: truncated chunk");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[(size + 2).min(data.len())..];
+    }
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> ResultType<Vec<u8>> {
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid host name: {}", host);
+        }
+        query.push(label.len() as _);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+// the addresses of `qtype` in the answers, and the lowest ttl of them
+fn parse_response(data: &[u8], id: u16, qtype: u16) -> ResultType<(Vec<IpAddr>, u32)> {
+    if data.len() < 12 {
+        bail!("DNS over HTTPS: short response");
+    }
+    if data[..2] != id.to_be_bytes() || data[2] & 0x80 == 0 {
+        bail!("DNS over HTTPS: not the response to our query");
+    }
+    let rcode = data[3] & 0xf;
+    if rcode != 0 {
+        bail!("DNS over HTTPS: rcode {}", rcode);
+    }
+    let qdcount = u16::from_be_bytes([data[4], data[5]]);
+    let ancount = u16::from_be_bytes([data[6], data[7]]);
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(data, pos)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..ancount {
+        pos = skip_name(data, pos)?;
+        if pos + 10 > data.len() {
+            bail!("DNS over HTTPS: truncated answer");
+        }
+        let ty = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let rr_ttl =
+            u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
+        let len = u16::from_be_bytes([data[pos + 8], data[pos + 9]]) as usize;
+        pos += 10;
+        if pos + len > data.len() {
+            bail!("DNS over HTTPS: truncated answer");
+        }
+        let rdata = &data[pos..pos + len];
+        pos += len;
+        // CNAMEs come first and are followed by the addresses they resolve to
+        if ty != qtype {
+            continue;
+        }
+        let ip = match (ty, len) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(rr_ttl);
+    }
+    Ok((ips, ttl))
+}
+
+fn skip_name(data: &[u8], mut pos: usize) -> ResultType<usize> {
+    loop {
+        let len = match data.get(pos) {
+            Some(len) => *len as usize,
+            None => bail!("DNS over HTTPS: truncated name"),
+        };
+        if len == 0 {
+            return Ok(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // compression pointer, the name ends here
+            return Ok(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_message() {
+        let query = build_query(0x1234, "rs-ny.rustdesk.com", TYPE_A).unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(query.len(), 12 + 20 + 4);
+        // the query echoed, a CNAME, then the address
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        response[7] = 2;
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 30, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 1, 2, 3, 4]);
+        let (ips, ttl) = parse_response(&response, 0x1234, TYPE_A).unwrap();
+        assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))]);
+        assert_eq!(ttl, 256);
+        assert!(parse_response(&response, 0x4321, TYPE_A).is_err());
+        assert!(parse_response(&response[..response.len() - 2], 0x1234, TYPE_A).is_err());
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_http_response() {
+        let res = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert_eq!(parse_http_response(res).unwrap(), b"abcde");
+        let res = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nab";
+        assert_eq!(parse_http_response(res).unwrap(), b"ab");
+        assert!(parse_http_response(b"HTTP/1.1 400 Bad Request\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::ResultType;
+use anyhow::anyhow;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// GF(2^8) limits data + parity shards of one group.
+pub const MAX_SHARDS: usize = 256;
+
+/// Number of parity shards protecting `data` shards at `ratio` (parity / data),
+/// at least one as long as the ratio is positive.
+pub fn parity_shards(data: usize, ratio: f32) -> usize {
+    if data == 0 || !(ratio > 0.) {
+        return 0;
+    }
+    ((data as f32 * ratio).ceil() as usize).min(MAX_SHARDS - data.min(MAX_SHARDS))
+}
+
+/// Reed-Solomon parity for `data`, which must all be of the same length.
+pub fn encode<T: AsRef<[u8]>>(data: &[T], parity: usize) -> ResultType<Vec<Vec<u8>>> {
+    if data.is_empty() || parity == 0 {
+        return Ok(Vec::new());
+    }
+    let rs = ReedSolomon::new(data.len(), parity).map_err(|e| anyhow!("{:?}", e))?;
+    let len = data[0].as_ref().len();
+    let mut parity = vec![vec![0u8; len]; parity];
+    let data: Vec<&[u8]> = data.iter().map(|x| x.as_ref()).collect();
+    rs.encode_sep(&data, &mut parity)
+        .map_err(|e| anyhow!("{:?}", e))?;
+    Ok(parity)
+}
+
+/// Fill in the missing data shards of `shards`, the first `data` of which are
+/// data and the rest parity. Returns false if fewer than `data` shards are present.
+pub fn reconstruct(shards: &mut [Option<Vec<u8>>], data: usize) -> ResultType<bool> {
+    if data > shards.len() {
+        return Ok(false);
+    }
+    if shards[..data].iter().all(|x| x.is_some()) {
+        return Ok(true);
+    }
+    if shards.iter().filter(|x| x.is_some()).count() < data {
+        return Ok(false);
+    }
+    let rs = ReedSolomon::new(data, shards.len() - data).map_err(|e| anyhow!("{:?}", e))?;
+    rs.reconstruct_data(shards)
+        .map_err(|e| anyhow!("{:?}", e))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fec() {
+        assert_eq!(parity_shards(10, 0.), 0);
+        assert_eq!(parity_shards(1, 0.1), 1);
+        assert_eq!(parity_shards(10, 0.25), 3);
+        assert_eq!(parity_shards(250, 0.5), 6);
+        let data: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 32]).collect();
+        let parity = encode(&data, 2).unwrap();
+        assert_eq!(parity.len(), 2);
+        let mut shards: Vec<Option<Vec<u8>>> = data
+            .iter()
+            .chain(parity.iter())
+            .cloned()
+            .map(Some)
+            .collect();
+        shards[1] = None;
+        shards[4] = None;
+        assert!(reconstruct(&mut shards, 5).unwrap());
+        for (i, d) in data.iter().enumerate() {
+            assert_eq!(shards[i].as_ref(), Some(d));
+        }
+        shards[0] = None;
+        shards[2] = None;
+        shards[3] = None;
+        assert!(!reconstruct(&mut shards, 5).unwrap());
+    }
+}
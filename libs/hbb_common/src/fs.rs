@@ -1,10 +1,16 @@
 use crate::{bail, message_proto::*, ResultType};
-use std::path::{Path, PathBuf};
+use sodiumoxide::crypto::hash::sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
 // https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
 use crate::{
     compress::{compress, decompress},
     config::{Config, COMPRESS_LEVEL},
 };
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 #[cfg(windows)]
 use std::os::windows::prelude::*;
 use tokio::{fs::File, io::*};
@@ -91,6 +97,7 @@ pub fn read_dir(path: &PathBuf, include_hidden: bool) -> ResultType<FileDirector
                 is_hidden,
                 size,
                 modified_time,
+                mode: get_mode(&meta),
                 ..Default::default()
             });
         }
@@ -98,6 +105,86 @@ pub fn read_dir(path: &PathBuf, include_hidden: bool) -> ResultType<FileDirector
     Ok(dir)
 }
 
+// the permission bits on unix, the read-only attribute as them on windows
+#[inline]
+fn get_mode(meta: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    return meta.permissions().mode() & 0o7777;
+    #[cfg(not(unix))]
+    return if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+}
+
+// not the setuid, setgid and sticky bits of the peer
+fn set_mode(path: &Path, mode: u32) -> ResultType<()> {
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    #[cfg(not(unix))]
+    if mode & 0o222 == 0 {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[inline]
+fn is_link(entry: &FileEntry) -> bool {
+    match entry.entry_type.enum_value() {
+        Ok(FileType::FileLink) | Ok(FileType::DirLink) => true,
+        _ => false,
+    }
+}
+
+// Whether the target of the link `name` of the peer stays within the root it
+// is written to: relative, its ".." only leading and not above the root.
+fn is_link_target_inside(name: &str, target: &str) -> bool {
+    let name = name.replace('\\', "/");
+    let depth = Path::new(&name)
+        .components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .count();
+    let target = target.replace('\\', "/");
+    let mut parents = 0;
+    let mut named = false;
+    for c in Path::new(&target).components() {
+        match c {
+            Component::Normal(_) => named = true,
+            Component::CurDir => {}
+            // after a name, ".." may go up from where a link of it points to
+            Component::ParentDir if !named => parents += 1,
+            _ => return false,
+        }
+    }
+    parents < depth
+}
+
+fn create_link(entry: &FileEntry, path: &Path) -> ResultType<()> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        bail!("{} already exists", path.display());
+    }
+    if !is_link_target_inside(&entry.name, &entry.link_target) {
+        bail!("{} points out of the destination", path.display());
+    }
+    // the separators of the peer, in relative targets
+    #[cfg(windows)]
+    let target = entry.link_target.replace('/', "\\");
+    #[cfg(not(windows))]
+    let target = entry.link_target.replace('\\', "/");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, path)?;
+    #[cfg(windows)]
+    if entry.entry_type.enum_value() == Ok(FileType::DirLink) {
+        std::os::windows::fs::symlink_dir(&target, path)?;
+    } else {
+        std::os::windows::fs::symlink_file(&target, path)?;
+    }
+    Ok(())
+}
+
 #[inline]
 pub fn get_file_name(p: &PathBuf) -> String {
     p.file_name()
@@ -121,15 +208,40 @@ pub fn get_home_as_string() -> String {
     get_string(&Config::get_home())
 }
 
+/// Where the files dropped on the remote view are written, the
+/// "file-drop-dir" option, or else the Desktop of the home.
+pub fn get_drop_dir() -> PathBuf {
+    let dir = Config::get_option("file-drop-dir");
+    if !dir.is_empty() {
+        return get_path(&dir);
+    }
+    let home = Config::get_home();
+    let desktop = home.join("Desktop");
+    if desktop.is_dir() {
+        desktop
+    } else {
+        home
+    }
+}
+
+/// The path in the drop folder of a dropped file, any folder of `name` is ignored.
+pub fn get_drop_path(name: &str) -> ResultType<String> {
+    let name = get_file_name(&get_path(&name.replace("\\", "/")));
+    if name.is_empty() {
+        bail!("Invalid name of the dropped file");
+    }
+    Ok(get_string(&get_drop_dir().join(name)))
+}
+
+// with the links themselves if `include_links`, not what they point to
 fn read_dir_recursive(
     path: &PathBuf,
     prefix: &PathBuf,
     include_hidden: bool,
+    include_links: bool,
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
     if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
         let fd = read_dir(&path, include_hidden)?;
         for entry in fd.entries.iter() {
             match entry.entry_type.enum_value() {
@@ -143,18 +255,27 @@ fn read_dir_recursive(
                         &path.join(&entry.name),
                         &prefix.join(&entry.name),
                         include_hidden,
+                        include_links,
                     ) {
                         for entry in tmp.drain(0..) {
                             files.push(entry);
                         }
                     }
                 }
+                Ok(FileType::FileLink) | Ok(FileType::DirLink) if include_links => {
+                    if let Ok(target) = std::fs::read_link(path.join(&entry.name)) {
+                        let mut entry = entry.clone();
+                        entry.name = get_string(&prefix.join(entry.name));
+                        entry.link_target = get_string(&target);
+                        files.push(entry);
+                    }
+                }
                 _ => {}
             }
         }
         Ok(files)
     } else if path.is_file() {
-        let (size, modified_time) = if let Ok(meta) = std::fs::metadata(&path) {
+        let (size, modified_time, mode) = if let Ok(meta) = std::fs::metadata(&path) {
             (
                 meta.len(),
                 meta.modified()
@@ -164,14 +285,16 @@ fn read_dir_recursive(
                             .unwrap_or(0)
                     })
                     .unwrap_or(0) as u64,
+                get_mode(&meta),
             )
         } else {
-            (0, 0)
+            (0, 0, 0)
         };
         files.push(FileEntry {
             entry_type: FileType::File.into(),
             size,
             modified_time,
+            mode,
             ..Default::default()
         });
         Ok(files)
@@ -181,9 +304,66 @@ fn read_dir_recursive(
 }
 
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    read_dir_recursive(&get_path(path), &get_path(""), include_hidden, false)
+}
+
+fn get_file_sha256(path: &Path) -> ResultType<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = sha256::State::new();
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().0.to_vec())
 }
 
+/// The sha256 of the files of get_recursive_files(path).
+pub fn hash_files(path: &str, files: &mut Vec<FileEntry>) {
+    let path = get_path(path);
+    for entry in files.iter_mut() {
+        let p = if entry.name.is_empty() {
+            path.clone()
+        } else {
+            path.join(&entry.name)
+        };
+        entry.sha256 = get_file_sha256(&p).unwrap_or_default().into();
+    }
+}
+
+/// The files of `src` not in `dst`, or not the same there, by their sha256
+/// if both have it, by their size and modified time otherwise.
+pub fn diff_files(src: &[FileEntry], dst: &[FileEntry]) -> Vec<FileEntry> {
+    // of peers of other platforms
+    let key = |name: &str| name.replace('\\', "/");
+    let dst: HashMap<String, &FileEntry> = dst.iter().map(|x| (key(&x.name), x)).collect();
+    src.iter()
+        .filter(|x| match dst.get(&key(&x.name)) {
+            None => true,
+            Some(y) => {
+                if !x.sha256.is_empty() && !y.sha256.is_empty() {
+                    x.sha256 != y.sha256
+                } else {
+                    x.size != y.size || x.modified_time != y.modified_time
+                }
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+const BUF_SIZE: usize = 128 * 1024;
+// the writer syncs the file and saves a checkpoint every so often
+const CHECKPOINT_SIZE: u64 = 64 * BUF_SIZE as u64;
+// smaller files are sent whole rather than as a delta to the writer's copy
+const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+const DELTA_BLOCK_SIZE: u64 = 64 * 1024;
+// larger blocks for huge files, not to have too many signatures
+const DELTA_MAX_BLOCKS: u64 = 64 * 1024;
+
 #[derive(Default)]
 pub struct TransferJob {
     id: i32,
@@ -194,6 +374,163 @@ pub struct TransferJob {
     total_size: u64,
     finished_size: u64,
     transferred: u64,
+    // reader: a FileTransferDigest is sent before each file, and the blocks
+    // only after the writer confirmed where to start from
+    enable_resume: bool,
+    resume_file: Option<(i32, bool)>,
+    offset: u64,
+    compress_level: i32,
+    // of the current file, sent with its last block
+    hasher: Option<sha256::State>,
+    delta: Option<Delta>,
+    // writer
+    checkpoint: Option<Checkpoint>,
+    resume_offset: Option<u64>,
+    // its copy of the file before, and the size of the blocks of a delta transfer
+    delta_source: Option<(File, u64)>,
+}
+
+/// What the writer has of a file for sure, saved next to its download file.
+#[derive(Default)]
+struct Checkpoint {
+    offset: u64,
+    // chained over the blocks, sha256(digest | block)
+    digest: Vec<u8>,
+    // of the file read, not to resume from another version of it
+    last_modified: u64,
+    file_size: u64,
+}
+
+impl Checkpoint {
+    #[inline]
+    fn path(download_path: &str) -> String {
+        format!("{}.checkpoint", download_path)
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let mut it = s.split_whitespace();
+        Some(Self {
+            offset: it.next()?.parse().ok()?,
+            digest: base64::decode(it.next()?).ok()?,
+            last_modified: it.next()?.parse().ok()?,
+            file_size: it.next()?.parse().ok()?,
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.offset,
+            base64::encode(&self.digest),
+            self.last_modified,
+            self.file_size
+        )
+    }
+}
+
+fn chain_digest(digest: &[u8], block: &[u8]) -> Vec<u8> {
+    let mut state = sha256::State::new();
+    state.update(digest);
+    state.update(block);
+    state.finalize().0.to_vec()
+}
+
+// rsync's, the sums of the bytes and of the sums, mod 2^16
+fn weak_checksum(data: &[u8]) -> (u32, u32) {
+    let n = data.len() as u32;
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    for (i, x) in data.iter().enumerate() {
+        a = a.wrapping_add(*x as u32);
+        b = b.wrapping_add((n - i as u32).wrapping_mul(*x as u32));
+    }
+    (a & 0xffff, b & 0xffff)
+}
+
+#[inline]
+fn strong_checksum(data: &[u8]) -> Vec<u8> {
+    sha256::hash(data).0[..16].to_vec()
+}
+
+/// The signatures of the whole blocks of the file the writer has.
+fn get_signatures(path: &Path) -> ResultType<(u64, Vec<BlockSignature>)> {
+    let size = std::fs::metadata(path)?.len();
+    let block_size = DELTA_BLOCK_SIZE.max((size + DELTA_MAX_BLOCKS - 1) / DELTA_MAX_BLOCKS);
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; block_size as usize];
+    let mut signatures = Vec::new();
+    for _ in 0..size / block_size {
+        std::io::Read::read_exact(&mut file, &mut buf)?;
+        let (a, b) = weak_checksum(&buf);
+        signatures.push(BlockSignature {
+            weak: a | b << 16,
+            strong: strong_checksum(&buf),
+            ..Default::default()
+        });
+    }
+    Ok((block_size, signatures))
+}
+
+// The reader's side of a delta transfer: a window of the block size rolls
+// over the file, and where it matches a block of the writer, the index of
+// the block is sent instead of the data.
+struct Delta {
+    block_size: usize,
+    signatures: HashMap<u32, Vec<(u32, Vec<u8>)>>,
+    // the literal data not sent yet, then the window from `pos`
+    buf: Vec<u8>,
+    pos: usize,
+    sums: Option<(u32, u32)>,
+    eof: bool,
+}
+
+impl Delta {
+    fn new(block_size: usize, signatures: &[BlockSignature]) -> Self {
+        let mut map: HashMap<u32, Vec<(u32, Vec<u8>)>> = HashMap::new();
+        for (i, s) in signatures.iter().enumerate() {
+            map.entry(s.weak)
+                .or_default()
+                .push((i as u32, s.strong.clone()));
+        }
+        Self {
+            block_size,
+            signatures: map,
+            buf: Vec::new(),
+            pos: 0,
+            sums: None,
+            eof: false,
+        }
+    }
+
+    fn find(&mut self) -> Option<u32> {
+        let window = &self.buf[self.pos..self.pos + self.block_size];
+        let (a, b) = *self.sums.get_or_insert_with(|| weak_checksum(window));
+        let candidates = self.signatures.get(&(a | b << 16))?;
+        let strong = strong_checksum(window);
+        candidates
+            .iter()
+            .find(|(_, s)| *s == strong)
+            .map(|(i, _)| *i)
+    }
+
+    fn roll(&mut self) {
+        let out = self.buf[self.pos] as u32;
+        let inp = self.buf[self.pos + self.block_size] as u32;
+        if let Some((a, b)) = self.sums.as_mut() {
+            *a = a.wrapping_sub(out).wrapping_add(inp) & 0xffff;
+            *b = b
+                .wrapping_sub((self.block_size as u32).wrapping_mul(out))
+                .wrapping_add(*a)
+                & 0xffff;
+        }
+        self.pos += 1;
+    }
+}
+
+/// Peers from 1.1.9 answer the FileTransferDigest of each file.
+#[inline]
+pub fn can_resume(version: i64) -> bool {
+    version >= crate::get_version_number("1.1.9")
 }
 
 #[inline]
@@ -206,52 +543,131 @@ fn get_ext(name: &str) -> &str {
 
 #[inline]
 fn is_compressed_file(name: &str) -> bool {
-    let ext = get_ext(name);
-    ext == "xz"
-        || ext == "gz"
-        || ext == "zip"
-        || ext == "7z"
-        || ext == "rar"
-        || ext == "bz2"
-        || ext == "tgz"
-        || ext == "png"
-        || ext == "jpg"
+    match get_ext(name).to_lowercase().as_str() {
+        "xz" | "gz" | "zip" | "7z" | "rar" | "bz2" | "tgz" | "zst" | "lz4" | "png" | "jpg"
+        | "jpeg" | "gif" | "webp" | "mp3" | "mp4" | "mkv" | "avi" | "mov" | "webm" => true,
+        _ => false,
+    }
+}
+
+/// The zstd level of the file blocks read here, from 1 to 22,
+/// or 0 for none, with the "file-compress-level" option.
+pub fn get_compress_level() -> i32 {
+    let v = Config::get_option("file-compress-level");
+    if v.is_empty() {
+        return COMPRESS_LEVEL;
+    }
+    v.parse::<i32>().unwrap_or(COMPRESS_LEVEL).max(0).min(22)
 }
 
 impl TransferJob {
     pub fn new_write(id: i32, path: String, files: Vec<FileEntry>) -> Self {
         let total_size = files.iter().map(|x| x.size as u64).sum();
-        Self {
+        let job = Self {
             id,
             path: get_path(&path),
             files,
             total_size,
             ..Default::default()
-        }
+        };
+        job
     }
 
     pub fn new_read(id: i32, path: String, include_hidden: bool) -> ResultType<Self> {
-        let files = get_recursive_files(&path, include_hidden)?;
+        // the writers not knowing links never get a block of them
+        let files = read_dir_recursive(&get_path(&path), &get_path(""), include_hidden, true)?;
         let total_size = files.iter().map(|x| x.size as u64).sum();
         Ok(Self {
             id,
             path: get_path(&path),
             files,
             total_size,
+            compress_level: get_compress_level(),
             ..Default::default()
         })
     }
 
+    #[inline]
+    pub fn set_resume(&mut self, enable: bool) {
+        self.enable_resume = enable;
+    }
+
+    /// The level the writer asked for in its FileTransferSendRequest,
+    /// -1 for none, 0 to keep ours.
+    #[inline]
+    pub fn set_compress_level(&mut self, level: i32) {
+        if level != 0 {
+            self.compress_level = level.max(0).min(22);
+        }
+    }
+
     #[inline]
     pub fn files(&self) -> &Vec<FileEntry> {
         &self.files
     }
 
+    /// Only these of the files are read, e.g. the ones differing in a sync.
+    pub fn retain_files(&mut self, names: &[String]) {
+        let names: HashSet<&String> = names.iter().collect();
+        self.files.retain(|x| names.contains(&x.name));
+        self.total_size = self.files.iter().map(|x| x.size as u64).sum();
+    }
+
     #[inline]
     pub fn set_files(&mut self, files: Vec<FileEntry>) {
         self.files = files;
     }
 
+    /// The writer's end of the job, once the reader is done.
+    pub fn write_done(&mut self) {
+        self.modify_time();
+        self.create_links();
+    }
+
+    // The reader does not send anything of the links, they are only made with
+    // the "enable-symlinks" option, after every file so that none is written
+    // through them, and not within another link.
+    fn create_links(&self) {
+        if Config::get_option("enable-symlinks") != "Y" {
+            return;
+        }
+        for entry in self.files.iter().filter(|x| is_link(x)) {
+            let path = self.join(&entry.name);
+            if self.is_in_link(&path) {
+                log::error!("Not to create link {} within a link", path.display());
+                continue;
+            }
+            if let Some(p) = path.parent() {
+                std::fs::create_dir_all(p).ok();
+            }
+            if let Err(err) = create_link(entry, &path) {
+                log::error!("Failed to create link {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    // a directory of `path` below the root of the job is a link
+    fn is_in_link(&self, path: &Path) -> bool {
+        path.ancestors()
+            .skip(1)
+            .take_while(|x| *x != self.path && x.starts_with(&self.path))
+            .any(|x| {
+                std::fs::symlink_metadata(x)
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false)
+            })
+    }
+
+    // the links have no content
+    fn skip_links(&mut self) {
+        while let Some(entry) = self.files.get(self.file_num as usize) {
+            if !is_link(entry) {
+                break;
+            }
+            self.file_num += 1;
+        }
+    }
+
     #[inline]
     pub fn id(&self) -> i32 {
         self.id
@@ -277,18 +693,24 @@ impl TransferJob {
         self.file_num
     }
 
-    pub fn modify_time(&self) {
+    pub fn modify_time(&mut self) {
+        // not to have it open when replaced
+        self.delta_source = None;
         let file_num = self.file_num as usize;
         if file_num < self.files.len() {
             let entry = &self.files[file_num];
             let path = self.join(&entry.name);
             let download_path = format!("{}.download", get_string(&path));
             std::fs::rename(&download_path, &path).ok();
+            std::fs::remove_file(Checkpoint::path(&download_path)).ok();
             filetime::set_file_mtime(
                 &path,
                 filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
             )
             .ok();
+            if entry.mode != 0 {
+                crate::allow_err!(set_mode(&path, entry.mode));
+            }
         }
     }
 
@@ -299,7 +721,156 @@ impl TransferJob {
             let path = self.join(&entry.name);
             let download_path = format!("{}.download", get_string(&path));
             std::fs::remove_file(&download_path).ok();
+            std::fs::remove_file(Checkpoint::path(&download_path)).ok();
+        }
+    }
+
+    /// The writer's answer to the FileTransferDigest of a file, from its
+    /// last checkpoint if it has one of the same file. It is always sent,
+    /// not to leave the reader waiting, and the errors come with the blocks.
+    pub async fn handle_digest(&mut self, d: FileTransferDigest) -> Message {
+        let mut delta = None;
+        match self.open_file(&d).await {
+            Ok(res) => delta = res,
+            Err(err) => {
+                log::error!(
+                    "Failed to resume file {} of job {}: {}",
+                    d.file_num,
+                    d.id,
+                    err
+                );
+                self.file = None;
+                self.checkpoint = None;
+                self.resume_offset = None;
+                self.delta_source = None;
+            }
+        }
+        let (offset, digest) = match self.checkpoint.as_ref() {
+            Some(checkpoint) => (checkpoint.offset, checkpoint.digest.clone()),
+            None => (0, Vec::new()),
+        };
+        let (block_size, signatures) = delta.unwrap_or_default();
+        let mut action = FileAction::new();
+        action.set_send_confirm(FileTransferSendConfirmRequest {
+            id: d.id,
+            file_num: d.file_num,
+            offset,
+            digest,
+            block_size: block_size as _,
+            signatures: signatures.into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_file_action(action);
+        msg_out
+    }
+
+    // the block size and the signatures, if a delta transfer
+    async fn open_file(
+        &mut self,
+        d: &FileTransferDigest,
+    ) -> ResultType<Option<(u64, Vec<BlockSignature>)>> {
+        if d.id != self.id {
+            bail!("Wrong id");
+        }
+        let file_num = d.file_num as usize;
+        if file_num >= self.files.len() {
+            bail!("Wrong file number");
+        }
+        if self.file.is_some() {
+            self.modify_time();
+        }
+        if let Some(file) = self.file.take() {
+            file.sync_all().await?;
+        }
+        self.delta_source = None;
+        self.file_num = d.file_num;
+        let path = self.join(&self.files[file_num].name);
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p).ok();
         }
+        let download_path = format!("{}.download", get_string(&path));
+        let len = std::fs::metadata(&download_path)
+            .map(|m| m.len())
+            .unwrap_or(0);
+        let checkpoint = std::fs::read_to_string(Checkpoint::path(&download_path))
+            .ok()
+            .and_then(|s| Checkpoint::parse(&s))
+            .filter(|c| {
+                c.last_modified == d.last_modified
+                    && c.file_size == d.file_size
+                    && c.offset > 0
+                    && c.offset <= len
+            })
+            .unwrap_or(Checkpoint {
+                last_modified: d.last_modified,
+                file_size: d.file_size,
+                ..Default::default()
+            });
+        let file = if checkpoint.offset > 0 {
+            log::info!(
+                "Resume writing {} from {} of {} bytes",
+                download_path,
+                checkpoint.offset,
+                checkpoint.file_size
+            );
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&download_path)
+                .await?;
+            // what came after the checkpoint may not be on the disk
+            file.set_len(checkpoint.offset).await?;
+            file.seek(SeekFrom::Start(checkpoint.offset)).await?;
+            self.finished_size += checkpoint.offset;
+            self.resume_offset = Some(checkpoint.offset);
+            file
+        } else {
+            self.resume_offset = None;
+            File::create(&download_path).await?
+        };
+        self.file = Some(file);
+        let old_len = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if checkpoint.offset == 0
+            && path.is_file()
+            && old_len >= DELTA_MIN_SIZE
+            && d.file_size >= DELTA_MIN_SIZE
+        {
+            let (block_size, signatures) = get_signatures(&path)?;
+            log::info!(
+                "Delta writing {} with {} blocks of {} bytes",
+                download_path,
+                signatures.len(),
+                block_size
+            );
+            self.delta_source = Some((File::open(&path).await?, block_size));
+            // the blocks of a delta are not the ones of the digest
+            self.checkpoint = None;
+            return Ok(Some((block_size, signatures)));
+        }
+        self.checkpoint = Some(checkpoint);
+        Ok(None)
+    }
+
+    async fn update_checkpoint(&mut self, data: &[u8]) -> ResultType<()> {
+        if self.checkpoint.is_none() {
+            return Ok(());
+        }
+        let path = self.join(&self.files[self.file_num as usize].name);
+        let download_path = format!("{}.download", get_string(&path));
+        let checkpoint = match self.checkpoint.as_mut() {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+        let before = checkpoint.offset;
+        checkpoint.offset += data.len() as u64;
+        checkpoint.digest = chain_digest(&checkpoint.digest, data);
+        if checkpoint.offset / CHECKPOINT_SIZE != before / CHECKPOINT_SIZE {
+            if let Some(file) = self.file.as_mut() {
+                file.sync_data().await?;
+            }
+            std::fs::write(Checkpoint::path(&download_path), checkpoint.format())?;
+        }
+        Ok(())
     }
 
     pub async fn write(&mut self, block: FileTransferBlock) -> ResultType<()> {
@@ -311,7 +882,10 @@ impl TransferJob {
             bail!("Wrong file number");
         }
         if file_num != self.file_num as usize || self.file.is_none() {
-            self.modify_time();
+            // a leftover download file of the first one is not finished
+            if self.file.is_some() {
+                self.modify_time();
+            }
             if let Some(file) = self.file.as_mut() {
                 file.sync_all().await?;
             }
@@ -323,39 +897,192 @@ impl TransferJob {
             }
             let path = format!("{}.download", get_string(&path));
             self.file = Some(File::create(&path).await?);
+            self.checkpoint = None;
+            self.resume_offset = None;
+            self.delta_source = None;
         }
-        if block.compressed {
-            let tmp = decompress(&block.data);
-            self.file.as_mut().unwrap().write_all(&tmp).await?;
-            self.finished_size += tmp.len() as u64;
-        } else {
-            self.file.as_mut().unwrap().write_all(&block.data).await?;
-            self.finished_size += block.data.len() as u64;
+        if let Some(offset) = self.resume_offset.take() {
+            if block.offset != offset {
+                // the file read is not the same as the one written so far
+                let file = self.file.as_mut().unwrap();
+                file.set_len(0).await?;
+                file.seek(SeekFrom::Start(0)).await?;
+                self.finished_size -= offset;
+                if let Some(checkpoint) = self.checkpoint.as_mut() {
+                    checkpoint.offset = 0;
+                    checkpoint.digest.clear();
+                }
+            }
         }
         self.transferred += block.data.len() as u64;
+        let data = if block.compressed {
+            decompress(&block.data)
+        } else {
+            block.data
+        };
+        self.file.as_mut().unwrap().write_all(&data).await?;
+        self.finished_size += data.len() as u64;
+        if !block.copy_blocks.is_empty() {
+            let (source, block_size) = match self.delta_source.as_mut() {
+                Some(source) => source,
+                None => bail!("No file to copy blocks from"),
+            };
+            let mut buf = vec![0u8; *block_size as usize];
+            for i in block.copy_blocks.iter() {
+                source
+                    .seek(SeekFrom::Start(*i as u64 * *block_size))
+                    .await?;
+                source.read_exact(&mut buf).await?;
+                self.file.as_mut().unwrap().write_all(&buf).await?;
+                self.finished_size += *block_size;
+            }
+        }
+        if !block.sha256.is_empty() {
+            return self.verify(&block.sha256).await;
+        }
+        self.update_checkpoint(&data).await
+    }
+
+    // the sha256 of the download file against the reader's, after its last block
+    async fn verify(&mut self, sha256: &[u8]) -> ResultType<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        file.flush().await?;
+        let path = self.join(&self.files[self.file_num as usize].name);
+        let download_path = format!("{}.download", get_string(&path));
+        if get_file_sha256(Path::new(&download_path))? != sha256 {
+            // not to take the place of the file when done
+            self.file = None;
+            self.remove_download_file();
+            bail!("Checksum mismatch of {}", get_string(&path));
+        }
         Ok(())
     }
 
+    // the names of the peer stay within the root, no "..", no absolute path
     #[inline]
     fn join(&self, name: &str) -> PathBuf {
-        if name.is_empty() {
-            self.path.clone()
-        } else {
-            self.path.join(name)
+        let mut path = self.path.clone();
+        for c in Path::new(name).components() {
+            if let Component::Normal(c) = c {
+                path.push(c);
+            }
+        }
+        path
+    }
+
+    /// The reader's FileTransferDigest of the current file, sent once.
+    pub fn next_digest(&mut self) -> Option<Message> {
+        if self.file.is_none() {
+            self.skip_links();
+        }
+        if !self.enable_resume {
+            return None;
+        }
+        let entry = self.files.get(self.file_num as usize)?;
+        if let Some((file_num, _)) = self.resume_file {
+            if file_num == self.file_num {
+                return None;
+            }
+        }
+        self.resume_file = Some((self.file_num, false));
+        let mut resp = FileResponse::new();
+        resp.set_digest(FileTransferDigest {
+            id: self.id,
+            file_num: self.file_num,
+            last_modified: entry.modified_time,
+            file_size: entry.size,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_file_response(resp);
+        Some(msg_out)
+    }
+
+    #[inline]
+    pub fn is_waiting_confirm(&self) -> bool {
+        self.resume_file == Some((self.file_num, false))
+    }
+
+    /// The reader continues from the offset of the writer if the digest of
+    /// what is before it is the same here, from the start otherwise.
+    pub async fn confirm(&mut self, r: &FileTransferSendConfirmRequest) -> ResultType<()> {
+        if r.id != self.id || self.resume_file != Some((r.file_num, false)) {
+            return Ok(());
+        }
+        self.resume_file = Some((r.file_num, true));
+        let entry = &self.files[r.file_num as usize];
+        let size = entry.size;
+        let mut file = match File::open(self.join(&entry.name)).await {
+            Ok(file) => file,
+            Err(err) => {
+                self.file_num += 1;
+                return Err(err.into());
+            }
+        };
+        self.offset = 0;
+        self.delta = None;
+        let mut hasher = sha256::State::new();
+        if r.block_size > 0 {
+            log::info!(
+                "Delta reading file {} against {} blocks",
+                r.file_num,
+                r.signatures.len()
+            );
+            self.delta = Some(Delta::new(r.block_size as _, &r.signatures));
+        } else if r.offset > 0 && r.offset <= size {
+            let mut digest = Vec::new();
+            let mut buf = vec![0u8; BUF_SIZE];
+            while self.offset < r.offset {
+                let n = ((r.offset - self.offset) as usize).min(BUF_SIZE);
+                file.read_exact(&mut buf[..n]).await?;
+                digest = chain_digest(&digest, &buf[..n]);
+                hasher.update(&buf[..n]);
+                self.offset += n as u64;
+            }
+            if digest == r.digest {
+                log::info!("Resume reading file {} from {}", r.file_num, r.offset);
+                self.finished_size += r.offset;
+            } else {
+                file.seek(SeekFrom::Start(0)).await?;
+                self.offset = 0;
+                hasher = sha256::State::new();
+            }
         }
+        self.file = Some(file);
+        self.hasher = Some(hasher);
+        Ok(())
     }
 
     pub async fn read(&mut self) -> ResultType<Option<FileTransferBlock>> {
+        if self.file.is_none() {
+            self.skip_links();
+        }
         let file_num = self.file_num as usize;
         if file_num >= self.files.len() {
             self.file.take();
             return Ok(None);
         }
+        if self.delta.is_some() && self.file.is_some() {
+            return match self.read_delta().await {
+                Ok(block) => Ok(Some(block)),
+                Err(err) => {
+                    self.file_num += 1;
+                    self.file = None;
+                    self.delta = None;
+                    Err(err)
+                }
+            };
+        }
         let name = &self.files[file_num].name;
         if self.file.is_none() {
             match File::open(self.join(&name)).await {
                 Ok(file) => {
                     self.file = Some(file);
+                    self.offset = 0;
+                    self.hasher = Some(sha256::State::new());
                 }
                 Err(err) => {
                     self.file_num += 1;
@@ -363,7 +1090,6 @@ impl TransferJob {
                 }
             }
         }
-        const BUF_SIZE: usize = 128 * 1024;
         let mut buf: Vec<u8> = Vec::with_capacity(BUF_SIZE);
         unsafe {
             buf.set_len(BUF_SIZE);
@@ -386,13 +1112,22 @@ impl TransferJob {
             }
         }
         unsafe { buf.set_len(offset) };
+        let block_offset = self.offset;
+        self.offset += offset as u64;
+        let mut sha256 = Vec::new();
         if offset == 0 {
             self.file_num += 1;
             self.file = None;
+            if let Some(hasher) = self.hasher.take() {
+                sha256 = hasher.finalize().0.to_vec();
+            }
         } else {
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf);
+            }
             self.finished_size += offset as u64;
-            if !is_compressed_file(name) {
-                let tmp = compress(&buf, COMPRESS_LEVEL);
+            if self.compress_level > 0 && !is_compressed_file(name) {
+                let tmp = compress(&buf, self.compress_level);
                 if tmp.len() < buf.len() {
                     buf = tmp;
                     compressed = true;
@@ -405,9 +1140,109 @@ impl TransferJob {
             file_num: file_num as _,
             data: buf.into(),
             compressed,
+            offset: block_offset,
+            sha256: sha256.into(),
             ..Default::default()
         }))
     }
+
+    // up to a block of literal data, or the data before a match and the
+    // matches right after it
+    async fn read_delta(&mut self) -> ResultType<FileTransferBlock> {
+        const MAX_COPIES: usize = 256;
+        let file_num = self.file_num;
+        let file = self.file.as_mut().unwrap();
+        let delta = self.delta.as_mut().unwrap();
+        let block_size = delta.block_size;
+        let mut literal = None;
+        let mut copies = Vec::new();
+        let mut done = false;
+        loop {
+            // the window and the byte after it
+            while !delta.eof && delta.buf.len() <= delta.pos + block_size {
+                let start = delta.buf.len();
+                delta.buf.resize(start + BUF_SIZE, 0);
+                let n = file.read(&mut delta.buf[start..]).await?;
+                delta.buf.truncate(start + n);
+                if let Some(hasher) = self.hasher.as_mut() {
+                    hasher.update(&delta.buf[start..]);
+                }
+                delta.eof = n == 0;
+            }
+            if delta.buf.len() < delta.pos + block_size {
+                // the rest comes after the copies, in the next block
+                done = copies.is_empty();
+                break;
+            }
+            if let Some(i) = delta.find() {
+                if literal.is_none() {
+                    literal = Some(delta.buf.drain(..delta.pos).collect::<Vec<u8>>());
+                }
+                delta.buf.drain(..block_size);
+                delta.pos = 0;
+                delta.sums = None;
+                copies.push(i);
+                if copies.len() < MAX_COPIES {
+                    continue;
+                }
+                break;
+            }
+            if !copies.is_empty() {
+                break;
+            }
+            if delta.pos >= BUF_SIZE {
+                // the window stays, only the data before it is sent
+                literal = Some(delta.buf.drain(..delta.pos).collect());
+                delta.pos = 0;
+                break;
+            }
+            if delta.buf.len() == delta.pos + block_size {
+                done = true;
+                break;
+            }
+            delta.roll();
+        }
+        let literal = match literal {
+            Some(literal) => literal,
+            None => std::mem::take(&mut delta.buf),
+        };
+        let block_offset = self.offset;
+        let size = literal.len() as u64 + (copies.len() * block_size) as u64;
+        self.offset += size;
+        self.finished_size += size;
+        let mut sha256 = Vec::new();
+        if done {
+            self.file_num += 1;
+            self.file = None;
+            self.delta = None;
+            if let Some(hasher) = self.hasher.take() {
+                sha256 = hasher.finalize().0.to_vec();
+            }
+        }
+        let mut data = literal;
+        let mut compressed = false;
+        if !data.is_empty()
+            && self.compress_level > 0
+            && !is_compressed_file(&self.files[file_num as usize].name)
+        {
+            let tmp = compress(&data, self.compress_level);
+            if tmp.len() < data.len() {
+                data = tmp;
+                compressed = true;
+            }
+        }
+        self.transferred += data.len() as u64;
+        Ok(FileTransferBlock {
+            id: self.id,
+            file_num,
+            data: data.into(),
+            compressed,
+            offset: block_offset,
+            copy_blocks: copies.into(),
+            sha256: sha256.into(),
+            ..Default::default()
+        })
+    }
 }
 
 #[inline]
@@ -461,12 +1296,19 @@ pub fn new_receive(id: i32, path: String, files: Vec<FileEntry>) -> Message {
 }
 
 #[inline]
-pub fn new_send(id: i32, path: String, include_hidden: bool) -> Message {
+pub fn new_send(id: i32, path: String, include_hidden: bool, files: Vec<String>) -> Message {
     let mut action = FileAction::new();
     action.set_send(FileTransferSendRequest {
         id,
         path,
         include_hidden,
+        files: files.into(),
+        // the writer here answers the digests
+        resume: true,
+        compress_level: match get_compress_level() {
+            0 => -1,
+            level => level,
+        },
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -503,6 +1345,13 @@ pub async fn handle_read_jobs(
 ) -> ResultType<()> {
     let mut finished = Vec::new();
     for job in jobs.iter_mut() {
+        if let Some(msg) = job.next_digest() {
+            stream.send(&msg).await?;
+            continue;
+        }
+        if job.is_waiting_confirm() {
+            continue;
+        }
         match job.read().await {
             Err(err) => {
                 stream
@@ -524,6 +1373,151 @@ pub async fn handle_read_jobs(
     Ok(())
 }
 
+const SEARCH_MAX_RESULTS: usize = 1000;
+// of each tick, not to hold the transfers back
+const SEARCH_DIRS_PER_TICK: usize = 16;
+// of the pattern of the peer, the rest ignored
+const SEARCH_MAX_PATTERN: usize = 256;
+
+/// The files and folders under `path` whose names match `pattern`,
+/// a glob with `*` and `?`, or a part of the names if it has neither.
+pub struct SearchJob {
+    id: i32,
+    path: PathBuf,
+    pattern: Vec<char>,
+    include_hidden: bool,
+    max_results: usize,
+    found: usize,
+    // relative to `path`, to look in
+    dirs: Vec<PathBuf>,
+}
+
+impl SearchJob {
+    pub fn new(s: FileSearch) -> Self {
+        let mut pattern = s.pattern.trim().to_lowercase();
+        if !pattern.contains(&['*', '?'][..]) {
+            pattern = format!("*{}*", pattern);
+        }
+        let max_results = if s.max_results > 0 {
+            (s.max_results as usize).min(SEARCH_MAX_RESULTS)
+        } else {
+            SEARCH_MAX_RESULTS
+        };
+        Self {
+            id: s.id,
+            path: get_path(&s.path),
+            pattern: pattern.chars().take(SEARCH_MAX_PATTERN).collect(),
+            include_hidden: s.include_hidden,
+            max_results,
+            found: 0,
+            dirs: vec![PathBuf::new()],
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    // what is found in the next folders, and if there is nothing more
+    fn next(&mut self) -> (Vec<FileEntry>, bool) {
+        let mut entries = Vec::new();
+        for _ in 0..SEARCH_DIRS_PER_TICK {
+            let rel = match self.dirs.pop() {
+                Some(rel) => rel,
+                None => break,
+            };
+            let fd = match read_dir(&self.path.join(&rel), self.include_hidden) {
+                Ok(fd) => fd,
+                Err(_) => continue,
+            };
+            for entry in fd.entries.iter() {
+                let name = rel.join(&entry.name);
+                // not in the links, not to go round in circles
+                if entry.entry_type.enum_value() == Ok(FileType::Dir) {
+                    self.dirs.push(name.clone());
+                }
+                let lower: Vec<char> = entry.name.to_lowercase().chars().collect();
+                if !glob_match(&self.pattern, &lower) {
+                    continue;
+                }
+                let mut entry = entry.clone();
+                entry.name = get_string(&name);
+                entries.push(entry);
+                self.found += 1;
+                if self.found >= self.max_results {
+                    return (entries, true);
+                }
+            }
+        }
+        let done = self.dirs.is_empty();
+        (entries, done)
+    }
+}
+
+/// `*` for any characters and `?` for one, in O(pattern * name) at worst:
+/// only the last `*` is backtracked to, the ones before can not match more.
+pub fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the pattern after the last `*`, and the name it is tried at
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp;
+                    n = sn + 1;
+                    star = Some((sp, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[inline]
+pub fn new_search_result(id: i32, entries: Vec<FileEntry>, done: bool) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_search_result(FileSearchResult {
+        id,
+        entries: entries.into(),
+        done,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
+pub async fn handle_search_jobs(
+    jobs: &mut Vec<SearchJob>,
+    stream: &mut crate::Stream,
+) -> ResultType<()> {
+    let mut finished = Vec::new();
+    for job in jobs.iter_mut() {
+        let (entries, done) = job.next();
+        if !entries.is_empty() || done {
+            stream
+                .send(&new_search_result(job.id, entries, done))
+                .await?;
+        }
+        if done {
+            finished.push(job.id);
+        }
+    }
+    jobs.retain(|x| !finished.contains(&x.id));
+    Ok(())
+}
+
 pub fn remove_all_empty_dir(path: &PathBuf) -> ResultType<()> {
     let fd = read_dir(path, true)?;
     for entry in fd.entries.iter() {
@@ -552,3 +1546,102 @@ pub fn create_dir(dir: &str) -> ResultType<()> {
     std::fs::create_dir_all(get_path(dir))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let block_size = 100;
+        let signatures = vec![BlockSignature {
+            weak: {
+                let (a, b) = weak_checksum(&data[500..600]);
+                a | b << 16
+            },
+            strong: strong_checksum(&data[500..600]),
+            ..Default::default()
+        }];
+        let mut delta = Delta::new(block_size, &signatures);
+        delta.buf = data.clone();
+        while delta.find().is_none() {
+            assert_eq!(
+                delta.sums,
+                Some(weak_checksum(&data[delta.pos..delta.pos + block_size]))
+            );
+            delta.roll();
+        }
+        assert_eq!(delta.pos, 500);
+    }
+
+    #[test]
+    fn test_link_target() {
+        assert!(is_link_target_inside("l", "a/b"));
+        assert!(is_link_target_inside("d/l", "../a"));
+        assert!(is_link_target_inside("d/l", "./b/c"));
+        assert!(!is_link_target_inside("d/l", "b/../c"));
+        assert!(!is_link_target_inside("l", "../a"));
+        assert!(!is_link_target_inside("d/l", "../../a"));
+        assert!(!is_link_target_inside("d/l", "b/../../.."));
+        assert!(!is_link_target_inside("l", "/etc/passwd"));
+        assert!(!is_link_target_inside("d\\l", "..\\..\\a"));
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let entry = |name: &str, size: u64, sha256: &[u8]| FileEntry {
+            name: name.to_owned(),
+            size,
+            modified_time: 1,
+            sha256: sha256.to_vec(),
+            ..Default::default()
+        };
+        let src = vec![
+            entry("a", 1, b""),
+            entry("b/c", 2, b""),
+            entry("d", 3, b"x"),
+            entry("e", 4, b""),
+        ];
+        let dst = vec![
+            entry("a", 1, b""),
+            entry("b\\c", 3, b""),
+            entry("d", 4, b"x"),
+        ];
+        let names: Vec<String> = diff_files(&src, &dst).drain(..).map(|x| x.name).collect();
+        assert_eq!(names, vec!["b/c", "e"]);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let m = |p: &str, n: &str| {
+            glob_match(
+                &p.chars().collect::<Vec<_>>(),
+                &n.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(m("*.rs", "main.rs"));
+        assert!(!m("*.rs", "main.rsx"));
+        assert!(m("ma?n*", "main.rs"));
+        assert!(m("*ai*", "main.rs"));
+        assert!(!m("?", ""));
+        assert!(m("*", ""));
+    }
+
+    #[test]
+    fn test_glob_match_backtrack() {
+        let m = |p: &str, n: &str| {
+            glob_match(
+                &p.chars().collect::<Vec<_>>(),
+                &n.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(m("a*b*c", "aXbYbZc"));
+        assert!(!m("a*b*c", "aXbYbZ"));
+        assert!(m("*?", "x"));
+        // exponential with the recursion
+        let name = "a".repeat(200);
+        assert!(!m(&format!("{}b", "*a".repeat(50)), &name));
+        assert!(m(&"*a".repeat(50), &name));
+    }
+}
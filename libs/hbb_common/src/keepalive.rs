@@ -0,0 +1,145 @@
+// The pings of the protocol on a stream, TestDelay messages echoed by the peer,
+// for the round trip time and to tell a dead peer long before TCP does: the
+// peer is taken as gone once `max_missed` pings in a row are unanswered with
+// nothing else received meanwhile. The interval and the pings missed are the
+// options "keepalive-interval" (ms) and "keepalive-max-missed".
+use crate::config::Config;
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_INTERVAL: u64 = 3_000;
+const MIN_INTERVAL: u64 = 500;
+const DEFAULT_MAX_MISSED: u32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum Tick {
+    /// a ping to send, of this time in ms, to be echoed
+    Ping(i64),
+    /// the ping before not answered yet
+    Wait,
+    Dead,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    interval: Duration,
+    max_missed: u32,
+    pending: Option<i64>,
+    received: bool,
+    missed: u32,
+    srtt: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        let interval = Config::get_option("keepalive-interval")
+            .parse()
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(MIN_INTERVAL);
+        let max_missed = Config::get_option("keepalive-max-missed")
+            .parse()
+            .unwrap_or(DEFAULT_MAX_MISSED)
+            .max(1);
+        Self::new(Duration::from_millis(interval), max_missed)
+    }
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, max_missed: u32) -> Self {
+        Self {
+            interval,
+            max_missed,
+            pending: None,
+            received: false,
+            missed: 0,
+            srtt: None,
+        }
+    }
+
+    #[inline]
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Anything received from the peer.
+    #[inline]
+    pub fn on_recv(&mut self) {
+        self.received = true;
+    }
+
+    /// To be called every `interval`.
+    pub fn tick(&mut self) -> Tick {
+        if self.pending.is_some() && !self.received {
+            self.missed += 1;
+        } else {
+            self.missed = 0;
+        }
+        self.received = false;
+        if self.missed >= self.max_missed {
+            return Tick::Dead;
+        }
+        if self.pending.is_some() {
+            return Tick::Wait;
+        }
+        let time = get_time();
+        self.pending = Some(time);
+        Tick::Ping(time)
+    }
+
+    /// The echo of a ping, with its round trip time, None if not the one
+    /// pending.
+    pub fn on_pong(&mut self, time: i64) -> Option<Duration> {
+        if self.pending != Some(time) {
+            return None;
+        }
+        self.pending = None;
+        self.missed = 0;
+        let rtt = Duration::from_millis((get_time() - time).max(0) as _);
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        Some(rtt)
+    }
+
+    /// Smoothed as TCP does (RFC 6298).
+    #[inline]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    #[inline]
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+fn get_time() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keepalive() {
+        let mut k = Keepalive::new(Duration::from_secs(1), 2);
+        let time = match k.tick() {
+            Tick::Ping(time) => time,
+            x => panic!("{:?}", x),
+        };
+        assert!(k.on_pong(time - 1).is_none());
+        assert!(k.on_pong(time).is_some());
+        assert!(k.rtt().is_some());
+        assert!(matches!(k.tick(), Tick::Ping(_)));
+        // busy with other data, the pong delayed
+        k.on_recv();
+        assert_eq!(k.tick(), Tick::Wait);
+        assert_eq!(k.tick(), Tick::Wait);
+        assert_eq!(k.missed(), 1);
+        assert_eq!(k.tick(), Tick::Dead);
+    }
+}
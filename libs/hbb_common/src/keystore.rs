@@ -0,0 +1,346 @@
+// The key the secrets of the config files are sealed with, so that they can not
+// be read by whoever can read the files: a random one kept in the keystore of
+// the OS, the Credential Manager (DPAPI) on Windows, the Keychain on macOS and
+// the Secret Service on Linux, or else one derived from the passphrase of the
+// env var RUSTDESK_CONFIG_PASSPHRASE, which takes precedence, e.g. for a copy
+// carried to other computers. With neither, the secrets are kept in clear.
+// The keystore is of an account, while the service runs as root or the
+// system and reads the config files of the user too: the files shared by
+// them are sealed with a key of the machine instead, which only the service
+// can get, protected with DPAPI of the machine scope on Windows, in the
+// System keychain on macOS and in a file only root can read on Linux.
+use crate::config::{hash_password, APP_NAME};
+use serde::{de::DeserializeOwned, Serialize};
+use sodiumoxide::crypto::secretbox;
+use std::{collections::HashMap, sync::Mutex};
+
+const PASSPHRASE_ENV: &str = "RUSTDESK_CONFIG_PASSPHRASE";
+const KEYSTORE: &str = "keystore";
+const MACHINE: &str = "machine";
+const PASSPHRASE: &str = "passphrase";
+
+lazy_static::lazy_static! {
+    // None until the keystore is tried, once a process
+    static ref KEYSTORE_KEY: Mutex<Option<Option<secretbox::Key>>> = Default::default();
+    // the same of the key of the machine
+    static ref MACHINE_KEY: Mutex<Option<Option<secretbox::Key>>> = Default::default();
+    // by salt, argon2id being slow, with the salt of the latest one to seal with
+    static ref PASSPHRASE_KEYS: Mutex<(HashMap<Vec<u8>, secretbox::Key>, Vec<u8>)> =
+        Default::default();
+}
+
+/// If the secrets can be sealed, `shared` for the files of the service too.
+pub fn is_available(shared: bool) -> bool {
+    get_passphrase().is_some()
+        || if shared {
+            get_machine_key().is_some()
+        } else {
+            get_keystore_key().is_some()
+        }
+}
+
+/// `value` sealed, None if there is no key, `shared` for the files of the
+/// service too.
+pub fn seal<T: Serialize>(value: &T, shared: bool) -> Option<String> {
+    let (tag, key) = get_key(get_passphrase().as_deref(), shared)?;
+    seal_(value, &tag, &key)
+}
+
+/// The value of `sealed`, None if it is empty or can not be opened here.
+pub fn open<T: DeserializeOwned>(sealed: &str) -> Option<T> {
+    open_(sealed, get_passphrase().as_deref())
+}
+
+/// If `sealed` is with the keystore of an account, which a shared file is
+/// not to be, e.g. by a version before.
+pub fn is_per_user(sealed: &str) -> bool {
+    sealed.starts_with(KEYSTORE)
+}
+
+// the tag of the sealed values and the key to seal with
+fn get_key(passphrase: Option<&str>, shared: bool) -> Option<(String, secretbox::Key)> {
+    match passphrase {
+        Some(passphrase) => {
+            let salt = PASSPHRASE_KEYS.lock().unwrap().1.clone();
+            let salt = if salt.is_empty() {
+                (0..16).map(|_| rand::random::<u8>()).collect()
+            } else {
+                salt
+            };
+            let key = get_passphrase_key(passphrase, &salt)?;
+            Some((format!("{}:{}", PASSPHRASE, base64::encode(salt)), key))
+        }
+        None if shared => Some((MACHINE.to_owned(), get_machine_key()?)),
+        None => Some((KEYSTORE.to_owned(), get_keystore_key()?)),
+    }
+}
+
+fn seal_<T: Serialize>(value: &T, tag: &str, key: &secretbox::Key) -> Option<String> {
+    let data = serde_json::to_vec(value).ok()?;
+    let nonce = secretbox::gen_nonce();
+    let mut v = nonce.0.to_vec();
+    v.extend(secretbox::seal(&data, &nonce, key));
+    Some(format!("{}:{}", tag, base64::encode(v)))
+}
+
+fn open_<T: DeserializeOwned>(sealed: &str, passphrase: Option<&str>) -> Option<T> {
+    let mut fds = sealed.split(':');
+    let key = match fds.next()? {
+        KEYSTORE => get_keystore_key()?,
+        MACHINE => get_machine_key()?,
+        PASSPHRASE => {
+            let salt = base64::decode(fds.next()?).ok()?;
+            get_passphrase_key(passphrase?, &salt)?
+        }
+        _ => return None,
+    };
+    let v = base64::decode(fds.next()?).ok()?;
+    if v.len() <= secretbox::NONCEBYTES {
+        return None;
+    }
+    let nonce = secretbox::Nonce::from_slice(&v[..secretbox::NONCEBYTES])?;
+    let data = secretbox::open(&v[secretbox::NONCEBYTES..], &nonce, &key).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+fn get_passphrase() -> Option<String> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(x) if !x.is_empty() => Some(x),
+        _ => None,
+    }
+}
+
+fn get_passphrase_key(passphrase: &str, salt: &[u8]) -> Option<secretbox::Key> {
+    let mut keys = PASSPHRASE_KEYS.lock().unwrap();
+    keys.1 = salt.to_vec();
+    if let Some(key) = keys.0.get(salt) {
+        return Some(key.clone());
+    }
+    let hash = match hash_password(passphrase, salt) {
+        Ok(hash) => hash,
+        Err(err) => {
+            log::error!("{}", err);
+            return None;
+        }
+    };
+    let key = secretbox::Key::from_slice(&hash)?;
+    keys.0.insert(salt.to_vec(), key.clone());
+    Some(key)
+}
+
+fn get_keystore_key() -> Option<secretbox::Key> {
+    let mut key = KEYSTORE_KEY.lock().unwrap();
+    if key.is_none() {
+        *key = Some(load_keystore_key());
+    }
+    key.clone().flatten()
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn load_keystore_key() -> Option<secretbox::Key> {
+    let entry = keyring::Entry::new(APP_NAME, "config");
+    match entry.get_password() {
+        Ok(x) => base64::decode(x)
+            .ok()
+            .and_then(|x| secretbox::Key::from_slice(&x)),
+        Err(keyring::Error::NoEntry) => {
+            let key = secretbox::gen_key();
+            match entry.set_password(&base64::encode(&key.0)) {
+                Ok(_) => Some(key),
+                Err(err) => {
+                    log::warn!("Failed to add the config key to the keystore: {}", err);
+                    None
+                }
+            }
+        }
+        Err(err) => {
+            log::warn!("No keystore for the config key: {}", err);
+            None
+        }
+    }
+}
+
+// the app data of the mobiles is only readable by the app
+#[cfg(any(target_os = "android", target_os = "ios"))]
+fn load_keystore_key() -> Option<secretbox::Key> {
+    None
+}
+
+fn get_machine_key() -> Option<secretbox::Key> {
+    let mut key = MACHINE_KEY.lock().unwrap();
+    if key.is_none() {
+        *key = Some(load_machine_key());
+    }
+    key.clone().flatten()
+}
+
+// created by root, the service, the others not able to read it
+#[cfg(target_os = "linux")]
+fn load_machine_key() -> Option<secretbox::Key> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let path = std::path::PathBuf::from("/etc")
+        .join(APP_NAME.to_lowercase())
+        .join("config.key");
+    if let Ok(x) = std::fs::read(&path) {
+        return secretbox::Key::from_slice(&x);
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    let key = secretbox::gen_key();
+    let res = std::fs::create_dir_all(path.parent()?).and_then(|_| {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        std::io::Write::write_all(&mut file, &key.0)
+    });
+    match res {
+        Ok(_) => Some(key),
+        Err(err) => {
+            log::warn!(
+                "Failed to create the config key {}: {}",
+                path.display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn load_machine_key() -> Option<secretbox::Key> {
+    use security_framework::os::macos::keychain::SecKeychain;
+    let keychain = match SecKeychain::open("/Library/Keychains/System.keychain") {
+        Ok(x) => x,
+        Err(err) => {
+            log::warn!("No System keychain for the config key: {}", err);
+            return None;
+        }
+    };
+    if let Ok((x, _)) = keychain.find_generic_password(APP_NAME, "config") {
+        return base64::decode(x.as_ref())
+            .ok()
+            .and_then(|x| secretbox::Key::from_slice(&x));
+    }
+    let key = secretbox::gen_key();
+    let value = base64::encode(&key.0);
+    match keychain.set_generic_password(APP_NAME, "config", value.as_bytes()) {
+        Ok(_) => Some(key),
+        Err(err) => {
+            log::warn!(
+                "Failed to add the config key to the System keychain: {}",
+                err
+            );
+            None
+        }
+    }
+}
+
+// next to the config of the service, of the LocalService profile
+#[cfg(windows)]
+fn load_machine_key() -> Option<secretbox::Key> {
+    let path = crate::config::Config::file().with_file_name(format!("{}.key", APP_NAME));
+    if let Ok(blob) = std::fs::read(&path) {
+        return dpapi(&blob, false).and_then(|x| secretbox::Key::from_slice(&x));
+    }
+    let key = secretbox::gen_key();
+    let blob = dpapi(&key.0, true)?;
+    match std::fs::write(&path, blob) {
+        Ok(_) => Some(key),
+        Err(err) => {
+            log::warn!(
+                "Failed to create the config key {}: {}",
+                path.display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+// CryptProtectData of the machine scope, or CryptUnprotectData
+#[cfg(windows)]
+fn dpapi(data: &[u8], protect: bool) -> Option<Vec<u8>> {
+    use std::ptr::{null, null_mut};
+    use winapi::um::{
+        dpapi::{
+            CryptProtectData, CryptUnprotectData, CRYPTPROTECT_LOCAL_MACHINE,
+            CRYPTPROTECT_UI_FORBIDDEN,
+        },
+        winbase::LocalFree,
+        wincrypt::DATA_BLOB,
+    };
+    let mut input = DATA_BLOB {
+        cbData: data.len() as _,
+        pbData: data.as_ptr() as _,
+    };
+    let mut output = DATA_BLOB {
+        cbData: 0,
+        pbData: null_mut(),
+    };
+    let ok = unsafe {
+        if protect {
+            let flags = CRYPTPROTECT_UI_FORBIDDEN | CRYPTPROTECT_LOCAL_MACHINE;
+            CryptProtectData(
+                &mut input,
+                null(),
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                flags,
+                &mut output,
+            )
+        } else {
+            CryptUnprotectData(
+                &mut input,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut output,
+            )
+        }
+    };
+    if ok == 0 {
+        log::warn!("DPAPI failed: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    let res = unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as _) }.to_vec();
+    unsafe { LocalFree(output.pbData as _) };
+    Some(res)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn load_machine_key() -> Option<secretbox::Key> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passphrase() {
+        let passphrase = Some("correct horse battery staple");
+        let value = ("password".to_owned(), vec![1u8, 2, 3]);
+        let (tag, key) = get_key(passphrase, true).unwrap();
+        let sealed = seal_(&value, &tag, &key).unwrap();
+        assert!(sealed.starts_with("passphrase:"));
+        assert!(!is_per_user(&sealed));
+        assert!(!sealed.contains("password"));
+        assert_eq!(open_::<(String, Vec<u8>)>(&sealed, passphrase), Some(value));
+        assert_eq!(open_::<(String, Vec<u8>)>(&sealed, Some("wrong")), None);
+        assert_eq!(open_::<(String, Vec<u8>)>(&sealed, None), None);
+        let mut v: Vec<char> = sealed.chars().collect();
+        let i = v.len() - 2;
+        v[i] = if v[i] == 'A' { 'B' } else { 'A' };
+        let tampered: String = v.into_iter().collect();
+        assert_eq!(open_::<(String, Vec<u8>)>(&tampered, passphrase), None);
+        assert_eq!(open_::<(String, Vec<u8>)>("", passphrase), None);
+        let sealed = seal_(&"password", MACHINE, &secretbox::gen_key()).unwrap();
+        assert!(!is_per_user(&sealed));
+    }
+}
@@ -21,12 +21,27 @@ pub mod udp;
 pub use env_logger;
 pub use log;
 pub mod bytes_codec;
+pub mod buffer_pool;
 #[cfg(feature = "quic")]
 pub mod quic;
+#[cfg(feature = "websocket")]
+pub mod ws;
 pub use anyhow::{self, bail};
 pub use futures_util;
+pub mod capabilities;
 pub mod config;
+pub mod doh;
+pub mod fec;
 pub mod fs;
+pub mod keepalive;
+pub mod keystore;
+pub mod logging;
+pub mod policy;
+pub mod port_mapping;
+pub mod rate_limiter;
+pub mod rudp;
+pub mod send_queue;
+pub mod totp;
 pub use regex;
 pub use sodiumoxide;
 pub use tokio_socks;
@@ -34,9 +49,6 @@ pub use tokio_socks::IntoTargetAddr;
 pub use tokio_socks::TargetAddr;
 pub use mac_address;
 
-#[cfg(feature = "quic")]
-pub type Stream = quic::Connection;
-#[cfg(not(feature = "quic"))]
 pub type Stream = tcp::FramedStream;
 
 #[inline]
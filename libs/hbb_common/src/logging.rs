@@ -0,0 +1,127 @@
+// The log of the app, to stderr or to the files of Config::log_path(), as text
+// or as JSON objects one a line with the option "log-format" ("json"). The
+// files are rotated daily, or once over "log-max-size" MB, and the last
+// "log-keep-files" (6 by default) kept. The levels are those of RUST_LOG unless
+// the option "log-spec" is set, e.g. "info,rustdesk::rendezvous_mediator=trace",
+// which is checked again every few seconds, to debug a host with no restart.
+use crate::config::Config;
+use flexi_logger::{
+    opt_format, Age, Cleanup, Criterion, DeferredNow, FileSpec, LogSpecification, Logger,
+    LoggerHandle, Naming, Record,
+};
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_KEEP_FILES: usize = 6;
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    // the logger stops once its handle is dropped
+    static ref HANDLE: Mutex<Option<LoggerHandle>> = Default::default();
+}
+
+/// Starts the logger, to the files of the sub folder `name` of the log path
+/// with `to_file`, with the levels of `default_spec` if nothing else is set.
+pub fn init(name: &str, to_file: bool, default_spec: &str) {
+    let default_spec = default_spec.to_owned();
+    let mut spec = get_spec(&default_spec);
+    let logger = match Logger::try_with_str(&spec) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Invalid log spec {}: {}", spec, err);
+            spec = default_spec.clone();
+            match Logger::try_with_str(&spec) {
+                Ok(x) => x,
+                Err(_) => return,
+            }
+        }
+    };
+    let logger = if Config::get_option("log-format") == "json" {
+        logger.format(json_format)
+    } else {
+        logger.format(opt_format)
+    };
+    let logger = if to_file {
+        logger
+            .log_to_file(FileSpec::default().directory(get_path(name)))
+            .rotate(get_criterion(), Naming::Timestamps, get_cleanup())
+    } else {
+        logger
+    };
+    match logger.start() {
+        Ok(handle) => *HANDLE.lock().unwrap() = Some(handle),
+        Err(err) => {
+            eprintln!("Failed to start the logger: {}", err);
+            return;
+        }
+    }
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        let new_spec = get_spec(&default_spec);
+        if new_spec == spec {
+            continue;
+        }
+        spec = new_spec;
+        match LogSpecification::parse(&spec) {
+            Ok(x) => {
+                if let Some(handle) = HANDLE.lock().unwrap().as_mut() {
+                    handle.set_new_spec(x);
+                }
+                log::info!("Log spec changed to {}", spec);
+            }
+            Err(err) => log::error!("Invalid log spec {}: {}", spec, err),
+        }
+    });
+}
+
+fn get_path(name: &str) -> PathBuf {
+    let mut path = Config::log_path();
+    if !name.is_empty() {
+        path.push(name);
+    }
+    path
+}
+
+fn get_spec(default_spec: &str) -> String {
+    let spec = Config::get_option("log-spec");
+    if !spec.trim().is_empty() {
+        return spec.trim().to_owned();
+    }
+    match std::env::var("RUST_LOG") {
+        Ok(spec) if !spec.trim().is_empty() => spec,
+        _ => default_spec.to_owned(),
+    }
+}
+
+fn get_criterion() -> Criterion {
+    match Config::get_option("log-max-size").parse::<u64>() {
+        Ok(mb) if mb > 0 => Criterion::AgeOrSize(Age::Day, mb * 1024 * 1024),
+        _ => Criterion::Age(Age::Day),
+    }
+}
+
+fn get_cleanup() -> Cleanup {
+    match Config::get_option("log-keep-files").parse::<usize>() {
+        Ok(n) if n > 0 => Cleanup::KeepLogFiles(n),
+        _ => Cleanup::KeepLogFiles(DEFAULT_KEEP_FILES),
+    }
+}
+
+fn json_format(w: &mut dyn Write, _now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0);
+    let line = serde_json::json!({
+        "time": time,
+        "level": record.level().to_string(),
+        "module": record.module_path().unwrap_or("<unnamed>"),
+        "line": record.line().unwrap_or(0),
+        "msg": record.args().to_string(),
+    });
+    write!(w, "{}", line)
+}
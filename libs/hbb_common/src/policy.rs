@@ -0,0 +1,119 @@
+// The options locked by the admin of a managed computer, e.g. to disable the
+// file transfer, require the 2FA or pin the relay server, in a file only the
+// admin can write: /etc/rustdesk/policy.toml, or policy.toml in
+// %ProgramData%\RustDesk on Windows, where the values of the registry key
+// HKLM\SOFTWARE\Policies\RustDesk, as set by a group policy, take precedence.
+// Each line is `option = "value"`, an empty value locking the option to its
+// default. It is read once a process, so it applies once the service restarts.
+use crate::config::APP_NAME;
+use std::{collections::HashMap, path::PathBuf};
+
+lazy_static::lazy_static! {
+    static ref POLICY: HashMap<String, String> = load();
+}
+
+/// The value an option is locked to, if it is.
+pub fn get(k: &str) -> Option<String> {
+    POLICY.get(k).cloned()
+}
+
+pub fn get_all() -> HashMap<String, String> {
+    POLICY.clone()
+}
+
+#[inline]
+pub fn is_locked(k: &str) -> bool {
+    POLICY.contains_key(k)
+}
+
+/// The value of the registry policy only, not of policy.toml, which a user
+/// may create if the folder in %ProgramData% is not there yet.
+#[cfg(windows)]
+pub fn get_machine(k: &str) -> Option<String> {
+    load_registry().remove(k)
+}
+
+fn load() -> HashMap<String, String> {
+    let mut policy = HashMap::new();
+    let path = get_path();
+    if let Ok(text) = std::fs::read_to_string(&path) {
+        match parse(&text) {
+            Ok(x) => policy = x,
+            Err(err) => log::error!("Invalid policy {}: {}", path.display(), err),
+        }
+    }
+    #[cfg(windows)]
+    policy.extend(load_registry());
+    if !policy.is_empty() {
+        log::info!("options locked by the policy: {:?}", policy.keys());
+    }
+    policy
+}
+
+fn parse(text: &str) -> Result<HashMap<String, String>, toml::de::Error> {
+    let values: HashMap<String, toml::Value> = toml::from_str(text)?;
+    let mut policy = HashMap::new();
+    for (k, v) in values {
+        match v {
+            toml::Value::String(v) => {
+                policy.insert(k, v);
+            }
+            toml::Value::Integer(v) => {
+                policy.insert(k, v.to_string());
+            }
+            // the options are "Y" or "N" rather than true or false
+            _ => log::error!("Invalid policy value of {}, a string is expected", k),
+        }
+    }
+    Ok(policy)
+}
+
+fn get_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let dir = std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".to_owned());
+        return PathBuf::from(dir).join(APP_NAME).join("policy.toml");
+    }
+    #[cfg(not(windows))]
+    PathBuf::from("/etc")
+        .join(APP_NAME.to_lowercase())
+        .join("policy.toml")
+}
+
+#[cfg(windows)]
+fn load_registry() -> HashMap<String, String> {
+    use winreg::{enums::HKEY_LOCAL_MACHINE, types::FromRegValue, RegKey};
+    let mut policy = HashMap::new();
+    let key = match RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(format!("SOFTWARE\\Policies\\{}", APP_NAME))
+    {
+        Ok(x) => x,
+        Err(_) => return policy,
+    };
+    for (k, v) in key.enum_values().filter_map(|x| x.ok()) {
+        if let Ok(v) = String::from_reg_value(&v) {
+            policy.insert(k, v);
+        } else if let Ok(v) = u32::from_reg_value(&v) {
+            policy.insert(k, v.to_string());
+        }
+    }
+    policy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "enable-file-transfer = \"N\"\nrelay-server = \"relay.corp\"\n\
+                    max_bandwidth_kbps = 2000\nallow-terminal = true\n";
+        let policy = parse(text).unwrap();
+        assert_eq!(policy.len(), 3);
+        assert_eq!(policy["enable-file-transfer"], "N");
+        assert_eq!(policy["max_bandwidth_kbps"], "2000");
+        assert!(!policy.contains_key("allow-terminal"));
+        assert!(parse("[options]\nkey = \"pk\"").unwrap().is_empty());
+        assert!(parse("key = ").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{bail, ResultType};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_TIMEOUT: u64 = 250;
+const NAT_PMP_RETRIES: u32 = 4;
+const DESCRIPTION: &str = "RustDesk";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Upnp(SocketAddrV4),
+    NatPmp(Ipv4Addr),
+}
+
+/// A temporary port mapping on the router, to be renewed before `lifetime`
+/// runs out and removed when not needed any more.
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub external: SocketAddr,
+    pub local_port: u16,
+    pub protocol: Protocol,
+    pub lifetime: Duration,
+    method: Method,
+}
+
+/// Request a mapping for `local_port`, trying UPnP IGD first, then NAT-PMP.
+pub async fn map_port(
+    local_port: u16,
+    protocol: Protocol,
+    lifetime: Duration,
+) -> ResultType<PortMapping> {
+    match map_upnp(local_port, protocol, lifetime, None).await {
+        Ok(m) => return Ok(m),
+        Err(err) => log::debug!("UPnP port mapping failed: {}", err),
+    }
+    let gateway = get_default_gateway()?;
+    map_nat_pmp(gateway, local_port, protocol, lifetime, 0).await
+}
+
+impl PortMapping {
+    /// Refresh the lease, keeping the same external port if the router allows.
+    pub async fn renew(&mut self) -> ResultType<()> {
+        let external_port = self.external.port();
+        *self = match self.method {
+            Method::Upnp(gateway) => {
+                map_upnp(
+                    self.local_port,
+                    self.protocol,
+                    self.lifetime,
+                    Some((gateway, external_port)),
+                )
+                .await?
+            }
+            Method::NatPmp(gateway) => {
+                map_nat_pmp(
+                    gateway,
+                    self.local_port,
+                    self.protocol,
+                    self.lifetime,
+                    external_port,
+                )
+                .await?
+            }
+        };
+        Ok(())
+    }
+
+    pub async fn remove(self) -> ResultType<()> {
+        match self.method {
+            Method::Upnp(addr) => {
+                let gateway = igd::aio::search_gateway(igd::SearchOptions::default()).await?;
+                if gateway.addr != addr {
+                    bail!("UPnP gateway changed");
+                }
+                gateway
+                    .remove_port(to_igd(self.protocol), self.external.port())
+                    .await?;
+            }
+            Method::NatPmp(gateway) => {
+                // a zero lifetime deletes the mapping
+                map_nat_pmp(
+                    gateway,
+                    self.local_port,
+                    self.protocol,
+                    Duration::from_secs(0),
+                    0,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_igd(protocol: Protocol) -> igd::PortMappingProtocol {
+    match protocol {
+        Protocol::Tcp => igd::PortMappingProtocol::TCP,
+        Protocol::Udp => igd::PortMappingProtocol::UDP,
+    }
+}
+
+// the local address the router sees us as
+async fn get_local_ip(gateway: Ipv4Addr) -> ResultType<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        _ => bail!("no local ipv4 address"),
+    }
+}
+
+async fn map_upnp(
+    local_port: u16,
+    protocol: Protocol,
+    lifetime: Duration,
+    renew: Option<(SocketAddrV4, u16)>,
+) -> ResultType<PortMapping> {
+    let gateway = igd::aio::search_gateway(igd::SearchOptions::default()).await?;
+    let local = SocketAddrV4::new(get_local_ip(*gateway.addr.ip()).await?, local_port);
+    let external_ip = gateway.get_external_ip().await?;
+    let lease = lifetime.as_secs() as u32;
+    let external_port = match renew {
+        Some((addr, port)) if addr == gateway.addr => {
+            gateway
+                .add_port(to_igd(protocol), port, local, lease, DESCRIPTION)
+                .await?;
+            port
+        }
+        _ => {
+            gateway
+                .add_any_port(to_igd(protocol), local, lease, DESCRIPTION)
+                .await?
+        }
+    };
+    Ok(PortMapping {
+        external: SocketAddr::new(external_ip.into(), external_port),
+        local_port,
+        protocol,
+        lifetime,
+        method: Method::Upnp(gateway.addr),
+    })
+}
+
+fn nat_pmp_map_request(
+    local_port: u16,
+    protocol: Protocol,
+    lifetime: Duration,
+    external_port: u16,
+) -> [u8; 12] {
+    let mut req = [0u8; 12];
+    req[1] = match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+    req[4..6].copy_from_slice(&local_port.to_be_bytes());
+    req[6..8].copy_from_slice(&external_port.to_be_bytes());
+    req[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    req
+}
+
+fn nat_pmp_check_header(res: &[u8], op: u8, len: usize) -> ResultType<()> {
+    if res.len() < len || res[0] != 0 || res[1] != op + 128 {
+        bail!("invalid NAT-PMP response");
+    }
+    let code = u16::from_be_bytes([res[2], res[3]]);
+    if code != 0 {
+        bail!("NAT-PMP error code {}", code);
+    }
+    Ok(())
+}
+
+// (external port, lifetime in seconds)
+fn nat_pmp_parse_map_response(res: &[u8], op: u8) -> ResultType<(u16, u32)> {
+    nat_pmp_check_header(res, op, 16)?;
+    let port = u16::from_be_bytes([res[10], res[11]]);
+    let lifetime = u32::from_be_bytes([res[12], res[13], res[14], res[15]]);
+    Ok((port, lifetime))
+}
+
+// a zero lifetime only answers a removal, to a mapping it is a refusal
+fn nat_pmp_check_lifetime(req: &[u8; 12], lifetime: u32) -> ResultType<()> {
+    let requested = u32::from_be_bytes([req[8], req[9], req[10], req[11]]);
+    if lifetime == 0 && requested > 0 {
+        bail!("NAT-PMP mapping refused, zero lifetime");
+    }
+    Ok(())
+}
+
+fn nat_pmp_parse_address_response(res: &[u8]) -> ResultType<Ipv4Addr> {
+    nat_pmp_check_header(res, 0, 12)?;
+    Ok(Ipv4Addr::new(res[8], res[9], res[10], res[11]))
+}
+
+async fn nat_pmp_request(gateway: Ipv4Addr, req: &[u8]) -> ResultType<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    let mut buf = [0u8; 16];
+    // RFC 6886: start at 250ms, double the wait on each retry
+    let mut wait = NAT_PMP_TIMEOUT;
+    for _ in 0..NAT_PMP_RETRIES {
+        socket.send(req).await?;
+        if let Ok(n) = super::timeout(wait, socket.recv(&mut buf)).await {
+            return Ok(buf[..n?].to_vec());
+        }
+        wait *= 2;
+    }
+    bail!("NAT-PMP gateway {} does not respond", gateway);
+}
+
+async fn map_nat_pmp(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    protocol: Protocol,
+    lifetime: Duration,
+    external_port: u16,
+) -> ResultType<PortMapping> {
+    let external_ip = nat_pmp_parse_address_response(&nat_pmp_request(gateway, &[0, 0]).await?)?;
+    let req = nat_pmp_map_request(local_port, protocol, lifetime, external_port);
+    let (port, lifetime) =
+        nat_pmp_parse_map_response(&nat_pmp_request(gateway, &req).await?, req[1])?;
+    nat_pmp_check_lifetime(&req, lifetime)?;
+    Ok(PortMapping {
+        external: SocketAddr::new(external_ip.into(), port),
+        local_port,
+        protocol,
+        lifetime: Duration::from_secs(lifetime as _),
+        method: Method::NatPmp(gateway),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn get_default_gateway() -> ResultType<Ipv4Addr> {
+    // Iface Destination Gateway ..., in little endian hex
+    for line in std::fs::read_to_string("/proc/net/route")?.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 2 && fields[1] == "00000000" {
+            if let Ok(gw) = u32::from_str_radix(fields[2], 16) {
+                if gw != 0 {
+                    return Ok(Ipv4Addr::from(gw.to_le_bytes()));
+                }
+            }
+        }
+    }
+    bail!("no default gateway");
+}
+
+// guess the usual x.y.z.1 of the interface used for internet traffic
+#[cfg(not(target_os = "linux"))]
+fn get_default_gateway() -> ResultType<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect("8.8.8.8:53")?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            Ok(Ipv4Addr::new(o[0], o[1], o[2], 1))
+        }
+        _ => bail!("no default gateway"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_pmp_packets() {
+        let req = nat_pmp_map_request(21118, Protocol::Tcp, Duration::from_secs(7200), 0);
+        assert_eq!(req, [0, 2, 0, 0, 0x52, 0x7e, 0, 0, 0, 0, 0x1c, 0x20]);
+        let res = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x52, 0x7e, 0xc3, 0x50, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(nat_pmp_parse_map_response(&res, 2).unwrap(), (50000, 3600));
+        let mut failed = res;
+        failed[3] = 3;
+        assert!(nat_pmp_parse_map_response(&failed, 2).is_err());
+        assert!(nat_pmp_parse_map_response(&res, 1).is_err());
+        let res = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(
+            nat_pmp_parse_address_response(&res).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 7)
+        );
+    }
+
+    #[test]
+    fn test_nat_pmp_zero_lifetime() {
+        let map = nat_pmp_map_request(21118, Protocol::Tcp, Duration::from_secs(7200), 0);
+        assert!(nat_pmp_check_lifetime(&map, 0).is_err());
+        assert!(nat_pmp_check_lifetime(&map, 3600).is_ok());
+        let remove = nat_pmp_map_request(21118, Protocol::Tcp, Duration::from_secs(0), 0);
+        assert!(nat_pmp_check_lifetime(&remove, 0).is_ok());
+    }
+}
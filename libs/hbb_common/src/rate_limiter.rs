@@ -0,0 +1,97 @@
+use crate::config::Config;
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+// how often the process wide limit is read from the config
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref GLOBAL: Mutex<(Option<TokenBucket>, Option<Instant>)> = Default::default();
+}
+
+/// Token bucket with one second worth of burst.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // bytes per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(kbps: u32) -> Self {
+        let rate = kbps as f64 * 1000. / 8.;
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn set_rate(&mut self, kbps: u32) {
+        self.rate = kbps as f64 * 1000. / 8.;
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    /// Take the tokens for `bytes` right away, returns how long to wait
+    /// before sending them.
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = (now - self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0. || self.rate <= 0. {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    pub async fn acquire(&mut self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if wait > Duration::from_millis(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Wait for the process wide `max-bandwidth-kbps` option to allow `bytes`.
+pub async fn acquire_global(bytes: usize) {
+    let wait = {
+        let mut lock = GLOBAL.lock().unwrap();
+        let (bucket, checked) = &mut *lock;
+        if checked.map_or(true, |tm| tm.elapsed() >= REFRESH_INTERVAL) {
+            *checked = Some(Instant::now());
+            match (Config::get_max_bandwidth_kbps(), bucket.as_mut()) {
+                (0, _) => *bucket = None,
+                (kbps, Some(bucket)) => bucket.set_rate(kbps),
+                (kbps, None) => *bucket = Some(TokenBucket::new(kbps)),
+            }
+        }
+        match bucket.as_mut() {
+            Some(bucket) => bucket.reserve(bytes),
+            None => return,
+        }
+    };
+    if wait > Duration::from_millis(0) {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        // 1000 bytes per second
+        let mut bucket = TokenBucket::new(8);
+        assert_eq!(bucket.reserve(1000), Duration::from_millis(0));
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        bucket.set_rate(16);
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+}
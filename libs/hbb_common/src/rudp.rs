@@ -0,0 +1,754 @@
+use crate::{
+    bail, fec,
+    rate_limiter::TokenBucket,
+    udp::{FramedSocket, RetransmitTimer, RtoConfig},
+    ResultType,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use protobuf::Message;
+use sodiumoxide::crypto::secretbox::{self, Key, Nonce};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::time::Instant;
+use tokio_socks::TargetAddr;
+
+const KIND_HELLO: u8 = 1;
+const KIND_HELLO_ACK: u8 = 2;
+const KIND_DATA: u8 = 3;
+const KIND_ACK: u8 = 4;
+const KIND_PARITY: u8 = 5;
+const FLAG_LAST: u8 = 1;
+// kind, seq, flags
+const DATA_HEADER_LEN: usize = 6;
+// keeps datagrams below the 1280 bytes minimum MTU of IPv6
+const MAX_PAYLOAD: usize = 1200;
+// data segments protected together by parity
+const FEC_GROUP: usize = 64;
+// flags, payload length, payload padded to MAX_PAYLOAD
+const SHARD_LEN: usize = 3 + MAX_PAYLOAD;
+// delivered segments kept for recovering a group whose parity comes late
+const FEC_HISTORY: usize = 1024;
+// groups with parity waiting for their data, the oldest evicted beyond
+const MAX_FEC_GROUPS: usize = 2 * RECV_WINDOW as usize / FEC_GROUP;
+const SEND_WINDOW: usize = 512;
+const RECV_WINDOW: u32 = 4096;
+const MAX_QUEUE: usize = 8192;
+const MAX_SACK: usize = 128;
+const FAST_RESEND: u32 = 3;
+const MAX_RETRANSMITS: u32 = 6;
+const HELLO_INTERVAL: Duration = Duration::from_millis(200);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TICK: Duration = Duration::from_secs(60);
+// the tcp stream with the same key only uses the first 8 bytes of the nonce
+const NONCE_LISTENER: u8 = 1;
+const NONCE_CONNECTOR: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Listening,
+    // time of the last hello
+    Connecting(Option<Instant>),
+    Established,
+}
+
+struct Segment {
+    packet: Bytes,
+    sent_at: Instant,
+    deadline: Instant,
+    retransmits: u32,
+    skipped: u32,
+}
+
+struct FecGroup {
+    data: usize,
+    parity: Vec<Option<Vec<u8>>>,
+}
+
+/// Reliable, ordered delivery of messages over udp: a selective repeat ARQ
+/// with SACKs and fast resend, so that a lost datagram only delays what
+/// follows by about one RTT instead of stalling the whole tcp window.
+/// Optional Reed-Solomon parity recovers minor loss without even waiting for that.
+/// Messages are sealed with the session key of the tcp stream they belong to.
+/// Sequence numbers are u32 and never wrap in practice (5TB per session).
+pub struct ReliableChannel {
+    socket: FramedSocket,
+    peer: Option<SocketAddr>,
+    token: Vec<u8>,
+    state: State,
+    started: Instant,
+    key: Key,
+    seal_dir: u8,
+    open_dir: u8,
+    seal_seq: u64,
+    open_seq: u64,
+    timer: RetransmitTimer,
+    snd_next: u32,
+    in_flight: BTreeMap<u32, Segment>,
+    // parity packets have no sequence number, they are never retransmitted
+    queue: VecDeque<(Option<u32>, Bytes)>,
+    rcv_next: u32,
+    rcv_buf: BTreeMap<u32, (bool, Bytes)>,
+    history: BTreeMap<u32, (bool, Bytes)>,
+    fec_groups: BTreeMap<u32, FecGroup>,
+    partial: BytesMut,
+    ready: VecDeque<BytesMut>,
+    // since the last take_loss_stats
+    sent: u64,
+    resent: u64,
+    limiter: Option<TokenBucket>,
+}
+
+impl ReliableChannel {
+    /// Wait on `socket` for the peer knowing `token`, which was handed over
+    /// the secured tcp stream.
+    pub fn listen(socket: FramedSocket, token: Vec<u8>, key: Key) -> Self {
+        Self::new(socket, None, token, key, State::Listening)
+    }
+
+    /// Say hello to `peer` until it answers, driven by `next`.
+    pub fn connect(socket: FramedSocket, peer: SocketAddr, token: Vec<u8>, key: Key) -> Self {
+        Self::new(socket, Some(peer), token, key, State::Connecting(None))
+    }
+
+    fn new(
+        socket: FramedSocket,
+        peer: Option<SocketAddr>,
+        token: Vec<u8>,
+        key: Key,
+        state: State,
+    ) -> Self {
+        let (seal_dir, open_dir) = if state == State::Listening {
+            (NONCE_LISTENER, NONCE_CONNECTOR)
+        } else {
+            (NONCE_CONNECTOR, NONCE_LISTENER)
+        };
+        Self {
+            socket,
+            peer,
+            token,
+            state,
+            started: Instant::now(),
+            key,
+            seal_dir,
+            open_dir,
+            seal_seq: 0,
+            open_seq: 0,
+            timer: RetransmitTimer::new(RtoConfig {
+                min_rto: Duration::from_millis(100),
+                max_rto: Duration::from_secs(2),
+                backoff: 1.5,
+            }),
+            snd_next: 0,
+            in_flight: Default::default(),
+            queue: Default::default(),
+            rcv_next: 0,
+            rcv_buf: Default::default(),
+            history: Default::default(),
+            fec_groups: Default::default(),
+            partial: BytesMut::new(),
+            ready: Default::default(),
+            sent: 0,
+            resent: 0,
+            limiter: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    #[inline]
+    pub fn srtt(&self) -> Option<Duration> {
+        self.timer.srtt()
+    }
+
+    /// Cap the send rate of this channel, 0 to remove.
+    pub fn set_rate_limit(&mut self, kbps: u32) {
+        self.limiter = if kbps > 0 {
+            Some(TokenBucket::new(kbps))
+        } else {
+            None
+        };
+    }
+
+    /// Data segments sent and retransmitted since the last call.
+    pub fn take_loss_stats(&mut self) -> (u64, u64) {
+        let stats = (self.sent, self.resent);
+        self.sent = 0;
+        self.resent = 0;
+        stats
+    }
+
+    /// Queue a message, sent as far as the window allows. Fails if the queue
+    /// overflows, i.e. the link can not keep up and the caller should fall back to tcp.
+    #[inline]
+    pub async fn send(&mut self, msg: &impl Message) -> ResultType<()> {
+        self.send_fec(msg, 0.).await
+    }
+
+    /// Like `send`, with `fec_ratio` parity packets per data packet of the message.
+    pub async fn send_fec(&mut self, msg: &impl Message, fec_ratio: f32) -> ResultType<()> {
+        if !self.is_established() {
+            bail!("udp channel not established");
+        }
+        if self.queue.len() >= MAX_QUEUE {
+            bail!("udp channel send queue overflow");
+        }
+        let mut data = crate::buffer_pool::get();
+        data.resize(secretbox::MACBYTES, 0);
+        msg.write_to_vec(&mut data)?;
+        self.seal(&mut data);
+        let chunks: Vec<&[u8]> = data.chunks(MAX_PAYLOAD).collect();
+        for (i, group) in chunks.chunks(FEC_GROUP).enumerate() {
+            let first_seq = self.snd_next;
+            let parity = fec::parity_shards(group.len(), fec_ratio);
+            let mut shards = Vec::new();
+            for (j, chunk) in group.iter().enumerate() {
+                let last = i * FEC_GROUP + j + 1 == chunks.len();
+                let mut packet = BytesMut::with_capacity(DATA_HEADER_LEN + chunk.len());
+                packet.put_u8(KIND_DATA);
+                packet.put_u32(self.snd_next);
+                packet.put_u8(if last { FLAG_LAST } else { 0 });
+                packet.put_slice(chunk);
+                self.queue.push_back((Some(self.snd_next), packet.freeze()));
+                self.snd_next += 1;
+                if parity > 0 {
+                    shards.push(to_shard(last, chunk));
+                }
+            }
+            for (j, shard) in fec::encode(&shards, parity)?.into_iter().enumerate() {
+                let mut packet = BytesMut::with_capacity(7 + shard.len());
+                packet.put_u8(KIND_PARITY);
+                packet.put_u32(first_seq);
+                packet.put_u8(group.len() as _);
+                packet.put_u8(j as _);
+                packet.put_slice(&shard);
+                self.queue.push_back((None, packet.freeze()));
+            }
+        }
+        crate::buffer_pool::put(data);
+        self.flush().await
+    }
+
+    /// Drive the handshake, acks and retransmits until a message arrives.
+    /// Dropping the future only loses an ack or a retransmit, which the ARQ
+    /// recovers from, so it can be used in `select!`.
+    pub async fn next(&mut self) -> ResultType<BytesMut> {
+        loop {
+            if let Some(msg) = self.ready.pop_front() {
+                return Ok(msg);
+            }
+            let deadline = self.next_deadline();
+            tokio::select! {
+                res = self.socket.next() => match res {
+                    Some(Ok((data, TargetAddr::Ip(addr)))) => self.on_datagram(data, addr).await?,
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err),
+                    None => bail!("udp channel closed"),
+                },
+                _ = tokio::time::sleep_until(deadline) => self.on_tick().await?,
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        match self.state {
+            State::Listening => self.started + HANDSHAKE_TIMEOUT,
+            State::Connecting(last) => {
+                let hello = last.map(|t| t + HELLO_INTERVAL).unwrap_or(self.started);
+                hello.min(self.started + HANDSHAKE_TIMEOUT)
+            }
+            State::Established => self
+                .in_flight
+                .values()
+                .map(|s| s.deadline)
+                .min()
+                .unwrap_or_else(|| Instant::now() + IDLE_TICK),
+        }
+    }
+
+    async fn on_tick(&mut self) -> ResultType<()> {
+        let now = Instant::now();
+        match self.state {
+            State::Established => {}
+            _ if now >= self.started + HANDSHAKE_TIMEOUT => {
+                bail!("udp channel handshake timed out")
+            }
+            State::Listening => return Ok(()),
+            State::Connecting(_) => {
+                self.state = State::Connecting(Some(now));
+                let mut packet = BytesMut::with_capacity(1 + self.token.len());
+                packet.put_u8(KIND_HELLO);
+                packet.put_slice(&self.token);
+                return self.send_packet(packet.freeze()).await;
+            }
+        }
+        let expired: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|(_, s)| s.deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        let rto = self.timer.on_timeout();
+        for seq in expired {
+            self.resend(seq, rto).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_datagram(&mut self, mut data: BytesMut, addr: SocketAddr) -> ResultType<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let kind = data.get_u8();
+        if kind == KIND_HELLO {
+            if !sodiumoxide::utils::memcmp(&data[..], &self.token[..]) {
+                return Ok(());
+            }
+            match self.state {
+                State::Listening => {
+                    log::info!("udp channel established with {}", addr);
+                    self.peer = Some(addr);
+                    self.state = State::Established;
+                }
+                // the ack may have been lost, the peer keeps saying hello then
+                State::Established if self.peer == Some(addr) => {}
+                _ => return Ok(()),
+            }
+            return self
+                .send_packet(Bytes::from_static(&[KIND_HELLO_ACK]))
+                .await;
+        }
+        if self.peer != Some(addr) {
+            return Ok(());
+        }
+        if let State::Connecting(_) = self.state {
+            // data also proves the peer got our hello
+            if kind == KIND_HELLO_ACK || kind == KIND_DATA || kind == KIND_ACK {
+                log::info!("udp channel established with {}", addr);
+                self.state = State::Established;
+            }
+        }
+        if !self.is_established() {
+            return Ok(());
+        }
+        match kind {
+            KIND_DATA => self.on_data(data).await,
+            KIND_ACK => self.on_ack(data).await,
+            KIND_PARITY => self.on_parity(data).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn on_data(&mut self, mut data: BytesMut) -> ResultType<()> {
+        if data.len() < DATA_HEADER_LEN - 1 {
+            return Ok(());
+        }
+        let seq = data.get_u32();
+        let last = data.get_u8() & FLAG_LAST != 0;
+        if seq >= self.rcv_next && seq - self.rcv_next < RECV_WINDOW {
+            self.rcv_buf.entry(seq).or_insert((last, data.freeze()));
+            let group = self.fec_groups.range(..=seq).next_back().map(|(x, _)| *x);
+            if let Some(first_seq) = group {
+                self.recover(first_seq)?;
+            }
+        }
+        self.deliver()?;
+        self.send_ack().await
+    }
+
+    async fn on_parity(&mut self, mut data: BytesMut) -> ResultType<()> {
+        if data.len() != 6 + SHARD_LEN {
+            return Ok(());
+        }
+        let first_seq = data.get_u32();
+        let n = data.get_u8() as usize;
+        let index = data.get_u8() as usize;
+        if n == 0
+            || n + index >= fec::MAX_SHARDS
+            || first_seq.wrapping_add(n as u32) <= self.rcv_next
+            || first_seq - self.rcv_next.min(first_seq) >= RECV_WINDOW
+        {
+            return Ok(());
+        }
+        if !self.fec_groups.contains_key(&first_seq) && self.fec_groups.len() >= MAX_FEC_GROUPS {
+            if let Some(oldest) = self.fec_groups.keys().next().cloned() {
+                self.fec_groups.remove(&oldest);
+            }
+        }
+        let group = self.fec_groups.entry(first_seq).or_insert(FecGroup {
+            data: n,
+            parity: Vec::new(),
+        });
+        if group.parity.len() <= index {
+            group.parity.resize(index + 1, None);
+        }
+        group.parity[index] = Some(data.to_vec());
+        if self.recover(first_seq)? {
+            self.deliver()?;
+            return self.send_ack().await;
+        }
+        Ok(())
+    }
+
+    // Rebuild the missing segments of a group once enough of it has arrived.
+    fn recover(&mut self, first_seq: u32) -> ResultType<bool> {
+        let group = match self.fec_groups.get(&first_seq) {
+            Some(group) => group,
+            None => return Ok(false),
+        };
+        let n = group.data;
+        let mut shards: Vec<Option<Vec<u8>>> = (0..n as u32)
+            .map(|i| {
+                let seq = first_seq.wrapping_add(i);
+                let seg = if seq < self.rcv_next {
+                    self.history.get(&seq)
+                } else {
+                    self.rcv_buf.get(&seq)
+                };
+                seg.map(|(last, payload)| to_shard(*last, payload))
+            })
+            .collect();
+        let missing = shards.iter().filter(|x| x.is_none()).count();
+        if missing > 0 && group.parity.iter().filter(|x| x.is_some()).count() < missing {
+            return Ok(false);
+        }
+        shards.extend(group.parity.iter().cloned());
+        self.fec_groups.remove(&first_seq);
+        if missing == 0 || !fec::reconstruct(&mut shards, n)? {
+            return Ok(false);
+        }
+        for (i, shard) in shards.into_iter().take(n).enumerate() {
+            let seq = first_seq.wrapping_add(i as u32);
+            if seq >= self.rcv_next && !self.rcv_buf.contains_key(&seq) {
+                if let Some(seg) = shard.as_deref().and_then(from_shard) {
+                    self.rcv_buf.insert(seq, seg);
+                }
+            }
+        }
+        log::debug!("udp channel recovered {} segments by FEC", missing);
+        Ok(true)
+    }
+
+    fn deliver(&mut self) -> ResultType<()> {
+        while let Some((last, payload)) = self.rcv_buf.remove(&self.rcv_next) {
+            self.partial.put_slice(&payload);
+            self.history.insert(self.rcv_next, (last, payload));
+            self.rcv_next += 1;
+            if last {
+                let sealed = self.partial.split();
+                let msg = self.open(&sealed)?;
+                self.ready.push_back(msg);
+            }
+        }
+        while self.history.len() > FEC_HISTORY {
+            if let Some(seq) = self.history.keys().next().cloned() {
+                self.history.remove(&seq);
+            }
+        }
+        let rcv_next = self.rcv_next;
+        self.fec_groups
+            .retain(|first_seq, group| first_seq.wrapping_add(group.data as u32) > rcv_next);
+        Ok(())
+    }
+
+    async fn send_ack(&mut self) -> ResultType<()> {
+        let mut packet = BytesMut::with_capacity(5 + 4 * MAX_SACK);
+        packet.put_u8(KIND_ACK);
+        packet.put_u32(self.rcv_next);
+        for seq in self.rcv_buf.keys().take(MAX_SACK) {
+            packet.put_u32(*seq);
+        }
+        self.send_packet(packet.freeze()).await
+    }
+
+    async fn on_ack(&mut self, mut data: BytesMut) -> ResultType<()> {
+        if data.len() < 4 {
+            return Ok(());
+        }
+        let una = data.get_u32();
+        let mut acked: Vec<u32> = self.in_flight.range(..una).map(|(seq, _)| *seq).collect();
+        let mut max_sacked = None;
+        while data.len() >= 4 {
+            let seq = data.get_u32();
+            acked.push(seq);
+            max_sacked = max_sacked.max(Some(seq));
+        }
+        let now = Instant::now();
+        for seq in acked {
+            if let Some(s) = self.in_flight.remove(&seq) {
+                // Karn's algorithm, the ack may be for any of the copies
+                if s.retransmits == 0 {
+                    self.timer.on_rtt_sample(now - s.sent_at);
+                }
+            }
+        }
+        if let Some(max_sacked) = max_sacked {
+            let lost: Vec<u32> = self
+                .in_flight
+                .range_mut(..max_sacked)
+                .filter_map(|(seq, s)| {
+                    s.skipped += 1;
+                    if s.skipped >= FAST_RESEND {
+                        Some(*seq)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            let rto = self.timer.rto();
+            for seq in lost {
+                self.resend(seq, rto).await?;
+            }
+        }
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> ResultType<()> {
+        while self.in_flight.len() < SEND_WINDOW {
+            let (seq, packet) = match self.queue.pop_front() {
+                Some((Some(seq), packet)) => (seq, packet),
+                Some((None, packet)) => {
+                    self.send_packet(packet).await?;
+                    continue;
+                }
+                None => break,
+            };
+            let now = Instant::now();
+            self.in_flight.insert(
+                seq,
+                Segment {
+                    packet: packet.clone(),
+                    sent_at: now,
+                    deadline: now + self.timer.rto(),
+                    retransmits: 0,
+                    skipped: 0,
+                },
+            );
+            self.sent += 1;
+            self.send_packet(packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn resend(&mut self, seq: u32, rto: Duration) -> ResultType<()> {
+        let packet = match self.in_flight.get_mut(&seq) {
+            Some(s) => {
+                s.retransmits += 1;
+                if s.retransmits > MAX_RETRANSMITS {
+                    bail!("udp channel peer not responding");
+                }
+                s.skipped = 0;
+                s.deadline = Instant::now() + rto;
+                s.packet.clone()
+            }
+            None => return Ok(()),
+        };
+        self.resent += 1;
+        self.send_packet(packet).await
+    }
+
+    #[inline]
+    async fn send_packet(&mut self, packet: Bytes) -> ResultType<()> {
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.acquire(packet.len()).await;
+        }
+        match self.peer {
+            Some(peer) => self.socket.send_bytes(packet, TargetAddr::Ip(peer)).await,
+            None => bail!("udp channel has no peer yet"),
+        }
+    }
+
+    // in place, the tag into the MACBYTES before the data, where secretbox::seal
+    // puts it
+    fn seal(&mut self, buf: &mut [u8]) {
+        self.seal_seq += 1;
+        let (tag, data) = buf.split_at_mut(secretbox::MACBYTES);
+        let nonce = get_nonce(self.seal_seq, self.seal_dir);
+        tag.copy_from_slice(&secretbox::seal_detached(data, &nonce, &self.key).0);
+    }
+
+    fn open(&mut self, data: &[u8]) -> ResultType<BytesMut> {
+        self.open_seq += 1;
+        match secretbox::open(data, &get_nonce(self.open_seq, self.open_dir), &self.key) {
+            Ok(res) => Ok(BytesMut::from(&res[..])),
+            Err(()) => bail!("udp channel decryption error"),
+        }
+    }
+}
+
+fn to_shard(last: bool, payload: &[u8]) -> Vec<u8> {
+    let mut shard = vec![0u8; SHARD_LEN];
+    shard[0] = if last { FLAG_LAST } else { 0 };
+    shard[1..3].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    shard[3..3 + payload.len()].copy_from_slice(payload);
+    shard
+}
+
+fn from_shard(shard: &[u8]) -> Option<(bool, Bytes)> {
+    if shard.len() != SHARD_LEN {
+        return None;
+    }
+    let len = u16::from_be_bytes([shard[1], shard[2]]) as usize;
+    if len > MAX_PAYLOAD {
+        return None;
+    }
+    let payload = Bytes::copy_from_slice(&shard[3..3 + len]);
+    Some((shard[0] & FLAG_LAST != 0, payload))
+}
+
+fn get_nonce(seqnum: u64, dir: u8) -> Nonce {
+    let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
+    nonce.0[..8].copy_from_slice(&seqnum.to_le_bytes());
+    nonce.0[8] = dir;
+    nonce
+}
+
+/// `next` of an optional channel, pending forever without one, for `select!`.
+pub async fn next_opt(channel: &mut Option<ReliableChannel>) -> ResultType<BytesMut> {
+    match channel {
+        Some(channel) => channel.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_proto::{message, Clipboard, Message as ProtoMessage, TestDelay};
+
+    #[tokio::test]
+    async fn test_reliable_channel() {
+        let key = secretbox::gen_key();
+        let token = vec![1, 2, 3, 4];
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut listener = ReliableChannel::listen(socket, token.clone(), key.clone());
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut intruder = ReliableChannel::connect(socket, addr, vec![4, 3, 2, 1], key.clone());
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut connector = ReliableChannel::connect(socket, addr, token, key);
+        let task = tokio::spawn(async move {
+            let mut msgs = Vec::new();
+            while msgs.len() < 2 {
+                msgs.push(connector.next().await.unwrap());
+            }
+            msgs
+        });
+        assert!(crate::timeout(1_000, intruder.next()).await.is_err());
+        crate::timeout(3_000, async {
+            while !listener.is_established() {
+                crate::timeout(10, listener.next()).await.ok();
+            }
+        })
+        .await
+        .unwrap();
+        let mut msg = ProtoMessage::new();
+        msg.set_test_delay(TestDelay {
+            time: 42,
+            ..Default::default()
+        });
+        listener.send(&msg).await.unwrap();
+        let mut msg = ProtoMessage::new();
+        msg.set_clipboard(Clipboard {
+            content: vec![7u8; 10 * MAX_PAYLOAD + 1].into(),
+            ..Default::default()
+        });
+        listener.send(&msg).await.unwrap();
+        let msgs = crate::timeout(3_000, task).await.unwrap().unwrap();
+        match ProtoMessage::parse_from_bytes(&msgs[0]).unwrap().union {
+            Some(message::Union::test_delay(t)) => assert_eq!(t.time, 42),
+            _ => panic!("unexpected message"),
+        }
+        match ProtoMessage::parse_from_bytes(&msgs[1]).unwrap().union {
+            Some(message::Union::clipboard(c)) => assert_eq!(c.content.len(), 10 * MAX_PAYLOAD + 1),
+            _ => panic!("unexpected message"),
+        }
+        // the acks arrive
+        crate::timeout(10, listener.next()).await.ok();
+        assert!(listener.in_flight.is_empty());
+        assert!(listener.srtt().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fec_recovery() {
+        let key = secretbox::gen_key();
+        let mut sink = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let sink_addr = sink.local_addr().unwrap();
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut sender = ReliableChannel::connect(socket, sink_addr, vec![], key.clone());
+        sender.state = State::Established;
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut receiver = ReliableChannel::listen(socket, vec![], key);
+        receiver.peer = Some(sink_addr);
+        receiver.state = State::Established;
+        let content: Vec<u8> = (0..5 * MAX_PAYLOAD).map(|i| i as u8).collect();
+        let mut msg = ProtoMessage::new();
+        msg.set_clipboard(Clipboard {
+            content: content.clone(),
+            ..Default::default()
+        });
+        sender.send_fec(&msg, 0.5).await.unwrap();
+        let mut packets = Vec::new();
+        while let Some(Ok((packet, _))) = sink.next_timeout(100).await {
+            packets.push(packet);
+        }
+        // 6 data + 3 parity
+        assert_eq!(packets.len(), 9);
+        for (i, packet) in packets.into_iter().enumerate() {
+            if i == 1 || i == 4 {
+                continue;
+            }
+            receiver.on_datagram(packet, sink_addr).await.unwrap();
+        }
+        let bytes = receiver.ready.pop_front().unwrap();
+        match ProtoMessage::parse_from_bytes(&bytes).unwrap().union {
+            Some(message::Union::clipboard(c)) => {
+                assert_eq!(c.content, content)
+            }
+            _ => panic!("unexpected message"),
+        }
+        assert!(receiver.fec_groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fec_groups_bounded() {
+        let key = secretbox::gen_key();
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut receiver = ReliableChannel::listen(socket, vec![], key);
+        receiver.peer = Some(peer);
+        receiver.state = State::Established;
+        let parity = |first_seq: u32| {
+            let mut packet = BytesMut::with_capacity(7 + SHARD_LEN);
+            packet.put_u8(KIND_PARITY);
+            packet.put_u32(first_seq);
+            packet.put_u8(FEC_GROUP as _);
+            packet.put_u8(0);
+            packet.put_slice(&[0u8; SHARD_LEN]);
+            packet
+        };
+        // the end of the group past u32::MAX
+        receiver
+            .on_datagram(parity(u32::MAX - 1), peer)
+            .await
+            .unwrap();
+        for i in 0..2 * MAX_FEC_GROUPS as u32 {
+            receiver.on_datagram(parity(i * 2), peer).await.unwrap();
+        }
+        assert_eq!(receiver.fec_groups.len(), MAX_FEC_GROUPS);
+        assert!(!receiver.fec_groups.contains_key(&0));
+    }
+}
@@ -0,0 +1,118 @@
+// The messages waiting to be sent to a stream, taken by priority, the input
+// first, then the clipboard and the others, the blocks of the files and the
+// print jobs last, so that a transfer does not hold back the rest. The queue
+// is bounded by the bytes in it, `is_full` the signal for its producers to
+// wait, e.g. not to read more of a file, before the queue is drained.
+use crate::message_proto::{message, Message};
+use std::collections::VecDeque;
+
+pub const DEFAULT_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk = 0,
+    Normal = 1,
+    Input = 2,
+}
+
+impl Priority {
+    pub fn of(msg: &Message) -> Self {
+        match &msg.union {
+            Some(message::Union::mouse_event(_))
+            | Some(message::Union::key_event(_))
+            | Some(message::Union::touch_event(_))
+            | Some(message::Union::pen_event(_))
+            | Some(message::Union::gamepad_state(_))
+            | Some(message::Union::cursor_data(_))
+            | Some(message::Union::cursor_position(_))
+            | Some(message::Union::cursor_id(_))
+            | Some(message::Union::cursor_delta(_))
+            | Some(message::Union::annotation(_))
+            | Some(message::Union::test_delay(_)) => Self::Input,
+            Some(message::Union::file_response(_)) | Some(message::Union::print_job(_)) => {
+                Self::Bulk
+            }
+            _ => Self::Normal,
+        }
+    }
+}
+
+pub struct SendQueue<T> {
+    // by priority, the lowest first
+    queues: [VecDeque<(T, usize)>; 3],
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            queues: Default::default(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Queues `item` of `size` bytes, false if the queue is full after it,
+    /// the item kept anyway, for the producer to wait.
+    pub fn push(&mut self, priority: Priority, size: usize, item: T) -> bool {
+        self.queues[priority as usize].push_back((item, size));
+        self.bytes += size;
+        !self.is_full()
+    }
+
+    /// The oldest item of the highest priority.
+    pub fn pop(&mut self) -> Option<T> {
+        let (item, size) = self.queues.iter_mut().rev().find_map(|q| q.pop_front())?;
+        self.bytes -= size;
+        Some(item)
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.bytes >= self.max_bytes
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl<T> Default for SendQueue<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority() {
+        let mut q = SendQueue::new(10);
+        assert!(q.push(Priority::Bulk, 4, "block"));
+        assert!(q.push(Priority::Normal, 1, "clipboard"));
+        assert!(q.push(Priority::Input, 1, "mouse"));
+        assert!(!q.push(Priority::Bulk, 4, "block2"));
+        assert!(q.is_full());
+        assert_eq!(q.pop(), Some("mouse"));
+        assert_eq!(q.pop(), Some("clipboard"));
+        assert_eq!(q.pop(), Some("block"));
+        assert!(!q.is_full());
+        assert_eq!(q.pop(), Some("block2"));
+        assert!(q.is_empty() && q.bytes() == 0 && q.pop().is_none());
+        let mut msg = Message::new();
+        msg.set_mouse_event(Default::default());
+        assert_eq!(Priority::of(&msg), Priority::Input);
+        msg.set_file_response(Default::default());
+        assert_eq!(Priority::of(&msg), Priority::Bulk);
+        assert_eq!(Priority::of(&Message::new()), Priority::Normal);
+    }
+}
@@ -1,14 +1,23 @@
 use crate::{
-    config::{Config, NetworkType},
+    bail,
+    config::{Config, NetworkType, ProxyType},
     tcp::FramedStream,
     udp::FramedSocket,
     ResultType,
 };
 use anyhow::Context;
-use std::net::SocketAddr;
-use tokio::net::ToSocketAddrs;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::{lookup_host, ToSocketAddrs};
 use tokio_socks::{IntoTargetAddr, TargetAddr};
 
+// RFC 8305, the next address tried if the one before has not connected by then
+const CONNECTION_ATTEMPT_DELAY: u64 = 250;
+
 fn to_socket_addr(host: &str) -> ResultType<SocketAddr> {
     use std::net::ToSocketAddrs;
     host.to_socket_addrs()?.next().context("Failed to solve")
@@ -16,13 +25,50 @@ fn to_socket_addr(host: &str) -> ResultType<SocketAddr> {
 
 pub fn get_target_addr(host: &str) -> ResultType<TargetAddr<'static>> {
     let addr = match Config::get_network_type() {
-        NetworkType::Direct => to_socket_addr(&host)?.into_target_addr()?,
+        // udp does not go through a http proxy, so resolve locally as direct
+        NetworkType::Direct | NetworkType::ProxyHttp => {
+            to_socket_addr(&host)?.into_target_addr()?
+        }
         NetworkType::ProxySocks => host.into_target_addr()?,
     }
     .to_owned();
     Ok(addr)
 }
 
+/// Like `get_target_addr`, but a host name is resolved with DNS over HTTPS
+/// first if the `use-doh` option is set, falling back to the system resolver.
+pub async fn resolve_target_addr(host: &str) -> ResultType<TargetAddr<'static>> {
+    if Config::get_network_type() != NetworkType::ProxySocks && crate::doh::is_enabled() {
+        if let Some(addr) = resolve_doh(host).await {
+            return Ok(addr.into_target_addr()?.to_owned());
+        }
+    }
+    get_target_addr(host)
+}
+
+async fn resolve_doh(host: &str) -> Option<SocketAddr> {
+    if host.parse::<SocketAddr>().is_ok() {
+        return None;
+    }
+    let i = host.rfind(':')?;
+    let port: u16 = host[i + 1..].parse().ok()?;
+    let name = &host[..i];
+    if name.parse::<std::net::IpAddr>().is_ok() {
+        return None;
+    }
+    match crate::doh::resolve(name).await {
+        Ok(ips) => ips.first().map(|ip| SocketAddr::new(*ip, port)),
+        Err(err) => {
+            log::info!(
+                "DNS over HTTPS failed for {}, use system DNS: {}",
+                name,
+                err
+            );
+            None
+        }
+    }
+}
+
 pub fn test_if_valid_server(host: &str) -> String {
     let mut host = host.to_owned();
     if !host.contains(":") {
@@ -30,7 +76,7 @@ pub fn test_if_valid_server(host: &str) -> String {
     }
 
     match Config::get_network_type() {
-        NetworkType::Direct => match to_socket_addr(&host) {
+        NetworkType::Direct | NetworkType::ProxyHttp => match to_socket_addr(&host) {
             Err(err) => err.to_string(),
             Ok(_) => "".to_owned(),
         },
@@ -48,30 +94,167 @@ pub async fn connect_tcp<'t, T: IntoTargetAddr<'t>>(
 ) -> ResultType<FramedStream> {
     let target_addr = target.into_target_addr()?;
 
-    if let Some(conf) = Config::get_socks() {
-        FramedStream::connect(
-            conf.proxy.as_str(),
-            target_addr,
-            local,
-            conf.username.as_str(),
-            conf.password.as_str(),
-            ms_timeout,
-        )
-        .await
+    #[cfg(feature = "websocket")]
+    if Config::get_option("use-websocket") == "Y" {
+        return crate::ws::connect(&target_addr, ms_timeout).await;
+    }
+
+    let res = if let Some(conf) = Config::get_socks() {
+        match conf.proxy_type() {
+            ProxyType::Socks5 => {
+                FramedStream::connect(
+                    conf.proxy_addr(),
+                    target_addr.to_owned(),
+                    local,
+                    conf.username.as_str(),
+                    conf.password.as_str(),
+                    ms_timeout,
+                )
+                .await
+            }
+            t => {
+                FramedStream::connect_http(
+                    conf.proxy_addr(),
+                    t == ProxyType::Https,
+                    target_addr.to_owned(),
+                    local,
+                    conf.username.as_str(),
+                    conf.password.as_str(),
+                    ms_timeout,
+                )
+                .await
+            }
+        }
+    } else {
+        match resolve_all(&target_addr).await {
+            Ok(addrs) => connect_any(addrs, local, ms_timeout).await,
+            Err(err) => Err(err),
+        }
+    };
+    #[cfg(feature = "websocket")]
+    if let Err(err) = &res {
+        if crate::ws::is_ws_fallback_target(&target_addr) {
+            log::info!("tcp to {} failed ({}), trying websocket", target_addr, err);
+            return crate::ws::connect(&target_addr, ms_timeout).await;
+        }
+    }
+    res
+}
+
+/// All the addresses of `target`, ipv4 and ipv6, resolved without blocking, with
+/// DNS over HTTPS first if enabled.
+async fn resolve_all(target: &TargetAddr<'_>) -> ResultType<Vec<SocketAddr>> {
+    let (name, port) = match target {
+        TargetAddr::Ip(addr) => return Ok(vec![*addr]),
+        TargetAddr::Domain(name, port) => (name, *port),
+    };
+    if crate::doh::is_enabled() && name.parse::<IpAddr>().is_err() {
+        match crate::doh::resolve(name).await {
+            Ok(ips) => return Ok(ips.into_iter().map(|ip| (ip, port).into()).collect()),
+            Err(err) => log::info!(
+                "DNS over HTTPS failed for {}, use system DNS: {}",
+                name,
+                err
+            ),
+        }
+    }
+    let addrs: Vec<_> = lookup_host((name.as_ref(), port)).await?.collect();
+    if addrs.is_empty() {
+        bail!("Failed to resolve {}", name);
+    }
+    Ok(addrs)
+}
+
+// the families alternated, the one of the first address first, as the
+// resolver has sorted them
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(|x| x.is_ipv6()).unwrap_or_default();
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|x| x.is_ipv6() == first_v6);
+    let mut res = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        res.extend(first.pop_front());
+        res.extend(second.pop_front());
+    }
+    res.dedup();
+    res
+}
+
+// `local` of the family of `remote`, None if bound to an address of the other
+fn get_local_addr(local: SocketAddr, remote: &SocketAddr) -> Option<SocketAddr> {
+    if local.is_ipv6() == remote.is_ipv6() {
+        return Some(local);
+    }
+    if !local.ip().is_unspecified() {
+        return None;
+    }
+    let ip: IpAddr = if remote.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
     } else {
-        let addr = std::net::ToSocketAddrs::to_socket_addrs(&target_addr)?
-            .next()
-            .context("Invalid target addr")?;
-        Ok(FramedStream::new(addr, local, ms_timeout).await?)
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    Some(SocketAddr::new(ip, local.port()))
+}
+
+/// Happy eyeballs (RFC 8305): the addresses connected to in turn, each once
+/// the one before has failed or CONNECTION_ATTEMPT_DELAY after it, the first
+/// stream connected returned and the other attempts dropped, so that a
+/// broken ipv6 or ipv4 does not stall the connection.
+pub async fn connect_any(
+    addrs: Vec<SocketAddr>,
+    local: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    let mut addrs: VecDeque<_> = interleave(addrs)
+        .into_iter()
+        .filter_map(|x| get_local_addr(local, &x).map(|local| (x, local)))
+        .collect();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if let Some((remote, local)) = addrs.pop_front() {
+            attempts.push(FramedStream::new(remote, local, ms_timeout));
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        let delay = tokio::time::sleep(Duration::from_millis(CONNECTION_ATTEMPT_DELAY));
+        tokio::select! {
+            Some(res) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            },
+            _ = delay, if !addrs.is_empty() => {}
+        }
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => bail!("No address to connect to from {}", local),
     }
 }
 
+/// TCP simultaneous open with `peer`, who connects to us at the same time,
+/// see `FramedStream::punch`. A proxy can not punch, so it's a plain connect there.
+pub async fn punch_tcp(
+    peer: SocketAddr,
+    local: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    #[cfg(feature = "websocket")]
+    if Config::get_option("use-websocket") == "Y" {
+        return connect_tcp(peer, local, ms_timeout).await;
+    }
+    if Config::get_socks().is_some() {
+        return connect_tcp(peer, local, ms_timeout).await;
+    }
+    FramedStream::punch(peer, local, ms_timeout).await
+}
+
 pub async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
     match Config::get_socks() {
-        None => Ok(FramedSocket::new(local).await?),
-        Some(conf) => {
+        Some(conf) if conf.proxy_type() == ProxyType::Socks5 => {
             let socket = FramedSocket::new_proxy(
-                conf.proxy.as_str(),
+                conf.proxy_addr(),
                 local,
                 conf.username.as_str(),
                 conf.password.as_str(),
@@ -80,12 +263,47 @@ pub async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<
             .await?;
             Ok(socket)
         }
+        _ => Ok(FramedSocket::new(local).await?),
     }
 }
 
 pub async fn rebind_udp<T: ToSocketAddrs>(local: T) -> ResultType<Option<FramedSocket>> {
     match Config::get_network_type() {
-        NetworkType::Direct => Ok(Some(FramedSocket::new(local).await?)),
+        NetworkType::Direct | NetworkType::ProxyHttp => Ok(Some(FramedSocket::new(local).await?)),
         _ => Ok(None),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |x| SocketAddr::new(Ipv4Addr::new(10, 0, 0, x).into(), 1);
+        let v6 = |x| SocketAddr::new(Ipv6Addr::new(1, 0, 0, 0, 0, 0, 0, x).into(), 1);
+        let addrs = interleave(vec![v6(1), v6(2), v6(3), v4(1), v4(2)]);
+        assert_eq!(addrs, vec![v6(1), v4(1), v6(2), v4(2), v6(3)]);
+        let any = Config::get_any_listen_addr();
+        assert_eq!(
+            get_local_addr(any, &v6(1)).unwrap().ip(),
+            Ipv6Addr::UNSPECIFIED
+        );
+        assert!(get_local_addr(v4(2), &v6(1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect_any() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // nothing listens on the port just freed
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let any = Config::get_any_listen_addr();
+        let stream = connect_any(vec![closed, addr], any, 3_000).await.unwrap();
+        assert_eq!(stream.local_addr().ip(), addr.ip());
+        assert!(connect_any(vec![closed], any, 3_000).await.is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{bail, bytes_codec::BytesCodec, ResultType};
+use crate::{
+    allow_err, bail, bytes_codec::BytesCodec, keepalive::Keepalive, rate_limiter::TokenBucket,
+    ResultType,
+};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protobuf::Message;
@@ -11,12 +14,16 @@ use std::{
     task::{Context, Poll},
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs},
 };
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, ToProxyAddrs};
+use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, TargetAddr, ToProxyAddrs};
 use tokio_util::codec::Framed;
 
+// a SYN unanswered this long was dropped, try again
+const PUNCH_ATTEMPT_TIMEOUT: u64 = 500;
+const PUNCH_INTERVAL: u64 = 50;
+
 pub trait TcpStreamTrait: AsyncRead + AsyncWrite + Unpin {}
 pub struct DynTcpStream(Box<dyn TcpStreamTrait + Send>);
 
@@ -25,6 +32,8 @@ pub struct FramedStream(
     SocketAddr,
     Option<(Key, u64, u64)>,
     u64,
+    Option<TokenBucket>,
+    Keepalive,
 );
 
 impl Deref for FramedStream {
@@ -91,6 +100,8 @@ impl FramedStream {
                     addr,
                     None,
                     0,
+                    None,
+                    Keepalive::default(),
                 ));
             }
         }
@@ -135,26 +146,136 @@ impl FramedStream {
                     addr,
                     None,
                     0,
+                    None,
+                    Keepalive::default(),
                 ));
             };
         };
         bail!("could not resolve to any address");
     }
 
+    /// Connect through a HTTP CONNECT proxy, with TLS to the proxy itself if `tls`.
+    pub async fn connect_http<'t, T1, T2>(
+        proxy: &str,
+        tls: bool,
+        target: T1,
+        local: T2,
+        username: &str,
+        password: &str,
+        ms_timeout: u64,
+    ) -> ResultType<Self>
+    where
+        T1: IntoTargetAddr<'t>,
+        T2: ToSocketAddrs,
+    {
+        let target = match target.into_target_addr()? {
+            TargetAddr::Ip(addr) => addr.to_string(),
+            TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+        };
+        if let Some(local) = lookup_host(&local).await?.next() {
+            if let Some(proxy_addr) = lookup_host(proxy).await?.next() {
+                let stream =
+                    super::timeout(ms_timeout, new_socket(local, true)?.connect(proxy_addr))
+                        .await??;
+                let addr = stream.local_addr()?;
+                let stream: Box<dyn TcpStreamTrait + Send> = if tls {
+                    Box::new(super::timeout(ms_timeout, tls_connect(proxy, stream)).await??)
+                } else {
+                    Box::new(stream)
+                };
+                let mut stream = DynTcpStream(stream);
+                super::timeout(
+                    ms_timeout,
+                    http_connect(&mut stream, &target, username, password),
+                )
+                .await??;
+                return Ok(Self(
+                    Framed::new(stream, BytesCodec::new()),
+                    addr,
+                    None,
+                    0,
+                    None,
+                    Keepalive::default(),
+                ));
+            }
+        }
+        bail!("could not resolve to any address");
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.1
     }
 
+    /// TCP simultaneous open: connect from `local_addr`, the port the peer was told
+    /// about, to `peer` again and again until an attempt gets through or `ms_timeout`
+    /// passes. With both sides doing this at the same time, each SYN opens the NAT
+    /// mapping for the other side's, which gets through NATs dropping unsolicited SYNs.
+    pub async fn punch(
+        peer: SocketAddr,
+        local_addr: SocketAddr,
+        ms_timeout: u64,
+    ) -> ResultType<Self> {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(ms_timeout);
+        let mut attempts = 0;
+        loop {
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                bail!("tcp punch to {} failed after {} attempts", peer, attempts);
+            }
+            let left = (deadline - now).as_millis() as u64;
+            attempts += 1;
+            match Self::new(peer, local_addr, left.min(PUNCH_ATTEMPT_TIMEOUT)).await {
+                Ok(stream) => {
+                    log::debug!(
+                        "tcp punch to {} succeeded after {} attempts",
+                        peer,
+                        attempts
+                    );
+                    return Ok(stream);
+                }
+                // refused or reset until the peer's SYN opened its side
+                Err(_) => {
+                    tokio::time::sleep(std::time::Duration::from_millis(PUNCH_INTERVAL)).await
+                }
+            }
+        }
+    }
+
+    /// The largest frame taken from the peer, `bytes_codec::DEFAULT_MAX_FRAME_SIZE`
+    /// by default.
+    pub fn set_max_frame_size(&mut self, n: usize) {
+        self.0.codec_mut().set_max_packet_length(n);
+    }
+
+    /// The pings of the protocol on this stream, `next` counting as received
+    /// whatever frame came.
+    #[inline]
+    pub fn keepalive(&mut self) -> &mut Keepalive {
+        &mut self.5
+    }
+
     pub fn set_send_timeout(&mut self, ms: u64) {
         self.3 = ms;
     }
 
+    /// Cap the send rate of this stream on top of the process wide
+    /// `max-bandwidth-kbps`, 0 to remove.
+    pub fn set_rate_limit(&mut self, kbps: u32) {
+        self.4 = if kbps > 0 {
+            Some(TokenBucket::new(kbps))
+        } else {
+            None
+        };
+    }
+
     pub fn from(stream: impl TcpStreamTrait + Send + 'static, addr: SocketAddr) -> Self {
         Self(
             Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
             addr,
             None,
             0,
+            None,
+            Keepalive::default(),
         )
     }
 
@@ -184,8 +305,45 @@ impl FramedStream {
         Ok(())
     }
 
+    /// As `send`, serialized into a buffer of `buffer_pool` and sealed in place,
+    /// the buffer given back once copied into the write buffer of the stream,
+    /// so that sending does not allocate, e.g. for the frames of the video.
+    pub async fn send_into(&mut self, msg: &impl Message) -> ResultType<()> {
+        let mut buf = crate::buffer_pool::get();
+        // the tag of the box before the message, as secretbox::seal puts it
+        let head = if self.2.is_some() {
+            secretbox::MACBYTES
+        } else {
+            0
+        };
+        buf.resize(head, 0);
+        if let Err(err) = msg.write_to_vec(&mut buf) {
+            crate::buffer_pool::put(buf);
+            return Err(err.into());
+        }
+        if let Some(key) = self.2.as_mut() {
+            key.1 += 1;
+            let nonce = Self::get_nonce(key.1);
+            let tag = secretbox::seal_detached(&mut buf[head..], &nonce, &key.0);
+            buf[..head].copy_from_slice(&tag.0);
+        }
+        self.acquire(buf.len()).await;
+        let codec = *self.0.codec();
+        let res = codec.encode_slice(&buf, self.0.write_buffer_mut());
+        crate::buffer_pool::put(buf);
+        res?;
+        let flush = SinkExt::<Bytes>::flush(&mut self.0);
+        if self.3 > 0 {
+            super::timeout(self.3, flush).await??;
+        } else {
+            flush.await?;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
+        self.acquire(bytes.len()).await;
         if self.3 > 0 {
             super::timeout(self.3, self.0.send(bytes)).await??;
         } else {
@@ -194,9 +352,20 @@ impl FramedStream {
         Ok(())
     }
 
+    // of the rate limits, of the stream and of all
+    async fn acquire(&mut self, len: usize) {
+        if let Some(bucket) = self.4.as_mut() {
+            bucket.acquire(len).await;
+        }
+        crate::rate_limiter::acquire_global(len).await;
+    }
+
     #[inline]
     pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
         let mut res = self.0.next().await;
+        if let Some(Ok(_)) = res {
+            self.5.on_recv();
+        }
         if let Some(key) = self.2.as_mut() {
             if let Some(Ok(bytes)) = res.as_mut() {
                 key.2 += 1;
@@ -228,6 +397,11 @@ impl FramedStream {
         self.2 = Some((key, 0, 0));
     }
 
+    /// The session key once secured, to seal side channels to the same peer.
+    pub fn get_key(&self) -> Option<Key> {
+        self.2.as_ref().map(|key| key.0.clone())
+    }
+
     fn get_nonce(seqnum: u64) -> Nonce {
         let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
         nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
@@ -235,6 +409,79 @@ impl FramedStream {
     }
 }
 
+/// `host` may have its port, e.g. "example.com:443" or "[::1]:443".
+pub(crate) async fn tls_connect(
+    host: &str,
+    stream: tokio::net::TcpStream,
+) -> ResultType<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        allow_err!(roots.add(&rustls::Certificate(cert.0)));
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = get_server_name(host)?;
+    let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+    Ok(connector.connect(name, stream).await?)
+}
+
+// the host of "host", "host:port", "ip", "ip:port" or "[ipv6]:port"
+fn get_server_name(authority: &str) -> ResultType<rustls::ServerName> {
+    use std::convert::TryFrom;
+    let host = if let Some(rest) = authority.strip_prefix('[') {
+        rest.split(']').next().unwrap_or_default()
+    } else if authority.parse::<std::net::IpAddr>().is_ok() {
+        authority
+    } else {
+        authority.rsplitn(2, ':').last().unwrap_or(authority)
+    };
+    if let Ok(ip) = host.parse() {
+        return Ok(rustls::ServerName::IpAddress(ip));
+    }
+    Ok(rustls::ServerName::try_from(host)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid tls host name"))?)
+}
+
+const MAX_HTTP_RESPONSE_HEAD: usize = 8192;
+
+async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &str,
+    username: &str,
+    password: &str,
+) -> ResultType<()> {
+    let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if !username.trim().is_empty() {
+        req += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(format!("{}:{}", username, password))
+        );
+    }
+    req += "\r\n";
+    stream.write_all(req.as_bytes()).await?;
+    // read byte by byte, we must not consume anything after the response head
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+            bail!("Too long response from http proxy");
+        }
+        let mut c = [0u8; 1];
+        if stream.read(&mut c).await? == 0 {
+            bail!("http proxy closed the connection");
+        }
+        head.push(c[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        Some("407") => bail!("http proxy authentication required"),
+        _ => bail!("http proxy refused: {}", status),
+    }
+}
+
 const DEFAULT_BACKLOG: u32 = 128;
 
 #[allow(clippy::never_loop)]
@@ -281,3 +528,104 @@ impl AsyncWrite for DynTcpStream {
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> TcpStreamTrait for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        use std::convert::TryFrom;
+        let ip = |s: &str| rustls::ServerName::IpAddress(s.parse().unwrap());
+        let dns = |s: &str| rustls::ServerName::try_from(s).unwrap();
+        assert_eq!(get_server_name("[::1]:443").unwrap(), ip("::1"));
+        assert_eq!(get_server_name("::1").unwrap(), ip("::1"));
+        assert_eq!(get_server_name("1.2.3.4:443").unwrap(), ip("1.2.3.4"));
+        assert_eq!(
+            get_server_name("proxy.example.com:443").unwrap(),
+            dns("proxy.example.com")
+        );
+        assert_eq!(get_server_name("dns.google").unwrap(), dns("dns.google"));
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let n = proxy.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).to_string();
+            proxy
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            req
+        });
+        http_connect(&mut client, "1.2.3.4:21116", "user", "pass")
+            .await
+            .unwrap();
+        let req = server.await.unwrap();
+        assert!(req.starts_with("CONNECT 1.2.3.4:21116 HTTP/1.1\r\n"));
+        assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+        // data after the response head is left for the tunnel
+        let mut rest = [0u8; 5];
+        client.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_refused() {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            proxy.read(&mut buf).await.unwrap();
+            proxy
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        assert!(http_connect(&mut client, "example.com:21116", "", "")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_punch() {
+        // the peer only starts listening after a few refused attempts
+        let listener = new_listener("127.0.0.1:0", true).await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        drop(listener);
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            let listener = new_listener(peer, true).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        assert!(FramedStream::punch(peer, local, 3_000).await.is_ok());
+        server.await.unwrap();
+        assert!(FramedStream::punch(peer, local, 200).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_into() {
+        use crate::message_proto::{message, Message as ProtoMessage, TestDelay};
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (mut a, mut b) = (FramedStream::from(a, addr), FramedStream::from(b, addr));
+        let key = secretbox::gen_key();
+        a.set_key(key.clone());
+        b.set_key(key);
+        let mut msg = ProtoMessage::new();
+        msg.set_test_delay(TestDelay {
+            time: 1,
+            ..Default::default()
+        });
+        a.send_into(&msg).await.unwrap();
+        a.send(&msg).await.unwrap();
+        for _ in 0..2 {
+            let bytes = b.next().await.unwrap().unwrap();
+            let res = ProtoMessage::parse_from_bytes(&bytes).unwrap();
+            assert!(matches!(res.union, Some(message::Union::test_delay(x)) if x.time == 1));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// TOTP (RFC 6238) second factor of the incoming connections, with the
+// defaults of the authenticator apps: HMAC-SHA1, 6 digits and 30 seconds.
+use crate::config::Config;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+// the steps before and after the current one accepted, for the clock drift
+const SKEW: u64 = 1;
+const SECRET_LEN: usize = 20;
+const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+lazy_static::lazy_static! {
+    // the last step a code was accepted for, so that a code is used only once
+    static ref LAST_STEP: Mutex<u64> = Default::default();
+}
+
+/// A new random secret, base32 encoded.
+pub fn generate_secret() -> String {
+    let secret: Vec<u8> = (0..SECRET_LEN).map(|_| rand::random::<u8>()).collect();
+    base32_encode(&secret)
+}
+
+/// The otpauth url to provision an authenticator app with, e.g. by a QR code.
+pub fn get_url(secret: &str, id: &str) -> String {
+    format!(
+        "otpauth://totp/RustDesk:{}?secret={}&issuer=RustDesk&digits={}&period={}",
+        id, secret, DIGITS, STEP
+    )
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::is_totp_enabled()
+}
+
+/// With "require-2fa", e.g. of the policy, no password is accepted until the 2FA is set up.
+pub fn is_required() -> bool {
+    Config::get_option("require-2fa") == "Y"
+}
+
+/// Check `code` against the configured secret, each code is accepted once.
+pub fn verify_code(code: &str) -> bool {
+    let secret = Config::get_totp_secret();
+    match find_step(&secret, code, now()) {
+        Some(step) => {
+            let mut last = LAST_STEP.lock().unwrap();
+            if step <= *last {
+                return false;
+            }
+            *last = step;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Check `code` against `secret` at the current time, e.g. before it is saved.
+pub fn verify(secret: &str, code: &str) -> bool {
+    find_step(secret, code, now()).is_some()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+fn find_step(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let code = code.trim();
+    if key.is_empty() || code.len() != DIGITS as usize {
+        return None;
+    }
+    let step = now / STEP;
+    (step.saturating_sub(SKEW)..=step + SKEW).find(|s| get_code(&key, *s) == code)
+}
+
+fn get_code(key: &[u8], step: u64) -> String {
+    let mut mac = match Hmac::<Sha1>::new_from_slice(key) {
+        Ok(mac) => mac,
+        Err(_) => return "".to_owned(),
+    };
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let v = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!("{:0width$}", v % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for b in data {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+// spaces, padding and lower case allowed, as typed from an app
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        if c == b' ' || c == b'=' {
+            continue;
+        }
+        let v = BASE32.iter().position(|x| *x == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totp() {
+        // the SHA1 vectors of RFC 6238
+        let key = b"12345678901234567890";
+        assert_eq!(get_code(key, 59 / STEP), "287082");
+        assert_eq!(get_code(key, 1111111109 / STEP), "081804");
+        assert_eq!(get_code(key, 2000000000 / STEP), "279037");
+        let secret = base32_encode(key);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&secret.to_lowercase()).unwrap(), key);
+        assert_eq!(find_step(&secret, "081804", 1111111109), Some(37037036));
+        assert_eq!(
+            find_step(&secret, "081804", 1111111109 + STEP),
+            Some(37037036)
+        );
+        assert_eq!(find_step(&secret, "081804", 1111111109 + 2 * STEP), None);
+        assert_eq!(find_step(&secret, "81804", 1111111109), None);
+        assert_eq!(find_step("", "081804", 1111111109), None);
+        assert_eq!(generate_secret().len(), 32);
+    }
+}
@@ -1,19 +1,102 @@
-use crate::{bail, ResultType};
+use crate::{allow_err, bail, ResultType};
 use anyhow::anyhow;
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protobuf::Message;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
 use tokio::net::{ToSocketAddrs, UdpSocket};
 use tokio_socks::{udp::Socks5UdpFramed, IntoTargetAddr, TargetAddr, ToProxyAddrs};
 use tokio_util::{codec::BytesCodec, udp::UdpFramed};
 
+/// Outcome of a receive which tells a momentary lack of data apart from
+/// a socket (or proxy association) which has ended.
+#[derive(Debug)]
+pub enum RecvResult {
+    Datagram(BytesMut, TargetAddr<'static>),
+    WouldBlock,
+    Closed,
+}
+
 pub enum FramedSocket {
     Direct(UdpFramed<BytesCodec>),
     ProxySocks(Socks5UdpFramed),
 }
 
+// 0 is never a valid protobuf field tag, so a traced datagram can not be
+// mistaken for a plain message
+const TRACE_MAGIC: [u8; 2] = [0, b'T'];
+const TRACE_HEADER_LEN: usize = 10;
+// the peers are told again with each of their messages, e.g. the pings of
+// the lan discovery from a new port each time
+const MAX_TRACE_PEERS: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref TRACE_PEERS: std::sync::Mutex<std::collections::HashSet<SocketAddr>> =
+        Default::default();
+}
+
+/// Whether `peer` understands the trace header, as it tells with the `trace`
+/// flag of its discovery and registration messages. Only the datagrams of such
+/// peers are sent with or looked for a trace header, the others go through
+/// untouched.
+pub fn set_trace_peer(peer: &TargetAddr, v: bool) {
+    let peer = match peer {
+        TargetAddr::Ip(peer) => *peer,
+        _ => return,
+    };
+    let mut peers = TRACE_PEERS.lock().unwrap();
+    if v {
+        if peers.len() >= MAX_TRACE_PEERS {
+            peers.clear();
+        }
+        peers.insert(peer);
+    } else {
+        peers.remove(&peer);
+    }
+}
+
+fn is_trace_peer(addr: &TargetAddr) -> bool {
+    match addr {
+        TargetAddr::Ip(addr) => TRACE_PEERS.lock().unwrap().contains(addr),
+        _ => false,
+    }
+}
+
+// the stages of a traced datagram, kept for the tests too
+fn log_trace(trace_id: u64, stage: String) {
+    log::info!("trace {}: {}", trace_id, stage);
+    #[cfg(test)]
+    tests::TRACES
+        .lock()
+        .unwrap()
+        .push(format!("trace {}: {}", trace_id, stage));
+}
+
+fn strip_trace_header(data: &mut BytesMut) -> Option<u64> {
+    if data.len() < TRACE_HEADER_LEN || data[..2] != TRACE_MAGIC {
+        return None;
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&data[2..TRACE_HEADER_LEN]);
+    data.advance(TRACE_HEADER_LEN);
+    Some(u64::from_le_bytes(id))
+}
+
+// the end of the stream, e.g. of a proxy association, is `Closed`
+fn to_recv_result(
+    res: Option<ResultType<(BytesMut, TargetAddr<'static>)>>,
+) -> ResultType<RecvResult> {
+    match res {
+        None => Ok(RecvResult::Closed),
+        Some(Ok((data, addr))) => Ok(RecvResult::Datagram(data, addr)),
+        Some(Err(err)) => Err(err),
+    }
+}
+
 fn new_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, std::io::Error> {
     let socket = match addr {
         SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::dgram(), None),
@@ -28,12 +111,59 @@ fn new_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, std::io::Error> {
         socket.set_reuse_address(true)?;
     }
     socket.bind(&addr.into())?;
+    #[cfg(windows)]
+    disable_connreset(&socket)?;
     Ok(socket)
 }
 
+#[cfg(windows)]
+static IGNORE_CONNRESET: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+
+/// Whether to turn off WSAECONNRESET reporting on new udp sockets (on by default).
+#[cfg(windows)]
+pub fn set_ignore_connreset(v: bool) {
+    IGNORE_CONNRESET.store(v, std::sync::atomic::Ordering::Relaxed);
+}
+
+// On Windows, an ICMP port unreachable from a previous send makes recv_from fail
+// with WSAECONNRESET (10054) even on an unconnected socket, which would end
+// the receive loop because of one bad peer.
+#[cfg(windows)]
+fn disable_connreset(socket: &impl std::os::windows::io::AsRawSocket) -> std::io::Result<()> {
+    use winapi::{
+        shared::minwindef::{BOOL, DWORD, FALSE},
+        um::winsock2::{WSAGetLastError, WSAIoctl, SOCKET, SOCKET_ERROR},
+    };
+    const SIO_UDP_CONNRESET: DWORD = 0x9800000C;
+    if !IGNORE_CONNRESET.load(std::sync::atomic::Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut enable: BOOL = FALSE;
+    let mut returned: DWORD = 0;
+    let res = unsafe {
+        WSAIoctl(
+            socket.as_raw_socket() as SOCKET,
+            SIO_UDP_CONNRESET,
+            &mut enable as *mut _ as _,
+            std::mem::size_of::<BOOL>() as _,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            None,
+        )
+    };
+    if res == SOCKET_ERROR {
+        return Err(std::io::Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+    }
+    Ok(())
+}
+
 impl FramedSocket {
     pub async fn new<T: ToSocketAddrs>(addr: T) -> ResultType<Self> {
         let socket = UdpSocket::bind(addr).await?;
+        #[cfg(windows)]
+        disable_connreset(&socket)?;
         Ok(Self::Direct(UdpFramed::new(socket, BytesCodec::new())))
     }
 
@@ -81,24 +211,98 @@ impl FramedSocket {
     ) -> ResultType<()> {
         let addr = addr.into_target_addr()?.to_owned();
         let send_data = Bytes::from(msg.write_to_bytes()?);
+        #[cfg(feature = "udp_correlate")]
+        correlate::stamp(&send_data);
+        self.send_bytes(send_data, addr).await
+    }
+
+    /// Send with a trace header so that this one datagram is logged at every stage,
+    /// on both ends. The header is only sent to the peers negotiated with
+    /// `set_trace_peer`, to the others the datagram goes plain.
+    pub async fn send_traced(
+        &mut self,
+        msg: &impl Message,
+        addr: impl IntoTargetAddr<'_>,
+        trace_id: u64,
+    ) -> ResultType<()> {
+        let addr = addr.into_target_addr()?.to_owned();
+        let payload = msg.write_to_bytes()?;
+        log_trace(trace_id, format!("serialized {} bytes", payload.len()));
+        if !is_trace_peer(&addr) {
+            log_trace(
+                trace_id,
+                format!("{} has no trace support, sent plain", addr),
+            );
+            return self.send_bytes(Bytes::from(payload), addr).await;
+        }
+        let mut data = BytesMut::with_capacity(TRACE_HEADER_LEN + payload.len());
+        data.put_slice(&TRACE_MAGIC);
+        data.put_u64_le(trace_id);
+        data.put_slice(&payload);
+        let dst = addr.to_string();
+        self.send_bytes(data.freeze(), addr).await?;
+        log_trace(trace_id, format!("sent to {}", dst));
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) async fn send_bytes(
+        &mut self,
+        data: Bytes,
+        addr: TargetAddr<'static>,
+    ) -> ResultType<()> {
+        crate::rate_limiter::acquire_global(data.len()).await;
         let _ = match self {
             Self::Direct(f) => match addr {
-                TargetAddr::Ip(addr) => f.send((send_data, addr)).await?,
+                TargetAddr::Ip(addr) => f.send((data, addr)).await?,
                 _ => unreachable!(),
             },
-            Self::ProxySocks(f) => f.send((send_data, addr)).await?,
+            Self::ProxySocks(f) => f.send((data, addr)).await?,
         };
         Ok(())
     }
 
+    /// Send with the egress interface chosen per datagram via an `IP_PKTINFO` /
+    /// `IPV6_PKTINFO` cmsg instead of binding, e.g. for a relay replying on the
+    /// interface a request arrived on. Linux only.
+    #[cfg(target_os = "linux")]
+    pub async fn send_via(
+        &mut self,
+        msg: &impl Message,
+        dst: SocketAddr,
+        iface_index: u32,
+    ) -> ResultType<()> {
+        use std::os::unix::io::AsRawFd;
+        let data = msg.write_to_bytes()?;
+        crate::rate_limiter::acquire_global(data.len()).await;
+        match self {
+            Self::Direct(f) => {
+                let socket = f.get_ref();
+                loop {
+                    socket.writable().await?;
+                    match socket.try_io(tokio::io::Interest::WRITABLE, || {
+                        sendmsg_via(socket.as_raw_fd(), &data, dst, iface_index)
+                    }) {
+                        Ok(_) => return Ok(()),
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
+            Self::ProxySocks(_) => bail!("send_via is not supported over proxy"),
+        }
+    }
+
     // https://stackoverflow.com/a/68733302/1926020
     #[inline]
     pub async fn send_raw(
         &mut self,
-        msg: &'static [u8],
+        msg: Vec<u8>,
         addr: impl IntoTargetAddr<'static>,
     ) -> ResultType<()> {
         let addr = addr.into_target_addr()?.to_owned();
+        #[cfg(feature = "udp_correlate")]
+        correlate::stamp(&msg);
 
         let _ = match self {
             Self::Direct(f) => match addr {
@@ -110,8 +314,96 @@ impl FramedSocket {
         Ok(())
     }
 
+    /// Clear the higher level framing state on reconnect while keeping the
+    /// underlying socket (same fd, same NAT mapping), unlike `rebind`
+    /// which replaces the socket.
+    pub fn reset(&mut self) {
+        match self {
+            Self::Direct(f) => {
+                *f.codec_mut() = BytesCodec::new();
+                f.read_buffer_mut().clear();
+            }
+            Self::ProxySocks(_) => {}
+        }
+    }
+
+    /// Replace the socket by a new one on the same ip and another port, so that
+    /// the NAT maps it anew, e.g. after the old mapping went stale.
+    pub async fn rebind(&mut self) -> ResultType<()> {
+        match self {
+            Self::Direct(f) => {
+                let mut addr = f.get_ref().local_addr()?;
+                addr.set_port(0);
+                *self = Self::new(addr).await?;
+                Ok(())
+            }
+            Self::ProxySocks(_) => bail!("rebind is not supported over proxy"),
+        }
+    }
+
+    /// Re-issue a multicast join, the OS may silently drop the membership after
+    /// interface events. Returns true if the membership was actually lost.
+    pub fn rejoin_multicast_v4(&self, maddr: Ipv4Addr, interface: Ipv4Addr) -> ResultType<bool> {
+        match self {
+            Self::Direct(f) => match f.get_ref().join_multicast_v4(maddr, interface) {
+                Ok(_) => Ok(true),
+                Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => Ok(false),
+                Err(err) => Err(err.into()),
+            },
+            Self::ProxySocks(_) => bail!("multicast is not supported over proxy"),
+        }
+    }
+
+    /// Leave and join again, for a membership the OS still has but which no
+    /// longer gets the datagrams of the group.
+    pub fn reset_multicast_v4(&self, maddr: Ipv4Addr, interface: Ipv4Addr) -> ResultType<()> {
+        match self {
+            Self::Direct(f) => {
+                let socket = f.get_ref();
+                // not a member anymore is fine
+                socket.leave_multicast_v4(maddr, interface).ok();
+                Ok(socket.join_multicast_v4(maddr, interface)?)
+            }
+            Self::ProxySocks(_) => bail!("multicast is not supported over proxy"),
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Direct(f) => f.get_ref().local_addr().ok(),
+            Self::ProxySocks(f) => f.local_addr().ok(),
+        }
+    }
+
     #[inline]
     pub async fn next(&mut self) -> Option<ResultType<(BytesMut, TargetAddr<'static>)>> {
+        let mut res = self.next_inner().await;
+        if let Some(Ok((data, addr))) = res.as_mut() {
+            if is_trace_peer(addr) {
+                if let Some(trace_id) = strip_trace_header(data) {
+                    log_trace(
+                        trace_id,
+                        format!("received {} bytes from {}", data.len(), addr),
+                    );
+                }
+            }
+        }
+        #[cfg(feature = "udp_correlate")]
+        if let Some(Ok((data, addr))) = res.as_ref() {
+            if let Some((tag, elapsed)) = correlate::lookup(data) {
+                log::debug!(
+                    "udp datagram from {} matches send #{}, {:?} after send",
+                    addr,
+                    tag,
+                    elapsed
+                );
+            }
+        }
+        res
+    }
+
+    #[inline]
+    async fn next_inner(&mut self) -> Option<ResultType<(BytesMut, TargetAddr<'static>)>> {
         match self {
             Self::Direct(f) => match f.next().await {
                 Some(Ok((data, addr))) => {
@@ -128,6 +420,20 @@ impl FramedSocket {
         }
     }
 
+    /// Receive without waiting.
+    pub fn try_next(&mut self) -> ResultType<RecvResult> {
+        use futures::FutureExt;
+        match self.next().now_or_never() {
+            None => Ok(RecvResult::WouldBlock),
+            Some(res) => to_recv_result(res),
+        }
+    }
+
+    /// Like `next`, but `Closed` is explicit instead of `None`.
+    pub async fn next_result(&mut self) -> ResultType<RecvResult> {
+        to_recv_result(self.next().await)
+    }
+
     #[inline]
     pub async fn next_timeout(
         &mut self,
@@ -141,17 +447,446 @@ impl FramedSocket {
             None
         }
     }
+
+    /// Set the don't fragment bit on outgoing datagrams, so that the ones larger
+    /// than the path MTU are dropped instead of fragmented. Linux and Windows only.
+    pub fn set_dont_fragment(&self, v: bool) -> ResultType<()> {
+        match self {
+            Self::Direct(f) => Ok(set_dont_fragment(f.get_ref(), v)?),
+            Self::ProxySocks(_) => bail!("don't fragment is not supported over proxy"),
+        }
+    }
+
+    pub fn set_broadcast(&self, v: bool) -> ResultType<()> {
+        match self {
+            Self::Direct(f) => Ok(f.get_ref().set_broadcast(v)?),
+            Self::ProxySocks(_) => bail!("broadcast is not supported over proxy"),
+        }
+    }
+
+    /// Binary search the largest udp payload in `[min, max]` which gets through to
+    /// `addr`, with the don't fragment bit set. `probe(size)` builds a datagram of
+    /// about `size` bytes which `addr` answers, any answer counts. `min` is assumed
+    /// to get through.
+    pub async fn probe_pmtu<F>(
+        &mut self,
+        addr: TargetAddr<'static>,
+        min: usize,
+        max: usize,
+        ms_timeout: u64,
+        mut probe: F,
+    ) -> ResultType<usize>
+    where
+        F: FnMut(usize) -> ResultType<Bytes>,
+    {
+        self.set_dont_fragment(true)?;
+        let (mut lo, mut hi) = (min, max.max(min));
+        let res = loop {
+            if hi - lo <= PMTU_PRECISION {
+                break Ok(lo);
+            }
+            let size = (lo + hi + 1) / 2;
+            let data = match probe(size) {
+                Ok(data) => data,
+                Err(err) => break Err(err),
+            };
+            let mut passed = false;
+            for _ in 0..PMTU_PROBE_TRIES {
+                match self.send_bytes(data.clone(), addr.clone()).await {
+                    Ok(_) => {}
+                    Err(err) if is_msg_size_error(&err) => break,
+                    Err(err) => {
+                        allow_err!(self.set_dont_fragment(false));
+                        return Err(err);
+                    }
+                }
+                if let Some(Ok(_)) = self.next_timeout(ms_timeout).await {
+                    passed = true;
+                    break;
+                }
+            }
+            log::debug!("pmtu probe of {} bytes to {}: {}", size, addr, passed);
+            if passed {
+                lo = size;
+            } else {
+                hi = size - 1;
+            }
+        };
+        allow_err!(self.set_dont_fragment(false));
+        res
+    }
+}
+
+// path MTU search stops once the range is this narrow
+const PMTU_PRECISION: usize = 8;
+const PMTU_PROBE_TRIES: usize = 2;
+
+fn is_msg_size_error(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<std::io::Error>() {
+        #[cfg(target_os = "linux")]
+        Some(err) => err.raw_os_error() == Some(libc::EMSGSIZE),
+        // WSAEMSGSIZE
+        #[cfg(windows)]
+        Some(err) => err.raw_os_error() == Some(10040),
+        #[cfg(not(any(target_os = "linux", windows)))]
+        Some(_) => false,
+        None => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, v: bool) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let (level, name, value) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            // PROBE sets DF but ignores the cached path MTU, which is what we test
+            if v {
+                libc::IP_PMTUDISC_PROBE
+            } else {
+                libc::IP_PMTUDISC_WANT
+            },
+        ),
+        SocketAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            if v {
+                libc::IPV6_PMTUDISC_PROBE
+            } else {
+                libc::IPV6_PMTUDISC_WANT
+            },
+        ),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as _,
+            std::mem::size_of::<libc::c_int>() as _,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_dont_fragment(socket: &UdpSocket, v: bool) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use winapi::{
+        shared::minwindef::DWORD,
+        um::winsock2::{setsockopt, WSAGetLastError, SOCKET, SOCKET_ERROR},
+    };
+    const IPPROTO_IP: i32 = 0;
+    const IPPROTO_IPV6: i32 = 41;
+    const IP_DONTFRAGMENT: i32 = 14;
+    const IPV6_DONTFRAG: i32 = 14;
+    let (level, name) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (IPPROTO_IP, IP_DONTFRAGMENT),
+        SocketAddr::V6(_) => (IPPROTO_IPV6, IPV6_DONTFRAG),
+    };
+    let value: DWORD = v as _;
+    let res = unsafe {
+        setsockopt(
+            socket.as_raw_socket() as SOCKET,
+            level,
+            name,
+            &value as *const _ as _,
+            std::mem::size_of::<DWORD>() as _,
+        )
+    };
+    if res == SOCKET_ERROR {
+        return Err(std::io::Error::from_raw_os_error(unsafe {
+            WSAGetLastError()
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn set_dont_fragment(_socket: &UdpSocket, _v: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "don't fragment is not supported on this platform",
+    ))
+}
+
+/// Retransmit timeout bounds for ack based reliable sends over udp.
+#[derive(Debug, Clone, Copy)]
+pub struct RtoConfig {
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    pub backoff: f32,
+}
+
+impl Default for RtoConfig {
+    fn default() -> Self {
+        Self {
+            min_rto: Duration::from_millis(200),
+            max_rto: Duration::from_secs(10),
+            backoff: 2.,
+        }
+    }
+}
+
+/// RFC 6298 style retransmit timer, the initial RTO comes from the RTT
+/// estimation once there is a sample, always clamped to [min_rto, max_rto].
+#[derive(Debug, Clone)]
+pub struct RetransmitTimer {
+    config: RtoConfig,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RetransmitTimer {
+    pub fn new(config: RtoConfig) -> Self {
+        let mut timer = Self {
+            config,
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: Duration::from_secs(1),
+        };
+        timer.rto = timer.clamp(timer.rto);
+        timer
+    }
+
+    fn clamp(&self, rto: Duration) -> Duration {
+        rto.max(self.config.min_rto).min(self.config.max_rto)
+    }
+
+    fn base_rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => self.clamp(srtt + self.rttvar * 4),
+            None => self.clamp(Duration::from_secs(1)),
+        }
+    }
+
+    #[inline]
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    #[inline]
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Feed a RTT measured on a packet which was not retransmitted.
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = self.base_rto();
+    }
+
+    /// Back off after a retransmit timeout, returns the new RTO.
+    pub fn on_timeout(&mut self) -> Duration {
+        self.rto = self.clamp(self.rto.mul_f32(self.config.backoff.max(1.)));
+        self.rto
+    }
+}
+
+/// Several egress sockets sharing outbound traffic by weight,
+/// e.g. a relay with multiple uplinks.
+#[derive(Default)]
+pub struct EgressGroup {
+    egresses: Vec<Egress>,
+}
+
+pub struct Egress {
+    socket: FramedSocket,
+    weight: i64,
+    current: i64,
+    pub sent: u64,
+    pub errors: u64,
+    pub failovers: u64,
+}
+
+impl EgressGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, socket: FramedSocket, weight: u32) {
+        self.egresses.push(Egress {
+            socket,
+            weight: weight.max(1) as i64,
+            current: 0,
+            sent: 0,
+            errors: 0,
+            failovers: 0,
+        });
+    }
+
+    #[inline]
+    pub fn egresses(&self) -> &[Egress] {
+        &self.egresses
+    }
+
+    // smooth weighted round-robin, as nginx does
+    fn pick(&mut self) -> Option<usize> {
+        let total: i64 = self.egresses.iter().map(|e| e.weight).sum();
+        let mut best: Option<usize> = None;
+        for i in 0..self.egresses.len() {
+            self.egresses[i].current += self.egresses[i].weight;
+            match best {
+                Some(b) if self.egresses[b].current >= self.egresses[i].current => {}
+                _ => best = Some(i),
+            }
+        }
+        if let Some(i) = best {
+            self.egresses[i].current -= total;
+        }
+        best
+    }
+
+    /// Send via the egress picked by weight, failing over to the next ones on error.
+    pub async fn send(
+        &mut self,
+        msg: &impl Message,
+        addr: impl IntoTargetAddr<'_>,
+    ) -> ResultType<()> {
+        let addr = addr.into_target_addr()?.to_owned();
+        let first = match self.pick() {
+            Some(i) => i,
+            None => bail!("no egress socket"),
+        };
+        let n = self.egresses.len();
+        let mut last_err = None;
+        for k in 0..n {
+            let i = (first + k) % n;
+            let e = &mut self.egresses[i];
+            match e.socket.send(msg, addr.clone()).await {
+                Ok(_) => {
+                    e.sent += 1;
+                    if k > 0 {
+                        e.failovers += 1;
+                        log::debug!("egress {} failed, sent via egress {}", first, i);
+                    }
+                    return Ok(());
+                }
+                Err(err) => {
+                    e.errors += 1;
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("no egress socket")))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg_via(
+    fd: std::os::unix::io::RawFd,
+    data: &[u8],
+    dst: SocketAddr,
+    iface_index: u32,
+) -> std::io::Result<usize> {
+    use std::mem::{size_of, zeroed};
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len: data.len(),
+        };
+        let mut storage: libc::sockaddr_storage = zeroed();
+        let (addr_len, level, ty, info_len) = match dst {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as _;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                };
+                (
+                    size_of::<libc::sockaddr_in>(),
+                    libc::IPPROTO_IP,
+                    libc::IP_PKTINFO,
+                    size_of::<libc::in_pktinfo>(),
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as _;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr = libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                };
+                sin6.sin6_scope_id = addr.scope_id();
+                (
+                    size_of::<libc::sockaddr_in6>(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_PKTINFO,
+                    size_of::<libc::in6_pktinfo>(),
+                )
+            }
+        };
+        // u64 for cmsghdr alignment, large enough for either pktinfo
+        let mut control = [0u64; 8];
+        let mut hdr: libc::msghdr = zeroed();
+        hdr.msg_name = &mut storage as *mut _ as *mut _;
+        hdr.msg_namelen = addr_len as _;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut _;
+        hdr.msg_controllen = libc::CMSG_SPACE(info_len as _) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(info_len as _) as _;
+        if dst.is_ipv4() {
+            let mut info: libc::in_pktinfo = zeroed();
+            info.ipi_ifindex = iface_index as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+        } else {
+            let mut info: libc::in6_pktinfo = zeroed();
+            info.ipi6_ifindex = iface_index as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo, info);
+        }
+        let n = libc::sendmsg(fd, &hdr, 0);
+        if n < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
 }
 
 // const DEFAULT_MULTICAST: &str = "239.255.42.98";
 
-pub fn bind_multicast(maddr: Option<SocketAddrV4>) -> ResultType<FramedSocket> {
-    // todo: https://github.com/bltavares/multicast-socket
-    // 0.0.0.0 bind to default interface, if there are two interfaces, there will be problem.
-    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+fn new_multicast_socket(domain: Domain) -> ResultType<Socket> {
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
     socket.set_reuse_address(true)?;
     // somehow without this, timer.tick() under tokio::select! does not work
     socket.set_read_timeout(Some(std::time::Duration::from_millis(100)))?;
+    Ok(socket)
+}
+
+fn into_framed(socket: Socket) -> ResultType<FramedSocket> {
+    #[cfg(windows)]
+    disable_connreset(&socket)?;
+    Ok(FramedSocket::Direct(UdpFramed::new(
+        UdpSocket::from_std(socket.into_udp_socket())?,
+        BytesCodec::new(),
+    )))
+}
+
+pub fn bind_multicast(maddr: Option<SocketAddrV4>) -> ResultType<FramedSocket> {
+    // 0.0.0.0 bind to default interface, if there are two interfaces, there will be problem,
+    // use MulticastSocket to cover all of them.
+    let socket = new_multicast_socket(Domain::ipv4())?;
     if let Some(maddr) = maddr {
         assert!(maddr.ip().is_multicast(), "Must be multcast address");
         let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0).into(), maddr.port());
@@ -165,8 +900,614 @@ pub fn bind_multicast(maddr: Option<SocketAddrV4>) -> ResultType<FramedSocket> {
             0,
         )))?;
     }
-    Ok(FramedSocket::Direct(UdpFramed::new(
-        UdpSocket::from_std(socket.into_udp_socket())?,
-        BytesCodec::new(),
-    )))
+    into_framed(socket)
+}
+
+/// Active non-loopback IPv4 interfaces, falling back to the default one.
+pub fn get_multicast_interfaces() -> Vec<Ipv4Addr> {
+    let mut res = Vec::new();
+    match if_addrs::get_if_addrs() {
+        Ok(ifaces) => {
+            for iface in ifaces {
+                if iface.is_loopback() {
+                    continue;
+                }
+                if let std::net::IpAddr::V4(ip) = iface.ip() {
+                    if !res.contains(&ip) {
+                        res.push(ip);
+                    }
+                }
+            }
+        }
+        Err(err) => log::debug!("failed to enumerate interfaces: {}", err),
+    }
+    if res.is_empty() {
+        res.push(Ipv4Addr::new(0, 0, 0, 0));
+    }
+    res
+}
+
+/// Multicast over all active interfaces, so that peers on a second NIC or
+/// a VPN adapter are reachable too.
+/// As a listener, one socket joins the group on every interface;
+/// as a sender, there is one socket per interface.
+pub struct MulticastSocket {
+    sockets: Vec<FramedSocket>,
+    group: Option<SocketAddrV4>,
+    interfaces: Vec<Ipv4Addr>,
+}
+
+impl MulticastSocket {
+    pub fn new(maddr: Option<SocketAddrV4>) -> ResultType<Self> {
+        let interfaces = get_multicast_interfaces();
+        let mut sockets = Vec::new();
+        if let Some(maddr) = maddr {
+            assert!(maddr.ip().is_multicast(), "Must be multcast address");
+            let socket = new_multicast_socket(Domain::ipv4())?;
+            let mut joined = 0;
+            for iface in interfaces.iter() {
+                match socket.join_multicast_v4(maddr.ip(), iface) {
+                    Ok(_) => joined += 1,
+                    Err(err) => log::debug!("failed to join {} on {}: {}", maddr, iface, err),
+                }
+            }
+            if joined == 0 {
+                bail!("failed to join {} on any interface", maddr);
+            }
+            socket.set_multicast_loop_v4(true)?;
+            socket.bind(&socket2::SockAddr::from(SocketAddrV4::new(
+                Ipv4Addr::new(0, 0, 0, 0),
+                maddr.port(),
+            )))?;
+            sockets.push(into_framed(socket)?);
+        } else {
+            for iface in interfaces.iter() {
+                let socket = new_multicast_socket(Domain::ipv4())?;
+                if let Err(err) = socket
+                    .set_multicast_if_v4(iface)
+                    .and_then(|_| socket.bind(&SocketAddr::new((*iface).into(), 0).into()))
+                {
+                    log::debug!("failed to bind multicast sender on {}: {}", iface, err);
+                    continue;
+                }
+                sockets.push(into_framed(socket)?);
+            }
+            if sockets.is_empty() {
+                bail!("failed to bind multicast sender on any interface");
+            }
+        }
+        Ok(Self {
+            sockets,
+            group: maddr,
+            interfaces,
+        })
+    }
+
+    /// Wrap a single socket, e.g. one from `bind_multicast_v6`.
+    pub fn from_socket(socket: FramedSocket) -> Self {
+        Self {
+            sockets: vec![socket],
+            group: None,
+            interfaces: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn interfaces(&self) -> &[Ipv4Addr] {
+        &self.interfaces
+    }
+
+    /// Multicast destinations are sent on every interface,
+    /// unicast ones (e.g. replies) via the first socket only.
+    pub async fn send(
+        &mut self,
+        msg: &impl Message,
+        addr: impl IntoTargetAddr<'_>,
+    ) -> ResultType<()> {
+        let addr = addr.into_target_addr()?.to_owned();
+        let multicast = matches!(&addr, TargetAddr::Ip(ip) if ip.ip().is_multicast());
+        if !multicast {
+            return self.sockets[0].send(msg, addr).await;
+        }
+        let mut last_err = None;
+        let mut n = 0;
+        for socket in self.sockets.iter_mut() {
+            match socket.send(msg, addr.clone()).await {
+                Ok(_) => n += 1,
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match last_err {
+            Some(err) if n == 0 => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// As `send`, of bytes not framed by protobuf, e.g. mDNS.
+    pub async fn send_raw(
+        &mut self,
+        msg: Vec<u8>,
+        addr: impl IntoTargetAddr<'static>,
+    ) -> ResultType<()> {
+        let addr = addr.into_target_addr()?.to_owned();
+        let multicast = matches!(&addr, TargetAddr::Ip(ip) if ip.ip().is_multicast());
+        if !multicast {
+            return self.sockets[0].send_raw(msg, addr).await;
+        }
+        let mut last_err = None;
+        let mut n = 0;
+        for socket in self.sockets.iter_mut() {
+            match socket.send_raw(msg.clone(), addr.clone()).await {
+                Ok(_) => n += 1,
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match last_err {
+            Some(err) if n == 0 => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<ResultType<(BytesMut, TargetAddr<'static>)>> {
+        if self.sockets.len() == 1 {
+            return self.sockets[0].next().await;
+        }
+        let futs = self.sockets.iter_mut().map(|s| Box::pin(s.next()));
+        futures::future::select_all(futs).await.0
+    }
+
+    /// Re-join the group on every interface, returns true if any membership was lost.
+    pub fn rejoin(&self) -> ResultType<bool> {
+        let maddr = match self.group {
+            Some(maddr) => maddr,
+            None => return Ok(false),
+        };
+        let mut lost = false;
+        for iface in self.interfaces.iter() {
+            match self.sockets[0].rejoin_multicast_v4(*maddr.ip(), *iface) {
+                Ok(true) => lost = true,
+                Ok(false) => {}
+                Err(err) => log::debug!("failed to rejoin {} on {}: {}", maddr, iface, err),
+            }
+        }
+        Ok(lost)
+    }
+
+    /// Leave and join the group again on every interface, when a probe of the
+    /// group is not looped back though `rejoin` finds the memberships in place.
+    /// The interfaces gone since are skipped.
+    pub fn reset_membership(&self) -> ResultType<()> {
+        let maddr = match self.group {
+            Some(maddr) => maddr,
+            None => return Ok(()),
+        };
+        let mut joined = 0;
+        for iface in self.interfaces.iter() {
+            match self.sockets[0].reset_multicast_v4(*maddr.ip(), *iface) {
+                Ok(_) => joined += 1,
+                Err(err) => log::debug!("failed to rejoin {} on {}: {}", maddr, iface, err),
+            }
+        }
+        if joined == 0 {
+            bail!("failed to rejoin {} on any interface", maddr);
+        }
+        Ok(())
+    }
+}
+
+/// IPv6 counterpart of `bind_multicast`, `interface` is the interface index
+/// (scope id) to join on / send from, 0 for the default one.
+pub fn bind_multicast_v6(maddr: Option<SocketAddrV6>, interface: u32) -> ResultType<FramedSocket> {
+    let socket = new_multicast_socket(Domain::ipv6())?;
+    socket.set_only_v6(true)?;
+    if let Some(maddr) = maddr {
+        assert!(maddr.ip().is_multicast(), "Must be multcast address");
+        socket.join_multicast_v6(maddr.ip(), interface)?;
+        socket.set_multicast_loop_v6(true)?;
+        socket.bind(&socket2::SockAddr::from(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            maddr.port(),
+            0,
+            0,
+        )))?;
+    } else {
+        socket.set_multicast_if_v6(interface)?;
+        socket.bind(&socket2::SockAddr::from(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            0,
+            0,
+            0,
+        )))?;
+    }
+    into_framed(socket)
+}
+
+/// Debug-only correlation of sent and received datagrams, e.g. for multicast loopback.
+/// Sends are stamped with a monotonic tag in a side table keyed by content hash,
+/// nothing extra goes on the wire.
+#[cfg(feature = "udp_correlate")]
+pub mod correlate {
+    use std::{
+        collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+        hash::{Hash, Hasher},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    const MAX_ENTRIES: usize = 1024;
+
+    #[derive(Default)]
+    struct Table {
+        sent: HashMap<u64, (u64, Instant)>,
+        order: VecDeque<u64>,
+    }
+
+    lazy_static::lazy_static! {
+        static ref TABLE: Mutex<Table> = Default::default();
+    }
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+
+    fn hash(data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Record a datagram about to be sent, returns its tag.
+    pub fn stamp(data: &[u8]) -> u64 {
+        let tag = SEQ.fetch_add(1, Ordering::Relaxed) + 1;
+        let key = hash(data);
+        let mut table = TABLE.lock().unwrap();
+        if table.sent.insert(key, (tag, Instant::now())).is_none() {
+            table.order.push_back(key);
+        }
+        while table.order.len() > MAX_ENTRIES {
+            if let Some(old) = table.order.pop_front() {
+                table.sent.remove(&old);
+            }
+        }
+        tag
+    }
+
+    /// Tag and elapsed time of the latest send with the same content, if any.
+    pub fn lookup(data: &[u8]) -> Option<(u64, Duration)> {
+        TABLE
+            .lock()
+            .unwrap()
+            .sent
+            .get(&hash(data))
+            .map(|(tag, tm)| (*tag, tm.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendezvous_proto::{rendezvous_message, PunchHoleRequest, RendezvousMessage};
+
+    #[tokio::test]
+    async fn test_reset_keeps_socket() {
+        let mut socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        #[cfg(unix)]
+        let fd = match &socket {
+            FramedSocket::Direct(f) => std::os::unix::io::AsRawFd::as_raw_fd(f.get_ref()),
+            _ => unreachable!(),
+        };
+        socket.reset();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        #[cfg(unix)]
+        match &socket {
+            FramedSocket::Direct(f) => {
+                assert_eq!(std::os::unix::io::AsRawFd::as_raw_fd(f.get_ref()), fd);
+                assert!(f.read_buffer().is_empty());
+            }
+            _ => unreachable!(),
+        }
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest {
+            id: "reset".to_owned(),
+            ..Default::default()
+        });
+        let mut sender = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        sender.send(&msg, addr).await.unwrap();
+        assert!(socket.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rebind_replaces_socket() {
+        let mut socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        #[cfg(unix)]
+        let fd = match &socket {
+            FramedSocket::Direct(f) => std::os::unix::io::AsRawFd::as_raw_fd(f.get_ref()),
+            _ => unreachable!(),
+        };
+        socket.rebind().await.unwrap();
+        let new_addr = socket.local_addr().unwrap();
+        assert_eq!(new_addr.ip(), addr.ip());
+        assert_ne!(new_addr.port(), addr.port());
+        #[cfg(unix)]
+        match &socket {
+            FramedSocket::Direct(f) => {
+                assert_ne!(std::os::unix::io::AsRawFd::as_raw_fd(f.get_ref()), fd)
+            }
+            _ => unreachable!(),
+        }
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest {
+            id: "rebind".to_owned(),
+            ..Default::default()
+        });
+        let mut sender = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        sender.send(&msg, new_addr).await.unwrap();
+        assert!(socket.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_rto_backoff() {
+        let config = RtoConfig {
+            min_rto: Duration::from_millis(100),
+            max_rto: Duration::from_secs(8),
+            backoff: 1.5,
+        };
+        let mut lan = RetransmitTimer::new(config);
+        for _ in 0..10 {
+            lan.on_rtt_sample(Duration::from_millis(1));
+        }
+        assert_eq!(lan.rto(), config.min_rto);
+        let mut satellite = RetransmitTimer::new(config);
+        satellite.on_rtt_sample(Duration::from_millis(700));
+        let mut last = satellite.rto();
+        assert!(last > Duration::from_millis(700));
+        for _ in 0..20 {
+            let rto = satellite.on_timeout();
+            assert!(rto >= last);
+            last = rto;
+        }
+        assert_eq!(last, config.max_rto);
+    }
+
+    #[tokio::test]
+    async fn test_rejoin_multicast() {
+        let maddr = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), 21198);
+        let mut receiver = bind_multicast(Some(maddr)).unwrap();
+        let any = Ipv4Addr::new(0, 0, 0, 0);
+        assert!(!receiver.rejoin_multicast_v4(*maddr.ip(), any).unwrap());
+        match &receiver {
+            FramedSocket::Direct(f) => f.get_ref().leave_multicast_v4(*maddr.ip(), any).unwrap(),
+            _ => unreachable!(),
+        }
+        assert!(receiver.rejoin_multicast_v4(*maddr.ip(), any).unwrap());
+        let mut sender = bind_multicast(None).unwrap();
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        sender.send(&msg, SocketAddr::V4(maddr)).await.unwrap();
+        assert!(receiver.next_timeout(3_000).await.unwrap().is_ok());
+        // joined or not, a reset ends joined
+        receiver.reset_multicast_v4(*maddr.ip(), any).unwrap();
+        assert!(!receiver.rejoin_multicast_v4(*maddr.ip(), any).unwrap());
+        sender.send(&msg, SocketAddr::V4(maddr)).await.unwrap();
+        assert!(receiver.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_send_via() {
+        let iface = unsafe { libc::if_nametoindex(b"lo\0".as_ptr() as *const _) };
+        assert!(iface > 0);
+        let mut receiver = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut sender = FramedSocket::new("0.0.0.0:0").await.unwrap();
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        sender
+            .send_via(&msg, receiver.local_addr().unwrap(), iface)
+            .await
+            .unwrap();
+        assert!(receiver.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_try_next() {
+        let mut socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        assert!(matches!(socket.try_next().unwrap(), RecvResult::WouldBlock));
+        let mut sender = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        sender.send(&msg, socket.local_addr().unwrap()).await.unwrap();
+        assert!(matches!(
+            socket.next_result().await.unwrap(),
+            RecvResult::Datagram(..)
+        ));
+        assert!(matches!(socket.try_next().unwrap(), RecvResult::WouldBlock));
+        // what a proxy association which has ended yields
+        assert!(matches!(to_recv_result(None).unwrap(), RecvResult::Closed));
+        assert!(to_recv_result(Some(Err(anyhow!("reset")))).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_probe_pmtu() {
+        // a peer behind a 1000 bytes path, anything larger is dropped
+        let mut peer = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(Ok((data, from))) = peer.next().await {
+                if data.len() <= 1000 {
+                    allow_err!(peer.send_bytes(Bytes::from_static(b"ok"), from).await);
+                }
+            }
+        });
+        let mut socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let size = socket
+            .probe_pmtu(TargetAddr::Ip(addr), 500, 1500, 200, |size| {
+                Ok(Bytes::from(vec![0u8; size]))
+            })
+            .await
+            .unwrap();
+        assert!(size <= 1000 && size > 1000 - PMTU_PRECISION);
+    }
+
+    #[tokio::test]
+    async fn test_multicast_v6() {
+        let maddr = SocketAddrV6::new(
+            Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x42, 0x2a62),
+            21197,
+            0,
+            0,
+        );
+        let (mut receiver, mut sender) =
+            match (bind_multicast_v6(Some(maddr), 0), bind_multicast_v6(None, 0)) {
+                (Ok(r), Ok(s)) => (r, s),
+                // no ipv6 on this host
+                _ => return,
+            };
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        if sender.send(&msg, SocketAddr::V6(maddr)).await.is_err() {
+            return;
+        }
+        assert!(receiver.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_multicast_all_interfaces() {
+        let maddr = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 97), 21196);
+        let mut receiver = MulticastSocket::new(Some(maddr)).unwrap();
+        let mut sender = MulticastSocket::new(None).unwrap();
+        assert!(!sender.interfaces().is_empty());
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        sender.send(&msg, SocketAddr::V4(maddr)).await.unwrap();
+        let (_, from) = crate::timeout(3_000, receiver.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // reply goes back unicast to whichever interface socket sent the probe
+        receiver.send(&msg, from).await.unwrap();
+        let res = crate::timeout(3_000, sender.next()).await.unwrap();
+        assert!(res.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_egress_weights() {
+        let mut group = EgressGroup::new();
+        for w in [1u32, 2, 5] {
+            group.add(FramedSocket::new("127.0.0.1:0").await.unwrap(), w);
+        }
+        let mut counts = [0usize; 3];
+        for _ in 0..8000 {
+            counts[group.pick().unwrap()] += 1;
+        }
+        assert_eq!(counts, [1000, 2000, 5000]);
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        let target = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        for _ in 0..16 {
+            group.send(&msg, addr).await.unwrap();
+        }
+        let sent: Vec<u64> = group.egresses().iter().map(|e| e.sent).collect();
+        assert_eq!(sent, vec![2, 4, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_send_traced() {
+        let mut receiver = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let mut sender = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        set_trace_peer(&TargetAddr::Ip(addr), true);
+        set_trace_peer(&TargetAddr::Ip(sender_addr), true);
+        // not negotiated, its traced datagram goes plain
+        let mut old = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest {
+            id: "traced".to_owned(),
+            ..Default::default()
+        });
+        sender.send_traced(&msg, addr, 42).await.unwrap();
+        sender.send(&msg, addr).await.unwrap();
+        old.send_traced(&msg, sender_addr, 43).await.unwrap();
+        let (data, _) = sender.next_timeout(3_000).await.unwrap().unwrap();
+        assert!(RendezvousMessage::parse_from_bytes(&data).is_ok());
+        for _ in 0..2 {
+            let (data, _) = receiver.next_timeout(3_000).await.unwrap().unwrap();
+            let msg_in = RendezvousMessage::parse_from_bytes(&data).unwrap();
+            match msg_in.union {
+                Some(rendezvous_message::Union::punch_hole_request(ph)) => {
+                    assert_eq!(ph.id, "traced")
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+        let logs = TRACES.lock().unwrap();
+        let traced: Vec<_> = logs.iter().filter(|x| x.starts_with("trace 42:")).collect();
+        assert_eq!(traced.len(), 3);
+        assert!(traced[0].contains("serialized"));
+        assert!(traced[1].contains(&format!("sent to {}", addr)));
+        let len = msg.write_to_bytes().unwrap().len();
+        let received = format!("received {} bytes from {}", len, sender_addr);
+        assert!(traced[2].ends_with(&received));
+        // the untraced one is quiet
+        assert_eq!(logs.iter().filter(|x| x.ends_with(&received)).count(), 1);
+        set_trace_peer(&TargetAddr::Ip(addr), false);
+        set_trace_peer(&TargetAddr::Ip(sender_addr), false);
+    }
+
+    lazy_static::lazy_static! {
+        // the lines logged by `log_trace`
+        pub(super) static ref TRACES: std::sync::Mutex<Vec<String>> = Default::default();
+    }
+
+    #[test]
+    fn test_trace_header() {
+        let mut traced = BytesMut::new();
+        traced.put_slice(&TRACE_MAGIC);
+        traced.put_u64_le(7);
+        traced.put_slice(b"payload");
+        assert_eq!(strip_trace_header(&mut traced), Some(7));
+        assert_eq!(&traced[..], b"payload");
+        let mut plain = BytesMut::from(&b"\x0a\x02id"[..]);
+        assert_eq!(strip_trace_header(&mut plain), None);
+        assert_eq!(&plain[..], b"\x0a\x02id");
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_connreset_ignored() {
+        let mut socket = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        let closed = {
+            let tmp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            tmp.local_addr().unwrap()
+        };
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest::default());
+        socket.send(&msg, closed).await.unwrap();
+        let mut sender = FramedSocket::new("127.0.0.1:0").await.unwrap();
+        sender.send(&msg, socket.local_addr().unwrap()).await.unwrap();
+        assert!(socket.next_timeout(3_000).await.unwrap().is_ok());
+    }
+
+    #[cfg(feature = "udp_correlate")]
+    #[tokio::test]
+    async fn test_correlate_multicast_loopback() {
+        let maddr = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 98), 21199);
+        let mut receiver = bind_multicast(Some(maddr)).unwrap();
+        let mut sender = bind_multicast(None).unwrap();
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest {
+            id: "correlate".to_owned(),
+            ..Default::default()
+        });
+        sender.send(&msg, SocketAddr::V4(maddr)).await.unwrap();
+        let (data, _) = receiver.next_timeout(3_000).await.unwrap().unwrap();
+        let (tag, elapsed) = correlate::lookup(&data).unwrap();
+        assert!(tag > 0);
+        assert!(elapsed < std::time::Duration::from_secs(3));
+    }
 }
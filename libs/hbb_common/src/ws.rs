@@ -0,0 +1,140 @@
+use crate::{
+    config::{Config, RELAY_PORT, RENDEZVOUS_PORT},
+    tcp::FramedStream,
+    ResultType,
+};
+use bytes::{Buf, BytesMut};
+use futures::{ready, Sink, Stream};
+use std::{
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_socks::TargetAddr;
+use tokio_tungstenite::{
+    tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream,
+};
+
+// hbbs / hbbr serve websocket on their tcp port + 2
+const WS_PORT_OFFSET: u16 = 2;
+
+/// Byte stream over websocket binary messages, so it can be framed like a
+/// tcp stream with `FramedStream::from` and go through proxies which only let
+/// http(s) traffic pass. `wss` uses rustls.
+pub struct WsStream {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    buf: BytesMut,
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.buf.is_empty() {
+                let n = std::cmp::min(self.buf.len(), buf.remaining());
+                buf.put_slice(&self.buf[..n]);
+                self.buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(WsMessage::Binary(data))) => self.buf.extend_from_slice(&data),
+                Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(Ok(())),
+                // ping / pong are answered by tungstenite itself
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(Error::new(ErrorKind::Other, err))),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ws = Pin::new(&mut self.ws);
+        ready!(ws.poll_ready(cx)).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        Pin::new(&mut self.ws)
+            .start_send(WsMessage::Binary(buf.to_vec()))
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws)
+            .poll_flush(cx)
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws)
+            .poll_close(cx)
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+}
+
+pub fn get_ws_url(target: &TargetAddr<'_>, secure: bool) -> String {
+    let (host, port) = match target {
+        TargetAddr::Ip(SocketAddr::V6(addr)) => (format!("[{}]", addr.ip()), addr.port()),
+        TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        TargetAddr::Domain(host, port) => (host.to_string(), *port),
+    };
+    format!(
+        "{}://{}:{}",
+        if secure { "wss" } else { "ws" },
+        host,
+        port + WS_PORT_OFFSET
+    )
+}
+
+/// Whether a failed tcp connection to `target` is worth retrying over websocket,
+/// only hbbs / hbbr ports have a websocket port next to them.
+pub fn is_ws_fallback_target(target: &TargetAddr<'_>) -> bool {
+    let port = match target {
+        TargetAddr::Ip(addr) => addr.port(),
+        TargetAddr::Domain(_, port) => *port,
+    };
+    port == RENDEZVOUS_PORT as u16 || port == RELAY_PORT as u16
+}
+
+pub async fn connect(target: &TargetAddr<'_>, ms_timeout: u64) -> ResultType<FramedStream> {
+    let url = get_ws_url(target, Config::get_option("websocket-tls") == "Y");
+    let (ws, _) = super::timeout(ms_timeout, tokio_tungstenite::connect_async(&url)).await??;
+    let local_addr = match ws.get_ref() {
+        MaybeTlsStream::Plain(s) => s.local_addr()?,
+        _ => Config::get_any_listen_addr(),
+    };
+    log::info!("websocket connected to {}", url);
+    Ok(FramedStream::from(
+        WsStream {
+            ws,
+            buf: BytesMut::new(),
+        },
+        local_addr,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ws_url() {
+        let addr: SocketAddr = "1.2.3.4:21116".parse().unwrap();
+        assert_eq!(get_ws_url(&TargetAddr::Ip(addr), false), "ws://1.2.3.4:21118");
+        let domain = TargetAddr::Domain("rs.example.com".into(), 21117);
+        assert_eq!(get_ws_url(&domain, true), "wss://rs.example.com:21119");
+        assert!(is_ws_fallback_target(&domain));
+        let peer: SocketAddr = "1.2.3.4:51234".parse().unwrap();
+        assert!(!is_ws_fallback_target(&TargetAddr::Ip(peer)));
+    }
+}
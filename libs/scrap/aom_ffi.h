@@ -0,0 +1,7 @@
+#include <aom/aom_codec.h>
+#include <aom/aom_decoder.h>
+#include <aom/aom_encoder.h>
+#include <aom/aom_image.h>
+#include <aom/aom_integer.h>
+#include <aom/aomcx.h>
+#include <aom/aomdx.h>
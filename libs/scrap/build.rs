@@ -45,13 +45,15 @@ fn generate_bindings(
     include_paths: &[PathBuf],
     ffi_rs: &Path,
     exact_file: &Path,
+    names: &str,
+    enums: &str,
 ) {
     let mut b = bindgen::builder()
         .header(ffi_header.to_str().unwrap())
-        .allowlist_type("^[vV].*")
-        .allowlist_var("^[vV].*")
-        .allowlist_function("^[vV].*")
-        .rustified_enum("^v.*")
+        .allowlist_type(names)
+        .allowlist_var(names)
+        .allowlist_function(names)
+        .rustified_enum(enums)
         .trust_clang_mangling(false)
         .layout_tests(false) // breaks 32/64-bit compat
         .generate_comments(false); // vpx comments have prefix /*!\
@@ -79,7 +81,76 @@ fn gen_vpx() {
 
     let ffi_rs = out_dir.join("vpx_ffi.rs");
     let exact_file = src_dir.join("generated").join("vpx_ffi.rs");
-    generate_bindings(&ffi_header, &includes, &ffi_rs, &exact_file);
+    generate_bindings(
+        &ffi_header,
+        &includes,
+        &ffi_rs,
+        &exact_file,
+        "^[vV].*",
+        "^v.*",
+    );
+}
+
+fn gen_aom() {
+    let includes = find_package("libaom");
+    let src_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let src_dir = Path::new(&src_dir);
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+
+    let ffi_header = src_dir.join("aom_ffi.h");
+    println!("rerun-if-changed={}", ffi_header.display());
+    for dir in &includes {
+        println!("rerun-if-changed={}", dir.display());
+    }
+
+    let ffi_rs = out_dir.join("aom_ffi.rs");
+    let exact_file = src_dir.join("generated").join("aom_ffi.rs");
+    generate_bindings(
+        &ffi_header,
+        &includes,
+        &ffi_rs,
+        &exact_file,
+        "^(aom|AOM|av1|AV1).*",
+        "^(aom|av1).*",
+    );
+}
+
+fn gen_ffmpeg() {
+    let mut includes = find_package("libavcodec");
+    includes.append(&mut find_package("libavutil"));
+    let src_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let src_dir = Path::new(&src_dir);
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+
+    let ffi_header = src_dir.join("ffmpeg_ffi.h");
+    println!("rerun-if-changed={}", ffi_header.display());
+    for dir in &includes {
+        println!("rerun-if-changed={}", dir.display());
+    }
+
+    let ffi_rs = out_dir.join("ffmpeg_ffi.rs");
+    let exact_file = src_dir.join("generated").join("ffmpeg_ffi.rs");
+    generate_bindings(
+        &ffi_header,
+        &includes,
+        &ffi_rs,
+        &exact_file,
+        "^(av|AV).*",
+        "^AV(PixelFormat|CodecID|HWDeviceType)$",
+    );
+
+    // the hardware apis the static ffmpeg is built against
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap();
+    if target_os == "linux" {
+        println!("cargo:rustc-link-lib=va");
+        println!("cargo:rustc-link-lib=va-drm");
+    } else if target_os == "macos" {
+        for framework in ["VideoToolbox", "CoreMedia", "CoreVideo", "CoreFoundation"].iter() {
+            println!("cargo:rustc-link-lib=framework={}", framework);
+        }
+    }
 }
 
 fn main() {
@@ -96,6 +167,12 @@ fn main() {
 
     find_package("libyuv");
     gen_vpx();
+    if env::var("CARGO_FEATURE_AV1").is_ok() {
+        gen_aom();
+    }
+    if env::var("CARGO_FEATURE_HWCODEC").is_ok() {
+        gen_ffmpeg();
+    }
 
     // there is problem with cfg(target_os) in build.rs, so use our workaround
     let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap();
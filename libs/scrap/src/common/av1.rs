@@ -0,0 +1,369 @@
+// AV1 with libaom, next to the VP9 path of codec.rs and with the same interface.
+// https://aomedia.googlesource.com/aom/+/refs/heads/main/examples/simple_encoder.c
+// https://aomedia.googlesource.com/aom/+/refs/heads/main/examples/simple_decoder.c
+
+use super::aom::{aom_codec_err_t::*, aome_enc_control_id::*, *};
+use super::codec::{Config, EncodeFrame, Error, Result};
+use std::os::raw::c_int;
+use std::{ptr, slice};
+
+macro_rules! call_aom {
+    ($x:expr) => {{
+        let result = unsafe { $x }; // original expression
+        if result != AOM_CODEC_OK {
+            return Err(Error::FailedCall(format!(
+                "errcode={:?} {}:{}:{}:{}",
+                result,
+                module_path!(),
+                file!(),
+                line!(),
+                column!()
+            ))
+            .into());
+        }
+        result
+    }};
+}
+
+macro_rules! call_aom_ptr {
+    ($x:expr) => {{
+        let result = unsafe { $x }; // original expression
+        if result.is_null() {
+            return Err(Error::BadPtr(format!(
+                "null {}:{}:{}:{}",
+                module_path!(),
+                file!(),
+                line!(),
+                column!()
+            ))
+            .into());
+        }
+        result
+    }};
+}
+
+pub struct Encoder {
+    ctx: aom_codec_ctx_t,
+    cfg: aom_codec_enc_cfg_t,
+    width: usize,
+    height: usize,
+    key_frame: bool,
+}
+
+pub struct Decoder {
+    ctx: aom_codec_ctx_t,
+}
+
+impl Encoder {
+    pub fn new(config: &Config, num_threads: u32) -> Result<Self> {
+        let i = call_aom_ptr!(aom_codec_av1_cx());
+        let mut c = Default::default();
+        call_aom!(aom_codec_enc_config_default(
+            i,
+            &mut c,
+            AOM_USAGE_REALTIME as _
+        ));
+
+        c.g_w = config.width;
+        c.g_h = config.height;
+        c.g_timebase.num = config.timebase[0];
+        c.g_timebase.den = config.timebase[1];
+        c.rc_target_bitrate = config.bitrate;
+        c.rc_undershoot_pct = 95;
+        c.rc_dropframe_thresh = 25;
+        // the quantizers of the config are in the vp9 range, same as av1's 0-63
+        if config.rc_min_quantizer > 0 {
+            c.rc_min_quantizer = config.rc_min_quantizer;
+        }
+        if config.rc_max_quantizer > 0 {
+            c.rc_max_quantizer = config.rc_max_quantizer;
+        }
+        c.g_threads = if num_threads == 0 {
+            num_cpus::get() as _
+        } else {
+            num_threads
+        };
+        c.g_error_resilient = AOM_ERROR_RESILIENT_DEFAULT;
+        // no look ahead, every frame is output right away
+        c.g_lag_in_frames = 0;
+        c.rc_end_usage = aom_rc_mode::AOM_CBR;
+        c.kf_mode = aom_kf_mode::AOM_KF_DISABLED; // same as vp9, reduce bandwidth a lot
+
+        let mut ctx = Default::default();
+        call_aom!(aom_codec_enc_init_ver(
+            &mut ctx,
+            i,
+            &c,
+            0,
+            AOM_ENCODER_ABI_VERSION as _
+        ));
+
+        // 7 to 10 for real time, the higher the faster
+        let speed = if config.speed <= 0 {
+            10
+        } else {
+            (config.speed + 3).min(10)
+        };
+        call_aom!(aom_codec_control(
+            &mut ctx,
+            AOME_SET_CPUUSED as _,
+            speed as c_int
+        ));
+        call_aom!(aom_codec_control(
+            &mut ctx,
+            AV1E_SET_ROW_MT as _,
+            1 as c_int
+        ));
+        call_aom!(aom_codec_control(
+            &mut ctx,
+            AV1E_SET_TILE_COLUMNS as _,
+            2 as c_int
+        ));
+        // screen content tools, e.g. palette mode and intra block copy
+        call_aom!(aom_codec_control(
+            &mut ctx,
+            AV1E_SET_TUNE_CONTENT as _,
+            aom_tune_content::AOM_CONTENT_SCREEN as c_int
+        ));
+
+        Ok(Self {
+            ctx,
+            cfg: c,
+            width: config.width as _,
+            height: config.height as _,
+            key_frame: false,
+        })
+    }
+
+    /// Make the next frame a keyframe.
+    pub fn request_key_frame(&mut self) {
+        self.key_frame = true;
+    }
+
+    /// Change the target bitrate (in kilobits per second) on the fly, without a keyframe.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        self.cfg.rc_target_bitrate = bitrate;
+        call_aom!(aom_codec_enc_config_set(&mut self.ctx, &self.cfg));
+        Ok(())
+    }
+
+    pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames> {
+        assert!(2 * data.len() >= 3 * self.width * self.height);
+
+        let mut image = Default::default();
+        call_aom_ptr!(aom_img_wrap(
+            &mut image,
+            aom_img_fmt::AOM_IMG_FMT_I420,
+            self.width as _,
+            self.height as _,
+            stride_align as _,
+            data.as_ptr() as _,
+        ));
+
+        let flags = if std::mem::take(&mut self.key_frame) {
+            AOM_EFLAG_FORCE_KF
+        } else {
+            0
+        };
+        call_aom!(aom_codec_encode(
+            &mut self.ctx,
+            &image,
+            pts as _,
+            1, // Duration
+            flags as _,
+        ));
+
+        Ok(EncodeFrames {
+            ctx: &mut self.ctx,
+            iter: ptr::null(),
+        })
+    }
+
+    /// Notify the encoder to return any pending packets
+    pub fn flush(&mut self) -> Result<EncodeFrames> {
+        call_aom!(aom_codec_encode(
+            &mut self.ctx,
+            ptr::null(),
+            -1, // PTS
+            1,  // Duration
+            0,  // Flags
+        ));
+
+        Ok(EncodeFrames {
+            ctx: &mut self.ctx,
+            iter: ptr::null(),
+        })
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            let result = aom_codec_destroy(&mut self.ctx);
+            if result != AOM_CODEC_OK {
+                panic!("failed to destroy aom codec");
+            }
+        }
+    }
+}
+
+pub struct EncodeFrames<'a> {
+    ctx: &'a mut aom_codec_ctx_t,
+    iter: aom_codec_iter_t,
+}
+
+impl<'a> Iterator for EncodeFrames<'a> {
+    type Item = EncodeFrame<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            unsafe {
+                let pkt = aom_codec_get_cx_data(self.ctx, &mut self.iter);
+                if pkt.is_null() {
+                    return None;
+                } else if (*pkt).kind == aom_codec_cx_pkt_kind::AOM_CODEC_CX_FRAME_PKT {
+                    let f = &(*pkt).data.frame;
+                    return Some(Self::Item {
+                        data: slice::from_raw_parts(f.buf as _, f.sz as _),
+                        key: (f.flags & AOM_FRAME_IS_KEY) != 0,
+                        pts: f.pts,
+                    });
+                } else {
+                    // Ignore the packet.
+                }
+            }
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new(num_threads: u32) -> Result<Self> {
+        let i = call_aom_ptr!(aom_codec_av1_dx());
+        let mut ctx = Default::default();
+        let cfg = aom_codec_dec_cfg_t {
+            threads: if num_threads == 0 {
+                num_cpus::get() as _
+            } else {
+                num_threads
+            },
+            w: 0,
+            h: 0,
+            // 8 bit output for 8 bit streams, which is all we encode
+            allow_lowbitdepth: 1,
+        };
+        call_aom!(aom_codec_dec_init_ver(
+            &mut ctx,
+            i,
+            &cfg,
+            0,
+            AOM_DECODER_ABI_VERSION as _,
+        ));
+        Ok(Self { ctx })
+    }
+
+    /// Feed some compressed data to the decoder, the images are
+    /// only valid until the next call.
+    pub fn decode(&mut self, data: &[u8]) -> Result<DecodeFrames> {
+        call_aom!(aom_codec_decode(
+            &mut self.ctx,
+            data.as_ptr(),
+            data.len() as _,
+            ptr::null_mut(),
+        ));
+
+        Ok(DecodeFrames {
+            ctx: &mut self.ctx,
+            iter: ptr::null(),
+        })
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe {
+            let result = aom_codec_destroy(&mut self.ctx);
+            if result != AOM_CODEC_OK {
+                panic!("failed to destroy aom codec");
+            }
+        }
+    }
+}
+
+pub struct DecodeFrames<'a> {
+    ctx: &'a mut aom_codec_ctx_t,
+    iter: aom_codec_iter_t,
+}
+
+impl<'a> Iterator for DecodeFrames<'a> {
+    type Item = Image<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let img = unsafe { aom_codec_get_frame(self.ctx, &mut self.iter) };
+        if img.is_null() {
+            return None;
+        } else {
+            return Some(Image(unsafe { &*img }));
+        }
+    }
+}
+
+// owned by the decoder, unlike the vpx one
+pub struct Image<'a>(&'a aom_image_t);
+
+impl<'a> Image<'a> {
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.0.d_w as _
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.0.d_h as _
+    }
+
+    pub fn rgb(&self, stride_align: usize, rgba: bool, dst: &mut Vec<u8>) -> Result<()> {
+        let img = self.0;
+        if img.fmt != aom_img_fmt::AOM_IMG_FMT_I420 {
+            return Err(Error::FailedCall(format!(
+                "unsupported image format {:?}",
+                img.fmt
+            )));
+        }
+        let h = self.height();
+        let mut w = self.width();
+        let bps = if rgba { 4 } else { 3 };
+        w = (w + stride_align - 1) & !(stride_align - 1);
+        dst.resize(h * w * bps, 0);
+        unsafe {
+            if rgba {
+                super::I420ToARGB(
+                    img.planes[0],
+                    img.stride[0],
+                    img.planes[1],
+                    img.stride[1],
+                    img.planes[2],
+                    img.stride[2],
+                    dst.as_mut_ptr(),
+                    (w * bps) as _,
+                    self.width() as _,
+                    self.height() as _,
+                );
+            } else {
+                super::I420ToRAW(
+                    img.planes[0],
+                    img.stride[0],
+                    img.planes[1],
+                    img.stride[1],
+                    img.planes[2],
+                    img.stride[2],
+                    dst.as_mut_ptr(),
+                    (w * bps) as _,
+                    self.width() as _,
+                    self.height() as _,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe impl Send for aom_codec_ctx_t {}
+unsafe impl Send for aom_codec_enc_cfg_t {}
@@ -20,8 +20,10 @@ impl Default for VideoCodecId {
 
 pub struct Encoder {
     ctx: vpx_codec_ctx_t,
+    cfg: vpx_codec_enc_cfg_t,
     width: usize,
     height: usize,
+    key_frame: bool,
 }
 
 pub struct Decoder {
@@ -189,11 +191,25 @@ impl Encoder {
 
         Ok(Self {
             ctx,
+            cfg: c,
             width: config.width as _,
             height: config.height as _,
+            key_frame: false,
         })
     }
 
+    /// Change the target bitrate (in kilobits per second) on the fly, without a keyframe.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        self.cfg.rc_target_bitrate = bitrate;
+        call_vpx!(vpx_codec_enc_config_set(&mut self.ctx, &self.cfg));
+        Ok(())
+    }
+
+    /// Make the next frame a keyframe.
+    pub fn request_key_frame(&mut self) {
+        self.key_frame = true;
+    }
+
     pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames> {
         assert!(2 * data.len() >= 3 * self.width * self.height);
 
@@ -207,12 +223,17 @@ impl Encoder {
             data.as_ptr() as _,
         ));
 
+        let flags = if std::mem::take(&mut self.key_frame) {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             1, // Duration
-            0, // Flags
+            flags as _,
             VPX_DL_REALTIME as _,
         ));
 
@@ -534,3 +555,4 @@ impl Drop for Image {
 }
 
 unsafe impl Send for vpx_codec_ctx_t {}
+unsafe impl Send for vpx_codec_enc_cfg_t {}
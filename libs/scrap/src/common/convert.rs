@@ -51,6 +51,60 @@ extern "C" {
         height: c_int,
     ) -> c_int;
 
+    pub fn I420ToNV12(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_u: *const u8,
+        src_stride_u: c_int,
+        src_v: *const u8,
+        src_stride_v: c_int,
+        dst_y: *mut u8,
+        dst_stride_y: c_int,
+        dst_uv: *mut u8,
+        dst_stride_uv: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
+    pub fn I420Copy(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_u: *const u8,
+        src_stride_u: c_int,
+        src_v: *const u8,
+        src_stride_v: c_int,
+        dst_y: *mut u8,
+        dst_stride_y: c_int,
+        dst_u: *mut u8,
+        dst_stride_u: c_int,
+        dst_v: *mut u8,
+        dst_stride_v: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
+    pub fn NV12ToARGB(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_uv: *const u8,
+        src_stride_uv: c_int,
+        dst_argb: *mut u8,
+        dst_stride_argb: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
+    pub fn NV12ToRAW(
+        src_y: *const u8,
+        src_stride_y: c_int,
+        src_uv: *const u8,
+        src_stride_uv: c_int,
+        dst_raw: *mut u8,
+        dst_stride_raw: c_int,
+        width: c_int,
+        height: c_int,
+    ) -> c_int;
+
     // I420ToRGB24: RGB little endian (bgr in memory)
     // I420ToRaw: RGB big endian (rgb in memory) to RGBA.
     pub fn I420ToRAW(
@@ -82,7 +136,7 @@ extern "C" {
 
 // https://github.com/webmproject/libvpx/blob/master/vpx/src/vpx_image.c
 #[inline]
-fn get_vpx_i420_stride(
+pub(crate) fn get_vpx_i420_stride(
     width: usize,
     height: usize,
     stride_align: usize,
@@ -155,6 +209,49 @@ pub fn bgra_to_i420(width: usize, height: usize, src: &[u8], dst: &mut Vec<u8>)
     }
 }
 
+/// Copy the rectangle `x, y, w, h` out of an i420 image of `width` x `height`,
+/// both in the layout of `bgra_to_i420`. `x` and `y` are rounded down to even
+/// for the chroma planes, the rectangle must be inside the image.
+pub fn i420_crop(
+    width: usize,
+    height: usize,
+    src: &[u8],
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    dst: &mut Vec<u8>,
+) {
+    let (_, _, src_stride_y, src_stride_uv, src_u, src_v) =
+        get_vpx_i420_stride(width, height, super::STRIDE_ALIGN);
+    let (_, dst_h, dst_stride_y, dst_stride_uv, dst_u, dst_v) =
+        get_vpx_i420_stride(w, h, super::STRIDE_ALIGN);
+    let (x, y) = (x & !1, y & !1);
+    dst.resize(dst_h * dst_stride_y * 12 / 8, 0);
+    let dst_y = dst.as_mut_ptr();
+    let dst_u = dst[dst_u..].as_mut_ptr();
+    let dst_v = dst[dst_v..].as_mut_ptr();
+    let uv_offset = y / 2 * src_stride_uv + x / 2;
+    unsafe {
+        I420Copy(
+            src[y * src_stride_y + x..].as_ptr(),
+            src_stride_y as _,
+            src[src_u + uv_offset..].as_ptr(),
+            src_stride_uv as _,
+            src[src_v + uv_offset..].as_ptr(),
+            src_stride_uv as _,
+            dst_y,
+            dst_stride_y as _,
+            dst_u,
+            dst_stride_uv as _,
+            dst_v,
+            dst_stride_uv as _,
+            w as _,
+            h as _,
+        );
+    }
+}
+
 pub unsafe fn nv12_to_i420(
     src_y: *const u8,
     src_stride_y: c_int,
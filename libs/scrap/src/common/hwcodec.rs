@@ -0,0 +1,595 @@
+// H.264 and H.265 with the hardware encoders of ffmpeg, selected at runtime
+// with a fallback to the software codecs done by the caller, and decoded on
+// the gpu where ffmpeg and the driver can.
+// https://trac.ffmpeg.org/wiki/HWAccelIntro
+// https://github.com/FFmpeg/FFmpeg/blob/master/doc/examples/vaapi_encode.c
+
+use super::codec::{Error, Result};
+use super::ffmpeg::*;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::{ptr, slice};
+
+macro_rules! call_av {
+    ($x:expr) => {{
+        let result = unsafe { $x }; // original expression
+        if result < 0 {
+            return Err(Error::FailedCall(format!(
+                "errcode={} {}:{}:{}:{}",
+                result,
+                module_path!(),
+                file!(),
+                line!(),
+                column!()
+            )));
+        }
+        result
+    }};
+}
+
+macro_rules! call_av_ptr {
+    ($x:expr) => {{
+        let result = unsafe { $x }; // original expression
+        if result.is_null() {
+            return Err(Error::BadPtr(format!(
+                "null {}:{}:{}:{}",
+                module_path!(),
+                file!(),
+                line!(),
+                column!()
+            )));
+        }
+        result
+    }};
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HwCodec {
+    H264,
+    H265,
+}
+
+impl HwCodec {
+    fn id(&self) -> AVCodecID {
+        match self {
+            HwCodec::H264 => AVCodecID::AV_CODEC_ID_H264,
+            HwCodec::H265 => AVCodecID::AV_CODEC_ID_HEVC,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HwBackend {
+    Nvenc,
+    Qsv,
+    Vaapi,
+    VideoToolbox,
+}
+
+impl HwBackend {
+    /// The backends of this platform, in the order they are tried.
+    pub fn candidates() -> Vec<HwBackend> {
+        if cfg!(target_os = "macos") {
+            vec![HwBackend::VideoToolbox]
+        } else if cfg!(target_os = "linux") {
+            vec![HwBackend::Nvenc, HwBackend::Qsv, HwBackend::Vaapi]
+        } else if cfg!(windows) {
+            vec![HwBackend::Nvenc, HwBackend::Qsv]
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HwBackend> {
+        match name {
+            "nvenc" => Some(HwBackend::Nvenc),
+            "qsv" => Some(HwBackend::Qsv),
+            "vaapi" => Some(HwBackend::Vaapi),
+            "videotoolbox" => Some(HwBackend::VideoToolbox),
+            _ => None,
+        }
+    }
+
+    fn encoder_name(&self, codec: HwCodec) -> String {
+        let backend = match self {
+            HwBackend::Nvenc => "nvenc",
+            HwBackend::Qsv => "qsv",
+            HwBackend::Vaapi => "vaapi",
+            HwBackend::VideoToolbox => "videotoolbox",
+        };
+        match codec {
+            HwCodec::H264 => format!("h264_{}", backend),
+            HwCodec::H265 => format!("hevc_{}", backend),
+        }
+    }
+
+    // the format of the frames handed to the encoder, in system memory
+    fn sw_format(&self) -> AVPixelFormat {
+        match self {
+            HwBackend::Nvenc | HwBackend::VideoToolbox => AVPixelFormat::AV_PIX_FMT_YUV420P,
+            HwBackend::Qsv | HwBackend::Vaapi => AVPixelFormat::AV_PIX_FMT_NV12,
+        }
+    }
+
+    // low latency settings, the ones a backend doesn't know are left in the
+    // dictionary and ignored
+    fn options(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            HwBackend::Nvenc => &[("preset", "llhp"), ("zerolatency", "1"), ("rc", "cbr")],
+            HwBackend::Qsv => &[("preset", "veryfast"), ("async_depth", "1")],
+            HwBackend::Vaapi => &[("rc_mode", "CBR")],
+            HwBackend::VideoToolbox => &[("realtime", "1"), ("allow_sw", "0")],
+        }
+    }
+}
+
+/// The first backend which can open an encoder of `codec` on this machine, of
+/// `forced` only if it is one: "nvenc", "qsv", "vaapi" or "videotoolbox".
+pub fn probe(forced: &str, codec: HwCodec) -> Option<HwBackend> {
+    unsafe {
+        av_log_set_level(AV_LOG_ERROR as _);
+    }
+    let forced = HwBackend::from_name(forced);
+    HwBackend::candidates()
+        .into_iter()
+        .filter(|b| forced.is_none() || forced == Some(*b))
+        .find(|b| HwEncoder::new(*b, codec, 1280, 720, 2_000, 30).is_ok())
+}
+
+// the decoding apis of this platform, in the order they are tried
+fn hw_device_types() -> Vec<AVHWDeviceType> {
+    if cfg!(target_os = "macos") {
+        vec![AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX]
+    } else if cfg!(target_os = "linux") {
+        vec![AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI]
+    } else if cfg!(windows) {
+        vec![
+            AVHWDeviceType::AV_HWDEVICE_TYPE_D3D11VA,
+            AVHWDeviceType::AV_HWDEVICE_TYPE_DXVA2,
+        ]
+    } else {
+        vec![]
+    }
+}
+
+// a device of the gpu which ffmpeg can decode `codec` with
+fn create_hw_device(codec: *const AVCodec) -> Option<*mut AVBufferRef> {
+    for device_type in hw_device_types() {
+        let mut supported = false;
+        let mut i = 0;
+        loop {
+            let config = unsafe { avcodec_get_hw_config(codec, i) };
+            if config.is_null() {
+                break;
+            }
+            let config = unsafe { &*config };
+            if config.device_type == device_type
+                && config.methods & AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX as c_int != 0
+            {
+                supported = true;
+                break;
+            }
+            i += 1;
+        }
+        if !supported {
+            continue;
+        }
+        let mut device = ptr::null_mut();
+        let res = unsafe {
+            av_hwdevice_ctx_create(&mut device, device_type, ptr::null(), ptr::null_mut(), 0)
+        };
+        if res >= 0 {
+            return Some(device);
+        }
+    }
+    None
+}
+
+/// Whether `codec` can be decoded on the gpu here.
+pub fn has_hw_decoder(codec: HwCodec) -> bool {
+    unsafe {
+        av_log_set_level(AV_LOG_ERROR as _);
+    }
+    match HwDecoder::new(codec) {
+        Ok(decoder) => decoder.is_hardware(),
+        Err(_) => false,
+    }
+}
+
+/// An encoded frame, copied out of the packet of ffmpeg.
+pub struct HwFrame {
+    pub data: Vec<u8>,
+    pub key: bool,
+    pub pts: i64,
+}
+
+pub struct HwEncoder {
+    backend: HwBackend,
+    codec: HwCodec,
+    ctx: *mut AVCodecContext,
+    frame: *mut AVFrame,
+    packet: *mut AVPacket,
+    device: *mut AVBufferRef,
+    width: usize,
+    height: usize,
+    fps: u32,
+}
+
+impl HwEncoder {
+    pub fn new(
+        backend: HwBackend,
+        codec: HwCodec,
+        width: usize,
+        height: usize,
+        bitrate: u32,
+        fps: u32,
+    ) -> Result<Self> {
+        let name = CString::new(backend.encoder_name(codec)).unwrap();
+        let av_codec = call_av_ptr!(avcodec_find_encoder_by_name(name.as_ptr()));
+        let ctx = call_av_ptr!(avcodec_alloc_context3(av_codec));
+        // freed by drop from here on, also on errors
+        let mut encoder = Self {
+            backend,
+            codec,
+            ctx,
+            frame: ptr::null_mut(),
+            packet: ptr::null_mut(),
+            device: ptr::null_mut(),
+            width,
+            height,
+            fps,
+        };
+        unsafe {
+            (*ctx).width = width as _;
+            (*ctx).height = height as _;
+            (*ctx).time_base = AVRational { num: 1, den: 1000 };
+            (*ctx).framerate = AVRational {
+                num: fps as _,
+                den: 1,
+            };
+            // keyframes are expensive, a new encoder is created when one is needed
+            (*ctx).gop_size = (fps * 60) as _;
+            (*ctx).max_b_frames = 0;
+            (*ctx).bit_rate = bitrate as i64 * 1000;
+            (*ctx).rc_max_rate = (*ctx).bit_rate;
+            (*ctx).rc_buffer_size = (*ctx).bit_rate as _;
+            (*ctx).pix_fmt = backend.sw_format();
+        }
+        if backend == HwBackend::Vaapi {
+            encoder.init_vaapi()?;
+        }
+        let mut options = ptr::null_mut();
+        for (k, v) in backend.options() {
+            let k = CString::new(*k).unwrap();
+            let v = CString::new(*v).unwrap();
+            unsafe {
+                av_dict_set(&mut options, k.as_ptr(), v.as_ptr(), 0);
+            }
+        }
+        let res = unsafe { avcodec_open2(ctx, av_codec, &mut options) };
+        unsafe {
+            av_dict_free(&mut options);
+        }
+        if res < 0 {
+            return Err(Error::FailedCall(format!(
+                "failed to open {}: {}",
+                backend.encoder_name(codec),
+                res
+            )));
+        }
+        encoder.frame = call_av_ptr!(av_frame_alloc());
+        encoder.packet = call_av_ptr!(av_packet_alloc());
+        unsafe {
+            (*encoder.frame).format = backend.sw_format() as c_int;
+            (*encoder.frame).width = width as _;
+            (*encoder.frame).height = height as _;
+        }
+        call_av!(av_frame_get_buffer(encoder.frame, 32));
+        Ok(encoder)
+    }
+
+    // vaapi only takes frames in video memory, uploaded from nv12 ones
+    fn init_vaapi(&mut self) -> Result<()> {
+        call_av!(av_hwdevice_ctx_create(
+            &mut self.device,
+            AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+            ptr::null(),
+            ptr::null_mut(),
+            0
+        ));
+        let mut frames_ref = call_av_ptr!(av_hwframe_ctx_alloc(self.device));
+        unsafe {
+            let frames = (*frames_ref).data as *mut AVHWFramesContext;
+            (*frames).format = AVPixelFormat::AV_PIX_FMT_VAAPI;
+            (*frames).sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            (*frames).width = self.width as _;
+            (*frames).height = self.height as _;
+            (*frames).initial_pool_size = 20;
+            let res = av_hwframe_ctx_init(frames_ref);
+            if res >= 0 {
+                (*self.ctx).hw_frames_ctx = av_buffer_ref(frames_ref);
+                (*self.ctx).pix_fmt = AVPixelFormat::AV_PIX_FMT_VAAPI;
+            }
+            av_buffer_unref(&mut frames_ref);
+            if res < 0 {
+                return Err(Error::FailedCall(format!(
+                    "failed to init vaapi frames: {}",
+                    res
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn backend(&self) -> HwBackend {
+        self.backend
+    }
+
+    #[inline]
+    pub fn codec(&self) -> HwCodec {
+        self.codec
+    }
+
+    /// ffmpeg can't change the bitrate of an opened encoder, so it starts over
+    /// with a keyframe.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        *self = Self::new(
+            self.backend,
+            self.codec,
+            self.width,
+            self.height,
+            bitrate,
+            self.fps,
+        )?;
+        Ok(())
+    }
+
+    /// Encode an i420 image laid out as for vpx, see `get_vpx_i420_stride`.
+    pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<Vec<HwFrame>> {
+        let (_, _, stride_y, stride_uv, u, v) =
+            super::get_vpx_i420_stride(self.width, self.height, stride_align);
+        assert!(data.len() >= v + stride_uv * ((self.height + 1) / 2));
+        call_av!(av_frame_make_writable(self.frame));
+        unsafe {
+            let f = &mut *self.frame;
+            if self.backend.sw_format() == AVPixelFormat::AV_PIX_FMT_NV12 {
+                super::I420ToNV12(
+                    data.as_ptr(),
+                    stride_y as _,
+                    data[u..].as_ptr(),
+                    stride_uv as _,
+                    data[v..].as_ptr(),
+                    stride_uv as _,
+                    f.data[0],
+                    f.linesize[0],
+                    f.data[1],
+                    f.linesize[1],
+                    self.width as _,
+                    self.height as _,
+                );
+            } else {
+                super::I420Copy(
+                    data.as_ptr(),
+                    stride_y as _,
+                    data[u..].as_ptr(),
+                    stride_uv as _,
+                    data[v..].as_ptr(),
+                    stride_uv as _,
+                    f.data[0],
+                    f.linesize[0],
+                    f.data[1],
+                    f.linesize[1],
+                    f.data[2],
+                    f.linesize[2],
+                    self.width as _,
+                    self.height as _,
+                );
+            }
+            f.pts = pts;
+        }
+        if self.device.is_null() {
+            call_av!(avcodec_send_frame(self.ctx, self.frame));
+        } else {
+            let mut hw_frame = call_av_ptr!(av_frame_alloc());
+            let res = unsafe {
+                let mut res = av_hwframe_get_buffer((*self.ctx).hw_frames_ctx, hw_frame, 0);
+                if res >= 0 {
+                    res = av_hwframe_transfer_data(hw_frame, self.frame, 0);
+                }
+                if res >= 0 {
+                    (*hw_frame).pts = pts;
+                    res = avcodec_send_frame(self.ctx, hw_frame);
+                }
+                av_frame_free(&mut hw_frame);
+                res
+            };
+            call_av!(res);
+        }
+        let mut frames = Vec::new();
+        // negative once there is no more packet for now
+        while unsafe { avcodec_receive_packet(self.ctx, self.packet) } == 0 {
+            unsafe {
+                let p = &*self.packet;
+                frames.push(HwFrame {
+                    data: slice::from_raw_parts(p.data, p.size as _).to_vec(),
+                    key: (p.flags & AV_PKT_FLAG_KEY as c_int) != 0,
+                    pts: p.pts,
+                });
+                av_packet_unref(self.packet);
+            }
+        }
+        Ok(frames)
+    }
+}
+
+impl Drop for HwEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            av_packet_free(&mut self.packet);
+            av_frame_free(&mut self.frame);
+            avcodec_free_context(&mut self.ctx);
+            av_buffer_unref(&mut self.device);
+        }
+    }
+}
+
+pub struct HwDecoder {
+    ctx: *mut AVCodecContext,
+    frame: *mut AVFrame,
+    sw_frame: *mut AVFrame, // the image copied out of video memory
+    packet: *mut AVPacket,
+    hardware: bool,
+}
+
+impl HwDecoder {
+    /// On the gpu if possible, otherwise in software.
+    pub fn new(codec: HwCodec) -> Result<Self> {
+        let av_codec = call_av_ptr!(avcodec_find_decoder(codec.id()));
+        let ctx = call_av_ptr!(avcodec_alloc_context3(av_codec));
+        let mut decoder = Self {
+            ctx,
+            frame: ptr::null_mut(),
+            sw_frame: ptr::null_mut(),
+            packet: ptr::null_mut(),
+            hardware: false,
+        };
+        unsafe {
+            (*ctx).flags |= AV_CODEC_FLAG_LOW_DELAY as c_int;
+            if let Some(mut device) = create_hw_device(av_codec) {
+                // the default get_format picks the format of the device
+                (*ctx).hw_device_ctx = av_buffer_ref(device);
+                av_buffer_unref(&mut device);
+                decoder.hardware = true;
+            }
+        }
+        call_av!(avcodec_open2(ctx, av_codec, ptr::null_mut()));
+        decoder.frame = call_av_ptr!(av_frame_alloc());
+        decoder.sw_frame = call_av_ptr!(av_frame_alloc());
+        decoder.packet = call_av_ptr!(av_packet_alloc());
+        Ok(decoder)
+    }
+
+    #[inline]
+    pub fn is_hardware(&self) -> bool {
+        self.hardware
+    }
+
+    /// Whether a new image is decoded.
+    pub fn decode(&mut self, data: &[u8]) -> Result<bool> {
+        unsafe {
+            (*self.packet).data = data.as_ptr() as _;
+            (*self.packet).size = data.len() as _;
+        }
+        call_av!(avcodec_send_packet(self.ctx, self.packet));
+        let mut got = false;
+        while unsafe { avcodec_receive_frame(self.ctx, self.frame) } == 0 {
+            got = true;
+        }
+        Ok(got)
+    }
+
+    // the last decoded image in system memory
+    fn image(&mut self) -> Result<&AVFrame> {
+        unsafe {
+            if (*self.frame).hw_frames_ctx.is_null() {
+                return Ok(&*self.frame);
+            }
+            av_frame_unref(self.sw_frame);
+            call_av!(av_hwframe_transfer_data(self.sw_frame, self.frame, 0));
+            Ok(&*self.sw_frame)
+        }
+    }
+
+    /// The last decoded image, valid until the next decode.
+    pub fn rgb(&mut self, stride_align: usize, rgba: bool, dst: &mut Vec<u8>) -> Result<()> {
+        let f = self.image()?;
+        let nv12 = f.format == AVPixelFormat::AV_PIX_FMT_NV12 as c_int;
+        if !nv12
+            && f.format != AVPixelFormat::AV_PIX_FMT_YUV420P as c_int
+            && f.format != AVPixelFormat::AV_PIX_FMT_YUVJ420P as c_int
+        {
+            return Err(Error::FailedCall(format!(
+                "unsupported image format {}",
+                f.format
+            )));
+        }
+        let h = f.height as usize;
+        let mut w = f.width as usize;
+        let bps = if rgba { 4 } else { 3 };
+        w = (w + stride_align - 1) & !(stride_align - 1);
+        dst.resize(h * w * bps, 0);
+        let dst_stride = (w * bps) as c_int;
+        unsafe {
+            match (nv12, rgba) {
+                (true, true) => {
+                    super::NV12ToARGB(
+                        f.data[0],
+                        f.linesize[0],
+                        f.data[1],
+                        f.linesize[1],
+                        dst.as_mut_ptr(),
+                        dst_stride,
+                        f.width,
+                        f.height,
+                    );
+                }
+                (true, false) => {
+                    super::NV12ToRAW(
+                        f.data[0],
+                        f.linesize[0],
+                        f.data[1],
+                        f.linesize[1],
+                        dst.as_mut_ptr(),
+                        dst_stride,
+                        f.width,
+                        f.height,
+                    );
+                }
+                (false, true) => {
+                    super::I420ToARGB(
+                        f.data[0],
+                        f.linesize[0],
+                        f.data[1],
+                        f.linesize[1],
+                        f.data[2],
+                        f.linesize[2],
+                        dst.as_mut_ptr(),
+                        dst_stride,
+                        f.width,
+                        f.height,
+                    );
+                }
+                (false, false) => {
+                    super::I420ToRAW(
+                        f.data[0],
+                        f.linesize[0],
+                        f.data[1],
+                        f.linesize[1],
+                        f.data[2],
+                        f.linesize[2],
+                        dst.as_mut_ptr(),
+                        dst_stride,
+                        f.width,
+                        f.height,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HwDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            av_packet_free(&mut self.packet);
+            av_frame_free(&mut self.sw_frame);
+            av_frame_free(&mut self.frame);
+            avcodec_free_context(&mut self.ctx);
+        }
+    }
+}
+
+unsafe impl Send for HwEncoder {}
+unsafe impl Send for HwDecoder {}
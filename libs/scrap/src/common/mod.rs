@@ -30,3 +30,11 @@ pub use self::convert::*;
 pub const STRIDE_ALIGN: usize = 16; // commonly used in libvpx vpx_img_alloc caller
 
 mod vpx;
+#[cfg(feature = "av1")]
+mod aom;
+#[cfg(feature = "av1")]
+pub mod av1;
+#[cfg(feature = "hwcodec")]
+mod ffmpeg;
+#[cfg(feature = "hwcodec")]
+pub mod hwcodec;
@@ -10,7 +10,10 @@ fn map_err<E: ToString>(err: E) -> io::Error {
 
 impl Capturer {
     pub fn new(display: Display, yuv: bool) -> io::Result<Capturer> {
-        let r = display.0.recorder(false).map_err(map_err)?;
+        let r = display.0.recorder(false).map_err(|err| {
+            pipewire::close_session();
+            map_err(err)
+        })?;
         Ok(Capturer(display, r, yuv, Default::default()))
     }
 
@@ -23,7 +26,11 @@ impl Capturer {
     }
 
     pub fn frame<'a>(&'a mut self, timeout_ms: u32) -> io::Result<Frame<'a>> {
-        match self.1.capture(timeout_ms as _).map_err(map_err)? {
+        let res = self.1.capture(timeout_ms as _).map_err(|err| {
+            pipewire::close_session();
+            map_err(err)
+        })?;
+        match res {
             PixelProvider::BGR0(w, h, x) => Ok(Frame(if self.2 {
                 crate::common::bgra_to_i420(w as _, h as _, &x, &mut self.3);
                 &self.3[..]
@@ -385,6 +385,41 @@ fn streams_from_response(response: OrgFreedesktopPortalRequestResponse) -> Vec<P
 
 static mut INIT: bool = false;
 
+// the portal asks again for every new session unless it is given the token of
+// the previous one, which the application persists with these
+static mut RESTORE_TOKEN_STORE: Option<(fn() -> String, fn(String))> = None;
+
+// persist_mode of SelectSources: until the permission is revoked
+const PERSIST_MODE: u32 = 2;
+// restore_token and persist_mode are available since version 4 of the portal
+const RESTORE_TOKEN_VERSION: u32 = 4;
+
+lazy_static::lazy_static! {
+    // the session is shared by all the displays and kept for the next captures
+    static ref CAPTURABLES: Mutex<Option<Vec<PipeWireCapturable>>> = Default::default();
+}
+
+/// Make the permission of the screen cast portal survive restarts, `load`
+/// returns the saved restore token, empty if none, `save` replaces it.
+pub fn set_restore_token_store(load: fn() -> String, save: fn(String)) {
+    unsafe {
+        RESTORE_TOKEN_STORE = Some((load, save));
+    }
+}
+
+fn load_restore_token() -> String {
+    match unsafe { RESTORE_TOKEN_STORE } {
+        Some((load, _)) => load(),
+        None => "".to_owned(),
+    }
+}
+
+fn save_restore_token(token: String) {
+    if let Some((_, save)) = unsafe { RESTORE_TOKEN_STORE } {
+        save(token);
+    }
+}
+
 // mostly inspired by https://gitlab.gnome.org/snippets/19
 fn request_screen_cast(
     capture_cursor: bool,
@@ -438,6 +473,14 @@ fn request_screen_cast(
                     You have been warned.");
             }
             args.insert("cursor_mode".into(), Variant(Box::new(cursor_mode)));
+            if portal.version().unwrap_or(0) >= RESTORE_TOKEN_VERSION {
+                args.insert("persist_mode".into(), Variant(Box::new(PERSIST_MODE)));
+                let token = load_restore_token();
+                if !token.is_empty() {
+                    // no dialog if the portal still knows it
+                    args.insert("restore_token".into(), Variant(Box::new(token)));
+                }
+            }
             let session: dbus::Path = r
                 .results
                 .get("session_handle")
@@ -476,6 +519,12 @@ fn request_screen_cast(
                         c,
                         path,
                         move |r: OrgFreedesktopPortalRequestResponse, c, _| {
+                            // single use, a new one comes with every session
+                            if let Some(token) = r.results.get("restore_token") {
+                                if let Some(token) = token.as_str() {
+                                    save_restore_token(token.to_owned());
+                                }
+                            }
                             streams
                                 .clone()
                                 .lock()
@@ -521,10 +570,29 @@ fn request_screen_cast(
 }
 
 pub fn get_capturables(capture_cursor: bool) -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
-    let (conn, fd, streams) = request_screen_cast(capture_cursor)?;
+    let mut lock = CAPTURABLES.lock().unwrap();
+    if let Some(capturables) = lock.as_ref() {
+        return Ok(capturables.clone());
+    }
+    let (conn, fd, streams) = match request_screen_cast(capture_cursor) {
+        Ok(res) => res,
+        Err(err) => {
+            // e.g. revoked, or the monitors changed, the dialog is shown next time
+            save_restore_token("".to_owned());
+            return Err(err);
+        }
+    };
     let conn = Arc::new(conn);
-    Ok(streams
+    let capturables: Vec<PipeWireCapturable> = streams
         .into_iter()
         .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), s))
-        .collect())
+        .collect();
+    *lock = Some(capturables.clone());
+    Ok(capturables)
+}
+
+/// Drop the kept session, e.g. after it is stopped from the panel of the
+/// desktop, so that the next capture requests a new one.
+pub fn close_session() {
+    CAPTURABLES.lock().unwrap().take();
 }
@@ -0,0 +1,198 @@
+// The sync of the address book with the url set by the user, any server
+// giving back by GET what was PUT to it, e.g. a WebDAV file: the peers are
+// sealed there with a key of the sync passphrase, so that the server never
+// sees them, and merged with the ones here, the latest change of each winning.
+// And the actions on many peers at once, of a group or a tag: their online
+// states asked to the rendezvous server, and waking them up.
+use hbb_common::{
+    bail,
+    config::{hash_password, AbPeer, AddressBook},
+    log,
+    sodiumoxide::{crypto::secretbox, randombytes::randombytes},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SYNCING: &str = "syncing";
+
+lazy_static::lazy_static! {
+    // "syncing", or the error of the last sync if it failed
+    static ref STATUS: Mutex<String> = Default::default();
+    // of the last queries, with if one is running
+    static ref ONLINE_STATES: Mutex<(HashMap<String, bool>, bool)> = Default::default();
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    version: i32,
+    salt: String,
+    data: String,
+}
+
+/// Syncs in the background, if there is a url to sync with.
+pub fn sync_in_background() {
+    if AddressBook::load().sync_url.is_empty() {
+        return;
+    }
+    {
+        let mut status = STATUS.lock().unwrap();
+        if *status == SYNCING {
+            return;
+        }
+        *status = SYNCING.to_owned();
+    }
+    std::thread::spawn(|| {
+        let status = match sync() {
+            Ok(_) => "".to_owned(),
+            Err(err) => {
+                log::error!("Failed to sync the address book: {}", err);
+                err.to_string()
+            }
+        };
+        *STATUS.lock().unwrap() = status;
+    });
+}
+
+pub fn get_status() -> String {
+    STATUS.lock().unwrap().clone()
+}
+
+/// Asks in the background if the peers are online, the answers in get_online_states().
+pub fn query_online_states(ids: Vec<String>) {
+    {
+        let mut states = ONLINE_STATES.lock().unwrap();
+        if states.1 {
+            return;
+        }
+        states.1 = true;
+    }
+    std::thread::spawn(move || {
+        let res = crate::rendezvous_mediator::query_online_states(ids);
+        let mut states = ONLINE_STATES.lock().unwrap();
+        match res {
+            Ok(x) => states.0.extend(x),
+            Err(err) => log::error!("Failed to query the online states: {}", err),
+        }
+        states.1 = false;
+    });
+}
+
+/// The online states known, and if a query is running.
+pub fn get_online_states() -> (HashMap<String, bool>, bool) {
+    ONLINE_STATES.lock().unwrap().clone()
+}
+
+/// The peers woken up one after the other, by the peers of their LAN.
+pub fn wake_up(ids: Vec<String>) {
+    std::thread::spawn(move || {
+        for id in ids {
+            if let Err(err) = crate::rendezvous_mediator::wake_up(id.clone()) {
+                log::error!("Failed to wake up {}: {}", id, err);
+            }
+        }
+    });
+}
+
+fn sync() -> ResultType<()> {
+    let ab = AddressBook::load();
+    if ab.sync_passphrase.is_empty() {
+        bail!("No sync passphrase");
+    }
+    let client = reqwest::blocking::Client::new();
+    let mut req = client.get(&ab.sync_url).timeout(TIMEOUT);
+    if !ab.sync_token.is_empty() {
+        req = req.bearer_auth(&ab.sync_token);
+    }
+    let res = req.send()?;
+    let peers = if res.status() == reqwest::StatusCode::NOT_FOUND {
+        Vec::new()
+    } else if !res.status().is_success() {
+        bail!("{}", res.status());
+    } else {
+        let text = res.text()?;
+        if text.trim().is_empty() {
+            Vec::new()
+        } else {
+            open(&text, &ab.sync_passphrase)?
+        }
+    };
+    // again, for the changes made in the meantime
+    let mut ab = AddressBook::load();
+    if ab.merge(peers) {
+        log::info!("address book updated by the sync");
+    }
+    let mut req = client
+        .put(&ab.sync_url)
+        .header("Content-Type", "application/json")
+        .body(seal(&ab.peers, &ab.sync_passphrase)?)
+        .timeout(TIMEOUT);
+    if !ab.sync_token.is_empty() {
+        req = req.bearer_auth(&ab.sync_token);
+    }
+    let res = req.send()?;
+    if !res.status().is_success() {
+        bail!("{}", res.status());
+    }
+    ab.synced = crate::get_time();
+    ab.store();
+    Ok(())
+}
+
+fn seal(peers: &[AbPeer], passphrase: &str) -> ResultType<String> {
+    let salt = randombytes(16);
+    let nonce = secretbox::gen_nonce();
+    let mut data = nonce.0.to_vec();
+    let key = get_key(passphrase, &salt)?;
+    data.extend(secretbox::seal(&serde_json::to_vec(peers)?, &nonce, &key));
+    let sealed = Sealed {
+        version: 1,
+        salt: base64::encode(salt),
+        data: base64::encode(data),
+    };
+    Ok(serde_json::to_string(&sealed)?)
+}
+
+fn open(text: &str, passphrase: &str) -> ResultType<Vec<AbPeer>> {
+    let sealed: Sealed = serde_json::from_str(text)?;
+    let key = get_key(passphrase, &base64::decode(sealed.salt)?)?;
+    let data = base64::decode(sealed.data)?;
+    if data.len() <= secretbox::NONCEBYTES {
+        bail!("Invalid address book");
+    }
+    let nonce = match secretbox::Nonce::from_slice(&data[..secretbox::NONCEBYTES]) {
+        Some(x) => x,
+        None => bail!("Invalid address book"),
+    };
+    match secretbox::open(&data[secretbox::NONCEBYTES..], &nonce, &key) {
+        Ok(x) => Ok(serde_json::from_slice(&x)?),
+        Err(_) => bail!("Wrong sync passphrase"),
+    }
+}
+
+fn get_key(passphrase: &str, salt: &[u8]) -> ResultType<secretbox::Key> {
+    match secretbox::Key::from_slice(&hash_password(passphrase, salt)?) {
+        Some(x) => Ok(x),
+        None => bail!("Invalid key"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let peers = vec![AbPeer {
+            id: "123456789".to_owned(),
+            password: "secret".to_owned(),
+            tags: vec!["office".to_owned()],
+            ..Default::default()
+        }];
+        let text = seal(&peers, "passphrase").unwrap();
+        assert!(!text.contains("secret") && !text.contains("123456789"));
+        assert_eq!(open(&text, "passphrase").unwrap(), peers);
+        assert!(open(&text, "wrong").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// The audit log of the sessions, incoming and outgoing, kept by the service in
+// audit.log of its config folder, one JSON object a line written at the end of
+// each session. An entry has the hash of the one before in its own hash, so
+// that an entry changed or removed breaks the chain from there on, which is
+// shown in the viewer. Only the service writes the log, the outgoing sessions
+// being sent to it by ipc, written here only if it can not be reached.
+use hbb_common::{config::Config, log, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs::OpenOptions, io::Write, sync::Mutex};
+
+lazy_static::lazy_static! {
+    static ref LOCK: Mutex<()> = Default::default();
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Entry {
+    pub direction: String, // incoming, outgoing
+    pub peer_id: String,
+    pub name: String,
+    pub ip: String,
+    pub conn_type: String, // desktop, file-transfer, port-forward, terminal
+    pub start: i64,        // in ms
+    pub end: i64,
+    // granted at any time of the session
+    pub permissions: Vec<String>,
+    // "sent <path>" or "received <path>", from this side
+    pub files: Vec<String>,
+    pub reason: String,
+    #[serde(default)]
+    pub prev: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+pub fn add(entry: Entry) {
+    if let Err(err) = add_(entry) {
+        log::error!("Failed to write the audit log: {}", err);
+    }
+}
+
+fn add_(mut entry: Entry) -> ResultType<()> {
+    let _lock = LOCK.lock().unwrap();
+    let path = Config::audit_log_path();
+    let text = std::fs::read_to_string(&path).unwrap_or_default();
+    entry.prev = text
+        .lines()
+        .rev()
+        .find_map(|x| serde_json::from_str::<Entry>(x).ok())
+        .map(|x| x.hash)
+        .unwrap_or_default();
+    entry.hash = get_hash(&entry);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// The entries of the log, with the index of the first one breaking the chain
+/// if it has been tampered with.
+pub fn load() -> (Vec<Entry>, Option<usize>) {
+    let _lock = LOCK.lock().unwrap();
+    parse(&std::fs::read_to_string(Config::audit_log_path()).unwrap_or_default())
+}
+
+fn parse(text: &str) -> (Vec<Entry>, Option<usize>) {
+    let mut entries = Vec::new();
+    let mut broken = None;
+    let mut prev = "".to_owned();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let entry = match serde_json::from_str::<Entry>(line) {
+            Ok(entry) => entry,
+            Err(_) => {
+                broken.get_or_insert(entries.len());
+                continue;
+            }
+        };
+        if entry.prev != prev || get_hash(&entry) != entry.hash {
+            broken.get_or_insert(entries.len());
+        }
+        prev = entry.hash.clone();
+        entries.push(entry);
+    }
+    (entries, broken)
+}
+
+fn get_hash(entry: &Entry) -> String {
+    let mut entry = entry.clone();
+    entry.hash = "".to_owned();
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_string(&entry).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+/// If `text` is in one of the fields of the entry, case insensitive.
+pub fn matches(entry: &Entry, text: &str) -> bool {
+    let text = text.trim().to_lowercase();
+    if text.is_empty() {
+        return true;
+    }
+    let fds = [
+        &entry.direction,
+        &entry.peer_id,
+        &entry.name,
+        &entry.ip,
+        &entry.conn_type,
+        &entry.reason,
+    ];
+    fds.iter().any(|x| x.to_lowercase().contains(&text))
+        || entry.permissions.iter().any(|x| x.contains(&text))
+        || entry.files.iter().any(|x| x.to_lowercase().contains(&text))
+}
+
+/// The entries as CSV, with the times in ms since the epoch.
+pub fn to_csv(entries: &[Entry]) -> String {
+    let mut out =
+        "direction,peer_id,name,ip,type,start,end,permissions,files,reason,hash\n".to_owned();
+    for e in entries {
+        let fds = [
+            e.direction.clone(),
+            e.peer_id.clone(),
+            e.name.clone(),
+            e.ip.clone(),
+            e.conn_type.clone(),
+            e.start.to_string(),
+            e.end.to_string(),
+            e.permissions.join(" "),
+            e.files.join("; "),
+            e.reason.clone(),
+            e.hash.clone(),
+        ];
+        let fds: Vec<_> = fds.iter().map(|x| quote(x)).collect();
+        out.push_str(&fds.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+// not to be taken for a formula by a spreadsheet either, the names being the peers'
+fn quote(field: &str) -> String {
+    let field = if field.starts_with(|c| "=+-@".contains(c)) {
+        format!("'{}", field)
+    } else {
+        field.to_owned()
+    };
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(entries: Vec<Entry>) -> String {
+        let mut prev = "".to_owned();
+        let mut lines = Vec::new();
+        for mut e in entries {
+            e.prev = prev;
+            e.hash = get_hash(&e);
+            prev = e.hash.clone();
+            lines.push(serde_json::to_string(&e).unwrap());
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn test_chain() {
+        let entries: Vec<_> = (0..3)
+            .map(|i| Entry {
+                direction: "incoming".to_owned(),
+                peer_id: format!("12345678{}", i),
+                start: i,
+                end: i + 1,
+                ..Default::default()
+            })
+            .collect();
+        let text = chain(entries);
+        assert_eq!(parse(&text).0.len(), 3);
+        assert_eq!(parse(&text).1, None);
+        let changed = text.replacen("123456781", "123456789", 1);
+        assert_eq!(parse(&changed).1, Some(1));
+        let lines: Vec<_> = text.lines().collect();
+        let removed = format!("{}\n{}", lines[0], lines[2]);
+        assert_eq!(parse(&removed).1, Some(1));
+        let removed = format!("{}\n{}", lines[1], lines[2]);
+        assert_eq!(parse(&removed).1, Some(0));
+    }
+
+    #[test]
+    fn test_csv() {
+        let e = Entry {
+            name: "a \"b\", c".to_owned(),
+            reason: "=1+1".to_owned(),
+            files: vec!["sent /x".to_owned(), "received /y".to_owned()],
+            ..Default::default()
+        };
+        let csv = to_csv(&[e]);
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            ",,\"a \"\"b\"\", c\",,,0,0,,sent /x; received /y,'=1+1,"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// The history of the chat with each peer, on both sides of the sessions, kept
+// in chats/<peer id>.log of the config folder, one JSON object a line, the
+// last MAX_MESSAGES of them, so that a conversation goes on after the peer
+// reconnects. The messages sent are marked delivered and read by the receipts
+// of the peer, and the messages received read once shown here.
+use hbb_common::{config::Config, log, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+const MAX_MESSAGES: usize = 500;
+// of a file attached, sent as the dropped files are
+pub const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref LOCK: Mutex<()> = Default::default();
+}
+static LAST_ID: AtomicI64 = AtomicI64::new(0);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub me: bool, // sent from here
+    pub name: String,
+    pub text: String,
+    #[serde(default)]
+    pub file: String,
+    pub time: i64, // in ms
+    #[serde(default)]
+    pub delivered: bool,
+    // by the peer for the messages sent, here for the ones received
+    #[serde(default)]
+    pub read: bool,
+}
+
+/// A new id for a message sent, the time in ms, after the one before.
+pub fn new_id() -> i64 {
+    let now = crate::common::get_time();
+    let last = LAST_ID.load(Ordering::SeqCst);
+    let id = if now > last { now } else { last + 1 };
+    LAST_ID.store(id, Ordering::SeqCst);
+    id
+}
+
+pub fn load(peer_id: &str) -> Vec<Message> {
+    let _lock = LOCK.lock().unwrap();
+    load_(peer_id)
+}
+
+pub fn add(peer_id: &str, msg: Message) {
+    let _lock = LOCK.lock().unwrap();
+    let mut msgs = load_(peer_id);
+    msgs.push(msg);
+    if msgs.len() > MAX_MESSAGES {
+        msgs.drain(..msgs.len() - MAX_MESSAGES);
+    }
+    store(peer_id, &msgs);
+}
+
+/// Marks the messages sent with a receipt of the peer.
+pub fn set_receipt(peer_id: &str, ids: &[i64], read: bool) {
+    let _lock = LOCK.lock().unwrap();
+    let mut msgs = load_(peer_id);
+    if set_receipt_(&mut msgs, ids, read) {
+        store(peer_id, &msgs);
+    }
+}
+
+/// Marks the messages received read, with their ids to acknowledge.
+pub fn read_all(peer_id: &str) -> Vec<i64> {
+    let _lock = LOCK.lock().unwrap();
+    let mut msgs = load_(peer_id);
+    let ids = read_all_(&mut msgs);
+    if !ids.is_empty() {
+        store(peer_id, &msgs);
+    }
+    ids
+}
+
+fn set_receipt_(msgs: &mut [Message], ids: &[i64], read: bool) -> bool {
+    let mut changed = false;
+    for m in msgs.iter_mut().filter(|m| m.me && ids.contains(&m.id)) {
+        if !m.delivered || (read && !m.read) {
+            m.delivered = true;
+            m.read = m.read || read;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn read_all_(msgs: &mut [Message]) -> Vec<i64> {
+    let mut ids = Vec::new();
+    for m in msgs.iter_mut().filter(|m| !m.me && !m.read) {
+        m.read = true;
+        if m.id != 0 {
+            ids.push(m.id);
+        }
+    }
+    ids
+}
+
+fn get_path(peer_id: &str) -> PathBuf {
+    // the id of a direct connection is an address
+    let name: String = peer_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Config::chat_dir().join(format!("{}.log", name))
+}
+
+fn load_(peer_id: &str) -> Vec<Message> {
+    std::fs::read_to_string(get_path(peer_id))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str(x).ok())
+        .collect()
+}
+
+fn store(peer_id: &str, msgs: &[Message]) {
+    if let Err(err) = store_(peer_id, msgs) {
+        log::error!("Failed to store the chat with {}: {}", peer_id, err);
+    }
+}
+
+fn store_(peer_id: &str, msgs: &[Message]) -> ResultType<()> {
+    let path = get_path(peer_id);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut text = String::new();
+    for m in msgs {
+        text.push_str(&serde_json::to_string(m)?);
+        text.push('\n');
+    }
+    std::fs::write(&path, text)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipts() {
+        let mut msgs: Vec<_> = (1..=4)
+            .map(|id| Message {
+                id,
+                me: id % 2 == 1,
+                ..Default::default()
+            })
+            .collect();
+        assert!(set_receipt_(&mut msgs, &[1, 2], false));
+        assert!(msgs[0].delivered && !msgs[0].read);
+        assert!(!msgs[1].delivered);
+        assert!(!set_receipt_(&mut msgs, &[1], false));
+        assert!(set_receipt_(&mut msgs, &[1, 3], true));
+        assert!(msgs[2].delivered && msgs[2].read);
+        assert_eq!(read_all_(&mut msgs), vec![2, 4]);
+        assert!(read_all_(&mut msgs).is_empty());
+        assert!(new_id() < new_id());
+    }
+}
@@ -1,13 +1,25 @@
 use crate::client::*;
+use crossterm::terminal;
 use hbb_common::{
-    config::PeerConfig,
-    log,
+    bail,
+    config::{PeerConfig, CONNECT_TIMEOUT},
+    fs, log,
     message_proto::*,
     protobuf::Message as _,
-    tokio::{self, sync::mpsc},
-    Stream,
+    rendezvous_proto::ConnType,
+    timeout,
+    tokio::{self, io::AsyncReadExt, sync::mpsc, time::Duration},
+    ResultType, Stream,
 };
-use std::sync::{Arc, RwLock};
+use std::{
+    io::Write,
+    sync::{Arc, RwLock},
+};
+
+// the exit codes of `rustdesk cli`, 1 being of the wrong arguments
+pub const EXIT_CONNECT: i32 = 2;
+pub const EXIT_LOGIN: i32 = 3;
+pub const EXIT_TRANSFER: i32 = 4;
 
 #[derive(Clone)]
 pub struct Session {
@@ -15,6 +27,7 @@ pub struct Session {
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    remember: bool,
 }
 
 impl Session {
@@ -23,17 +36,28 @@ impl Session {
         if PeerConfig::load(id).password.is_empty() {
             password = rpassword::read_password_from_tty(Some("Enter password: ")).unwrap();
         }
+        Self::new_(id, sender, password, true, false)
+    }
+
+    fn new_(
+        id: &str,
+        sender: mpsc::UnboundedSender<Data>,
+        password: String,
+        remember: bool,
+        is_file_transfer: bool,
+    ) -> Self {
         let session = Self {
             id: id.to_owned(),
             sender,
             password,
+            remember,
             lc: Default::default(),
         };
         session
             .lc
             .write()
             .unwrap()
-            .initialize(id.to_owned(), false, true);
+            .initialize(id.to_owned(), is_file_transfer, !is_file_transfer);
         session
     }
 }
@@ -43,7 +67,7 @@ impl Interface for Session {
     fn msgbox(&self, msgtype: &str, title: &str, text: &str) {
         if msgtype == "input-password" {
             self.sender
-                .send(Data::Login((self.password.clone(), true)))
+                .send(Data::Login((self.password.clone(), self.remember)))
                 .ok();
         } else if msgtype == "re-input-password" {
             log::error!("{}: {}", title, text);
@@ -85,10 +109,439 @@ pub async fn start_one_port_forward(id: String, port: i32, remote_host: String,
     let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
     let handler = Session::new(&id, sender);
     handler.lc.write().unwrap().port_forward = (remote_host, remote_port);
-    if let Err(err) =
-        crate::port_forward::listen(handler.id.clone(), port, handler.clone(), receiver).await
-    {
+    let id = handler.id.clone();
+    let res = if remote_port == 0 {
+        crate::port_forward::listen_dynamic(id, port, handler.clone(), receiver).await
+    } else {
+        crate::port_forward::listen(id, port, handler.clone(), receiver).await
+    };
+    if let Err(err) = res {
         log::error!("Failed to listen on {}: {}", port, err);
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_udp_forward(id: String, port: i32, remote_host: String, remote_port: i32) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, sender);
+    {
+        let mut lc = handler.lc.write().unwrap();
+        lc.port_forward = (remote_host.clone(), remote_port);
+        lc.udp_forward = true;
+    }
+    let target = (remote_host, remote_port);
+    if let Err(err) =
+        crate::port_forward::listen_udp(id, port, target, handler.clone(), receiver).await
+    {
+        log::error!("Failed to listen on {}/udp: {}", port, err);
+    }
+    log::info!("udp port forward (:{}) exit", port);
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_reverse_forward(
+    id: String,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, sender);
+    {
+        let mut lc = handler.lc.write().unwrap();
+        lc.port_forward = ("".to_owned(), remote_port);
+        lc.reverse_forward = true;
+    }
+    let target = (local_host, local_port);
+    if let Err(err) =
+        crate::port_forward::connect_reverse(id, target, handler.clone(), receiver).await
+    {
+        log::error!(
+            "Failed to forward from the remote port {}: {}",
+            remote_port,
+            err
+        );
+    }
+    log::info!("reverse port forward (:{}) exit", remote_port);
+}
+
+// A shell of the peer in this terminal, which does the emulation, with the keys
+// sent as typed in the raw mode, until the shell exits.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_terminal(id: String) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, sender);
+    let (cols, rows) = terminal::size().unwrap_or((80, 24));
+    handler.lc.write().unwrap().terminal = Some((rows as _, cols as _));
+    if let Err(err) = run_terminal(&id, handler, receiver).await {
+        log::error!("Terminal of {} exit: {}", id, err);
+    }
+    terminal::disable_raw_mode().ok();
+}
+
+async fn run_terminal(
+    id: &str,
+    handler: Session,
+    receiver: mpsc::UnboundedReceiver<Data>,
+) -> ResultType<()> {
+    let (mut stream, _) = Client::start(id, ConnType::TERMINAL).await?;
+    let mut handler = handler;
+    let mut receiver = receiver;
+    loop {
+        tokio::select! {
+            res = timeout(CONNECT_TIMEOUT, stream.next()) => match res {
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::hash(hash)) => {
+                            handler.handle_hash(hash, &mut stream).await;
+                        }
+                        Some(message::Union::login_response(lr)) => match lr.union {
+                            Some(login_response::Union::error(err)) => {
+                                if !handler.handle_login_error(&err) {
+                                    return Ok(());
+                                }
+                            }
+                            Some(login_response::Union::peer_info(pi)) => {
+                                handler.handle_peer_info(pi);
+                                break;
+                            }
+                            _ => {}
+                        }
+                        Some(message::Union::test_delay(t)) => {
+                            handler.handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Err(_) => bail!("Timeout"),
+                _ => bail!("Reset by the peer"),
+            },
+            Some(Data::Login((password, remember))) = receiver.recv() => {
+                handler.handle_login_from_ui(password, remember, &mut stream).await;
+            }
+        }
+    }
+    terminal::enable_raw_mode()?;
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut buf = vec![0u8; 1024];
+    let mut size = terminal::size()?;
+    let mut timer = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    _ => bail!("Reset by the peer"),
+                };
+                let msg_in = Message::parse_from_bytes(&bytes)?;
+                match msg_in.union {
+                    Some(message::Union::terminal_data(d)) => {
+                        if d.closed {
+                            break;
+                        }
+                        stdout.write_all(&d.data)?;
+                        stdout.flush()?;
+                    }
+                    Some(message::Union::test_delay(t)) => {
+                        handler.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::misc(misc)) => {
+                        if let Some(misc::Union::close_reason(reason)) = misc.union {
+                            bail!(reason);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            res = stdin.read(&mut buf) => {
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                let mut msg_out = Message::new();
+                msg_out.set_terminal_data(TerminalData {
+                    data: buf[..n].to_vec().into(),
+                    ..Default::default()
+                });
+                stream.send(&msg_out).await?;
+            }
+            _ = timer.tick() => {
+                let new_size = terminal::size()?;
+                if new_size != size {
+                    size = new_size;
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_resize(Terminal {
+                        rows: size.1 as _,
+                        cols: size.0 as _,
+                        ..Default::default()
+                    });
+                    stream.send(&msg_out).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What `rustdesk cli` does once logged in, nothing but the login without any.
+#[derive(Default)]
+pub struct HeadlessOptions {
+    pub id: String,
+    // the remembered one if empty
+    pub password: String,
+    pub send: Option<String>,
+    pub receive: Option<String>,
+    // the destination of the files sent or received
+    pub to: String,
+    pub include_hidden: bool,
+    // (local port, remote host, remote port)
+    pub port_forward: Option<(i32, String, i32)>,
+    pub json: bool,
+}
+
+// Without any prompt, for the scripts: the events on stdout, a JSON object a
+// line with `json`, and the result in the exit code.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run_headless(options: HeadlessOptions) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let password = options.password.clone();
+    let is_file_transfer = options.port_forward.is_none();
+    let handler = Session::new_(&options.id, sender, password, false, is_file_transfer);
+    let res = match options.port_forward.clone() {
+        Some((port, remote_host, remote_port)) => {
+            handler.lc.write().unwrap().port_forward = (remote_host, remote_port);
+            crate::port_forward::listen(options.id.clone(), port, handler, receiver)
+                .await
+                .map_err(|err| (EXIT_CONNECT, err.to_string()))
+        }
+        None => run_headless_(&options, handler, receiver).await,
+    };
+    match res {
+        Ok(()) => 0,
+        Err((code, err)) => {
+            report(
+                options.json,
+                "error",
+                serde_json::json!({ "code": code, "message": err }),
+            );
+            code
+        }
+    }
+}
+
+async fn run_headless_(
+    options: &HeadlessOptions,
+    handler: Session,
+    receiver: mpsc::UnboundedReceiver<Data>,
+) -> Result<(), (i32, String)> {
+    let mut handler = handler;
+    let (mut stream, pi) = headless_login(&options.id, &mut handler, receiver).await?;
+    report(
+        options.json,
+        "connected",
+        serde_json::json!({
+            "id": options.id,
+            "hostname": pi.hostname,
+            "platform": pi.platform,
+            "version": pi.version,
+        }),
+    );
+    let transfer_error = |err: hbb_common::anyhow::Error| (EXIT_TRANSFER, err.to_string());
+    let (job, msg) = if let Some(path) = options.send.as_ref() {
+        let mut job = fs::TransferJob::new_read(JOB_ID, path.clone(), options.include_hidden)
+            .map_err(transfer_error)?;
+        job.set_resume(fs::can_resume(handler.lc.read().unwrap().version));
+        let msg = fs::new_receive(JOB_ID, options.to.clone(), job.files().clone());
+        (job, msg)
+    } else if let Some(path) = options.receive.as_ref() {
+        let job = fs::TransferJob::new_write(JOB_ID, options.to.clone(), Vec::new());
+        let msg = fs::new_send(JOB_ID, path.clone(), options.include_hidden, Vec::new());
+        (job, msg)
+    } else {
+        return Ok(());
+    };
+    stream.send(&msg).await.map_err(transfer_error)?;
+    let is_read = options.send.is_some();
+    let transferred = transfer(&mut stream, &mut handler, job, is_read, options.json).await?;
+    report(
+        options.json,
+        "done",
+        serde_json::json!({ "transferred": transferred }),
+    );
+    Ok(())
+}
+
+async fn headless_login(
+    id: &str,
+    handler: &mut Session,
+    receiver: mpsc::UnboundedReceiver<Data>,
+) -> Result<(Stream, PeerInfo), (i32, String)> {
+    let connect_error = |err: hbb_common::anyhow::Error| (EXIT_CONNECT, err.to_string());
+    let (mut stream, _) = Client::start(id, ConnType::FILE_TRANSFER)
+        .await
+        .map_err(connect_error)?;
+    let mut receiver = receiver;
+    loop {
+        tokio::select! {
+            res = timeout(CONNECT_TIMEOUT, stream.next()) => match res {
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)
+                        .map_err(|err| (EXIT_CONNECT, err.to_string()))?;
+                    match msg_in.union {
+                        Some(message::Union::hash(hash)) => {
+                            handler.handle_hash(hash, &mut stream).await;
+                        }
+                        Some(message::Union::login_response(lr)) => match lr.union {
+                            // nobody to ask for another password or a 2FA code
+                            Some(login_response::Union::error(err)) => {
+                                return Err((EXIT_LOGIN, err));
+                            }
+                            Some(login_response::Union::peer_info(pi)) => {
+                                handler.handle_peer_info(pi.clone());
+                                return Ok((stream, pi));
+                            }
+                            _ => {}
+                        }
+                        Some(message::Union::test_delay(t)) => {
+                            handler.handle_test_delay(t, &mut stream).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Err(_) => return Err((EXIT_CONNECT, "Timeout".to_owned())),
+                _ => return Err((EXIT_CONNECT, "Reset by the peer".to_owned())),
+            },
+            Some(Data::Login((password, remember))) = receiver.recv() => {
+                handler.handle_login_from_ui(password, remember, &mut stream).await;
+            }
+        }
+    }
+}
+
+const JOB_ID: i32 = 1;
+
+// the bytes transferred of the one job, reading the files to send if `is_read`
+async fn transfer(
+    stream: &mut Stream,
+    handler: &mut Session,
+    job: fs::TransferJob,
+    is_read: bool,
+    json: bool,
+) -> Result<u64, (i32, String)> {
+    let transfer_error = |err: hbb_common::anyhow::Error| (EXIT_TRANSFER, err.to_string());
+    let mut jobs = vec![job];
+    let mut timer = tokio::time::interval(Duration::from_millis(if is_read { 1 } else { 1000 }));
+    let mut last_report = std::time::Instant::now();
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    _ => return Err((EXIT_TRANSFER, "Reset by the peer".to_owned())),
+                };
+                let msg_in = Message::parse_from_bytes(&bytes)
+                    .map_err(|err| (EXIT_TRANSFER, err.to_string()))?;
+                match msg_in.union {
+                    Some(message::Union::file_response(fr)) => match fr.union {
+                        Some(file_response::Union::dir(fd)) => {
+                            if let Some(job) = fs::get_job(fd.id, &mut jobs) {
+                                job.set_files(fd.entries.to_vec());
+                            }
+                        }
+                        Some(file_response::Union::block(block)) => {
+                            if let Some(job) = fs::get_job(block.id, &mut jobs) {
+                                job.write(block).await.map_err(transfer_error)?;
+                            }
+                        }
+                        Some(file_response::Union::digest(d)) => {
+                            if let Some(job) = fs::get_job(d.id, &mut jobs) {
+                                let msg = job.handle_digest(d).await;
+                                stream.send(&msg).await.map_err(transfer_error)?;
+                            }
+                        }
+                        Some(file_response::Union::done(d)) => {
+                            if let Some(job) = fs::get_job(d.id, &mut jobs) {
+                                job.write_done();
+                                return Ok(job.transferred());
+                            }
+                        }
+                        Some(file_response::Union::error(e)) if e.id == JOB_ID => {
+                            return Err((EXIT_TRANSFER, e.error));
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::file_action(fa)) => {
+                        if let Some(file_action::Union::send_confirm(r)) = fa.union {
+                            if let Some(job) = fs::get_job(r.id, &mut jobs) {
+                                job.confirm(&r).await.map_err(transfer_error)?;
+                            }
+                        }
+                    }
+                    Some(message::Union::test_delay(t)) => {
+                        handler.handle_test_delay(t, stream).await;
+                    }
+                    Some(message::Union::misc(misc)) => {
+                        if let Some(misc::Union::close_reason(reason)) = misc.union {
+                            return Err((EXIT_TRANSFER, reason));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                // the job is removed once all is read and sent
+                let transferred = jobs.first().map(|x| x.transferred()).unwrap_or(0);
+                if is_read {
+                    fs::handle_read_jobs(&mut jobs, stream).await.map_err(transfer_error)?;
+                    if jobs.is_empty() {
+                        return Ok(transferred);
+                    }
+                }
+                if last_report.elapsed().as_secs() >= 1 {
+                    last_report = std::time::Instant::now();
+                    if let Some(job) = jobs.first() {
+                        report(
+                            json,
+                            "progress",
+                            serde_json::json!({
+                                "finished": job.finished_size(),
+                                "total": job.total_size(),
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+// `event k=v ...`, or a JSON object with the event in it
+fn report(json: bool, event: &str, value: serde_json::Value) {
+    let mut value = value;
+    if json {
+        value["event"] = event.into();
+        println!("{}", value);
+        return;
+    }
+    let mut line = event.to_owned();
+    if let Some(map) = value.as_object() {
+        for (k, v) in map {
+            match v.as_str() {
+                Some(v) => line += &format!(" {}={}", k, v),
+                None => line += &format!(" {}={}", k, v),
+            }
+        }
+    }
+    println!("{}", line);
+}
@@ -8,17 +8,25 @@ use hbb_common::{
     allow_err,
     anyhow::{anyhow, Context},
     bail,
-    config::{Config, PeerConfig, PeerInfoSerde, CONNECT_TIMEOUT, RELAY_PORT, RENDEZVOUS_TIMEOUT},
-    log,
+    capabilities::{self, Capabilities},
+    config::{
+        self, AddressBook, Config, PeerConfig, PeerInfoSerde, CONNECT_TIMEOUT, RELAY_PORT,
+        RENDEZVOUS_TIMEOUT,
+    },
+    keepalive, log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::*,
+    rudp::ReliableChannel,
     socket_client,
     sodiumoxide::crypto::{box_, secretbox, sign},
     timeout,
     tokio::time::Duration,
+    udp::FramedSocket,
     AddrMangle, ResultType, Stream,
 };
+#[cfg(not(any(target_os = "android")))]
+use magnum_opus::{Application::Voip, Encoder as AudioEncoder};
 use magnum_opus::{Channels::*, Decoder as AudioDecoder};
 use scrap::{Decoder, Image, VideoCodecId};
 use sha2::{Digest, Sha256};
@@ -29,8 +37,17 @@ use std::{
     sync::{Arc, Mutex, RwLock},
 };
 use uuid::Uuid;
+use webm::mux::{self, Track};
+#[cfg(not(any(target_os = "android")))]
+use webrtc_audio_processing::{
+    Config as ProcessorConfig, EchoCancellation, EchoCancellationSuppressionLevel,
+    InitializationConfig, Processor, NUM_SAMPLES_PER_FRAME,
+};
 
 pub const SEC30: Duration = Duration::from_secs(30);
+const RELAY_PING_TIMEOUT: u64 = 1_000;
+#[cfg(feature = "quic")]
+const QUIC_TIMEOUT: u64 = 3_000;
 
 pub struct Client;
 
@@ -41,6 +58,16 @@ lazy_static::lazy_static! {
 static ref AUDIO_HOST: Host = cpal::default_host();
 }
 
+lazy_static::lazy_static! {
+    // peer id -> the key of the peer not matching the pinned one,
+    // empty if the peer could not be verified at all
+    static ref KEY_CHANGES: Mutex<HashMap<String, Vec<u8>>> = Default::default();
+}
+
+/// The error of a connection to a peer whose key is not the pinned one,
+/// see `get_key_change` and `trust_peer_key`.
+pub const KEY_CHANGED: &str = "Peer key changed";
+
 cfg_if::cfg_if! {
     if #[cfg(target_os = "android")] {
 
@@ -120,13 +147,18 @@ impl Client {
         // to-do: remember the port for each peer, so that we can retry easier
         let any_addr = Config::get_any_listen_addr();
         if crate::is_ip(peer) {
+            let peer = crate::check_port(peer, RELAY_PORT + 1);
+            // the direct server listens on both tcp and udp (QUIC) if built with QUIC,
+            // there is no rendezvous exchange to tell it, so fall back to tcp if the
+            // peer does not answer QUIC
+            #[cfg(feature = "quic")]
+            if let Ok(addr) = peer.parse::<SocketAddr>() {
+                if let Some(stream) = Self::connect_quic(addr).await {
+                    return Ok((stream, true));
+                }
+            }
             return Ok((
-                socket_client::connect_tcp(
-                    crate::check_port(peer, RELAY_PORT + 1),
-                    any_addr,
-                    RENDEZVOUS_TIMEOUT,
-                )
-                .await?,
+                socket_client::connect_tcp(peer, any_addr, RENDEZVOUS_TIMEOUT).await?,
                 true,
             ));
         }
@@ -144,6 +176,8 @@ impl Client {
         let mut peer_nat_type = NatType::UNKNOWN_NAT;
         let my_nat_type = crate::get_nat_type(100).await;
         let mut is_local = false;
+        let mut tcp_punch = false;
+        let mut quic = false;
         for i in 1..=3 {
             log::info!("#{} punch attempt with {}, id: {}", i, my_addr, peer);
             let mut msg_out = RendezvousMessage::new();
@@ -153,6 +187,7 @@ impl Client {
                 id: peer.to_owned(),
                 nat_type: nat_type.into(),
                 conn_type: conn_type.into(),
+                tcp_punch: crate::is_tcp_punch_enabled(),
                 ..Default::default()
             });
             socket.send(&msg_out).await?;
@@ -180,8 +215,14 @@ impl Client {
                             } else {
                                 peer_nat_type = ph.get_nat_type();
                                 is_local = ph.get_is_local();
+                                tcp_punch = ph.tcp_punch;
+                                quic = ph.quic;
                                 pk = ph.pk;
                                 relay_server = ph.relay_server;
+                                let custom = Config::get_option("relay-server");
+                                if !custom.is_empty() {
+                                    relay_server = custom;
+                                }
                                 peer_addr = AddrMangle::decode(&ph.socket_addr);
                                 log::info!("Hole Punched {} = {}", peer, peer_addr);
                                 break;
@@ -235,11 +276,33 @@ impl Client {
             peer_nat_type,
             my_nat_type,
             is_local,
+            tcp_punch,
+            quic,
             conn_type,
         )
         .await
     }
 
+    // with the option "enable-quic", and not through a socks proxy which has no
+    // udp here, None to fall back to tcp
+    #[cfg(feature = "quic")]
+    async fn connect_quic(addr: SocketAddr) -> Option<Stream> {
+        if Config::get_option("enable-quic") != "Y" || Config::get_socks().is_some() {
+            return None;
+        }
+        let any_addr = Config::get_any_listen_addr();
+        match hbb_common::quic::connect(addr, any_addr, QUIC_TIMEOUT).await {
+            Ok(stream) => {
+                log::info!("QUIC connection established with {}", addr);
+                Some(stream)
+            }
+            Err(err) => {
+                log::info!("QUIC to {} failed, fallback to tcp: {}", addr, err);
+                None
+            }
+        }
+    }
+
     async fn connect(
         local_addr: SocketAddr,
         peer: SocketAddr,
@@ -251,6 +314,8 @@ impl Client {
         peer_nat_type: NatType,
         my_nat_type: i32,
         is_local: bool,
+        tcp_punch: bool,
+        quic: bool,
         conn_type: ConnType,
     ) -> ResultType<(Stream, bool)> {
         let direct_failures = PeerConfig::load(peer_id).direct_failures;
@@ -287,14 +352,33 @@ impl Client {
         }
         log::info!("peer address: {}, timeout: {}", peer, connect_timeout);
         let start = std::time::Instant::now();
+        // the direct server of the peer, if it told it takes QUIC
+        #[cfg(feature = "quic")]
+        let quic_conn = if quic {
+            Self::connect_quic(SocketAddr::new(peer.ip(), (RELAY_PORT + 1) as _)).await
+        } else {
+            None
+        };
+        #[cfg(not(feature = "quic"))]
+        let quic_conn: Option<Stream> = {
+            let _ = quic;
+            None
+        };
         // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
-        let mut conn = socket_client::connect_tcp(peer, local_addr, connect_timeout).await;
+        let mut conn = if let Some(conn) = quic_conn {
+            Ok(conn)
+        } else if tcp_punch && !is_local {
+            // the peer is connecting to us at the same time, see punch_connection
+            socket_client::punch_tcp(peer, local_addr, connect_timeout).await
+        } else {
+            socket_client::connect_tcp(peer, local_addr, connect_timeout).await
+        };
         let direct = !conn.is_err();
         if conn.is_err() {
             if !relay_server.is_empty() {
                 conn = Self::request_relay(
                     peer_id,
-                    relay_server.to_owned(),
+                    Self::select_relay_server(relay_server).await,
                     rendezvous_server,
                     pk.len() == sign::PUBLICKEYBYTES,
                     conn_type,
@@ -322,6 +406,30 @@ impl Client {
         Ok((conn, direct))
     }
 
+    /// Say hello to the udp media channel offered by the peer, video and audio
+    /// arrive on it once established, everything else stays on `peer`.
+    /// The offered address is the peer's local one, so only try on a direct connection.
+    pub async fn connect_media_channel(
+        mc: &MediaChannel,
+        peer: &Stream,
+    ) -> ResultType<ReliableChannel> {
+        if !Config::get_option("enable-udp-media").is_empty() {
+            bail!("Udp media channel disabled");
+        }
+        let key = match peer.get_key() {
+            Some(key) => key,
+            None => bail!("Udp media channel requires a secured connection"),
+        };
+        let addr = AddrMangle::decode(&mc.addr);
+        let socket = FramedSocket::new(Config::get_any_listen_addr()).await?;
+        Ok(ReliableChannel::connect(
+            socket,
+            addr,
+            mc.token.clone(),
+            key,
+        ))
+    }
+
     async fn secure_connection(peer_id: &str, pk: Vec<u8>, conn: &mut Stream) -> ResultType<()> {
         let mut pk = pk;
         const RS_PK: &[u8; 32] = &[
@@ -338,6 +446,15 @@ impl Client {
             }
         }
         if pk.len() != sign::PUBLICKEYBYTES {
+            pk.clear();
+        }
+        // trust on first use, a key change may be a man in the middle,
+        // e.g. a compromised rendezvous or relay server
+        let pinned = PeerConfig::load(peer_id).pinned_key;
+        if !pinned.is_empty() && pk != pinned {
+            return Self::key_changed(peer_id, pk);
+        }
+        if pk.is_empty() {
             // send an empty message out in case server is setting up secure and waiting for first message
             conn.send(&Message::new()).await?;
             return Ok(());
@@ -380,10 +497,17 @@ impl Client {
                                 });
                                 timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
                                 conn.set_key(key);
+                                if pinned.is_empty() {
+                                    let mut config = PeerConfig::load(peer_id);
+                                    config.pinned_key = sign_pk.0.to_vec();
+                                    config.store(peer_id);
+                                }
                             } else {
                                 log::error!("Handshake failed: sign failure");
                                 conn.send(&Message::new()).await?;
                             }
+                        } else if !pinned.is_empty() {
+                            return Self::key_changed(peer_id, Vec::new());
                         } else {
                             // fall back to non-secure connection in case pk mismatch
                             log::info!("pk mismatch, fall back to non-secure");
@@ -391,6 +515,8 @@ impl Client {
                             msg_out.set_public_key(PublicKey::new());
                             timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
                         }
+                    } else if !pinned.is_empty() {
+                        return Self::key_changed(peer_id, Vec::new());
                     } else {
                         log::error!("Handshake failed: invalid message type");
                         conn.send(&Message::new()).await?;
@@ -407,6 +533,12 @@ impl Client {
         Ok(())
     }
 
+    fn key_changed(peer_id: &str, pk: Vec<u8>) -> ResultType<()> {
+        log::warn!("{}: {}, connection refused", KEY_CHANGED, peer_id);
+        KEY_CHANGES.lock().unwrap().insert(peer_id.to_owned(), pk);
+        bail!(KEY_CHANGED);
+    }
+
     async fn request_relay(
         peer: &str,
         relay_server: String,
@@ -460,6 +592,84 @@ impl Client {
         Self::create_relay(peer, uuid, relay_server, conn_type).await
     }
 
+    /// Pick one of the comma separated `relay_server`, the first one echoing a
+    /// ping when racing connections to all of them. If none of them echoes,
+    /// e.g. with older relay servers, the one connected first.
+    pub async fn select_relay_server(relay_server: &str) -> String {
+        let candidates: Vec<String> = relay_server
+            .split(",")
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect();
+        if candidates.len() <= 1 {
+            return candidates.into_iter().next().unwrap_or_default();
+        }
+        let (tx, mut rx) = hbb_common::tokio::sync::mpsc::unbounded_channel();
+        for host in candidates.iter() {
+            let host = host.clone();
+            let tx = tx.clone();
+            hbb_common::tokio::spawn(async move {
+                let res = Self::ping_relay(&host).await;
+                if let Err(err) = &res {
+                    log::debug!("Failed to ping relay server {}: {}", host, err);
+                }
+                tx.send((host, res.ok())).ok();
+            });
+        }
+        drop(tx);
+        let mut connected_first = None;
+        while let Some((host, res)) = rx.recv().await {
+            match res {
+                Some((_, Some(rtt))) => {
+                    log::info!("Relay server {} selected, rtt: {:?}", host, rtt);
+                    return host;
+                }
+                Some((connect_time, None)) if connected_first.is_none() => {
+                    connected_first = Some((host, connect_time));
+                }
+                _ => {}
+            }
+        }
+        match connected_first {
+            Some((host, connect_time)) => {
+                log::info!(
+                    "Relay server {} selected, connect time: {:?}",
+                    host,
+                    connect_time
+                );
+                host
+            }
+            None => candidates[0].clone(),
+        }
+    }
+
+    // (connect time, echo time if the relay server knows the ping)
+    async fn ping_relay(host: &str) -> ResultType<(Duration, Option<Duration>)> {
+        let tm = std::time::Instant::now();
+        let mut conn = socket_client::connect_tcp(
+            crate::check_port(host, RELAY_PORT),
+            Config::get_any_listen_addr(),
+            RELAY_PING_TIMEOUT,
+        )
+        .await?;
+        let connect_time = tm.elapsed();
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_relay_ping(RelayPing {
+            time: crate::get_time(),
+            ..Default::default()
+        });
+        conn.send(&msg_out).await?;
+        if let Some(Ok(bytes)) = conn.next_timeout(RELAY_PING_TIMEOUT).await {
+            if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+                if let Some(rendezvous_message::Union::relay_ping(_)) = msg_in.union {
+                    return Ok((connect_time, Some(tm.elapsed())));
+                }
+            }
+        }
+        Ok((connect_time, None))
+    }
+
     async fn create_relay(
         peer: &str,
         uuid: String,
@@ -485,6 +695,27 @@ impl Client {
     }
 }
 
+/// The fingerprints of the pinned key of the peer and of the one it has now,
+/// after a connection to it failed with `KEY_CHANGED`.
+pub fn get_key_change(peer_id: &str) -> Option<(String, String)> {
+    let key = KEY_CHANGES.lock().unwrap().get(peer_id)?.clone();
+    let pinned = PeerConfig::load(peer_id).pinned_key;
+    Some((
+        crate::common::get_fingerprint(&pinned),
+        crate::common::get_fingerprint(&key),
+    ))
+}
+
+/// Accept the key change of the peer: pin its new key, or unpin the old one if
+/// the peer could not be verified, so that the next secured connection pins it.
+pub fn trust_peer_key(peer_id: &str) {
+    if let Some(key) = KEY_CHANGES.lock().unwrap().remove(peer_id) {
+        let mut config = PeerConfig::load(peer_id);
+        config.pinned_key = key;
+        config.store(peer_id);
+    }
+}
+
 #[derive(Default)]
 pub struct AudioHandler {
     audio_decoder: Option<(AudioDecoder, Vec<f32>)>,
@@ -496,6 +727,8 @@ pub struct AudioHandler {
     #[cfg(not(any(target_os = "android")))]
     audio_stream: Option<Box<dyn StreamTrait>>,
     channels: u16,
+    #[cfg(not(any(target_os = "android")))]
+    echo: Option<EchoCanceller>,
 }
 
 impl AudioHandler {
@@ -556,6 +789,7 @@ impl AudioHandler {
         let channels = self.channels;
         cfg_if::cfg_if! {
         if #[cfg(not(target_os = "android"))] {
+        let echo = self.echo.clone();
         let audio_buffer = self.audio_buffer.clone();
         // avoiding memory overflow if audio_buffer consumer side has problem
         if audio_buffer.lock().unwrap().len() as u32 > sample_rate * 120 {
@@ -576,6 +810,9 @@ impl AudioHandler {
                 let n = n * (channels as usize);
                 #[cfg(not(any(target_os = "android")))]
                 {
+                    if let Some(echo) = echo {
+                        echo.process_render(&mut buffer[0..n]);
+                    }
                     if sample_rate != sample_rate0 {
                         let buffer = crate::resample_channels(
                             &buffer[0..n],
@@ -634,8 +871,286 @@ impl AudioHandler {
     }
 }
 
+enum Voice {
+    Format(AudioFormat),
+    Frame(AudioFrame),
+}
+
+/// The microphone of the peer on the speakers here. The output stream of cpal
+/// can not be moved across threads, so it is played in a thread of its own, which
+/// ends when this is dropped.
+pub struct VoicePlayer(std::sync::mpsc::Sender<Voice>);
+
+impl VoicePlayer {
+    pub fn new(format: AudioFormat) -> Self {
+        Self::start(format, |_| {})
+    }
+
+    /// The echo of what is played is removed from the microphone of a call.
+    #[cfg(not(any(target_os = "android")))]
+    pub fn with_echo_canceller(format: AudioFormat, echo: EchoCanceller) -> Self {
+        Self::start(format, move |handler| handler.echo = Some(echo))
+    }
+
+    fn start<F: FnOnce(&mut AudioHandler) + Send + 'static>(format: AudioFormat, init: F) -> Self {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut handler = AudioHandler::default();
+            init(&mut handler);
+            while let Ok(voice) = rx.recv() {
+                match voice {
+                    Voice::Format(f) => handler.handle_format(f),
+                    Voice::Frame(f) => handler.handle_frame(f, true),
+                }
+            }
+            log::info!("Voice of the peer stopped");
+        });
+        tx.send(Voice::Format(format)).ok();
+        Self(tx)
+    }
+
+    #[inline]
+    pub fn play(&self, frame: AudioFrame) {
+        self.0.send(Voice::Frame(frame)).ok();
+    }
+}
+
+/// Removes from the microphone the voice of the peer played on the speakers, both
+/// mono at 48 kHz and in frames of 10 ms.
+#[cfg(not(any(target_os = "android")))]
+#[derive(Clone)]
+pub struct EchoCanceller(Arc<Mutex<Processor>>);
+
+#[cfg(not(any(target_os = "android")))]
+impl EchoCanceller {
+    pub const SAMPLE_RATE: u32 = 48000;
+
+    pub fn new() -> ResultType<Self> {
+        let mut processor = Processor::new(&InitializationConfig {
+            num_capture_channels: 1,
+            num_render_channels: 1,
+            ..Default::default()
+        })
+        .map_err(|e| anyhow!("Failed to create the echo canceller: {:?}", e))?;
+        processor.set_config(ProcessorConfig {
+            echo_cancellation: Some(EchoCancellation {
+                suppression_level: EchoCancellationSuppressionLevel::High,
+                enable_delay_agnostic: true,
+                enable_extended_filter: true,
+                stream_delay_ms: None,
+            }),
+            enable_high_pass_filter: true,
+            ..Default::default()
+        });
+        Ok(Self(Arc::new(Mutex::new(processor))))
+    }
+
+    fn process_capture(&self, data: &mut [f32]) {
+        let mut processor = self.0.lock().unwrap();
+        for frame in data.chunks_exact_mut(NUM_SAMPLES_PER_FRAME as _) {
+            processor.process_capture_frame(frame).ok();
+        }
+    }
+
+    fn process_render(&self, data: &mut [f32]) {
+        let mut processor = self.0.lock().unwrap();
+        for frame in data.chunks_exact_mut(NUM_SAMPLES_PER_FRAME as _) {
+            processor.process_render_frame(frame).ok();
+        }
+    }
+}
+
+/// The microphone here, encoded with opus for the speakers of the peer. The input
+/// stream of cpal can not be moved across threads, so it lives in a thread of its
+/// own until this is dropped. With an echo canceller it is the voice of a call,
+/// mono at its rate and sent as `voice_format` and `voice_frame`.
+#[cfg(not(any(target_os = "android")))]
+pub struct Microphone {
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
+#[cfg(not(any(target_os = "android")))]
+impl Microphone {
+    pub fn start<F: FnMut(Message) + Send + 'static>(
+        send: F,
+        echo: Option<EchoCanceller>,
+    ) -> ResultType<Self> {
+        let (tx_stop, rx_stop) = std::sync::mpsc::channel::<()>();
+        let (tx_res, rx_res) = std::sync::mpsc::channel();
+        std::thread::spawn(move || match Self::build_input_stream(send, echo) {
+            Ok(stream) => {
+                tx_res.send(Ok(())).ok();
+                // until the sender is dropped
+                rx_stop.recv().ok();
+                drop(stream);
+                log::info!("Microphone stopped");
+            }
+            Err(err) => {
+                tx_res.send(Err(err)).ok();
+            }
+        });
+        rx_res.recv()??;
+        Ok(Self { _stop: tx_stop })
+    }
+
+    fn build_input_stream<F: FnMut(Message) + Send + 'static>(
+        mut send: F,
+        echo: Option<EchoCanceller>,
+    ) -> ResultType<Box<dyn StreamTrait>> {
+        let device = AUDIO_HOST
+            .default_input_device()
+            .with_context(|| "Failed to get default input device")?;
+        log::info!(
+            "Using default input device: \"{}\"",
+            device.name().unwrap_or("".to_owned())
+        );
+        let config = device.default_input_config().map_err(|e| anyhow!(e))?;
+        log::info!("Default input format: {:?}", config);
+        let sample_rate0 = config.sample_rate().0;
+        let channels0 = config.channels();
+        let (sample_rate, channels) = if echo.is_some() {
+            (EchoCanceller::SAMPLE_RATE, 1)
+        } else {
+            // one of those of opus
+            let sample_rate = [48000, 24000, 16000, 12000]
+                .iter()
+                .find(|r| **r <= sample_rate0)
+                .cloned()
+                .unwrap_or(8000);
+            (sample_rate, channels0)
+        };
+        let mut encoder =
+            AudioEncoder::new(sample_rate, if channels > 1 { Stereo } else { Mono }, Voip)?;
+        let format = AudioFormat {
+            sample_rate,
+            channels: channels as _,
+            ..Default::default()
+        };
+        let is_call = echo.is_some();
+        let mut misc = Misc::new();
+        if is_call {
+            misc.set_voice_format(format);
+        } else {
+            misc.set_audio_format(format);
+        }
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        send(msg_out);
+        // opus takes frames of 10 ms
+        let frame_size = (sample_rate / 100) as usize * channels as usize;
+        let mut buffer = Vec::new();
+        let mut on_data = move |data: &[f32]| {
+            let mono;
+            let data = if channels != channels0 {
+                mono = data
+                    .chunks(channels0 as _)
+                    .map(|x| x.iter().sum::<f32>() / x.len() as f32)
+                    .collect::<Vec<_>>();
+                &mono[..]
+            } else {
+                data
+            };
+            if sample_rate != sample_rate0 {
+                buffer.extend(crate::resample_channels(
+                    data,
+                    sample_rate0,
+                    sample_rate,
+                    channels,
+                ));
+            } else {
+                buffer.extend_from_slice(data);
+            }
+            while buffer.len() >= frame_size {
+                let rest = buffer.split_off(frame_size);
+                if let Some(echo) = echo.as_ref() {
+                    echo.process_capture(&mut buffer);
+                }
+                if let Ok(data) = encoder.encode_vec_float(&buffer, frame_size * 6) {
+                    let frame = AudioFrame {
+                        data,
+                        ..Default::default()
+                    };
+                    let mut msg_out = Message::new();
+                    if is_call {
+                        msg_out.set_voice_frame(frame);
+                    } else {
+                        msg_out.set_audio_frame(frame);
+                    }
+                    send(msg_out);
+                }
+                buffer = rest;
+            }
+        };
+        let err_fn = move |err| {
+            log::trace!("an error occurred on stream: {}", err);
+        };
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_input_stream(
+                &config.into(),
+                move |data: &[f32], _: &_| on_data(data),
+                err_fn,
+            )?,
+            cpal::SampleFormat::I16 => device.build_input_stream(
+                &config.into(),
+                move |data: &[i16], _: &_| {
+                    let buffer: Vec<_> = data.iter().map(|s| cpal::Sample::to_f32(s)).collect();
+                    on_data(&buffer);
+                },
+                err_fn,
+            )?,
+            cpal::SampleFormat::U16 => device.build_input_stream(
+                &config.into(),
+                move |data: &[u16], _: &_| {
+                    let buffer: Vec<_> = data.iter().map(|s| cpal::Sample::to_f32(s)).collect();
+                    on_data(&buffer);
+                },
+                err_fn,
+            )?,
+        };
+        stream.play()?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// A call with the peer, its voice on the speakers here without the echo of it
+/// in the microphone.
+#[cfg(not(any(target_os = "android")))]
+pub struct VoiceCall {
+    _microphone: Microphone,
+    echo: EchoCanceller,
+    player: Option<VoicePlayer>,
+}
+
+#[cfg(not(any(target_os = "android")))]
+impl VoiceCall {
+    pub fn start<F: FnMut(Message) + Send + 'static>(send: F) -> ResultType<Self> {
+        let echo = EchoCanceller::new()?;
+        Ok(Self {
+            _microphone: Microphone::start(send, Some(echo.clone()))?,
+            echo,
+            player: None,
+        })
+    }
+
+    pub fn handle_format(&mut self, format: AudioFormat) {
+        self.player = Some(VoicePlayer::with_echo_canceller(format, self.echo.clone()));
+    }
+
+    pub fn handle_frame(&self, frame: AudioFrame) {
+        if let Some(player) = self.player.as_ref() {
+            player.play(frame);
+        }
+    }
+}
+
 pub struct VideoHandler {
     decoder: Decoder,
+    #[cfg(feature = "av1")]
+    av1_decoder: Option<scrap::av1::Decoder>, // created with the first av1 frame
+    #[cfg(feature = "hwcodec")]
+    h264_decoder: Option<scrap::hwcodec::HwDecoder>, // created with the first h264 frame
+    #[cfg(feature = "hwcodec")]
+    h265_decoder: Option<scrap::hwcodec::HwDecoder>, // created with the first h265 frame
     pub rgb: Vec<u8>,
 }
 
@@ -643,10 +1158,124 @@ impl VideoHandler {
     pub fn new() -> Self {
         VideoHandler {
             decoder: Decoder::new(VideoCodecId::VP9, 0).unwrap(),
+            #[cfg(feature = "av1")]
+            av1_decoder: None,
+            #[cfg(feature = "hwcodec")]
+            h264_decoder: None,
+            #[cfg(feature = "hwcodec")]
+            h265_decoder: None,
             rgb: Default::default(),
         }
     }
 
+    // h265 only if the gpu decodes it, it is too slow in software
+    #[inline]
+    pub fn supported_decoding(prefer: &str) -> SupportedDecoding {
+        SupportedDecoding {
+            ability_av1: cfg!(feature = "av1"),
+            ability_h264: cfg!(feature = "hwcodec"),
+            #[cfg(feature = "hwcodec")]
+            ability_h265: scrap::hwcodec::has_hw_decoder(scrap::hwcodec::HwCodec::H265),
+            prefer: match prefer {
+                "vp9" => supported_decoding::PreferCodec::VP9,
+                "av1" => supported_decoding::PreferCodec::AV1,
+                "h264" => supported_decoding::PreferCodec::H264,
+                "h265" => supported_decoding::PreferCodec::H265,
+                _ => supported_decoding::PreferCodec::Auto,
+            }
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "av1")]
+    pub fn handle_av1s(&mut self, av1s: &AV1s) -> ResultType<bool> {
+        if self.av1_decoder.is_none() {
+            self.av1_decoder = Some(scrap::av1::Decoder::new(0)?);
+        }
+        let mut ok = false;
+        if let Some(decoder) = self.av1_decoder.as_mut() {
+            for av1 in av1s.frames.iter() {
+                // the image is only valid until the next decode
+                if let Some(image) = decoder.decode(&av1.data)?.last() {
+                    image.rgb(1, true, &mut self.rgb)?;
+                    ok = true;
+                }
+            }
+        }
+        Ok(ok)
+    }
+
+    #[cfg(not(feature = "av1"))]
+    pub fn handle_av1s(&mut self, _av1s: &AV1s) -> ResultType<bool> {
+        bail!("AV1 is not supported");
+    }
+
+    #[cfg(feature = "hwcodec")]
+    pub fn handle_h264s(&mut self, h264s: &H264s) -> ResultType<bool> {
+        Self::handle_hw(
+            &mut self.h264_decoder,
+            scrap::hwcodec::HwCodec::H264,
+            h264s.frames.iter().map(|f| &f.data[..]),
+            &mut self.rgb,
+        )
+    }
+
+    #[cfg(not(feature = "hwcodec"))]
+    pub fn handle_h264s(&mut self, _h264s: &H264s) -> ResultType<bool> {
+        bail!("H264 is not supported");
+    }
+
+    #[cfg(feature = "hwcodec")]
+    pub fn handle_h265s(&mut self, h265s: &H265s) -> ResultType<bool> {
+        Self::handle_hw(
+            &mut self.h265_decoder,
+            scrap::hwcodec::HwCodec::H265,
+            h265s.frames.iter().map(|f| &f.data[..]),
+            &mut self.rgb,
+        )
+    }
+
+    #[cfg(not(feature = "hwcodec"))]
+    pub fn handle_h265s(&mut self, _h265s: &H265s) -> ResultType<bool> {
+        bail!("H265 is not supported");
+    }
+
+    #[cfg(feature = "hwcodec")]
+    fn handle_hw<'a>(
+        decoder: &mut Option<scrap::hwcodec::HwDecoder>,
+        codec: scrap::hwcodec::HwCodec,
+        frames: impl Iterator<Item = &'a [u8]>,
+        rgb: &mut Vec<u8>,
+    ) -> ResultType<bool> {
+        if decoder.is_none() {
+            let d = scrap::hwcodec::HwDecoder::new(codec)?;
+            log::info!("{:?} decoder, hardware: {}", codec, d.is_hardware());
+            *decoder = Some(d);
+        }
+        let mut ok = false;
+        if let Some(decoder) = decoder.as_mut() {
+            for data in frames {
+                ok |= decoder.decode(data)?;
+            }
+            if ok {
+                decoder.rgb(1, true, rgb)?;
+            }
+        }
+        Ok(ok)
+    }
+
+    /// Whether a new image is decoded into `rgb`.
+    pub fn handle_frame(&mut self, vf: &VideoFrame) -> ResultType<bool> {
+        match &vf.union {
+            Some(video_frame::Union::vp9s(vp9s)) => self.handle_vp9s(vp9s),
+            Some(video_frame::Union::av1s(av1s)) => self.handle_av1s(av1s),
+            Some(video_frame::Union::h264s(h264s)) => self.handle_h264s(h264s),
+            Some(video_frame::Union::h265s(h265s)) => self.handle_h265s(h265s),
+            _ => Ok(false),
+        }
+    }
+
     pub fn handle_vp9s(&mut self, vp9s: &VP9s) -> ResultType<bool> {
         let mut last_frame = Image::new();
         for vp9 in vp9s.frames.iter() {
@@ -669,6 +1298,156 @@ impl VideoHandler {
 
     pub fn reset(&mut self) {
         self.decoder = Decoder::new(VideoCodecId::VP9, 1).unwrap();
+        #[cfg(feature = "av1")]
+        {
+            self.av1_decoder = None;
+        }
+        #[cfg(feature = "hwcodec")]
+        {
+            self.h264_decoder = None;
+            self.h265_decoder = None;
+        }
+    }
+}
+
+/// The video as received here, shown next to the `SessionStats` of the peer.
+pub struct VideoStats {
+    frames: usize,
+    bytes: usize,
+    since: std::time::Instant,
+    pub codec: &'static str,
+}
+
+impl Default for VideoStats {
+    fn default() -> Self {
+        Self {
+            frames: 0,
+            bytes: 0,
+            since: std::time::Instant::now(),
+            codec: "",
+        }
+    }
+}
+
+impl VideoStats {
+    /// `bytes` is the size of the message on the wire.
+    pub fn on_frame(&mut self, vf: &VideoFrame, bytes: usize) {
+        self.bytes += bytes;
+        if vf.display > 0 {
+            return;
+        }
+        self.frames += 1;
+        self.codec = match &vf.union {
+            Some(video_frame::Union::vp9s(_)) => "VP9",
+            Some(video_frame::Union::av1s(_)) => "AV1",
+            Some(video_frame::Union::h264s(_)) => "H264",
+            Some(video_frame::Union::h265s(_)) => "H265",
+            _ => self.codec,
+        };
+    }
+
+    /// (fps, kbps) since the last call.
+    pub fn take(&mut self) -> (u32, u32) {
+        let ms = (self.since.elapsed().as_millis() as usize).max(1);
+        let res = ((self.frames * 1000 / ms) as _, (self.bytes * 8 / ms) as _);
+        *self = Self {
+            codec: self.codec,
+            ..Default::default()
+        };
+        res
+    }
+}
+
+/// Writes the VP9 video and the Opus audio received from the peer into a webm file,
+/// as they are, without decoding or encoding them again.
+pub struct Recorder {
+    pub path: std::path::PathBuf,
+    segment: Option<mux::Segment<mux::Writer<std::fs::File>>>,
+    video: mux::VideoTrack,
+    audio: Option<mux::AudioTrack>,
+    start: std::time::Instant,
+    key: bool,
+}
+
+impl Recorder {
+    /// The audio track can only be added before the first frame,
+    /// so `audio` is the format the peer has announced so far, if any.
+    pub fn new(
+        dir: &std::path::Path,
+        name: &str,
+        width: usize,
+        height: usize,
+        audio: Option<&AudioFormat>,
+    ) -> ResultType<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.webm", name));
+        let file = std::fs::File::create(&path)?;
+        let mut segment = match mux::Segment::new(mux::Writer::new(file)) {
+            Some(segment) => segment,
+            None => bail!("Failed to create the webm muxer"),
+        };
+        let video = segment.add_video_track(width as _, height as _, None, mux::VideoCodecId::VP9);
+        let audio = audio.map(|f| {
+            segment.add_audio_track(
+                f.sample_rate as _,
+                f.channels as _,
+                None,
+                mux::AudioCodecId::Opus,
+            )
+        });
+        log::info!("Recording to {:?}", path);
+        Ok(Self {
+            path,
+            segment: Some(segment),
+            video,
+            audio,
+            start: std::time::Instant::now(),
+            key: false,
+        })
+    }
+
+    #[inline]
+    fn timestamp(&self) -> u64 {
+        self.start.elapsed().as_nanos() as _
+    }
+
+    pub fn write_video(&mut self, vf: &VideoFrame) -> ResultType<()> {
+        let frames = match &vf.union {
+            Some(video_frame::Union::vp9s(vp9s)) => &vp9s.frames,
+            _ => bail!("Only VP9 can be recorded"),
+        };
+        let ts = self.timestamp();
+        for f in frames.iter() {
+            // nothing can be decoded before the first key frame
+            if !self.key && !f.key {
+                continue;
+            }
+            self.key = true;
+            self.video.add_frame(&f.data, ts, f.key);
+        }
+        Ok(())
+    }
+
+    pub fn write_audio(&mut self, af: &AudioFrame) {
+        if !self.key {
+            return;
+        }
+        let ts = self.timestamp();
+        if let Some(audio) = self.audio.as_mut() {
+            audio.add_frame(&af.data, ts, true);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            if segment.finalize(None) {
+                log::info!("Recording saved to {:?}", self.path);
+            } else {
+                log::error!("Failed to finalize the recording {:?}", self.path);
+            }
+        }
     }
 }
 
@@ -681,10 +1460,18 @@ pub struct LoginConfigHandler {
     password: Vec<u8>, // remember password for reconnect
     pub remember: bool,
     config: PeerConfig,
-    pub port_forward: (String, i32),
+    pub port_forward: (String, i32), // no port for the dynamic forwarding
+    pub reverse_forward: bool,       // `port_forward` to listen on by the peer
+    pub udp_forward: bool,
+    pub terminal: Option<(u32, u32)>, // (rows, cols) of a shell instead of the desktop
     pub support_press: bool,
     pub support_refresh: bool,
     pub version: i64,
+    pub resume_token: Vec<u8>, // of the last logon, to resume the session after a drop
+    pub view_only: bool,       // no input is sent, and the host ignores it
+    totp: String,              // the 2FA code entered, sent with the next login only
+    pub switch_sides: String,  // the token of the peer to connect back to, no password
+    pub capabilities: Capabilities, // of the protocol, both sides have
 }
 
 impl Deref for LoginConfigHandler {
@@ -719,6 +1506,13 @@ impl LoginConfigHandler {
         self.config = config;
     }
 
+    /// The peer could not turn the privacy mode on.
+    pub fn reset_privacy_mode(&mut self) {
+        let mut config = self.load_config();
+        config.privacy_mode = false;
+        self.save_config(config);
+    }
+
     pub fn set_option(&mut self, k: String, v: String) {
         let mut config = self.load_config();
         config.options.insert(k, v);
@@ -774,6 +1568,25 @@ impl LoginConfigHandler {
                 BoolOption::No
             })
             .into();
+        } else if name == "enable-printer" {
+            let enabled = self.get_option(&name).is_empty();
+            if enabled {
+                self.config.options.insert(name, "Y".to_owned());
+            } else {
+                self.config.options.remove(&name);
+            }
+            self.config.store(&self.id);
+            option.enable_printer = (if enabled {
+                BoolOption::Yes
+            } else {
+                BoolOption::No
+            })
+            .into();
+            let mut misc = Misc::new();
+            misc.set_option(option);
+            let mut msg_out = Message::new();
+            msg_out.set_misc(misc);
+            return Some(msg_out);
         } else if name == "block-input" {
             option.block_input = BoolOption::Yes.into();
         } else if name == "unblock-input" {
@@ -818,6 +1631,12 @@ impl LoginConfigHandler {
                 }
             }
         }
+        if let Some((_, fps, _)) = get_quality_preset(&self.quality_preset) {
+            if fps != NO_FPS_CAP {
+                msg.custom_fps = fps;
+                n += 1;
+            }
+        }
         if self.get_toggle_option("show-remote-cursor") {
             msg.show_remote_cursor = BoolOption::Yes.into();
             n += 1;
@@ -826,10 +1645,36 @@ impl LoginConfigHandler {
             msg.lock_after_session_end = BoolOption::Yes.into();
             n += 1;
         }
-        if self.get_toggle_option("privacy_mode") {
+        if self.get_toggle_option("privacy-mode") {
             msg.privacy_mode = BoolOption::Yes.into();
             n += 1;
         }
+        msg.cursor_delta = BoolOption::Yes.into();
+        n += 1;
+        if self.get_toggle_option("enable-printer") {
+            msg.enable_printer = BoolOption::Yes.into();
+            n += 1;
+        }
+        if let Some(settings) = self.get_audio_settings() {
+            msg.audio_settings = Some(settings).into();
+            n += 1;
+        }
+        let direction = self.get_clipboard_direction();
+        if direction != option_message::ClipboardDirection::Both {
+            msg.clipboard_direction = direction.into();
+            n += 1;
+        }
+        let kbps = Config::get_max_bandwidth_kbps();
+        if kbps > 0 {
+            msg.max_bandwidth_kbps = kbps;
+            n += 1;
+        }
+        // without it the peer sticks to vp9
+        if cfg!(feature = "av1") || cfg!(feature = "hwcodec") {
+            let prefer = self.get_option("codec-preference");
+            msg.supported_decoding = Some(VideoHandler::supported_decoding(&prefer)).into();
+            n += 1;
+        }
         if n > 0 {
             Some(msg)
         } else {
@@ -881,12 +1726,14 @@ impl LoginConfigHandler {
         let mut misc = Misc::new();
         misc.set_option(OptionMessage {
             custom_image_quality: bitrate << 8 | quantizer,
+            custom_fps: self.leave_quality_preset(),
             ..Default::default()
         });
         let mut msg_out = Message::new();
         msg_out.set_misc(misc);
         let mut config = self.load_config();
         config.image_quality = "custom".to_owned();
+        config.quality_preset.clear();
         config.custom_image_quality = vec![bitrate, quantizer];
         self.save_config(config);
         msg_out
@@ -898,6 +1745,7 @@ impl LoginConfigHandler {
             let mut misc = Misc::new();
             misc.set_option(OptionMessage {
                 image_quality: q.into(),
+                custom_fps: self.leave_quality_preset(),
                 ..Default::default()
             });
             let mut msg_out = Message::new();
@@ -906,10 +1754,106 @@ impl LoginConfigHandler {
         }
         let mut config = self.load_config();
         config.image_quality = value;
+        config.quality_preset.clear();
         self.save_config(config);
         res
     }
 
+    /// "low-latency", "balanced" or "best-quality", the image quality, the
+    /// frames per second and the codec of the preset at once, sent again at
+    /// each connection to the peer.
+    pub fn save_quality_preset(&mut self, value: String) -> Option<Message> {
+        let (q, fps, codec) = get_quality_preset(&value)?;
+        let mut option = OptionMessage {
+            image_quality: self.get_image_quality_enum(q, false)?.into(),
+            custom_fps: fps,
+            ..Default::default()
+        };
+        if cfg!(feature = "av1") || cfg!(feature = "hwcodec") {
+            option.supported_decoding = Some(VideoHandler::supported_decoding(codec)).into();
+        }
+        let mut config = self.load_config();
+        config.quality_preset = value;
+        config.image_quality = q.to_owned();
+        if codec.is_empty() {
+            config.options.remove("codec-preference");
+        } else {
+            config
+                .options
+                .insert("codec-preference".to_owned(), codec.to_owned());
+        }
+        self.save_config(config);
+        let mut misc = Misc::new();
+        misc.set_option(option);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        Some(msg_out)
+    }
+
+    // the limit of the frames per second to send when a setting of the preset
+    // is changed on its own, 0 for none to lift
+    fn leave_quality_preset(&self) -> u32 {
+        match get_quality_preset(&self.quality_preset) {
+            Some((_, fps, _)) if fps != NO_FPS_CAP => NO_FPS_CAP,
+            _ => 0,
+        }
+    }
+
+    /// "low-latency", "balanced" or "music", the defaults of the peer otherwise.
+    pub fn save_audio_mode(&mut self, value: String) -> Option<Message> {
+        self.set_option("audio-mode".to_owned(), value);
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            audio_settings: Some(self.get_audio_settings()?).into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        Some(msg_out)
+    }
+
+    /// "to-client", "to-host" or "both", limited further by the "clipboard-direction" of the peer.
+    pub fn save_clipboard_direction(&mut self, value: String) -> Message {
+        self.set_option("clipboard-direction".to_owned(), value);
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            clipboard_direction: self.get_clipboard_direction().into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        msg_out
+    }
+
+    pub fn get_clipboard_direction(&self) -> option_message::ClipboardDirection {
+        match self.get_option("clipboard-direction").as_str() {
+            "to-client" => option_message::ClipboardDirection::ToClient,
+            "to-host" => option_message::ClipboardDirection::ToHost,
+            _ => option_message::ClipboardDirection::Both,
+        }
+    }
+
+    // "audio-bitrate" in kbps and "audio-frame-ms" have no menu
+    fn get_audio_settings(&self) -> Option<AudioSettings> {
+        let mode = match self.get_option("audio-mode").as_str() {
+            "low-latency" => audio_settings::Mode::LowLatency,
+            "balanced" => audio_settings::Mode::Balanced,
+            "music" => audio_settings::Mode::Music,
+            _ => audio_settings::Mode::NotSet,
+        };
+        let bitrate = self.get_option("audio-bitrate").parse().unwrap_or(0);
+        let frame_ms = self.get_option("audio-frame-ms").parse().unwrap_or(0);
+        if mode == audio_settings::Mode::NotSet && bitrate == 0 && frame_ms == 0 {
+            return None;
+        }
+        Some(AudioSettings {
+            mode: mode.into(),
+            bitrate,
+            frame_ms,
+            ..Default::default()
+        })
+    }
+
     pub fn get_option(&self, k: &str) -> String {
         if let Some(v) = self.config.options.get(k) {
             v.clone()
@@ -923,6 +1867,13 @@ impl LoginConfigHandler {
             self.password = Default::default();
             interface.msgbox("re-input-password", err, "Do you want to enter again?");
             true
+        } else if err == "2FA required" || err == "Wrong 2FA Code" {
+            interface.msgbox(
+                "input-2fa",
+                err,
+                "Please enter the code of your authenticator app",
+            );
+            true
         } else {
             interface.msgbox("error", "Login Error", err);
             false
@@ -938,6 +1889,9 @@ impl LoginConfigHandler {
     }
 
     pub fn handle_peer_info(&mut self, username: String, pi: PeerInfo) {
+        self.resume_token = pi.resume_token.clone();
+        self.capabilities = Capabilities::new(&pi.capabilities);
+        log::info!("Capabilities of the peer: {:?}", self.capabilities.names());
         if !pi.version.is_empty() {
             self.support_press = true;
             self.support_refresh = true;
@@ -948,6 +1902,18 @@ impl LoginConfigHandler {
             hostname: pi.hostname.clone(),
             platform: pi.platform.clone(),
         };
+        let mut ab = AddressBook::load();
+        if let Some(p) = ab.get(&self.id) {
+            let info = (&p.username, &p.hostname, &p.platform);
+            if info != (&serde.username, &serde.hostname, &serde.platform) {
+                let mut p = p.clone();
+                p.username = serde.username.clone();
+                p.hostname = serde.hostname.clone();
+                p.platform = serde.platform.clone();
+                ab.set(p);
+                ab.store();
+            }
+        }
         let mut config = self.load_config();
         config.info = serde;
         let password = self.password.clone();
@@ -979,6 +1945,11 @@ impl LoginConfigHandler {
             my_id,
             my_name: crate::username(),
             option: self.get_option_message(true).into(),
+            resume_token: self.resume_token.clone(),
+            totp: self.totp.clone(),
+            view_only: self.view_only,
+            switch_sides: self.switch_sides.clone(),
+            capabilities: capabilities::local(),
             ..Default::default()
         };
         if self.is_file_transfer {
@@ -987,10 +1958,19 @@ impl LoginConfigHandler {
                 show_hidden: !self.get_option("remote_show_hidden").is_empty(),
                 ..Default::default()
             });
+        } else if let Some((rows, cols)) = self.terminal {
+            lr.set_terminal(Terminal {
+                rows,
+                cols,
+                ..Default::default()
+            });
         } else if self.is_port_forward {
             lr.set_port_forward(PortForward {
                 host: self.port_forward.0.clone(),
                 port: self.port_forward.1,
+                dynamic: self.port_forward.1 == 0 && !self.reverse_forward && !self.udp_forward,
+                reverse: self.reverse_forward,
+                udp: self.udp_forward,
                 ..Default::default()
             });
         }
@@ -1000,11 +1980,48 @@ impl LoginConfigHandler {
     }
 }
 
+// sent as the frames per second at most for no limit, the host clamping it to
+// its own maximum
+const NO_FPS_CAP: u32 = u32::MAX;
+
+/// (image quality, frames per second at most, codec preferred) of a quality
+/// preset, the codec used only if both sides have it.
+fn get_quality_preset(name: &str) -> Option<(&'static str, u32, &'static str)> {
+    match name {
+        "low-latency" => Some(("low", NO_FPS_CAP, "h264")),
+        "balanced" => Some(("balanced", NO_FPS_CAP, "")),
+        "best-quality" => Some(("best", 20, "h265")),
+        _ => None,
+    }
+}
+
 pub async fn handle_test_delay(t: TestDelay, peer: &mut Stream) {
     if !t.from_client {
         let mut msg_out = Message::new();
         msg_out.set_test_delay(t);
         allow_err!(peer.send(&msg_out).await);
+    } else {
+        // the echo of our own ping
+        peer.keepalive().on_pong(t.time);
+    }
+}
+
+/// To be called every `peer.keepalive().interval()`, pinging the peer, false
+/// once too many pings were missed and the peer is taken as dead.
+pub async fn send_keepalive(peer: &mut Stream) -> bool {
+    match peer.keepalive().tick() {
+        keepalive::Tick::Dead => false,
+        keepalive::Tick::Ping(time) => {
+            let mut msg_out = Message::new();
+            msg_out.set_test_delay(TestDelay {
+                time,
+                from_client: true,
+                ..Default::default()
+            });
+            allow_err!(peer.send(&msg_out).await);
+            true
+        }
+        keepalive::Tick::Wait => true,
     }
 }
 
@@ -1018,17 +2035,59 @@ pub async fn handle_hash(
     if password.is_empty() {
         password = lc.read().unwrap().config.password.clone();
     }
+    if password.is_empty() {
+        // the one of the address book
+        let id = lc.read().unwrap().id.clone();
+        if let Some(p) = AddressBook::load().get(&id) {
+            if !p.password.is_empty() {
+                match get_login_hash(&p.password, &hash) {
+                    Ok(x) => {
+                        password = x;
+                        lc.write().unwrap().password = password.clone();
+                    }
+                    Err(err) => log::error!("{}", err),
+                }
+            }
+        }
+    }
     if password.is_empty() {
         // login without password, the remote side can click accept
         send_login(lc.clone(), Vec::new(), peer).await;
-        interface.msgbox("input-password", "Password Required", "");
+        let asked = {
+            let lc = lc.read().unwrap();
+            lc.resume_token.is_empty() && lc.switch_sides.is_empty()
+        };
+        if asked {
+            interface.msgbox("input-password", "Password Required", "");
+        }
     } else {
+        send_login(lc.clone(), get_login_proof(&password, &hash), peer).await;
+    }
+    lc.write().unwrap().hash = hash;
+}
+
+// argon2id if the host keeps only the hash of a permanent password
+fn get_login_hash(password: &str, hash: &Hash) -> ResultType<Vec<u8>> {
+    if hash.kdf_salt.is_empty() {
         let mut hasher = Sha256::new();
-        hasher.update(&password);
+        hasher.update(password);
+        hasher.update(&hash.salt);
+        Ok(hasher.finalize()[..].to_vec())
+    } else {
+        config::hash_password(password, &hash.kdf_salt)
+    }
+}
+
+// of the key of the login, to the challenge of the host
+fn get_login_proof(key: &[u8], hash: &Hash) -> Vec<u8> {
+    if hash.kdf_salt.is_empty() {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
         hasher.update(&hash.challenge);
-        send_login(lc.clone(), hasher.finalize()[..].into(), peer).await;
+        hasher.finalize()[..].to_vec()
+    } else {
+        config::mask_login_key(key, &config::password_verifier(key), &hash.challenge)
     }
-    lc.write().unwrap().hash = hash;
 }
 
 async fn send_login(lc: Arc<RwLock<LoginConfigHandler>>, password: Vec<u8>, peer: &mut Stream) {
@@ -1042,16 +2101,33 @@ pub async fn handle_login_from_ui(
     remember: bool,
     peer: &mut Stream,
 ) {
-    let mut hasher = Sha256::new();
-    hasher.update(password);
-    hasher.update(&lc.read().unwrap().hash.salt);
-    let res = hasher.finalize();
+    let hash = lc.read().unwrap().hash.clone();
+    let res = match get_login_hash(&password, &hash) {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        }
+    };
     lc.write().unwrap().remember = remember;
-    lc.write().unwrap().password = res[..].into();
-    let mut hasher2 = Sha256::new();
-    hasher2.update(&res[..]);
-    hasher2.update(&lc.read().unwrap().hash.challenge);
-    send_login(lc.clone(), hasher2.finalize()[..].into(), peer).await;
+    lc.write().unwrap().password = res.clone();
+    send_login(lc.clone(), get_login_proof(&res, &hash), peer).await;
+}
+
+/// Login again with the password of the last attempt and the 2FA code.
+pub async fn handle_login_totp(
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    code: String,
+    peer: &mut Stream,
+) {
+    let mut password = lc.read().unwrap().password.clone();
+    if password.is_empty() {
+        password = lc.read().unwrap().config.password.clone();
+    }
+    let hash = lc.read().unwrap().hash.clone();
+    lc.write().unwrap().totp = code;
+    send_login(lc.clone(), get_login_proof(&password, &hash), peer).await;
+    lc.write().unwrap().totp.clear();
 }
 
 #[async_trait]
@@ -1068,6 +2144,7 @@ pub trait Interface: Send + Clone + 'static + Sized {
 pub enum Data {
     Close,
     Login((String, bool)),
+    LoginTotp(String),
     Message(Message),
     SendFiles((i32, String, String, bool, bool)),
     RemoveDirAll((i32, String, bool)),
@@ -1077,9 +2154,17 @@ pub enum Data {
     RemoveFile((i32, String, i32, bool)),
     CreateDir((i32, String, bool)),
     CancelJob(i32),
+    PauseJob(i32),
+    ResumeJob(i32),
+    SyncDir((i32, String, String, bool, bool, bool)),
+    ConfirmSync(i32),
+    DropFile((i32, String, bool)),
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
     NewRDP,
+    RecordScreen(bool),
+    Microphone(bool),
+    VoiceCall(bool),
 }
 
 #[derive(Clone)]
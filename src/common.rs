@@ -13,13 +13,25 @@ use hbb_common::{
 };
 #[cfg(any(target_os = "android", target_os = "ios", feature = "cli"))]
 use hbb_common::{config::RENDEZVOUS_PORT, futures::future::join_all};
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
 pub const CLIPBOARD_NAME: &'static str = "clipboard";
 pub const CLIPBOARD_INTERVAL: u64 = 333;
+const CLIPBOARD_MAX_PIXELS: usize = 8192 * 8192;
+// larger content is only sent when asked for, in chunks of this size
+const CLIPBOARD_LAZY_SIZE: usize = 256 * 1024;
+const CLIPBOARD_HISTORY_SIZE: usize = 10;
 
 lazy_static::lazy_static! {
-    pub static ref CONTENT: Arc<Mutex<String>> = Default::default();
+    pub static ref CONTENT: Arc<Mutex<ClipboardContent>> = Default::default();
+    static ref LAZY_CLIPBOARD: Mutex<Option<Clipboard>> = Default::default();
+    // the announcement of the clipboard being received, and the content so far
+    static ref PENDING_CLIPBOARD: Mutex<Option<Clipboard>> = Default::default();
+    // the texts copied or received last, the newest first
+    static ref CLIPBOARD_HISTORY: Mutex<VecDeque<String>> = Default::default();
     pub static ref SOFTWARE_UPDATE_URL: Arc<Mutex<String>> = Default::default();
 }
 
@@ -41,61 +53,342 @@ pub fn valid_for_numlock(evt: &KeyEvent) -> bool {
 }
 
 pub fn create_clipboard_msg(content: String) -> Message {
-    let bytes = content.into_bytes();
-    let compressed = compress_func(&bytes, COMPRESS_LEVEL);
-    let compress = compressed.len() < bytes.len();
+    create_clipboard_msg_of(clipboard::Format::Text, content.into_bytes(), "".to_owned())
+}
+
+fn create_clipboard_msg_of(format: clipboard::Format, bytes: Vec<u8>, text: String) -> Message {
+    // png is compressed already
+    let compressed = if format == clipboard::Format::ImagePng {
+        Vec::new()
+    } else {
+        compress_func(&bytes, COMPRESS_LEVEL)
+    };
+    let compress = !compressed.is_empty() && compressed.len() < bytes.len();
     let content = if compress { compressed } else { bytes };
-    let mut msg = Message::new();
-    msg.set_clipboard(Clipboard {
+    let mut cb = Clipboard {
         compress,
         content,
+        format: format.into(),
+        text,
+        ..Default::default()
+    };
+    if cb.content.len() > CLIPBOARD_LAZY_SIZE {
+        cb.size = cb.content.len() as _;
+        cb.id = hash_bytes(&cb.content);
+        let content = std::mem::take(&mut cb.content);
+        *LAZY_CLIPBOARD.lock().unwrap() = Some(Clipboard {
+            content,
+            ..cb.clone()
+        });
+    }
+    let mut msg = Message::new();
+    msg.set_clipboard(cb);
+    msg
+}
+
+/// The chunk asked for of the clipboard announced last, not to hold up
+/// the other messages with a large clipboard.
+pub fn get_clipboard_chunk(req: &ClipboardRequest) -> Option<Message> {
+    let lazy = LAZY_CLIPBOARD.lock().unwrap();
+    let cb = lazy.as_ref()?;
+    let offset = req.offset as usize;
+    if cb.id != req.id || offset >= cb.content.len() {
+        return None;
+    }
+    let end = (offset + CLIPBOARD_LAZY_SIZE).min(cb.content.len());
+    let mut msg = Message::new();
+    msg.set_clipboard_chunk(ClipboardChunk {
+        id: cb.id,
+        offset: req.offset,
+        data: cb.content[offset..end].to_vec(),
+        ..Default::default()
+    });
+    Some(msg)
+}
+
+/// The percent received of the clipboard being transferred, if any.
+pub fn get_clipboard_progress() -> Option<i32> {
+    PENDING_CLIPBOARD
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|cb| (cb.content.len() as u64 * 100 / cb.size.max(1)) as _)
+}
+
+/// In bytes, the clipboard over it is neither sent nor received.
+pub fn get_clipboard_max_size() -> usize {
+    Config::get_option("clipboard-max-size")
+        .parse::<usize>()
+        .unwrap_or(32)
+        * 1024
+        * 1024
+}
+
+/// The texts of the clipboard copied or received last, the newest first,
+/// "clipboard-history-size" of them at most, 0 to keep none.
+pub fn get_clipboard_history() -> Vec<String> {
+    CLIPBOARD_HISTORY.lock().unwrap().iter().cloned().collect()
+}
+
+/// The message of a text of `get_clipboard_history`, to sync it again.
+pub fn create_clipboard_history_msg(index: usize) -> Option<Message> {
+    let text = CLIPBOARD_HISTORY.lock().unwrap().get(index)?.clone();
+    ClipboardContent {
+        text,
+        ..Default::default()
+    }
+    .to_msg()
+}
+
+fn add_clipboard_history(text: &str) {
+    // never sent anyway
+    if text.len() > get_clipboard_max_size() {
+        return;
+    }
+    let size = Config::get_option("clipboard-history-size")
+        .parse()
+        .unwrap_or(CLIPBOARD_HISTORY_SIZE);
+    let mut history = CLIPBOARD_HISTORY.lock().unwrap();
+    history.retain(|x| x != text);
+    history.push_front(text.to_owned());
+    history.truncate(size);
+}
+
+/// In bytes, the largest frame taken from a peer, "max-frame-size" in MB.
+pub fn get_max_frame_size() -> usize {
+    match Config::get_option("max-frame-size").parse::<usize>() {
+        Ok(mb) if mb > 0 => mb * 1024 * 1024,
+        _ => hbb_common::bytes_codec::DEFAULT_MAX_FRAME_SIZE,
+    }
+}
+
+fn create_clipboard_request(id: u64, offset: u64) -> Message {
+    let mut msg = Message::new();
+    msg.set_clipboard_request(ClipboardRequest {
+        id,
+        offset,
         ..Default::default()
     });
     msg
 }
 
+/// The last clipboard synced, to tell the local changes apart from the updates by the peer.
+#[derive(Default, Clone)]
+pub struct ClipboardContent {
+    text: String,
+    html: String,
+    // hash of the rgba pixels, not to encode the image on every check
+    image: u64,
+    png: Vec<u8>,
+}
+
+impl ClipboardContent {
+    /// None if empty or over the max size.
+    pub fn to_msg(&self) -> Option<Message> {
+        let (format, bytes) = if !self.png.is_empty() {
+            (clipboard::Format::ImagePng, self.png.as_slice())
+        } else if !self.html.is_empty() {
+            (clipboard::Format::Html, self.html.as_bytes())
+        } else {
+            (clipboard::Format::Text, self.text.as_bytes())
+        };
+        if bytes.is_empty() {
+            return None;
+        }
+        if bytes.len() > get_clipboard_max_size() {
+            log::info!(
+                "{} of {} bytes not sent, over the max size",
+                CLIPBOARD_NAME,
+                bytes.len()
+            );
+            return None;
+        }
+        let text = if format == clipboard::Format::Html {
+            self.text.clone()
+        } else {
+            "".to_owned()
+        };
+        Some(create_clipboard_msg_of(format, bytes.to_vec(), text))
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn encode_png(image: &arboard::ImageData) -> ResultType<Vec<u8>> {
+    let mut png = Vec::new();
+    repng::encode(&mut png, image.width as _, image.height as _, &image.bytes)?;
+    Ok(png)
+}
+
+fn decode_png(data: &[u8]) -> ResultType<arboard::ImageData<'static>> {
+    let decoder = png::Decoder::new_with_limits(
+        data,
+        png::Limits {
+            bytes: CLIPBOARD_MAX_PIXELS * 4,
+        },
+    );
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+        bail!(
+            "unsupported clipboard image: {:?} {:?}",
+            info.color_type,
+            info.bit_depth
+        );
+    }
+    buf.truncate(info.buffer_size());
+    Ok(arboard::ImageData {
+        width: info.width as _,
+        height: info.height as _,
+        bytes: buf.into(),
+    })
+}
+
 pub fn check_clipboard(
     ctx: &mut ClipboardContext,
-    old: Option<&Arc<Mutex<String>>>,
+    old: Option<&Arc<Mutex<ClipboardContent>>>,
 ) -> Option<Message> {
     let side = if old.is_none() { "host" } else { "client" };
     let old = if let Some(old) = old { old } else { &CONTENT };
-    if let Ok(content) = ctx.get_text() {
-        if content.len() < 2_000_000 && !content.is_empty() {
-            let changed = content != *old.lock().unwrap();
-            if changed {
-                log::info!("{} update found on {}", CLIPBOARD_NAME, side);
-                *old.lock().unwrap() = content.clone();
-                return Some(create_clipboard_msg(content));
-            }
+    // an image on the clipboard has no text
+    let text = ctx.get_text().unwrap_or_default();
+    if !text.is_empty() {
+        if text == old.lock().unwrap().text {
+            return None;
         }
+        log::info!("{} update found on {}", CLIPBOARD_NAME, side);
+        add_clipboard_history(&text);
+        let content = ClipboardContent {
+            html: ctx.get().html().unwrap_or_default(),
+            text,
+            ..Default::default()
+        };
+        let msg = content.to_msg();
+        *old.lock().unwrap() = content;
+        return msg;
     }
-    None
+    let image = ctx.get_image().ok()?;
+    let hash = hash_bytes(&image.bytes);
+    if hash == old.lock().unwrap().image {
+        return None;
+    }
+    let mut content = ClipboardContent {
+        image: hash,
+        ..Default::default()
+    };
+    log::info!("{} image update found on {}", CLIPBOARD_NAME, side);
+    if image.width * image.height <= CLIPBOARD_MAX_PIXELS {
+        match encode_png(&image) {
+            Ok(png) => content.png = png,
+            Err(err) => log::error!("Failed to encode the clipboard image: {}", err),
+        }
+    }
+    // remembered even if not sent, not to try again until it changes
+    let msg = content.to_msg();
+    *old.lock().unwrap() = content;
+    msg
 }
 
-pub fn update_clipboard(clipboard: Clipboard, old: Option<&Arc<Mutex<String>>>) {
+/// Returns the request for the first chunk, if only announced.
+pub fn update_clipboard(
+    clipboard: Clipboard,
+    old: Option<&Arc<Mutex<ClipboardContent>>>,
+) -> Option<Message> {
+    if clipboard.content.is_empty() && clipboard.size > 0 {
+        if clipboard.size as usize > get_clipboard_max_size() {
+            log::info!(
+                "{} of {} bytes ignored, over the max size",
+                CLIPBOARD_NAME,
+                clipboard.size
+            );
+            return None;
+        }
+        let id = clipboard.id;
+        *PENDING_CLIPBOARD.lock().unwrap() = Some(clipboard);
+        return Some(create_clipboard_request(id, 0));
+    }
+    // a newer clipboard than the one being received
+    *PENDING_CLIPBOARD.lock().unwrap() = None;
+    let format = clipboard.format.enum_value_or_default();
     let content = if clipboard.compress {
         decompress(&clipboard.content)
     } else {
         clipboard.content
     };
-    if let Ok(content) = String::from_utf8(content) {
-        match ClipboardContext::new() {
-            Ok(mut ctx) => {
-                let side = if old.is_none() { "host" } else { "client" };
-                let old = if let Some(old) = old { old } else { &CONTENT };
-                *old.lock().unwrap() = content.clone();
-                if !content.is_empty() {
-                    // empty content make ctx.set_text crash
-                    allow_err!(ctx.set_text(content));
-                }
-                log::debug!("{} updated on {}", CLIPBOARD_NAME, side);
+    let mut ctx = match ClipboardContext::new() {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            log::error!("Failed to create clipboard context: {}", err);
+            return None;
+        }
+    };
+    let mut new = ClipboardContent::default();
+    match format {
+        clipboard::Format::Text => {
+            new.text = String::from_utf8(content).ok()?;
+            if !new.text.is_empty() {
+                // empty content make ctx.set_text crash
+                allow_err!(ctx.set_text(new.text.clone()));
+            }
+        }
+        clipboard::Format::Html => {
+            new.html = String::from_utf8(content).ok()?;
+            new.text = clipboard.text;
+            allow_err!(ctx.set_html(new.html.as_str(), Some(new.text.as_str())));
+        }
+        clipboard::Format::ImagePng => match decode_png(&content) {
+            Ok(image) => {
+                allow_err!(ctx.set_image(image));
+                // what the platform made of it, not to send it back
+                new.image = ctx
+                    .get_image()
+                    .map(|image| hash_bytes(&image.bytes))
+                    .unwrap_or_default();
+                new.png = content;
             }
             Err(err) => {
-                log::error!("Failed to create clipboard context: {}", err);
+                log::error!("Failed to decode the clipboard image: {}", err);
+                return None;
             }
-        }
+        },
+    }
+    if !new.text.is_empty() {
+        add_clipboard_history(&new.text);
+    }
+    let side = if old.is_none() { "host" } else { "client" };
+    let old = if let Some(old) = old { old } else { &CONTENT };
+    *old.lock().unwrap() = new;
+    log::debug!("{} updated on {}", CLIPBOARD_NAME, side);
+    None
+}
+
+/// Returns the request for the next chunk, until the clipboard is complete.
+pub fn update_clipboard_chunk(
+    chunk: ClipboardChunk,
+    old: Option<&Arc<Mutex<ClipboardContent>>>,
+) -> Option<Message> {
+    let mut pending = PENDING_CLIPBOARD.lock().unwrap();
+    let cb = pending.as_mut()?;
+    if cb.id != chunk.id || cb.content.len() as u64 != chunk.offset {
+        return None;
     }
+    if chunk.data.is_empty() {
+        *pending = None;
+        return None;
+    }
+    cb.content.extend(chunk.data);
+    if (cb.content.len() as u64) < cb.size {
+        return Some(create_clipboard_request(cb.id, cb.content.len() as _));
+    }
+    let cb = pending.take()?;
+    drop(pending);
+    update_clipboard(cb, old)
 }
 
 #[cfg(feature = "use_rubato")]
@@ -458,3 +751,18 @@ pub fn is_ip(id: &str) -> bool {
         .unwrap()
         .is_match(id)
 }
+
+/// A short digest of a peer's public key, to be compared by both sides,
+/// e.g. "1A2B 3C4D ...", empty without a key.
+pub fn get_fingerprint(pk: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    if pk.is_empty() {
+        return "".to_owned();
+    }
+    let hash = Sha256::digest(pk);
+    hash[..16]
+        .chunks(2)
+        .map(|x| format!("{:02X}{:02X}", x[0], x[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
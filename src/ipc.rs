@@ -41,16 +41,30 @@ pub enum FS {
     NewWrite {
         path: String,
         id: i32,
-        files: Vec<(String, u64)>,
+        // path is a name in the drop folder, and pasted when done
+        drop: bool,
+        paste: bool,
+        // name, modified_time, entry_type, mode and link_target
+        files: Vec<(String, u64, i32, u32, String)>,
     },
     CancelWrite {
         id: i32,
+        keep_download: bool,
     },
     WriteBlock {
         id: i32,
         file_num: i32,
         data: Vec<u8>,
         compressed: bool,
+        offset: u64,
+        copy_blocks: Vec<u32>,
+        sha256: Vec<u8>,
+    },
+    WriteDigest {
+        id: i32,
+        file_num: i32,
+        last_modified: u64,
+        file_size: u64,
     },
     WriteDone {
         id: i32,
@@ -68,17 +82,31 @@ pub enum Data {
         name: String,
         authorized: bool,
         port_forward: String,
+        is_terminal: bool,
         keyboard: bool,
         clipboard: bool,
         audio: bool,
+        microphone: bool,
     },
     ChatMessage {
         text: String,
+        id: i64,
+        file: String,
+    },
+    // of the chat messages of the peer, or of the connection manager
+    ChatReceipt {
+        ids: Vec<i64>,
+        read: bool,
     },
     SwitchPermission {
         name: String,
         enabled: bool,
     },
+    // asked by the peer, answered in the connection manager
+    VoiceCallIncoming,
+    VoiceCallResponse(bool),
+    // hung up on either side, with the error if it failed
+    CloseVoiceCall(String),
     SystemInfo(Option<String>),
     Authorize,
     Close,
@@ -90,10 +118,43 @@ pub enum Data {
     ConfirmedKey(Option<(Vec<u8>, Vec<u8>)>),
     RawMessage(Vec<u8>),
     Socks(Option<config::Socks5Server>),
+    // the bans of the failed logins, by "ip:<addr>"
+    Bans(Option<HashMap<String, (i64, u32)>>),
+    Unban(String),
+    // (peer id, one-time token, pinned key of the peer) of a switch of sides,
+    // for the peer to connect back
+    SwitchSides(String, String, Vec<u8>),
+    // (peer id, signed token) to connect back to the peer with, taken once
+    SwitchSidesBack(String, Option<String>),
+    // the connections to this host, for the monitoring agents and scripts
+    Sessions(Option<Vec<SessionStatus>>),
+    // closes the connection of the id as in the cm
+    Disconnect(i32),
+    // (connection id, permission, enabled) switched as in the cm
+    SessionPermission(i32, String, bool),
+    // the entries of the audit log, with the first one breaking its chain if any
+    AuditLog(Option<(Vec<crate::audit::Entry>, Option<usize>)>),
+    // an outgoing session, for the service to write to the audit log
+    AddAudit(crate::audit::Entry),
     FS(FS),
     Test,
 }
 
+/// A connection to this host, as shown in the cm.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SessionStatus {
+    pub id: i32,
+    pub peer_id: String,
+    pub name: String,
+    pub ip: String,
+    pub conn_type: String, // desktop, file-transfer, port-forward, terminal
+    pub authorized: bool,
+    pub keyboard: bool,
+    pub clipboard: bool,
+    pub audio: bool,
+    pub microphone: bool,
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn start(postfix: &str) -> ResultType<()> {
     let mut incoming = new_listener(postfix).await?;
@@ -207,6 +268,39 @@ async fn handle(data: Data, stream: &mut Connection) {
                 log::info!("socks updated");
             }
         },
+        Data::Bans(None) => {
+            allow_err!(stream.send(&Data::Bans(Some(Config::get_bans()))).await);
+        }
+        Data::Unban(key) => {
+            Config::unban(&key);
+            log::info!("{} unbanned", key);
+        }
+        Data::SwitchSides(id, token, pk) => {
+            crate::server::add_switch_sides(id, token, pk);
+        }
+        Data::SwitchSidesBack(id, None) => {
+            let token = crate::server::take_switch_sides_back(&id);
+            allow_err!(stream.send(&Data::SwitchSidesBack(id, Some(token))).await);
+        }
+        Data::Sessions(None) => {
+            let sessions = crate::server::get_sessions();
+            allow_err!(stream.send(&Data::Sessions(Some(sessions))).await);
+        }
+        Data::Disconnect(id) => {
+            if crate::server::send_to_session(id, Data::Close) {
+                log::info!("#{} disconnected by ipc", id);
+            }
+        }
+        Data::SessionPermission(id, name, enabled) => {
+            crate::server::send_to_session(id, Data::SwitchPermission { name, enabled });
+        }
+        Data::AuditLog(None) => {
+            let log = crate::audit::load();
+            allow_err!(stream.send(&Data::AuditLog(Some(log))).await);
+        }
+        Data::AddAudit(entry) => {
+            crate::audit::add(entry);
+        }
         Data::Config((name, value)) => match value {
             None => {
                 let value;
@@ -216,6 +310,12 @@ async fn handle(data: Data, stream: &mut Connection) {
                     value = Some(Config::get_password());
                 } else if name == "salt" {
                     value = Some(Config::get_salt());
+                } else if name == "totp" {
+                    // the secret is never sent, only if it is set
+                    value = Some(if Config::is_totp_enabled() { "Y" } else { "" }.to_owned());
+                } else if name == "fingerprint" {
+                    let pk = Config::get_key_pair().1;
+                    value = Some(crate::common::get_fingerprint(&pk));
                 } else if name == "rendezvous_server" {
                     value = Some(Config::get_rendezvous_server());
                 } else if name == "rendezvous_servers" {
@@ -231,8 +331,17 @@ async fn handle(data: Data, stream: &mut Connection) {
                     Config::set_id(&value);
                 } else if name == "password" {
                     Config::set_password(&value);
+                } else if name == "permanent-password" {
+                    Config::set_permanent_password(&value);
+                } else if name == "permanent-password-hash" {
+                    // salt:hash, base64
+                    let mut it = value.splitn(2, ':');
+                    let salt = it.next().unwrap_or_default();
+                    Config::set_permanent_password_hash(salt, it.next().unwrap_or_default());
                 } else if name == "salt" {
                     Config::set_salt(&value);
+                } else if name == "totp" {
+                    Config::set_totp_secret(&value);
                 } else {
                     return;
                 }
@@ -390,6 +499,29 @@ pub fn set_password(v: String) -> ResultType<()> {
     set_config("password", v)
 }
 
+pub fn set_permanent_password(v: String) -> ResultType<()> {
+    Config::set_permanent_password(&v);
+    set_config("permanent-password", v)
+}
+
+pub fn set_permanent_password_hash(salt: String, hash: String) -> ResultType<()> {
+    Config::set_permanent_password_hash(&salt, &hash);
+    set_config("permanent-password-hash", format!("{}:{}", salt, hash))
+}
+
+pub fn set_totp_secret(v: String) -> ResultType<()> {
+    Config::set_totp_secret(&v);
+    set_config("totp", v)
+}
+
+pub fn is_totp_enabled() -> bool {
+    if let Ok(Some(v)) = get_config("totp") {
+        v == "Y"
+    } else {
+        Config::is_totp_enabled()
+    }
+}
+
 pub fn get_id() -> String {
     if let Ok(Some(v)) = get_config("id") {
         // update salt also, so that next time reinstallation not causing first-time auto-login failure
@@ -406,9 +538,21 @@ pub fn get_id() -> String {
     }
 }
 
+/// Of the public key of this host, empty if the service is not running.
+pub fn get_fingerprint() -> String {
+    if let Ok(Some(v)) = get_config("fingerprint") {
+        v
+    } else {
+        "".to_owned()
+    }
+}
+
 pub fn get_password() -> String {
     if let Ok(Some(v)) = get_config("password") {
-        Config::set_password(&v);
+        // empty if the permanent password is set
+        if !v.is_empty() {
+            Config::set_password(&v);
+        }
         v
     } else {
         Config::get_password()
@@ -520,6 +664,126 @@ pub async fn set_socks(value: config::Socks5Server) -> ResultType<()> {
     Ok(())
 }
 
+async fn get_bans_() -> ResultType<HashMap<String, (i64, u32)>> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::Bans(None)).await?;
+    if let Some(Data::Bans(Some(value))) = c.next_timeout(1_000).await? {
+        Ok(value)
+    } else {
+        Ok(Config::get_bans())
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_bans() -> HashMap<String, (i64, u32)> {
+    get_bans_().await.unwrap_or(Config::get_bans())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn switch_sides(id: String, token: String, pk: Vec<u8>) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::SwitchSides(id, token, pk))
+        .await?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn take_switch_sides_back(id: String) -> ResultType<String> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::SwitchSidesBack(id, None)).await?;
+    match c.next_timeout(1_000).await? {
+        Some(Data::SwitchSidesBack(_, Some(token))) if !token.is_empty() => Ok(token),
+        _ => bail!("No token to switch sides"),
+    }
+}
+
+// The status of the service: the id, if it is online and the connections, as JSON.
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_status() -> ResultType<String> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::Config(("id".to_owned(), None))).await?;
+    let id = match c.next_timeout(1_000).await? {
+        Some(Data::Config((_, Some(id)))) => id,
+        _ => bail!("No id of the service"),
+    };
+    c.send(&Data::OnlineStatus(None)).await?;
+    let (online, key_confirmed) = match c.next_timeout(1_000).await? {
+        Some(Data::OnlineStatus(Some((x, confirmed)))) => (x > 0, confirmed),
+        _ => bail!("No online status of the service"),
+    };
+    c.send(&Data::Sessions(None)).await?;
+    let sessions = match c.next_timeout(1_000).await? {
+        Some(Data::Sessions(Some(sessions))) => sessions,
+        _ => bail!("No sessions of the service"),
+    };
+    let status = serde_json::json!({
+        "id": id,
+        "online": online,
+        "key_confirmed": key_confirmed,
+        "sessions": sessions,
+    });
+    Ok(status.to_string())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn disconnect(id: i32) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::Disconnect(id))
+        .await?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn switch_session_permission(id: i32, name: String, enabled: bool) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::SessionPermission(id, name, enabled))
+        .await?;
+    Ok(())
+}
+
+async fn get_audit_log_() -> ResultType<(Vec<crate::audit::Entry>, Option<usize>)> {
+    let mut c = connect(1_000, "").await?;
+    c.send(&Data::AuditLog(None)).await?;
+    if let Some(Data::AuditLog(Some(value))) = c.next_timeout(3_000).await? {
+        Ok(value)
+    } else {
+        bail!("No audit log of the service")
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_audit_log() -> (Vec<crate::audit::Entry>, Option<usize>) {
+    match get_audit_log_().await {
+        Ok(value) => value,
+        Err(_) => crate::audit::load(),
+    }
+}
+
+async fn add_audit_(entry: crate::audit::Entry) -> ResultType<()> {
+    connect(1_000, "")
+        .await?
+        .send(&Data::AddAudit(entry))
+        .await?;
+    Ok(())
+}
+
+// written here if the service can not be reached, not to be lost
+pub async fn add_audit(entry: crate::audit::Entry) {
+    if add_audit_(entry.clone()).await.is_err() {
+        crate::audit::add(entry);
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn unban(key: String) -> ResultType<()> {
+    Config::unban(&key);
+    connect(1_000, "").await?.send(&Data::Unban(key)).await?;
+    Ok(())
+}
+
 /*
 static mut SHARED_MEMORY: *mut i64 = std::ptr::null_mut();
 
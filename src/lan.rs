@@ -0,0 +1,321 @@
+// mDNS / DNS-SD of the peers on the LAN, service `_rustdesk._udp.local`, next
+// to the multicast ping of rendezvous_mediator, which some switches block and
+// no standard tool understands. The responder answers the queries of the
+// service with a PTR to `<id>._rustdesk._udp.local`, its SRV on the direct
+// access port, and a TXT of the id, the version and the names of this peer, so
+// that `avahi-browse` or `dns-sd -B` see it too. `discover` sends a one-shot
+// query along with its ping, answered by unicast, RFC 6762 6.7.
+use hbb_common::{
+    bail,
+    config::{Config, RENDEZVOUS_PORT},
+    log,
+    rendezvous_proto::PeerDiscovery,
+    udp::{self, MulticastSocket},
+    ResultType, TargetAddr,
+};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4},
+};
+
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_rustdesk._udp.local";
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// of the records unique to this peer, the caches to replace theirs
+const CACHE_FLUSH: u16 = 0x8000;
+const TTL: u32 = 120;
+
+pub fn get_mdns_addr() -> SocketAddrV4 {
+    SocketAddrV4::new([224, 0, 0, 251].into(), MDNS_PORT)
+}
+
+/// "enable-mdns" is not "N".
+#[inline]
+pub fn is_mdns_enabled() -> bool {
+    Config::get_option("enable-mdns") != "N"
+}
+
+/// The responder, till the socket fails.
+pub async fn start_mdns() -> ResultType<()> {
+    let mut socket = MulticastSocket::new(Some(get_mdns_addr()))?;
+    log::info!("mdns responder started");
+    // for the browsers listening already
+    let group = SocketAddr::V4(get_mdns_addr());
+    socket.send_raw(build_response(0)?, group).await?;
+    while let Some(res) = socket.next().await {
+        let (bytes, addr) = res?;
+        let addr = match addr {
+            TargetAddr::Ip(addr) => addr,
+            _ => continue,
+        };
+        let id = match parse_query(&bytes) {
+            Ok(Some(id)) => id,
+            _ => continue,
+        };
+        if addr.port() == MDNS_PORT {
+            socket.send_raw(build_response(0)?, group).await?;
+        } else {
+            // a one-shot query, answered to its port with its id
+            socket.send_raw(build_response(id)?, addr).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A socket on every interface, the query of the service sent, to read the
+/// answers of with `parse_response`.
+pub async fn query() -> ResultType<MulticastSocket> {
+    let mut socket = MulticastSocket::new(None)?;
+    let mut msg = Vec::with_capacity(SERVICE.len() + 18);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    put_name(&mut msg, SERVICE)?;
+    msg.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    socket
+        .send_raw(msg, SocketAddr::V4(get_mdns_addr()))
+        .await?;
+    Ok(socket)
+}
+
+/// The peers of the TXT records of the service in an answer, as if they
+/// answered the ping of `discover`.
+pub fn parse_response(data: &[u8]) -> Vec<PeerDiscovery> {
+    let txts = match parse_txts(data) {
+        Ok(txts) => txts,
+        Err(err) => {
+            log::debug!("invalid mdns response: {}", err);
+            return Vec::new();
+        }
+    };
+    txts.into_iter()
+        .filter_map(|mut txt| {
+            Some(PeerDiscovery {
+                cmd: "pong".to_owned(),
+                id: txt.remove("id").filter(|x| !x.is_empty())?,
+                mac: txt.remove("mac").unwrap_or_default(),
+                hostname: txt.remove("hostname").unwrap_or_default(),
+                username: txt.remove("username").unwrap_or_default(),
+                platform: txt.remove("platform").unwrap_or_default(),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn get_txt() -> Vec<(&'static str, String)> {
+    vec![
+        ("id", Config::get_id()),
+        ("version", crate::VERSION.to_owned()),
+        ("hostname", whoami::hostname()),
+        ("username", crate::platform::get_active_username()),
+        ("platform", whoami::platform().to_string()),
+        ("mac", crate::rendezvous_mediator::get_mac()),
+    ]
+}
+
+// a label of the host name, which may have dots
+fn get_host() -> String {
+    let host: String = whoami::hostname()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    format!("{}.local", host)
+}
+
+fn build_response(id: u16) -> ResultType<Vec<u8>> {
+    let instance = format!("{}.{}", Config::get_id(), SERVICE);
+    let host = get_host();
+    let ips: Vec<_> = udp::get_multicast_interfaces()
+        .into_iter()
+        .filter(|ip| !ip.is_unspecified())
+        .collect();
+    let mut msg = Vec::with_capacity(512);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // an authoritative answer, the rest in the additional records
+    msg.extend_from_slice(&[0x84, 0, 0, 0, 0, 1, 0, 0]);
+    msg.extend_from_slice(&(2 + ips.len() as u16).to_be_bytes());
+    // the caches of a one-shot query are not flushed
+    let flush = if id == 0 { CACHE_FLUSH } else { 0 };
+    let mut rdata = Vec::new();
+    put_name(&mut rdata, &instance)?;
+    put_record(&mut msg, SERVICE, TYPE_PTR, 0, &rdata)?;
+    let mut rdata = vec![0, 0, 0, 0];
+    rdata.extend_from_slice(&((RENDEZVOUS_PORT + 2) as u16).to_be_bytes());
+    put_name(&mut rdata, &host)?;
+    put_record(&mut msg, &instance, TYPE_SRV, flush, &rdata)?;
+    let mut rdata = Vec::new();
+    for (k, v) in get_txt() {
+        let kv = format!("{}={}", k, v);
+        // the longest string of a TXT
+        if kv.len() > 255 {
+            continue;
+        }
+        rdata.push(kv.len() as _);
+        rdata.extend_from_slice(kv.as_bytes());
+    }
+    put_record(&mut msg, &instance, TYPE_TXT, flush, &rdata)?;
+    for ip in ips {
+        put_record(&mut msg, &host, TYPE_A, flush, &ip.octets())?;
+    }
+    Ok(msg)
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) -> ResultType<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid name: {}", name);
+        }
+        buf.push(label.len() as _);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn put_record(buf: &mut Vec<u8>, name: &str, ty: u16, flush: u16, rdata: &[u8]) -> ResultType<()> {
+    put_name(buf, name)?;
+    buf.extend_from_slice(&ty.to_be_bytes());
+    buf.extend_from_slice(&(CLASS_IN | flush).to_be_bytes());
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(rdata);
+    Ok(())
+}
+
+// the name at `pos`, lowercase, and the position after it
+fn read_name(data: &[u8], mut pos: usize) -> ResultType<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bounds the loops of the pointers
+    for _ in 0..128 {
+        let len = match data.get(pos) {
+            Some(len) => *len as usize,
+            None => bail!("truncated name"),
+        };
+        if len == 0 {
+            let name = labels.join(".").to_lowercase();
+            return Ok((name, end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = match data.get(pos + 1) {
+                Some(low) => *low as usize,
+                None => bail!("truncated name"),
+            };
+            end.get_or_insert(pos + 2);
+            pos = (len & 0x3f) << 8 | low;
+            continue;
+        }
+        let label = match data.get(pos + 1..pos + 1 + len) {
+            Some(label) => label,
+            None => bail!("truncated name"),
+        };
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += len + 1;
+    }
+    bail!("name too long");
+}
+
+// the id of a query asking for the service or this peer, None if another
+fn parse_query(data: &[u8]) -> ResultType<Option<u16>> {
+    if data.len() < 12 || data[2] & 0x80 != 0 {
+        return Ok(None);
+    }
+    let instance = format!("{}.{}", Config::get_id(), SERVICE).to_lowercase();
+    let qdcount = u16::from_be_bytes([data[4], data[5]]);
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let (name, next) = read_name(data, pos)?;
+        if next + 4 > data.len() {
+            bail!("truncated question");
+        }
+        let ty = u16::from_be_bytes([data[next], data[next + 1]]);
+        pos = next + 4;
+        let asked = match ty {
+            TYPE_PTR => name == SERVICE,
+            TYPE_SRV | TYPE_TXT => name == instance,
+            TYPE_ANY => name == SERVICE || name == instance,
+            _ => false,
+        };
+        if asked {
+            return Ok(Some(u16::from_be_bytes([data[0], data[1]])));
+        }
+    }
+    Ok(None)
+}
+
+// the key values of each TXT of an instance of the service
+fn parse_txts(data: &[u8]) -> ResultType<Vec<HashMap<String, String>>> {
+    if data.len() < 12 || data[2] & 0x80 == 0 {
+        return Ok(Vec::new());
+    }
+    let count = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]) as usize;
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = read_name(data, pos)?.1 + 4;
+    }
+    let mut txts = Vec::new();
+    let suffix = format!(".{}", SERVICE);
+    for _ in 0..count(6) + count(8) + count(10) {
+        let (name, next) = read_name(data, pos)?;
+        if next + 10 > data.len() {
+            bail!("truncated record");
+        }
+        let ty = u16::from_be_bytes([data[next], data[next + 1]]);
+        let len = count(next + 8);
+        pos = next + 10 + len;
+        let rdata = match data.get(next + 10..pos) {
+            Some(rdata) => rdata,
+            None => bail!("truncated record"),
+        };
+        if ty != TYPE_TXT || !name.ends_with(&suffix) {
+            continue;
+        }
+        let mut txt = HashMap::new();
+        let mut i = 0;
+        while let Some(n) = rdata.get(i) {
+            let s = match rdata.get(i + 1..i + 1 + *n as usize) {
+                Some(s) => String::from_utf8_lossy(s),
+                None => bail!("truncated TXT"),
+            };
+            if let Some(j) = s.find('=') {
+                txt.insert(s[..j].to_lowercase(), s[j + 1..].to_owned());
+            }
+            i += 1 + *n as usize;
+        }
+        txts.push(txt);
+    }
+    Ok(txts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns() {
+        let mut query = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        put_name(&mut query, "_RustDesk._udp.local").unwrap();
+        query.extend_from_slice(&[0, 12, 0x80, 1]);
+        assert_eq!(parse_query(&query).unwrap(), Some(0x1234));
+        query[query.len() - 3] = TYPE_A as _;
+        assert_eq!(parse_query(&query).unwrap(), None);
+        let response = build_response(0x1234).unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(parse_query(&response).unwrap(), None);
+        let peers = parse_response(&response);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, Config::get_id());
+        assert!(parse_response(&response[..response.len() - 3]).is_empty());
+        // a pointer to itself
+        let mut looped = response[..12].to_vec();
+        looped[7] = 1;
+        looped.extend_from_slice(&[0xc0, 12]);
+        assert!(read_name(&looped, 12).is_err());
+    }
+}
@@ -21,11 +21,190 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Remove", "删除"),
         ("Refresh random password", "刷新随机密码"),
         ("Set your own password", "设置密码"),
+        ("Two-factor authentication", "双重认证"),
+        ("Do you want to turn off the two-factor authentication?", "是否关闭双重认证？"),
+        ("Scan the QR code with your authenticator app, or enter the key", "请用身份验证器应用扫描二维码，或输入密钥"),
+        ("Code", "验证码"),
+        ("2FA required", "需要双重认证"),
+        ("Wrong 2FA Code", "双重认证验证码错误"),
+        ("Notification Hooks", "通知钩子"),
+        ("hooks_tip", "会话被接受、拒绝、认证失败和断开时, 以 JSON 发送 POST 到此 URL, 并以该 JSON 为参数运行此脚本"),
+        ("Script", "脚本"),
+        ("Logging", "日志"),
+        ("logging_tip", "级别立即生效, 例如 info,rustdesk::rendezvous_mediator=trace; 格式和文件大小在服务重启后生效"),
+        ("Audit Log", "审计日志"),
+        ("Filter", "筛选"),
+        ("Export CSV", "导出 CSV"),
+        ("No sessions", "没有会话"),
+        ("Incoming", "传入"),
+        ("Outgoing", "传出"),
+        ("Files", "文件"),
+        ("Failed to export", "导出失败"),
+        ("audit_broken_tip", "红色的条目起日志已被改动或删除过"),
+        ("2FA not set up", "被控端未设置双重认证"),
+        ("2fa_required_tip", "管理员要求启用双重认证，无法关闭"),
+        ("Edit", "编辑"),
+        ("Add to Address Book", "添加到地址簿"),
+        ("Remove from Address Book", "从地址簿中删除"),
+        ("Alias", "别名"),
+        ("Separated by commas", "以逗号分隔"),
+        ("Notes", "备注"),
+        ("Address Book Sync", "地址簿同步"),
+        ("Token", "令牌"),
+        ("Passphrase", "口令"),
+        ("Invalid URL", "无效的URL"),
+        ("The passphrase is required", "需要口令"),
+        ("Syncing ...", "正在同步..."),
+        ("Sync", "同步"),
+        ("Failed", "失败"),
+        ("ab_sync_tip", "与一个保存PUT到该URL内容的服务器（如WebDAV文件）同步，并以口令加密，使您所有电脑上的地址簿保持一致"),
+        ("Group", "分组"),
+        ("ab_group_tip", "子分组以/分隔，如 办公室/一楼"),
+        ("Online", "在线"),
+        ("Offline", "离线"),
+        ("Actions", "批量操作"),
+        ("Check online status", "检查在线状态"),
+        ("Apply settings", "应用设置"),
+        ("apply_settings_tip", "以下设置将应用于所显示的{}个对端"),
+        ("Unchanged", "不变"),
+        ("Image quality", "画质"),
+        ("View style", "显示方式"),
+        ("Yes", "是"),
+        ("No", "否"),
+        ("Connect one by one", "逐个连接"),
+        ("connect_one_by_one_tip", "将依次打开与所显示的{}个对端的连接，每隔3秒一个"),
+        ("Read", "已读"),
+        ("Delivered", "已送达"),
+        ("Attach a file", "发送文件"),
+        ("file_received_tip", "已保存到拖放文件夹"),
+        ("File too large", "文件过大"),
+        ("Not a file", "不是文件"),
+        ("attach_not_allowed_tip", "只有在允许使用键盘和鼠标时才能发送文件"),
+        ("Annotate", "标注"),
+        ("Laser pointer", "激光笔"),
+        ("Arrow", "箭头"),
+        ("Circle", "圆圈"),
+        ("Clear annotations", "清除标注"),
+        ("Stop annotating", "停止标注"),
+        ("Enable Remote Printer", "允许远程打印"),
+        ("virtual_printer_tip", "添加一台虚拟打印机，打印到它的文档会发送给请求接收打印任务并有文件传输权限的控制端"),
+        ("Receive remote print jobs", "接收远程打印任务"),
+        ("remote_printer_tip", "被控端虚拟打印机的打印任务发送到这里，在本地打印或保存"),
+        ("Ask for each print job", "每个打印任务都询问"),
+        ("Print here", "在本地打印"),
+        ("Save the print jobs", "保存打印任务"),
+        ("Print job", "打印任务"),
+        ("print_job_tip", "对方打印了一个文档："),
+        ("Save as", "另存为"),
+        ("Discard", "丢弃"),
+        ("Always do this", "总是这样处理"),
+        ("Clipboard history", "剪贴板历史"),
+        ("clipboard_history_tip", "最近复制的文本，选择一项将其发送到远程剪贴板并粘贴"),
+        ("Low latency preset", "预设：低延迟"),
+        ("Balanced preset", "预设：均衡"),
+        ("Best quality preset", "预设：最佳画质"),
+        ("quality_preset_tip", "一次设置画质、帧率和编码，并在每次连接此设备时使用"),
+        ("Plugins", "插件"),
+        ("No plugins", "没有插件"),
+        ("plugins_tip", "安装在程序目录的 plugins 文件夹中 (Linux 上为 /usr/lib/rustdesk/plugins), 每个插件一个文件夹, 带有 manifest.json; 启用后可查看和修改会话的消息, 在远程窗口添加操作"),
+        ("Log levels", "日志级别"),
+        ("Max file size (MB)", "单个文件上限 (MB)"),
+        ("JSON format", "JSON 格式"),
+        ("Metrics Exporter", "指标导出"),
+        ("metrics_tip", "在此地址上以 Prometheus 格式提供服务的指标 (/metrics), 仅端口表示只在本机, 例如 0.0.0.0:9400 对网络开放, 留空则关闭"),
+        ("Invalid address", "无效地址"),
+        ("Saved Tunnels", "保存的隧道"),
+        ("saved_tunnels_tip", "每行一个: 名称 方向(local, udp, dynamic, reverse) 本地端口 [远程主机:]远程端口 [auto], auto 在对方在线时自动建立"),
+        ("Invalid tunnel", "无效的隧道"),
+        ("dynamic_forward_tip", "留空远程端口，则在本地端口开启一个 SOCKS5 代理，经由对方连接任意目标"),
+        ("Allow Terminal", "允许终端"),
+        ("terminal_tip", "允许对方通过命令行工具打开本机的终端（shell）"),
+        ("Terminal", "终端"),
+        ("Allow Headless", "允许无头模式"),
+        ("headless_tip", "没有任何会话时（例如无显示器的服务器）启动虚拟 X 服务器"),
+        ("Request Elevation", "请求提权"),
+        ("Elevation Error", "提权失败"),
+        ("elevation_tip", "对方未以管理员权限运行，无法操作 UAC 提示和管理员权限的窗口。留空用户名将由对方确认 UAC 提示，否则以该管理员账户登录。"),
+        ("elevation_waiting_tip", "正在等待对方确认 UAC 提示"),
+        ("elevation_logon_tip", "正在以管理员账户登录"),
+        ("elevation_restarting_tip", "对方正在以管理员权限重启，完成后将自动重新连接"),
+        ("Restart Remote Device", "重启远程电脑"),
+        ("Restart in Safe Mode", "重启到安全模式"),
+        ("Restarting Remote Device", "正在重启远程电脑"),
+        ("remote_restarting_tip", "远程电脑正在重启，重启完成后将自动重新连接"),
+        ("restart_remote_device_tip", "确定要重启远程电脑吗？重启完成后将自动重新连接。"),
+        ("restart_safe_mode_tip", "确定要将远程电脑重启到带网络连接的安全模式吗？下次重启将恢复正常模式。"),
+        ("wake_up_tip", "通过网络唤醒此设备，由其局域网内一台在线的设备转发"),
+        ("Wake", "唤醒"),
+        ("switch_sides_tip", "由远程计算机反过来控制本机，对方无需密码即可连回"),
+        ("Switch Sides", "切换控制方向"),
+        ("single_controller_tip", "同一时间只有一个远程桌面会话可以控制，其他会话仅可查看，直到在连接管理器中移交控制权"),
+        ("One Controller at a Time", "同一时间仅一个控制者"),
+        ("Forward gamepads", "转发游戏手柄"),
+        ("forward_gamepad_tip", "将本地游戏手柄作为对方的虚拟手柄，Windows 上需要对方安装 ViGEmBus 驱动"),
+        ("Legacy keyboard mode", "传统键盘模式"),
+        ("Character keyboard mode", "字符键盘模式"),
+        ("Positional keyboard mode", "按键位置键盘模式"),
+        ("keyboard_character_tip", "按本地键盘布局输入字符，适合两端键盘布局不同时打字"),
+        ("keyboard_positional_tip", "按按键的物理位置发送，使用对方的键盘布局，适合游戏和快捷键"),
+        ("Media keys", "媒体键"),
+        ("Play/Pause", "播放/暂停"),
+        ("Next track", "下一曲"),
+        ("Previous track", "上一曲"),
+        ("Stop", "停止"),
+        ("Volume up", "增大音量"),
+        ("Volume down", "减小音量"),
+        ("Block user input", "阻止用户输入"),
+        ("Unblock user input", "取消阻止用户输入"),
+        ("block_input_failed_tip", "无法阻止对方的本地输入"),
+        ("privacy_mode_tip", "会话期间关闭对方的显示器并屏蔽对方的本地输入"),
+        ("privacy_mode_failed_tip", "无法开启隐私模式，对方不支持或者正被其他会话使用"),
+        ("lock_on_disconnect_tip", "最后一个远程会话结束或中断时锁定屏幕或注销"),
+        ("On Disconnect", "断开连接后"),
+        ("Do nothing", "不做任何操作"),
+        ("Lock", "锁定"),
+        ("Log out", "注销"),
+        ("Session Time Limits", "会话时间限制"),
+        ("session_limits_tip", "无输入超过空闲时间，或者达到最长时间后自动断开连接，提前一分钟提醒对方，留空则不限制"),
+        ("Idle timeout (minutes)", "空闲超时（分钟）"),
+        ("Maximum duration (minutes)", "最长时间（分钟）"),
+        ("Session Timeout", "会话超时"),
+        ("idle_timeout_tip", "由于长时间没有输入，会话将在{}秒后断开"),
+        ("session_limit_tip", "会话达到时间限制，将在{}秒后断开"),
+        ("Closed after a period of no input", "长时间无输入，连接已断开"),
+        ("Closed at the session time limit", "达到会话时间限制，连接已断开"),
+        ("Permission Profiles", "权限配置"),
+        ("Global permissions", "全局权限"),
+        ("Full control", "完全控制"),
+        ("View only", "仅查看"),
+        ("File transfer only", "仅文件传输"),
+        ("Other peers", "其他对方"),
+        ("Invalid profile", "无效配置"),
+        ("Only file transfer is allowed", "仅允许文件传输"),
+        ("permission_profiles_tip", "按对方ID选择权限，每行一个ID和权限：full（完全控制）、view（仅查看）或 file（仅文件传输）"),
+        ("Password Rotation", "密码轮换"),
+        ("password_rotation_tip", "自动更换随机密码，分享出去的密码之后就无法再用了，不影响您自己设置的密码"),
+        ("After each accepted connection", "每次连接成功后"),
+        ("After failed attempts", "失败次数达到"),
+        ("Every (minutes)", "每隔（分钟）"),
+        ("Invalid number", "无效数字"),
+        ("Fingerprint", "指纹"),
+        ("Pinned Keys", "固定的公钥"),
+        ("pinned_keys_tip", "每个对方ID在首次加密连接时固定的公钥，每行一个，可以复制导出，或者粘贴导入"),
+        ("Invalid key", "无效公钥"),
+        ("Security Warning", "安全警告"),
+        ("key_changed_tip", "对方的公钥与首次连接时固定的不一致，或者无法验证。可能是对方重新安装了，也可能是中间人攻击，例如服务器被入侵。请通过其他途径和对方核对指纹，确认后点击确定信任当前公钥并连接。"),
+        ("Pinned key", "固定的公钥"),
+        ("Current key", "当前公钥"),
+        ("Unverified", "无法验证"),
+        ("Please enter the code of your authenticator app", "请输入身份验证器应用中的验证码"),
         ("Enable Keyboard/Mouse", "允许控制键盘/鼠标"),
         ("Enable Clipboard", "允许同步剪贴板"),
         ("Enable File Transfer", "允许传输文件"),
         ("Enable TCP Tunneling", "允许建立TCP隧道"),
-        ("IP Whitelisting", "IP白名单"),
+        ("Enable Virtual Display", "允许虚拟显示器"),
+        ("virtual_display_tip", "没有显示器时，插入一个虚拟显示器以供远程控制"),
+        ("Access Control", "访问控制"),
         ("ID/Relay Server", "ID/中继服务器"),
         ("Stop service", "停止服务"),
         ("Change ID", "改变ID"),
@@ -37,7 +216,9 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Relay Server", "中继服务器"),
         ("API Server", "API服务器"),
         ("invalid_http", "必须以http://或者https://开头"),
-        ("Invalid IP", "无效IP"),
+        ("Invalid rule", "无效规则"),
+        ("Allow", "允许"),
+        ("Deny", "拒绝"),
         ("id_change_tip", "只可以使用字母a-z, A-Z, 0-9, _ (下划线)。首字母必须是a-z, A-Z。长度在6与16之间。"),
         ("Invalid format", "无效格式"),
         ("This function is turned off by the server", "服务器关闭了此功能"),
@@ -59,6 +240,11 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Connecting...", "正在连接..."),
         ("Connection in progress. Please wait.", "连接进行中，请稍等。"),
         ("Please try 1 minute later", "一分钟后再试"),
+        ("Please try again later", "请稍后再试"),
+        ("Ban list", "封禁列表"),
+        ("Unban", "解除封禁"),
+        ("No bans", "没有封禁"),
+        ("minutes", "分钟"),
         ("Login Error", "登录错误"),
         ("Successful", "成功"),
         ("Connected, waiting for image...", "已连接，等待画面传输..."),
@@ -76,6 +262,9 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Deleting", "正在删除"),
         ("files", "文件"),
         ("Waiting", "等待..."),
+        ("Paused", "已暂停"),
+        ("Running", "进行中"),
+        ("Queued", "排队中"),
         ("Finished", "完成"),
         ("Custom Image Quality", "设置画面质量"),
         ("Privacy mode", "隐私模式"),
@@ -88,8 +277,44 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Optimize reaction time", "优化反应时间"),
         ("Custom", "自定义画质"),
         ("Show remote cursor", "显示远程光标"),
+        ("Show all displays", "显示所有显示器"),
+        ("Show quality monitor", "显示质量监测"),
+        ("Latency", "延迟"),
+        ("Codec", "编解码器"),
+        ("Bitrate", "码率"),
+        ("Packet loss", "丢包率"),
+        ("Connection", "连接"),
+        ("Direct", "直连"),
+        ("Relay", "中继"),
+        ("Match window resolution", "匹配窗口分辨率"),
+        ("Select window", "选择窗口"),
+        ("Select region", "选择区域"),
+        ("Whole display", "整个显示器"),
+        ("Record session", "录制会话"),
+        ("Stop recording", "停止录制"),
+        ("Recording", "录制"),
+        ("Receiving clipboard", "正在接收剪贴板"),
+        ("Talk with the microphone", "用麦克风讲话"),
+        ("Turn off microphone", "关闭麦克风"),
+        ("Microphone", "麦克风"),
+        ("Allow talking through the speakers", "允许通过扬声器讲话"),
+        ("Voice call", "语音通话"),
+        ("Hang up", "挂断"),
+        ("Cancel the voice call", "取消语音通话"),
+        ("Incoming voice call", "语音通话请求"),
+        ("The voice call was declined", "语音通话被拒绝"),
+        ("Low latency audio", "低延迟音频"),
+        ("Balanced audio", "平衡音频"),
+        ("High quality audio", "高质量音频"),
+        ("The window is followed when it moves", "窗口移动时会跟随"),
+        ("No window found", "未找到窗口"),
         ("Disable clipboard", "禁止剪贴板"),
+        ("Clipboard both ways", "双向同步剪贴板"),
+        ("Clipboard from remote only", "仅从远端同步剪贴板"),
+        ("Clipboard to remote only", "仅向远端同步剪贴板"),
         ("Lock after session end", "断开后锁定远程电脑"),
+        ("Paste after drop", "拖放文件后粘贴"),
+        ("Drop files", "拖放文件"),
         ("Insert", "插入"),
         ("Insert Lock", "锁定远程电脑"),
         ("Refresh", "刷新画面"),
@@ -156,6 +381,14 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Change Path", "改变路径"),
         ("Create Folder", "创建文件夹"),
         ("Please enter the folder name", "请输入文件夹名称"),
+        ("Search", "搜索"),
+        ("Searching", "搜索中"),
+        ("Please enter the file name or pattern", "请输入文件名或通配符"),
+        ("Synchronize Folders", "同步文件夹"),
+        ("From the remote computer", "从远程电脑"),
+        ("Compare the content of the files", "比较文件内容"),
+        ("The folders are the same", "文件夹相同"),
+        ("Files to copy", "要复制的文件"),
         ("Fix it", "修复"),
         ("Warning", "警告"),
         ("Login screen using Wayland is not supported", "不支持使用 Wayland 登录界面"),
@@ -171,7 +404,7 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Run without install", "无安装运行"),
         ("Always connected via relay", "强制走中继连接"),
         ("Always connect via relay", "强制走中继连接"),
-        ("whitelist_tip", "只有白名单里的ip才能访问我"),
+        ("acl_tip", "只有允许且未被拒绝的IP、CIDR网段或ID模式（支持*和?）才能访问我"),
         ("Login", "登录"),
         ("Logout", "登出"),
         ("Tags", "标签"),
@@ -12,9 +12,55 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("not_close_tcp_tip", "Don't close this window while you are using the tunnel"),
         ("setup_server_tip", "For faster connection, please set up your own server"),
         ("Auto Login", "Auto Login (Only valid if you set \"Lock after session end\")"),
-        ("whitelist_tip", "Only whitelisted IP can access me"),
+        ("acl_tip", "Only the IPs, CIDR ranges or ID patterns (with * and ?) allowed and not denied can access me"),
+        ("virtual_display_tip", "Plug in a virtual monitor for remote control when there is no monitor"),
         ("whitelist_sep", "Seperated by comma, semicolon, spaces or new line"),
         ("Wrong credentials", "Wrong username or password"),
         ("invalid_http", "must start with http:// or https://"),
+        ("hooks_tip", "When a session is accepted, rejected, fails to authenticate or ends, a JSON POST to the URL, and the script run with the JSON as its argument"),
+        ("logging_tip", "The levels apply at once, e.g. info,rustdesk::rendezvous_mediator=trace; the format and the file size after a restart of the service"),
+        ("audit_broken_tip", "The log has been changed, or entries removed, from the ones in red on"),
+        ("2fa_required_tip", "The two-factor authentication is required by the administrator and can not be turned off"),
+        ("ab_sync_tip", "Synced with a server storing what is PUT to the URL, e.g. a WebDAV file, encrypted with the passphrase, to be the same on all your computers"),
+        ("ab_group_tip", "Subgroups separated by /, e.g. Office/Floor 1"),
+        ("apply_settings_tip", "The settings below are applied to the {} peers shown"),
+        ("connect_one_by_one_tip", "The connections to the {} peers shown are opened one after the other, every 3 seconds"),
+        ("file_received_tip", "Saved in the drop folder"),
+        ("attach_not_allowed_tip", "Files can only be sent with the keyboard and mouse allowed"),
+        ("virtual_printer_tip", "Adds a virtual printer whose documents are sent to the controllers asking for them, with the file transfer allowed"),
+        ("remote_printer_tip", "The jobs of the virtual printer of the remote side are sent here, to be printed or saved"),
+        ("print_job_tip", "The remote side printed a document:"),
+        ("clipboard_history_tip", "The texts copied last, choosing one sends it to the remote clipboard and pastes it"),
+        ("quality_preset_tip", "Sets the image quality, frames per second and codec at once, applied at each connection to this peer"),
+        ("plugins_tip", "Installed in the plugins folder of the program (/usr/lib/rustdesk/plugins on Linux), a folder a plugin with its manifest.json; once enabled, they may see and change the messages of the sessions and add actions to the remote window"),
+        ("metrics_tip", "The metrics of the service in the Prometheus format (/metrics) on this address, a port alone for localhost only, e.g. 0.0.0.0:9400 for the network, empty to turn it off"),
+        ("saved_tunnels_tip", "One a line: name direction (local, udp, dynamic, reverse) local-port [remote-host:]remote-port [auto], auto to establish it whenever the peer is online. For reverse, the remote port is the one the peer listens on, forwarded to the local port here. dynamic takes no remote port."),
+        ("dynamic_forward_tip", "Without a remote port, a SOCKS5 proxy on the local port, to any destination through the peer"),
+        ("terminal_tip", "Allow the peer to open a shell of this computer with the command line tool"),
+        ("headless_tip", "Start a virtual X server when there is no session, e.g. on a server without any display"),
+        ("elevation_tip", "The remote device is not running as an administrator, the UAC prompts and the elevated windows can not be used. Leave the username empty for the remote user to accept the UAC prompt, otherwise it logs on as this administrator."),
+        ("elevation_waiting_tip", "Waiting for the remote user to accept the UAC prompt"),
+        ("elevation_logon_tip", "Logging on as the administrator"),
+        ("elevation_restarting_tip", "The remote device is restarting as an administrator, the session reconnects automatically"),
+        ("remote_restarting_tip", "The remote device is restarting, the session reconnects automatically once it is back online"),
+        ("restart_remote_device_tip", "Are you sure you want to restart the remote device? The session reconnects automatically."),
+        ("restart_safe_mode_tip", "Are you sure you want to restart the remote device in the safe mode with networking? The boot after is a normal one."),
+        ("wake_up_tip", "Wake this device up over the network, through a device online on its LAN"),
+        ("switch_sides_tip", "Let the remote computer control this one instead, connecting back without a password"),
+        ("single_controller_tip", "Only one remote session controls at a time, the first one, the others only view until the control is handed over in the connection manager"),
+        ("forward_gamepad_tip", "Use the local gamepads as virtual ones of the peer, which needs the ViGEmBus driver on Windows"),
+        ("keyboard_character_tip", "Type the characters of the local layout, to write when the layouts differ"),
+        ("keyboard_positional_tip", "Send the position of the keys, typed in the layout of the peer, for games and shortcuts"),
+        ("block_input_failed_tip", "The local input of the peer could not be blocked"),
+        ("privacy_mode_tip", "Black out the monitors of the peer and block its local input during the session"),
+        ("privacy_mode_failed_tip", "The privacy mode could not be turned on, it is not supported by the peer or used by another session"),
+        ("lock_on_disconnect_tip", "Lock the screen or log out when the last remote session ends or drops"),
+        ("session_limits_tip", "Disconnect automatically after a period of no input or at the maximum duration, the peer is warned a minute before, empty for no limit"),
+        ("idle_timeout_tip", "With no input, the session will be closed in {} seconds"),
+        ("session_limit_tip", "The session reaches its time limit and will be closed in {} seconds"),
+        ("permission_profiles_tip", "The permissions by the ID of the peer, an ID and a profile per line: full (full control), view (view only) or file (file transfer only)"),
+        ("password_rotation_tip", "Change the random password automatically, so that a shared one can not be used again later, your own password is kept"),
+        ("pinned_keys_tip", "The key pinned for each remote ID at the first encrypted connection, one per line, copy them to export or paste them to import"),
+        ("key_changed_tip", "The key of the remote desktop is not the one pinned at the first connection, or could not be verified. It may have been reinstalled, or it is a man in the middle attack, e.g. by a compromised server. Check the fingerprint with your partner in another way, then click OK to trust the current key and connect."),
     ].iter().cloned().collect();
 }
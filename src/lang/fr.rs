@@ -21,11 +21,190 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Remove", "Supprimer"),
         ("Refresh random password", "Actualiser le mot de passe aléatoire"),
         ("Set your own password", "Définir votre propre mot de passe"),
+        ("Two-factor authentication", "Authentification à deux facteurs"),
+        ("Do you want to turn off the two-factor authentication?", "Voulez-vous désactiver l'authentification à deux facteurs ?"),
+        ("Scan the QR code with your authenticator app, or enter the key", "Scannez le code QR avec votre application d'authentification, ou saisissez la clé"),
+        ("Code", "Code"),
+        ("2FA required", "Double authentification requise"),
+        ("Wrong 2FA Code", "Code de double authentification incorrect"),
+        ("Notification Hooks", "Crochets de notification"),
+        ("hooks_tip", "Quand une session est acceptée, refusée, échoue à l'authentification ou se termine, un POST en JSON vers l'URL et le script lancé avec ce JSON en argument"),
+        ("Script", "Script"),
+        ("Logging", "Journalisation"),
+        ("logging_tip", "Les niveaux s'appliquent aussitôt, par ex. info,rustdesk::rendezvous_mediator=trace ; le format et la taille des fichiers après un redémarrage du service"),
+        ("Audit Log", "Journal d'audit"),
+        ("Filter", "Filtrer"),
+        ("Export CSV", "Exporter en CSV"),
+        ("No sessions", "Aucune session"),
+        ("Incoming", "Entrante"),
+        ("Outgoing", "Sortante"),
+        ("Files", "Fichiers"),
+        ("Failed to export", "Échec de l'export"),
+        ("audit_broken_tip", "Le journal a été modifié ou des entrées supprimées à partir de celles en rouge"),
+        ("2FA not set up", "La double authentification n'est pas configurée sur l'hôte"),
+        ("2fa_required_tip", "La double authentification est exigée par l'administrateur et ne peut pas être désactivée"),
+        ("Edit", "Modifier"),
+        ("Add to Address Book", "Ajouter au carnet d'adresses"),
+        ("Remove from Address Book", "Retirer du carnet d'adresses"),
+        ("Alias", "Alias"),
+        ("Separated by commas", "Séparés par des virgules"),
+        ("Notes", "Notes"),
+        ("Address Book Sync", "Synchronisation du carnet d'adresses"),
+        ("Token", "Jeton"),
+        ("Passphrase", "Phrase secrète"),
+        ("Invalid URL", "URL invalide"),
+        ("The passphrase is required", "La phrase secrète est requise"),
+        ("Syncing ...", "Synchronisation ..."),
+        ("Sync", "Synchroniser"),
+        ("Failed", "Échec"),
+        ("ab_sync_tip", "Synchronisé avec un serveur gardant ce qui est envoyé par PUT à l'URL, par exemple un fichier WebDAV, chiffré avec la phrase secrète, pour être le même sur tous vos ordinateurs"),
+        ("Group", "Groupe"),
+        ("ab_group_tip", "Sous-groupes séparés par /, ex. Bureau/Étage 1"),
+        ("Online", "En ligne"),
+        ("Offline", "Hors ligne"),
+        ("Actions", "Actions"),
+        ("Check online status", "Vérifier l'état en ligne"),
+        ("Apply settings", "Appliquer des paramètres"),
+        ("apply_settings_tip", "Les paramètres ci-dessous seront appliqués aux {} pairs affichés"),
+        ("Unchanged", "Inchangé"),
+        ("Image quality", "Qualité d'image"),
+        ("View style", "Style d'affichage"),
+        ("Yes", "Oui"),
+        ("No", "Non"),
+        ("Connect one by one", "Se connecter un par un"),
+        ("connect_one_by_one_tip", "Les connexions aux {} pairs affichés seront ouvertes l'une après l'autre, toutes les 3 secondes"),
+        ("Read", "Lu"),
+        ("Delivered", "Distribué"),
+        ("Attach a file", "Joindre un fichier"),
+        ("file_received_tip", "Enregistré dans le dossier de dépôt"),
+        ("File too large", "Fichier trop volumineux"),
+        ("Not a file", "Pas un fichier"),
+        ("attach_not_allowed_tip", "Les fichiers ne peuvent être envoyés qu'avec le clavier et la souris autorisés"),
+        ("Annotate", "Annoter"),
+        ("Laser pointer", "Pointeur laser"),
+        ("Arrow", "Flèche"),
+        ("Circle", "Cercle"),
+        ("Clear annotations", "Effacer les annotations"),
+        ("Stop annotating", "Arrêter d'annoter"),
+        ("Enable Remote Printer", "Activer l'imprimante distante"),
+        ("virtual_printer_tip", "Ajoute une imprimante virtuelle dont les documents sont envoyés aux contrôleurs qui les demandent et ont le transfert de fichiers"),
+        ("Receive remote print jobs", "Recevoir les impressions distantes"),
+        ("remote_printer_tip", "Les impressions sur l'imprimante virtuelle de l'hôte sont envoyées ici, pour être imprimées ou enregistrées"),
+        ("Ask for each print job", "Demander pour chaque impression"),
+        ("Print here", "Imprimer ici"),
+        ("Save the print jobs", "Enregistrer les impressions"),
+        ("Print job", "Impression"),
+        ("print_job_tip", "Le pair a imprimé un document :"),
+        ("Save as", "Enregistrer sous"),
+        ("Discard", "Ignorer"),
+        ("Always do this", "Toujours faire ainsi"),
+        ("Clipboard history", "Historique du presse-papiers"),
+        ("clipboard_history_tip", "Les derniers textes copiés, choisir l'un d'eux l'envoie au presse-papiers distant et le colle"),
+        ("Low latency preset", "Préréglage faible latence"),
+        ("Balanced preset", "Préréglage équilibré"),
+        ("Best quality preset", "Préréglage meilleure qualité"),
+        ("quality_preset_tip", "Règle la qualité d'image, les images par seconde et le codec en une fois, appliqués à chaque connexion à ce pair"),
+        ("Plugins", "Plugins"),
+        ("No plugins", "Aucun plugin"),
+        ("plugins_tip", "Installés dans le dossier plugins du programme (/usr/lib/rustdesk/plugins sous Linux), un dossier par plugin avec son manifest.json ; une fois activés, ils peuvent voir et modifier les messages des sessions et ajouter des actions à la fenêtre distante"),
+        ("Log levels", "Niveaux de journal"),
+        ("Max file size (MB)", "Taille maximale d'un fichier (Mo)"),
+        ("JSON format", "Format JSON"),
+        ("Metrics Exporter", "Exportateur de métriques"),
+        ("metrics_tip", "Les métriques du service au format Prometheus (/metrics) sur cette adresse, un port seul pour l'hôte local uniquement, par ex. 0.0.0.0:9400 pour le réseau, vide pour désactiver"),
+        ("Invalid address", "Adresse invalide"),
+        ("Saved Tunnels", "Tunnels enregistrés"),
+        ("saved_tunnels_tip", "Un par ligne : nom direction (local, udp, dynamic, reverse) port-local [hôte-distant:]port-distant [auto], auto pour l'établir dès que le pair est en ligne"),
+        ("Invalid tunnel", "Tunnel invalide"),
+        ("dynamic_forward_tip", "Sans port distant, un proxy SOCKS5 sur le port local, vers n'importe quelle destination à travers le pair"),
+        ("Allow Terminal", "Autoriser le terminal"),
+        ("terminal_tip", "Permettre au pair d'ouvrir un shell de cet ordinateur depuis l'outil en ligne de commande"),
+        ("Terminal", "Terminal"),
+        ("Allow Headless", "Autoriser le mode sans écran"),
+        ("headless_tip", "Démarrer un serveur X virtuel lorsqu'aucune session n'est ouverte, par exemple sur un serveur sans écran"),
+        ("Request Elevation", "Demander l'élévation"),
+        ("Elevation Error", "Erreur d'élévation"),
+        ("elevation_tip", "L'appareil distant ne s'exécute pas en tant qu'administrateur, les invites UAC et les fenêtres élevées ne peuvent pas être utilisées. Laissez le nom d'utilisateur vide pour que l'utilisateur distant accepte l'invite UAC, sinon la session ouvre ce compte administrateur."),
+        ("elevation_waiting_tip", "En attente de l'acceptation de l'invite UAC par l'utilisateur distant"),
+        ("elevation_logon_tip", "Connexion avec le compte administrateur"),
+        ("elevation_restarting_tip", "L'appareil distant redémarre en tant qu'administrateur, la session se reconnectera automatiquement"),
+        ("Restart Remote Device", "Redémarrer l'appareil distant"),
+        ("Restart in Safe Mode", "Redémarrer en mode sans échec"),
+        ("Restarting Remote Device", "Redémarrage de l'appareil distant"),
+        ("remote_restarting_tip", "L'appareil distant redémarre, la session se reconnectera automatiquement une fois qu'il sera de nouveau en ligne"),
+        ("restart_remote_device_tip", "Êtes-vous sûr de vouloir redémarrer l'appareil distant ? La session se reconnectera automatiquement."),
+        ("restart_safe_mode_tip", "Êtes-vous sûr de vouloir redémarrer l'appareil distant en mode sans échec avec prise en charge réseau ? Le démarrage suivant sera normal."),
+        ("wake_up_tip", "Réveiller cet appareil par le réseau, par un appareil en ligne de son réseau local"),
+        ("Wake", "Réveiller"),
+        ("switch_sides_tip", "Être contrôlé par l'ordinateur distant à la place, celui-ci se reconnectant sans mot de passe"),
+        ("Switch Sides", "Inverser les rôles"),
+        ("single_controller_tip", "Une seule session distante contrôle à la fois, les autres ne font que regarder jusqu'à ce que le contrôle leur soit donné dans le gestionnaire de connexions"),
+        ("One Controller at a Time", "Un seul contrôleur à la fois"),
+        ("Forward gamepads", "Transférer les manettes"),
+        ("forward_gamepad_tip", "Utiliser les manettes locales comme manettes virtuelles du pair, qui a besoin du pilote ViGEmBus sous Windows"),
+        ("Legacy keyboard mode", "Mode clavier classique"),
+        ("Character keyboard mode", "Mode clavier par caractères"),
+        ("Positional keyboard mode", "Mode clavier positionnel"),
+        ("keyboard_character_tip", "Taper les caractères de la disposition locale, pour écrire quand les dispositions diffèrent"),
+        ("keyboard_positional_tip", "Envoyer la position des touches, tapées dans la disposition du pair, pour les jeux et les raccourcis"),
+        ("Media keys", "Touches multimédia"),
+        ("Play/Pause", "Lecture/Pause"),
+        ("Next track", "Piste suivante"),
+        ("Previous track", "Piste précédente"),
+        ("Stop", "Arrêt"),
+        ("Volume up", "Augmenter le volume"),
+        ("Volume down", "Baisser le volume"),
+        ("Block user input", "Bloquer la saisie de l'utilisateur"),
+        ("Unblock user input", "Débloquer la saisie de l'utilisateur"),
+        ("block_input_failed_tip", "La saisie locale du pair n'a pas pu être bloquée"),
+        ("privacy_mode_tip", "Éteindre les écrans du pair et bloquer sa saisie locale pendant la session"),
+        ("privacy_mode_failed_tip", "Le mode privé n'a pas pu être activé, il n'est pas pris en charge par le pair ou utilisé par une autre session"),
+        ("lock_on_disconnect_tip", "Verrouiller l'écran ou fermer la session quand la dernière session distante se termine ou est coupée"),
+        ("On Disconnect", "À la déconnexion"),
+        ("Do nothing", "Ne rien faire"),
+        ("Lock", "Verrouiller"),
+        ("Log out", "Se déconnecter"),
+        ("Session Time Limits", "Limites de durée de session"),
+        ("session_limits_tip", "Déconnecter automatiquement après une période sans saisie ou à la durée maximale, le pair est averti une minute avant, vide pour aucune limite"),
+        ("Idle timeout (minutes)", "Délai d'inactivité (minutes)"),
+        ("Maximum duration (minutes)", "Durée maximale (minutes)"),
+        ("Session Timeout", "Expiration de la session"),
+        ("idle_timeout_tip", "Sans saisie, la session sera fermée dans {} secondes"),
+        ("session_limit_tip", "La session atteint sa durée maximale et sera fermée dans {} secondes"),
+        ("Closed after a period of no input", "Fermée après une période sans saisie"),
+        ("Closed at the session time limit", "Fermée à la durée maximale de la session"),
+        ("Permission Profiles", "Profils de permissions"),
+        ("Global permissions", "Permissions globales"),
+        ("Full control", "Contrôle total"),
+        ("View only", "Affichage seul"),
+        ("File transfer only", "Transfert de fichiers seul"),
+        ("Other peers", "Autres pairs"),
+        ("Invalid profile", "Profil invalide"),
+        ("Only file transfer is allowed", "Seul le transfert de fichiers est autorisé"),
+        ("permission_profiles_tip", "Les permissions selon l'ID du pair, un ID et un profil par ligne : full (contrôle total), view (affichage seul) ou file (transfert de fichiers seul)"),
+        ("Password Rotation", "Rotation du mot de passe"),
+        ("password_rotation_tip", "Changer automatiquement le mot de passe aléatoire, pour qu'un mot de passe partagé ne puisse plus servir plus tard, sans effet sur le vôtre"),
+        ("After each accepted connection", "Après chaque connexion acceptée"),
+        ("After failed attempts", "Après des tentatives échouées"),
+        ("Every (minutes)", "Toutes les (minutes)"),
+        ("Invalid number", "Nombre invalide"),
+        ("Fingerprint", "Empreinte"),
+        ("Pinned Keys", "Clés épinglées"),
+        ("pinned_keys_tip", "La clé épinglée de chaque ID distant à la première connexion chiffrée, une par ligne, à copier pour l'export ou à coller pour l'import"),
+        ("Invalid key", "Clé invalide"),
+        ("Security Warning", "Avertissement de sécurité"),
+        ("key_changed_tip", "La clé du poste distant n'est pas celle épinglée à la première connexion, ou n'a pas pu être vérifiée. Il a peut-être été réinstallé, ou c'est une attaque de l'homme du milieu, par exemple d'un serveur compromis. Vérifiez l'empreinte avec votre correspondant par un autre moyen, puis cliquez sur OK pour faire confiance à la clé actuelle et vous connecter."),
+        ("Pinned key", "Clé épinglée"),
+        ("Current key", "Clé actuelle"),
+        ("Unverified", "Non vérifiée"),
+        ("Please enter the code of your authenticator app", "Veuillez saisir le code de votre application d'authentification"),
         ("Enable Keyboard/Mouse", "Activer le contrôle clavier/souris"),
         ("Enable Clipboard", "Activer la synchronisation du presse-papiers"),
         ("Enable File Transfer", "Activer le transfert de fichiers"),
         ("Enable TCP Tunneling", "Activer le tunneling TCP"),
-        ("IP Whitelisting", "Liste blanche IP"),
+        ("Enable Virtual Display", "Activer l'écran virtuel"),
+        ("virtual_display_tip", "Brancher un écran virtuel pour le contrôle à distance lorsqu'aucun écran n'est présent"),
+        ("Access Control", "Contrôle d'accès"),
         ("ID/Relay Server", "ID/Serveur Relais"),
         ("Stop service", "Arrêter service"),
         ("Change ID", "Changer d'ID"),
@@ -37,7 +216,9 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Relay Server", "Serveur Relais"),
         ("API Server", "Serveur API"),
         ("invalid_http", "Doit commencer par http:// ou https://"),
-        ("Invalid IP", "IP invalide"),
+        ("Invalid rule", "Règle invalide"),
+        ("Allow", "Autoriser"),
+        ("Deny", "Refuser"),
         ("id_change_tip", "Seules les lettres a-z, A-Z, 0-9, _ (trait de soulignement) peuvent être utilisées. La première lettre doit être a-z, A-Z. La longueur est comprise entre 6 et 16."),
         ("Invalid format", "Format invalide"),
         ("This function is turned off by the server", "Cette fonction est désactivée par le serveur"),
@@ -59,6 +240,11 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Connecting...", "Connexion..."),
         ("Connection in progress. Please wait.", "Connexion en cours. Veuillez patienter."),
         ("Please try 1 minute later", "Réessayez dans une minute"),
+        ("Please try again later", "Veuillez réessayer plus tard"),
+        ("Ban list", "Liste des bannissements"),
+        ("Unban", "Débannir"),
+        ("No bans", "Aucun bannissement"),
+        ("minutes", "minutes"),
         ("Login Error", "Erreur de connexion"),
         ("Successful", "Succès"),
         ("Connected, waiting for image...", "Connecté, en attente de transmission d'image..."),
@@ -76,6 +262,9 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Deleting", "Suppression"),
         ("files", "fichier"),
         ("Waiting", "En attente en attente..."),
+        ("Paused", "En pause"),
+        ("Running", "En cours"),
+        ("Queued", "En attente"),
         ("Finished", "Terminé"),
         ("Custom Image Quality", "Définir la qualité d'image"),
         ("Privacy mode", "Mode privé"),
@@ -88,8 +277,44 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Optimize reaction time", "Optimiser le temps de réaction"),
         ("Custom", "Qualité d'image personnalisée"),
         ("Show remote cursor", "Afficher le curseur distant"),
+        ("Show all displays", "Afficher tous les écrans"),
+        ("Show quality monitor", "Afficher le moniteur de qualité"),
+        ("Latency", "Latence"),
+        ("Codec", "Codec"),
+        ("Bitrate", "Débit"),
+        ("Packet loss", "Perte de paquets"),
+        ("Connection", "Connexion"),
+        ("Direct", "Directe"),
+        ("Relay", "Relais"),
+        ("Match window resolution", "Adapter la résolution à la fenêtre"),
+        ("Select window", "Sélectionner une fenêtre"),
+        ("Select region", "Sélectionner une zone"),
+        ("Whole display", "Écran entier"),
+        ("Record session", "Enregistrer la session"),
+        ("Stop recording", "Arrêter l'enregistrement"),
+        ("Recording", "Enregistrement"),
+        ("Receiving clipboard", "Réception du presse-papier"),
+        ("Talk with the microphone", "Parler avec le microphone"),
+        ("Turn off microphone", "Couper le microphone"),
+        ("Microphone", "Microphone"),
+        ("Allow talking through the speakers", "Autoriser à parler dans les haut-parleurs"),
+        ("Voice call", "Appel vocal"),
+        ("Hang up", "Raccrocher"),
+        ("Cancel the voice call", "Annuler l'appel vocal"),
+        ("Incoming voice call", "Appel vocal entrant"),
+        ("The voice call was declined", "L'appel vocal a été refusé"),
+        ("Low latency audio", "Audio à faible latence"),
+        ("Balanced audio", "Audio équilibré"),
+        ("High quality audio", "Audio haute qualité"),
+        ("The window is followed when it moves", "La fenêtre est suivie lorsqu'elle est déplacée"),
+        ("No window found", "Aucune fenêtre trouvée"),
         ("Disable clipboard", "Désactiver le presse-papiers"),
+        ("Clipboard both ways", "Presse-papiers dans les deux sens"),
+        ("Clipboard from remote only", "Presse-papiers depuis la machine distante uniquement"),
+        ("Clipboard to remote only", "Presse-papiers vers la machine distante uniquement"),
         ("Lock after session end", "Verrouiller l'ordinateur distant après la déconnexion"),
+        ("Paste after drop", "Coller après le dépôt"),
+        ("Drop files", "Déposer des fichiers"),
         ("Insert", "Insérer"),
         ("Insert Lock", "Verrouiller l'ordinateur distant"),
         ("Refresh", "Rafraîchir l'écran"),
@@ -156,6 +381,14 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Change Path", "Changer de chemin"),
         ("Create Folder", "Créer un dossier"),
         ("Please enter the folder name", "Veuillez saisir le nom du dossier"),
+        ("Search", "Rechercher"),
+        ("Searching", "Recherche en cours"),
+        ("Please enter the file name or pattern", "Veuillez saisir le nom ou le motif du fichier"),
+        ("Synchronize Folders", "Synchroniser les dossiers"),
+        ("From the remote computer", "Depuis l'ordinateur distant"),
+        ("Compare the content of the files", "Comparer le contenu des fichiers"),
+        ("The folders are the same", "Les dossiers sont identiques"),
+        ("Files to copy", "Fichiers à copier"),
         ("Fix it", "Réparez-le"),
         ("Warning", "Avertissement"),
         ("Login screen using Wayland is not supported", "L'écran de connexion utilisant Wayland n'est pas pris en charge"),
@@ -171,7 +404,7 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Run without install", "Exécuter sans installer"),
         ("Always connected via relay", "Forcer la connexion relais"),
         ("Always connect via relay", "Forcer la connexion relais"),
-        ("whitelist_tip", "Seul l'ip dans la liste blanche peut m'accéder"),
+        ("acl_tip", "Seuls les IP, plages CIDR ou modèles d'ID (avec * et ?) autorisés et non refusés peuvent m'accéder"),
         ("Login", "Connexion"),
         ("Logout", "Déconnexion"),
         ("Tags", "Étiqueter"),
@@ -21,11 +21,190 @@ lazy_static::lazy_static! {
             ("Remove", "Rimuovi"),
             ("Refresh random password", "Nuova password casuale"),
             ("Set your own password", "Imposta la tua password"),
+            ("Two-factor authentication", "Autenticazione a due fattori"),
+            ("Do you want to turn off the two-factor authentication?", "Vuoi disattivare l'autenticazione a due fattori?"),
+            ("Scan the QR code with your authenticator app, or enter the key", "Scansiona il codice QR con la tua app di autenticazione, o inserisci la chiave"),
+            ("Code", "Codice"),
+            ("2FA required", "Autenticazione a due fattori richiesta"),
+            ("Wrong 2FA Code", "Codice di autenticazione errato"),
+            ("Notification Hooks", "Hook di notifica"),
+            ("hooks_tip", "Quando una sessione è accettata, rifiutata, fallisce l'autenticazione o termina, un POST in JSON all'URL e lo script eseguito con questo JSON come argomento"),
+            ("Script", "Script"),
+            ("Logging", "Registro"),
+            ("logging_tip", "I livelli si applicano subito, ad es. info,rustdesk::rendezvous_mediator=trace; il formato e la dimensione dei file dopo un riavvio del servizio"),
+            ("Audit Log", "Registro di controllo"),
+            ("Filter", "Filtra"),
+            ("Export CSV", "Esporta CSV"),
+            ("No sessions", "Nessuna sessione"),
+            ("Incoming", "In entrata"),
+            ("Outgoing", "In uscita"),
+            ("Files", "File"),
+            ("Failed to export", "Esportazione non riuscita"),
+            ("audit_broken_tip", "Il registro è stato modificato o delle voci rimosse a partire da quelle in rosso"),
+            ("2FA not set up", "L'autenticazione a due fattori non è configurata sull'host"),
+            ("2fa_required_tip", "L'autenticazione a due fattori è richiesta dall'amministratore e non può essere disattivata"),
+            ("Edit", "Modifica"),
+            ("Add to Address Book", "Aggiungi alla rubrica"),
+            ("Remove from Address Book", "Rimuovi dalla rubrica"),
+            ("Alias", "Alias"),
+            ("Separated by commas", "Separati da virgole"),
+            ("Notes", "Note"),
+            ("Address Book Sync", "Sincronizzazione della rubrica"),
+            ("Token", "Token"),
+            ("Passphrase", "Passphrase"),
+            ("Invalid URL", "URL non valido"),
+            ("The passphrase is required", "La passphrase è obbligatoria"),
+            ("Syncing ...", "Sincronizzazione ..."),
+            ("Sync", "Sincronizza"),
+            ("Failed", "Non riuscito"),
+            ("ab_sync_tip", "Sincronizzata con un server che conserva ciò che viene inviato con PUT all'URL, ad esempio un file WebDAV, cifrata con la passphrase, per essere la stessa su tutti i tuoi computer"),
+            ("Group", "Gruppo"),
+            ("ab_group_tip", "Sottogruppi separati da /, es. Ufficio/Piano 1"),
+            ("Online", "Online"),
+            ("Offline", "Offline"),
+            ("Actions", "Azioni"),
+            ("Check online status", "Verifica stato online"),
+            ("Apply settings", "Applica impostazioni"),
+            ("apply_settings_tip", "Le impostazioni seguenti saranno applicate ai {} peer mostrati"),
+            ("Unchanged", "Invariato"),
+            ("Image quality", "Qualità immagine"),
+            ("View style", "Stile di visualizzazione"),
+            ("Yes", "Sì"),
+            ("No", "No"),
+            ("Connect one by one", "Connetti uno alla volta"),
+            ("connect_one_by_one_tip", "Le connessioni ai {} peer mostrati saranno aperte una dopo l'altra, ogni 3 secondi"),
+            ("Read", "Letto"),
+            ("Delivered", "Consegnato"),
+            ("Attach a file", "Allega un file"),
+            ("file_received_tip", "Salvato nella cartella di rilascio"),
+            ("File too large", "File troppo grande"),
+            ("Not a file", "Non è un file"),
+            ("attach_not_allowed_tip", "I file possono essere inviati solo con tastiera e mouse consentiti"),
+            ("Annotate", "Annota"),
+            ("Laser pointer", "Puntatore laser"),
+            ("Arrow", "Freccia"),
+            ("Circle", "Cerchio"),
+            ("Clear annotations", "Cancella annotazioni"),
+            ("Stop annotating", "Interrompi annotazioni"),
+            ("Enable Remote Printer", "Abilita stampante remota"),
+            ("virtual_printer_tip", "Aggiunge una stampante virtuale i cui documenti sono inviati ai controllori che li richiedono e hanno il trasferimento file"),
+            ("Receive remote print jobs", "Ricevi le stampe remote"),
+            ("remote_printer_tip", "Le stampe sulla stampante virtuale dell'host sono inviate qui, per essere stampate o salvate"),
+            ("Ask for each print job", "Chiedi per ogni stampa"),
+            ("Print here", "Stampa qui"),
+            ("Save the print jobs", "Salva le stampe"),
+            ("Print job", "Stampa"),
+            ("print_job_tip", "Il peer ha stampato un documento:"),
+            ("Save as", "Salva come"),
+            ("Discard", "Scarta"),
+            ("Always do this", "Fai sempre così"),
+            ("Clipboard history", "Cronologia appunti"),
+            ("clipboard_history_tip", "Gli ultimi testi copiati, sceglierne uno lo invia agli appunti remoti e lo incolla"),
+            ("Low latency preset", "Preimpostazione bassa latenza"),
+            ("Balanced preset", "Preimpostazione bilanciata"),
+            ("Best quality preset", "Preimpostazione migliore qualità"),
+            ("quality_preset_tip", "Imposta qualità immagine, fotogrammi al secondo e codec in una volta, applicati a ogni connessione a questo peer"),
+            ("Plugins", "Plugin"),
+            ("No plugins", "Nessun plugin"),
+            ("plugins_tip", "Installati nella cartella plugins del programma (/usr/lib/rustdesk/plugins su Linux), una cartella per plugin con il suo manifest.json; una volta attivati possono vedere e modificare i messaggi delle sessioni e aggiungere azioni alla finestra remota"),
+            ("Log levels", "Livelli di registro"),
+            ("Max file size (MB)", "Dimensione massima di un file (MB)"),
+            ("JSON format", "Formato JSON"),
+            ("Metrics Exporter", "Esportatore di metriche"),
+            ("metrics_tip", "Le metriche del servizio nel formato Prometheus (/metrics) su questo indirizzo, solo una porta per il solo localhost, es. 0.0.0.0:9400 per la rete, vuoto per disattivare"),
+            ("Invalid address", "Indirizzo non valido"),
+            ("Saved Tunnels", "Tunnel salvati"),
+            ("saved_tunnels_tip", "Uno per riga: nome direzione (local, udp, dynamic, reverse) porta-locale [host-remoto:]porta-remota [auto], auto per stabilirlo quando il peer è online"),
+            ("Invalid tunnel", "Tunnel non valido"),
+            ("dynamic_forward_tip", "Senza porta remota, un proxy SOCKS5 sulla porta locale, verso qualsiasi destinazione attraverso il peer"),
+            ("Allow Terminal", "Consenti terminale"),
+            ("terminal_tip", "Consenti al peer di aprire una shell di questo computer dallo strumento a riga di comando"),
+            ("Terminal", "Terminale"),
+            ("Allow Headless", "Consenti modalità headless"),
+            ("headless_tip", "Avvia un server X virtuale quando non c'è alcuna sessione, ad esempio su un server senza schermo"),
+            ("Request Elevation", "Richiedi elevazione"),
+            ("Elevation Error", "Errore di elevazione"),
+            ("elevation_tip", "Il dispositivo remoto non è in esecuzione come amministratore, i prompt UAC e le finestre con privilegi elevati non possono essere utilizzati. Lascia vuoto il nome utente perché l'utente remoto accetti il prompt UAC, altrimenti verrà usato questo account amministratore."),
+            ("elevation_waiting_tip", "In attesa che l'utente remoto accetti il prompt UAC"),
+            ("elevation_logon_tip", "Accesso con l'account amministratore"),
+            ("elevation_restarting_tip", "Il dispositivo remoto si sta riavviando come amministratore, la sessione si riconnetterà automaticamente"),
+            ("Restart Remote Device", "Riavvia dispositivo remoto"),
+            ("Restart in Safe Mode", "Riavvia in modalità provvisoria"),
+            ("Restarting Remote Device", "Riavvio del dispositivo remoto"),
+            ("remote_restarting_tip", "Il dispositivo remoto si sta riavviando, la sessione si riconnetterà automaticamente quando tornerà online"),
+            ("restart_remote_device_tip", "Sei sicuro di voler riavviare il dispositivo remoto? La sessione si riconnetterà automaticamente."),
+            ("restart_safe_mode_tip", "Sei sicuro di voler riavviare il dispositivo remoto in modalità provvisoria con rete? L'avvio successivo sarà normale."),
+            ("wake_up_tip", "Riattiva questo dispositivo dalla rete, tramite un dispositivo online della sua rete locale"),
+            ("Wake", "Riattiva"),
+            ("switch_sides_tip", "Far controllare questo computer dal computer remoto, che si riconnette senza password"),
+            ("Switch Sides", "Inverti i ruoli"),
+            ("single_controller_tip", "Solo una sessione remota alla volta controlla, le altre guardano soltanto finché il controllo non viene ceduto nel gestore connessioni"),
+            ("One Controller at a Time", "Un solo controllore alla volta"),
+            ("Forward gamepads", "Inoltra i gamepad"),
+            ("forward_gamepad_tip", "Usa i gamepad locali come gamepad virtuali del peer, che su Windows richiede il driver ViGEmBus"),
+            ("Legacy keyboard mode", "Modalità tastiera classica"),
+            ("Character keyboard mode", "Modalità tastiera a caratteri"),
+            ("Positional keyboard mode", "Modalità tastiera posizionale"),
+            ("keyboard_character_tip", "Digita i caratteri del layout locale, per scrivere quando i layout sono diversi"),
+            ("keyboard_positional_tip", "Invia la posizione dei tasti, digitati nel layout del peer, per giochi e scorciatoie"),
+            ("Media keys", "Tasti multimediali"),
+            ("Play/Pause", "Riproduci/Pausa"),
+            ("Next track", "Traccia successiva"),
+            ("Previous track", "Traccia precedente"),
+            ("Stop", "Ferma"),
+            ("Volume up", "Alza il volume"),
+            ("Volume down", "Abbassa il volume"),
+            ("Block user input", "Blocca l'input dell'utente"),
+            ("Unblock user input", "Sblocca l'input dell'utente"),
+            ("block_input_failed_tip", "Impossibile bloccare l'input locale del peer"),
+            ("privacy_mode_tip", "Oscura i monitor del peer e blocca il suo input locale durante la sessione"),
+            ("privacy_mode_failed_tip", "Impossibile attivare la modalità privacy, non è supportata dal peer o è usata da un'altra sessione"),
+            ("lock_on_disconnect_tip", "Blocca lo schermo o disconnetti l'utente quando l'ultima sessione remota termina o cade"),
+            ("On Disconnect", "Alla disconnessione"),
+            ("Do nothing", "Non fare nulla"),
+            ("Lock", "Blocca"),
+            ("Log out", "Disconnetti utente"),
+            ("Session Time Limits", "Limiti di durata della sessione"),
+            ("session_limits_tip", "Disconnetti automaticamente dopo un periodo senza input o alla durata massima, il peer viene avvisato un minuto prima, vuoto per nessun limite"),
+            ("Idle timeout (minutes)", "Timeout di inattività (minuti)"),
+            ("Maximum duration (minutes)", "Durata massima (minuti)"),
+            ("Session Timeout", "Scadenza della sessione"),
+            ("idle_timeout_tip", "Senza input, la sessione verrà chiusa tra {} secondi"),
+            ("session_limit_tip", "La sessione ha raggiunto la durata massima e verrà chiusa tra {} secondi"),
+            ("Closed after a period of no input", "Chiusa dopo un periodo senza input"),
+            ("Closed at the session time limit", "Chiusa al limite di durata della sessione"),
+            ("Permission Profiles", "Profili dei permessi"),
+            ("Global permissions", "Permessi globali"),
+            ("Full control", "Controllo completo"),
+            ("View only", "Solo visualizzazione"),
+            ("File transfer only", "Solo trasferimento file"),
+            ("Other peers", "Altri peer"),
+            ("Invalid profile", "Profilo non valido"),
+            ("Only file transfer is allowed", "È consentito solo il trasferimento file"),
+            ("permission_profiles_tip", "I permessi in base all'ID del peer, un ID e un profilo per riga: full (controllo completo), view (solo visualizzazione) o file (solo trasferimento file)"),
+            ("Password Rotation", "Rotazione della password"),
+            ("password_rotation_tip", "Cambia automaticamente la password casuale, così una password condivisa non può essere riutilizzata più tardi, senza effetto sulla tua password"),
+            ("After each accepted connection", "Dopo ogni connessione accettata"),
+            ("After failed attempts", "Dopo tentativi falliti"),
+            ("Every (minutes)", "Ogni (minuti)"),
+            ("Invalid number", "Numero non valido"),
+            ("Fingerprint", "Impronta"),
+            ("Pinned Keys", "Chiavi fissate"),
+            ("pinned_keys_tip", "La chiave fissata di ogni ID remoto alla prima connessione cifrata, una per riga, da copiare per esportarle o incollare per importarle"),
+            ("Invalid key", "Chiave non valida"),
+            ("Security Warning", "Avviso di sicurezza"),
+            ("key_changed_tip", "La chiave del desktop remoto non è quella fissata alla prima connessione, o non è stato possibile verificarla. Potrebbe essere stato reinstallato, oppure è un attacco man in the middle, ad esempio da un server compromesso. Verifica l'impronta con il tuo interlocutore in un altro modo, poi fai clic su OK per fidarti della chiave attuale e connetterti."),
+            ("Pinned key", "Chiave fissata"),
+            ("Current key", "Chiave attuale"),
+            ("Unverified", "Non verificata"),
+            ("Please enter the code of your authenticator app", "Inserisci il codice della tua app di autenticazione"),
             ("Enable Keyboard/Mouse", "Abilita tastiera/mouse"),
             ("Enable Clipboard", "Abilita appunti"),
             ("Enable File Transfer", "Abilita trasferimento file"),
             ("Enable TCP Tunneling", "Abilita tunnel TCP"),
-            ("IP Whitelisting", "IP autorizzati"),
+            ("Enable Virtual Display", "Abilita schermo virtuale"),
+            ("virtual_display_tip", "Collega uno schermo virtuale per il controllo remoto quando non c'è alcuno schermo"),
+            ("Access Control", "Controllo accessi"),
             ("ID/Relay Server", "Server ID/Relay"),
             ("Stop service", "Arresta servizio"),
             ("Change ID", "Cambia ID"),
@@ -37,7 +216,9 @@ lazy_static::lazy_static! {
             ("Relay Server", "Server relay"),
             ("API Server", "Server API"),
             ("invalid_http", "deve iniziare con http:// o https://"),
-            ("Invalid IP", "Indirizzo IP non valido"),
+            ("Invalid rule", "Regola non valida"),
+            ("Allow", "Consenti"),
+            ("Deny", "Nega"),
             ("id_change_tip", "Puoi usare solo i caratteri a-z, A-Z, 0-9 e _ (underscore). Il primo carattere deve essere a-z o A-Z. La lunghezza deve essere fra 6 e 16 caratteri."),
             ("Invalid format", "Formato non valido"),
             ("This function is turned off by the server", "Questa funzione è disabilitata sul server"),
@@ -59,6 +240,11 @@ lazy_static::lazy_static! {
             ("Connecting...", "Connessione..."),
             ("Connection in progress. Please wait.", "Connessione in corso. Attendi."),
             ("Please try 1 minute later", "Per favore riprova fra 1 minuto"),
+            ("Please try again later", "Per favore riprova più tardi"),
+            ("Ban list", "Elenco dei ban"),
+            ("Unban", "Rimuovi ban"),
+            ("No bans", "Nessun ban"),
+            ("minutes", "minuti"),
             ("Login Error", "Errore di login"),
             ("Successful", "Successo"),
             ("Connected, waiting for image...", "Connesso, in attesa dell'immagine..."),
@@ -76,6 +262,9 @@ lazy_static::lazy_static! {
             ("Deleting", "Cancellazione di"),
             ("files", "file"),
             ("Waiting", "In attesa"),
+            ("Paused", "In pausa"),
+            ("Running", "In corso"),
+            ("Queued", "In coda"),
             ("Finished", "Terminato"),
             ("Custom Image Quality", "Qualità immagine personalizzata"),
             ("Privacy mode", "Modalità privacy"),
@@ -88,8 +277,44 @@ lazy_static::lazy_static! {
             ("Optimize reaction time", "Ottimizza il tempo di reazione"),
             ("Custom", "Personalizzato"),
             ("Show remote cursor", "Mostra il cursore remoto"),
+            ("Show all displays", "Mostra tutti gli schermi"),
+            ("Show quality monitor", "Mostra monitor qualità"),
+            ("Latency", "Latenza"),
+            ("Codec", "Codec"),
+            ("Bitrate", "Bitrate"),
+            ("Packet loss", "Perdita di pacchetti"),
+            ("Connection", "Connessione"),
+            ("Direct", "Diretta"),
+            ("Relay", "Relay"),
+            ("Match window resolution", "Adatta la risoluzione alla finestra"),
+            ("Select window", "Seleziona finestra"),
+            ("Select region", "Seleziona area"),
+            ("Whole display", "Schermo intero"),
+            ("Record session", "Registra sessione"),
+            ("Stop recording", "Interrompi registrazione"),
+            ("Recording", "Registrazione"),
+            ("Receiving clipboard", "Ricezione appunti"),
+            ("Talk with the microphone", "Parla con il microfono"),
+            ("Turn off microphone", "Disattiva microfono"),
+            ("Microphone", "Microfono"),
+            ("Allow talking through the speakers", "Consenti di parlare tramite gli altoparlanti"),
+            ("Voice call", "Chiamata vocale"),
+            ("Hang up", "Riaggancia"),
+            ("Cancel the voice call", "Annulla la chiamata vocale"),
+            ("Incoming voice call", "Chiamata vocale in arrivo"),
+            ("The voice call was declined", "La chiamata vocale è stata rifiutata"),
+            ("Low latency audio", "Audio a bassa latenza"),
+            ("Balanced audio", "Audio bilanciato"),
+            ("High quality audio", "Audio di alta qualità"),
+            ("The window is followed when it moves", "La finestra viene seguita quando si sposta"),
+            ("No window found", "Nessuna finestra trovata"),
             ("Disable clipboard", "Disabilita appunti"),
+            ("Clipboard both ways", "Appunti in entrambe le direzioni"),
+            ("Clipboard from remote only", "Appunti solo dal remoto"),
+            ("Clipboard to remote only", "Appunti solo verso il remoto"),
             ("Lock after session end", "Blocca al termine della sessione"),
+            ("Paste after drop", "Incolla dopo il rilascio"),
+            ("Drop files", "Rilascia file"),
             ("Insert", "Inserisci"),
             ("Insert Lock", "Blocco inserimento"),
             ("Refresh", "Aggiorna"),
@@ -156,6 +381,14 @@ lazy_static::lazy_static! {
             ("Change Path", "Cambia percorso"),
             ("Create Folder", "Crea cartella"),
             ("Please enter the folder name", "Inserisci il nome della cartella"),
+            ("Search", "Cerca"),
+            ("Searching", "Ricerca in corso"),
+            ("Please enter the file name or pattern", "Inserisci il nome o lo schema del file"),
+            ("Synchronize Folders", "Sincronizza cartelle"),
+            ("From the remote computer", "Dal computer remoto"),
+            ("Compare the content of the files", "Confronta il contenuto dei file"),
+            ("The folders are the same", "Le cartelle sono uguali"),
+            ("Files to copy", "File da copiare"),
             ("Fix it", "Risolvi"),
             ("Warning", "Avviso"),
             ("Login screen using Wayland is not supported", "La schermata di login non è supportata utilizzando Wayland"),
@@ -171,7 +404,7 @@ lazy_static::lazy_static! {
             ("Run without install", "Avvia senza installare"),
             ("Always connected via relay", "Connesso sempre tramite relay"),
             ("Always connect via relay", "Connetti sempre tramite relay"),
-            ("whitelist_tip", "Solo gli indirizzi IP autorizzati possono connettersi a questo desktop"),
+            ("acl_tip", "Solo gli IP, gli intervalli CIDR o i modelli di ID (con * e ?) autorizzati e non rifiutati possono connettersi a questo desktop"),
             ("Login", "Accedi"),
             ("Logout", "Esci"),
             ("Tags", "Tag"),
@@ -10,6 +10,8 @@ mod client;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod rendezvous_mediator;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod lan;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub use self::rendezvous_mediator::*;
 pub mod common;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -27,4 +29,18 @@ use common::*;
 pub mod cli;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod port_forward;
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+mod ab;
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+mod chat;
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+mod printer;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod plugins;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod audit;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod provision;
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+mod tunnels;
 mod lang;
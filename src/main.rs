@@ -29,33 +29,14 @@ fn main() {
         return;
     }
     #[cfg(not(feature = "inline"))]
-    {
-        use hbb_common::env_logger::*;
-        init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
-    }
+    hbb_common::logging::init("", false, "info");
     #[cfg(feature = "inline")]
     {
-        let mut path = hbb_common::config::Config::log_path();
+        let mut name = "".to_owned();
         if args.len() > 0 && args[0].starts_with("--") {
-            let name = args[0].replace("--", "");
-            if !name.is_empty() {
-                path.push(name);
-            }
+            name = args[0].replace("--", "");
         }
-        use flexi_logger::*;
-        Logger::try_with_env_or_str("debug")
-            .map(|x| {
-                x.log_to_file(FileSpec::default().directory(path))
-                    .format(opt_format)
-                    .rotate(
-                        Criterion::Age(Age::Day),
-                        Naming::Timestamps,
-                        Cleanup::KeepLogFiles(6),
-                    )
-                    .start()
-                    .ok();
-            })
-            .ok();
+        hbb_common::logging::init(&name, true, "debug");
     }
     if args.is_empty() {
         std::thread::spawn(move || start_server(false, false));
@@ -74,6 +55,13 @@ fn main() {
                 hbb_common::allow_err!(platform::uninstall_me());
                 hbb_common::allow_err!(platform::install_me("desktopicon startmenu",));
                 return;
+            } else if args[0] == "--silent-install" {
+                // with the provision.toml next to the installer if any
+                if let Err(err) = platform::install_me("desktopicon startmenu") {
+                    log::error!("Failed to install: {}", err);
+                    std::process::exit(1);
+                }
+                return;
             }
         }
         if args[0] == "--remove" {
@@ -89,16 +77,73 @@ fn main() {
             return;
         } else if args[0] == "--server" {
             log::info!("start --server");
+            #[cfg(target_os = "macos")]
+            platform::macos::check_permissions();
             start_server(true, true);
             return;
         } else if args[0] == "--import-config" {
             if args.len() == 2 {
-                hbb_common::config::Config::import(&args[1]);
+                if let Err(err) = provision::import_config(&args[1]) {
+                    log::error!("Failed to import the config: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        } else if args[0] == "--export-config" {
+            let res = match args.get(1) {
+                Some(path) => provision::export_config(path),
+                _ => Err(hbb_common::anyhow::anyhow!("Usage: --export-config <file>")),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to export the config: {}", err);
+                std::process::exit(1);
             }
             return;
         } else if args[0] == "--password" {
             if args.len() == 2 {
-                ipc::set_password(args[1].to_owned()).unwrap();
+                ipc::set_permanent_password(args[1].to_owned()).unwrap();
+            }
+            return;
+        } else if args[0] == "--get-id" {
+            println!("{}", ipc::get_id());
+            return;
+        } else if args[0] == "--get-temporary-password" {
+            // empty with a permanent password
+            println!("{}", ipc::get_password());
+            return;
+        } else if args[0] == "--status" {
+            match ipc::get_status() {
+                Ok(status) => println!("{}", status),
+                Err(err) => {
+                    log::error!("Failed to get the status of the service: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        } else if args[0] == "--disconnect" {
+            // the id of the connection in the status
+            let res = match args.get(1).map(|x| x.parse::<i32>()) {
+                Some(Ok(id)) => ipc::disconnect(id),
+                _ => Err(hbb_common::anyhow::anyhow!("Usage: --disconnect <id>")),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to disconnect: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        } else if args[0] == "--set-session-permission" {
+            // keyboard, clipboard, audio or microphone of a connection, Y or N
+            let res = match (args.len(), args.get(1).map(|x| x.parse::<i32>())) {
+                (4, Some(Ok(id))) => {
+                    ipc::switch_session_permission(id, args[2].clone(), args[3] == "Y")
+                }
+                _ => Err(hbb_common::anyhow::anyhow!(
+                    "Usage: --set-session-permission <id> <permission> <Y|N>"
+                )),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to set the permission: {}", err);
+                std::process::exit(1);
             }
             return;
         }
@@ -108,9 +153,13 @@ fn main() {
 
 #[cfg(feature = "cli")]
 fn main() {
-    use clap::App;
+    use clap::{App, SubCommand};
     let args = format!(
         "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+       -D, --dynamic-forward=[DYNAMIC-FORWARD-OPTIONS] 'Format: remote-id:local-port, a SOCKS5 proxy through the remote computer'
+       -U, --udp-forward=[UDP-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+       -R, --reverse-forward=[REVERSE-FORWARD-OPTIONS] 'Format: remote-id:remote-port:local-port[:local-host]'
+       -t, --terminal=[ID] 'Open a shell of the remote computer'
        -s, --server... 'Start server'",
     );
     let matches = App::new("rustdesk")
@@ -118,10 +167,32 @@ fn main() {
         .author("CarrieZ Studio<info@rustdesk.com>")
         .about("RustDesk command line tool")
         .args_from_usage(&args)
+        .subcommand(
+            SubCommand::with_name("cli")
+                .about("Connect without any prompt, for the scripts")
+                .args_from_usage(
+                    "-c, --connect=<ID> 'The remote computer'
+                    --password-file=[FILE] 'The password in FILE, - for stdin, the remembered one if none'
+                    --send=[PATH] 'Send the file or folder to --to on the remote computer'
+                    --receive=[PATH] 'Receive the file or folder of the remote computer to --to'
+                    --to=[PATH] 'The destination of --send or --receive, in the current folder for --receive by default'
+                    --include-hidden 'Send or receive the hidden files too'
+                    -p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: local-port:remote-port[:remote-host]'
+                    --json 'Print the events as JSON objects, one a line'",
+                ),
+        )
         .get_matches();
-    use hbb_common::env_logger::*;
-    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
-    if let Some(p) = matches.value_of("port-forward") {
+    hbb_common::logging::init("", false, "info");
+    if let Some(matches) = matches.subcommand_matches("cli") {
+        let code = match get_headless_options(matches) {
+            Ok(options) => cli::run_headless(options),
+            Err(err) => {
+                log::error!("{}", err);
+                1
+            }
+        };
+        std::process::exit(code);
+    } else if let Some(p) = matches.value_of("port-forward") {
         let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
         if options.len() < 3 {
             log::error!("Wrong port-forward options");
@@ -146,5 +217,107 @@ fn main() {
             remote_host = options[3].clone();
         }
         cli::start_one_port_forward(options[0].clone(), port, remote_host, remote_port);
+    } else if let Some(p) = matches.value_of("dynamic-forward") {
+        let options: Vec<&str> = p.split(":").collect();
+        let port = options
+            .get(1)
+            .and_then(|x| x.parse::<i32>().ok())
+            .unwrap_or(0);
+        if options.len() != 2 || port <= 0 {
+            log::error!("Wrong dynamic-forward options");
+            return;
+        }
+        cli::start_one_port_forward(options[0].to_owned(), port, "".to_owned(), 0);
+    } else if let Some(p) = matches.value_of("udp-forward") {
+        let options: Vec<&str> = p.split(":").collect();
+        let get_port = |i: usize| {
+            options
+                .get(i)
+                .and_then(|x| x.parse::<i32>().ok())
+                .unwrap_or(0)
+        };
+        let (port, remote_port) = (get_port(1), get_port(2));
+        if options.len() < 3 || port <= 0 || remote_port <= 0 {
+            log::error!("Wrong udp-forward options");
+            return;
+        }
+        let remote_host = options.get(3).unwrap_or(&"localhost").to_string();
+        cli::start_udp_forward(options[0].to_owned(), port, remote_host, remote_port);
+    } else if let Some(p) = matches.value_of("reverse-forward") {
+        let options: Vec<&str> = p.split(":").collect();
+        let get_port = |i: usize| {
+            options
+                .get(i)
+                .and_then(|x| x.parse::<i32>().ok())
+                .unwrap_or(0)
+        };
+        let (remote_port, local_port) = (get_port(1), get_port(2));
+        if options.len() < 3 || remote_port <= 0 || local_port <= 0 {
+            log::error!("Wrong reverse-forward options");
+            return;
+        }
+        let local_host = options.get(3).unwrap_or(&"localhost").to_string();
+        cli::start_reverse_forward(options[0].to_owned(), remote_port, local_host, local_port);
+    } else if let Some(id) = matches.value_of("terminal") {
+        cli::start_terminal(id.to_owned());
+    }
+}
+
+#[cfg(feature = "cli")]
+fn get_headless_options(matches: &clap::ArgMatches) -> Result<cli::HeadlessOptions, String> {
+    let mut options = cli::HeadlessOptions {
+        id: matches.value_of("connect").unwrap_or_default().to_owned(),
+        send: matches.value_of("send").map(|x| x.to_owned()),
+        receive: matches.value_of("receive").map(|x| x.to_owned()),
+        to: matches.value_of("to").unwrap_or_default().to_owned(),
+        include_hidden: matches.is_present("include-hidden"),
+        json: matches.is_present("json"),
+        ..Default::default()
+    };
+    match matches.value_of("password-file") {
+        Some("-") => {
+            let mut password = String::new();
+            std::io::stdin()
+                .read_line(&mut password)
+                .map_err(|err| format!("Failed to read the password: {}", err))?;
+            options.password = password.trim_end_matches(&['\r', '\n'][..]).to_owned();
+        }
+        Some(file) => {
+            let password = std::fs::read_to_string(file)
+                .map_err(|err| format!("Failed to read {}: {}", file, err))?;
+            options.password = password.trim_end_matches(&['\r', '\n'][..]).to_owned();
+        }
+        None => {}
+    }
+    if let Some(p) = matches.value_of("port-forward") {
+        let fds: Vec<&str> = p.split(":").collect();
+        let get_port = |i: usize| fds.get(i).and_then(|x| x.parse::<i32>().ok()).unwrap_or(0);
+        let (port, remote_port) = (get_port(0), get_port(1));
+        if fds.len() < 2 || port <= 0 || remote_port <= 0 {
+            return Err("Wrong port-forward options".to_owned());
+        }
+        let remote_host = fds.get(2).unwrap_or(&"localhost").to_string();
+        options.port_forward = Some((port, remote_host, remote_port));
+    }
+    let actions = [
+        options.send.is_some(),
+        options.receive.is_some(),
+        options.port_forward.is_some(),
+    ];
+    if actions.iter().filter(|x| **x).count() > 1 {
+        return Err("Only one of --send, --receive and --port-forward at a time".to_owned());
+    }
+    if options.send.is_some() && options.to.is_empty() {
+        return Err("No --to of the files sent".to_owned());
+    }
+    if let (Some(path), true) = (options.receive.as_ref(), options.to.is_empty()) {
+        let name = path
+            .trim_end_matches(&['/', '\\'][..])
+            .rsplit(&['/', '\\'][..])
+            .next()
+            .unwrap_or_default();
+        let dir = std::env::current_dir().map_err(|err| err.to_string())?;
+        options.to = dir.join(name).to_string_lossy().to_string();
     }
+    Ok(options)
 }
@@ -1,19 +1,37 @@
-use super::{CursorData, ResultType};
-use hbb_common::{allow_err, bail, log};
-use libc::{c_char, c_int, c_void};
+use super::{CursorData, OverlayShape, ResultType, WindowInfo};
+use hbb_common::{
+    allow_err, bail,
+    config::{Config, APP_NAME},
+    log,
+};
+use libc::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
 use std::io::prelude::*;
 use std::{
     cell::RefCell,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        mpsc, Arc, Mutex,
     },
+    time::Duration,
 };
 type Xdo = *const c_void;
 
 pub const PA_SAMPLE_RATE: u32 = 48000;
 static mut UNMODIFIED: bool = true;
 
+static PRIVACY_MODE: AtomicBool = AtomicBool::new(false);
+static TRACKING_ID: AtomicI32 = AtomicI32::new(0);
+
+lazy_static::lazy_static! {
+    // the input devices disabled to block the local input, None if it is not
+    static ref BLOCKED_DEVICES: Mutex<Option<Vec<String>>> = Default::default();
+    // the uinput devices and the size of the screen they are made for
+    static ref TOUCH_DEVICE: Mutex<Option<(std::fs::File, (i32, i32))>> = Default::default();
+    static ref PEN_DEVICE: Mutex<Option<(std::fs::File, (i32, i32))>> = Default::default();
+    // to the thread of the window of the annotations, closed once dropped
+    static ref OVERLAY: Mutex<Option<mpsc::Sender<Vec<OverlayShape>>>> = Default::default();
+}
+
 thread_local! {
     static XDO: RefCell<Xdo> = RefCell::new(unsafe { xdo_new(std::ptr::null()) });
     static DISPLAY: RefCell<*mut c_void> = RefCell::new(unsafe { XOpenDisplay(std::ptr::null())});
@@ -33,8 +51,190 @@ extern "C" {
 extern "C" {
     fn XOpenDisplay(display_name: *const c_char) -> *mut c_void;
     // fn XCloseDisplay(d: *mut c_void) -> c_int;
+    fn XDefaultRootWindow(dpy: *mut c_void) -> c_ulong;
+    fn XInternAtom(dpy: *mut c_void, name: *const c_char, only_if_exists: c_int) -> c_ulong;
+    fn XGetWindowProperty(
+        dpy: *mut c_void,
+        w: c_ulong,
+        property: c_ulong,
+        long_offset: c_long,
+        long_length: c_long,
+        delete: c_int,
+        req_type: c_ulong,
+        actual_type: *mut c_ulong,
+        actual_format: *mut c_int,
+        nitems: *mut c_ulong,
+        bytes_after: *mut c_ulong,
+        prop: *mut *mut u8,
+    ) -> c_int;
+    fn XGetGeometry(
+        dpy: *mut c_void,
+        d: c_ulong,
+        root: *mut c_ulong,
+        x: *mut c_int,
+        y: *mut c_int,
+        width: *mut c_uint,
+        height: *mut c_uint,
+        border_width: *mut c_uint,
+        depth: *mut c_uint,
+    ) -> c_int;
+    fn XTranslateCoordinates(
+        dpy: *mut c_void,
+        src_w: c_ulong,
+        dest_w: c_ulong,
+        src_x: c_int,
+        src_y: c_int,
+        dest_x: *mut c_int,
+        dest_y: *mut c_int,
+        child: *mut c_ulong,
+    ) -> c_int;
+    fn XCloseDisplay(dpy: *mut c_void) -> c_int;
+    fn XCreateSimpleWindow(
+        dpy: *mut c_void,
+        parent: c_ulong,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+        border_width: c_uint,
+        border: c_ulong,
+        background: c_ulong,
+    ) -> c_ulong;
+    fn XChangeWindowAttributes(
+        dpy: *mut c_void,
+        w: c_ulong,
+        valuemask: c_ulong,
+        attributes: *mut XSetWindowAttributes,
+    ) -> c_int;
+    fn XSelectInput(dpy: *mut c_void, w: c_ulong, event_mask: c_long) -> c_int;
+    fn XMapRaised(dpy: *mut c_void, w: c_ulong) -> c_int;
+    fn XRaiseWindow(dpy: *mut c_void, w: c_ulong) -> c_int;
+    fn XDestroyWindow(dpy: *mut c_void, w: c_ulong) -> c_int;
+    fn XCreatePixmap(
+        dpy: *mut c_void,
+        d: c_ulong,
+        width: c_uint,
+        height: c_uint,
+        depth: c_uint,
+    ) -> c_ulong;
+    fn XFreePixmap(dpy: *mut c_void, pixmap: c_ulong) -> c_int;
+    fn XCreateGC(
+        dpy: *mut c_void,
+        d: c_ulong,
+        valuemask: c_ulong,
+        values: *mut c_void,
+    ) -> *mut c_void;
+    fn XFreeGC(dpy: *mut c_void, gc: *mut c_void) -> c_int;
+    fn XSetForeground(dpy: *mut c_void, gc: *mut c_void, foreground: c_ulong) -> c_int;
+    fn XSetLineAttributes(
+        dpy: *mut c_void,
+        gc: *mut c_void,
+        line_width: c_uint,
+        line_style: c_int,
+        cap_style: c_int,
+        join_style: c_int,
+    ) -> c_int;
+    fn XFillRectangle(
+        dpy: *mut c_void,
+        d: c_ulong,
+        gc: *mut c_void,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+    ) -> c_int;
+    fn XDrawLine(
+        dpy: *mut c_void,
+        d: c_ulong,
+        gc: *mut c_void,
+        x1: c_int,
+        y1: c_int,
+        x2: c_int,
+        y2: c_int,
+    ) -> c_int;
+    fn XDrawArc(
+        dpy: *mut c_void,
+        d: c_ulong,
+        gc: *mut c_void,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+        angle1: c_int,
+        angle2: c_int,
+    ) -> c_int;
+    fn XFillArc(
+        dpy: *mut c_void,
+        d: c_ulong,
+        gc: *mut c_void,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+        angle1: c_int,
+        angle2: c_int,
+    ) -> c_int;
+    fn XPending(dpy: *mut c_void) -> c_int;
+    fn XNextEvent(dpy: *mut c_void, event: *mut c_long) -> c_int;
+    fn XFlush(dpy: *mut c_void) -> c_int;
+}
+
+#[link(name = "Xext")]
+extern "C" {
+    fn XShapeCombineMask(
+        dpy: *mut c_void,
+        dest: c_ulong,
+        dest_kind: c_int,
+        x_off: c_int,
+        y_off: c_int,
+        src: c_ulong,
+        op: c_int,
+    );
+    fn XShapeCombineRectangles(
+        dpy: *mut c_void,
+        dest: c_ulong,
+        dest_kind: c_int,
+        x_off: c_int,
+        y_off: c_int,
+        rectangles: *mut c_void,
+        n_rects: c_int,
+        op: c_int,
+        ordering: c_int,
+    );
+}
+
+// /usr/include/X11/Xlib.h
+#[repr(C)]
+struct XSetWindowAttributes {
+    background_pixmap: c_ulong,
+    background_pixel: c_ulong,
+    border_pixmap: c_ulong,
+    border_pixel: c_ulong,
+    bit_gravity: c_int,
+    win_gravity: c_int,
+    backing_store: c_int,
+    backing_planes: c_ulong,
+    backing_pixel: c_ulong,
+    save_under: c_int,
+    event_mask: c_long,
+    do_not_propagate_mask: c_long,
+    override_redirect: c_int,
+    colormap: c_ulong,
+    cursor: c_ulong,
 }
 
+const CW_OVERRIDE_REDIRECT: c_ulong = 1 << 9;
+const EXPOSURE_MASK: c_long = 1 << 15;
+const LINE_SOLID: c_int = 0;
+const CAP_ROUND: c_int = 2;
+const JOIN_ROUND: c_int = 1;
+// /usr/include/X11/extensions/shape.h
+const SHAPE_SET: c_int = 0;
+const SHAPE_BOUNDING: c_int = 0;
+const SHAPE_INPUT: c_int = 2;
+// the size of the XEvent union
+const X_EVENT_LONGS: usize = 24;
+
 #[link(name = "Xfixes")]
 extern "C" {
     // fn XFixesQueryExtension(dpy: *mut c_void, event: *mut c_int, error: *mut c_int) -> c_int;
@@ -93,6 +293,174 @@ pub fn get_cursor() -> ResultType<Option<u64>> {
     Ok(res)
 }
 
+// the items of a property of a window, e.g. 32 bits ones as c_ulong
+unsafe fn get_window_property<T: Clone>(d: *mut c_void, w: c_ulong, name: &str) -> Vec<T> {
+    let name = std::ffi::CString::new(name).unwrap();
+    let atom = XInternAtom(d, name.as_ptr(), 1);
+    if atom == 0 {
+        return Vec::new();
+    }
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut nitems = 0;
+    let mut bytes_after = 0;
+    let mut prop = std::ptr::null_mut();
+    // AnyPropertyType
+    XGetWindowProperty(
+        d,
+        w,
+        atom,
+        0,
+        c_long::MAX / 4,
+        0,
+        0,
+        &mut actual_type,
+        &mut actual_format,
+        &mut nitems,
+        &mut bytes_after,
+        &mut prop,
+    );
+    if prop.is_null() {
+        return Vec::new();
+    }
+    let items = std::slice::from_raw_parts(prop as *const T, nitems as _).to_vec();
+    XFree(prop as _);
+    items
+}
+
+/// The top level windows the window manager lists, the topmost first.
+pub fn get_windows() -> Vec<WindowInfo> {
+    let mut windows = Vec::new();
+    DISPLAY.with(|conn| {
+        if let Ok(d) = conn.try_borrow_mut() {
+            if d.is_null() {
+                return;
+            }
+            unsafe {
+                let root = XDefaultRootWindow(*d);
+                let clients: Vec<c_ulong> =
+                    get_window_property(*d, root, "_NET_CLIENT_LIST_STACKING");
+                for w in clients.into_iter().rev() {
+                    let mut title: Vec<u8> = get_window_property(*d, w, "_NET_WM_NAME");
+                    if title.is_empty() {
+                        title = get_window_property(*d, w, "WM_NAME");
+                    }
+                    if title.is_empty() {
+                        continue;
+                    }
+                    let (mut x, mut y, mut width, mut height) = (0, 0, 0, 0);
+                    let (mut r, mut border, mut depth, mut child) = (0, 0, 0, 0);
+                    if XGetGeometry(
+                        *d,
+                        w,
+                        &mut r,
+                        &mut x,
+                        &mut y,
+                        &mut width,
+                        &mut height,
+                        &mut border,
+                        &mut depth,
+                    ) == 0
+                        || XTranslateCoordinates(*d, w, root, 0, 0, &mut x, &mut y, &mut child) == 0
+                    {
+                        continue;
+                    }
+                    windows.push(WindowInfo {
+                        id: w as _,
+                        title: String::from_utf8_lossy(&title).into(),
+                        x,
+                        y,
+                        width: width as _,
+                        height: height as _,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    });
+    windows
+}
+
+// (output, modes, current mode) of the output `name` in the output of xrandr,
+// or of the primary one for x11 displays, which have no name
+fn xrandr_output(name: &str) -> ResultType<(String, Vec<(usize, usize)>, Option<(usize, usize)>)> {
+    let output = std::process::Command::new("xrandr").output()?;
+    if !output.status.success() {
+        bail!("xrandr failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut outputs = Vec::new();
+    for line in output.lines() {
+        if !line.starts_with(' ') {
+            let mut words = line.split_whitespace();
+            let output = words.next().unwrap_or_default().to_owned();
+            let connected = words.next() == Some("connected");
+            let primary = words.next() == Some("primary");
+            outputs.push((output, connected, primary, Vec::new(), None));
+        } else if let Some(o) = outputs.last_mut() {
+            let mut words = line.split_whitespace();
+            let mode = words.next().unwrap_or_default();
+            let mut wh = mode.trim_end_matches('i').splitn(2, 'x');
+            if let (Some(Ok(w)), Some(Ok(h))) = (
+                wh.next().map(|w| w.parse::<usize>()),
+                wh.next().map(|h| h.parse::<usize>()),
+            ) {
+                if !o.3.contains(&(w, h)) {
+                    o.3.push((w, h));
+                }
+                if words.any(|rate| rate.contains('*')) {
+                    o.4 = Some((w, h));
+                }
+            }
+        }
+    }
+    let found = outputs
+        .iter()
+        .position(|o| o.1 && o.0 == name)
+        .or_else(|| outputs.iter().position(|o| o.1 && o.2))
+        .or_else(|| outputs.iter().position(|o| o.1));
+    match found {
+        Some(i) => {
+            let (output, _, _, modes, current) = outputs.remove(i);
+            Ok((output, modes, current))
+        }
+        None => bail!("No output found by xrandr"),
+    }
+}
+
+pub fn resolutions(name: &str) -> Vec<(usize, usize)> {
+    match xrandr_output(name) {
+        Ok((_, modes, _)) => modes,
+        Err(err) => {
+            log::error!("Failed to get display modes: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+pub fn current_resolution(name: &str) -> ResultType<(usize, usize)> {
+    match xrandr_output(name)? {
+        (_, _, Some(current)) => Ok(current),
+        (output, _, None) => bail!("{} is off", output),
+    }
+}
+
+pub fn change_resolution(name: &str, width: usize, height: usize) -> ResultType<()> {
+    let (output, _, _) = xrandr_output(name)?;
+    let res = std::process::Command::new("xrandr")
+        .args(&[
+            "--output",
+            &output,
+            "--mode",
+            &format!("{}x{}", width, height),
+        ])
+        .output()?;
+    if !res.status.success() {
+        bail!("xrandr failed: {}", String::from_utf8_lossy(&res.stderr));
+    }
+    Ok(())
+}
+
 pub fn get_cursor_data(hcursor: u64) -> ResultType<CursorData> {
     let mut res = None;
     DISPLAY.with(|conn| {
@@ -157,6 +525,8 @@ pub fn start_os_service() {
 
     let mut cm0 = false;
     let mut last_restart = std::time::Instant::now();
+    let mut headless: Option<Headless> = None;
+    let mut headless_failed = false;
     while running.load(Ordering::SeqCst) {
         let cm = get_cm();
         let tmp = get_active_userid();
@@ -164,6 +534,8 @@ pub fn start_os_service() {
         if tmp != uid && !tmp.is_empty() {
             uid = tmp;
             log::info!("uid of seat0: {}", uid);
+            headless = None;
+            headless_failed = false;
             let gdm = format!("/run/user/{}/gdm/Xauthority", uid);
             let mut auth = get_env_tries("XAUTHORITY", &uid, 10);
             if auth.is_empty() {
@@ -191,6 +563,12 @@ pub fn start_os_service() {
                 d = ":0".to_owned();
             }
             d = d.replace(&whoami::hostname(), "").replace("localhost", "");
+            if !std::path::Path::new(&auth).exists() {
+                let tmp = get_xorg_auth(&d);
+                if !tmp.is_empty() {
+                    auth = tmp;
+                }
+            }
             log::info!("DISPLAY: {}", d);
             log::info!("XAUTHORITY: {}", auth);
             std::env::set_var("XAUTHORITY", auth);
@@ -200,6 +578,30 @@ pub fn start_os_service() {
                 std::thread::sleep(std::time::Duration::from_millis(30));
                 last_restart = std::time::Instant::now();
             }
+        } else if tmp.is_empty()
+            && headless.is_none()
+            && !headless_failed
+            && Config::get_option("allow-headless") == "Y"
+            && !has_x_server()
+        {
+            match Headless::start() {
+                Ok((h, d, auth)) => {
+                    log::info!("headless DISPLAY: {}, XAUTHORITY: {}", d, auth);
+                    headless = Some(h);
+                    uid = "".to_owned();
+                    std::env::set_var("XAUTHORITY", auth);
+                    std::env::set_var("DISPLAY", d);
+                    if let Some(ps) = server.as_mut() {
+                        allow_err!(ps.kill());
+                        std::thread::sleep(std::time::Duration::from_millis(30));
+                        last_restart = std::time::Instant::now();
+                    }
+                }
+                Err(err) => {
+                    log::error!("Failed to start the headless X server: {}", err);
+                    headless_failed = true;
+                }
+            }
         } else if !cm
             && ((cm0 && last_restart.elapsed().as_secs() > 60)
                 || last_restart.elapsed().as_secs() > 3600)
@@ -243,6 +645,148 @@ pub fn start_os_service() {
     println!("Exit");
 }
 
+const XORG_DUMMY_CONF: &str = r#"Section "Device"
+    Identifier "dummy"
+    Driver "dummy"
+    VideoRam 256000
+EndSection
+Section "Monitor"
+    Identifier "monitor"
+    HorizSync 5.0-1000.0
+    VertRefresh 5.0-200.0
+    Modeline "1920x1080" 148.50 1920 2008 2052 2200 1080 1084 1089 1125 +hsync +vsync
+EndSection
+Section "Screen"
+    Identifier "screen"
+    Device "dummy"
+    Monitor "monitor"
+    DefaultDepth 24
+    SubSection "Display"
+        Depth 24
+        Modes "1920x1080"
+    EndSubSection
+EndSection
+"#;
+
+// The virtual X server of a machine without any session, e.g. a headless server,
+// with "allow-headless", Xvfb, or Xorg with the dummy driver. The command of the
+// "headless-session" option is started in it, e.g. "runuser -u alice -- startxfce4".
+struct Headless {
+    children: Vec<std::process::Child>,
+}
+
+impl Headless {
+    fn start() -> ResultType<(Self, String, String)> {
+        let n = (99..200)
+            .find(|n| {
+                !std::path::Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists()
+                    && !std::path::Path::new(&format!("/tmp/.X{}-lock", n)).exists()
+            })
+            .unwrap_or(99);
+        let display = format!(":{}", n);
+        let auth = format!("/tmp/.{}-headless-xauth", APP_NAME);
+        let cookie: String = hbb_common::sodiumoxide::randombytes::randombytes(16)
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect();
+        let res = std::process::Command::new("xauth")
+            .args(vec!["-f", &auth, "add", &display, ".", &cookie])
+            .output()?;
+        if !res.status.success() {
+            bail!("xauth: {}", String::from_utf8_lossy(&res.stderr));
+        }
+        let mut headless = Self {
+            children: vec![Self::start_x(&display, &auth)?],
+        };
+        let socket = format!("/tmp/.X11-unix/X{}", n);
+        for _ in 0..50 {
+            if std::path::Path::new(&socket).exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let session = Config::get_option("headless-session");
+        if !session.is_empty() {
+            match std::process::Command::new("sh")
+                .args(vec!["-c", &session])
+                .env("DISPLAY", &display)
+                .env("XAUTHORITY", &auth)
+                .spawn()
+            {
+                Ok(child) => headless.children.push(child),
+                Err(err) => log::error!("Failed to start {}: {}", session, err),
+            }
+        }
+        Ok((headless, display, auth))
+    }
+
+    fn start_x(display: &str, auth: &str) -> ResultType<std::process::Child> {
+        let args = vec![display, "-auth", auth, "-nolisten", "tcp"];
+        match std::process::Command::new("Xvfb")
+            .args(&args)
+            .args(vec!["-screen", "0", "1920x1080x24"])
+            .spawn()
+        {
+            Ok(child) => return Ok(child),
+            Err(err) => log::info!("Failed to start Xvfb, try Xorg: {}", err),
+        }
+        let conf = std::env::temp_dir().join(format!("{}-xorg-dummy.conf", APP_NAME));
+        std::fs::write(&conf, XORG_DUMMY_CONF)?;
+        Ok(std::process::Command::new("Xorg")
+            .args(&args)
+            .args(vec!["-config", &conf.to_string_lossy(), "-noreset"])
+            .spawn()?)
+    }
+}
+
+impl Drop for Headless {
+    fn drop(&mut self) {
+        for child in self.children.iter_mut().rev() {
+            allow_err!(child.kill());
+            allow_err!(child.wait());
+        }
+    }
+}
+
+fn has_x_server() -> bool {
+    std::fs::read_dir("/tmp/.X11-unix")
+        .map(|mut x| x.next().is_some())
+        .unwrap_or(false)
+}
+
+// The -auth file of the X server of `display`, of any if not found, as GDM starts
+// it with -displayfd. It is readable by root, e.g. of the greeter of the display
+// manager (GDM, SDDM, LightDM) before anybody logs in.
+fn get_xorg_auth(display: &str) -> String {
+    let mut any = "".to_owned();
+    if let Ok(output) = std::process::Command::new("ps")
+        .args(vec!["-eo", "args"])
+        .output()
+    {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let is_x = match args.first() {
+                Some(x) => x.ends_with("Xorg") || x.ends_with("/X") || *x == "X",
+                None => false,
+            };
+            if !is_x {
+                continue;
+            }
+            let auth = match args.iter().position(|x| *x == "-auth") {
+                Some(i) => args.get(i + 1).map(|x| x.to_string()).unwrap_or_default(),
+                None => continue,
+            };
+            if args.contains(&display) {
+                return auth;
+            }
+            if any.is_empty() {
+                any = auth;
+            }
+        }
+    }
+    any
+}
+
 fn get_active_userid() -> String {
     get_value_of_seat0(1)
 }
@@ -434,6 +978,10 @@ pub fn fix_login_wayland() {
 }
 
 pub fn current_is_wayland() -> bool {
+    // captured with pipewire, no need to switch to x11
+    if cfg!(feature = "wayland") {
+        return false;
+    }
     let dtype = get_display_server();
     return "wayland" == dtype && unsafe { UNMODIFIED };
 }
@@ -530,7 +1078,7 @@ pub fn is_root() -> bool {
     crate::username() == "root"
 }
 
-pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
+pub fn run_as_user(args: Vec<&str>) -> ResultType<Option<std::process::Child>> {
     let uid = get_active_userid();
     let cmd = std::env::current_exe()?;
     let task = std::process::Command::new("sudo")
@@ -539,8 +1087,8 @@ pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
             "-u",
             &get_active_username(),
             cmd.to_str().unwrap_or(""),
-            arg,
         ])
+        .args(args)
         .spawn()?;
     Ok(Some(task))
 }
@@ -594,18 +1142,512 @@ pub fn lock_screen() {
             ..Default::default()
         };
         evt.set_chr('l' as _);
-        handle_key(&evt);
+        handle_key(&evt, 0);
         evt.down = false;
-        handle_key(&evt);
+        handle_key(&evt, 0);
+    });
+}
+
+pub fn logout() {
+    let sid = get_value_of_seat0(0);
+    if !sid.is_empty() {
+        allow_err!(std::process::Command::new("loginctl")
+            .args(vec!["terminate-session", &sid])
+            .output());
+    }
+}
+
+pub fn restart(safe_mode: bool) -> ResultType<()> {
+    if safe_mode {
+        bail!("No safe mode on Linux");
+    }
+    std::process::Command::new("systemctl")
+        .arg("reboot")
+        .spawn()?;
+    Ok(())
+}
+
+// The monitors are turned off by DPMS, again and again as the remote input wakes
+// them up, the screen is still captured.
+pub fn toggle_privacy_mode(v: bool) -> bool {
+    if !v {
+        if PRIVACY_MODE.swap(false, Ordering::SeqCst) {
+            run_dpms("on");
+        }
+        return false;
+    }
+    if PRIVACY_MODE.load(Ordering::SeqCst) {
+        return true;
+    }
+    if !run_dpms("off") {
+        return false;
+    }
+    PRIVACY_MODE.store(true, Ordering::SeqCst);
+    std::thread::spawn(|| loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        if !PRIVACY_MODE.load(Ordering::SeqCst) {
+            break;
+        }
+        run_dpms("off");
     });
+    true
+}
+
+/// Shows the annotations over the screen, the window closed with none.
+pub fn show_overlay(shapes: &[OverlayShape]) -> bool {
+    let mut overlay = OVERLAY.lock().unwrap();
+    if shapes.is_empty() {
+        *overlay = None;
+        return false;
+    }
+    if let Some(tx) = overlay.as_ref() {
+        if tx.send(shapes.to_vec()).is_ok() {
+            return true;
+        }
+    }
+    let (tx, rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    std::thread::spawn(move || unsafe { run_overlay(rx, ready_tx) });
+    // no window without X, e.g. on Wayland
+    if !ready_rx.recv().unwrap_or(false) {
+        return false;
+    }
+    tx.send(shapes.to_vec()).ok();
+    *overlay = Some(tx);
+    true
+}
+
+// The annotations in an override-redirect window over the screen, cut to the
+// strokes by its shape and with no input shape, passing the input through.
+// Painted again once exposed, the pixels being the colors 0xRRGGBB of the
+// TrueColor visuals.
+unsafe fn run_overlay(rx: mpsc::Receiver<Vec<OverlayShape>>, ready: mpsc::Sender<bool>) {
+    let dpy = XOpenDisplay(std::ptr::null());
+    if dpy.is_null() {
+        ready.send(false).ok();
+        return;
+    }
+    let root = XDefaultRootWindow(dpy);
+    let (mut x, mut y, mut w, mut h, mut border, mut depth) = (0, 0, 0, 0, 0, 0);
+    let mut parent = 0;
+    XGetGeometry(
+        dpy,
+        root,
+        &mut parent,
+        &mut x,
+        &mut y,
+        &mut w,
+        &mut h,
+        &mut border,
+        &mut depth,
+    );
+    let win = XCreateSimpleWindow(dpy, root, 0, 0, w, h, 0, 0, 0);
+    let mut attributes: XSetWindowAttributes = std::mem::zeroed();
+    attributes.override_redirect = 1;
+    XChangeWindowAttributes(dpy, win, CW_OVERRIDE_REDIRECT, &mut attributes);
+    let null = std::ptr::null_mut();
+    XShapeCombineRectangles(dpy, win, SHAPE_INPUT, 0, 0, null, 0, SHAPE_SET, 0);
+    XSelectInput(dpy, win, EXPOSURE_MASK);
+    let mask = XCreatePixmap(dpy, win, w, h, 1);
+    let mask_gc = XCreateGC(dpy, mask, 0, null);
+    let gc = XCreateGC(dpy, win, 0, null);
+    for gc in [mask_gc, gc].iter() {
+        XSetLineAttributes(dpy, *gc, 4, LINE_SOLID, CAP_ROUND, JOIN_ROUND);
+    }
+    ready.send(true).ok();
+    let mut shapes = Vec::new();
+    let mut mapped = false;
+    let mut event = [0 as c_long; X_EVENT_LONGS];
+    loop {
+        let mut changed = false;
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(x) => {
+                shapes = x;
+                changed = true;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(_) => break,
+        }
+        // the latest ones only
+        while let Ok(x) = rx.try_recv() {
+            shapes = x;
+        }
+        let mut exposed = false;
+        while XPending(dpy) > 0 {
+            XNextEvent(dpy, event.as_mut_ptr());
+            exposed = true;
+        }
+        if changed {
+            XSetForeground(dpy, mask_gc, 0);
+            XFillRectangle(dpy, mask, mask_gc, 0, 0, w, h);
+            XSetForeground(dpy, mask_gc, 1);
+            for s in shapes.iter() {
+                draw_overlay_shape(dpy, mask, mask_gc, s);
+            }
+            XShapeCombineMask(dpy, win, SHAPE_BOUNDING, 0, 0, mask, SHAPE_SET);
+            if mapped {
+                // above the windows raised meanwhile
+                XRaiseWindow(dpy, win);
+            } else {
+                XMapRaised(dpy, win);
+                mapped = true;
+            }
+        }
+        if changed || exposed {
+            for s in shapes.iter() {
+                XSetForeground(dpy, gc, s.color as _);
+                draw_overlay_shape(dpy, win, gc, s);
+            }
+            XFlush(dpy);
+        }
+    }
+    XFreeGC(dpy, gc);
+    XFreeGC(dpy, mask_gc);
+    XFreePixmap(dpy, mask);
+    XDestroyWindow(dpy, win);
+    XCloseDisplay(dpy);
 }
 
-pub fn toggle_privacy_mode(_v: bool) {
-    // https://unix.stackexchange.com/questions/17170/disable-keyboard-mouse-input-on-unix-under-x
+unsafe fn draw_overlay_shape(dpy: *mut c_void, d: c_ulong, gc: *mut c_void, s: &OverlayShape) {
+    const FULL: c_int = 360 * 64;
+    match s.kind {
+        super::OVERLAY_LASER => {
+            XFillArc(dpy, d, gc, s.x1 - 8, s.y1 - 8, 16, 16, 0, FULL);
+        }
+        super::OVERLAY_ARROW => {
+            XDrawLine(dpy, d, gc, s.x0, s.y0, s.x1, s.y1);
+            // the head, two strokes at 30 degrees of the shaft
+            let a = ((s.y0 - s.y1) as f64).atan2((s.x0 - s.x1) as f64);
+            for delta in [-0.5236f64, 0.5236].iter() {
+                let x = s.x1 + (20. * (a + delta).cos()) as c_int;
+                let y = s.y1 + (20. * (a + delta).sin()) as c_int;
+                XDrawLine(dpy, d, gc, s.x1, s.y1, x, y);
+            }
+        }
+        super::OVERLAY_CIRCLE => {
+            let w = (s.x1 - s.x0).abs() as c_uint;
+            let h = (s.y1 - s.y0).abs() as c_uint;
+            XDrawArc(dpy, d, gc, s.x0.min(s.x1), s.y0.min(s.y1), w, h, 0, FULL);
+        }
+        _ => {}
+    }
 }
 
-pub fn block_input(_v: bool) {
-    //
+// The physical input devices are disabled, keeping the XTest ones of the remote
+// input.
+// https://unix.stackexchange.com/questions/17170/disable-keyboard-mouse-input-on-unix-under-x
+pub fn block_input(v: bool) -> bool {
+    let mut devices = BLOCKED_DEVICES.lock().unwrap();
+    if !v {
+        for id in devices.take().unwrap_or_default() {
+            run_xinput("enable", &id);
+        }
+        return false;
+    }
+    if devices.is_some() {
+        return true;
+    }
+    let ids = get_physical_input_devices();
+    if ids.is_empty() {
+        return false;
+    }
+    for id in ids.iter() {
+        run_xinput("disable", id);
+    }
+    *devices = Some(ids);
+    true
+}
+
+fn run_dpms(mode: &str) -> bool {
+    std::process::Command::new("xset")
+        .args(vec!["dpms", "force", mode])
+        .status()
+        .map(|x| x.success())
+        .unwrap_or(false)
+}
+
+fn run_xinput(action: &str, id: &str) {
+    allow_err!(std::process::Command::new("xinput")
+        .args(vec![action, id])
+        .status());
+}
+
+fn get_physical_input_devices() -> Vec<String> {
+    let mut ids = Vec::new();
+    if let Ok(output) = std::process::Command::new("xinput")
+        .args(vec!["list", "--short"])
+        .output()
+    {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if !line.contains("slave") || line.contains("XTEST") || line.contains("RustDesk") {
+                continue;
+            }
+            if let Some(id) = line.split("id=").nth(1) {
+                if let Some(id) = id.split_whitespace().next() {
+                    ids.push(id.to_owned());
+                }
+            }
+        }
+    }
+    ids
+}
+
+// Touch and pen injected by uinput devices, a touch screen and a tablet mapped
+// onto the whole X screen, created on the first use, which needs /dev/uinput to
+// be writable.
+// linux/input-event-codes.h and linux/uinput.h
+const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOOL_RUBBER: u16 = 0x141;
+const BTN_TOUCH: u16 = 0x14a;
+const BTN_STYLUS: u16 = 0x14b;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_PRESSURE: u16 = 0x18;
+const ABS_TILT_X: u16 = 0x1a;
+const ABS_TILT_Y: u16 = 0x1b;
+const ABS_MT_SLOT: u16 = 0x2f;
+const ABS_MT_POSITION_X: u16 = 0x35;
+const ABS_MT_POSITION_Y: u16 = 0x36;
+const ABS_MT_TRACKING_ID: u16 = 0x39;
+const ABS_MT_PRESSURE: u16 = 0x3a;
+const INPUT_PROP_DIRECT: c_int = 0x01;
+pub const BUS_VIRTUAL: u16 = 0x06;
+pub const BUS_USB: u16 = 0x03;
+const UI_SET_EVBIT: c_ulong = 0x40045564;
+const UI_SET_KEYBIT: c_ulong = 0x40045565;
+const UI_SET_ABSBIT: c_ulong = 0x40045567;
+const UI_SET_PROPBIT: c_ulong = 0x4004556e;
+const UI_DEV_CREATE: c_ulong = 0x5501;
+
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; 80],
+    // bus type, vendor, product and version
+    id: [u16; 4],
+    ff_effects_max: u32,
+    absmax: [i32; 64],
+    absmin: [i32; 64],
+    absfuzz: [i32; 64],
+    absflat: [i32; 64],
+}
+
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+pub fn inject_touch(contacts: &[super::TouchContact]) -> bool {
+    use super::TouchState;
+    let create = |(w, h)| {
+        create_uinput_device(
+            "RustDesk Touch",
+            [BUS_VIRTUAL, 0, 0, 1],
+            true,
+            &[BTN_TOUCH],
+            &[
+                (ABS_X, 0, w - 1),
+                (ABS_Y, 0, h - 1),
+                (ABS_MT_SLOT, 0, 9),
+                (ABS_MT_TRACKING_ID, 0, 0xffff),
+                (ABS_MT_POSITION_X, 0, w - 1),
+                (ABS_MT_POSITION_Y, 0, h - 1),
+                (ABS_MT_PRESSURE, 0, 1024),
+            ],
+        )
+    };
+    with_uinput_device(&TOUCH_DEVICE, create, |file| {
+        let mut events = Vec::new();
+        for c in contacts.iter() {
+            events.push((EV_ABS, ABS_MT_SLOT, c.id as i32));
+            match c.state {
+                TouchState::Up => {
+                    events.push((EV_ABS, ABS_MT_TRACKING_ID, -1));
+                    continue;
+                }
+                TouchState::Down => {
+                    let id = TRACKING_ID.fetch_add(1, Ordering::SeqCst) & 0xffff;
+                    events.push((EV_ABS, ABS_MT_TRACKING_ID, id));
+                }
+                TouchState::Move => {}
+            }
+            events.push((EV_ABS, ABS_MT_POSITION_X, c.x));
+            events.push((EV_ABS, ABS_MT_POSITION_Y, c.y));
+            events.push((EV_ABS, ABS_MT_PRESSURE, c.pressure as i32));
+        }
+        // the single touch of the first contact down
+        let first = contacts.iter().find(|c| c.state != TouchState::Up);
+        events.push((EV_KEY, BTN_TOUCH, first.is_some() as i32));
+        if let Some(c) = first {
+            events.push((EV_ABS, ABS_X, c.x));
+            events.push((EV_ABS, ABS_Y, c.y));
+        }
+        write_input_events(file, &events)
+    })
+}
+
+pub fn inject_pen(evt: &hbb_common::message_proto::PenEvent) -> bool {
+    let create = |(w, h)| {
+        create_uinput_device(
+            "RustDesk Pen",
+            [BUS_VIRTUAL, 0, 0, 1],
+            true,
+            &[BTN_TOOL_PEN, BTN_TOOL_RUBBER, BTN_TOUCH, BTN_STYLUS],
+            &[
+                (ABS_X, 0, w - 1),
+                (ABS_Y, 0, h - 1),
+                (ABS_PRESSURE, 0, 1024),
+                (ABS_TILT_X, -90, 90),
+                (ABS_TILT_Y, -90, 90),
+            ],
+        )
+    };
+    with_uinput_device(&PEN_DEVICE, create, |file| {
+        let pressure = if evt.down { evt.pressure as i32 } else { 0 };
+        write_input_events(
+            file,
+            &[
+                (EV_KEY, BTN_TOOL_PEN, (evt.in_range && !evt.eraser) as i32),
+                (EV_KEY, BTN_TOOL_RUBBER, (evt.in_range && evt.eraser) as i32),
+                (EV_KEY, BTN_TOUCH, evt.down as i32),
+                (EV_KEY, BTN_STYLUS, evt.barrel as i32),
+                (EV_ABS, ABS_X, evt.x),
+                (EV_ABS, ABS_Y, evt.y),
+                (EV_ABS, ABS_PRESSURE, pressure),
+                (EV_ABS, ABS_TILT_X, evt.tilt_x),
+                (EV_ABS, ABS_TILT_Y, evt.tilt_y),
+            ],
+        )
+    })
+}
+
+// the device is made again when the size of the screen changes
+fn with_uinput_device(
+    device: &Mutex<Option<(std::fs::File, (i32, i32))>>,
+    create: impl FnOnce((i32, i32)) -> ResultType<std::fs::File>,
+    f: impl FnOnce(&mut std::fs::File) -> bool,
+) -> bool {
+    let size = match get_screen_size() {
+        Some(size) => size,
+        None => return false,
+    };
+    let mut device = device.lock().unwrap();
+    if device.as_ref().map(|x| x.1) != Some(size) {
+        // closed, the old one is destroyed
+        *device = None;
+        match create(size) {
+            Ok(file) => *device = Some((file, size)),
+            Err(err) => {
+                log::debug!("Failed to create the uinput device: {}", err);
+                return false;
+            }
+        }
+    }
+    match device.as_mut() {
+        Some((file, _)) => f(file),
+        None => false,
+    }
+}
+
+/// A uinput device, `id` its bus type, vendor, product and version, `direct` for
+/// a screen or a tablet mapped onto it, removed once the file is closed.
+pub fn create_uinput_device(
+    name: &str,
+    id: [u16; 4],
+    direct: bool,
+    keys: &[u16],
+    axes: &[(u16, i32, i32)],
+) -> ResultType<std::fs::File> {
+    use std::os::unix::io::AsRawFd;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/uinput")?;
+    let fd = file.as_raw_fd();
+    let mut dev: UinputUserDev = unsafe { std::mem::zeroed() };
+    dev.name[..name.len()].copy_from_slice(name.as_bytes());
+    dev.id = id;
+    unsafe {
+        libc::ioctl(fd, UI_SET_EVBIT as _, EV_KEY as c_int);
+        libc::ioctl(fd, UI_SET_EVBIT as _, EV_ABS as c_int);
+        if direct {
+            libc::ioctl(fd, UI_SET_PROPBIT as _, INPUT_PROP_DIRECT);
+        }
+        for key in keys.iter() {
+            libc::ioctl(fd, UI_SET_KEYBIT as _, *key as c_int);
+        }
+        for (axis, min, max) in axes.iter() {
+            libc::ioctl(fd, UI_SET_ABSBIT as _, *axis as c_int);
+            dev.absmin[*axis as usize] = *min;
+            dev.absmax[*axis as usize] = *max;
+        }
+        file.write_all(std::slice::from_raw_parts(
+            &dev as *const _ as *const u8,
+            std::mem::size_of::<UinputUserDev>(),
+        ))?;
+        if libc::ioctl(fd, UI_DEV_CREATE as _) < 0 {
+            bail!("Failed to create the uinput device {}", name);
+        }
+    }
+    Ok(file)
+}
+
+/// Write the events, (type, code, value), to a uinput device at once.
+pub fn write_input_events(file: &mut std::fs::File, events: &[(u16, u16, i32)]) -> bool {
+    let mut data = Vec::new();
+    for (type_, code, value) in events.iter().chain(&[(EV_SYN, SYN_REPORT, 0)]) {
+        let evt = InputEvent {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: *type_,
+            code: *code,
+            value: *value,
+        };
+        data.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &evt as *const _ as *const u8,
+                std::mem::size_of::<InputEvent>(),
+            )
+        });
+    }
+    file.write_all(&data).is_ok()
+}
+
+fn get_screen_size() -> Option<(i32, i32)> {
+    let mut res = None;
+    DISPLAY.with(|conn| {
+        if let Ok(d) = conn.try_borrow_mut() {
+            if d.is_null() {
+                return;
+            }
+            let (mut x, mut y, mut width, mut height) = (0, 0, 0, 0);
+            let (mut root, mut border, mut depth) = (0, 0, 0);
+            unsafe {
+                if XGetGeometry(
+                    *d,
+                    XDefaultRootWindow(*d),
+                    &mut root,
+                    &mut x,
+                    &mut y,
+                    &mut width,
+                    &mut height,
+                    &mut border,
+                    &mut depth,
+                ) != 0
+                {
+                    res = Some((width as i32, height as i32));
+                }
+            }
+        }
+    });
+    res
 }
 
 pub fn is_installed() -> bool {
@@ -2,25 +2,40 @@
 // https://github.com/servo/core-foundation-rs
 // https://github.com/rust-windowing/winit
 
-use super::{CursorData, ResultType};
+use super::{CursorData, ResultType, WindowInfo};
 use cocoa::{
     base::{id, nil, BOOL, NO, YES},
     foundation::{NSDictionary, NSPoint, NSSize, NSString},
 };
 use core_foundation::{
-    array::{CFArrayGetCount, CFArrayGetValueAtIndex},
+    array::{CFArrayGetCount, CFArrayGetValueAtIndex, CFArrayRef},
+    base::CFRelease,
     dictionary::CFDictionaryRef,
     string::CFStringRef,
 };
 use core_graphics::{
-    display::{kCGNullWindowID, kCGWindowListOptionOnScreenOnly, CGWindowListCopyWindowInfo},
-    window::{kCGWindowName, kCGWindowOwnerPID},
+    display::{
+        kCGNullWindowID, kCGWindowListExcludeDesktopElements, kCGWindowListOptionOnScreenOnly,
+        CGWindowListCopyWindowInfo,
+    },
+    window::{
+        kCGWindowBounds, kCGWindowLayer, kCGWindowName, kCGWindowNumber, kCGWindowOwnerName,
+        kCGWindowOwnerPID,
+    },
+};
+use hbb_common::{
+    allow_err, bail,
+    config::{Config, APP_NAME, ORG},
+    log,
 };
-use hbb_common::{allow_err, bail, log};
 use objc::{class, msg_send, sel, sel_impl};
 use scrap::{libc::c_void, quartz::ffi::*};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static mut LATEST_SEED: i32 = 0;
+static INPUT_TAP_STARTED: AtomicBool = AtomicBool::new(false);
+static INPUT_BLOCKED: AtomicBool = AtomicBool::new(false);
+static POINTER_DOWN: AtomicBool = AtomicBool::new(false);
 
 extern "C" {
     fn CGSCurrentCursorSeed() -> i32;
@@ -28,6 +43,13 @@ extern "C" {
     fn CGEventGetLocation(e: *const c_void) -> CGPoint;
     static kAXTrustedCheckOptionPrompt: CFStringRef;
     fn AXIsProcessTrustedWithOptions(options: CFDictionaryRef) -> BOOL;
+    fn CGDisplayCopyAllDisplayModes(display: u32, options: CFDictionaryRef) -> CFArrayRef;
+    fn CGDisplayCopyDisplayMode(display: u32) -> *const c_void;
+    fn CGDisplayModeGetWidth(mode: *const c_void) -> usize;
+    fn CGDisplayModeGetHeight(mode: *const c_void) -> usize;
+    fn CGDisplayModeIsUsableForDesktopGUI(mode: *const c_void) -> bool;
+    fn CGDisplayModeRelease(mode: *const c_void);
+    fn CGDisplaySetDisplayMode(display: u32, mode: *const c_void, options: CFDictionaryRef) -> i32;
 }
 
 pub fn is_process_trusted(prompt: bool) -> bool {
@@ -98,6 +120,158 @@ pub fn is_can_screen_recording(prompt: bool) -> bool {
     can_record_screen
 }
 
+/// The windows of the applications on screen, the topmost first. Their
+/// offsets from the origin of their display are in its pixels, like the
+/// images captured of it, the bounds of the windows are in points.
+pub fn get_windows() -> Vec<WindowInfo> {
+    let mut windows = Vec::new();
+    let displays = scrap::quartz::Display::online().unwrap_or_default();
+    unsafe {
+        let window_list = CGWindowListCopyWindowInfo(
+            kCGWindowListOptionOnScreenOnly | kCGWindowListExcludeDesktopElements,
+            kCGNullWindowID,
+        );
+        let n = CFArrayGetCount(window_list);
+        let number = |w: id, key: CFStringRef| -> f64 {
+            let v: id = msg_send![w, valueForKey: key as id];
+            if v.is_null() {
+                return 0.;
+            }
+            msg_send![v, doubleValue]
+        };
+        let string = |w: id, key: CFStringRef| -> String {
+            let v: id = msg_send![w, valueForKey: key as id];
+            if v.is_null() {
+                return "".to_owned();
+            }
+            let v: *const std::os::raw::c_char = msg_send![v, UTF8String];
+            std::ffi::CStr::from_ptr(v).to_string_lossy().into()
+        };
+        for i in 0..n {
+            let w: id = CFArrayGetValueAtIndex(window_list, i) as _;
+            // the menu bar, the dock and the like are above 0
+            if number(w, kCGWindowLayer) != 0. {
+                continue;
+            }
+            let mut title = string(w, kCGWindowName);
+            let owner = string(w, kCGWindowOwnerName);
+            if title.is_empty() {
+                title = owner;
+            } else if !owner.is_empty() {
+                title = format!("{} - {}", owner, title);
+            }
+            let bounds: id = msg_send![w, valueForKey: kCGWindowBounds as id];
+            if title.is_empty() || bounds.is_null() {
+                continue;
+            }
+            let value = |key: &str| -> f64 {
+                let key = NSString::alloc(nil).init_str(key);
+                let v: id = msg_send![bounds, valueForKey: key];
+                if v.is_null() {
+                    return 0.;
+                }
+                msg_send![v, doubleValue]
+            };
+            let (mut x, mut y, mut width, mut height) =
+                (value("X"), value("Y"), value("Width"), value("Height"));
+            for d in displays.iter() {
+                let b = d.bounds();
+                if x >= b.origin.x
+                    && x < b.origin.x + b.size.width
+                    && y >= b.origin.y
+                    && y < b.origin.y + b.size.height
+                {
+                    let scale = d.width() as f64 / b.size.width;
+                    x = b.origin.x + (x - b.origin.x) * scale;
+                    y = b.origin.y + (y - b.origin.y) * scale;
+                    width *= scale;
+                    height *= scale;
+                    break;
+                }
+            }
+            windows.push(WindowInfo {
+                id: number(w, kCGWindowNumber) as _,
+                title,
+                x: x as _,
+                y: y as _,
+                width: width as _,
+                height: height as _,
+                ..Default::default()
+            });
+        }
+        // polled while a window is streamed
+        CFRelease(window_list as _);
+    }
+    windows
+}
+
+// calls `f` with the modes usable for the desktop, until it returns true
+fn for_each_display_mode(display: u32, mut f: impl FnMut(*const c_void, usize, usize) -> bool) {
+    unsafe {
+        let modes = CGDisplayCopyAllDisplayModes(display, std::ptr::null());
+        if modes.is_null() {
+            return;
+        }
+        for i in 0..CFArrayGetCount(modes) {
+            let mode = CFArrayGetValueAtIndex(modes, i);
+            if CGDisplayModeIsUsableForDesktopGUI(mode)
+                && f(
+                    mode,
+                    CGDisplayModeGetWidth(mode),
+                    CGDisplayModeGetHeight(mode),
+                )
+            {
+                break;
+            }
+        }
+        CFRelease(modes as _);
+    }
+}
+
+/// The display modes of the display, named by its id, in points.
+pub fn resolutions(name: &str) -> Vec<(usize, usize)> {
+    let mut modes = Vec::new();
+    if let Ok(display) = name.parse() {
+        for_each_display_mode(display, |_, w, h| {
+            if !modes.contains(&(w, h)) {
+                modes.push((w, h));
+            }
+            false
+        });
+    }
+    modes
+}
+
+pub fn current_resolution(name: &str) -> ResultType<(usize, usize)> {
+    let display = name.parse()?;
+    unsafe {
+        let mode = CGDisplayCopyDisplayMode(display);
+        if mode.is_null() {
+            bail!("No display mode of display {}", name);
+        }
+        let res = (CGDisplayModeGetWidth(mode), CGDisplayModeGetHeight(mode));
+        CGDisplayModeRelease(mode);
+        Ok(res)
+    }
+}
+
+pub fn change_resolution(name: &str, width: usize, height: usize) -> ResultType<()> {
+    let display = name.parse()?;
+    let mut res = None;
+    for_each_display_mode(display, |mode, w, h| {
+        if w == width && h == height {
+            res = Some(unsafe { CGDisplaySetDisplayMode(display, mode, std::ptr::null()) });
+            return true;
+        }
+        false
+    });
+    match res {
+        Some(0) => Ok(()),
+        Some(err) => bail!("CGDisplaySetDisplayMode failed: {}", err),
+        None => bail!("No display mode {}x{} of display {}", width, height, name),
+    }
+}
+
 pub fn get_cursor_pos() -> Option<(i32, i32)> {
     unsafe {
         let e = CGEventCreate(0 as _);
@@ -268,11 +442,12 @@ pub fn is_root() -> bool {
     crate::username() == "root"
 }
 
-pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
+pub fn run_as_user(args: Vec<&str>) -> ResultType<Option<std::process::Child>> {
     let uid = get_active_userid();
     let cmd = std::env::current_exe()?;
     let task = std::process::Command::new("launchctl")
-        .args(vec!["asuser", &uid, cmd.to_str().unwrap_or(""), arg])
+        .args(vec!["asuser", &uid, cmd.to_str().unwrap_or("")])
+        .args(args)
         .spawn()?;
     Ok(Some(task))
 }
@@ -286,50 +461,311 @@ pub fn lock_screen() {
     .ok();
 }
 
+pub fn logout() {
+    // without the confirmation of "System Events"
+    std::process::Command::new("osascript")
+        .args(vec![
+            "-e",
+            "tell application \"loginwindow\" to «event aevtrlgo»",
+        ])
+        .output()
+        .ok();
+}
+
+pub fn restart(safe_mode: bool) -> ResultType<()> {
+    if safe_mode {
+        bail!("No safe mode with networking on macOS");
+    }
+    std::process::Command::new("shutdown")
+        .args(vec!["-r", "now"])
+        .spawn()?;
+    Ok(())
+}
+
+// The LaunchDaemon, running as root across the logins and logouts. The server
+// runs in the LaunchAgent of the GUI session, as the user, or as root at the login
+// window, with the config of the last user imported so that the id and the
+// password stay the same after a logout.
 pub fn start_os_service() {
-    let mut server: Option<std::process::Child> = None;
     let mut uid = "".to_owned();
+    let mut config_time = None;
     loop {
         let tmp = get_active_userid();
-        let mut start_new = false;
         if tmp != uid && !tmp.is_empty() {
             uid = tmp;
             log::info!("active uid: {}", uid);
-            if let Some(ps) = server.as_mut() {
-                allow_err!(ps.kill());
-            }
-        }
-        if let Some(ps) = server.as_mut() {
-            match ps.try_wait() {
-                Ok(Some(_)) => {
-                    server = None;
-                    start_new = true;
-                }
-                _ => {}
+            if uid != "0" {
+                // loaded at the login, but not yet in the session of the install
+                let agent = get_agent_path();
+                allow_err!(std::process::Command::new("launchctl")
+                    .args(vec!["asuser", &uid, "launchctl", "load", "-w", &agent])
+                    .output());
             }
-        } else {
-            start_new = true;
         }
-        if start_new {
-            match crate::run_me(vec!["--server"]) {
-                Ok(ps) => server = Some(ps),
-                Err(err) => {
-                    log::error!("Failed to start server: {}", err);
-                }
+        if !uid.is_empty() && uid != "0" {
+            let path = format!(
+                "/Users/{}/Library/Preferences/{}.{}/{}.toml",
+                get_active_username(),
+                ORG,
+                APP_NAME,
+                APP_NAME
+            );
+            let time = std::fs::metadata(&path).and_then(|x| x.modified()).ok();
+            if time.is_some() && time != config_time {
+                config_time = time;
+                Config::import(&path);
             }
         }
         std::thread::sleep(std::time::Duration::from_millis(super::SERVICE_INTERVAL));
     }
 }
 
-pub fn toggle_privacy_mode(_v: bool) {
+fn get_daemon_path() -> String {
+    format!("/Library/LaunchDaemons/{}.{}_service.plist", ORG, APP_NAME)
+}
+
+fn get_agent_path() -> String {
+    format!("/Library/LaunchAgents/{}.{}_server.plist", ORG, APP_NAME)
+}
+
+fn get_plist(path: &str, arg: &str, agent: bool) -> ResultType<String> {
+    let label = std::path::Path::new(path)
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let exe = std::env::current_exe()?.to_string_lossy().to_string();
+    let session = if agent {
+        "
+  <key>LimitLoadToSessionType</key>
+  <array>
+    <string>Aqua</string>
+    <string>LoginWindow</string>
+  </array>"
+    } else {
+        ""
+    };
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{exe}</string>
+    <string>{arg}</string>
+  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <true/>{session}
+</dict>
+</plist>
+"#,
+        label = label,
+        exe = exe,
+        arg = arg,
+        session = session
+    ))
+}
+
+// The daemon and the agent, with the administrator password asked, and the app
+// opened again to use the server of the agent.
+pub fn install_service() -> ResultType<()> {
+    let (daemon, agent) = (get_daemon_path(), get_agent_path());
+    let tmp = std::env::temp_dir();
+    let daemon_tmp = tmp.join("daemon.plist").to_string_lossy().to_string();
+    let agent_tmp = tmp.join("agent.plist").to_string_lossy().to_string();
+    std::fs::write(&daemon_tmp, get_plist(&daemon, "--service", false)?)?;
+    std::fs::write(&agent_tmp, get_plist(&agent, "--server", true)?)?;
+    let script = format!(
+        "cp -f '{}' '{}' && cp -f '{}' '{}' && chown root:wheel '{}' '{}' && launchctl load -w '{}'",
+        daemon_tmp, daemon, agent_tmp, agent, daemon, agent, daemon
+    );
+    let script = format!(
+        "do shell script \"{}\" with administrator privileges",
+        script.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let res = std::process::Command::new("osascript")
+        .args(vec!["-e", &script])
+        .output()?;
+    if !res.status.success() {
+        bail!("{}", String::from_utf8_lossy(&res.stderr));
+    }
+    std::process::Command::new("launchctl")
+        .args(vec!["load", "-w", &agent])
+        .output()?;
+    let exe = std::env::current_exe()?.to_string_lossy().to_string();
+    std::process::Command::new("sh")
+        .args(vec!["-c", &format!("sleep 2; '{}' &", exe)])
+        .spawn()?;
+    Ok(())
+}
+
+// The server of the agent in the session of a user asks for the permissions
+// missing, e.g. after an update, none is needed at the login window.
+pub fn check_permissions() {
+    if is_prelogin() || is_root() {
+        return;
+    }
+    if !is_can_screen_recording(false) {
+        log::info!("No permission of the screen recording");
+        is_can_screen_recording(true);
+    }
+    if !is_process_trusted(false) {
+        log::info!("No permission of the accessibility");
+        is_process_trusted(true);
+    }
+}
+
+pub fn toggle_privacy_mode(_v: bool) -> bool {
     // https://unix.stackexchange.com/questions/17115/disable-keyboard-mouse-temporarily
+    // the screen can not be captured with the displays asleep
+    false
 }
 
-pub fn block_input(_v: bool) {
-    //
+// The events of the hardware, with no source process, are dropped by an event
+// tap, letting the ones injected by the remote input through. The tap needs the
+// accessibility permission, and is kept once created.
+pub fn block_input(v: bool) -> bool {
+    if v && !INPUT_TAP_STARTED.load(Ordering::SeqCst) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || run_input_tap(tx));
+        if rx.recv() != Ok(true) {
+            log::error!("Failed to create the event tap to block the input");
+            return false;
+        }
+        INPUT_TAP_STARTED.store(true, Ordering::SeqCst);
+    }
+    INPUT_BLOCKED.store(v, Ordering::SeqCst);
+    v
 }
 
-pub fn is_installed() -> bool {
+fn run_input_tap(tx: std::sync::mpsc::Sender<bool>) {
+    use core_foundation::runloop::{kCFRunLoopCommonModes, CFRunLoop};
+    use core_graphics::event::{
+        CGEventTap, CGEventTapLocation, CGEventTapOptions, CGEventTapPlacement, CGEventType,
+        EventField,
+    };
+    let tap = CGEventTap::new(
+        CGEventTapLocation::HID,
+        CGEventTapPlacement::HeadInsertEventTap,
+        CGEventTapOptions::Default,
+        vec![
+            CGEventType::KeyDown,
+            CGEventType::KeyUp,
+            CGEventType::FlagsChanged,
+            CGEventType::MouseMoved,
+            CGEventType::LeftMouseDown,
+            CGEventType::LeftMouseUp,
+            CGEventType::LeftMouseDragged,
+            CGEventType::RightMouseDown,
+            CGEventType::RightMouseUp,
+            CGEventType::RightMouseDragged,
+            CGEventType::OtherMouseDown,
+            CGEventType::OtherMouseUp,
+            CGEventType::OtherMouseDragged,
+            CGEventType::ScrollWheel,
+        ],
+        |_, _, event| {
+            if INPUT_BLOCKED.load(Ordering::SeqCst)
+                && event.get_integer_value_field(EventField::EVENT_SOURCE_UNIX_PROCESS_ID) == 0
+            {
+                return None;
+            }
+            Some(event.clone())
+        },
+    );
+    let tap = match tap {
+        Ok(tap) => tap,
+        Err(_) => {
+            allow_err!(tx.send(false));
+            return;
+        }
+    };
+    let source = match tap.mach_port.create_runloop_source(0) {
+        Ok(source) => source,
+        Err(_) => {
+            allow_err!(tx.send(false));
+            return;
+        }
+    };
+    unsafe {
+        CFRunLoop::get_current().add_source(&source, kCFRunLoopCommonModes);
+    }
+    tap.enable();
+    allow_err!(tx.send(true));
+    CFRunLoop::run_current();
+}
+
+// No API injects the touch, the first contact down drives the mouse, and the pen
+// is the mouse with the pressure and the tilt of a tablet.
+pub fn inject_touch(contacts: &[super::TouchContact]) -> bool {
+    match contacts
+        .iter()
+        .find(|c| c.state != super::TouchState::Up)
+        .or(contacts.first())
+    {
+        Some(c) => post_pointer_event(c.x, c.y, c.state != super::TouchState::Up, None),
+        None => true,
+    }
+}
+
+pub fn inject_pen(evt: &hbb_common::message_proto::PenEvent) -> bool {
+    post_pointer_event(evt.x, evt.y, evt.down, Some(evt))
+}
+
+fn post_pointer_event(
+    x: i32,
+    y: i32,
+    down: bool,
+    pen: Option<&hbb_common::message_proto::PenEvent>,
+) -> bool {
+    use core_graphics::{
+        event::{CGEvent, CGEventTapLocation, CGEventType, CGMouseButton, EventField},
+        event_source::{CGEventSource, CGEventSourceStateID},
+        geometry::CGPoint,
+    };
+    let was_down = POINTER_DOWN.swap(down, Ordering::SeqCst);
+    let event_type = match (was_down, down) {
+        (false, true) => CGEventType::LeftMouseDown,
+        (true, true) => CGEventType::LeftMouseDragged,
+        (true, false) => CGEventType::LeftMouseUp,
+        (false, false) => CGEventType::MouseMoved,
+    };
+    let source = match CGEventSource::new(CGEventSourceStateID::HIDSystemState) {
+        Ok(source) => source,
+        Err(_) => return false,
+    };
+    let point = CGPoint::new(x as _, y as _);
+    let event = match CGEvent::new_mouse_event(source, event_type, point, CGMouseButton::Left) {
+        Ok(event) => event,
+        Err(_) => return false,
+    };
+    if let Some(pen) = pen {
+        let pressure = if down {
+            pen.pressure as f64 / 1024.
+        } else {
+            0.
+        };
+        // kCGEventMouseSubtypeTabletPoint
+        event.set_integer_value_field(EventField::MOUSE_EVENT_SUB_TYPE, 1);
+        event.set_double_value_field(EventField::MOUSE_EVENT_PRESSURE, pressure);
+        event.set_double_value_field(EventField::TABLET_EVENT_POINT_PRESSURE, pressure);
+        event.set_double_value_field(EventField::TABLET_EVENT_TILT_X, pen.tilt_x as f64 / 90.);
+        event.set_double_value_field(EventField::TABLET_EVENT_TILT_Y, pen.tilt_y as f64 / 90.);
+    }
+    event.post(CGEventTapLocation::HID);
     true
 }
+
+pub fn is_installed() -> bool {
+    std::path::Path::new(&get_daemon_path()).exists()
+}
+
+// No window over the displays yet, the annotations are only drawn in the view
+// of the peer.
+pub fn show_overlay(_shapes: &[super::OverlayShape]) -> bool {
+    false
+}
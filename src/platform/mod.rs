@@ -14,9 +14,47 @@ pub mod macos;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
-use hbb_common::{message_proto::CursorData, ResultType};
+use hbb_common::{
+    message_proto::{CursorData, WindowInfo},
+    ResultType,
+};
 const SERVICE_INTERVAL: u64 = 300;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TouchState {
+    Down,
+    Move,
+    Up,
+}
+
+/// A contact of the touch injected, `id` from 0 to the contacts down at once.
+#[derive(Clone, Debug)]
+pub struct TouchContact {
+    pub id: u32,
+    pub x: i32,
+    pub y: i32,
+    pub pressure: u32,
+    pub state: TouchState,
+}
+
+// the kinds of OverlayShape, as in message_proto::annotation::Shape
+pub const OVERLAY_LASER: u32 = 0;
+pub const OVERLAY_ARROW: u32 = 1;
+pub const OVERLAY_CIRCLE: u32 = 2;
+
+/// A shape of the annotations shown over the displays, in the coordinates of
+/// the virtual screen.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverlayShape {
+    pub kind: u32,
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+    pub color: u32, // 0xRRGGBB
+}
+
 pub fn is_xfce() -> bool {
     #[cfg(target_os = "linux")]
     {
@@ -28,10 +66,33 @@ pub fn is_xfce() -> bool {
     }
 }
 
+/// The largest of the display modes fitting in `width`x`height`, or the smallest
+/// one if none fits.
+pub fn pick_resolution(
+    modes: &[(usize, usize)],
+    width: usize,
+    height: usize,
+) -> Option<(usize, usize)> {
+    modes
+        .iter()
+        .filter(|(w, h)| *w <= width && *h <= height)
+        .max_by_key(|(w, h)| w * h)
+        .or_else(|| modes.iter().min_by_key(|(w, h)| w * h))
+        .cloned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    fn test_pick_resolution() {
+        let modes = [(1920, 1080), (1280, 720), (1600, 900), (800, 600)];
+        assert_eq!(pick_resolution(&modes, 1700, 1000), Some((1600, 900)));
+        assert_eq!(pick_resolution(&modes, 1920, 1080), Some((1920, 1080)));
+        assert_eq!(pick_resolution(&modes, 640, 480), Some((800, 600)));
+        assert_eq!(pick_resolution(&[], 640, 480), None);
+    }
+    #[test]
     fn test_cursor_data() {
         for _ in 0..30 {
             if let Some(hc) = get_cursor().unwrap() {
@@ -1,4 +1,4 @@
-use super::{CursorData, ResultType};
+use super::{CursorData, ResultType, WindowInfo};
 use crate::ipc;
 use hbb_common::{
     allow_err, bail,
@@ -15,8 +15,18 @@ use std::{
 use winapi::{
     shared::{minwindef::*, ntdef::NULL, windef::*},
     um::{
-        errhandlingapi::GetLastError, handleapi::CloseHandle, minwinbase::STILL_ACTIVE,
-        processthreadsapi::GetExitCodeProcess, winbase::*, wingdi::*, winnt::HANDLE, winuser::*,
+        errhandlingapi::GetLastError,
+        handleapi::CloseHandle,
+        minwinbase::STILL_ACTIVE,
+        processthreadsapi::{
+            GetCurrentProcess, GetExitCodeProcess, OpenProcessToken, PROCESS_INFORMATION,
+            STARTUPINFOW,
+        },
+        securitybaseapi::GetTokenInformation,
+        winbase::*,
+        wingdi::*,
+        winnt::{TokenElevation, HANDLE, TOKEN_ELEVATION, TOKEN_QUERY},
+        winuser::*,
     },
 };
 use windows_service::{
@@ -394,6 +404,35 @@ pub fn start_os_service() {
 }
 
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+const WTS_ACTIVE: u32 = 0;
+const WTS_USER_NAME: u32 = 5;
+
+#[repr(C)]
+#[allow(non_snake_case, non_camel_case_types)]
+struct WTS_SESSION_INFOW {
+    SessionId: DWORD,
+    pWinStationName: *mut u16,
+    State: u32,
+}
+
+#[link(name = "wtsapi32")]
+extern "system" {
+    fn WTSEnumerateSessionsW(
+        server: HANDLE,
+        reserved: DWORD,
+        version: DWORD,
+        info: *mut *mut WTS_SESSION_INFOW,
+        count: *mut DWORD,
+    ) -> BOOL;
+    fn WTSQuerySessionInformationW(
+        server: HANDLE,
+        session_id: DWORD,
+        info_class: u32,
+        buffer: *mut *mut u16,
+        bytes: *mut DWORD,
+    ) -> BOOL;
+    fn WTSFreeMemory(memory: *mut std::ffi::c_void);
+}
 
 extern "C" {
     fn LaunchProcessWin(cmd: *const u16, session_id: DWORD, as_user: BOOL) -> HANDLE;
@@ -409,13 +448,41 @@ extern "C" {
     ) -> i32;
     fn drawOutline(out: *mut u8, in_: *const u8, width: i32, height: i32, out_size: i32);
     fn get_di_bits(out: *mut u8, dc: HDC, hbmColor: HBITMAP, width: i32, height: i32) -> i32;
-    fn blank_screen(v: BOOL);
+    fn privacy_mode(v: BOOL) -> BOOL;
+    fn annotation_overlay(shapes: *const super::OverlayShape, n: u32) -> BOOL;
+    fn block_local_input(v: BOOL) -> BOOL;
+    fn inject_touch_input(contacts: *const TouchInput, n: u32) -> BOOL;
+    fn inject_pen_input(
+        x: i32,
+        y: i32,
+        pressure: u32,
+        tilt_x: i32,
+        tilt_y: i32,
+        flags: u32,
+    ) -> BOOL;
+    fn hook_pointer_input(callback: PointerCallback) -> BOOL;
 }
 
-extern "system" {
-    fn BlockInput(v: BOOL) -> BOOL;
+// touch_contact of windows.cc
+#[repr(C)]
+struct TouchInput {
+    id: u32,
+    x: i32,
+    y: i32,
+    pressure: u32,
+    state: u32,
 }
 
+// the flags of the pen
+pub const PEN_DOWN: u32 = 1;
+pub const PEN_IN_RANGE: u32 = 2;
+pub const PEN_BARREL: u32 = 4;
+pub const PEN_ERASER: u32 = 8;
+
+/// (kind, 0 touch or 1 pen, id, x, y, pressure, tilt x, tilt y, flags), true if
+/// the input is taken.
+pub type PointerCallback = extern "C" fn(u32, u32, i32, i32, u32, i32, i32, u32) -> BOOL;
+
 #[tokio::main(flavor = "current_thread")]
 async fn run_service(_arguments: Vec<OsString>) -> ResultType<()> {
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
@@ -451,8 +518,9 @@ async fn run_service(_arguments: Vec<OsString>) -> ResultType<()> {
 
     // Tell the system that the service is running now
     status_handle.set_service_status(next_status)?;
+    clear_safe_boot();
 
-    let mut session_id = unsafe { WTSGetActiveConsoleSessionId() };
+    let mut session_id = get_current_session_id();
     log::info!("session id {}", session_id);
     let mut h_process = launch_server(session_id, true).await.unwrap_or(NULL);
     let mut incoming = ipc::new_listener(crate::POSTFIX_SERVICE).await?;
@@ -480,7 +548,7 @@ async fn run_service(_arguments: Vec<OsString>) -> ResultType<()> {
             Err(_) => {
                 // timeout
                 unsafe {
-                    let tmp = WTSGetActiveConsoleSessionId();
+                    let tmp = get_current_session_id();
                     if tmp == 0xFFFFFFFF {
                         continue;
                     }
@@ -529,6 +597,52 @@ async fn run_service(_arguments: Vec<OsString>) -> ResultType<()> {
     Ok(())
 }
 
+// The session to serve: the console one, unless nobody is logged on to it while a
+// remote desktop session is active, e.g. the user connected with RDP, leaving the
+// console at the login screen. A fast user switch changes the console session.
+fn get_current_session_id() -> DWORD {
+    let console = unsafe { WTSGetActiveConsoleSessionId() };
+    if console == 0xFFFFFFFF || !get_session_username(console).is_empty() {
+        return console;
+    }
+    let mut id = None;
+    unsafe {
+        let mut info = std::ptr::null_mut();
+        let mut count = 0;
+        if WTSEnumerateSessionsW(NULL, 0, 1, &mut info, &mut count) == FALSE {
+            return console;
+        }
+        for s in std::slice::from_raw_parts(info, count as _) {
+            if s.State == WTS_ACTIVE
+                && s.SessionId != console
+                && !get_session_username(s.SessionId).is_empty()
+            {
+                id = Some(s.SessionId);
+                break;
+            }
+        }
+        WTSFreeMemory(info as _);
+    }
+    id.unwrap_or(console)
+}
+
+fn get_session_username(session_id: DWORD) -> String {
+    unsafe {
+        let mut buffer = std::ptr::null_mut();
+        let mut bytes = 0;
+        if WTSQuerySessionInformationW(NULL, session_id, WTS_USER_NAME, &mut buffer, &mut bytes)
+            == FALSE
+            || buffer.is_null()
+        {
+            return "".to_owned();
+        }
+        let len = (bytes as usize / 2).saturating_sub(1);
+        let name = String::from_utf16_lossy(std::slice::from_raw_parts(buffer, len));
+        WTSFreeMemory(buffer as _);
+        name
+    }
+}
+
 async fn launch_server(session_id: DWORD, close_first: bool) -> ResultType<HANDLE> {
     if close_first {
         // in case started some elsewhere
@@ -551,13 +665,13 @@ async fn launch_server(session_id: DWORD, close_first: bool) -> ResultType<HANDL
     Ok(h)
 }
 
-pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
+pub fn run_as_user(args: Vec<&str>) -> ResultType<Option<std::process::Child>> {
     let cmd = format!(
         "\"{}\" {}",
         std::env::current_exe()?.to_str().unwrap_or(""),
-        arg,
+        args.join(" "),
     );
-    let session_id = unsafe { WTSGetActiveConsoleSessionId() };
+    let session_id = get_current_session_id();
     use std::os::windows::ffi::OsStrExt;
     let wstr: Vec<u16> = std::ffi::OsStr::new(&cmd)
         .encode_wide()
@@ -567,8 +681,8 @@ pub fn run_as_user(arg: &str) -> ResultType<Option<std::process::Child>> {
     let h = unsafe { LaunchProcessWin(wstr, session_id, TRUE) };
     if h.is_null() {
         bail!(
-            "Failed to launch {} with session id {}: {}",
-            arg,
+            "Failed to launch {:?} with session id {}: {}",
+            args,
             session_id,
             get_error()
         );
@@ -598,12 +712,27 @@ fn send_sas() {
     extern "system" {
         pub fn SendSAS(AsUser: BOOL);
     }
+    allow_err!(enable_software_sas());
     unsafe {
         log::info!("SAS received");
         SendSAS(FALSE);
     }
 }
 
+// SendSAS is ignored unless the policy lets the services generate the SAS,
+// 1 for the services and 3 for the services and the ease of access apps
+fn enable_software_sas() -> ResultType<()> {
+    use winreg::{enums::*, RegKey};
+    let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .create_subkey("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Policies\\System")?;
+    let value: u32 = key.get_value("SoftwareSASGeneration").unwrap_or(0);
+    if value & 1 == 0 {
+        key.set_value("SoftwareSASGeneration", &(value | 1))?;
+        log::info!("SoftwareSASGeneration set to {}", value | 1);
+    }
+    Ok(())
+}
+
 lazy_static::lazy_static! {
     static ref SUPPRESS: Arc<Mutex<Instant>> = Arc::new(Mutex::new(Instant::now()));
 }
@@ -721,6 +850,97 @@ pub fn is_root() -> bool {
     crate::username() == "SYSTEM"
 }
 
+pub fn is_elevated() -> bool {
+    unsafe {
+        let mut token: HANDLE = NULL;
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == FALSE {
+            return false;
+        }
+        let mut elevation: TOKEN_ELEVATION = mem::zeroed();
+        let mut size = 0;
+        let res = GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut _ as _,
+            mem::size_of::<TOKEN_ELEVATION>() as _,
+            &mut size,
+        );
+        CloseHandle(token);
+        res != FALSE && elevation.TokenIsElevated != 0
+    }
+}
+
+// The portable (not installed) server runs as the user, which can not see the UAC
+// prompts or control the elevated windows, so it is started again elevated once
+// this process exits, after the UAC prompt for the local user, or as the
+// administrator of `logon`, local or domain\user. The latter is elevated only if
+// UAC does not filter the account, e.g. the built-in Administrator.
+pub fn elevate(logon: Option<(String, String)>) -> ResultType<()> {
+    let exe = std::env::current_exe()?.to_str().unwrap_or("").to_owned();
+    // for the ipc and the rendezvous server to be released
+    let start = format!("ping 127.0.0.1 -n 3 > nul\nstart \"\" \"{}\"", exe);
+    let (username, password) = match logon {
+        Some(logon) => logon,
+        None => return run_cmds(start, false),
+    };
+    // the account has its own config, the id and password are imported into it
+    let config = Config::save_tmp();
+    let cmds = format!("\"{}\" --import-config \"{}\"\n{}", exe, config, start);
+    let tmp = write_cmds(cmds, "cmd")?;
+    let tmp = tmp.to_str().unwrap_or("").to_owned();
+    for path in vec![&config, &config.replace(".toml", "2.toml"), &tmp] {
+        let res = std::process::Command::new("icacls")
+            .args(vec![path, "/grant", &format!("{}:(R,D)", username)])
+            .output()?;
+        if !res.status.success() {
+            bail!("icacls: {}", String::from_utf8_lossy(&res.stdout));
+        }
+    }
+    create_process_with_logon(&username, &password, &format!("cmd /c \"{}\"", tmp))
+}
+
+fn create_process_with_logon(username: &str, password: &str, cmd: &str) -> ResultType<()> {
+    use std::os::windows::ffi::OsStrExt;
+    let wide = |s: &str| -> Vec<u16> {
+        std::ffi::OsStr::new(s)
+            .encode_wide()
+            .chain(Some(0).into_iter())
+            .collect()
+    };
+    let (domain, user) = match username.find('\\') {
+        Some(i) => (wide(&username[..i]), wide(&username[i + 1..])),
+        None => (wide("."), wide(username)),
+    };
+    let password = wide(password);
+    let mut cmd = wide(cmd);
+    let mut si: STARTUPINFOW = unsafe { mem::zeroed() };
+    si.cb = mem::size_of::<STARTUPINFOW>() as _;
+    let mut pi: PROCESS_INFORMATION = unsafe { mem::zeroed() };
+    let res = unsafe {
+        CreateProcessWithLogonW(
+            user.as_ptr(),
+            domain.as_ptr(),
+            password.as_ptr(),
+            LOGON_WITH_PROFILE,
+            NULL as _,
+            cmd.as_mut_ptr(),
+            CREATE_NO_WINDOW,
+            NULL,
+            NULL as _,
+            &mut si,
+            &mut pi,
+        )
+    };
+    if res == FALSE {
+        bail!("Failed to log on as {}: {}", username, get_error());
+    }
+    unsafe {
+        CloseHandle(pi.hThread);
+        CloseHandle(pi.hProcess);
+    }
+    Ok(())
+}
+
 pub fn lock_screen() {
     extern "system" {
         pub fn LockWorkStation() -> BOOL;
@@ -730,6 +950,49 @@ pub fn lock_screen() {
     }
 }
 
+pub fn logout() {
+    let session_id = get_current_session_id();
+    allow_err!(std::process::Command::new("logoff")
+        .arg(session_id.to_string())
+        .output());
+}
+
+// In the safe mode with networking, the service is registered to start there too
+// and clears the safe boot once started, so that the boot after is a normal one.
+pub fn restart(safe_mode: bool) -> ResultType<()> {
+    if safe_mode {
+        let res = std::process::Command::new("bcdedit")
+            .args(vec!["/set", "{current}", "safeboot", "network"])
+            .output()?;
+        if !res.status.success() {
+            bail!("bcdedit: {}", String::from_utf8_lossy(&res.stdout));
+        }
+        let key = format!(
+            "HKLM\\SYSTEM\\CurrentControlSet\\Control\\SafeBoot\\Network\\{}",
+            APP_NAME
+        );
+        std::process::Command::new("reg")
+            .args(vec![
+                "add", &key, "/ve", "/t", "REG_SZ", "/d", "Service", "/f",
+            ])
+            .output()?;
+    }
+    std::process::Command::new("shutdown")
+        .args(vec!["/r", "/t", "0"])
+        .spawn()?;
+    Ok(())
+}
+
+fn clear_safe_boot() {
+    if unsafe { GetSystemMetrics(SM_CLEANBOOT) } == 0 {
+        return;
+    }
+    log::info!("Started in the safe mode, the next boot is a normal one");
+    allow_err!(std::process::Command::new("bcdedit")
+        .args(vec!["/deletevalue", "{current}", "safeboot"])
+        .output());
+}
+
 pub fn get_install_info() -> (String, String, String, String) {
     let subkey = format!(
         "HKEY_LOCAL_MACHINE\\Software\\Microsoft\\Windows\\CurrentVersion\\Uninstall\\{}",
@@ -974,6 +1237,7 @@ rd /s /q \"{start_menu}\"
 del /f /q \"%PUBLIC%\\Desktop\\{app_name}*\"
 del /f /q \"C:\\ProgramData\\Microsoft\\Windows\\Start Menu\\Programs\\Startup\\{app_name} Tray.lnk\"
 netsh advfirewall firewall delete rule name=\"{app_name} Service\"
+powershell -NoProfile -Command \"Remove-Printer -Name '{app_name} Printer' -ErrorAction SilentlyContinue\"
     ",
         app_name = APP_NAME,
         path = path,
@@ -1013,21 +1277,165 @@ fn run_cmds(cmds: String, show: bool) -> ResultType<()> {
     Ok(())
 }
 
-pub fn toggle_privacy_mode(v: bool) {
-    let v = if v { TRUE } else { FALSE };
+pub fn toggle_privacy_mode(v: bool) -> bool {
+    unsafe { privacy_mode(if v { TRUE } else { FALSE }) == TRUE }
+}
+
+pub fn block_input(v: bool) -> bool {
+    unsafe { block_local_input(if v { TRUE } else { FALSE }) == TRUE }
+}
+
+/// Shows the annotations over the displays, the window closed with none.
+pub fn show_overlay(shapes: &[super::OverlayShape]) -> bool {
+    unsafe { annotation_overlay(shapes.as_ptr(), shapes.len() as _) == TRUE }
+}
+
+pub fn inject_touch(contacts: &[super::TouchContact]) -> bool {
+    let contacts: Vec<TouchInput> = contacts
+        .iter()
+        .map(|c| TouchInput {
+            id: c.id,
+            x: c.x,
+            y: c.y,
+            pressure: c.pressure,
+            state: match c.state {
+                super::TouchState::Down => 1,
+                super::TouchState::Up => 2,
+                super::TouchState::Move => 0,
+            },
+        })
+        .collect();
+    unsafe { inject_touch_input(contacts.as_ptr(), contacts.len() as _) == TRUE }
+}
+
+pub fn inject_pen(evt: &hbb_common::message_proto::PenEvent) -> bool {
+    let mut flags = 0;
+    if evt.down {
+        flags |= PEN_DOWN;
+    }
+    if evt.in_range {
+        flags |= PEN_IN_RANGE;
+    }
+    if evt.barrel {
+        flags |= PEN_BARREL;
+    }
+    if evt.eraser {
+        flags |= PEN_ERASER;
+    }
+    unsafe { inject_pen_input(evt.x, evt.y, evt.pressure, evt.tilt_x, evt.tilt_y, flags) == TRUE }
+}
+
+/// The touch and pen input of the windows of the current thread, i.e. the UI
+/// one, given to `callback` instead of the mouse input made of it.
+pub fn hook_pointer(callback: PointerCallback) -> bool {
+    unsafe { hook_pointer_input(callback) == TRUE }
+}
+
+/// The visible top level windows with a title, the topmost first.
+pub fn get_windows() -> Vec<WindowInfo> {
+    unsafe extern "system" fn callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let windows = &mut *(lparam as *mut Vec<WindowInfo>);
+        if IsWindowVisible(hwnd) == FALSE
+            || IsIconic(hwnd) != FALSE
+            || GetWindowLongW(hwnd, GWL_EXSTYLE) as DWORD & WS_EX_TOOLWINDOW != 0
+        {
+            return TRUE;
+        }
+        let len = GetWindowTextLengthW(hwnd);
+        if len <= 0 {
+            return TRUE;
+        }
+        let mut title = vec![0u16; len as usize + 1];
+        let len = GetWindowTextW(hwnd, title.as_mut_ptr(), title.len() as _);
+        let mut rect: RECT = mem::zeroed();
+        if GetWindowRect(hwnd, &mut rect) == FALSE {
+            return TRUE;
+        }
+        windows.push(WindowInfo {
+            id: hwnd as _,
+            title: String::from_utf16_lossy(&title[..len.max(0) as usize]),
+            x: rect.left,
+            y: rect.top,
+            width: rect.right - rect.left,
+            height: rect.bottom - rect.top,
+            ..Default::default()
+        });
+        TRUE
+    }
+    let mut windows: Vec<WindowInfo> = Vec::new();
+    unsafe {
+        EnumWindows(Some(callback), &mut windows as *mut _ as LPARAM);
+    }
+    windows
+}
+
+fn device_name(name: &str) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    std::ffi::OsStr::new(name)
+        .encode_wide()
+        .chain(Some(0).into_iter())
+        .collect()
+}
+
+/// The display modes of the display, e.g. \\.\DISPLAY1, without duplicates
+/// of other refresh rates or color depths.
+pub fn resolutions(name: &str) -> Vec<(usize, usize)> {
+    let device = device_name(name);
+    let mut modes = Vec::new();
     unsafe {
-        blank_screen(v);
-        BlockInput(v);
+        let mut dm: DEVMODEW = mem::zeroed();
+        dm.dmSize = mem::size_of::<DEVMODEW>() as _;
+        let mut i = 0;
+        while EnumDisplaySettingsW(device.as_ptr(), i, &mut dm) != FALSE {
+            let mode = (dm.dmPelsWidth as usize, dm.dmPelsHeight as usize);
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
+            i += 1;
+        }
     }
+    modes
 }
 
-pub fn block_input(v: bool) {
-    let v = if v { TRUE } else { FALSE };
+fn current_mode(name: &str) -> ResultType<DEVMODEW> {
+    let device = device_name(name);
     unsafe {
-        BlockInput(v);
+        let mut dm: DEVMODEW = mem::zeroed();
+        dm.dmSize = mem::size_of::<DEVMODEW>() as _;
+        if EnumDisplaySettingsW(device.as_ptr(), ENUM_CURRENT_SETTINGS, &mut dm) == FALSE {
+            bail!("EnumDisplaySettingsW failed: {}", get_error());
+        }
+        Ok(dm)
     }
 }
 
+pub fn current_resolution(name: &str) -> ResultType<(usize, usize)> {
+    let dm = current_mode(name)?;
+    Ok((dm.dmPelsWidth as _, dm.dmPelsHeight as _))
+}
+
+// not saved in the registry, a reboot or sign out restores the mode anyway
+pub fn change_resolution(name: &str, width: usize, height: usize) -> ResultType<()> {
+    let device = device_name(name);
+    let mut dm = current_mode(name)?;
+    dm.dmPelsWidth = width as _;
+    dm.dmPelsHeight = height as _;
+    dm.dmFields = DM_PELSWIDTH | DM_PELSHEIGHT;
+    let res = unsafe {
+        ChangeDisplaySettingsExW(
+            device.as_ptr(),
+            &mut dm,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if res != DISP_CHANGE_SUCCESSFUL {
+        bail!("ChangeDisplaySettingsExW failed: {}", res);
+    }
+    Ok(())
+}
+
 pub fn add_recent_document(path: &str) {
     extern "C" {
         fn AddRecentDocument(path: *const u16);
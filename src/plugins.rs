@@ -0,0 +1,444 @@
+// The plugins, dynamic libraries each in a folder of the plugins folder with
+// its manifest.json, loaded on both sides of the sessions once enabled by the
+// option "plugin-<id>" = "Y". A plugin may see the messages of the sessions,
+// change or drop them, add actions to the toolbar of the remote window, the
+// messages they make sent to the peer, and add permissions to the settings,
+// "plugin-<id>-<permission>" = "Y" when given, which it is told of. The
+// options are read again every SYNC_INTERVAL. The functions of a plugin, of C,
+// all but the first optional:
+//   int rustdesk_plugin_init(int api_version), else than 0 to refuse to load
+//   int rustdesk_plugin_on_message(int side, const char *peer_id, int outgoing,
+//       const uint8_t *msg, size_t len, uint8_t **out, size_t *out_len)
+//     with the message in protobuf, returning PASS, REPLACE by *out or DROP,
+//     only called if "messages" is true in the manifest
+//   int rustdesk_plugin_on_action(const char *peer_id, const char *action,
+//       uint8_t **out, size_t *out_len), 1 to send the message in *out
+//   void rustdesk_plugin_on_permission(const char *permission, int given)
+//   void rustdesk_plugin_free(uint8_t *ptr, size_t len), of *out
+// The frames of the video and audio are not seen. The plugins of WebAssembly
+// are not supported.
+use hbb_common::{
+    bail,
+    config::Config,
+    log,
+    message_proto::{message, Message},
+    protobuf::Message as _,
+    ResultType,
+};
+use libloading::Library;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    os::raw::{c_char, c_int},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+pub const API_VERSION: i32 = 1;
+pub const SIDE_CLIENT: i32 = 0;
+pub const SIDE_SERVER: i32 = 1;
+const PASS: c_int = 0;
+const REPLACE: c_int = 1;
+const DROP: c_int = 2;
+const SYNC_INTERVAL: i64 = 5_000;
+const MANIFEST: &str = "manifest.json";
+
+type Init = unsafe extern "C" fn(c_int) -> c_int;
+type OnMessage = unsafe extern "C" fn(
+    c_int,
+    *const c_char,
+    c_int,
+    *const u8,
+    usize,
+    *mut *mut u8,
+    *mut usize,
+) -> c_int;
+type OnAction =
+    unsafe extern "C" fn(*const c_char, *const c_char, *mut *mut u8, *mut usize) -> c_int;
+type OnPermission = unsafe extern "C" fn(*const c_char, c_int);
+type Free = unsafe extern "C" fn(*mut u8, usize);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Item {
+    pub id: String,
+    pub label: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    // the file of the library, in the folder of the manifest
+    pub library: String,
+    #[serde(default)]
+    pub messages: bool,
+    #[serde(default)]
+    pub actions: Vec<Item>,
+    #[serde(default)]
+    pub permissions: Vec<Item>,
+}
+
+struct Functions {
+    on_message: Option<OnMessage>,
+    on_action: Option<OnAction>,
+    on_permission: Option<OnPermission>,
+    free: Option<Free>,
+    // never unloaded, the functions above being of it
+    _lib: Library,
+}
+
+struct Plugin {
+    manifest: Manifest,
+    dir: PathBuf,
+    functions: Option<Functions>,
+    failed: bool,
+    enabled: bool,
+    // as told to the plugin
+    permissions: HashMap<String, bool>,
+}
+
+#[derive(Default)]
+struct Plugins {
+    list: Vec<Plugin>,
+    // the folders read, those of the invalid plugins not read again
+    seen: HashSet<PathBuf>,
+}
+
+lazy_static::lazy_static! {
+    static ref PLUGINS: Mutex<Plugins> = Default::default();
+}
+static LAST_SYNC: AtomicI64 = AtomicI64::new(0);
+// any plugin enabled seeing the messages, not to serialize them else
+static ANY_MESSAGES: AtomicBool = AtomicBool::new(false);
+
+/// The folder of the plugins, only writable by the administrators once
+/// installed, the service loading them.
+pub fn get_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    let dir = PathBuf::from("/usr/lib/rustdesk/plugins");
+    #[cfg(not(target_os = "linux"))]
+    let dir = std::env::current_exe()
+        .ok()
+        .and_then(|x| x.parent().map(|x| x.join("plugins")))
+        .unwrap_or_default();
+    dir
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn read_manifest(dir: &Path) -> ResultType<Manifest> {
+    let manifest: Manifest = serde_json::from_str(&std::fs::read_to_string(dir.join(MANIFEST))?)?;
+    if !is_valid_id(&manifest.id) {
+        bail!("invalid id {:?}", manifest.id);
+    }
+    let mut items = manifest.actions.iter().chain(manifest.permissions.iter());
+    if let Some(item) = items.find(|x| !is_valid_id(&x.id)) {
+        bail!("invalid id {:?}", item.id);
+    }
+    if manifest.library.is_empty() || manifest.library.contains(&['/', '\\'][..]) {
+        bail!("invalid library {:?}", manifest.library);
+    }
+    Ok(manifest)
+}
+
+fn load(dir: &Path, manifest: &Manifest) -> ResultType<Functions> {
+    let path = dir.join(&manifest.library);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(&path)?.permissions().mode() & 0o022 != 0 {
+            bail!("{:?} is writable by others", path);
+        }
+    }
+    unsafe {
+        let lib = Library::new(&path)?;
+        let res = lib.get::<Init>(b"rustdesk_plugin_init\0")?(API_VERSION);
+        if res != 0 {
+            bail!("refused to load, {}", res);
+        }
+        Ok(Functions {
+            on_message: lib
+                .get::<OnMessage>(b"rustdesk_plugin_on_message\0")
+                .ok()
+                .map(|x| *x),
+            on_action: lib
+                .get::<OnAction>(b"rustdesk_plugin_on_action\0")
+                .ok()
+                .map(|x| *x),
+            on_permission: lib
+                .get::<OnPermission>(b"rustdesk_plugin_on_permission\0")
+                .ok()
+                .map(|x| *x),
+            free: lib.get::<Free>(b"rustdesk_plugin_free\0").ok().map(|x| *x),
+            _lib: lib,
+        })
+    }
+}
+
+fn get_option(key: &str) -> bool {
+    Config::get_option(key) == "Y"
+}
+
+// new plugins of the folder added, the enabled ones loaded and told of their
+// permissions
+fn sync(plugins: &mut Plugins) {
+    LAST_SYNC.store(crate::get_time(), Ordering::SeqCst);
+    if let Ok(entries) = std::fs::read_dir(get_dir()) {
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            if !dir.is_dir() || !plugins.seen.insert(dir.clone()) {
+                continue;
+            }
+            match read_manifest(&dir) {
+                Ok(manifest) if plugins.list.iter().all(|x| x.manifest.id != manifest.id) => {
+                    plugins.list.push(Plugin {
+                        manifest,
+                        dir,
+                        functions: None,
+                        failed: false,
+                        enabled: false,
+                        permissions: Default::default(),
+                    });
+                }
+                Ok(manifest) => log::error!("Duplicated plugin {} in {:?}", manifest.id, dir),
+                Err(err) => log::error!("Failed to read the plugin in {:?}: {}", dir, err),
+            }
+        }
+    }
+    for p in plugins.list.iter_mut() {
+        let id = p.manifest.id.clone();
+        p.enabled = get_option(&format!("plugin-{}", id));
+        if p.enabled && p.functions.is_none() && !p.failed {
+            match load(&p.dir, &p.manifest) {
+                Ok(functions) => {
+                    log::info!("Plugin {} {} loaded", id, p.manifest.version);
+                    p.functions = Some(functions);
+                }
+                Err(err) => {
+                    log::error!("Failed to load the plugin {}: {}", id, err);
+                    p.failed = true;
+                }
+            }
+        }
+        let on_permission = match p.functions.as_ref().and_then(|x| x.on_permission) {
+            Some(f) if p.enabled => f,
+            _ => continue,
+        };
+        for perm in p.manifest.permissions.iter() {
+            let given = get_option(&format!("plugin-{}-{}", id, perm.id));
+            if p.permissions.insert(perm.id.clone(), given) != Some(given) {
+                if let Ok(name) = CString::new(perm.id.as_str()) {
+                    unsafe { on_permission(name.as_ptr(), given as _) };
+                }
+            }
+        }
+    }
+    let any = plugins
+        .list
+        .iter()
+        .any(|p| p.enabled && p.manifest.messages && on_message(p).is_some());
+    ANY_MESSAGES.store(any, Ordering::SeqCst);
+}
+
+fn on_message(p: &Plugin) -> Option<OnMessage> {
+    p.functions.as_ref().and_then(|x| x.on_message)
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Plugins> {
+    let mut plugins = PLUGINS.lock().unwrap();
+    if crate::get_time() - LAST_SYNC.load(Ordering::SeqCst) >= SYNC_INTERVAL {
+        sync(&mut plugins);
+    }
+    plugins
+}
+
+// the buffer given by the plugin, freed by it
+unsafe fn take_out(functions: &Functions, out: *mut u8, len: usize) -> Vec<u8> {
+    if out.is_null() {
+        return Vec::new();
+    }
+    let data = std::slice::from_raw_parts(out, len).to_vec();
+    if let Some(free) = functions.free {
+        free(out, len);
+    }
+    data
+}
+
+pub enum Filtered {
+    Pass,
+    Replace(Message),
+    Drop,
+}
+
+/// A message received from or sent to `peer_id` seen by the plugins, in the
+/// order of their folders.
+pub fn filter(side: i32, peer_id: &str, outgoing: bool, msg: &Message) -> Filtered {
+    if crate::get_time() - LAST_SYNC.load(Ordering::SeqCst) >= SYNC_INTERVAL {
+        drop(lock());
+    }
+    if !ANY_MESSAGES.load(Ordering::SeqCst) {
+        return Filtered::Pass;
+    }
+    match msg.union {
+        Some(message::Union::video_frame(_)) | Some(message::Union::audio_frame(_)) => {
+            return Filtered::Pass;
+        }
+        _ => {}
+    }
+    let peer_id = CString::new(peer_id).unwrap_or_default();
+    let mut data = match msg.write_to_bytes() {
+        Ok(data) => data,
+        Err(_) => return Filtered::Pass,
+    };
+    let mut replaced = false;
+    let plugins = lock();
+    for p in plugins.list.iter() {
+        let (f, functions) = match (on_message(p), p.functions.as_ref()) {
+            (Some(f), Some(functions)) if p.enabled && p.manifest.messages => (f, functions),
+            _ => continue,
+        };
+        let mut out = std::ptr::null_mut();
+        let mut out_len = 0;
+        let res = unsafe {
+            let res = f(
+                side,
+                peer_id.as_ptr(),
+                outgoing as _,
+                data.as_ptr(),
+                data.len(),
+                &mut out,
+                &mut out_len,
+            );
+            let out = take_out(functions, out, out_len);
+            if res == REPLACE {
+                data = out;
+            }
+            res
+        };
+        match res {
+            REPLACE => replaced = true,
+            DROP => return Filtered::Drop,
+            PASS => {}
+            _ => log::error!("Plugin {} returned {}", p.manifest.id, res),
+        }
+    }
+    if !replaced {
+        return Filtered::Pass;
+    }
+    match Message::parse_from_bytes(&data) {
+        Ok(msg) => Filtered::Replace(msg),
+        Err(err) => {
+            log::error!("Invalid message from the plugins: {}", err);
+            Filtered::Drop
+        }
+    }
+}
+
+/// As `filter`, with the message to go on with, None if dropped.
+pub fn filter_owned(side: i32, peer_id: &str, outgoing: bool, msg: Message) -> Option<Message> {
+    match filter(side, peer_id, outgoing, &msg) {
+        Filtered::Pass => Some(msg),
+        Filtered::Replace(msg) => Some(msg),
+        Filtered::Drop => None,
+    }
+}
+
+/// The plugins in the folder, by id, for the settings, none loaded.
+pub fn list() -> Vec<Manifest> {
+    let mut list: Vec<_> = std::fs::read_dir(get_dir())
+        .map(|x| {
+            x.flatten()
+                .filter_map(|x| read_manifest(&x.path()).ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    list.sort_by(|a, b| a.id.cmp(&b.id));
+    list.dedup_by(|a, b| a.id == b.id);
+    list
+}
+
+/// The actions of the plugins enabled: plugin, action, label.
+pub fn actions() -> Vec<(String, String, String)> {
+    let mut actions = Vec::new();
+    for p in lock().list.iter() {
+        if !p.enabled || p.functions.as_ref().and_then(|x| x.on_action).is_none() {
+            continue;
+        }
+        for a in p.manifest.actions.iter() {
+            actions.push((p.manifest.id.clone(), a.id.clone(), a.label.clone()));
+        }
+    }
+    actions
+}
+
+/// Runs an action of a plugin in the session with `peer_id`, with the message
+/// to send to the peer if any.
+pub fn on_action(plugin: &str, action: &str, peer_id: &str) -> Option<Message> {
+    let plugins = lock();
+    let p = plugins
+        .list
+        .iter()
+        .find(|p| p.enabled && p.manifest.id == plugin)?;
+    let functions = p.functions.as_ref()?;
+    let f = functions.on_action?;
+    if p.manifest.actions.iter().all(|x| x.id != action) {
+        return None;
+    }
+    let peer_id = CString::new(peer_id).ok()?;
+    let action = CString::new(action).ok()?;
+    let mut out = std::ptr::null_mut();
+    let mut out_len = 0;
+    let (res, data) = unsafe {
+        let res = f(peer_id.as_ptr(), action.as_ptr(), &mut out, &mut out_len);
+        (res, take_out(functions, out, out_len))
+    };
+    if res != 1 {
+        return None;
+    }
+    match Message::parse_from_bytes(&data) {
+        Ok(msg) => Some(msg),
+        Err(err) => {
+            log::error!("Invalid message from the plugin {}: {}", plugin, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = std::env::temp_dir().join(format!("rustdesk-plugin-{}", crate::get_time()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |s: &str| std::fs::write(dir.join(MANIFEST), s).unwrap();
+        write(r#"{"id": "echo", "name": "Echo", "library": "libecho.so"}"#);
+        let manifest = read_manifest(&dir).unwrap();
+        assert!(!manifest.messages && manifest.actions.is_empty());
+        write(r#"{"id": "../echo", "name": "Echo", "library": "libecho.so"}"#);
+        assert!(read_manifest(&dir).is_err());
+        write(r#"{"id": "echo", "name": "Echo", "library": "../libecho.so"}"#);
+        assert!(read_manifest(&dir).is_err());
+        write(
+            r#"{"id": "echo", "name": "Echo", "library": "libecho.so",
+                "permissions": [{"id": "a b", "label": "A"}]}"#,
+        );
+        assert!(read_manifest(&dir).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
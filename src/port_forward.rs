@@ -1,6 +1,7 @@
 use crate::client::*;
 use hbb_common::{
     allow_err, bail,
+    bytes::{Bytes, BytesMut},
     config::CONNECT_TIMEOUT,
     futures::{SinkExt, StreamExt},
     log,
@@ -8,10 +9,20 @@ use hbb_common::{
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc,
+        time::{interval, sleep, Duration, Instant},
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 fn run_rdp(port: u16) {
     std::process::Command::new("mstsc")
@@ -40,7 +51,7 @@ pub async fn listen(
                 log::info!("new connection from {:?}", addr);
                 let id = id.clone();
                 let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &mut ui_receiver, interface.clone(), &mut forward, is_rdp).await {
+                match connect_and_login(&id, &mut ui_receiver, interface.clone(), Some(&mut forward), is_rdp).await {
                     Ok(Some(stream)) => {
                         let interface = interface.clone();
                         tokio::spawn(async move {
@@ -76,7 +87,7 @@ async fn connect_and_login(
     id: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     is_rdp: bool,
 ) -> ResultType<Option<Stream>> {
     let conn_type = if is_rdp {
@@ -86,6 +97,7 @@ async fn connect_and_login(
     };
     let (mut stream, _) = Client::start(id, conn_type).await?;
     let mut interface = interface;
+    let mut forward = forward;
     let mut buffer = Vec::new();
     loop {
         tokio::select! {
@@ -128,7 +140,7 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = next_forward(&mut forward) => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -137,6 +149,9 @@ async fn connect_and_login(
             },
         }
     }
+    if forward.is_none() {
+        return Ok(Some(stream));
+    }
     stream.set_raw();
     if !buffer.is_empty() {
         allow_err!(stream.send_bytes(buffer.into()).await);
@@ -144,6 +159,16 @@ async fn connect_and_login(
     Ok(Some(stream))
 }
 
+// never ready without a local connection, as for the dynamic forwarding
+async fn next_forward(
+    forward: &mut Option<&mut Framed<TcpStream, BytesCodec>>,
+) -> Option<Result<BytesMut, std::io::Error>> {
+    match forward {
+        Some(forward) => forward.next().await,
+        None => std::future::pending().await,
+    }
+}
+
 async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) -> ResultType<()> {
     log::info!("new port forwarding connection started");
     let mut forward = forward;
@@ -168,3 +193,499 @@ async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) ->
     }
     Ok(())
 }
+
+// a UDP flow is closed after this without any datagram either way
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Dynamic port forwarding: a SOCKS5 proxy here, its connections to any
+// destination opened by the host, all of them in one connection to it.
+pub async fn listen_dynamic(
+    id: String,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+) -> ResultType<()> {
+    // not to be an open proxy into the network of the host
+    let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
+    log::info!("SOCKS5 listening on port {:?}", listener.local_addr()?);
+    run_tunnels(&id, Some(listener), None, interface, ui_receiver).await
+}
+
+// Reverse port forwarding: the host listens on the port of the login, and the
+// connections to it are forwarded to `target` from here.
+pub async fn connect_reverse(
+    id: String,
+    target: (String, i32),
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+) -> ResultType<()> {
+    run_tunnels(&id, None, Some(target), interface, ui_receiver).await
+}
+
+// UDP port forwarding: the datagrams to the local port sent to `target` by the
+// host, in a stream for each source address as a NAT maps them, and the ones
+// back to the source of the stream.
+pub async fn listen_udp(
+    id: String,
+    port: i32,
+    target: (String, i32),
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+) -> ResultType<()> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+    log::info!("UDP listening on port {:?}", socket.local_addr()?);
+    let mut ui_receiver = ui_receiver;
+    let mut interface = interface;
+    let mut stream =
+        match connect_and_login(&id, &mut ui_receiver, interface.clone(), None, false).await? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+    // source -> (id, time of the last datagram)
+    let mut flows = HashMap::<SocketAddr, (u32, Instant)>::new();
+    let mut next_id = 0;
+    let mut buf = vec![0u8; 65536];
+    let mut timer = interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (n, addr) = res?;
+                let id = match flows.get_mut(&addr) {
+                    Some(flow) => {
+                        flow.1 = Instant::now();
+                        flow.0
+                    }
+                    None => {
+                        next_id += 1;
+                        flows.insert(addr, (next_id, Instant::now()));
+                        let connect = tunnel::Union::connect(PortForward {
+                            host: target.0.clone(),
+                            port: target.1,
+                            udp: true,
+                            ..Default::default()
+                        });
+                        send_tunnel(&mut stream, new_tunnel(next_id, connect)).await?;
+                        next_id
+                    }
+                };
+                let data = tunnel::Union::data(buf[..n].to_vec());
+                send_tunnel(&mut stream, new_tunnel(id, data)).await?;
+            }
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    _ => bail!("Reset by the peer"),
+                };
+                match Message::parse_from_bytes(&bytes)?.union {
+                    Some(message::Union::tunnel(t)) => {
+                        let addr = flows
+                            .iter()
+                            .find(|(_, x)| x.0 == t.id)
+                            .map(|(addr, _)| *addr);
+                        match (t.union, addr) {
+                            (Some(tunnel::Union::data(data)), Some(addr)) => {
+                                socket.send_to(&data, addr).await.ok();
+                            }
+                            (Some(tunnel::Union::close(_)), Some(addr)) => {
+                                flows.remove(&addr);
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(message::Union::test_delay(t)) => {
+                        interface.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::misc(misc)) => {
+                        if let Some(misc::Union::close_reason(reason)) = misc.union {
+                            bail!(reason);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                let expired: Vec<SocketAddr> = flows
+                    .iter()
+                    .filter(|(_, x)| x.1.elapsed() > UDP_TIMEOUT)
+                    .map(|(addr, _)| *addr)
+                    .collect();
+                for addr in expired {
+                    if let Some((id, _)) = flows.remove(&addr) {
+                        let close = tunnel::Union::close("".to_owned());
+                        send_tunnel(&mut stream, new_tunnel(id, close)).await?;
+                    }
+                }
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send_tunnel(stream: &mut Stream, t: Tunnel) -> ResultType<()> {
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel(t);
+    stream.send(&msg_out).await
+}
+
+async fn run_tunnels(
+    id: &str,
+    listener: Option<TcpListener>,
+    target: Option<(String, i32)>,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+) -> ResultType<()> {
+    let mut ui_receiver = ui_receiver;
+    let mut interface = interface;
+    let mut stream =
+        match connect_and_login(id, &mut ui_receiver, interface.clone(), None, false).await? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+    let (tx, mut rx) = mpsc::unbounded_channel::<Tunnel>();
+    let mut tunnels = HashMap::<u32, mpsc::UnboundedSender<Tunnel>>::new();
+    let mut next_id = 0;
+    loop {
+        tokio::select! {
+            Ok((socket, addr)) = accept(&listener) => {
+                log::debug!("new SOCKS5 connection from {:?}", addr);
+                next_id += 1;
+                let (tx_tunnel, rx_tunnel) = mpsc::unbounded_channel();
+                tunnels.insert(next_id, tx_tunnel);
+                let tx = tx.clone();
+                tokio::spawn(run_socks(next_id, socket, rx_tunnel, move |t| {
+                    tx.send(t).ok();
+                }));
+            }
+            Some(t) = rx.recv() => {
+                if let Some(tunnel::Union::close(_)) = t.union {
+                    tunnels.remove(&t.id);
+                }
+                send_tunnel(&mut stream, t).await?;
+            }
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    _ => bail!("Reset by the peer"),
+                };
+                match Message::parse_from_bytes(&bytes)?.union {
+                    Some(message::Union::tunnel(t)) => match (t.union, target.as_ref()) {
+                        (Some(tunnel::Union::connect(_)), Some((host, port))) => {
+                            let (tx_tunnel, rx_tunnel) = mpsc::unbounded_channel();
+                            tunnels.insert(t.id, tx_tunnel);
+                            let tx = tx.clone();
+                            let (id, host) = (t.id, host.clone());
+                            tokio::spawn(serve_tunnel(id, host, *port, rx_tunnel, move |t| {
+                                tx.send(t).ok();
+                            }));
+                        }
+                        (union, _) => dispatch(&mut tunnels, t.id, union),
+                    },
+                    Some(message::Union::test_delay(t)) => {
+                        interface.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::misc(misc)) => {
+                        if let Some(misc::Union::close_reason(reason)) = misc.union {
+                            bail!(reason);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// never ready without a listener
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// To the end of the stream `id` in `tunnels`, which is removed once closed.
+pub fn dispatch(
+    tunnels: &mut HashMap<u32, mpsc::UnboundedSender<Tunnel>>,
+    id: u32,
+    union: Option<tunnel::Union>,
+) {
+    let closed = matches!(union, Some(tunnel::Union::close(_)));
+    if let Some(tx) = tunnels.get(&id) {
+        tx.send(Tunnel {
+            id,
+            union,
+            ..Default::default()
+        })
+        .ok();
+    }
+    if closed {
+        tunnels.remove(&id);
+    }
+}
+
+async fn run_socks<F: FnMut(Tunnel)>(
+    id: u32,
+    socket: TcpStream,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: F,
+) {
+    let mut send = send;
+    let res = run_socks_(id, socket, rx, &mut send).await;
+    close_tunnel(id, res, &mut send);
+}
+
+async fn run_socks_<F: FnMut(Tunnel)>(
+    id: u32,
+    socket: TcpStream,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: &mut F,
+) -> ResultType<()> {
+    let mut socket = socket;
+    let mut rx = rx;
+    let (host, port) = socks_handshake(&mut socket).await?;
+    send(new_tunnel(
+        id,
+        tunnel::Union::connect(PortForward {
+            host,
+            port,
+            ..Default::default()
+        }),
+    ));
+    if let Err(err) = wait_connected(&mut rx).await {
+        // host unreachable
+        socks_reply(&mut socket, 4).await?;
+        return Err(err);
+    }
+    socks_reply(&mut socket, 0).await?;
+    pump(id, socket, rx, send).await
+}
+
+// RFC 1928, only the CONNECT command without authentication
+async fn socks_handshake(socket: &mut TcpStream) -> ResultType<(String, i32)> {
+    let mut buf = [0u8; 2];
+    socket.read_exact(&mut buf).await?;
+    if buf[0] != 5 {
+        bail!("Not a SOCKS5 client");
+    }
+    let mut methods = vec![0u8; buf[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&0) {
+        socket.write_all(&[5, 0xff]).await?;
+        bail!("No supported authentication method");
+    }
+    socket.write_all(&[5, 0]).await?;
+    let mut buf = [0u8; 4];
+    socket.read_exact(&mut buf).await?;
+    if buf[1] != 1 {
+        socks_reply(socket, 7).await?;
+        bail!("Unsupported SOCKS5 command {}", buf[1]);
+    }
+    let host = match buf[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).to_string()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        atyp => {
+            socks_reply(socket, 8).await?;
+            bail!("Unsupported SOCKS5 address type {}", atyp);
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await?;
+    Ok((host, u16::from_be_bytes(port) as _))
+}
+
+// with 0.0.0.0:0 as the bound address, the clients do not need it
+async fn socks_reply(socket: &mut TcpStream, rep: u8) -> ResultType<()> {
+    socket.write_all(&[5, rep, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
+
+#[inline]
+fn new_tunnel(id: u32, union: tunnel::Union) -> Tunnel {
+    Tunnel {
+        id,
+        union: Some(union),
+        ..Default::default()
+    }
+}
+
+fn close_tunnel<F: FnMut(Tunnel)>(id: u32, res: ResultType<()>, send: &mut F) {
+    let reason = match res {
+        Ok(_) => "".to_owned(),
+        Err(err) => {
+            log::debug!("Tunnel {} closed: {}", id, err);
+            err.to_string()
+        }
+    };
+    send(new_tunnel(id, tunnel::Union::close(reason)));
+}
+
+/// The end of the stream `id` connecting to `host`:`port`, the host's for the
+/// dynamic forwarding and the peer's for the reverse one. `rx` has what the
+/// other end sends, and `send` sends to it.
+pub async fn serve_tunnel<F: FnMut(Tunnel)>(
+    id: u32,
+    host: String,
+    port: i32,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: F,
+) {
+    let mut send = send;
+    let res = serve_tunnel_(id, &host, port, rx, &mut send).await;
+    close_tunnel(id, res, &mut send);
+}
+
+async fn serve_tunnel_<F: FnMut(Tunnel)>(
+    id: u32,
+    host: &str,
+    port: i32,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: &mut F,
+) -> ResultType<()> {
+    let host = if host.is_empty() { "localhost" } else { host };
+    let socket = match timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port as u16))).await {
+        Ok(res) => res?,
+        Err(_) => bail!("Timeout"),
+    };
+    send(new_tunnel(id, tunnel::Union::connected(true)));
+    pump(id, socket, rx, send).await
+}
+
+/// The host end of the UDP flow `id` to `host`:`port`, closed once idle.
+pub async fn serve_udp<F: FnMut(Tunnel)>(
+    id: u32,
+    host: String,
+    port: i32,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: F,
+) {
+    let mut send = send;
+    let res = serve_udp_(id, &host, port, rx, &mut send).await;
+    close_tunnel(id, res, &mut send);
+}
+
+async fn serve_udp_<F: FnMut(Tunnel)>(
+    id: u32,
+    host: &str,
+    port: i32,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: &mut F,
+) -> ResultType<()> {
+    let host = if host.is_empty() { "localhost" } else { host };
+    let addr = match tokio::net::lookup_host((host, port as u16)).await?.next() {
+        Some(addr) => addr,
+        None => bail!("Failed to resolve {}", host),
+    };
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    let mut rx = rx;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            res = socket.recv(&mut buf) => {
+                send(new_tunnel(id, tunnel::Union::data(buf[..res?].to_vec())));
+            }
+            t = rx.recv() => match t.and_then(|t| t.union) {
+                Some(tunnel::Union::data(data)) => {
+                    socket.send(&data).await?;
+                }
+                _ => break,
+            },
+            _ = sleep(UDP_TIMEOUT) => break,
+        }
+    }
+    Ok(())
+}
+
+/// The end of the stream `id` of a connection accepted by the host listening
+/// for the reverse forwarding, the other end connecting to its own target.
+pub async fn accept_tunnel<F: FnMut(Tunnel)>(
+    id: u32,
+    socket: TcpStream,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: F,
+) {
+    let mut send = send;
+    let mut rx = rx;
+    send(new_tunnel(id, tunnel::Union::connect(PortForward::new())));
+    let res = match wait_connected(&mut rx).await {
+        Ok(_) => pump(id, socket, rx, &mut send).await,
+        Err(err) => Err(err),
+    };
+    close_tunnel(id, res, &mut send);
+}
+
+async fn wait_connected(rx: &mut mpsc::UnboundedReceiver<Tunnel>) -> ResultType<()> {
+    match timeout(CONNECT_TIMEOUT, rx.recv()).await {
+        Ok(Some(Tunnel {
+            union: Some(tunnel::Union::connected(_)),
+            ..
+        })) => Ok(()),
+        Ok(Some(Tunnel {
+            union: Some(tunnel::Union::close(reason)),
+            ..
+        })) => bail!(reason),
+        Ok(_) => bail!("Closed"),
+        Err(_) => bail!("Timeout"),
+    }
+}
+
+// until either end closes
+async fn pump<F: FnMut(Tunnel)>(
+    id: u32,
+    socket: TcpStream,
+    rx: mpsc::UnboundedReceiver<Tunnel>,
+    send: &mut F,
+) -> ResultType<()> {
+    let mut socket = Framed::new(socket, BytesCodec::new());
+    let mut rx = rx;
+    loop {
+        tokio::select! {
+            res = socket.next() => match res {
+                Some(Ok(bytes)) => send(new_tunnel(id, tunnel::Union::data(bytes.to_vec()))),
+                _ => break,
+            },
+            t = rx.recv() => match t.and_then(|t| t.union) {
+                Some(tunnel::Union::data(data)) => socket.send(Bytes::from(data)).await?,
+                _ => break,
+            },
+        }
+    }
+    Ok(())
+}
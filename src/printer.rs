@@ -0,0 +1,164 @@
+// The print jobs of the virtual printers of the peers, received in chunks into
+// print/received of the config folder, then printed on the default printer
+// here, saved elsewhere or dropped, as the option "print-action" of the peer
+// says ("print", "save", else asked for each job).
+use hbb_common::{bail, config::Config, log, message_proto::PrintJob, ResultType};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const MAX_JOB_SIZE: u64 = 100 * 1024 * 1024;
+// the files printed here are left to the printing app, removed after this
+const KEEP_TIME: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Default)]
+pub struct Spooler {
+    // by id, with the size written
+    jobs: HashMap<i32, (File, PathBuf, u64)>,
+}
+
+impl Spooler {
+    /// Writes a chunk of a job, its file once it is all received.
+    pub fn add(&mut self, job: PrintJob) -> Option<PathBuf> {
+        match self.add_(&job) {
+            Ok(path) => path,
+            Err(err) => {
+                log::error!("Failed to receive the print job {}: {}", job.name, err);
+                if let Some((_, path, _)) = self.jobs.remove(&job.id) {
+                    fs::remove_file(path).ok();
+                }
+                None
+            }
+        }
+    }
+
+    fn add_(&mut self, job: &PrintJob) -> ResultType<Option<PathBuf>> {
+        if job.total_size > MAX_JOB_SIZE {
+            bail!("too large, {} bytes", job.total_size);
+        }
+        if !self.jobs.contains_key(&job.id) {
+            let dir = Config::print_dir("received");
+            fs::create_dir_all(&dir)?;
+            remove_old_files(&dir);
+            let path = dir.join(get_file_name(&job.name, &job.format));
+            self.jobs.insert(job.id, (File::create(&path)?, path, 0));
+        }
+        if let Some((file, _, size)) = self.jobs.get_mut(&job.id) {
+            *size += job.data.len() as u64;
+            if *size > job.total_size {
+                bail!("more than the {} bytes announced", job.total_size);
+            }
+            file.write_all(&job.data)?;
+        }
+        if job.last {
+            return Ok(self.jobs.remove(&job.id).map(|x| x.1));
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Spooler {
+    fn drop(&mut self) {
+        for (_, (_, path, _)) in self.jobs.drain() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+fn remove_old_files(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let old = entry
+                .metadata()
+                .and_then(|x| x.modified())
+                .map(|x| x.elapsed().unwrap_or_default() > KEEP_TIME)
+                .unwrap_or(false);
+            if old {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+}
+
+// the name of the document, which is the peer's, made a file name here
+fn get_file_name(name: &str, format: &str) -> String {
+    let format = if format == "xps" { "xps" } else { "pdf" };
+    let name: String = name
+        .chars()
+        .take(64)
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}-{}.{}", crate::get_time(), name, format)
+}
+
+/// Prints the file of a job on the default printer.
+pub fn print(path: &Path) -> ResultType<()> {
+    check_path(path)?;
+    #[cfg(windows)]
+    let output = {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        let script = format!(
+            "Start-Process -FilePath '{}' -Verb Print",
+            path.to_string_lossy().replace('\'', "''")
+        );
+        std::process::Command::new("powershell")
+            .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+            .creation_flags(CREATE_NO_WINDOW)
+            .output()?
+    };
+    #[cfg(not(windows))]
+    let output = std::process::Command::new("lp").arg(path).output()?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Moves the file of a job to `to`.
+pub fn save(path: &Path, to: &Path) -> ResultType<()> {
+    check_path(path)?;
+    if fs::rename(path, to).is_err() {
+        // to another drive
+        fs::copy(path, to)?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+pub fn discard(path: &Path) -> ResultType<()> {
+    check_path(path)?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+// only the jobs received, the paths coming from the script
+fn check_path(path: &Path) -> ResultType<()> {
+    if path.parent() != Some(Config::print_dir("received").as_path()) {
+        bail!("Not a print job: {:?}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        let name = get_file_name("../a b/c.txt", "exe");
+        assert!(name.ends_with("-___a_b_c_txt.pdf"));
+        assert!(!name.contains('/'));
+        assert!(get_file_name("x", "xps").ends_with("-x.xps"));
+    }
+}
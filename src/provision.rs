@@ -0,0 +1,98 @@
+// The settings of a host exported with `--export-config <file>` and imported
+// with `--import-config <file>`, here and in the service, and the provisioning
+// of the installed hosts rolled out with a provision.toml in a folder only the
+// administrators can write to: the install folder on Windows, /etc/rustdesk on
+// Linux and /Library/Application Support/RustDesk on macOS. It is applied when
+// the service starts, once, and again only if it is changed, so that hundreds
+// of hosts get their servers, password and permissions silently.
+use hbb_common::{
+    config::{Config, Provisioning, APP_NAME},
+    log, ResultType,
+};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const FILE_NAME: &str = "provision.toml";
+// the hash of the provision.toml applied
+const APPLIED: &str = "provisioned";
+
+/// A provisioning file, or else a config file of before, e.g. by the installer.
+pub fn import_config(path: &str) -> ResultType<()> {
+    let text = std::fs::read_to_string(path)?;
+    match Provisioning::parse(&text) {
+        Some(p) => {
+            apply(&p);
+            // to the service, which has a config of its own
+            crate::ipc::set_options(p.merge_options(crate::ipc::get_options()))?;
+            if !p.password_hash.is_empty() {
+                crate::ipc::set_permanent_password_hash(p.password_salt, p.password_hash)?;
+            }
+        }
+        None => Config::import(path),
+    }
+    Ok(())
+}
+
+/// With the hash of the permanent password, so only readable by the owner.
+pub fn export_config(path: &str) -> ResultType<()> {
+    let text = Config::get_provisioning().to_toml();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, text.as_bytes())?;
+    log::info!("config exported to {}", path);
+    Ok(())
+}
+
+/// The provision.toml found applied, if not yet.
+pub fn apply_file() {
+    let path = match get_path().filter(|x| x.exists()) {
+        Some(x) => x,
+        None => return,
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("Failed to read {}: {}", path.display(), err);
+            return;
+        }
+    };
+    let hash = format!("{:x}", Sha256::digest(text.as_bytes()));
+    if Config::get_option(APPLIED) == hash {
+        return;
+    }
+    match Provisioning::parse(&text) {
+        Some(p) => {
+            apply(&p);
+            Config::set_option(APPLIED.to_owned(), hash);
+            log::info!("provisioned with {}", path.display());
+        }
+        None => log::error!("Invalid provisioning file {}", path.display()),
+    }
+}
+
+fn apply(p: &Provisioning) {
+    Config::set_options(p.merge_options(Config::get_options()));
+    if !p.password_hash.is_empty() {
+        Config::set_permanent_password_hash(&p.password_salt, &p.password_hash);
+    }
+}
+
+// not next to the executable, or in the config folder, which a user may write
+// to, e.g. the one of the user for the service on Linux
+fn get_path() -> Option<PathBuf> {
+    if !crate::platform::is_installed() {
+        return None;
+    }
+    #[cfg(windows)]
+    let dir = PathBuf::from(crate::platform::windows::get_install_info().1);
+    #[cfg(target_os = "macos")]
+    let dir = PathBuf::from("/Library/Application Support").join(APP_NAME);
+    #[cfg(target_os = "linux")]
+    let dir = PathBuf::from("/etc").join(APP_NAME.to_lowercase());
+    Some(dir.join(FILE_NAME))
+}
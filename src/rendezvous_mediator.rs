@@ -2,9 +2,11 @@ use crate::server::{check_zombie, new as new_server, ServerPtr};
 use hbb_common::{
     allow_err,
     anyhow::bail,
+    bytes::BytesMut,
     config::{self, Config, RENDEZVOUS_PORT, RENDEZVOUS_TIMEOUT},
     futures::future::join_all,
-    log,
+    log, message_proto,
+    port_mapping::{self, PortMapping},
     protobuf::Message as _,
     rendezvous_proto::*,
     sleep, socket_client,
@@ -12,11 +14,12 @@ use hbb_common::{
         self, select,
         time::{interval, Duration},
     },
-    udp::{self, FramedSocket},
+    udp::{self, FramedSocket, MulticastSocket},
     AddrMangle, IntoTargetAddr, ResultType, TargetAddr,
 };
 use std::{
-    net::{SocketAddr, SocketAddrV4},
+    collections::HashMap,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
@@ -29,9 +32,25 @@ type Message = RendezvousMessage;
 
 lazy_static::lazy_static! {
     static ref SOLVING_PK_MISMATCH: Arc<Mutex<String>> = Default::default();
+    static ref MAPPED_ADDR: Arc<Mutex<Option<SocketAddr>>> = Default::default();
+    // largest udp payload which got through to the rendezvous server
+    static ref PATH_MTU: Arc<Mutex<Option<usize>>> = Default::default();
 }
 static SHOULD_EXIT: AtomicBool = AtomicBool::new(false);
 const REG_INTERVAL: i64 = 12_000;
+// the peers of an online query, and the ms its answer is waited for
+const ONLINE_BATCH: usize = 100;
+const ONLINE_TIMEOUT: u64 = 3_000;
+// udp payload within the minimum MTU every host must accept (576 / 1280),
+// and within ethernet, after the IP and udp headers
+const MIN_DATAGRAM_V4: usize = 548;
+const MIN_DATAGRAM_V6: usize = 1232;
+const MAX_DATAGRAM_V4: usize = 1472;
+const MAX_DATAGRAM_V6: usize = 1452;
+const PMTU_PROBE_TIMEOUT: u64 = 500;
+const FAILOVER_PROBE_TIMEOUT: u64 = 3_000;
+// how often a server before the active one in the custom list is probed to fail back
+const FAILBACK_INTERVAL: i64 = 30_000;
 
 #[derive(Clone)]
 pub struct RendezvousMediator {
@@ -60,8 +79,18 @@ impl RendezvousMediator {
             allow_err!(direct_server(server_cloned).await);
         });
         tokio::spawn(async move {
-            allow_err!(lan_discovery().await);
+            port_mapping().await;
         });
+        for family in get_lan_families() {
+            tokio::spawn(async move {
+                allow_err!(lan_discovery(family).await);
+            });
+        }
+        if crate::lan::is_mdns_enabled() {
+            tokio::spawn(async move {
+                allow_err!(crate::lan::start_mdns().await);
+            });
+        }
         loop {
             Config::reset_online();
             if Config::get_option("stop-service").is_empty() {
@@ -72,14 +101,21 @@ impl RendezvousMediator {
                 let mut futs = Vec::new();
                 let servers = Config::get_rendezvous_servers();
                 SHOULD_EXIT.store(false, Ordering::SeqCst);
-                for host in servers.clone() {
+                if is_failover_list(&servers) {
                     let server = server.clone();
-                    let servers = servers.clone();
                     futs.push(tokio::spawn(async move {
-                        allow_err!(Self::start(server, host, servers).await);
-                        // SHOULD_EXIT here is to ensure once one exits, the others also exit.
-                        SHOULD_EXIT.store(true, Ordering::SeqCst);
+                        allow_err!(Self::start_failover(server, servers).await);
                     }));
+                } else {
+                    for host in servers.clone() {
+                        let server = server.clone();
+                        let servers = servers.clone();
+                        futs.push(tokio::spawn(async move {
+                            allow_err!(Self::start(server, host, servers, None).await);
+                            // SHOULD_EXIT here is to ensure once one exits, the others also exit.
+                            SHOULD_EXIT.store(true, Ordering::SeqCst);
+                        }));
+                    }
                 }
                 join_all(futs).await;
             }
@@ -87,10 +123,48 @@ impl RendezvousMediator {
         }
     }
 
+    /// Register with one server of a custom list at a time: the first one in list
+    /// order which answers, failing over to the next once it stops answering and
+    /// back once a server before it answers again.
+    async fn start_failover(server: ServerPtr, servers: Vec<String>) -> ResultType<()> {
+        loop {
+            if Config::get_rendezvous_servers() != servers
+                || !Config::get_option("stop-service").is_empty()
+                || SHOULD_EXIT.load(Ordering::SeqCst)
+            {
+                return Ok(());
+            }
+            let latencies = probe_rendezvous_servers(&servers).await;
+            let i = match latencies.iter().position(|x| x.is_some()) {
+                Some(i) => i,
+                None => {
+                    log::info!("None of the rendezvous servers answered: {:?}", servers);
+                    for host in servers.iter() {
+                        Config::update_latency(host, -1);
+                    }
+                    sleep(FAILOVER_PROBE_TIMEOUT as f32 / 1000.).await;
+                    continue;
+                }
+            };
+            let host = servers[i].clone();
+            log::info!("Active rendezvous server: {}, probed {:?}", host, latencies);
+            for (j, other) in servers.iter().enumerate() {
+                if j != i {
+                    Config::update_latency(other, -1);
+                }
+            }
+            let preferred = servers[..i].to_vec();
+            Self::start(server.clone(), host, servers.clone(), Some(preferred)).await?;
+        }
+    }
+
+    /// `failover` is the servers before `host` in a custom list, the mediator
+    /// exits if `host` stops answering or one of them answers again.
     pub async fn start(
         server: ServerPtr,
         host: String,
         rendezvous_servers: Vec<String>,
+        failover: Option<Vec<String>>,
     ) -> ResultType<()> {
         log::info!("start rendezvous mediator of {}", host);
         let host_prefix: String = host
@@ -112,9 +186,13 @@ impl RendezvousMediator {
             last_id_pk_registry: "".to_owned(),
         };
 
-        rz.addr = socket_client::get_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT))?;
+        rz.addr =
+            socket_client::resolve_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT)).await?;
         let any_addr = Config::get_any_listen_addr();
         let mut socket = socket_client::new_udp(any_addr, RENDEZVOUS_TIMEOUT).await?;
+        if PATH_MTU.lock().unwrap().is_none() {
+            rz.probe_path_mtu(&mut socket).await;
+        }
 
         const TIMER_OUT: Duration = Duration::from_secs(1);
         let mut timer = interval(TIMER_OUT);
@@ -129,6 +207,8 @@ impl RendezvousMediator {
         let mut last_dns_check = SystemTime::UNIX_EPOCH;
         let mut old_latency = 0;
         let mut ema_latency = 0;
+        let mut last_failback_check = SystemTime::now();
+        let failback = Arc::new(AtomicBool::new(false));
         loop {
             let mut update_latency = || {
                 last_register_resp = SystemTime::now();
@@ -164,6 +244,7 @@ impl RendezvousMediator {
                                 match msg_in.union {
                                     Some(rendezvous_message::Union::register_peer_response(rpr)) => {
                                         update_latency();
+                                        udp::set_trace_peer(&rz.addr, rpr.trace);
                                         if rpr.request_pk {
                                             log::info!("request_pk received from {}", host);
                                             allow_err!(rz.register_pk(&mut socket).await);
@@ -231,12 +312,31 @@ impl RendezvousMediator {
                     if SHOULD_EXIT.load(Ordering::SeqCst) {
                         break;
                     }
+                    if failback.load(Ordering::SeqCst) {
+                        log::info!("Fail back from rendezvous server {}", host);
+                        Config::update_latency(&host, -1);
+                        break;
+                    }
                     let now = SystemTime::now();
                     if now.duration_since(last_timer).map(|d| d < TIMER_OUT).unwrap_or(false) {
                         // a workaround of tokio timer bug
                         continue;
                     }
                     last_timer = now;
+                    match &failover {
+                        Some(preferred) if !preferred.is_empty()
+                            && now.duration_since(last_failback_check).map(|d| d.as_millis() as i64).unwrap_or(0) >= FAILBACK_INTERVAL => {
+                            last_failback_check = now;
+                            let preferred = preferred.clone();
+                            let failback = failback.clone();
+                            tokio::spawn(async move {
+                                if probe_rendezvous_servers(&preferred).await.iter().any(|x| x.is_some()) {
+                                    failback.store(true, Ordering::SeqCst);
+                                }
+                            });
+                        }
+                        _ => {}
+                    }
                     let elapsed_resp = now.duration_since(last_register_resp).map(|d| d.as_millis() as i64).unwrap_or(REG_INTERVAL);
                     let timeout = last_register_sent.duration_since(last_register_resp).map(|d| d.as_millis() as i64).unwrap_or(0) >= REG_TIMEOUT;
                     if timeout || elapsed_resp >= REG_INTERVAL {
@@ -247,8 +347,12 @@ impl RendezvousMediator {
                             if fails > MAX_FAILS2 {
                                 Config::update_latency(&host, -1);
                                 old_latency = 0;
+                                if failover.is_some() {
+                                    log::info!("Fail over from rendezvous server {}", host);
+                                    break;
+                                }
                                 if now.duration_since(last_dns_check).map(|d| d.as_millis() as i64).unwrap_or(0) > DNS_INTERVAL {
-                                    rz.addr = socket_client::get_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT))?;
+                                    rz.addr = socket_client::resolve_target_addr(&crate::check_port(&host, RENDEZVOUS_PORT)).await?;
                                     // in some case of network reconnect (dial IP network),
                                     // old UDP socket not work any more after network recover
                                     if let Some(s) = socket_client::rebind_udp(any_addr).await? {
@@ -290,6 +394,7 @@ impl RendezvousMediator {
         initiate: bool,
     ) -> ResultType<()> {
         let peer_addr = AddrMangle::decode(&socket_addr);
+        let relay_server = crate::client::Client::select_relay_server(&relay_server).await;
         log::info!(
             "create_relay requested from from {:?}, relay_server: {}, uuid: {}, secure: {}",
             peer_addr,
@@ -346,6 +451,7 @@ impl RendezvousMediator {
             local_addr: AddrMangle::encode(local_addr),
             relay_server,
             version: crate::VERSION.to_owned(),
+            quic: is_quic_direct_server(),
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
@@ -368,7 +474,8 @@ impl RendezvousMediator {
                 .await;
         }
         let peer_addr = AddrMangle::decode(&ph.socket_addr);
-        log::debug!("Punch hole to {:?}", peer_addr);
+        let tcp_punch = ph.tcp_punch && is_tcp_punch_enabled();
+        log::debug!("Punch hole to {:?}, tcp punch: {}", peer_addr, tcp_punch);
         let mut socket = {
             let socket = socket_client::connect_tcp(
                 self.addr.to_owned(),
@@ -376,8 +483,10 @@ impl RendezvousMediator {
                 RENDEZVOUS_TIMEOUT,
             )
             .await?;
-            let local_addr = socket.local_addr();
-            allow_err!(socket_client::connect_tcp(peer_addr, local_addr, 300).await);
+            if !tcp_punch {
+                let local_addr = socket.local_addr();
+                allow_err!(socket_client::connect_tcp(peer_addr, local_addr, 300).await);
+            }
             socket
         };
         let mut msg_out = Message::new();
@@ -389,14 +498,62 @@ impl RendezvousMediator {
             relay_server,
             nat_type: nat_type.into(),
             version: crate::VERSION.to_owned(),
+            tcp_punch,
+            quic: is_quic_direct_server(),
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
         socket.send_raw(bytes).await?;
-        crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        if tcp_punch {
+            crate::punch_connection(server.clone(), socket, peer_addr, true).await;
+        } else {
+            crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        }
         Ok(())
     }
 
+    /// Find the largest registration the path to the rendezvous server takes,
+    /// with padded register_peer messages which the server answers as usual.
+    async fn probe_path_mtu(&self, socket: &mut FramedSocket) {
+        let (min, max) = match self.addr {
+            TargetAddr::Ip(SocketAddr::V6(_)) => (MIN_DATAGRAM_V6, MAX_DATAGRAM_V6),
+            _ => (MIN_DATAGRAM_V4, MAX_DATAGRAM_V4),
+        };
+        let id = Config::get_id();
+        let serial = Config::get_serial();
+        let res = socket
+            .probe_pmtu(self.addr.to_owned(), min, max, PMTU_PROBE_TIMEOUT, |size| {
+                let mut rp = RegisterPeer {
+                    id: id.clone(),
+                    serial,
+                    ..Default::default()
+                };
+                // register_peer tag and length, padding tag and length
+                let overhead = rp.compute_size() as usize + 8;
+                rp.padding = vec![0; size.saturating_sub(overhead)];
+                let mut msg_out = Message::new();
+                msg_out.set_register_peer(rp);
+                Ok(msg_out.write_to_bytes()?.into())
+            })
+            .await;
+        match res {
+            Ok(size) => {
+                log::info!("Path MTU to {}: {} bytes of udp payload", self.host, size);
+                *PATH_MTU.lock().unwrap() = Some(size);
+            }
+            Err(err) => {
+                log::debug!("Path MTU probing to {} skipped: {}", self.host, err);
+            }
+        }
+    }
+
+    fn fits_path_mtu(msg: &impl hbb_common::protobuf::Message) -> bool {
+        match *PATH_MTU.lock().unwrap() {
+            Some(size) => msg.compute_size() as usize <= size,
+            None => true,
+        }
+    }
+
     async fn register_pk(&mut self, socket: &mut FramedSocket) -> ResultType<()> {
         let mut msg_out = Message::new();
         let pk = Config::get_key_pair().1;
@@ -414,6 +571,13 @@ impl RendezvousMediator {
             pk,
             ..Default::default()
         });
+        if !Self::fits_path_mtu(&msg_out) {
+            log::warn!(
+                "register_pk of {} bytes exceeds the path MTU to {}",
+                msg_out.compute_size(),
+                self.host
+            );
+        }
         socket.send(&msg_out, self.addr.to_owned()).await?;
         Ok(())
     }
@@ -455,19 +619,83 @@ impl RendezvousMediator {
         );
         let mut msg_out = Message::new();
         let serial = Config::get_serial();
-        msg_out.set_register_peer(RegisterPeer {
+        let mapped_addr = MAPPED_ADDR
+            .lock()
+            .unwrap()
+            .map(AddrMangle::encode)
+            .unwrap_or_default();
+        let mut rp = RegisterPeer {
             id,
             serial,
+            mapped_addr,
+            trace: true,
             ..Default::default()
-        });
-        socket.send(&msg_out, self.addr.to_owned()).await?;
+        };
+        if !Self::fits_path_mtu(&rp) {
+            // the mapped address is optional, better registered without it than not at all
+            rp.mapped_addr.clear();
+        }
+        msg_out.set_register_peer(rp);
+        if log::log_enabled!(log::Level::Trace) {
+            // followed on both ends if the server takes the trace header too
+            let trace_id = Uuid::new_v4().as_u128() as u64;
+            socket.send_traced(&msg_out, self.addr.to_owned(), trace_id).await?;
+        } else {
+            socket.send(&msg_out, self.addr.to_owned()).await?;
+        }
         Ok(())
     }
 }
 
+// the direct server takes QUIC too, told to the peers when punching the hole
+fn is_quic_direct_server() -> bool {
+    cfg!(feature = "quic") && !Config::get_option("direct-server").is_empty()
+}
+
+// listening only while the direct server is on, as the tcp one
+#[cfg(feature = "quic")]
+async fn direct_server_quic(server: ServerPtr, addr: SocketAddr) -> ResultType<()> {
+    let mut listener = None;
+    loop {
+        if !is_quic_direct_server() {
+            if listener.take().is_some() {
+                log::info!("Direct server stopped (QUIC)");
+            }
+            sleep(1.).await;
+            continue;
+        }
+        if listener.is_none() {
+            let l = hbb_common::quic::new_listener(addr)?;
+            log::info!("Direct server listening on: {} (QUIC)", l.local_addr()?);
+            listener = Some(l);
+        }
+        if let Some(l) = listener.as_mut() {
+            // the failed handshakes are logged by the listener, only its end is here
+            if let Ok(res) = hbb_common::timeout(1000, l.accept()).await {
+                let (stream, addr) = res?;
+                log::info!("direct access from {} (QUIC)", addr);
+                let server = server.clone();
+                tokio::spawn(async move {
+                    allow_err!(
+                        crate::server::create_tcp_connection(server, stream, addr, false).await
+                    );
+                });
+            }
+        }
+    }
+}
+
 async fn direct_server(server: ServerPtr) -> ResultType<()> {
     let port = RENDEZVOUS_PORT + 2;
     let addr = format!("0.0.0.0:{}", port);
+    #[cfg(feature = "quic")]
+    {
+        let server = server.clone();
+        let addr = addr.parse()?;
+        tokio::spawn(async move {
+            allow_err!(direct_server_quic(server, addr).await);
+        });
+    }
     let mut listener = None;
     loop {
         if !Config::get_option("direct-server").is_empty() && listener.is_none() {
@@ -505,11 +733,162 @@ async fn direct_server(server: ServerPtr) -> ResultType<()> {
     }
 }
 
+const PORT_MAPPING_LIFETIME: u64 = 3600;
+// seconds before another try after a failure, doubled on each one in a row
+const PORT_MAPPING_RETRY: u64 = 60;
+const PORT_MAPPING_MAX_RETRY: u64 = 3600;
+
+// map the direct server port on the router with UPnP / NAT-PMP, and keep it
+// renewed while enabled, the mapped address goes with our registration
+async fn port_mapping() {
+    let mut mapping: Option<PortMapping> = None;
+    let mut next_renew = Instant::now();
+    let mut retry = PORT_MAPPING_RETRY;
+    loop {
+        let enabled = Config::get_option("enable-upnp") == "Y"
+            && !Config::get_option("direct-server").is_empty();
+        if !enabled {
+            if let Some(m) = mapping.take() {
+                log::info!("remove port mapping {}", m.external);
+                allow_err!(m.remove().await);
+                *MAPPED_ADDR.lock().unwrap() = None;
+            }
+        } else if Instant::now() >= next_renew {
+            let res = match mapping.as_mut() {
+                Some(m) => m.renew().await,
+                None => port_mapping::map_port(
+                    (RENDEZVOUS_PORT + 2) as _,
+                    port_mapping::Protocol::Tcp,
+                    std::time::Duration::from_secs(PORT_MAPPING_LIFETIME),
+                )
+                .await
+                .map(|m| mapping = Some(m)),
+            };
+            match (res, mapping.as_ref()) {
+                (Ok(_), Some(m)) => {
+                    log::info!("port mapping {} -> {}", m.external, m.local_port);
+                    *MAPPED_ADDR.lock().unwrap() = Some(m.external);
+                    next_renew = Instant::now() + m.lifetime / 2;
+                    retry = PORT_MAPPING_RETRY;
+                }
+                (res, _) => {
+                    if let Err(err) = res {
+                        log::info!("port mapping failed: {}", err);
+                    }
+                    mapping = None;
+                    *MAPPED_ADDR.lock().unwrap() = None;
+                    next_renew = Instant::now() + std::time::Duration::from_secs(retry);
+                    retry = (retry * 2).min(PORT_MAPPING_MAX_RETRY);
+                }
+            }
+        }
+        sleep(3.).await;
+    }
+}
+
 pub fn get_multicast_addr() -> SocketAddrV4 {
     let port = (RENDEZVOUS_PORT + 3) as u16;
     SocketAddrV4::new([239, 255, 42, 98].into(), port)
 }
 
+pub fn get_multicast_addr_v6() -> SocketAddrV6 {
+    let port = (RENDEZVOUS_PORT + 3) as u16;
+    SocketAddrV6::new([0xff02, 0, 0, 0, 0, 0, 0x42, 0x2a62].into(), port, 0, 0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LanFamily {
+    V4,
+    V6,
+}
+
+impl LanFamily {
+    fn multicast_addr(self) -> SocketAddr {
+        match self {
+            LanFamily::V4 => SocketAddr::V4(get_multicast_addr()),
+            LanFamily::V6 => SocketAddr::V6(get_multicast_addr_v6()),
+        }
+    }
+
+    fn bind(self, listen: bool) -> ResultType<MulticastSocket> {
+        match self {
+            LanFamily::V4 => MulticastSocket::new(if listen {
+                Some(get_multicast_addr())
+            } else {
+                None
+            }),
+            LanFamily::V6 => Ok(MulticastSocket::from_socket(udp::bind_multicast_v6(
+                if listen {
+                    Some(get_multicast_addr_v6())
+                } else {
+                    None
+                },
+                0,
+            )?)),
+        }
+    }
+}
+
+// "ipv4" (default), "ipv6" or "both"
+pub fn get_lan_families() -> Vec<LanFamily> {
+    match Config::get_option("lan-discovery-family").as_str() {
+        "ipv6" => vec![LanFamily::V6],
+        "both" => vec![LanFamily::V4, LanFamily::V6],
+        _ => vec![LanFamily::V4],
+    }
+}
+
+// a custom rendezvous server setting listing more than one server
+fn is_failover_list(servers: &[String]) -> bool {
+    servers.len() > 1 && !Config::get_option("custom-rendezvous-server").is_empty()
+}
+
+/// Send a registration to each of `servers` at the same time and wait for the
+/// answers, the latency of those who answered.
+async fn probe_rendezvous_servers(servers: &[String]) -> Vec<Option<Duration>> {
+    let futs = servers.iter().map(|host| {
+        let host = host.clone();
+        tokio::spawn(async move {
+            let res = probe_rendezvous_server(&host).await;
+            if let Err(err) = &res {
+                log::debug!("Failed to probe rendezvous server {}: {}", host, err);
+            }
+            res.ok().flatten()
+        })
+    });
+    join_all(futs)
+        .await
+        .into_iter()
+        .map(|x| x.ok().flatten())
+        .collect()
+}
+
+async fn probe_rendezvous_server(host: &str) -> ResultType<Option<Duration>> {
+    let addr =
+        socket_client::resolve_target_addr(&crate::check_port(host, RENDEZVOUS_PORT)).await?;
+    let mut socket =
+        socket_client::new_udp(Config::get_any_listen_addr(), RENDEZVOUS_TIMEOUT).await?;
+    let mut msg_out = Message::new();
+    msg_out.set_register_peer(RegisterPeer {
+        id: Config::get_id(),
+        serial: Config::get_serial(),
+        ..Default::default()
+    });
+    let tm = Instant::now();
+    socket.send(&msg_out, addr).await?;
+    match socket.next_timeout(FAILOVER_PROBE_TIMEOUT).await {
+        Some(Ok(_)) => Ok(Some(tm.elapsed())),
+        Some(Err(err)) => Err(err),
+        None => Ok(None),
+    }
+}
+
+/// TCP simultaneous open with the peer when hole punching, on by default.
+#[inline]
+pub fn is_tcp_punch_enabled() -> bool {
+    Config::get_option("enable-tcp-punch").is_empty()
+}
+
 pub fn get_mac() -> String {
     if let Ok(Some(mac)) = mac_address::get_mac_address() {
         mac.to_string()
@@ -518,16 +897,57 @@ pub fn get_mac() -> String {
     }
 }
 
-async fn lan_discovery() -> ResultType<()> {
-    let mut socket = udp::bind_multicast(Some(get_multicast_addr()))?;
-    log::info!("lan discovery listener started");
+// seconds between multicast membership checks, 0 to disable
+fn get_multicast_health_interval() -> u64 {
+    Config::get_option("multicast-health-interval")
+        .parse()
+        .unwrap_or(30)
+}
+
+async fn lan_discovery(family: LanFamily) -> ResultType<()> {
+    let mut socket = family.bind(true)?;
+    log::info!("lan discovery listener started ({:?})", family);
+    let health_interval = get_multicast_health_interval();
+    let mut health_timer = interval(Duration::from_secs(health_interval.max(1)));
+    // the probe sent to the group at the last check, looped back to this
+    // socket as long as the membership holds
+    let mut probe: Option<String> = None;
     loop {
         select! {
+            _ = health_timer.tick(), if health_interval > 0 => {
+                if probe.take().is_some() {
+                    log::info!("lan discovery multicast probe not looped back, rejoining");
+                    allow_err!(socket.reset_membership());
+                } else {
+                    // a join failing with "in use" tells the membership is there
+                    match socket.rejoin() {
+                        Ok(true) => log::info!("lan discovery multicast membership lost, rejoined"),
+                        Ok(false) => {}
+                        Err(err) => log::debug!("failed to rejoin lan discovery group: {}", err),
+                    }
+                }
+                let nonce = Uuid::new_v4().to_string();
+                let mut msg_out = Message::new();
+                msg_out.set_peer_discovery(PeerDiscovery {
+                    cmd: "probe".to_owned(),
+                    misc: nonce.clone(),
+                    ..Default::default()
+                });
+                match socket.send(&msg_out, family.multicast_addr()).await {
+                    Ok(_) => probe = Some(nonce),
+                    Err(err) => log::debug!("failed to send lan discovery probe: {}", err),
+                }
+            }
             Some(Ok((bytes, addr))) = socket.next() => {
                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                     match msg_in.union {
                         Some(rendezvous_message::Union::peer_discovery(p)) => {
-                            if p.cmd == "ping" {
+                            if p.cmd == "probe" {
+                                if probe.as_ref() == Some(&p.misc) {
+                                    probe = None;
+                                }
+                            } else if p.cmd == "ping" {
+                                udp::set_trace_peer(&addr, p.trace);
                                 let mut msg_out = Message::new();
                                 let peer = PeerDiscovery {
                                     cmd: "pong".to_owned(),
@@ -536,6 +956,7 @@ async fn lan_discovery() -> ResultType<()> {
                                     hostname: whoami::hostname(),
                                     username: crate::platform::get_active_username(),
                                     platform: whoami::platform().to_string(),
+                                    trace: true,
                                     ..Default::default()
                                 };
                                 msg_out.set_peer_discovery(peer);
@@ -551,43 +972,91 @@ async fn lan_discovery() -> ResultType<()> {
     }
 }
 
+/// The peers online, asked to the rendezvous server in batches on one
+/// connection, so that hundreds of them take a few round trips. The servers
+/// of before do not answer it.
+#[tokio::main(flavor = "current_thread")]
+pub async fn query_online_states(ids: Vec<String>) -> ResultType<HashMap<String, bool>> {
+    let rendezvous_server = crate::get_rendezvous_server(1_000).await;
+    let mut socket = socket_client::connect_tcp(
+        &*rendezvous_server,
+        Config::get_any_listen_addr(),
+        RENDEZVOUS_TIMEOUT,
+    )
+    .await?;
+    let mut states = HashMap::new();
+    for batch in ids.chunks(ONLINE_BATCH) {
+        let mut msg_out = Message::new();
+        msg_out.set_online_request(OnlineRequest {
+            id: Config::get_id(),
+            peers: batch.to_vec(),
+            ..Default::default()
+        });
+        socket.send(&msg_out).await?;
+        let bytes = match socket.next_timeout(ONLINE_TIMEOUT).await {
+            Some(Ok(bytes)) => bytes,
+            _ => bail!("No online states from the rendezvous server"),
+        };
+        let res = match Message::parse_from_bytes(&bytes)?.union {
+            Some(rendezvous_message::Union::online_response(res)) => res,
+            _ => bail!("No online states from the rendezvous server"),
+        };
+        for (i, id) in batch.iter().enumerate() {
+            let byte = res.states.get(i / 8).cloned().unwrap_or(0);
+            states.insert(id.clone(), byte & (0x80 >> (i % 8)) != 0);
+        }
+    }
+    Ok(states)
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn discover() -> ResultType<()> {
-    let mut socket = udp::bind_multicast(None)?;
+    let mut sockets = Vec::new();
     let mut msg_out = Message::new();
     let peer = PeerDiscovery {
         cmd: "ping".to_owned(),
+        trace: true,
         ..Default::default()
     };
     msg_out.set_peer_discovery(peer);
-    let maddr = SocketAddr::V4(get_multicast_addr());
-    socket.send(&msg_out, maddr).await?;
+    for family in get_lan_families() {
+        match family.bind(false) {
+            Ok(mut socket) => {
+                socket.send(&msg_out, family.multicast_addr()).await?;
+                sockets.push(socket);
+            }
+            Err(err) => log::error!("failed to bind {:?} multicast: {}", family, err),
+        }
+    }
+    if sockets.is_empty() {
+        bail!("no lan discovery socket");
+    }
+    let mut socket = sockets.remove(0);
+    let mut socket_v6 = sockets.pop();
     log::debug!("discover ping sent");
+    let mut mdns = None;
+    if crate::lan::is_mdns_enabled() {
+        match crate::lan::query().await {
+            Ok(socket) => mdns = Some(socket),
+            Err(err) => log::debug!("failed to send the mdns query: {}", err),
+        }
+    }
     const TIMER_OUT: Duration = Duration::from_millis(100);
     let mut timer = interval(TIMER_OUT);
     let mut last_recv_time = Instant::now();
     let mut last_write_time = Instant::now();
     let mut last_write_n = 0;
     // to-do: load saved peers, and update incrementally (then we can see offline)
-    let mut peers = Vec::new();
+    let mut peers: Vec<(String, String, String, String)> = Vec::new();
+    let mut macs = Vec::new();
     let mac = get_mac();
     loop {
-        select! {
-            Some(Ok((bytes, _))) = socket.next() => {
-                if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
-                    match msg_in.union {
-                        Some(rendezvous_message::Union::peer_discovery(p)) => {
-                            last_recv_time = Instant::now();
-                            if p.cmd == "pong" {
-                                if p.mac != mac {
-                                    peers.push((p.id, p.username, p.hostname, p.platform));
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            },
+        let found = select! {
+            Some(Ok((bytes, addr))) = socket.next() => parse_peer_discovery(&bytes, &addr),
+            Some(Ok((bytes, addr))) = next_opt(&mut socket_v6) => {
+                parse_peer_discovery(&bytes, &addr)
+            }
+            Some(Ok((bytes, _))) = next_opt(&mut mdns) => crate::lan::parse_response(&bytes),
             _ = timer.tick() => {
                 if last_write_time.elapsed().as_millis() > 300 && last_write_n != peers.len() {
                     config::LanPeers::store(serde_json::to_string(&peers)?);
@@ -597,10 +1066,94 @@ pub async fn discover() -> ResultType<()> {
                 if last_recv_time.elapsed().as_millis() > 3_000 {
                     break;
                 }
+                continue;
+            }
+        };
+        for p in found {
+            last_recv_time = Instant::now();
+            // the same peer may answer on both ipv4 and ipv6, and by mdns
+            if p.cmd == "pong" && p.mac != mac && !peers.iter().any(|x| x.0 == p.id) {
+                if !p.mac.is_empty() {
+                    macs.push((p.id.clone(), p.mac));
+                }
+                peers.push((p.id, p.username, p.hostname, p.platform));
             }
         }
     }
     log::debug!("discover ping done");
     config::LanPeers::store(serde_json::to_string(&peers)?);
+    config::LanPeers::store_macs(macs);
+    Ok(())
+}
+
+// the answer to the ping of `discover`, or any other discovery message
+fn parse_peer_discovery(bytes: &[u8], addr: &TargetAddr) -> Vec<PeerDiscovery> {
+    match Message::parse_from_bytes(bytes).map(|msg| msg.union) {
+        Ok(Some(rendezvous_message::Union::peer_discovery(p))) => {
+            udp::set_trace_peer(addr, p.trace);
+            vec![p]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Wake up a peer found on the LAN before, with the magic packet of its mac
+/// address broadcast here and by the first peer online of the last discovery.
+#[tokio::main(flavor = "current_thread")]
+pub async fn wake_up(id: String) -> ResultType<()> {
+    let lan = config::LanPeers::load();
+    let mac = match lan.macs.get(&id) {
+        Some(mac) => mac.clone(),
+        None => bail!("no mac address of {}", id),
+    };
+    // this side may be on that LAN too
+    allow_err!(wake_on_lan(&mac).await);
+    let peers: Vec<(String, String, String, String)> =
+        serde_json::from_str(&lan.peers).unwrap_or_default();
+    for (relay, ..) in peers.iter().filter(|x| x.0 != id) {
+        match request_wake_on_lan(relay, &mac).await {
+            Ok(()) => {
+                log::info!("{} woken up through {}", id, relay);
+                return Ok(());
+            }
+            Err(err) => log::debug!("failed to wake up {} through {}: {}", id, relay, err),
+        }
+    }
+    bail!("no peer online on the LAN of {}", id);
+}
+
+async fn request_wake_on_lan(relay: &str, mac: &str) -> ResultType<()> {
+    let (mut stream, _) = crate::client::Client::start(relay, ConnType::DEFAULT_CONN).await?;
+    let mut msg_out = message_proto::Message::new();
+    msg_out.set_wake_on_lan(message_proto::WakeOnLan {
+        mac: mac.to_owned(),
+        ..Default::default()
+    });
+    stream.send(&msg_out).await?;
     Ok(())
 }
+
+/// Broadcast the magic packet of `mac` on the LAN: 6 bytes of 0xff and 16 times
+/// the mac address.
+pub async fn wake_on_lan(mac: &str) -> ResultType<()> {
+    let mac = mac.parse::<mac_address::MacAddress>()?.bytes();
+    let mut packet = vec![0xffu8; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    let mut socket = FramedSocket::new("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_raw(packet, SocketAddr::from(([255, 255, 255, 255], 9)))
+        .await?;
+    Ok(())
+}
+
+async fn next_opt(
+    socket: &mut Option<MulticastSocket>,
+) -> Option<ResultType<(BytesMut, TargetAddr<'static>)>> {
+    match socket {
+        Some(socket) => socket.next().await,
+        None => std::future::pending().await,
+    }
+}
@@ -1,4 +1,5 @@
 use crate::ipc::Data;
+pub use connection::{add_switch_sides, get_sessions, send_to_session, take_switch_sides_back};
 use connection::{ConnInner, Connection};
 use hbb_common::{
     allow_err,
@@ -21,12 +22,25 @@ use std::{
     sync::{Arc, Mutex, RwLock, Weak},
 };
 
+mod acl;
+mod annotation;
 mod audio_service;
 mod clipboard_service;
+mod congestion;
 mod connection;
+mod gamepad_service;
+mod hooks;
+mod login_guard;
+mod metrics;
+mod password_rotation;
 pub mod input_service;
+mod print_service;
 mod service;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod terminal;
 mod video_service;
+#[cfg(any(windows, target_os = "linux"))]
+mod virtual_display;
 
 use hbb_common::tcp::new_listener;
 
@@ -57,10 +71,16 @@ pub fn new() -> ServerPtr {
     server.add_service(Box::new(clipboard_service::new()));
     server.add_service(Box::new(input_service::new_cursor()));
     server.add_service(Box::new(input_service::new_pos()));
+    server.add_service(Box::new(print_service::new()));
     Arc::new(RwLock::new(server))
 }
 
-async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+async fn accept_connection_(
+    server: ServerPtr,
+    socket: Stream,
+    secure: bool,
+    punch_to: Option<SocketAddr>,
+) -> ResultType<()> {
     let local_addr = socket.local_addr();
     drop(socket);
     // even we drop socket, below still may fail if not use reuse_addr,
@@ -68,9 +88,24 @@ async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) ->
     // see “Only one usage of each socket address is normally permitted” on windows sometimes,
     let listener = new_listener(local_addr, true).await?;
     log::info!("Server listening on: {}", &listener.local_addr()?);
-    if let Ok((stream, addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
+    let accept = async {
+        let (stream, addr) = listener.accept().await?;
         let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), addr, secure).await?;
+        Ok::<_, hbb_common::anyhow::Error>((Stream::from(stream, stream_addr), addr))
+    };
+    let res = if let Some(peer_addr) = punch_to {
+        // whichever gets through first, the peer's SYN or ours
+        tokio::select! {
+            res = timeout(CONNECT_TIMEOUT, accept) => res?,
+            res = socket_client::punch_tcp(peer_addr, local_addr, CONNECT_TIMEOUT) => {
+                res.map(|stream| (stream, peer_addr))
+            }
+        }
+    } else {
+        timeout(CONNECT_TIMEOUT, accept).await?
+    };
+    if let Ok((stream, addr)) = res {
+        create_tcp_connection(server, stream, addr, secure).await?;
     }
     Ok(())
 }
@@ -82,6 +117,7 @@ pub async fn create_tcp_connection(
     secure: bool,
 ) -> ResultType<()> {
     let mut stream = stream;
+    metrics::add(&metrics::CONNECTIONS, 1);
     let id = {
         let mut w = server.write().unwrap();
         w.id_count += 1;
@@ -153,11 +189,24 @@ pub async fn accept_connection(
     peer_addr: SocketAddr,
     secure: bool,
 ) {
-    if let Err(err) = accept_connection_(server, socket, secure).await {
+    if let Err(err) = accept_connection_(server, socket, secure, None).await {
         log::error!("Failed to accept connection from {}: {}", peer_addr, err);
     }
 }
 
+/// Like `accept_connection`, but also connect out to `peer_addr` from the same port
+/// at the same time, for the peer doing TCP simultaneous open.
+pub async fn punch_connection(
+    server: ServerPtr,
+    socket: Stream,
+    peer_addr: SocketAddr,
+    secure: bool,
+) {
+    if let Err(err) = accept_connection_(server, socket, secure, Some(peer_addr)).await {
+        log::error!("Failed to punch connection with {}: {}", peer_addr, err);
+    }
+}
+
 pub async fn create_relay_connection(
     server: ServerPtr,
     relay_server: String,
@@ -196,6 +245,7 @@ async fn create_relay_connection_(
         ..Default::default()
     });
     stream.send(&msg_out).await?;
+    metrics::add(&metrics::RELAY_CONNECTIONS, 1);
     create_tcp_connection(server, stream, peer_addr, secure).await?;
     Ok(())
 }
@@ -269,6 +319,7 @@ pub async fn start_server(is_server: bool, _tray: bool) {
         log::info!("XAUTHORITY={:?}", std::env::var("XAUTHORITY"));
     }
     if is_server {
+        crate::provision::apply_file();
         std::thread::spawn(move || {
             if let Err(err) = crate::ipc::start("") {
                 log::error!("Failed to start ipc: {}", err);
@@ -276,6 +327,8 @@ pub async fn start_server(is_server: bool, _tray: bool) {
             }
         });
         input_service::fix_key_down_timeout_loop();
+        password_rotation::start_timer_loop();
+        metrics::start();
         crate::RendezvousMediator::start_all().await;
     } else {
         match crate::ipc::connect(1000, "").await {
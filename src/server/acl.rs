@@ -0,0 +1,138 @@
+// Access control of the incoming connections, checked before the login:
+// "whitelist" is the allow list and "blacklist" the deny list, both comma
+// separated rules, each an ip, a CIDR range, e.g. 10.0.0.0/8 or fe80::/10, or
+// a peer id pattern with * and ?, e.g. 1234*. A deny rule always wins, and an
+// allow list with rules of a kind has to match one of them, "0.0.0.0" letting
+// any ip in as before.
+use hbb_common::{config::Config, fs::glob_match};
+use std::net::IpAddr;
+
+enum Rule {
+    Cidr(IpAddr, u8),
+    Id(String),
+}
+
+pub fn check_ip(ip: IpAddr) -> bool {
+    let ip = normalize(ip);
+    check(|rule| match rule {
+        Rule::Cidr(net, prefix) => Some(in_cidr(ip, *net, *prefix)),
+        Rule::Id(_) => None,
+    })
+}
+
+pub fn check_id(id: &str) -> bool {
+    check(|rule| match rule {
+        Rule::Cidr(..) => None,
+        Rule::Id(pattern) => Some(is_match(pattern, id)),
+    })
+}
+
+// `f` tells if a rule of the kind checked matches, None for the other kind
+fn check(f: impl Fn(&Rule) -> Option<bool>) -> bool {
+    if get_rules("blacklist").iter().any(|x| f(x) == Some(true)) {
+        return false;
+    }
+    let mut allowed = None;
+    for rule in get_rules("whitelist").iter() {
+        match f(rule) {
+            Some(true) => return true,
+            Some(false) => allowed = Some(false),
+            None => {}
+        }
+    }
+    allowed.unwrap_or(true)
+}
+
+fn get_rules(name: &str) -> Vec<Rule> {
+    Config::get_option(name)
+        .split(",")
+        .filter_map(|x| parse_rule(x.trim()))
+        .collect()
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    if rule.is_empty() {
+        return None;
+    }
+    let (addr, prefix) = match rule.find('/') {
+        Some(i) => match rule[i + 1..].parse::<u8>() {
+            Ok(prefix) => (&rule[..i], Some(prefix)),
+            Err(_) => return None,
+        },
+        None => (rule, None),
+    };
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        let ip = normalize(ip);
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if prefix > max => return None,
+            Some(prefix) => prefix,
+            // the legacy "allow any" of the whitelist
+            None if ip == IpAddr::from([0, 0, 0, 0]) => 0,
+            None => max,
+        };
+        return Some(Rule::Cidr(ip, prefix));
+    }
+    Some(Rule::Id(rule.to_owned()))
+}
+
+// the ipv4 clients on a dual stack listener come as ::ffff:a.b.c.d
+fn normalize(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        let o = v6.octets();
+        if o[..10].iter().all(|x| *x == 0) && o[10] == 0xff && o[11] == 0xff {
+            return IpAddr::from([o[12], o[13], o[14], o[15]]);
+        }
+    }
+    ip
+}
+
+fn in_cidr(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let (ip, net, bits) = match (ip, net) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix as u32;
+    ip >> shift == net >> shift
+}
+
+// glob match of a peer id, * for any characters and ? for one
+fn is_match(pattern: &str, id: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let id: Vec<char> = id.chars().collect();
+    glob_match(&pattern, &id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(rule: &str, ip: &str) -> bool {
+        match parse_rule(rule) {
+            Some(Rule::Cidr(net, prefix)) => in_cidr(normalize(ip.parse().unwrap()), net, prefix),
+            _ => panic!("not a cidr: {}", rule),
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        assert!(cidr("10.0.0.0/8", "10.1.2.3"));
+        assert!(!cidr("10.0.0.0/8", "11.1.2.3"));
+        assert!(cidr("192.168.1.7", "192.168.1.7"));
+        assert!(!cidr("192.168.1.7", "192.168.1.8"));
+        assert!(cidr("192.168.1.0/24", "::ffff:192.168.1.8"));
+        assert!(cidr("0.0.0.0", "fe80::1"));
+        assert!(cidr("fe80::/10", "fe80::1"));
+        assert!(!cidr("fe80::/10", "10.1.2.3"));
+        assert!(parse_rule("10.0.0.0/33").is_none());
+        assert!(matches!(parse_rule("123456789"), Some(Rule::Id(_))));
+        assert!(is_match("1234*", "123456789"));
+        assert!(is_match("12?4*9", "123456789"));
+        assert!(!is_match("1234*", "12356789"));
+        assert!(!is_match("12345678", "123456789"));
+    }
+}
@@ -0,0 +1,96 @@
+// The annotations of the sessions drawn over the displays of the host, in a
+// window excluded from the capture: the arrows and circles kept until cleared,
+// the last MAX_SHAPES of a session, and the dot of the laser pointer, hidden
+// LASER_TIMEOUT after its last move. The coordinates are those of the virtual
+// screen, as the mouse events' are.
+use crate::platform::{OverlayShape, OVERLAY_ARROW, OVERLAY_CIRCLE, OVERLAY_LASER};
+use hbb_common::message_proto::{annotation, Annotation};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const MAX_SHAPES: usize = 64;
+const LASER_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Shapes {
+    shapes: Vec<OverlayShape>,
+    laser: Option<(OverlayShape, Instant)>,
+}
+
+lazy_static::lazy_static! {
+    // by connection
+    static ref SHAPES: Mutex<HashMap<i32, Shapes>> = Default::default();
+}
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+pub fn handle(conn: i32, a: &Annotation) {
+    let mut all = SHAPES.lock().unwrap();
+    let s = all.entry(conn).or_default();
+    let shape = |kind| OverlayShape {
+        kind,
+        x0: a.x0,
+        y0: a.y0,
+        x1: a.x1,
+        y1: a.y1,
+        color: a.color & 0xFFFFFF,
+    };
+    match a.shape.enum_value() {
+        Ok(annotation::Shape::Laser) => s.laser = Some((shape(OVERLAY_LASER), Instant::now())),
+        Ok(annotation::Shape::Arrow) => s.shapes.push(shape(OVERLAY_ARROW)),
+        Ok(annotation::Shape::Circle) => s.shapes.push(shape(OVERLAY_CIRCLE)),
+        Ok(annotation::Shape::Clear) => *s = Default::default(),
+        Err(_) => return,
+    }
+    if s.shapes.len() > MAX_SHAPES {
+        s.shapes.drain(..s.shapes.len() - MAX_SHAPES);
+    }
+    let laser = s.laser.is_some();
+    update(&all);
+    if laser && !WATCHING.swap(true, Ordering::SeqCst) {
+        std::thread::spawn(watch_laser);
+    }
+}
+
+pub fn clear(conn: i32) {
+    let mut all = SHAPES.lock().unwrap();
+    if all.remove(&conn).is_some() {
+        update(&all);
+    }
+}
+
+fn update(all: &HashMap<i32, Shapes>) {
+    let mut shapes = Vec::new();
+    for s in all.values() {
+        shapes.extend(s.shapes.iter().cloned());
+        shapes.extend(s.laser.iter().map(|x| x.0));
+    }
+    crate::platform::show_overlay(&shapes);
+}
+
+// the lasers hidden once not moved, till there is none left
+fn watch_laser() {
+    loop {
+        std::thread::sleep(Duration::from_millis(200));
+        let mut all = SHAPES.lock().unwrap();
+        let mut changed = false;
+        for s in all.values_mut() {
+            if matches!(s.laser, Some((_, t)) if t.elapsed() > LASER_TIMEOUT) {
+                s.laser = None;
+                changed = true;
+            }
+        }
+        if changed {
+            update(&all);
+        }
+        if all.values().all(|s| s.laser.is_none()) {
+            WATCHING.store(false, Ordering::SeqCst);
+            return;
+        }
+    }
+}
@@ -13,10 +13,15 @@
 // https://github.com/krruzic/pulsectl
 
 use super::*;
-use magnum_opus::{Application::*, Channels::*, Encoder};
+use audio_settings::Mode;
+use magnum_opus::{Application, Bitrate, Channels::*, Encoder};
 
 pub const NAME: &'static str = "audio";
 
+lazy_static::lazy_static! {
+    static ref SETTINGS: Arc<Mutex<HashMap<i32, AudioSettings>>> = Default::default();
+}
+
 #[cfg(not(target_os = "linux"))]
 pub fn new() -> GenericService {
     let sp = GenericService::new(NAME, true);
@@ -41,7 +46,8 @@ mod pa_impl {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
         }
-        let mut encoder = Encoder::new(crate::platform::linux::PA_SAMPLE_RATE, Stereo, LowDelay)?;
+        let mut settings = get_settings();
+        let mut encoder = AudioEncoder::new(crate::platform::linux::PA_SAMPLE_RATE, 2, &settings)?;
         allow_err!(
             stream
                 .send(&crate::ipc::Data::Config((
@@ -55,6 +61,10 @@ mod pa_impl {
                 sps.send(create_format_msg(crate::platform::linux::PA_SAMPLE_RATE, 2));
                 Ok(())
             })?;
+            if get_settings() != settings {
+                settings = get_settings();
+                encoder = AudioEncoder::new(crate::platform::linux::PA_SAMPLE_RATE, 2, &settings)?;
+            }
             if let Some(data) = stream.next_timeout2(1000).await {
                 match data? {
                     Some(crate::ipc::Data::RawMessage(bytes)) => {
@@ -86,6 +96,7 @@ mod cpal_impl {
     #[derive(Default)]
     pub struct State {
         stream: Option<(Box<dyn StreamTrait>, Arc<Message>)>,
+        settings: Option<Settings>,
     }
 
     impl super::service::Reset for State {
@@ -95,10 +106,16 @@ mod cpal_impl {
     }
 
     pub fn run(sp: GenericService, state: &mut State) -> ResultType<()> {
+        let settings = get_settings();
+        if state.settings != Some(settings) {
+            // a new encoder in a new stream
+            state.stream.take();
+            state.settings = Some(settings);
+        }
         sp.snapshot(|sps| {
             match &state.stream {
                 None => {
-                    state.stream = Some(play(&sp)?);
+                    state.stream = Some(play(&sp, &settings)?);
                 }
                 _ => {}
             }
@@ -115,7 +132,7 @@ mod cpal_impl {
         sample_rate0: u32,
         sample_rate: u32,
         channels: u16,
-        encoder: &mut Encoder,
+        encoder: &mut AudioEncoder,
         sp: &GenericService,
     ) {
         let buffer;
@@ -187,7 +204,10 @@ mod cpal_impl {
         Ok((device, format))
     }
 
-    fn play(sp: &GenericService) -> ResultType<(Box<dyn StreamTrait>, Arc<Message>)> {
+    fn play(
+        sp: &GenericService,
+        settings: &Settings,
+    ) -> ResultType<(Box<dyn StreamTrait>, Arc<Message>)> {
         let (device, config) = get_device()?;
         let sp = sp.clone();
         let err_fn = move |err| {
@@ -211,12 +231,8 @@ mod cpal_impl {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
         }
-        let mut encoder = Encoder::new(
-            sample_rate,
-            if config.channels() > 1 { Stereo } else { Mono },
-            LowDelay,
-        )?;
         let channels = config.channels();
+        let mut encoder = AudioEncoder::new(sample_rate, channels, settings)?;
         let stream = match config.sample_format() {
             cpal::SampleFormat::F32 => device.build_input_stream(
                 &config.into(),
@@ -290,7 +306,108 @@ fn create_format_msg(sample_rate: u32, channels: u16) -> Message {
 const MAX_AUDIO_ZERO_COUNT: u16 = 800;
 static mut AUDIO_ZERO_COUNT: u16 = 0;
 
-fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
+/// Per connection, the encoder is shared and runs with the lowest latency, the
+/// highest bitrate and the shortest frames asked.
+pub fn update_settings(id: i32, settings: Option<AudioSettings>) {
+    match settings {
+        Some(settings) => {
+            SETTINGS.lock().unwrap().insert(id, settings);
+        }
+        None => {
+            SETTINGS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Settings {
+    mode: Mode,
+    // in bits per second, 0 to let opus decide
+    bitrate: u32,
+    frame_ms: u32,
+}
+
+// "audio-mode" ("low-latency", "balanced" or "music"), "audio-bitrate" in kbps
+// and "audio-frame-ms" here are the defaults of what the connections leave unset.
+fn get_settings() -> Settings {
+    let mut mode = match Config::get_option("audio-mode").as_str() {
+        "low-latency" => Mode::LowLatency,
+        "music" => Mode::Music,
+        _ => Mode::Balanced,
+    };
+    let mut bitrate = Config::get_option("audio-bitrate").parse().unwrap_or(0);
+    let mut frame_ms = Config::get_option("audio-frame-ms").parse().unwrap_or(0);
+    let settings = SETTINGS.lock().unwrap();
+    let modes = settings.values().map(|s| s.mode.enum_value_or_default());
+    if let Some(m) = modes
+        .filter(|m| *m != Mode::NotSet)
+        .min_by_key(|m| *m as i32)
+    {
+        mode = m;
+    }
+    if let Some(b) = settings.values().map(|s| s.bitrate).max() {
+        if b > 0 {
+            bitrate = b;
+        }
+    }
+    if let Some(f) = settings
+        .values()
+        .map(|s| s.frame_ms)
+        .filter(|f| *f > 0)
+        .min()
+    {
+        frame_ms = f;
+    }
+    if ![5, 10, 20, 40, 60].contains(&frame_ms) {
+        frame_ms = match mode {
+            Mode::LowLatency => 5,
+            Mode::Music => 20,
+            _ => 10,
+        };
+    }
+    if bitrate == 0 && mode == Mode::Music {
+        bitrate = 128;
+    }
+    Settings {
+        mode,
+        bitrate: bitrate * 1000,
+        frame_ms,
+    }
+}
+
+// opus takes frames of a few fixed lengths, the captured audio is cut to them
+struct AudioEncoder {
+    encoder: Encoder,
+    buffer: Vec<f32>,
+    // of all the channels
+    frame_size: usize,
+}
+
+impl AudioEncoder {
+    fn new(sample_rate: u32, channels: u16, settings: &Settings) -> ResultType<Self> {
+        log::info!("Audio encoder: {:?}", settings);
+        let application = if settings.mode == Mode::Music {
+            Application::Audio
+        } else {
+            Application::LowDelay
+        };
+        let mut encoder = Encoder::new(
+            sample_rate,
+            if channels > 1 { Stereo } else { Mono },
+            application,
+        )?;
+        if settings.bitrate > 0 {
+            encoder.set_bitrate(Bitrate::Bits(settings.bitrate as _))?;
+        }
+        Ok(Self {
+            encoder,
+            buffer: Vec::new(),
+            frame_size: (sample_rate * settings.frame_ms / 1000) as usize * channels as usize,
+        })
+    }
+}
+
+fn send_f32(data: &[f32], encoder: &mut AudioEncoder, sp: &GenericService) {
     if data.iter().filter(|x| **x != 0.).next().is_some() {
         unsafe {
             AUDIO_ZERO_COUNT = 0;
@@ -307,16 +424,24 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
             AUDIO_ZERO_COUNT += 1;
         }
     }
-    match encoder.encode_vec_float(data, data.len() * 6) {
-        Ok(data) => {
-            let mut msg_out = Message::new();
-            msg_out.set_audio_frame(AudioFrame {
-                data,
-                ..Default::default()
-            });
-            sp.send(msg_out);
+    encoder.buffer.extend_from_slice(data);
+    while encoder.buffer.len() >= encoder.frame_size {
+        let rest = encoder.buffer.split_off(encoder.frame_size);
+        match encoder
+            .encoder
+            .encode_vec_float(&encoder.buffer, encoder.frame_size * 6)
+        {
+            Ok(data) => {
+                let mut msg_out = Message::new();
+                msg_out.set_audio_frame(AudioFrame {
+                    data,
+                    ..Default::default()
+                });
+                sp.send(msg_out);
+            }
+            Err(_) => {}
         }
-        Err(_) => {}
+        encoder.buffer = rest;
     }
 }
 
@@ -77,9 +77,8 @@ mod listen {
             }
 
             sp.snapshot(|sps| {
-                let txt = crate::CONTENT.lock().unwrap().clone();
-                if !txt.is_empty() {
-                    let msg_out = crate::create_clipboard_msg(txt);
+                let content = crate::CONTENT.lock().unwrap().clone();
+                if let Some(msg_out) = content.to_msg() {
                     sps.send_shared(Arc::new(msg_out));
                 }
                 Ok(())
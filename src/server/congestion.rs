@@ -0,0 +1,229 @@
+use hbb_common::tokio::time::{Duration, Instant};
+use std::collections::VecDeque;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+// delivery rate samples, one per update
+const BW_WINDOW: usize = 10;
+// probe for more bandwidth one interval, drain the queue this built the next
+const PACING_GAINS: [f32; 8] = [1.25, 0.75, 1., 1., 1., 1., 1., 1.];
+const STARTUP_GAIN: f32 = 2.;
+const DRAIN_GAIN: f32 = 0.75;
+const QUEUE_DELAY_THRESHOLD: Duration = Duration::from_millis(100);
+const RTT_INFLATION_THRESHOLD: Duration = Duration::from_millis(100);
+const LOSS_THRESHOLD: f32 = 0.1;
+const MIN_KBPS: u32 = 64;
+
+/// Per connection bandwidth estimation, BBR like but driven by what the server
+/// sees: the bottleneck bandwidth is the max delivery rate of the recent link
+/// limited intervals, the target is that times a gain cycling to probe for more,
+/// and it backs off once the RTT or the send queue delay grows (a queue is
+/// building somewhere) or the udp media channel reports loss.
+pub struct CongestionController {
+    min_rtt: Option<(Duration, Instant)>,
+    rtt: Option<Duration>,
+    bytes: usize,
+    busy: Duration,
+    frames: usize,
+    queue_delay: Duration,
+    sent: u64,
+    lost: u64,
+    bw_samples: VecDeque<u32>,
+    startup: bool,
+    cycle: usize,
+    target: Option<u32>,
+    last_update: Instant,
+}
+
+impl Default for CongestionController {
+    fn default() -> Self {
+        Self {
+            min_rtt: None,
+            rtt: None,
+            bytes: 0,
+            busy: Duration::from_millis(0),
+            frames: 0,
+            queue_delay: Duration::from_millis(0),
+            sent: 0,
+            lost: 0,
+            bw_samples: Default::default(),
+            startup: true,
+            cycle: 0,
+            target: None,
+            last_update: Instant::now(),
+        }
+    }
+}
+
+impl CongestionController {
+    pub fn on_rtt(&mut self, rtt: Duration) {
+        let now = Instant::now();
+        match self.min_rtt {
+            Some((min_rtt, tm)) if min_rtt <= rtt && tm.elapsed() < MIN_RTT_WINDOW => {}
+            _ => self.min_rtt = Some((rtt, now)),
+        }
+        self.rtt = Some(rtt);
+    }
+
+    /// A frame of `bytes` was written, after waiting `queue_delay` since it was
+    /// captured and taking `busy` for the write itself.
+    pub fn on_frame_sent(&mut self, bytes: usize, busy: Duration, queue_delay: Duration) {
+        self.bytes += bytes;
+        self.busy += busy;
+        self.frames += 1;
+        self.queue_delay = self.queue_delay.max(queue_delay);
+    }
+
+    pub fn on_loss(&mut self, sent: u64, lost: u64) {
+        self.sent += sent;
+        self.lost += lost;
+    }
+
+    #[inline]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    #[inline]
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+
+    /// The target in kbps, if it changed since the last call.
+    pub fn update(&mut self) -> Option<u32> {
+        let interval = self.last_update.elapsed();
+        if interval < UPDATE_INTERVAL {
+            return None;
+        }
+        self.last_update = Instant::now();
+        if self.frames == 0 {
+            // nothing sent, nothing learned
+            self.reset_interval();
+            return None;
+        }
+        // the write only blocks once the socket buffer is full, i.e. the link
+        // limits us, otherwise the encoder does and the rate is just a lower bound
+        let link_limited = self.busy * 4 >= interval;
+        let rate = if link_limited {
+            kbps(self.bytes, self.busy)
+        } else {
+            kbps(self.bytes, interval)
+        };
+        let btl_bw = self.bw_samples.iter().cloned().max().unwrap_or(0);
+        if link_limited || rate > btl_bw {
+            self.bw_samples.push_back(rate);
+            if self.bw_samples.len() > BW_WINDOW {
+                self.bw_samples.pop_front();
+            }
+        }
+        let btl_bw = self.bw_samples.iter().cloned().max().unwrap_or(rate);
+        let rtt_inflated = match (self.rtt, self.min_rtt) {
+            (Some(rtt), Some((min_rtt, _))) => {
+                rtt > min_rtt.mul_f32(1.5) && rtt > min_rtt + RTT_INFLATION_THRESHOLD
+            }
+            _ => false,
+        };
+        let loss = if self.sent > 0 {
+            self.lost as f32 / self.sent as f32
+        } else {
+            0.
+        };
+        let congested =
+            self.queue_delay > QUEUE_DELAY_THRESHOLD || rtt_inflated || loss > LOSS_THRESHOLD;
+        let target = if congested {
+            self.startup = false;
+            self.cycle = 0;
+            let base = self.target.unwrap_or(btl_bw).min(btl_bw.max(MIN_KBPS));
+            base as f32 * DRAIN_GAIN
+        } else {
+            let gain = if self.startup {
+                STARTUP_GAIN
+            } else {
+                self.cycle = (self.cycle + 1) % PACING_GAINS.len();
+                PACING_GAINS[self.cycle]
+            };
+            if link_limited {
+                btl_bw as f32 * gain
+            } else {
+                // the encoder produced less than the link takes, keep the target
+                // so that a busy screen can use it again
+                (self.target.unwrap_or(0).max(btl_bw) as f32 * gain.max(1.)).max(rate as f32)
+            }
+        };
+        log::debug!(
+            "congestion: rate={}kbps, btl_bw={}kbps, rtt={:?}, min_rtt={:?}, queue_delay={:?}, loss={}, target={}kbps",
+            rate,
+            btl_bw,
+            self.rtt,
+            self.min_rtt.map(|x| x.0),
+            self.queue_delay,
+            loss,
+            target
+        );
+        self.reset_interval();
+        let target = (target as u32).max(MIN_KBPS);
+        if self.target == Some(target) {
+            return None;
+        }
+        self.target = Some(target);
+        Some(target)
+    }
+
+    fn reset_interval(&mut self) {
+        self.bytes = 0;
+        self.busy = Duration::from_millis(0);
+        self.frames = 0;
+        self.queue_delay = Duration::from_millis(0);
+        self.sent = 0;
+        self.lost = 0;
+    }
+}
+
+#[inline]
+fn kbps(bytes: usize, duration: Duration) -> u32 {
+    let ms = duration.as_millis().max(1) as usize;
+    (bytes * 8 / ms) as _
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(cc: &mut CongestionController, bytes: usize, busy_ms: u64, queue_ms: u64) {
+        for _ in 0..10 {
+            cc.on_frame_sent(
+                bytes / 10,
+                Duration::from_millis(busy_ms / 10),
+                Duration::from_millis(queue_ms),
+            );
+        }
+        cc.last_update -= UPDATE_INTERVAL;
+    }
+
+    #[test]
+    fn test_congestion_controller() {
+        let mut cc = CongestionController::default();
+        cc.on_rtt(Duration::from_millis(50));
+        // link limited at 1000kbps, startup doubles
+        interval(&mut cc, 125_000, 1_000, 10);
+        assert_eq!(cc.update(), Some(2_000));
+        // queue builds up, back off below the bottleneck bandwidth
+        interval(&mut cc, 125_000, 1_000, 500);
+        assert_eq!(cc.update(), Some(750));
+        interval(&mut cc, 125_000, 1_000, 500);
+        assert_eq!(cc.update(), Some(562));
+        // rtt inflation is congestion too
+        cc.on_rtt(Duration::from_millis(300));
+        interval(&mut cc, 125_000, 1_000, 10);
+        assert!(cc.update().unwrap() < 562);
+        // static screen, keep the target
+        cc.on_rtt(Duration::from_millis(50));
+        interval(&mut cc, 1_000, 10, 0);
+        let target = cc.update().unwrap();
+        interval(&mut cc, 1_000, 10, 0);
+        assert!(cc.update().unwrap_or(target) >= target);
+        // no frames, no update
+        cc.last_update -= UPDATE_INTERVAL;
+        assert_eq!(cc.update(), None);
+    }
+}
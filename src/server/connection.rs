@@ -1,25 +1,71 @@
+use super::acl;
+use super::congestion::CongestionController;
+use super::hooks;
 use super::input_service::*;
+use super::login_guard;
+use super::metrics;
+use super::password_rotation;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+use super::terminal::Terminal;
 use super::*;
-use crate::common::update_clipboard;
+use crate::audit;
+#[cfg(not(any(target_os = "android")))]
+use crate::client::VoiceCall;
+use crate::client::{Recorder, VoicePlayer};
+use crate::common::{
+    get_clipboard_chunk, get_max_frame_size, update_clipboard, update_clipboard_chunk,
+};
 use crate::ipc;
 use hbb_common::{
-    config::Config,
+    capabilities::{self, Capabilities},
+    config::{self, Config},
     fs,
     futures::{SinkExt, StreamExt},
-    sleep, timeout,
+    keepalive,
+    rudp::{self, ReliableChannel},
+    send_queue::{Priority, SendQueue},
+    sleep, sodiumoxide, timeout,
     tokio::{
-        net::TcpStream,
+        net::{TcpListener, TcpStream},
         sync::mpsc,
         time::{self, Duration, Instant, Interval},
     },
     tokio_util::codec::{BytesCodec, Framed},
+    totp,
+    udp::FramedSocket,
+    AddrMangle,
 };
 use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub type Sender = mpsc::Sender<(Instant, Arc<Message>)>;
 
-pub type Sender = mpsc::UnboundedSender<(Instant, Arc<Message>)>;
+// the messages of the services waiting for the connection, see ConnInner::send
+// for the ones after, and ConnInner::send_wait for the streams waiting for room
+const SEND_CHANNEL_SIZE: usize = 256;
+
+// the authorized remote desktop sessions, the host is locked or logged out by
+// the option "lock-on-disconnect" only when the last of them ends
+static DESKTOP_SESSIONS: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static::lazy_static! {
-    static ref LOGIN_FAILURES: Arc::<Mutex<HashMap<String, (i32, i32, i32)>>> = Default::default();
+    // resume token -> suspended connection waiting for a new stream
+    static ref SUSPENDED: Arc::<Mutex<HashMap<Vec<u8>, mpsc::UnboundedSender<super::Stream>>>> = Default::default();
+    // the connection with the privacy mode on, 0 for none
+    static ref PRIVACY_MODE_CONN: Mutex<i32> = Default::default();
+    // the desktop session controlling the host with the option "single-controller",
+    // and the sender to take the control from it
+    static ref CONTROLLER: Mutex<Option<(i32, mpsc::UnboundedSender<ipc::Data>)>> = Default::default();
+    // peer id -> (time given, one-time token it connects back with to switch sides,
+    // the public key of the peer signing it)
+    static ref SWITCH_SIDES: Mutex<HashMap<String, (Instant, String, Vec<u8>)>> = Default::default();
+    // peer id -> (time given, the token of the peer signed here) to connect back with
+    static ref SWITCH_SIDES_BACK: Mutex<HashMap<String, (Instant, String)>> = Default::default();
+    // the connections shown in the cm, for the status of the service, with the
+    // sender to them as from the cm
+    static ref SESSIONS: Mutex<HashMap<i32, (ipc::SessionStatus, mpsc::UnboundedSender<ipc::Data>)>> = Default::default();
+    // source ip -> time of its last Wake-on-LAN
+    static ref WAKE_ON_LAN: Mutex<HashMap<String, Instant>> = Default::default();
 }
 
 #[derive(Clone, Default)]
@@ -27,6 +73,9 @@ pub struct ConnInner {
     id: i32,
     tx: Option<Sender>,
     tx_video: Option<Sender>,
+    // a message which can not be dropped found the queue full, the connection
+    // is closed then
+    overflow: Arc<AtomicBool>,
 }
 
 pub struct Connection {
@@ -35,23 +84,74 @@ pub struct Connection {
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    // of the services, see send_queued
+    send_queue: SendQueue<(Instant, Arc<Message>)>,
+    search_jobs: Vec<fs::SearchJob>,
     timer: Interval,
+    session_timer: Interval,
+    last_input: Instant,
+    session_start: Option<Instant>,
+    timeout_warned: bool,
     file_transfer: Option<(String, bool)>,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
     port_forward_address: String,
+    // the streams of the dynamic and the reverse port forwarding by id
+    tunnels: Option<HashMap<u32, mpsc::UnboundedSender<Tunnel>>>,
+    reverse_forward: bool,
+    // of the reverse port forwarding once authorized, with the last id of its streams
+    reverse_listener: Option<(TcpListener, u32)>,
+    // (rows, cols) the peer asked a shell of, instead of the desktop
+    terminal_size: Option<(u32, u32)>,
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    shell: Option<Terminal>,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
+    tx_from_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
     keyboard: bool,
     clipboard: bool,
     audio: bool,
-    last_test_delay: i64,
+    microphone: bool,
+    file: bool,
+    tunnel: bool,
+    terminal: bool,
+    view_only: bool,
     image_quality: i32,
     lock_after_session_end: bool,
     show_remote_cursor: bool, // by peer
     privacy_mode: bool,
+    block_input: bool,
     ip: String,
+    // the hook of the login called, for the one of the end
+    accepted: bool,
+    // of the session once accepted, written to the audit log at its end
+    audit: audit::Entry,
     disable_clipboard: bool, // by peer
+    // by peer
+    clipboard_direction: option_message::ClipboardDirection,
     disable_audio: bool,     // by peer
+    enable_printer: bool,    // by peer
+    max_bandwidth_kbps: u32, // by peer
+    media: Option<ReliableChannel>,
+    congestion: CongestionController,
+    resume_token: Vec<u8>,
+    resume_to: Option<mpsc::UnboundedSender<super::Stream>>,
+    supported_decoding: SupportedDecoding, // by peer
+    request_stats: bool,                   // by peer
+    // (sent, lost) packets of the media channel since the last stats
+    media_loss: (u64, u64),
+    // (id, name) the peer logged in with
+    peer: (String, String),
+    capabilities: Capabilities, // by peer
+    // of the current display while the session is recorded here for audit
+    record_size: Option<(usize, usize)>,
+    // with the start time, opened on the first frame after `record_size` is set
+    recorder: Option<(Recorder, i64)>,
+    audio_format: Option<AudioFormat>,
+    // of the microphone of the peer, played while the permission is on
+    voice_format: Option<AudioFormat>,
+    voice: Option<VoicePlayer>,
+    #[cfg(not(any(target_os = "android")))]
+    voice_call: Option<VoiceCall>,
 }
 
 impl Subscriber for ConnInner {
@@ -60,25 +160,51 @@ impl Subscriber for ConnInner {
         self.id
     }
 
+    // Queued without waiting. If the connection is that far behind, a frame
+    // of the video is dropped and a key frame requested to go on from, and a
+    // frame of the audio is dropped too. The other messages can not be lost,
+    // so the connection is closed instead.
     #[inline]
     fn send(&mut self, msg: Arc<Message>) {
-        match &msg.union {
-            Some(message::Union::video_frame(_)) => {
-                self.tx_video.as_mut().map(|tx| {
-                    allow_err!(tx.send((Instant::now(), msg)));
-                });
-            }
-            _ => {
-                self.tx.as_mut().map(|tx| {
-                    allow_err!(tx.send((Instant::now(), msg)));
-                });
+        let video = matches!(msg.union, Some(message::Union::video_frame(_)));
+        let audio = matches!(msg.union, Some(message::Union::audio_frame(_)));
+        let tx = if video { &self.tx_video } else { &self.tx };
+        if let Some(tx) = tx {
+            if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send((Instant::now(), msg)) {
+                if video {
+                    log::debug!("#{} send queue full, video frame dropped", self.id);
+                    video_service::request_key_frame(self.id);
+                } else if audio {
+                    log::debug!("#{} send queue full, audio frame dropped", self.id);
+                } else if !self.overflow.swap(true, Ordering::SeqCst) {
+                    log::warn!("#{} send queue full, closing the connection", self.id);
+                }
             }
         }
     }
 }
 
-const TEST_DELAY_TIMEOUT: Duration = Duration::from_secs(3);
+impl ConnInner {
+    // As send, for the streams, e.g. of the tunnels and the terminal, which
+    // can not lose any, waiting for room instead. Never by the connection,
+    // which drains the channel.
+    fn send_wait(&mut self, msg: Arc<Message>) {
+        if let Some(tx) = self.tx.as_ref() {
+            let value = (Instant::now(), msg);
+            let res = if tokio::runtime::Handle::try_current().is_ok() {
+                tokio::task::block_in_place(|| tx.blocking_send(value))
+            } else {
+                tx.blocking_send(value)
+            };
+            res.ok();
+        }
+    }
+}
+
 const SEC30: Duration = Duration::from_secs(30);
+const SEC5: Duration = Duration::from_secs(5);
+// how long before the session time limits the peer is warned
+const TIMEOUT_WARNING: Duration = Duration::from_secs(60);
 const H1: Duration = Duration::from_secs(3600);
 const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
@@ -94,39 +220,80 @@ impl Connection {
         let hash = Hash {
             salt: Config::get_salt(),
             challenge: Config::get_auto_password(),
+            kdf_salt: Config::get_password_hash().map(|x| x.0).unwrap_or_default(),
             ..Default::default()
         };
         let (tx_from_cm, mut rx_from_cm) = mpsc::unbounded_channel::<ipc::Data>();
         let (tx_to_cm, rx_to_cm) = mpsc::unbounded_channel::<ipc::Data>();
-        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
-        let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
+        let (tx, mut rx) = mpsc::channel::<(Instant, Arc<Message>)>(SEND_CHANNEL_SIZE);
+        let (tx_video, mut rx_video) = mpsc::channel::<(Instant, Arc<Message>)>(SEND_CHANNEL_SIZE);
         let mut conn = Self {
             inner: ConnInner {
                 id,
                 tx: Some(tx),
                 tx_video: Some(tx_video),
+                overflow: Default::default(),
             },
             stream,
             server,
             hash,
             read_jobs: Vec::new(),
+            search_jobs: Vec::new(),
+            send_queue: Default::default(),
             timer: time::interval(SEC30),
+            session_timer: time::interval(SEC5),
+            last_input: Instant::now(),
+            session_start: None,
+            timeout_warned: false,
             file_transfer: None,
             port_forward_socket: None,
             port_forward_address: "".to_owned(),
+            tunnels: None,
+            reverse_forward: false,
+            reverse_listener: None,
+            terminal_size: None,
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            shell: None,
             tx_to_cm,
+            tx_from_cm: tx_from_cm.clone(),
             authorized: false,
             keyboard: Config::get_option("enable-keyboard").is_empty(),
             clipboard: Config::get_option("enable-clipboard").is_empty(),
             audio: Config::get_option("audio-input") != "Mute",
-            last_test_delay: 0,
+            microphone: Config::get_option("enable-microphone").is_empty(),
+            file: Config::get_option("enable-file-transfer").is_empty(),
+            tunnel: Config::get_option("enable-tunnel").is_empty(),
+            terminal: Config::get_option("allow-terminal") == "Y",
+            view_only: false,
             image_quality: ImageQuality::Balanced.value(),
             lock_after_session_end: false,
             show_remote_cursor: false,
             privacy_mode: false,
+            block_input: false,
             ip: "".to_owned(),
+            accepted: false,
             disable_audio: false,
             disable_clipboard: false,
+            enable_printer: false,
+            clipboard_direction: Default::default(),
+            max_bandwidth_kbps: 0,
+            media: None,
+            congestion: Default::default(),
+            resume_token: Vec::new(),
+            resume_to: None,
+            supported_decoding: Default::default(),
+            request_stats: false,
+            media_loss: (0, 0),
+            peer: Default::default(),
+            capabilities: Default::default(),
+            audit: Default::default(),
+            record_size: None,
+            recorder: None,
+            audio_format: None,
+            voice_format: None,
+            voice: None,
+            #[cfg(not(any(target_os = "android")))]
+            voice_call: None,
         };
         tokio::spawn(async move {
             if let Err(err) = start_ipc(rx_to_cm, tx_from_cm).await {
@@ -136,26 +303,12 @@ impl Connection {
         if !conn.on_open(addr).await {
             return;
         }
-        if !conn.keyboard {
-            conn.send_permission(Permission::Keyboard, false).await;
-        }
-        if !conn.clipboard {
-            conn.send_permission(Permission::Clipboard, false).await;
-        }
-        if !conn.audio {
-            conn.send_permission(Permission::Audio, false).await;
-        }
-        let mut test_delay_timer =
-            time::interval_at(Instant::now() + TEST_DELAY_TIMEOUT, TEST_DELAY_TIMEOUT);
-        let mut last_recv_time = Instant::now();
+        conn.send_disabled_permissions().await;
+        let keepalive = conn.stream.keepalive().interval();
+        let mut keepalive_timer = time::interval_at(Instant::now() + keepalive, keepalive);
 
-        conn.stream.set_send_timeout(
-            if conn.file_transfer.is_some() || conn.port_forward_socket.is_some() {
-                SEND_TIMEOUT_OTHER
-            } else {
-                SEND_TIMEOUT_VIDEO
-            },
-        );
+        conn.set_send_timeout();
+        conn.stream.set_max_frame_size(get_max_frame_size());
 
         loop {
             tokio::select! {
@@ -165,6 +318,7 @@ impl Connection {
                     match data {
                         ipc::Data::Authorize => {
                             conn.send_logon_response().await;
+                            conn.update_session_status();
                             if conn.port_forward_socket.is_some() {
                                 break;
                             }
@@ -175,13 +329,29 @@ impl Connection {
                             let mut msg_out = Message::new();
                             msg_out.set_misc(misc);
                             conn.send(msg_out).await;
-                            conn.on_close("Close requested from connection manager", false);
+                            conn.on_close(CLOSED_BY_CM, false);
                             break;
                         }
-                        ipc::Data::ChatMessage{text} => {
+                        ipc::Data::ChatMessage{text, id, file} => {
                             let mut misc = Misc::new();
                             misc.set_chat_message(ChatMessage {
                                 text,
+                                id,
+                                file,
+                                ..Default::default()
+                            });
+                            let mut msg_out = Message::new();
+                            msg_out.set_misc(misc);
+                            conn.send(msg_out).await;
+                        }
+                        ipc::Data::ChatReceipt{ids, read} => {
+                            if !conn.capabilities.has(capabilities::CHAT_RECEIPT) {
+                                continue;
+                            }
+                            let mut misc = Misc::new();
+                            misc.set_chat_receipt(ChatReceipt {
+                                ids,
+                                read,
                                 ..Default::default()
                             });
                             let mut msg_out = Message::new();
@@ -192,7 +362,19 @@ impl Connection {
                             log::info!("Change permission {} -> {}", name, enabled);
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
+                                if enabled {
+                                    conn.take_control(true);
+                                } else {
+                                    conn.release_control();
+                                }
+                                // the cm is told too, the control may be taken by another session
+                                conn.send_to_cm(ipc::Data::SwitchPermission{name: name.clone(), enabled});
                                 conn.send_permission(Permission::Keyboard, enabled).await;
+                                if !enabled && conn.privacy_mode {
+                                    conn.set_privacy_mode(false);
+                                } else if !enabled && conn.block_input {
+                                    conn.update_block_input();
+                                }
                                 if let Some(s) = conn.server.upgrade() {
                                     s.write().unwrap().subscribe(
                                         NAME_CURSOR,
@@ -204,7 +386,7 @@ impl Connection {
                                 if let Some(s) = conn.server.upgrade() {
                                     s.write().unwrap().subscribe(
                                         super::clipboard_service::NAME,
-                                        conn.inner.clone(), conn.clipboard_enabled() && conn.keyboard);
+                                        conn.inner.clone(), conn.clipboard_to_client() && conn.keyboard);
                                 }
                             } else if &name == "audio" {
                                 conn.audio = enabled;
@@ -214,11 +396,25 @@ impl Connection {
                                         super::audio_service::NAME,
                                         conn.inner.clone(), conn.audio_enabled());
                                 }
+                            } else if &name == "microphone" {
+                                conn.microphone = enabled;
+                                conn.update_voice();
+                                // only the peers talking know about this permission
+                                if conn.voice_format.is_some() {
+                                    conn.send_permission(Permission::Microphone, enabled).await;
+                                }
                             }
+                            conn.update_session_status();
                         }
                         ipc::Data::RawMessage(bytes) => {
                             allow_err!(conn.stream.send_raw(bytes).await);
                         }
+                        ipc::Data::VoiceCallResponse(accepted) => {
+                            conn.handle_voice_call(accepted).await;
+                        }
+                        ipc::Data::CloseVoiceCall(_) => {
+                            conn.close_voice_call(true).await;
+                        }
                         _ => {}
                     }
                 },
@@ -226,12 +422,20 @@ impl Connection {
                     if let Some(res) = res {
                         match res {
                             Err(err) => {
+                                if conn.suspend(&err.to_string()).await {
+                                    continue;
+                                }
                                 conn.on_close(&err.to_string(), true);
                                 break;
                             },
                             Ok(bytes) => {
-                                last_recv_time = Instant::now();
+                                metrics::add(&metrics::BYTES_RECEIVED, bytes.len() as _);
                                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
+                                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                                    let msg_in = match conn.filter(false, msg_in) {
+                                        Some(msg_in) => msg_in,
+                                        None => continue,
+                                    };
                                     if !conn.on_message(msg_in).await {
                                         break;
                                     }
@@ -239,67 +443,125 @@ impl Connection {
                             }
                         }
                     } else {
+                        if conn.suspend("Reset by the peer").await {
+                            continue;
+                        }
                         conn.on_close("Reset by the peer", true);
                         break;
                     }
                 },
+                Ok((socket, addr)) = accept_reverse(&conn.reverse_listener) => {
+                    conn.accept_tunnel(socket, addr);
+                },
+                res = rudp::next_opt(&mut conn.media) => {
+                    // only acks are expected from the peer
+                    if let Err(err) = res {
+                        conn.close_media(&err.to_string());
+                    }
+                },
+                _ = conn.session_timer.tick() => {
+                    if !conn.check_session_timeout().await {
+                        break;
+                    }
+                },
                 _ = conn.timer.tick() => {
-                    if !conn.read_jobs.is_empty() {
+                    if !conn.read_jobs.is_empty() || !conn.search_jobs.is_empty() {
+                        // the messages waiting before the blocks of the files
+                        if let Err(err) = conn.send_queued(&mut rx).await {
+                            conn.on_close(&err.to_string(), false);
+                            break;
+                        }
                         if let Err(err) = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
                             conn.on_close(&err.to_string(), false);
                             break;
                         }
+                        if let Err(err) = fs::handle_search_jobs(&mut conn.search_jobs, &mut conn.stream).await {
+                            conn.on_close(&err.to_string(), false);
+                            break;
+                        }
                     } else {
                         conn.timer = time::interval_at(Instant::now() + SEC30, SEC30);
                     }
                 },
                 Some((instant, value)) = rx_video.recv() => {
                     video_service::notify_video_frame_feched(id, Some(instant.into()));
-                    if let Err(err) = conn.stream.send(&value as &Message).await {
+                    conn.record_video(&value);
+                    let queue_delay = instant.elapsed();
+                    let begin = Instant::now();
+                    if let Err(err) = conn.send_media(&value, video_service::get_fec_ratio()).await {
                         conn.on_close(&err.to_string(), false);
                         break;
                     }
+                    let size = value.compute_size();
+                    metrics::add(&metrics::BYTES_SENT, size as _);
+                    conn.congestion.on_frame_sent(size as _, begin.elapsed(), queue_delay);
+                    conn.update_congestion();
                 },
-                Some((instant, value)) = rx.recv() => {
-                    let latency = instant.elapsed().as_millis() as i64;
-                    let msg: &Message = &value;
-
-                    if latency > 1000 {
-                        match &msg.union {
-                            Some(message::Union::audio_frame(_)) => {
-                                // log::info!("audio frame latency {}", instant.elapsed().as_secs_f32());
-                                continue;
-                            }
-                            _ => {}
-                        }
+                Some(value) = rx.recv() => {
+                    if conn.inner.overflow.load(Ordering::SeqCst) {
+                        conn.on_close(SEND_QUEUE_FULL, false);
+                        break;
                     }
-                    if let Err(err) = conn.stream.send(msg).await {
+                    conn.queue(value);
+                    if let Err(err) = conn.send_queued(&mut rx).await {
                         conn.on_close(&err.to_string(), false);
                         break;
                     }
                 },
-                _ = test_delay_timer.tick() => {
-                    if last_recv_time.elapsed() >= SEC30 {
-                        conn.on_close("Timeout", true);
+                _ = keepalive_timer.tick() => {
+                    if conn.inner.overflow.load(Ordering::SeqCst) {
+                        conn.on_close(SEND_QUEUE_FULL, false);
                         break;
                     }
-                    let time = crate::get_time();
-                    if time > 0 && conn.last_test_delay == 0 {
-                        conn.last_test_delay = time;
-                        let mut msg_out = Message::new();
-                        msg_out.set_test_delay(TestDelay{
-                            time,
-                            ..Default::default()
-                        });
+                    match conn.stream.keepalive().tick() {
+                        keepalive::Tick::Dead => {
+                            if conn.suspend("Timeout").await {
+                                continue;
+                            }
+                            conn.on_close("Timeout", true);
+                            break;
+                        }
+                        keepalive::Tick::Ping(time) => {
+                            let mut msg_out = Message::new();
+                            msg_out.set_test_delay(TestDelay{
+                                time,
+                                ..Default::default()
+                            });
+                            conn.inner.send(msg_out.into());
+                        }
+                        keepalive::Tick::Wait => {}
+                    }
+                    if conn.request_stats {
+                        let msg_out = conn.get_stats();
                         conn.inner.send(msg_out.into());
                     }
                 }
             }
         }
 
+        conn.stop_recording();
         video_service::notify_video_frame_feched(id, None);
         super::video_service::update_test_latency(id, 0);
         super::video_service::update_image_quality(id, None);
+        super::video_service::update_bandwidth_estimate(id, None);
+        super::video_service::update_fps_cap(id, None);
+        super::video_service::update_supported_decoding(id, None);
+        super::video_service::update_capture_displays(id, None);
+        super::video_service::update_capture_region(id, None);
+        super::video_service::update_resolution(id, None);
+        super::input_service::update_cursor_delta(id, false);
+        super::input_service::update_view_only(id, false);
+        super::input_service::update_block_input(id, false);
+        super::input_service::release_touch_and_pen(id);
+        super::gamepad_service::release_gamepads(id);
+        super::annotation::clear(id);
+        super::audio_service::update_settings(id, None);
+        SESSIONS.lock().unwrap().remove(&id);
+        if let Some(tx) = conn.resume_to.take() {
+            log::info!("#{} Stream handed over to the suspended session", id);
+            allow_err!(tx.send(conn.stream));
+            return;
+        }
         if let Err(err) = conn.try_port_forward_loop(&mut rx_from_cm).await {
             conn.on_close(&err.to_string(), false);
         }
@@ -326,7 +588,9 @@ impl Connection {
                     res = forward.next() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            self.stream.send_bytes(res?.into()).await?;
+                            let bytes = res?;
+                            metrics::add(&metrics::BYTES_SENT, bytes.len() as _);
+                            self.stream.send_bytes(bytes.into()).await?;
                         } else {
                             bail!("Forward reset by the peer");
                         }
@@ -334,7 +598,9 @@ impl Connection {
                     res = self.stream.next() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            timeout(SEND_TIMEOUT_OTHER, forward.send(res?.into())).await??;
+                            let bytes = res?;
+                            metrics::add(&metrics::BYTES_RECEIVED, bytes.len() as _);
+                            timeout(SEND_TIMEOUT_OTHER, forward.send(bytes.into())).await??;
                         } else {
                             bail!("Stream reset by the peer");
                         }
@@ -362,31 +628,85 @@ impl Connection {
         self.send(msg_out).await;
     }
 
+    async fn send_disabled_permissions(&mut self) {
+        if !self.keyboard {
+            self.send_permission(Permission::Keyboard, false).await;
+        }
+        if !self.clipboard {
+            self.send_permission(Permission::Clipboard, false).await;
+        }
+        if !self.audio {
+            self.send_permission(Permission::Audio, false).await;
+        }
+    }
+
+    fn set_send_timeout(&mut self) {
+        self.stream.set_send_timeout(
+            if self.file_transfer.is_some()
+                || self.port_forward_socket.is_some()
+                || self.tunnels.is_some()
+                || self.terminal_size.is_some()
+            {
+                SEND_TIMEOUT_OTHER
+            } else {
+                SEND_TIMEOUT_VIDEO
+            },
+        );
+    }
+
+    // Keep an authorized session for a while after its stream dropped, so that the
+    // peer can reconnect and hand the new stream over with the token of the last
+    // logon, without asking for the password again and with the permissions kept.
+    // Transfers are restarted by the peer, blocks in flight are lost with the stream.
+    async fn suspend(&mut self, reason: &str) -> bool {
+        let secs = Config::get_session_resume_timeout();
+        if secs == 0 || self.resume_token.is_empty() || self.port_forward_socket.is_some() {
+            return false;
+        }
+        let id = self.inner.id();
+        log::info!("#{} Connection suspended for {}s: {}", id, secs, reason);
+        if let Some(s) = self.server.upgrade() {
+            s.write().unwrap().remove_connection(&self.inner);
+        }
+        video_service::notify_video_frame_feched(id, None);
+        self.media.take();
+        let token = std::mem::take(&mut self.resume_token);
+        let (tx, mut rx) = mpsc::unbounded_channel::<super::Stream>();
+        SUSPENDED.lock().unwrap().insert(token.clone(), tx);
+        let res = timeout(secs * 1000, rx.recv()).await;
+        SUSPENDED.lock().unwrap().remove(&token);
+        let stream = match res {
+            Ok(Some(stream)) => stream,
+            _ => return false,
+        };
+        log::info!("#{} Connection resumed", id);
+        self.stream = stream;
+        self.set_send_timeout();
+        self.stream.set_max_frame_size(get_max_frame_size());
+        if self.max_bandwidth_kbps > 0 {
+            self.stream.set_rate_limit(self.max_bandwidth_kbps);
+        }
+        self.read_jobs.clear();
+        self.search_jobs.clear();
+        self.authorized = false;
+        self.send_logon_response().await;
+        self.send_disabled_permissions().await;
+        if self.file_transfer.is_none() {
+            // the peer's decoder starts over
+            super::video_service::refresh();
+        }
+        true
+    }
+
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
-        let whitelist: Vec<String> = Config::get_option("whitelist")
-            .split(",")
-            .filter(|x| !x.is_empty())
-            .map(|x| x.to_owned())
-            .collect();
-        if !whitelist.is_empty()
-            && whitelist
-                .iter()
-                .filter(|x| x == &"0.0.0.0")
-                .next()
-                .is_none()
-            && whitelist
-                .iter()
-                .filter(|x| x.parse() == Ok(addr.ip()))
-                .next()
-                .is_none()
-        {
+        self.ip = addr.ip().to_string();
+        if !acl::check_ip(addr.ip()) {
             self.send_login_error("Your ip is blocked by the peer")
                 .await;
             sleep(1.).await;
             return false;
         }
-        self.ip = addr.ip().to_string();
         let mut msg_out = Message::new();
         msg_out.set_hash(self.hash.clone());
         self.send(msg_out).await;
@@ -397,18 +717,61 @@ impl Connection {
         if self.authorized {
             return;
         }
+        self.send_logon_response_().await;
+        // a port forwarding is not authorized for the messages, but runs all the same
+        if !self.accepted && (self.authorized || self.port_forward_socket.is_some()) {
+            self.accepted = true;
+            self.audit.start = crate::get_time();
+            self.call_hooks("accepted", "");
+        }
+    }
+
+    async fn send_logon_response_(&mut self) {
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
-        if self.port_forward_socket.is_some() {
+        if self.port_forward_socket.is_some() || self.tunnels.is_some() {
+            if let Err(err) = self.listen_reverse().await {
+                res.set_error(format!(
+                    "Failed to listen on {}: {}",
+                    self.port_forward_address, err
+                ));
+            } else {
+                // the streams of the dynamic and the reverse forwarding come in this connection
+                self.authorized = self.tunnels.is_some();
+                res.set_peer_info(PeerInfo {
+                    hostname: whoami::hostname(),
+                    username,
+                    platform: whoami::platform().to_string(),
+                    version: crate::VERSION.to_owned(),
+                    capabilities: capabilities::local(),
+                    ..Default::default()
+                });
+            }
+            let mut msg_out = Message::new();
+            msg_out.set_login_response(res);
+            self.send(msg_out).await;
+            return;
+        }
+        if let Some((rows, cols)) = self.terminal_size {
+            match self.start_terminal(rows, cols) {
+                Ok(_) => {
+                    self.authorized = true;
+                    res.set_peer_info(PeerInfo {
+                        hostname: whoami::hostname(),
+                        username,
+                        platform: whoami::platform().to_string(),
+                        version: crate::VERSION.to_owned(),
+                        capabilities: capabilities::local(),
+                        ..Default::default()
+                    });
+                }
+                Err(err) => {
+                    log::error!("Failed to start the terminal: {}", err);
+                    res.set_error(format!("Failed to start the terminal: {}", err));
+                }
+            }
             let mut msg_out = Message::new();
-            res.set_peer_info(PeerInfo {
-                hostname: whoami::hostname(),
-                username,
-                platform: whoami::platform().to_string(),
-                version: crate::VERSION.to_owned(),
-                ..Default::default()
-            });
             msg_out.set_login_response(res);
             self.send(msg_out).await;
             return;
@@ -429,9 +792,13 @@ impl Connection {
         }
         #[allow(unused_mut)]
         let mut sas_enabled = false;
+        #[allow(unused_mut)]
+        let mut can_elevate = false;
         #[cfg(windows)]
         if crate::platform::is_root() {
             sas_enabled = true;
+        } else {
+            can_elevate = !crate::platform::is_elevated();
         }
         if self.file_transfer.is_some() {
             if crate::platform::is_prelogin() || self.tx_to_cm.send(ipc::Data::Test).is_err() {
@@ -439,24 +806,43 @@ impl Connection {
             }
         }
         self.authorized = true;
+        if self.session_start.is_none() {
+            // kept when the session is resumed
+            self.session_start = Some(Instant::now());
+            self.last_input = Instant::now();
+            if self.file_transfer.is_none() {
+                DESKTOP_SESSIONS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.resume_token = hbb_common::sodiumoxide::randombytes::randombytes(32);
         let mut pi = PeerInfo {
             hostname: whoami::hostname(),
             username,
             platform: whoami::platform().to_string(),
             version: crate::VERSION.to_owned(),
             sas_enabled,
+            resume_token: self.resume_token.clone(),
+            can_elevate,
+            capabilities: capabilities::local(),
             ..Default::default()
         };
         let mut sub_service = false;
+        let mut record_size = None;
         if self.file_transfer.is_some() {
             res.set_peer_info(pi);
         } else {
+            if self.keyboard && !self.take_control(false) {
+                self.send_permission(Permission::Keyboard, false).await;
+            }
             try_activate_screen();
             match super::video_service::get_displays() {
                 Err(err) => {
                     res.set_error(format!("X11 error: {}", err));
                 }
                 Ok((current, displays)) => {
+                    record_size = displays
+                        .get(current)
+                        .map(|d| (d.width as usize, d.height as usize));
                     pi.displays = displays.into();
                     pi.current_display = current as _;
                     res.set_peer_info(pi);
@@ -483,16 +869,200 @@ impl Connection {
                 if !self.show_remote_cursor {
                     noperms.push(NAME_POS);
                 }
-                if !self.clipboard_enabled() || !self.keyboard {
+                if !self.clipboard_to_client() || !self.keyboard {
                     noperms.push(super::clipboard_service::NAME);
                 }
                 if !self.audio_enabled() {
                     noperms.push(super::audio_service::NAME);
                 }
+                if !self.printer_enabled() {
+                    noperms.push(super::print_service::NAME);
+                }
+                // peers too old to tell keep the codec at vp9
+                super::video_service::update_supported_decoding(
+                    self.inner.id(),
+                    Some(self.supported_decoding.clone()),
+                );
                 s.write()
                     .unwrap()
                     .add_connection(self.inner.clone(), &noperms);
             }
+            if video_service::is_recording_incoming() {
+                self.start_recording(record_size);
+            }
+            self.offer_media_channel().await;
+        }
+    }
+
+    // Video and audio over udp avoid the head-of-line blocking of tcp on lossy
+    // high latency links. Only offered on a secured stream since the channel is
+    // sealed with its key; tcp keeps carrying everything until the peer says hello.
+    async fn offer_media_channel(&mut self) {
+        if !Config::get_option("enable-udp-media").is_empty() {
+            return;
+        }
+        let key = if let Some(key) = self.stream.get_key() {
+            key
+        } else {
+            return;
+        };
+        let local_addr = self.stream.local_addr();
+        if !local_addr.is_ipv4() {
+            return;
+        }
+        let socket = match FramedSocket::new(SocketAddr::new(local_addr.ip(), 0)).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::error!("Failed to bind udp media channel: {}", err);
+                return;
+            }
+        };
+        let addr = match socket.local_addr() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let token = hbb_common::sodiumoxide::randombytes::randombytes(16);
+        let mut media = ReliableChannel::listen(socket, token.clone(), key);
+        media.set_rate_limit(self.max_bandwidth_kbps);
+        self.media = Some(media);
+        let mut misc = Misc::new();
+        misc.set_media_channel(MediaChannel {
+            addr: AddrMangle::encode(addr),
+            token,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(msg_out).await;
+    }
+
+    async fn send_media(&mut self, msg: &Message, fec_ratio: f32) -> ResultType<()> {
+        if let Some(media) = self.media.as_mut() {
+            if media.is_established() {
+                match media.send_fec(msg, fec_ratio).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) => self.close_media(&err.to_string()),
+                }
+            }
+        }
+        self.stream.send_into(msg).await
+    }
+
+    // false if the queue is full after it
+    fn queue(&mut self, value: (Instant, Arc<Message>)) -> bool {
+        let size = value.1.compute_size() as _;
+        self.send_queue.push(Priority::of(&value.1), size, value)
+    }
+
+    // the messages of the services waiting, taken while the queue has room,
+    // the rest left in the channel, then sent by priority
+    async fn send_queued(
+        &mut self,
+        rx: &mut mpsc::Receiver<(Instant, Arc<Message>)>,
+    ) -> ResultType<()> {
+        let mut room = !self.send_queue.is_full();
+        while room {
+            match rx.try_recv() {
+                Ok(value) => room = self.queue(value),
+                Err(_) => break,
+            }
+        }
+        while let Some((instant, value)) = self.send_queue.pop() {
+            self.send_service_message(instant, &value).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_service_message(&mut self, instant: Instant, msg: &Message) -> ResultType<()> {
+        if instant.elapsed().as_millis() > 1000 {
+            if let Some(message::Union::audio_frame(_)) = &msg.union {
+                return Ok(());
+            }
+        }
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        let replaced;
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        let msg = match crate::plugins::filter(crate::plugins::SIDE_SERVER, &self.peer.0, true, msg)
+        {
+            crate::plugins::Filtered::Pass => msg,
+            crate::plugins::Filtered::Replace(msg) => {
+                replaced = msg;
+                &replaced
+            }
+            crate::plugins::Filtered::Drop => return Ok(()),
+        };
+        self.record_other(msg);
+        match &msg.union {
+            Some(message::Union::audio_frame(_)) => self.send_media(msg, 0.).await?,
+            _ => self.stream.send_into(msg).await?,
+        }
+        metrics::add(&metrics::BYTES_SENT, msg.compute_size() as _);
+        Ok(())
+    }
+
+    fn update_congestion(&mut self) {
+        if let Some(media) = self.media.as_mut() {
+            if media.is_established() {
+                let (sent, lost) = media.take_loss_stats();
+                self.congestion.on_loss(sent, lost);
+                self.media_loss.0 += sent;
+                self.media_loss.1 += lost;
+                if let Some(srtt) = media.srtt() {
+                    self.congestion.on_rtt(srtt);
+                }
+            }
+        }
+        if self.congestion.update().is_some() {
+            self.publish_bandwidth_estimate();
+        }
+    }
+
+    // the congestion target, capped by the limit the peer asked for
+    fn publish_bandwidth_estimate(&self) {
+        let cap = if self.max_bandwidth_kbps > 0 {
+            Some(self.max_bandwidth_kbps)
+        } else {
+            None
+        };
+        let kbps = match (self.congestion.target(), cap) {
+            (Some(target), Some(cap)) => Some(target.min(cap)),
+            (target, cap) => target.or(cap),
+        };
+        super::video_service::update_bandwidth_estimate(self.inner.id(), kbps);
+    }
+
+    fn get_stats(&mut self) -> Message {
+        let (codec, fps, target_bitrate) = super::video_service::get_encoder_stats();
+        let (sent, lost) = std::mem::replace(&mut self.media_loss, (0, 0));
+        let mut misc = Misc::new();
+        misc.set_stats(SessionStats {
+            rtt: self
+                .congestion
+                .rtt()
+                .map(|rtt| rtt.as_millis() as _)
+                .unwrap_or_default(),
+            codec,
+            fps,
+            target_bitrate,
+            loss: if sent > 0 {
+                (lost * 1000 / sent) as _
+            } else {
+                0
+            },
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        msg_out
+    }
+
+    fn close_media(&mut self, reason: &str) {
+        if let Some(media) = self.media.take() {
+            log::info!("Udp media channel closed, fall back to tcp: {}", reason);
+            if media.is_established() {
+                // frames lost with the channel break the decoder state of this peer
+                super::video_service::request_key_frame(self.inner.id());
+            }
         }
     }
 
@@ -500,53 +1070,441 @@ impl Connection {
         self.clipboard && !self.disable_clipboard
     }
 
+    // "clipboard-direction" here is "to-client", "to-host" or both if empty,
+    // and the peer can only narrow it down
+    fn clipboard_to_client(&self) -> bool {
+        self.clipboard_enabled()
+            && Config::get_option("clipboard-direction") != "to-host"
+            && self.clipboard_direction != option_message::ClipboardDirection::ToHost
+    }
+
+    fn clipboard_to_host(&self) -> bool {
+        self.clipboard_enabled()
+            && Config::get_option("clipboard-direction") != "to-client"
+            && self.clipboard_direction != option_message::ClipboardDirection::ToClient
+    }
+
     fn audio_enabled(&self) -> bool {
         self.audio && !self.disable_audio
     }
 
-    async fn try_start_cm(&mut self, peer_id: String, name: String, authorized: bool) {
-        self.send_to_cm(ipc::Data::Login {
-            id: self.inner.id(),
-            is_file_transfer: self.file_transfer.is_some(),
-            port_forward: self.port_forward_address.clone(),
-            peer_id,
-            name,
-            authorized,
-            keyboard: self.keyboard,
-            clipboard: self.clipboard,
-            audio: self.audio,
+    // the jobs of the printer are files sent to the peer
+    fn printer_enabled(&self) -> bool {
+        self.authorized
+            && self.enable_printer
+            && self.capabilities.has(capabilities::PRINT_JOB)
+            && self.file
+            && self.file_transfer.is_none()
+            && super::print_service::is_enabled()
+    }
+
+    // Answered in the connection manager, the call is on its own and goes on
+    // while the audio of the session is off.
+    async fn handle_voice_call(&mut self, accepted: bool) {
+        let mut accepted = accepted;
+        if accepted {
+            if let Err(err) = self.start_voice_call() {
+                log::error!("Failed to start the voice call: {}", err);
+                self.send_to_cm(ipc::Data::CloseVoiceCall(err.to_string()));
+                accepted = false;
+            }
+        }
+        let mut msg_out = Message::new();
+        msg_out.set_voice_call_response(VoiceCallResponse {
+            accepted,
+            req_timestamp: crate::get_time(),
+            ..Default::default()
         });
+        self.send(msg_out).await;
     }
 
-    #[inline]
-    fn send_to_cm(&mut self, data: ipc::Data) {
-        self.tx_to_cm.send(data).ok();
+    #[cfg(not(any(target_os = "android")))]
+    fn start_voice_call(&mut self) -> ResultType<()> {
+        let mut inner = self.inner.clone();
+        self.voice_call = Some(VoiceCall::start(move |msg| {
+            inner.send(Arc::new(msg));
+        })?);
+        Ok(())
     }
 
-    #[inline]
-    fn send_fs(&mut self, data: ipc::FS) {
-        self.send_to_cm(ipc::Data::FS(data));
+    #[cfg(any(target_os = "android"))]
+    fn start_voice_call(&mut self) -> ResultType<()> {
+        bail!("Voice call is not supported");
     }
 
-    async fn send_login_error<T: std::string::ToString>(&mut self, err: T) {
-        let mut msg_out = Message::new();
-        let mut res = LoginResponse::new();
-        res.set_error(err.to_string());
-        msg_out.set_login_response(res);
-        self.send(msg_out).await;
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn handle_tunnel(&mut self, t: Tunnel) {
+        let tunnels = match self.tunnels.as_mut() {
+            Some(tunnels) => tunnels,
+            None => return,
+        };
+        match t.union {
+            // the peer connects only in its dynamic and UDP forwarding
+            Some(tunnel::Union::connect(pf)) if !self.reverse_forward => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tunnels.insert(t.id, tx);
+                let send = self.get_tunnel_sender();
+                if pf.udp {
+                    tokio::spawn(crate::port_forward::serve_udp(
+                        t.id, pf.host, pf.port, rx, send,
+                    ));
+                } else {
+                    tokio::spawn(crate::port_forward::serve_tunnel(
+                        t.id, pf.host, pf.port, rx, send,
+                    ));
+                }
+            }
+            union => crate::port_forward::dispatch(tunnels, t.id, union),
+        }
     }
 
-    async fn on_message(&mut self, msg: Message) -> bool {
-        if let Some(message::Union::login_request(lr)) = msg.union {
-            if let Some(o) = lr.option.as_ref() {
-                self.update_option(o);
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    fn handle_tunnel(&mut self, _: Tunnel) {}
+
+    async fn listen_reverse(&mut self) -> ResultType<()> {
+        if self.reverse_forward && self.reverse_listener.is_none() {
+            // not to share the port with a listener already there
+            let listener = new_listener(&self.port_forward_address, false).await?;
+            log::info!("Reverse port forwarding on {}", self.port_forward_address);
+            self.reverse_listener = Some((listener, 0));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn accept_tunnel(&mut self, socket: TcpStream, addr: SocketAddr) {
+        let (tunnels, (_, id)) = match (self.tunnels.as_mut(), self.reverse_listener.as_mut()) {
+            (Some(tunnels), Some(listener)) => (tunnels, listener),
+            _ => return,
+        };
+        log::debug!("#{} Reverse forwarding of {}", self.inner.id(), addr);
+        *id += 1;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tunnels.insert(*id, tx);
+        let id = *id;
+        let send = self.get_tunnel_sender();
+        tokio::spawn(crate::port_forward::accept_tunnel(id, socket, rx, send));
+    }
+
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    fn accept_tunnel(&mut self, _: TcpStream, _: SocketAddr) {}
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn get_tunnel_sender(&self) -> impl FnMut(Tunnel) + Send + 'static {
+        let mut inner = self.inner.clone();
+        move |t| {
+            let mut msg_out = Message::new();
+            msg_out.set_tunnel(t);
+            inner.send_wait(Arc::new(msg_out));
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn start_terminal(&mut self, rows: u32, cols: u32) -> ResultType<()> {
+        let mut inner = self.inner.clone();
+        self.shell = Some(Terminal::start(rows, cols, move |msg| {
+            inner.send_wait(Arc::new(msg));
+        })?);
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    fn start_terminal(&mut self, _rows: u32, _cols: u32) -> ResultType<()> {
+        bail!("Terminal is not supported");
+    }
+
+    // On a thread, as the UAC prompt waits for the local user. This process exits
+    // for the elevated one once the peer is told, and the session reconnects.
+    #[cfg(windows)]
+    fn elevate(&mut self, r: ElevationRequest) {
+        let logon = match r.union {
+            Some(elevation_request::Union::logon(l)) => Some((l.username, l.password)),
+            _ => None,
+        };
+        let mut inner = self.inner.clone();
+        std::thread::spawn(move || {
+            let res = crate::platform::elevate(logon);
+            let mut misc = Misc::new();
+            match &res {
+                Ok(_) => misc.set_elevation_response("".to_owned()),
+                Err(err) => {
+                    log::error!("Failed to elevate: {}", err);
+                    misc.set_elevation_response(err.to_string());
+                }
             }
-            if self.authorized {
+            let mut msg_out = Message::new();
+            msg_out.set_misc(misc);
+            inner.send(Arc::new(msg_out));
+            if res.is_ok() {
+                log::info!("Restart elevated");
+                std::thread::sleep(Duration::from_secs(1));
+                std::process::exit(0);
+            }
+        });
+    }
+
+    #[cfg(not(windows))]
+    fn elevate(&mut self, _: ElevationRequest) {}
+
+    // `by_cm` to tell the peer, otherwise it was the peer to hang up
+    async fn close_voice_call(&mut self, by_cm: bool) {
+        #[cfg(not(any(target_os = "android")))]
+        {
+            self.voice_call = None;
+        }
+        if by_cm {
+            let mut msg_out = Message::new();
+            msg_out.set_voice_call_request(VoiceCallRequest {
+                req_timestamp: crate::get_time(),
+                is_connect: false,
+                ..Default::default()
+            });
+            self.send(msg_out).await;
+        } else {
+            self.send_to_cm(ipc::Data::CloseVoiceCall("".to_owned()));
+        }
+    }
+
+    fn update_voice(&mut self) {
+        self.voice = if self.microphone && self.file_transfer.is_none() {
+            self.voice_format.clone().map(VoicePlayer::new)
+        } else {
+            None
+        };
+    }
+
+    async fn try_start_cm(&mut self, peer_id: String, name: String, authorized: bool) {
+        self.peer = (peer_id.clone(), name.clone());
+        self.send_to_cm(ipc::Data::Login {
+            id: self.inner.id(),
+            is_file_transfer: self.file_transfer.is_some(),
+            port_forward: self.port_forward_address.clone(),
+            is_terminal: self.terminal_size.is_some(),
+            peer_id,
+            name,
+            authorized,
+            keyboard: self.keyboard,
+            clipboard: self.clipboard,
+            audio: self.audio,
+            microphone: self.microphone,
+        });
+        self.update_session_status();
+    }
+
+    fn call_hooks(&self, event: &str, reason: &str) {
+        hooks::call(event, &self.peer.0, &self.peer.1, &self.ip, reason);
+    }
+
+    fn get_conn_type(&self) -> &'static str {
+        if self.file_transfer.is_some() {
+            "file-transfer"
+        } else if self.terminal_size.is_some() {
+            "terminal"
+        } else if !self.port_forward_address.is_empty() || self.tunnels.is_some() {
+            "port-forward"
+        } else {
+            "desktop"
+        }
+    }
+
+    fn update_session_status(&mut self) {
+        if self.authorized {
+            let granted = [
+                ("keyboard", self.keyboard),
+                ("clipboard", self.clipboard),
+                ("audio", self.audio),
+                ("microphone", self.microphone),
+            ];
+            for (name, _) in granted.iter().filter(|x| x.1) {
+                if !self.audit.permissions.iter().any(|x| x == name) {
+                    self.audit.permissions.push(name.to_string());
+                }
+            }
+        }
+        let status = ipc::SessionStatus {
+            id: self.inner.id(),
+            peer_id: self.peer.0.clone(),
+            name: self.peer.1.clone(),
+            ip: self.ip.clone(),
+            conn_type: self.get_conn_type().to_owned(),
+            authorized: self.authorized,
+            keyboard: self.keyboard,
+            clipboard: self.clipboard,
+            audio: self.audio,
+            microphone: self.microphone,
+        };
+        SESSIONS
+            .lock()
+            .unwrap()
+            .insert(status.id, (status, self.tx_from_cm.clone()));
+    }
+
+    // the permission profile of the peer over the global permissions, so that
+    // e.g. a trusted peer has full control and the others only view
+    fn apply_permission_profile(&mut self, peer_id: &str) -> String {
+        let profile = Config::get_permission_profile(peer_id);
+        let (keyboard, clipboard, file, tunnel) = match profile.as_str() {
+            "full" => (true, true, true, true),
+            "view" => (false, false, false, false),
+            "file" => (false, false, true, false),
+            _ => return profile,
+        };
+        self.keyboard = keyboard;
+        self.clipboard = clipboard;
+        self.file = file;
+        self.tunnel = tunnel;
+        profile
+    }
+
+    // the time left before the session is closed, for no input in a desktop
+    // session or for its duration, by the host's options in minutes,
+    // and if it is for no input
+    fn get_session_time_left(&self) -> Option<(Duration, bool)> {
+        let get_minutes = |name| Config::get_option(name).parse::<u64>().unwrap_or(0);
+        let mut left = None;
+        let idle = get_minutes("idle-timeout");
+        if idle > 0 && self.file_transfer.is_none() && !self.view_only {
+            let limit = Duration::from_secs(idle * 60);
+            left = Some((limit.saturating_sub(self.last_input.elapsed()), true));
+        }
+        let max = get_minutes("max-session-duration");
+        if let (true, Some(start)) = (max > 0, self.session_start) {
+            let t = Duration::from_secs(max * 60).saturating_sub(start.elapsed());
+            if left.map(|x: (Duration, bool)| t < x.0).unwrap_or(true) {
+                left = Some((t, false));
+            }
+        }
+        left
+    }
+
+    // warn the peer a while before the session time limits, false if closed
+    async fn check_session_timeout(&mut self) -> bool {
+        if !self.authorized {
+            return true;
+        }
+        let (left, idle) = match self.get_session_time_left() {
+            Some(x) => x,
+            None => return true,
+        };
+        let mut misc = Misc::new();
+        if left.as_secs() == 0 {
+            let reason = if idle {
+                "Closed after a period of no input"
+            } else {
+                "Closed at the session time limit"
+            };
+            misc.set_close_reason(reason.into());
+            let mut msg_out = Message::new();
+            msg_out.set_misc(misc);
+            self.send(msg_out).await;
+            self.on_close(reason, true);
+            return false;
+        }
+        if left > TIMEOUT_WARNING {
+            self.timeout_warned = false;
+        } else if !self.timeout_warned {
+            self.timeout_warned = true;
+            misc.set_session_timeout(SessionTimeout {
+                seconds: left.as_secs() as _,
+                idle,
+                ..Default::default()
+            });
+            let mut msg_out = Message::new();
+            msg_out.set_misc(misc);
+            self.send(msg_out).await;
+        }
+        true
+    }
+
+    #[inline]
+    fn send_to_cm(&mut self, data: ipc::Data) {
+        self.tx_to_cm.send(data).ok();
+    }
+
+    // files dropped on the remote view of a desktop session, written to the drop folder
+    fn can_drop_files(&self, fa: &FileAction) -> bool {
+        if !self.keyboard || !self.file {
+            return false;
+        }
+        match &fa.union {
+            Some(file_action::Union::receive(r)) => r.drop,
+            Some(file_action::Union::cancel(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn send_fs(&mut self, data: ipc::FS) {
+        self.send_to_cm(ipc::Data::FS(data));
+    }
+
+    async fn send_login_error<T: std::string::ToString>(&mut self, err: T) {
+        let err = err.to_string();
+        match err.as_str() {
+            "Wrong Password" | "Wrong 2FA Code" => self.call_hooks("auth-failed", &err),
+            // asked for, not failed yet
+            "2FA required" => {}
+            _ => self.call_hooks("rejected", &err),
+        }
+        let mut msg_out = Message::new();
+        let mut res = LoginResponse::new();
+        res.set_error(err);
+        msg_out.set_login_response(res);
+        self.send(msg_out).await;
+    }
+
+    async fn on_message(&mut self, msg: Message) -> bool {
+        if let Some(message::Union::login_request(lr)) = msg.union {
+            if let Some(o) = lr.option.as_ref() {
+                self.update_option(o);
+            }
+            if self.authorized {
                 return true;
             }
+            self.peer = (lr.my_id.clone(), lr.my_name.clone());
+            self.capabilities = Capabilities::new(&lr.capabilities);
+            if !acl::check_id(&lr.my_id) {
+                log::info!(
+                    "#{} {} denied by the access control",
+                    self.inner.id,
+                    lr.my_id
+                );
+                self.send_login_error("Your ID is blocked by the peer")
+                    .await;
+                sleep(1.).await;
+                return false;
+            }
+            if self.apply_permission_profile(&lr.my_id) == "file"
+                && !matches!(lr.union, Some(login_request::Union::file_transfer(_)))
+            {
+                self.send_login_error("Only file transfer is allowed").await;
+                sleep(1.).await;
+                return false;
+            }
+            if lr.view_only && !self.view_only {
+                self.view_only = true;
+                self.keyboard = false;
+                super::input_service::update_view_only(self.inner.id(), true);
+            }
+            if !lr.resume_token.is_empty() {
+                let tx = SUSPENDED.lock().unwrap().remove(&lr.resume_token);
+                match tx {
+                    Some(tx) if lr.username == Config::get_id() => {
+                        self.resume_to = Some(tx);
+                        return false;
+                    }
+                    _ => {
+                        if lr.password.is_empty() {
+                            self.send_login_error("Session expired").await;
+                            sleep(1.).await;
+                            return false;
+                        }
+                    }
+                }
+            }
             match lr.union {
                 Some(login_request::Union::file_transfer(ft)) => {
-                    if !Config::get_option("enable-file-transfer").is_empty() {
+                    if !self.file {
                         self.send_login_error("No permission of file transfer")
                             .await;
                         sleep(1.).await;
@@ -554,8 +1512,36 @@ impl Connection {
                     }
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
                 }
+                Some(login_request::Union::port_forward(pf))
+                    if pf.dynamic || pf.reverse || pf.udp =>
+                {
+                    if !self.tunnel {
+                        self.send_login_error("No permission of IP tunneling").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    // listening only once authorized
+                    self.port_forward_address = if pf.reverse {
+                        // the loopback only, unless the host lets the peer
+                        // listen on the other interfaces too
+                        let host = if pf.host.is_empty()
+                            || Config::get_option("allow-remote-reverse-forward") != "Y"
+                        {
+                            "127.0.0.1"
+                        } else {
+                            &pf.host
+                        };
+                        format!("{}:{}", host, pf.port)
+                    } else if pf.udp {
+                        format!("{}:{}/udp", pf.host, pf.port)
+                    } else {
+                        "SOCKS5".to_owned()
+                    };
+                    self.reverse_forward = pf.reverse;
+                    self.tunnels = Some(HashMap::new());
+                }
                 Some(login_request::Union::port_forward(mut pf)) => {
-                    if !Config::get_option("enable-tunnel").is_empty() {
+                    if !self.tunnel {
                         self.send_login_error("No permission of IP tunneling").await;
                         sleep(1.).await;
                         return false;
@@ -587,50 +1573,64 @@ impl Connection {
                         }
                     }
                 }
+                Some(login_request::Union::terminal(t)) => {
+                    if !self.terminal || !self.keyboard {
+                        self.send_login_error("No permission of terminal").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    self.terminal_size = Some((t.rows, t.cols));
+                }
                 _ => {}
             }
             if !crate::is_ip(&lr.username) && lr.username != Config::get_id() {
                 self.send_login_error("Offline").await;
+            } else if login_guard::is_banned(&self.ip) {
+                self.send_login_error("Too many wrong password attempts")
+                    .await;
+            } else if take_switch_sides(&lr.my_id, &lr.switch_sides) {
+                log::info!("#{} {} switched sides", self.inner.id(), lr.my_id);
+                self.send_logon_response().await;
+                self.try_start_cm(lr.my_id, lr.my_name, true).await;
             } else if lr.password.is_empty() {
                 self.try_start_cm(lr.my_id, lr.my_name, false).await;
             } else {
-                let mut hasher = Sha256::new();
-                hasher.update(&Config::get_password());
-                hasher.update(&self.hash.salt);
-                let mut hasher2 = Sha256::new();
-                hasher2.update(&hasher.finalize()[..]);
-                hasher2.update(&self.hash.challenge);
-                let mut failure = LOGIN_FAILURES
-                    .lock()
-                    .unwrap()
-                    .get(&self.ip)
-                    .map(|x| x.clone())
-                    .unwrap_or((0, 0, 0));
-                let time = (crate::get_time() / 60_000) as i32;
-                if failure.2 > 30 {
-                    self.send_login_error("Too many wrong password attempts")
-                        .await;
-                } else if time == failure.0 && failure.1 > 6 {
-                    self.send_login_error("Please try 1 minute later").await;
-                } else if hasher2.finalize()[..] != lr.password[..] {
-                    if failure.0 == time {
-                        failure.1 += 1;
-                        failure.2 += 1;
-                    } else {
-                        failure.0 = time;
-                        failure.1 = 1;
-                        failure.2 += 1;
-                    }
-                    LOGIN_FAILURES
-                        .lock()
-                        .unwrap()
-                        .insert(self.ip.clone(), failure);
+                let valid = match Config::get_password_hash() {
+                    // the key of the login is only known to the client
+                    Some((_, verifier)) => {
+                        let challenge = &self.hash.challenge;
+                        let key = config::mask_login_key(&lr.password, &verifier, challenge);
+                        let res = config::password_verifier(&key);
+                        key.len() == verifier.len() && sodiumoxide::utils::memcmp(&res, &verifier)
+                    }
+                    None => {
+                        let mut hasher = Sha256::new();
+                        hasher.update(&Config::get_password());
+                        hasher.update(&self.hash.salt);
+                        let mut hasher2 = Sha256::new();
+                        hasher2.update(&hasher.finalize()[..]);
+                        hasher2.update(&self.hash.challenge);
+                        sodiumoxide::utils::memcmp(&hasher2.finalize()[..], &lr.password)
+                    }
+                };
+                if login_guard::is_delayed(&self.ip) {
+                    self.send_login_error("Please try again later").await;
+                } else if !valid {
+                    login_guard::add_failure(&self.ip);
+                    password_rotation::on_failure();
                     self.send_login_error("Wrong Password").await;
                     self.try_start_cm(lr.my_id, lr.my_name, false).await;
+                } else if totp::is_required() && !totp::is_enabled() {
+                    self.send_login_error("2FA not set up").await;
+                } else if totp::is_enabled() && lr.totp.is_empty() {
+                    self.send_login_error("2FA required").await;
+                } else if totp::is_enabled() && !totp::verify_code(&lr.totp) {
+                    login_guard::add_failure(&self.ip);
+                    password_rotation::on_failure();
+                    self.send_login_error("Wrong 2FA Code").await;
                 } else {
-                    if failure.0 != 0 {
-                        LOGIN_FAILURES.lock().unwrap().remove(&self.ip);
-                    }
+                    login_guard::clear_failures(&self.ip);
+                    password_rotation::on_login();
                     self.send_logon_response().await;
                     self.try_start_cm(lr.my_id, lr.my_name, true).await;
                     if self.port_forward_socket.is_some() {
@@ -638,26 +1638,87 @@ impl Connection {
                     }
                 }
             }
+        } else if let Some(message::Union::wake_on_lan(w)) = msg.union {
+            // no login needed, the peer not known here, so it is only done if
+            // the user opts in, and once a while of each source ip
+            if Config::get_option("enable-wake-on-lan") == "Y" && allow_wake_on_lan(&self.ip) {
+                log::info!("#{} Wake-on-LAN of {}", self.inner.id(), w.mac);
+                if let Err(err) = crate::rendezvous_mediator::wake_on_lan(&w.mac).await {
+                    log::error!("Failed to send Wake-on-LAN: {}", err);
+                }
+            }
+            return false;
         } else if let Some(message::Union::test_delay(t)) = msg.union {
             if t.from_client {
                 let mut msg_out = Message::new();
                 msg_out.set_test_delay(t);
                 self.inner.send(msg_out.into());
-            } else {
-                self.last_test_delay = 0;
-                let latency = crate::get_time() - t.time;
+            } else if let Some(rtt) = self.stream.keepalive().on_pong(t.time) {
+                let latency = rtt.as_millis() as i64;
                 if latency > 0 {
                     super::video_service::update_test_latency(self.inner.id(), latency);
+                    self.congestion.on_rtt(rtt);
+                }
+            }
+        } else if self.authorized && self.tunnels.is_some() {
+            match msg.union {
+                Some(message::Union::tunnel(t)) => {
+                    self.last_input = Instant::now();
+                    self.handle_tunnel(t);
                 }
+                _ => {}
+            }
+        } else if self.authorized && self.terminal_size.is_some() {
+            // nothing of the desktop in a terminal session
+            match msg.union {
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                Some(message::Union::terminal_data(d)) => {
+                    self.last_input = Instant::now();
+                    if let Some(shell) = self.shell.as_mut() {
+                        shell.write(&d.data);
+                    }
+                }
+                #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                Some(message::Union::terminal_resize(t)) => {
+                    if let Some(shell) = self.shell.as_ref() {
+                        shell.resize(t.rows, t.cols);
+                    }
+                }
+                _ => {}
             }
         } else if self.authorized {
             match msg.union {
                 Some(message::Union::mouse_event(me)) => {
+                    self.last_input = Instant::now();
                     if self.keyboard {
                         handle_mouse(&me, self.inner.id());
                     }
                 }
+                Some(message::Union::touch_event(me)) => {
+                    self.last_input = Instant::now();
+                    if self.keyboard {
+                        handle_touch(&me, self.inner.id());
+                    }
+                }
+                Some(message::Union::pen_event(me)) => {
+                    self.last_input = Instant::now();
+                    if self.keyboard {
+                        handle_pen(&me, self.inner.id());
+                    }
+                }
+                Some(message::Union::gamepad_state(state)) => {
+                    self.last_input = Instant::now();
+                    if self.keyboard {
+                        super::gamepad_service::handle_gamepad(&state, self.inner.id());
+                    }
+                }
+                Some(message::Union::annotation(a)) => {
+                    if self.keyboard && self.file_transfer.is_none() {
+                        super::annotation::handle(self.inner.id(), &a);
+                    }
+                }
                 Some(message::Union::key_event(mut me)) => {
+                    self.last_input = Instant::now();
                     if self.keyboard {
                         // handle all down as press
                         // fix unexpected repeating key on remote linux, seems also fix abnormal alt/shift, which
@@ -669,27 +1730,43 @@ impl Connection {
                         };
                         if is_press {
                             if let Some(key_event::Union::unicode(_)) = me.union {
-                                handle_key(&me);
+                                handle_key(&me, self.inner.id());
                             } else if let Some(key_event::Union::seq(_)) = me.union {
-                                handle_key(&me);
+                                handle_key(&me, self.inner.id());
                             } else {
                                 me.down = true;
-                                handle_key(&me);
+                                handle_key(&me, self.inner.id());
                                 me.down = false;
-                                handle_key(&me);
+                                handle_key(&me, self.inner.id());
                             }
                         } else {
-                            handle_key(&me);
+                            handle_key(&me, self.inner.id());
                         }
                     }
                 }
                 Some(message::Union::clipboard(cb)) => {
-                    if self.clipboard {
-                        update_clipboard(cb, None);
+                    if self.clipboard_to_host() {
+                        if let Some(msg) = update_clipboard(cb, None) {
+                            self.send(msg).await;
+                        }
+                    }
+                }
+                Some(message::Union::clipboard_chunk(chunk)) => {
+                    if self.clipboard_to_host() {
+                        if let Some(msg) = update_clipboard_chunk(chunk, None) {
+                            self.send(msg).await;
+                        }
+                    }
+                }
+                Some(message::Union::clipboard_request(req)) => {
+                    if self.clipboard_to_client() && self.keyboard {
+                        if let Some(msg) = get_clipboard_chunk(&req) {
+                            self.send(msg).await;
+                        }
                     }
                 }
                 Some(message::Union::file_action(fa)) => {
-                    if self.file_transfer.is_some() {
+                    if self.file_transfer.is_some() || self.can_drop_files(&fa) {
                         match fa.union {
                             Some(file_action::Union::read_dir(rd)) => {
                                 self.read_dir(&rd.path, rd.include_hidden);
@@ -699,18 +1776,27 @@ impl Connection {
                                     Err(err) => {
                                         self.send(fs::new_error(f.id, err, -1)).await;
                                     }
-                                    Ok(files) => {
+                                    Ok(mut files) => {
+                                        if f.hash {
+                                            fs::hash_files(&f.path, &mut files);
+                                        }
                                         self.send(fs::new_dir(f.id, files)).await;
                                     }
                                 }
                             }
                             Some(file_action::Union::send(s)) => {
+                                self.audit.files.push(format!("sent {}", s.path));
                                 let id = s.id;
                                 match fs::TransferJob::new_read(id, s.path, s.include_hidden) {
                                     Err(err) => {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
-                                    Ok(job) => {
+                                    Ok(mut job) => {
+                                        job.set_resume(s.resume);
+                                        job.set_compress_level(s.compress_level);
+                                        if !s.files.is_empty() {
+                                            job.retain_files(&s.files);
+                                        }
                                         self.send(fs::new_dir(id, job.files().to_vec())).await;
                                         self.read_jobs.push(job);
                                         self.timer = time::interval(MILLI1);
@@ -718,14 +1804,25 @@ impl Connection {
                                 }
                             }
                             Some(file_action::Union::receive(r)) => {
+                                self.audit.files.push(format!("received {}", r.path));
                                 self.send_fs(ipc::FS::NewWrite {
                                     path: r.path,
                                     id: r.id,
+                                    drop: r.drop,
+                                    paste: r.paste,
                                     files: r
                                         .files
                                         .to_vec()
                                         .drain(..)
-                                        .map(|f| (f.name, f.modified_time))
+                                        .map(|f| {
+                                            (
+                                                f.name,
+                                                f.modified_time,
+                                                f.entry_type.value(),
+                                                f.mode,
+                                                f.link_target,
+                                            )
+                                        })
                                         .collect(),
                                 });
                             }
@@ -750,8 +1847,23 @@ impl Connection {
                                 });
                             }
                             Some(file_action::Union::cancel(c)) => {
-                                self.send_fs(ipc::FS::CancelWrite { id: c.id });
+                                self.send_fs(ipc::FS::CancelWrite {
+                                    id: c.id,
+                                    keep_download: c.keep_download,
+                                });
                                 fs::remove_job(c.id, &mut self.read_jobs);
+                                self.search_jobs.retain(|x| x.id() != c.id);
+                            }
+                            Some(file_action::Union::search(s)) => {
+                                self.search_jobs.push(fs::SearchJob::new(s));
+                                self.timer = time::interval(MILLI1);
+                            }
+                            Some(file_action::Union::send_confirm(r)) => {
+                                if let Some(job) = fs::get_job(r.id, &mut self.read_jobs) {
+                                    if let Err(err) = job.confirm(&r).await {
+                                        self.send(fs::new_error(r.id, err, r.file_num)).await;
+                                    }
+                                }
                             }
                             _ => {}
                         }
@@ -764,6 +1876,17 @@ impl Connection {
                             file_num: block.file_num,
                             data: block.data,
                             compressed: block.compressed,
+                            offset: block.offset,
+                            copy_blocks: block.copy_blocks,
+                            sha256: block.sha256,
+                        });
+                    }
+                    Some(file_response::Union::digest(d)) => {
+                        self.send_fs(ipc::FS::WriteDigest {
+                            id: d.id,
+                            file_num: d.file_num,
+                            last_modified: d.last_modified,
+                            file_size: d.file_size,
                         });
                     }
                     Some(file_response::Union::done(d)) => {
@@ -778,8 +1901,68 @@ impl Connection {
                     Some(misc::Union::switch_display(s)) => {
                         super::video_service::switch_display(s.display);
                     }
+                    Some(misc::Union::get_windows(true)) => {
+                        if self.file_transfer.is_none() && self.port_forward_socket.is_none() {
+                            let mut misc = Misc::new();
+                            misc.set_windows(WindowList {
+                                windows: crate::platform::get_windows().into(),
+                                ..Default::default()
+                            });
+                            let mut msg_out = Message::new();
+                            msg_out.set_misc(misc);
+                            self.send(msg_out).await;
+                        }
+                    }
+                    Some(misc::Union::capture_displays(c)) => {
+                        super::video_service::update_capture_displays(
+                            self.inner.id(),
+                            Some(c.displays.iter().map(|d| *d as usize).collect()),
+                        );
+                    }
+                    Some(misc::Union::change_resolution(r)) => {
+                        if self.keyboard {
+                            super::video_service::update_resolution(
+                                self.inner.id(),
+                                if r.width > 0 && r.height > 0 {
+                                    Some((r.width as _, r.height as _))
+                                } else {
+                                    None
+                                },
+                            );
+                        }
+                    }
+                    Some(misc::Union::request_stats(b)) => {
+                        self.request_stats = b;
+                    }
+                    Some(misc::Union::switch_sides(token)) => {
+                        if self.keyboard && self.file_transfer.is_none() {
+                            self.switch_sides(&token);
+                        }
+                    }
+                    Some(misc::Union::restart(r)) => {
+                        if self.keyboard && self.file_transfer.is_none() {
+                            log::info!("Restart by {}, safe mode: {}", self.ip, r.safe_mode);
+                            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+                            allow_err!(crate::platform::restart(r.safe_mode));
+                        }
+                    }
+                    Some(misc::Union::elevation_request(r)) => {
+                        if self.keyboard && self.file_transfer.is_none() {
+                            self.elevate(r);
+                        }
+                    }
                     Some(misc::Union::chat_message(c)) => {
-                        self.send_to_cm(ipc::Data::ChatMessage { text: c.text });
+                        self.send_to_cm(ipc::Data::ChatMessage {
+                            text: c.text,
+                            id: c.id,
+                            file: c.file,
+                        });
+                    }
+                    Some(misc::Union::chat_receipt(r)) => {
+                        self.send_to_cm(ipc::Data::ChatReceipt {
+                            ids: r.ids,
+                            read: r.read,
+                        });
                     }
                     Some(misc::Union::option(o)) => {
                         self.update_option(&o);
@@ -789,8 +1972,39 @@ impl Connection {
                             super::video_service::refresh();
                         }
                     }
+                    #[cfg(not(any(target_os = "android")))]
+                    Some(misc::Union::voice_format(f)) => {
+                        if let Some(call) = self.voice_call.as_mut() {
+                            call.handle_format(f);
+                        }
+                    }
+                    Some(misc::Union::audio_format(f)) => {
+                        self.voice_format = Some(f);
+                        self.update_voice();
+                        if !self.microphone {
+                            self.send_permission(Permission::Microphone, false).await;
+                        }
+                    }
                     _ => {}
                 },
+                Some(message::Union::audio_frame(frame)) => {
+                    if let Some(voice) = self.voice.as_ref() {
+                        voice.play(frame);
+                    }
+                }
+                Some(message::Union::voice_call_request(r)) => {
+                    if !r.is_connect {
+                        self.close_voice_call(false).await;
+                    } else if self.file_transfer.is_none() && self.port_forward_socket.is_none() {
+                        self.send_to_cm(ipc::Data::VoiceCallIncoming);
+                    }
+                }
+                #[cfg(not(any(target_os = "android")))]
+                Some(message::Union::voice_frame(frame)) => {
+                    if let Some(call) = self.voice_call.as_ref() {
+                        call.handle_frame(frame);
+                    }
+                }
                 _ => {}
             }
         }
@@ -808,6 +2022,24 @@ impl Connection {
             self.image_quality = q;
             super::video_service::update_image_quality(self.inner.id(), Some(q));
         }
+        if o.custom_fps > 0 {
+            super::video_service::update_fps_cap(self.inner.id(), Some(o.custom_fps));
+        }
+        if let Some(settings) = o.audio_settings.as_ref() {
+            super::audio_service::update_settings(self.inner.id(), Some(settings.clone()));
+        }
+        if let Some(region) = o.capture_region.as_ref() {
+            super::video_service::update_capture_region(self.inner.id(), Some(region.clone()));
+        }
+        if let Some(decoding) = o.supported_decoding.as_ref() {
+            self.supported_decoding = decoding.clone();
+            if self.authorized && self.file_transfer.is_none() {
+                super::video_service::update_supported_decoding(
+                    self.inner.id(),
+                    Some(decoding.clone()),
+                );
+            }
+        }
         if let Ok(q) = o.lock_after_session_end.enum_value() {
             if q != BoolOption::NotSet {
                 self.lock_after_session_end = q == BoolOption::Yes;
@@ -830,6 +2062,11 @@ impl Connection {
                 }
             }
         }
+        if let Ok(q) = o.cursor_delta.enum_value() {
+            if q != BoolOption::NotSet {
+                super::input_service::update_cursor_delta(self.inner.id(), q == BoolOption::Yes);
+            }
+        }
         if let Ok(q) = o.disable_audio.enum_value() {
             if q != BoolOption::NotSet {
                 self.disable_audio = q == BoolOption::Yes;
@@ -849,25 +2086,143 @@ impl Connection {
                     s.write().unwrap().subscribe(
                         super::clipboard_service::NAME,
                         self.inner.clone(),
-                        self.clipboard_enabled() && self.keyboard,
+                        self.clipboard_to_client() && self.keyboard,
+                    );
+                }
+            }
+        }
+        if let Ok(q) = o.enable_printer.enum_value() {
+            if q != BoolOption::NotSet {
+                self.enable_printer = q == BoolOption::Yes;
+                if let Some(s) = self.server.upgrade() {
+                    s.write().unwrap().subscribe(
+                        super::print_service::NAME,
+                        self.inner.clone(),
+                        self.printer_enabled(),
+                    );
+                }
+            }
+        }
+        if let Ok(q) = o.clipboard_direction.enum_value() {
+            if q != option_message::ClipboardDirection::NotSet {
+                self.clipboard_direction = q;
+                if let Some(s) = self.server.upgrade() {
+                    s.write().unwrap().subscribe(
+                        super::clipboard_service::NAME,
+                        self.inner.clone(),
+                        self.clipboard_to_client() && self.keyboard,
                     );
                 }
             }
         }
         if let Ok(q) = o.privacy_mode.enum_value() {
             if q != BoolOption::NotSet {
-                self.privacy_mode = q == BoolOption::Yes;
-                if self.privacy_mode && self.keyboard {
-                    crate::platform::toggle_privacy_mode(true);
+                self.set_privacy_mode(q == BoolOption::Yes && self.keyboard);
+            }
+        }
+        if o.max_bandwidth_kbps > 0 && o.max_bandwidth_kbps != self.max_bandwidth_kbps {
+            self.max_bandwidth_kbps = o.max_bandwidth_kbps;
+            self.stream.set_rate_limit(self.max_bandwidth_kbps);
+            if let Some(media) = self.media.as_mut() {
+                media.set_rate_limit(self.max_bandwidth_kbps);
+            }
+            self.publish_bandwidth_estimate();
+        }
+        if let Ok(q) = o.block_input.enum_value() {
+            if q != BoolOption::NotSet {
+                self.block_input = q == BoolOption::Yes;
+                self.update_block_input();
+            }
+        }
+    }
+
+    // A new file for each size of the display, the next frame of the encoder
+    // is a key frame to start it with.
+    fn start_recording(&mut self, size: Option<(usize, usize)>) {
+        self.stop_recording();
+        self.record_size = size;
+        if size.is_some() {
+            video_service::refresh();
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if self.recorder.is_some() {
+            self.write_record_info(true);
+            self.recorder = None;
+        }
+    }
+
+    fn record_video(&mut self, msg: &Message) {
+        let (width, height) = match self.record_size {
+            Some(size) => size,
+            None => return,
+        };
+        let vf = match &msg.union {
+            Some(message::Union::video_frame(vf)) if vf.display == 0 => vf,
+            _ => return,
+        };
+        if self.recorder.is_none() {
+            let dir = Config::get_incoming_record_directory();
+            clean_recordings(&dir);
+            let start = crate::get_time();
+            let name = format!("{}_{}", self.peer.0, start);
+            match Recorder::new(&dir, &name, width, height, self.audio_format.as_ref()) {
+                Ok(recorder) => {
+                    self.recorder = Some((recorder, start));
+                    self.write_record_info(false);
+                }
+                Err(err) => {
+                    log::error!("Failed to record the session: {}", err);
+                    self.record_size = None;
+                    return;
                 }
             }
         }
-        if self.keyboard {
-            if let Ok(q) = o.block_input.enum_value() {
-                if q != BoolOption::NotSet {
-                    crate::platform::block_input(q == BoolOption::Yes);
+        if let Some(Err(err)) = self.recorder.as_mut().map(|(r, _)| r.write_video(vf)) {
+            log::error!("Recording of the session stopped: {}", err);
+            self.stop_recording();
+            self.record_size = None;
+        }
+    }
+
+    fn record_other(&mut self, msg: &Message) {
+        match &msg.union {
+            Some(message::Union::audio_frame(frame)) => {
+                if let Some((recorder, _)) = self.recorder.as_mut() {
+                    recorder.write_audio(frame);
                 }
             }
+            Some(message::Union::misc(misc)) => match &misc.union {
+                Some(misc::Union::audio_format(f)) => {
+                    self.audio_format = Some(f.clone());
+                }
+                Some(misc::Union::switch_display(s)) if self.record_size.is_some() => {
+                    self.start_recording(Some((s.width as _, s.height as _)));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // who connected, next to the video, `end` is 0 until the file is complete
+    fn write_record_info(&self, end: bool) {
+        if let Some((recorder, start)) = self.recorder.as_ref() {
+            let info = serde_json::json!({
+                "peer_id": self.peer.0,
+                "peer_name": self.peer.1,
+                "ip": self.ip,
+                "start": start,
+                "end": if end { crate::get_time() } else { 0 },
+                "keyboard": self.keyboard,
+                "clipboard": self.clipboard,
+                "audio": self.audio,
+            });
+            allow_err!(std::fs::write(
+                recorder.path.with_extension("json"),
+                info.to_string()
+            ));
         }
     }
 
@@ -875,17 +2230,154 @@ impl Connection {
         if let Some(s) = self.server.upgrade() {
             s.write().unwrap().remove_connection(&self.inner);
         }
+        SESSIONS.lock().unwrap().remove(&self.inner.id());
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        if std::mem::replace(&mut self.accepted, false) {
+            self.call_hooks("disconnected", reason);
+            audit::add(audit::Entry {
+                direction: "incoming".to_owned(),
+                peer_id: self.peer.0.clone(),
+                name: self.peer.1.clone(),
+                ip: self.ip.clone(),
+                conn_type: self.get_conn_type().to_owned(),
+                end: crate::get_time(),
+                reason: reason.to_owned(),
+                ..std::mem::take(&mut self.audit)
+            });
+        } else if reason == CLOSED_BY_CM {
+            self.call_hooks("rejected", reason);
+        }
+        let mut locked = false;
         if lock && self.lock_after_session_end && self.keyboard {
             crate::platform::lock_screen();
             super::video_service::switch_to_primary();
+            locked = true;
+        }
+        // whatever the reason, a dropped session included once its resume timed out
+        if self.session_start.take().is_some()
+            && self.file_transfer.is_none()
+            && DESKTOP_SESSIONS.fetch_sub(1, Ordering::SeqCst) == 1
+        {
+            match Config::get_option("lock-on-disconnect").as_str() {
+                "lock" if !locked => {
+                    log::info!("Lock the screen after the last session");
+                    crate::platform::lock_screen();
+                }
+                "logout" => {
+                    log::info!("Log out after the last session");
+                    crate::platform::logout();
+                }
+                _ => {}
+            }
         }
         if self.privacy_mode {
-            crate::platform::toggle_privacy_mode(false);
+            self.set_privacy_mode(false);
         }
+        self.release_control();
         self.port_forward_socket.take();
     }
 
+    // The host connects back to the peer with its one-time token, in a new window
+    // of the user, so that the peer is controlled instead. The token is signed
+    // with the key pair of the id, for the peer to know it is the host it was
+    // connected to, and taken by the window through ipc, not to be seen in the
+    // command line.
+    fn switch_sides(&self, token: &str) {
+        let id = self.peer.0.clone();
+        let sk = match sodiumoxide::crypto::sign::SecretKey::from_slice(&Config::get_key_pair().0) {
+            Some(sk) => sk,
+            None => {
+                log::error!("Failed to switch sides with {}: no key pair", id);
+                return;
+            }
+        };
+        let signed = sodiumoxide::crypto::sign::sign(token.as_bytes(), &sk);
+        SWITCH_SIDES_BACK
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (Instant::now(), base64::encode(signed)));
+        let args = vec!["--connect", &id, "--switch-sides"];
+        let res = if crate::platform::is_root() {
+            crate::platform::run_as_user(args)
+        } else {
+            crate::run_me(args).map(Some).map_err(|err| err.into())
+        };
+        match res {
+            Ok(Some(task)) => super::CHILD_PROCESS.lock().unwrap().push(task),
+            Ok(None) => {}
+            Err(err) => log::error!("Failed to switch sides with {}: {}", id, err),
+        }
+    }
+
+    // With the option "single-controller" only one desktop session has the
+    // keyboard and mouse of the host at a time, the first one, and the others
+    // only view until it is handed over in the cm, `force` taking it from the
+    // session having it. False if it is kept by another session.
+    fn take_control(&mut self, force: bool) -> bool {
+        if !self.keyboard || Config::get_option("single-controller") != "Y" {
+            return true;
+        }
+        let id = self.inner.id();
+        let mut controller = CONTROLLER.lock().unwrap();
+        if let Some((other, tx)) = controller.as_ref() {
+            if *other != id {
+                if !force {
+                    self.keyboard = false;
+                    return false;
+                }
+                log::info!("#{} Control taken from #{}", id, other);
+                allow_err!(tx.send(ipc::Data::SwitchPermission {
+                    name: "keyboard".to_owned(),
+                    enabled: false,
+                }));
+            }
+        }
+        *controller = Some((id, self.tx_from_cm.clone()));
+        true
+    }
+
+    fn release_control(&self) {
+        let mut controller = CONTROLLER.lock().unwrap();
+        if controller.as_ref().map(|x| x.0) == Some(self.inner.id()) {
+            *controller = None;
+        }
+    }
+
+    // The privacy mode of the host is owned by the session turning it on, and
+    // turned off with it, the peer is told if it is on after each request.
+    fn set_privacy_mode(&mut self, on: bool) {
+        let id = self.inner.id();
+        let mut owner = PRIVACY_MODE_CONN.lock().unwrap();
+        if on && *owner == 0 {
+            if crate::platform::toggle_privacy_mode(true) {
+                *owner = id;
+            }
+        } else if !on && *owner == id {
+            crate::platform::toggle_privacy_mode(false);
+            *owner = 0;
+        }
+        self.privacy_mode = *owner == id;
+        drop(owner);
+        let mut misc = Misc::new();
+        misc.set_privacy_mode(self.privacy_mode);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.inner.send(msg_out.into());
+        self.update_block_input();
+    }
+
+    // The local input of the host is blocked by the toggle of the peer or with
+    // the privacy mode, the peer is told if it is after each change.
+    fn update_block_input(&mut self) {
+        let on = (self.block_input || self.privacy_mode) && self.keyboard;
+        let blocked = super::input_service::update_block_input(self.inner.id(), on);
+        let mut misc = Misc::new();
+        misc.set_block_input(blocked);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.inner.send(msg_out.into());
+    }
+
     fn read_dir(&mut self, dir: &str, include_hidden: bool) {
         let dir = dir.to_string();
         self.send_fs(ipc::FS::ReadDir {
@@ -896,7 +2388,148 @@ impl Connection {
 
     #[inline]
     async fn send(&mut self, msg: Message) {
-        allow_err!(self.stream.send(&msg).await);
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        let msg = match self.filter(true, msg) {
+            Some(msg) => msg,
+            None => return,
+        };
+        allow_err!(self.stream.send_into(&msg).await);
+    }
+
+    // seen by the plugins, None if dropped
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn filter(&self, outgoing: bool, msg: Message) -> Option<Message> {
+        crate::plugins::filter_owned(crate::plugins::SIDE_SERVER, &self.peer.0, outgoing, msg)
+    }
+}
+
+// The oldest recordings go first, once older than "record-retention-days" (30 by
+// default, 0 to keep them) or when all of them take more than "record-max-size"
+// megabytes (no limit by default).
+fn clean_recordings(dir: &std::path::Path) {
+    let days = Config::get_option("record-retention-days")
+        .parse::<u64>()
+        .unwrap_or(30);
+    let max_size = Config::get_option("record-max-size")
+        .parse::<u64>()
+        .unwrap_or(0)
+        * 1024
+        * 1024;
+    let mut files: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| {
+                let e = e.ok()?;
+                let path = e.path();
+                if path.extension()? != "webm" {
+                    return None;
+                }
+                let meta = e.metadata().ok()?;
+                Some((path, meta.modified().ok()?, meta.len()))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    // newest first
+    files.sort_by(|a, b| b.1.cmp(&a.1));
+    let now = std::time::SystemTime::now();
+    let mut total = 0;
+    for (path, modified, len) in files {
+        total += len;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if (days > 0 && age > Duration::from_secs(days * 86_400))
+            || (max_size > 0 && total > max_size)
+        {
+            log::info!("Remove the recording {:?}", path);
+            allow_err!(std::fs::remove_file(&path));
+            std::fs::remove_file(path.with_extension("json")).ok();
+        }
+    }
+}
+
+const SWITCH_SIDES_TIMEOUT: u64 = 30;
+const CLOSED_BY_CM: &str = "Close requested from connection manager";
+const SEND_QUEUE_FULL: &str = "Send queue full";
+const WAKE_ON_LAN_INTERVAL: u64 = 10;
+
+fn allow_wake_on_lan(ip: &str) -> bool {
+    let mut times = WAKE_ON_LAN.lock().unwrap();
+    times.retain(|_, x| x.elapsed().as_secs() < WAKE_ON_LAN_INTERVAL);
+    if times.contains_key(ip) {
+        log::debug!("Wake-on-LAN of {} too often", ip);
+        return false;
+    }
+    times.insert(ip.to_owned(), Instant::now());
+    true
+}
+
+/// The one-time token of `peer_id` to connect back with, signed with the key
+/// `pk` pinned of it.
+pub fn add_switch_sides(peer_id: String, token: String, pk: Vec<u8>) {
+    let mut tokens = SWITCH_SIDES.lock().unwrap();
+    tokens.retain(|_, x| x.0.elapsed().as_secs() < SWITCH_SIDES_TIMEOUT);
+    tokens.insert(peer_id, (Instant::now(), token, pk));
+}
+
+/// The signed token to connect back to `peer_id` with, taken once by the
+/// window started to switch sides, empty if there is none.
+pub fn take_switch_sides_back(peer_id: &str) -> String {
+    match SWITCH_SIDES_BACK.lock().unwrap().remove(peer_id) {
+        Some((time, x)) if time.elapsed().as_secs() < SWITCH_SIDES_TIMEOUT => x,
+        _ => "".to_owned(),
+    }
+}
+
+// each token is used once, and only by the peer it was given to, which signs
+// it with the key pair of its id
+fn take_switch_sides(peer_id: &str, signed: &str) -> bool {
+    if signed.is_empty() {
+        return false;
+    }
+    let (time, token, pk) = match SWITCH_SIDES.lock().unwrap().remove(peer_id) {
+        Some(x) => x,
+        None => return false,
+    };
+    if time.elapsed().as_secs() >= SWITCH_SIDES_TIMEOUT {
+        return false;
+    }
+    let pk = match sodiumoxide::crypto::sign::PublicKey::from_slice(&pk) {
+        Some(pk) => pk,
+        None => return false,
+    };
+    match base64::decode(signed) {
+        Ok(x) => sodiumoxide::crypto::sign::verify(&x, &pk)
+            .map(|x| x == token.as_bytes())
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+pub fn get_sessions() -> Vec<ipc::SessionStatus> {
+    let mut sessions: Vec<_> = SESSIONS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|x| x.0.clone())
+        .collect();
+    sessions.sort_by_key(|x| x.id);
+    sessions
+}
+
+/// To the connection `id` as if from the cm, false if there is none.
+pub fn send_to_session(id: i32, data: ipc::Data) -> bool {
+    match SESSIONS.lock().unwrap().get(&id) {
+        Some((_, tx)) => tx.send(data).is_ok(),
+        None => false,
+    }
+}
+
+// never ready without the listener of the reverse port forwarding
+async fn accept_reverse(
+    listener: &Option<(TcpListener, u32)>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -916,7 +2549,7 @@ async fn start_ipc(
         if crate::platform::is_root() {
             let mut res = Ok(None);
             for _ in 0..10 {
-                res = crate::platform::run_as_user("--cm");
+                res = crate::platform::run_as_user(vec!["--cm"]);
                 if res.is_ok() {
                     break;
                 }
@@ -0,0 +1,189 @@
+// Virtual gamepads of the host driven by the ones of the peers: an Xbox 360
+// controller by ViGEm on Windows, which needs the ViGEmBus driver, and one by
+// uinput on Linux, which needs /dev/uinput to be writable. The buttons of the
+// messages are the ones of XInput.
+use super::*;
+#[cfg(target_os = "linux")]
+use uinput::Gamepad;
+
+// the gamepads of a peer at most
+const MAX_GAMEPADS: u32 = 4;
+
+lazy_static::lazy_static! {
+    // (connection, index of the gamepad of the peer) -> the virtual one, None if
+    // it failed to be created, not tried again until the gamepad is unplugged
+    static ref GAMEPADS: Arc<Mutex<HashMap<(i32, u32), Option<Gamepad>>>> = Default::default();
+}
+
+pub fn handle_gamepad(state: &GamepadState, conn: i32) {
+    let mut gamepads = GAMEPADS.lock().unwrap();
+    let key = (conn, state.index);
+    if !state.connected {
+        if gamepads.remove(&key).is_some() {
+            log::info!("#{} gamepad {} unplugged", conn, state.index);
+        }
+        return;
+    }
+    if state.index >= MAX_GAMEPADS {
+        return;
+    }
+    let gamepad = gamepads.entry(key).or_insert_with(|| match Gamepad::new() {
+        Ok(gamepad) => {
+            log::info!("#{} gamepad {} plugged", conn, state.index);
+            Some(gamepad)
+        }
+        Err(err) => {
+            log::error!("Failed to create the virtual gamepad: {}", err);
+            None
+        }
+    });
+    if let Some(gamepad) = gamepad {
+        if let Err(err) = gamepad.update(state) {
+            log::error!("Failed to update the virtual gamepad: {}", err);
+            gamepads.insert(key, None);
+        }
+    }
+}
+
+/// Unplug the gamepads of a connection closed.
+pub fn release_gamepads(conn: i32) {
+    GAMEPADS.lock().unwrap().retain(|k, _| k.0 != conn);
+}
+
+#[cfg(any(windows, target_os = "linux"))]
+fn get_thumb(v: i32) -> i16 {
+    v.max(i16::MIN as i32).min(i16::MAX as i32) as i16
+}
+
+#[cfg(windows)]
+struct Gamepad(vigem_client::Xbox360Wired<vigem_client::Client>);
+
+#[cfg(windows)]
+impl Gamepad {
+    fn new() -> ResultType<Self> {
+        let client = vigem_client::Client::connect()?;
+        let id = vigem_client::TargetId::XBOX360_WIRED;
+        let mut target = vigem_client::Xbox360Wired::new(client, id);
+        target.plugin()?;
+        target.wait_ready()?;
+        Ok(Self(target))
+    }
+
+    fn update(&mut self, state: &GamepadState) -> ResultType<()> {
+        self.0.update(&vigem_client::XGamepad {
+            buttons: vigem_client::XButtons {
+                raw: state.buttons as u16,
+            },
+            left_trigger: state.left_trigger.min(255) as u8,
+            right_trigger: state.right_trigger.min(255) as u8,
+            thumb_lx: get_thumb(state.left_thumb_x),
+            thumb_ly: get_thumb(state.left_thumb_y),
+            thumb_rx: get_thumb(state.right_thumb_x),
+            thumb_ry: get_thumb(state.right_thumb_y),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod uinput {
+    use super::*;
+    use crate::platform::linux::{
+        create_uinput_device, write_input_events, BUS_USB, EV_ABS, EV_KEY,
+    };
+
+    // XInput -> linux/input-event-codes.h, as the xpad driver maps them
+    const BUTTONS: [(u32, u16); 11] = [
+        (0x0010, 0x13b), // start
+        (0x0020, 0x13a), // back, select
+        (0x0040, 0x13d), // left thumb
+        (0x0080, 0x13e), // right thumb
+        (0x0100, 0x136), // left shoulder
+        (0x0200, 0x137), // right shoulder
+        (0x0400, 0x13c), // guide, mode
+        (0x1000, 0x130), // a, south
+        (0x2000, 0x131), // b, east
+        (0x4000, 0x134), // x, west
+        (0x8000, 0x133), // y, north
+    ];
+    const DPAD_UP: u32 = 0x0001;
+    const DPAD_DOWN: u32 = 0x0002;
+    const DPAD_LEFT: u32 = 0x0004;
+    const DPAD_RIGHT: u32 = 0x0008;
+    const ABS_X: u16 = 0x00;
+    const ABS_Y: u16 = 0x01;
+    const ABS_Z: u16 = 0x02;
+    const ABS_RX: u16 = 0x03;
+    const ABS_RY: u16 = 0x04;
+    const ABS_RZ: u16 = 0x05;
+    const ABS_HAT0X: u16 = 0x10;
+    const ABS_HAT0Y: u16 = 0x11;
+
+    pub struct Gamepad(std::fs::File);
+
+    impl Gamepad {
+        pub fn new() -> ResultType<Self> {
+            let keys: Vec<u16> = BUTTONS.iter().map(|x| x.1).collect();
+            let (min, max) = (i16::MIN as i32, i16::MAX as i32);
+            // the ids of an Xbox 360 controller, for the games to know its layout
+            let file = create_uinput_device(
+                "RustDesk Gamepad",
+                [BUS_USB, 0x045e, 0x028e, 0x0114],
+                false,
+                &keys,
+                &[
+                    (ABS_X, min, max),
+                    (ABS_Y, min, max),
+                    (ABS_RX, min, max),
+                    (ABS_RY, min, max),
+                    (ABS_Z, 0, 255),
+                    (ABS_RZ, 0, 255),
+                    (ABS_HAT0X, -1, 1),
+                    (ABS_HAT0Y, -1, 1),
+                ],
+            )?;
+            Ok(Self(file))
+        }
+
+        pub fn update(&mut self, state: &GamepadState) -> ResultType<()> {
+            let pressed = |bit: u32| (state.buttons & bit != 0) as i32;
+            let mut events: Vec<(u16, u16, i32)> = BUTTONS
+                .iter()
+                .map(|(bit, code)| (EV_KEY, *code, pressed(*bit)))
+                .collect();
+            events.extend_from_slice(&[
+                (EV_ABS, ABS_X, get_thumb(state.left_thumb_x) as i32),
+                (EV_ABS, ABS_Y, get_thumb_down(state.left_thumb_y)),
+                (EV_ABS, ABS_RX, get_thumb(state.right_thumb_x) as i32),
+                (EV_ABS, ABS_RY, get_thumb_down(state.right_thumb_y)),
+                (EV_ABS, ABS_Z, state.left_trigger.min(255) as i32),
+                (EV_ABS, ABS_RZ, state.right_trigger.min(255) as i32),
+                (EV_ABS, ABS_HAT0X, pressed(DPAD_RIGHT) - pressed(DPAD_LEFT)),
+                (EV_ABS, ABS_HAT0Y, pressed(DPAD_DOWN) - pressed(DPAD_UP)),
+            ]);
+            if !write_input_events(&mut self.0, &events) {
+                bail!("Failed to write the events of the gamepad");
+            }
+            Ok(())
+        }
+    }
+
+    // the y axes of evdev are down, the ones of XInput up
+    fn get_thumb_down(v: i32) -> i32 {
+        -(get_thumb(v).max(-i16::MAX) as i32)
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+struct Gamepad;
+
+#[cfg(not(any(windows, target_os = "linux")))]
+impl Gamepad {
+    fn new() -> ResultType<Self> {
+        bail!("No virtual gamepad on this platform");
+    }
+
+    fn update(&mut self, _state: &GamepadState) -> ResultType<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+// Notifications of the session events, for the admins to be alerted when an
+// unattended host is accessed: the event is posted as JSON to the url of the
+// option "hook-url", and given as the argument of the program of the option
+// "hook-script". The events are "accepted", "rejected", "auth-failed" and
+// "disconnected", with the reason of the last three. The script is run by the
+// service, as root or SYSTEM: on Windows it is only taken from the machine
+// policy, HKLM\SOFTWARE\Policies\RustDesk, which only the admins can set.
+use hbb_common::{config::Config, log};
+use std::time::Duration;
+
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn call(event: &str, peer_id: &str, name: &str, ip: &str, reason: &str) {
+    let url = Config::get_option("hook-url");
+    let script = get_script();
+    if url.is_empty() && script.is_empty() {
+        return;
+    }
+    let body = serde_json::json!({
+        "event": event,
+        "id": Config::get_id(),
+        "peer_id": peer_id,
+        "name": name,
+        "ip": ip,
+        "reason": reason,
+        "time": crate::get_time(),
+    })
+    .to_string();
+    std::thread::spawn(move || {
+        if !url.is_empty() {
+            post(&url, &body);
+        }
+        if !script.is_empty() {
+            run(&script, &body);
+        }
+    });
+}
+
+fn post(url: &str, body: &str) {
+    let res = reqwest::blocking::Client::new()
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_owned())
+        .timeout(POST_TIMEOUT)
+        .send();
+    match res {
+        Ok(res) if !res.status().is_success() => {
+            log::error!("Hook {} failed: {}", url, res.status());
+        }
+        Err(err) => log::error!("Hook {} failed: {}", url, err),
+        _ => {}
+    }
+}
+
+#[cfg(not(windows))]
+fn get_script() -> String {
+    Config::get_option("hook-script")
+}
+
+// the options of the service can be set by any user over the ipc
+#[cfg(windows)]
+fn get_script() -> String {
+    hbb_common::policy::get_machine("hook-script").unwrap_or_default()
+}
+
+// never through a shell, the peer id and name being of the peer
+fn run(script: &str, body: &str) {
+    if !is_safe(script) {
+        log::error!("Hook {} is writable by the other users, not run", script);
+        return;
+    }
+    match std::process::Command::new(script).arg(body).status() {
+        Ok(status) if !status.success() => log::error!("Hook {} failed: {}", script, status),
+        Err(err) => log::error!("Failed to run hook {}: {}", script, err),
+        _ => {}
+    }
+}
+
+// the service runs as root, not to run what any user can change, the script
+// or any folder above it, which would let it be replaced
+#[cfg(not(windows))]
+fn is_safe(script: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let path = match std::fs::canonicalize(script) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let uid = unsafe { libc::getuid() };
+    path.ancestors().all(|x| match std::fs::metadata(x) {
+        Ok(m) => (m.uid() == 0 || m.uid() == uid) && m.mode() & 0o022 == 0,
+        Err(_) => false,
+    })
+}
+
+// of the machine policy, see get_script
+#[cfg(windows)]
+fn is_safe(_script: &str) -> bool {
+    true
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_is_safe() {
+        assert!(!is_safe("/nonexistent/hook"));
+        // in a world writable folder, as /tmp usually is
+        let tmp = std::env::temp_dir();
+        let writable = std::fs::metadata(&tmp)
+            .map(|m| m.mode() & 0o002 != 0)
+            .unwrap_or(false);
+        let script = tmp.join(format!("hook_{}.sh", std::process::id()));
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        if writable {
+            assert!(!is_safe(script.to_str().unwrap()));
+        }
+        std::fs::remove_file(&script).ok();
+    }
+}
@@ -1,9 +1,11 @@
 use super::*;
+use crate::platform::{TouchContact, TouchState};
 #[cfg(target_os = "macos")]
 use dispatch::Queue;
 use enigo::{Enigo, Key, KeyboardControllable, MouseButton, MouseControllable};
 use hbb_common::{config::COMPRESS_LEVEL, protobuf::ProtobufEnumOrUnknown};
 use std::{
+    collections::HashSet,
     convert::TryFrom,
     sync::atomic::{AtomicBool, Ordering},
     time::Instant,
@@ -41,6 +43,8 @@ struct Input {
 }
 
 const KEY_CHAR_START: i32 = 9999;
+const KEY_SCAN_START: i32 = 19999;
+const MAX_TOUCH_CONTACTS: u32 = 10;
 
 #[derive(Clone, Default)]
 pub struct MouseCursorSub {
@@ -81,9 +85,62 @@ impl Subscriber for MouseCursorSub {
     }
 }
 
+// an absolute position once in a while, in case the peer missed one, e.g.
+// while the session was suspended
+const ABSOLUTE_POS_INTERVAL: usize = 30;
+
+// the positions as moves from the previous one, which are smaller on the wire,
+// if the peer understands them
+#[derive(Clone, Default)]
+pub struct MousePosSub {
+    inner: ConnInner,
+    last: Option<(i32, i32)>,
+    count: usize,
+}
+
+impl From<ConnInner> for MousePosSub {
+    fn from(inner: ConnInner) -> Self {
+        Self {
+            inner,
+            last: None,
+            count: 0,
+        }
+    }
+}
+
+impl Subscriber for MousePosSub {
+    #[inline]
+    fn id(&self) -> i32 {
+        self.inner.id()
+    }
+
+    #[inline]
+    fn send(&mut self, msg: Arc<Message>) {
+        if let Some(message::Union::cursor_position(cp)) = &msg.union {
+            self.count += 1;
+            if let Some((x, y)) = self.last.replace((cp.x, cp.y)) {
+                if self.count % ABSOLUTE_POS_INTERVAL != 0
+                    && CURSOR_DELTA_CONNS.lock().unwrap().contains(&self.id())
+                {
+                    let mut tmp = Message::new();
+                    tmp.set_cursor_delta(CursorDelta {
+                        dx: cp.x - x,
+                        dy: cp.y - y,
+                        ..Default::default()
+                    });
+                    self.inner.send(Arc::new(tmp));
+                    return;
+                }
+            }
+        }
+        self.inner.send(msg);
+    }
+}
+
 pub const NAME_CURSOR: &'static str = "mouse_cursor";
 pub const NAME_POS: &'static str = "mouse_pos";
 pub type MouseCursorService = ServiceTmpl<MouseCursorSub>;
+pub type MousePosService = ServiceTmpl<MousePosSub>;
 
 pub fn new_cursor() -> MouseCursorService {
     let sp = MouseCursorService::new(NAME_CURSOR, true);
@@ -91,13 +148,13 @@ pub fn new_cursor() -> MouseCursorService {
     sp
 }
 
-pub fn new_pos() -> GenericService {
-    let sp = GenericService::new(NAME_POS, false);
+pub fn new_pos() -> MousePosService {
+    let sp = MousePosService::new(NAME_POS, false);
     sp.repeat::<StatePos, _>(33, run_pos);
     sp
 }
 
-fn run_pos(sp: GenericService, state: &mut StatePos) -> ResultType<()> {
+fn run_pos(sp: MousePosService, state: &mut StatePos) -> ResultType<()> {
     if let Some((x, y)) = crate::get_cursor_pos() {
         if state.cursor_pos.0 != x || state.cursor_pos.1 != y {
             state.cursor_pos = (x, y);
@@ -133,6 +190,45 @@ fn run_pos(sp: GenericService, state: &mut StatePos) -> ResultType<()> {
     Ok(())
 }
 
+pub fn update_cursor_delta(id: i32, enabled: bool) {
+    if enabled {
+        CURSOR_DELTA_CONNS.lock().unwrap().insert(id);
+    } else {
+        CURSOR_DELTA_CONNS.lock().unwrap().remove(&id);
+    }
+}
+
+pub fn update_view_only(id: i32, enabled: bool) {
+    if enabled {
+        VIEW_ONLY_CONNS.lock().unwrap().insert(id);
+    } else {
+        VIEW_ONLY_CONNS.lock().unwrap().remove(&id);
+    }
+}
+
+#[inline]
+fn is_view_only(conn: i32) -> bool {
+    VIEW_ONLY_CONNS.lock().unwrap().contains(&conn)
+}
+
+/// The local input of the host is blocked while any connection asks for it,
+/// true if it is.
+pub fn update_block_input(id: i32, enabled: bool) -> bool {
+    let mut conns = BLOCK_INPUT_CONNS.lock().unwrap();
+    if enabled {
+        if conns.is_empty() && !crate::platform::block_input(true) {
+            return false;
+        }
+        conns.insert(id);
+        true
+    } else {
+        if conns.remove(&id) && conns.is_empty() {
+            crate::platform::block_input(false);
+        }
+        false
+    }
+}
+
 fn run_cursor(sp: MouseCursorService, state: &mut StateCursor) -> ResultType<()> {
     if let Some(hcursor) = crate::get_cursor()? {
         if hcursor != state.hcursor {
@@ -165,6 +261,15 @@ lazy_static::lazy_static! {
     static ref ENIGO: Arc<Mutex<Enigo>> = Arc::new(Mutex::new(Enigo::new()));
     static ref KEYS_DOWN: Arc<Mutex<HashMap<i32, Instant>>> = Default::default();
     static ref LATEST_INPUT: Arc<Mutex<Input>> = Default::default();
+    static ref CURSOR_DELTA_CONNS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    // the connections of view only sessions, whose input is ignored
+    static ref VIEW_ONLY_CONNS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    // the connections blocking the local input, by the toggle or the privacy mode
+    static ref BLOCK_INPUT_CONNS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    // (connection, id of the contact by the peer, contact injected)
+    static ref TOUCH_CONTACTS: Arc<Mutex<Vec<(i32, u32, TouchContact)>>> = Default::default();
+    // the connection of the pen in range and its last event
+    static ref PEN: Arc<Mutex<Option<(i32, PenEvent)>>> = Default::default();
 }
 static EXITING: AtomicBool = AtomicBool::new(false);
 
@@ -214,6 +319,9 @@ fn get_modifier_state(key: Key, en: &mut Enigo) -> bool {
 }
 
 pub fn handle_mouse(evt: &MouseEvent, conn: i32) {
+    if is_view_only(conn) {
+        return;
+    }
     #[cfg(target_os = "macos")]
     if !*IS_SERVER {
         // having GUI, run main GUI thread, otherwise crash
@@ -224,6 +332,114 @@ pub fn handle_mouse(evt: &MouseEvent, conn: i32) {
     handle_mouse_(evt, conn);
 }
 
+pub fn handle_touch(evt: &TouchEvent, conn: i32) {
+    if is_view_only(conn) || EXITING.load(Ordering::SeqCst) {
+        return;
+    }
+    #[cfg(windows)]
+    crate::platform::windows::try_change_desktop();
+    let mut contacts = TOUCH_CONTACTS.lock().unwrap();
+    match contacts.iter_mut().find(|x| x.0 == conn && x.1 == evt.id) {
+        Some((_, _, contact)) => {
+            contact.x = evt.x;
+            contact.y = evt.y;
+            contact.pressure = evt.pressure;
+            contact.state = if evt.down {
+                TouchState::Move
+            } else {
+                TouchState::Up
+            };
+        }
+        None if evt.down => {
+            let free = (0..MAX_TOUCH_CONTACTS).find(|id| contacts.iter().all(|x| x.2.id != *id));
+            let id = match free {
+                Some(id) => id,
+                None => return,
+            };
+            contacts.push((
+                conn,
+                evt.id,
+                TouchContact {
+                    id,
+                    x: evt.x,
+                    y: evt.y,
+                    pressure: evt.pressure,
+                    state: TouchState::Down,
+                },
+            ));
+        }
+        None => return,
+    }
+    inject_touch_frame(&mut contacts);
+}
+
+pub fn handle_pen(evt: &PenEvent, conn: i32) {
+    if is_view_only(conn) || EXITING.load(Ordering::SeqCst) {
+        return;
+    }
+    #[cfg(windows)]
+    crate::platform::windows::try_change_desktop();
+    *PEN.lock().unwrap() = if evt.in_range {
+        Some((conn, evt.clone()))
+    } else {
+        None
+    };
+    inject_pen_event(evt.clone());
+}
+
+/// Lift the touch contacts and the pen of a connection closed.
+pub fn release_touch_and_pen(conn: i32) {
+    let mut contacts = TOUCH_CONTACTS.lock().unwrap();
+    if contacts.iter().any(|x| x.0 == conn) {
+        for x in contacts.iter_mut().filter(|x| x.0 == conn) {
+            x.2.state = TouchState::Up;
+        }
+        inject_touch_frame(&mut contacts);
+    }
+    let mut pen = PEN.lock().unwrap();
+    if pen.as_ref().map(|x| x.0) == Some(conn) {
+        if let Some((_, mut evt)) = pen.take() {
+            evt.down = false;
+            evt.in_range = false;
+            inject_pen_event(evt);
+        }
+    }
+}
+
+// all the contacts down at once, the lifted ones forgotten after
+fn inject_touch_frame(contacts: &mut Vec<(i32, u32, TouchContact)>) {
+    let frame: Vec<TouchContact> = contacts.iter().map(|x| x.2.clone()).collect();
+    contacts.retain(|x| x.2.state != TouchState::Up);
+    for x in contacts.iter_mut() {
+        x.2.state = TouchState::Move;
+    }
+    let func = move || {
+        if !crate::platform::inject_touch(&frame) {
+            log::debug!("Failed to inject the touch");
+        }
+    };
+    #[cfg(target_os = "macos")]
+    if !*IS_SERVER {
+        QUEUE.exec_async(func);
+        return;
+    }
+    func();
+}
+
+fn inject_pen_event(evt: PenEvent) {
+    let func = move || {
+        if !crate::platform::inject_pen(&evt) {
+            log::debug!("Failed to inject the pen");
+        }
+    };
+    #[cfg(target_os = "macos")]
+    if !*IS_SERVER {
+        QUEUE.exec_async(func);
+        return;
+    }
+    func();
+}
+
 pub fn fix_key_down_timeout_loop() {
     std::thread::spawn(move || loop {
         std::thread::sleep(std::time::Duration::from_millis(300));
@@ -255,7 +471,9 @@ fn fix_key_down_timeout(force: bool) {
     for (key, value) in cloned.into_iter() {
         if force || value.elapsed().as_millis() >= 3_000 {
             KEYS_DOWN.lock().unwrap().remove(&key);
-            let key = if key < KEY_CHAR_START {
+            let key = if key >= KEY_SCAN_START {
+                get_positional_key((key - KEY_SCAN_START) as _)
+            } else if key < KEY_CHAR_START {
                 if let Some(key) = KEY_MAP.get(&key) {
                     Some(*key)
                 } else {
@@ -387,20 +605,27 @@ fn handle_mouse_(evt: &MouseEvent, conn: i32) {
             _ => {}
         },
         3 => {
+            let smooth = evt.wheel_x != 0 || evt.wheel_y != 0;
             #[allow(unused_mut)]
-            let mut x = evt.x;
-            #[allow(unused_mut)]
-            let mut y = evt.y;
+            let (mut x, mut y) = if smooth {
+                (evt.wheel_x, evt.wheel_y)
+            } else {
+                (evt.x, evt.y)
+            };
             #[cfg(not(windows))]
             {
                 x = -x;
                 y = -y;
             }
-            if x != 0 {
-                en.mouse_scroll_x(x);
-            }
-            if y != 0 {
-                en.mouse_scroll_y(y);
+            if smooth {
+                en.mouse_scroll_smooth(x, y);
+            } else {
+                if x != 0 {
+                    en.mouse_scroll_x(x);
+                }
+                if y != 0 {
+                    en.mouse_scroll_y(y);
+                }
             }
         }
         _ => {}
@@ -500,6 +725,13 @@ lazy_static::lazy_static! {
         (ControlKey::RWin, Key::RWin),
         (ControlKey::RControl, Key::RightControl),
         (ControlKey::RShift, Key::RightShift),
+        (ControlKey::VolumeMute, Key::Mute),
+        (ControlKey::VolumeDown, Key::VolumeDown),
+        (ControlKey::VolumeUp, Key::VolumeUp),
+        (ControlKey::MediaPlayPause, Key::MediaPlayPause),
+        (ControlKey::MediaNextTrack, Key::MediaNextTrack),
+        (ControlKey::MediaPrevTrack, Key::MediaPrevTrack),
+        (ControlKey::MediaStop, Key::MediaStop),
     ].iter().map(|(a, b)| (a.value(), b.clone())).collect();
     static ref NUMPAD_KEY_MAP: HashMap<i32, bool> =
     [
@@ -516,7 +748,11 @@ lazy_static::lazy_static! {
     ].iter().map(|(a, b)| (a.value(), b.clone())).collect();
 }
 
-pub fn handle_key(evt: &KeyEvent) {
+/// `conn` is 0 if not from a connection.
+pub fn handle_key(evt: &KeyEvent, conn: i32) {
+    if is_view_only(conn) {
+        return;
+    }
     #[cfg(target_os = "macos")]
     if !*IS_SERVER {
         // having GUI, run main GUI thread, otherwise crash
@@ -634,6 +870,23 @@ fn handle_key_(evt: &KeyEvent) {
                     .remove(&(chr as i32 + KEY_CHAR_START));
             }
         }
+        Some(key_event::Union::scan_code(code)) => {
+            if let Some(key) = get_positional_key(code) {
+                if evt.down {
+                    allow_err!(en.key_down(key));
+                    KEYS_DOWN
+                        .lock()
+                        .unwrap()
+                        .insert(code as i32 + KEY_SCAN_START, Instant::now());
+                } else {
+                    en.key_up(key);
+                    KEYS_DOWN
+                        .lock()
+                        .unwrap()
+                        .remove(&(code as i32 + KEY_SCAN_START));
+                }
+            }
+        }
         Some(key_event::Union::unicode(chr)) => {
             if let Ok(chr) = char::try_from(chr) {
                 en.key_sequence(&chr.to_string());
@@ -655,6 +908,85 @@ fn handle_key_(evt: &KeyEvent) {
     }
 }
 
+// the key at the position of a PC set 1 scan code, whatever the layout
+#[cfg(windows)]
+fn get_positional_key(code: u32) -> Option<Key> {
+    // MAPVK_VSC_TO_VK_EX
+    let vk = unsafe { winapi::um::winuser::MapVirtualKeyW(code, 3) };
+    if vk == 0 {
+        return None;
+    }
+    Some(Key::Raw(vk as _))
+}
+
+#[cfg(target_os = "linux")]
+fn get_positional_key(code: u32) -> Option<Key> {
+    // the X key codes are the evdev ones, i.e. the scan codes here, plus 8
+    if code == 0 || code > 0x7F {
+        return None;
+    }
+    Some(Key::Raw((code + 8) as _))
+}
+
+#[cfg(target_os = "macos")]
+fn get_positional_key(code: u32) -> Option<Key> {
+    // scan code -> kVK_ANSI_*
+    const KEY_CODES: [(u32, u16); 48] = [
+        (0x02, 0x12),
+        (0x03, 0x13),
+        (0x04, 0x14),
+        (0x05, 0x15),
+        (0x06, 0x17),
+        (0x07, 0x16),
+        (0x08, 0x1A),
+        (0x09, 0x1C),
+        (0x0A, 0x19),
+        (0x0B, 0x1D),
+        (0x0C, 0x1B),
+        (0x0D, 0x18),
+        (0x10, 0x0C),
+        (0x11, 0x0D),
+        (0x12, 0x0E),
+        (0x13, 0x0F),
+        (0x14, 0x11),
+        (0x15, 0x10),
+        (0x16, 0x20),
+        (0x17, 0x22),
+        (0x18, 0x1F),
+        (0x19, 0x23),
+        (0x1A, 0x21),
+        (0x1B, 0x1E),
+        (0x1E, 0x00),
+        (0x1F, 0x01),
+        (0x20, 0x02),
+        (0x21, 0x03),
+        (0x22, 0x05),
+        (0x23, 0x04),
+        (0x24, 0x26),
+        (0x25, 0x28),
+        (0x26, 0x25),
+        (0x27, 0x29),
+        (0x28, 0x27),
+        (0x29, 0x32),
+        (0x2B, 0x2A),
+        (0x2C, 0x06),
+        (0x2D, 0x07),
+        (0x2E, 0x08),
+        (0x2F, 0x09),
+        (0x30, 0x0B),
+        (0x31, 0x2D),
+        (0x32, 0x2E),
+        (0x33, 0x2B),
+        (0x34, 0x2F),
+        (0x35, 0x2C),
+        (0x56, 0x0A),
+    ];
+    KEY_CODES
+        .iter()
+        .find(|x| x.0 == code)
+        .map(|x| Key::Raw(x.1 as _))
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_sas() -> ResultType<()> {
     let mut stream = crate::ipc::connect(1000, crate::POSTFIX_SERVICE).await?;
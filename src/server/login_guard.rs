@@ -0,0 +1,136 @@
+// Fail2ban like protection of the logins: the wrong passwords or 2FA codes
+// of a source ip make the next attempt wait exponentially longer, and too many
+// of them ban it for a while, longer for each ban. The bans are kept in
+// Config2, to be listed and lifted in the main window. The peer id of a login
+// is not taken, it is whatever the peer claims, and would let anyone lock a
+// known id out.
+use hbb_common::{config::Config, log};
+use std::{collections::HashMap, sync::Mutex};
+
+// failures without any delay before the next attempt
+const FREE_FAILURES: u32 = 3;
+const MAX_DELAY: i64 = 60_000;
+// failures in a row banning the source ip
+const BAN_FAILURES: u32 = 10;
+// the first ban, doubled for each one after
+const BAN_TIME: i64 = 10 * 60_000;
+const MAX_BAN_TIME: i64 = 24 * 3600_000;
+// the failures are forgotten after this without any
+const FORGET_TIME: i64 = 3600_000;
+// the expired bans are forgotten after this, and the next one is short again
+const BAN_MEMORY: i64 = 7 * 24 * 3600_000;
+
+lazy_static::lazy_static! {
+    // "ip:<addr>" -> (failures in a row, time of the last)
+    static ref FAILURES: Mutex<HashMap<String, (u32, i64)>> = Default::default();
+}
+
+fn get_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn is_banned(ip: &str) -> bool {
+    let now = crate::get_time();
+    let bans = Config::get_bans();
+    bans.get(&get_key(ip)).map(|x| x.0 > now).unwrap_or(false)
+}
+
+/// If the next attempt is too early after the last failures.
+pub fn is_delayed(ip: &str) -> bool {
+    let now = crate::get_time();
+    let failures = FAILURES.lock().unwrap();
+    match failures.get(&get_key(ip)) {
+        Some((n, last)) => now < last + get_delay(*n),
+        None => false,
+    }
+}
+
+pub fn add_failure(ip: &str) {
+    super::metrics::add(&super::metrics::FAILED_LOGINS, 1);
+    let now = crate::get_time();
+    let key = get_key(ip);
+    if !add_failure_(&mut FAILURES.lock().unwrap(), &key, now) {
+        return;
+    }
+    let mut bans = Config::get_bans();
+    let ban = bans.entry(key.clone()).or_insert((0, 0));
+    if now - ban.0 > BAN_MEMORY {
+        ban.1 = 0;
+    }
+    ban.1 += 1;
+    let time = get_ban_time(ban.1);
+    ban.0 = now + time;
+    log::warn!(
+        "{} banned for {} minutes after {} failed logins",
+        key,
+        time / 60_000,
+        BAN_FAILURES
+    );
+    bans.retain(|_, x| now - x.0 <= BAN_MEMORY);
+    Config::set_bans(bans);
+}
+
+pub fn clear_failures(ip: &str) {
+    FAILURES.lock().unwrap().remove(&get_key(ip));
+}
+
+// true if the failures in a row reach the ban, the forgotten ones of all the
+// ips dropped, not to grow with every source ever seen
+fn add_failure_(failures: &mut HashMap<String, (u32, i64)>, key: &str, now: i64) -> bool {
+    failures.retain(|_, x| now - x.1 <= FORGET_TIME);
+    let failure = failures.entry(key.to_owned()).or_insert((0, now));
+    failure.0 += 1;
+    failure.1 = now;
+    if failure.0 >= BAN_FAILURES {
+        failures.remove(key);
+        return true;
+    }
+    false
+}
+
+fn get_delay(failures: u32) -> i64 {
+    if failures < FREE_FAILURES {
+        return 0;
+    }
+    (1_000i64 << (failures - FREE_FAILURES).min(16)).min(MAX_DELAY)
+}
+
+fn get_ban_time(times: u32) -> i64 {
+    (BAN_TIME << (times.max(1) - 1).min(16)).min(MAX_BAN_TIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_ban() {
+        assert_eq!(get_delay(FREE_FAILURES - 1), 0);
+        assert_eq!(get_delay(FREE_FAILURES), 1_000);
+        assert_eq!(get_delay(FREE_FAILURES + 2), 4_000);
+        assert_eq!(get_delay(100), MAX_DELAY);
+        assert_eq!(get_ban_time(1), BAN_TIME);
+        assert_eq!(get_ban_time(2), 2 * BAN_TIME);
+        assert_eq!(get_ban_time(100), MAX_BAN_TIME);
+        let mut failures = HashMap::new();
+        for i in 1..BAN_FAILURES {
+            assert!(!add_failure_(&mut failures, "ip:1.2.3.4", i as i64));
+        }
+        assert!(add_failure_(&mut failures, "ip:1.2.3.4", 100));
+        assert!(failures.is_empty());
+        // the old failures are forgotten
+        for i in 1..BAN_FAILURES {
+            assert!(!add_failure_(
+                &mut failures,
+                "ip:5.6.7.8",
+                i as i64 * FORGET_TIME * 2
+            ));
+        }
+        assert_eq!(failures.get("ip:5.6.7.8").map(|x| x.0), Some(1));
+        // and dropped with the next failure of another ip
+        let now = BAN_FAILURES as i64 * FORGET_TIME * 2;
+        add_failure_(&mut failures, "ip:1.2.3.4", now);
+        assert!(failures.get("ip:5.6.7.8").is_none());
+        assert_eq!(failures.len(), 1);
+    }
+}
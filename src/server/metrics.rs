@@ -0,0 +1,181 @@
+// Metrics of the service in the Prometheus text format, served at /metrics on
+// the address of the option "metrics-address", e.g. 0.0.0.0:9400, or a port
+// alone for localhost only. Nothing listens with the option empty.
+use hbb_common::{
+    config::Config,
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::{interval, timeout, Duration},
+    },
+};
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+pub static FAILED_LOGINS: AtomicU64 = AtomicU64::new(0);
+// the connections accepted, over a relay or not
+pub static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub static RELAY_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+const CONN_TYPES: [&str; 4] = ["desktop", "file-transfer", "port-forward", "terminal"];
+
+#[inline]
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn start() {
+    std::thread::spawn(run);
+}
+
+// the option is checked again every few seconds, to listen on a new address
+#[tokio::main(flavor = "current_thread")]
+async fn run() {
+    let mut address = "".to_owned();
+    let mut listener = None;
+    let mut timer = interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let new_address = get_address();
+                if new_address == address {
+                    continue;
+                }
+                address = new_address;
+                listener = None;
+                if address.is_empty() {
+                    continue;
+                }
+                match TcpListener::bind(&address).await {
+                    Ok(x) => {
+                        log::info!("Metrics listening on {}", address);
+                        listener = Some(x);
+                    }
+                    Err(err) => {
+                        log::error!("Failed to listen on {} for the metrics: {}", address, err);
+                    }
+                }
+            }
+            Ok((socket, _)) = accept(&listener) => {
+                tokio::spawn(serve(socket));
+            }
+        }
+    }
+}
+
+fn get_address() -> String {
+    let address = Config::get_option("metrics-address");
+    if address.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", address)
+    } else {
+        address
+    }
+}
+
+// never ready without a listener
+async fn accept(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn serve(socket: TcpStream) {
+    let mut socket = socket;
+    let mut buf = vec![0u8; 4096];
+    let n = match timeout(Duration::from_secs(5), socket.read(&mut buf)).await {
+        Ok(Ok(n)) => n,
+        _ => return,
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let response = if request.starts_with("GET /metrics ") {
+        let body = get_metrics();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    socket.write_all(response.as_bytes()).await.ok();
+}
+
+fn get_metrics() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+        writeln!(out, "# HELP {} {}", name, help).ok();
+        writeln!(out, "# TYPE {} {}", name, kind).ok();
+        for (labels, value) in values {
+            writeln!(out, "{}{} {}", name, labels, value).ok();
+        }
+    };
+    let get = |counter: &AtomicU64| vec![("".to_owned(), counter.load(Ordering::Relaxed))];
+    metric(
+        "rustdesk_bytes_sent_total",
+        "counter",
+        "Bytes sent to the peers.",
+        get(&BYTES_SENT),
+    );
+    metric(
+        "rustdesk_bytes_received_total",
+        "counter",
+        "Bytes received from the peers.",
+        get(&BYTES_RECEIVED),
+    );
+    let (_, fps, bitrate) = super::video_service::get_encoder_stats();
+    metric(
+        "rustdesk_encoder_fps",
+        "gauge",
+        "Frames encoded a second of the current display.",
+        vec![("".to_owned(), fps as _)],
+    );
+    metric(
+        "rustdesk_encoder_bitrate_kbps",
+        "gauge",
+        "Target bitrate of the video encoder.",
+        vec![("".to_owned(), bitrate as _)],
+    );
+    let sessions = super::get_sessions();
+    metric(
+        "rustdesk_active_sessions",
+        "gauge",
+        "Logged in sessions by type.",
+        CONN_TYPES
+            .iter()
+            .map(|t| {
+                let n = sessions
+                    .iter()
+                    .filter(|x| x.authorized && x.conn_type == *t)
+                    .count();
+                (format!("{{type=\"{}\"}}", t), n as _)
+            })
+            .collect(),
+    );
+    metric(
+        "rustdesk_failed_logins_total",
+        "counter",
+        "Wrong passwords and 2FA codes.",
+        get(&FAILED_LOGINS),
+    );
+    let total = CONNECTIONS.load(Ordering::Relaxed);
+    let relay = RELAY_CONNECTIONS.load(Ordering::Relaxed).min(total);
+    metric(
+        "rustdesk_connections_total",
+        "counter",
+        "Connections accepted by the way they came.",
+        vec![
+            ("{path=\"direct\"}".to_owned(), total - relay),
+            ("{path=\"relay\"}".to_owned(), relay),
+        ],
+    );
+    out
+}
@@ -0,0 +1,56 @@
+// Rotation of the temporary (random) password, so that a shared one can not be
+// used again later, by the options: "rotate-password-after-session" ("Y") after
+// each login with it, "rotate-password-after-failures" after as many wrong
+// passwords or 2FA codes, and "rotate-password-interval" every as many minutes.
+// The permanent password is never rotated.
+use hbb_common::{config::Config, log};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+lazy_static::lazy_static! {
+    // (failures since the last rotation, time of the last rotation)
+    static ref STATE: Mutex<(u32, Instant)> = Mutex::new((0, Instant::now()));
+}
+
+pub fn start_timer_loop() {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(10));
+        let minutes = get_number("rotate-password-interval");
+        if minutes > 0 && STATE.lock().unwrap().1.elapsed().as_secs() >= minutes as u64 * 60 {
+            rotate("timer");
+        }
+    });
+}
+
+pub fn on_login() {
+    if Config::get_option("rotate-password-after-session") == "Y" {
+        rotate("login");
+    }
+}
+
+pub fn on_failure() {
+    let max = get_number("rotate-password-after-failures");
+    let failures = {
+        let mut state = STATE.lock().unwrap();
+        state.0 += 1;
+        state.0
+    };
+    if max > 0 && failures >= max {
+        rotate("failed logins");
+    }
+}
+
+fn get_number(name: &str) -> u32 {
+    Config::get_option(name).parse().unwrap_or(0)
+}
+
+fn rotate(reason: &str) {
+    *STATE.lock().unwrap() = (0, Instant::now());
+    if !Config::is_random_password() {
+        return;
+    }
+    Config::set_password(&Config::get_auto_password());
+    log::info!("temporary password rotated after {}", reason);
+}
@@ -0,0 +1,273 @@
+// The virtual printer of the host, its jobs sent to the sessions whose peers
+// have asked for them, with the file transfer allowed, to be printed on a
+// printer there or saved. The printer is added on the first such session:
+// windows: "RustDesk Printer" of the driver Microsoft Print to PDF, on a port
+// which is a file of the spool folder, moved aside once the spooler is done
+// linux: a CUPS queue of the same name, with a backend writing the PDF of the
+// jobs to the spool folder
+// The jobs printed while no one takes them are dropped.
+
+use super::*;
+use hbb_common::{
+    bail,
+    config::{Config, APP_NAME},
+    ResultType,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const NAME: &'static str = "printer";
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_JOB_SIZE: u64 = 100 * 1024 * 1024;
+// on windows, the file the driver prints to, on the port of the printer
+const PORT_FILE: &str = "port.pdf";
+
+#[derive(Default)]
+struct State {
+    installed: bool,
+    next_id: i32,
+}
+
+impl Reset for State {
+    fn reset(&mut self) {
+        for (path, _) in get_jobs() {
+            fs::remove_file(&path).ok();
+        }
+    }
+}
+
+pub fn new() -> GenericService {
+    let sp = GenericService::new(NAME, false);
+    sp.repeat::<State, _>(1_000, run);
+    sp
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    cfg!(any(windows, target_os = "linux")) && Config::get_option("enable-remote-printer") != "N"
+}
+
+fn get_printer_name() -> String {
+    format!("{} Printer", APP_NAME)
+}
+
+fn get_spool_dir() -> PathBuf {
+    Config::print_dir("spool")
+}
+
+fn run(sp: GenericService, state: &mut State) -> ResultType<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    if !state.installed {
+        // tried once, the printer may have been removed on purpose
+        state.installed = true;
+        fs::create_dir_all(get_spool_dir())?;
+        if let Err(err) = install() {
+            log::error!("Failed to add the virtual printer: {}", err);
+        }
+    }
+    for (path, name) in get_jobs() {
+        state.next_id += 1;
+        if let Err(err) = send_job(&sp, state.next_id, &path, &name) {
+            log::error!("Failed to send the print job {:?}: {}", path, err);
+        }
+        fs::remove_file(&path).ok();
+    }
+    Ok(())
+}
+
+fn send_job(sp: &GenericService, id: i32, path: &Path, name: &str) -> ResultType<()> {
+    let data = fs::read(path)?;
+    if data.is_empty() {
+        bail!("empty");
+    }
+    if data.len() as u64 > MAX_JOB_SIZE {
+        bail!("too large, {} bytes", data.len());
+    }
+    let format = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    log::info!("Print job #{} {}, {} bytes", id, name, data.len());
+    let n = (data.len() + CHUNK_SIZE - 1) / CHUNK_SIZE;
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        let mut msg_out = Message::new();
+        msg_out.set_print_job(PrintJob {
+            id,
+            name: name.to_owned(),
+            format: format.clone(),
+            total_size: data.len() as _,
+            data: chunk.to_vec().into(),
+            last: i + 1 == n,
+            ..Default::default()
+        });
+        sp.send(msg_out);
+    }
+    Ok(())
+}
+
+// the finished jobs, with the names of their documents
+fn get_jobs() -> Vec<(PathBuf, String)> {
+    let dir = get_spool_dir();
+    #[cfg(windows)]
+    windows::take_port_file(&dir);
+    let mut jobs = Vec::new();
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let ext = path.extension().map(|x| x.to_string_lossy().to_lowercase());
+            if ext.as_deref() != Some("pdf") && ext.as_deref() != Some("xps") {
+                continue;
+            }
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.starts_with('.') || file_name == PORT_FILE {
+                continue;
+            }
+            // written along by the backend of CUPS
+            let title = path.with_extension("title");
+            let name = fs::read_to_string(&title).unwrap_or_default();
+            fs::remove_file(&title).ok();
+            let name = if name.trim().is_empty() {
+                file_name
+            } else {
+                name.trim().to_owned()
+            };
+            jobs.push((path, name));
+        }
+    }
+    jobs
+}
+
+#[cfg(windows)]
+use windows::install;
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+
+    // moved aside once the spooler has closed it, a job at a time
+    pub fn take_port_file(dir: &Path) {
+        let port = dir.join(PORT_FILE);
+        if port.exists() {
+            let job = dir.join(format!("{}.pdf", crate::get_time()));
+            fs::rename(&port, &job).ok();
+        }
+    }
+
+    pub fn install() -> ResultType<()> {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        let port = get_spool_dir().join(PORT_FILE);
+        let script = format!(
+            "if (-not (Get-Printer -Name '{name}' -ErrorAction SilentlyContinue)) {{ \
+                Add-PrinterPort -Name '{port}'; \
+                Add-Printer -Name '{name}' -DriverName 'Microsoft Print to PDF' -PortName '{port}' \
+            }}",
+            name = get_printer_name(),
+            port = port.to_string_lossy().replace('\'', "''"),
+        );
+        let output = std::process::Command::new("powershell")
+            .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+            .creation_flags(CREATE_NO_WINDOW)
+            .output()?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::install;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const BACKEND: &str = "/usr/lib/cups/backend/rustdesk";
+
+    // a PostScript printer description whose jobs are turned into PDF by the
+    // filters of CUPS
+    const PPD: &str = r#"*PPD-Adobe: "4.3"
+*FormatVersion: "4.3"
+*FileVersion: "1.0"
+*LanguageVersion: English
+*LanguageEncoding: ISOLatin1
+*PCFileName: "RUSTDESK.PPD"
+*Manufacturer: "RustDesk"
+*Product: "(RustDesk Printer)"
+*ModelName: "RustDesk Printer"
+*ShortNickName: "RustDesk Printer"
+*NickName: "RustDesk Printer"
+*PSVersion: "(3010.000) 0"
+*LanguageLevel: "3"
+*ColorDevice: True
+*DefaultColorSpace: RGB
+*FileSystem: False
+*Throughput: "1"
+*LandscapeOrientation: Plus90
+*TTRasterizer: Type42
+*cupsFilter2: "application/pdf application/pdf 0 -"
+*OpenUI *PageSize/Media Size: PickOne
+*OrderDependency: 10 AnySetup *PageSize
+*DefaultPageSize: A4
+*PageSize A4/A4: "<</PageSize[595 842]>>setpagedevice"
+*PageSize Letter/Letter: "<</PageSize[612 792]>>setpagedevice"
+*CloseUI: *PageSize
+*DefaultImageableArea: A4
+*ImageableArea A4: "0 0 595 842"
+*ImageableArea Letter: "0 0 612 792"
+*DefaultPaperDimension: A4
+*PaperDimension A4: "595 842"
+*PaperDimension Letter: "612 792"
+"#;
+
+    // run by CUPS as root with the file of the job or it on stdin, written
+    // aside then moved, so that only the finished jobs are seen
+    fn get_backend(dir: &Path) -> String {
+        format!(
+            r#"#!/bin/sh
+if [ $# -eq 0 ]; then
+    echo 'direct rustdesk "Unknown" "{name}"'
+    exit 0
+fi
+dir='{dir}'
+tmp="$dir/.$1.tmp"
+if [ $# -ge 6 ]; then cat "$6" > "$tmp"; else cat > "$tmp"; fi
+printf '%s' "$3" > "$dir/$1.title"
+mv "$tmp" "$dir/$1.pdf"
+"#,
+            name = get_printer_name(),
+            dir = dir.to_string_lossy().replace('\'', "'\\''"),
+        )
+    }
+
+    pub fn install() -> ResultType<()> {
+        fs::write(BACKEND, get_backend(&get_spool_dir()))?;
+        // 0700 for CUPS to run it as root, the spool folder being root's
+        fs::set_permissions(BACKEND, fs::Permissions::from_mode(0o700))?;
+        let ppd = std::env::temp_dir().join("rustdesk-printer.ppd");
+        fs::write(&ppd, PPD)?;
+        let output = std::process::Command::new("lpadmin")
+            .arg("-p")
+            .arg(get_printer_name().replace(' ', "_"))
+            .args(&["-E", "-v", "rustdesk:/", "-P"])
+            .arg(&ppd)
+            .output();
+        fs::remove_file(&ppd).ok();
+        let output = output?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn install() -> ResultType<()> {
+    bail!("No virtual printer on macOS yet")
+}
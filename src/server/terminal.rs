@@ -0,0 +1,105 @@
+// The shell of a terminal session, in a pseudo terminal: ConPTY on Windows,
+// forkpty on the others. Its output is sent to the peer as it comes, and the
+// peer's terminal does the emulation, so that no video is needed at all.
+use hbb_common::{log, message_proto::*, ResultType};
+use portable_pty::{native_pty_system, Child, ChildKiller, CommandBuilder, MasterPty, PtySize};
+use std::io::{Read, Write};
+
+pub struct Terminal {
+    master: Box<dyn MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    child: Box<dyn Child + Send + Sync>,
+}
+
+impl Terminal {
+    pub fn start<F: FnMut(Message) + Send + 'static>(
+        rows: u32,
+        cols: u32,
+        send: F,
+    ) -> ResultType<Self> {
+        let pair = native_pty_system().openpty(get_size(rows, cols))?;
+        let child = pair.slave.spawn_command(get_shell())?;
+        // the reader gets the end of the output once the shell exits
+        drop(pair.slave);
+        let mut reader = pair.master.try_clone_reader()?;
+        let writer = pair.master.take_writer()?;
+        let mut send = send;
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(n) if n > 0 => n,
+                    _ => break,
+                };
+                let mut msg_out = Message::new();
+                msg_out.set_terminal_data(TerminalData {
+                    data: buf[..n].to_vec().into(),
+                    ..Default::default()
+                });
+                send(msg_out);
+            }
+            log::info!("Terminal closed");
+            let mut msg_out = Message::new();
+            msg_out.set_terminal_data(TerminalData {
+                closed: true,
+                ..Default::default()
+            });
+            send(msg_out);
+        });
+        Ok(Self {
+            master: pair.master,
+            writer,
+            child,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        if let Err(err) = self.writer.write_all(data) {
+            log::error!("Failed to write to the terminal: {}", err);
+        }
+    }
+
+    pub fn resize(&self, rows: u32, cols: u32) {
+        if let Err(err) = self.master.resize(get_size(rows, cols)) {
+            log::error!("Failed to resize the terminal: {}", err);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+    }
+}
+
+fn get_size(rows: u32, cols: u32) -> PtySize {
+    PtySize {
+        rows: rows.max(1).min(u16::MAX as _) as _,
+        cols: cols.max(1).min(u16::MAX as _) as _,
+        pixel_width: 0,
+        pixel_height: 0,
+    }
+}
+
+#[cfg(windows)]
+fn get_shell() -> CommandBuilder {
+    let mut cmd = CommandBuilder::new("powershell.exe");
+    cmd.arg("-NoLogo");
+    if let Ok(home) = std::env::var("USERPROFILE") {
+        cmd.cwd(home);
+    }
+    cmd
+}
+
+// a login shell of the user the host runs as
+#[cfg(not(windows))]
+fn get_shell() -> CommandBuilder {
+    let shell = std::env::var("SHELL").unwrap_or("/bin/sh".to_owned());
+    let mut cmd = CommandBuilder::new(shell);
+    cmd.arg("-l");
+    cmd.env("TERM", "xterm-256color");
+    if let Ok(home) = std::env::var("HOME") {
+        cmd.cwd(home);
+    }
+    cmd
+}
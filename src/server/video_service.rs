@@ -26,6 +26,10 @@ use hbb_common::tokio::{
         Mutex as TokioMutex,
     },
 };
+#[cfg(feature = "av1")]
+use scrap::av1;
+#[cfg(feature = "hwcodec")]
+use scrap::hwcodec;
 use scrap::{Capturer, Config, Display, EncodeFrame, Encoder, VideoCodecId, STRIDE_ALIGN};
 use std::{
     collections::HashSet,
@@ -34,6 +38,13 @@ use std::{
 };
 
 const WAIT_BASE: i32 = 17;
+const DEFAULT_FEC_RATIO: f32 = 0.1;
+const MAX_FPS: u32 = 30;
+const MIN_FPS: u32 = 5;
+// range of the bandwidth estimate around the bitrate of the image quality
+const MAX_BITRATE_GAIN: f32 = 2.;
+const MIN_BITRATE_GAIN: f32 = 0.125;
+const MIN_CROP_SIZE: i32 = 64;
 pub const NAME: &'static str = "video";
 
 lazy_static::lazy_static! {
@@ -42,6 +53,21 @@ lazy_static::lazy_static! {
     static ref SWITCH: Arc<Mutex<bool>> = Default::default();
     static ref TEST_LATENCIES: Arc<Mutex<HashMap<i32, i64>>> = Default::default();
     static ref IMAGE_QUALITIES: Arc<Mutex<HashMap<i32, i32>>> = Default::default();
+    static ref BANDWIDTH_ESTIMATES: Arc<Mutex<HashMap<i32, u32>>> = Default::default();
+    static ref FPS_CAPS: Arc<Mutex<HashMap<i32, u32>>> = Default::default();
+    // the connections whose decoder needs a keyframe
+    static ref KEY_FRAME_REQUESTS: Arc<Mutex<HashSet<i32>>> = Default::default();
+    static ref SUPPORTED_DECODINGS: Arc<Mutex<HashMap<i32, SupportedDecoding>>> = Default::default();
+    static ref CAPTURE_DISPLAYS: Arc<Mutex<HashMap<i32, Vec<usize>>>> = Default::default();
+    static ref CAPTURE_REGIONS: Arc<Mutex<HashMap<i32, CaptureRegion>>> = Default::default();
+    static ref RESOLUTIONS: Arc<Mutex<HashMap<i32, (usize, usize)>>> = Default::default();
+    // (codec, fps, bitrate) of the current display
+    static ref ENCODER_STATS: Arc<Mutex<(String, u32, u32)>> = Default::default();
+    // the display whose mode is changed for the connections, and its own size
+    static ref ORIGINAL_RESOLUTION: Arc<Mutex<Option<(String, (usize, usize))>>> = Default::default();
+    // per codec, the option it is probed with, and the result
+    #[cfg(feature = "hwcodec")]
+    static ref HW_BACKENDS: Arc<Mutex<HashMap<hwcodec::HwCodec, (String, Option<hwcodec::HwBackend>)>>> = Default::default();
     static ref FRAME_FETCHED_NOTIFIER: (UnboundedSender<(i32, Option<Instant>)>, Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>) = {
         let (tx, rx) = unbounded_channel();
         (tx, Arc::new(TokioMutex::new(rx)))
@@ -122,6 +148,11 @@ impl VideoFrameController {
 }
 
 pub fn new() -> GenericService {
+    #[cfg(all(target_os = "linux", feature = "wayland"))]
+    scrap::wayland::pipewire::set_restore_token_store(
+        hbb_common::config::Config::get_restore_token,
+        hbb_common::config::Config::set_restore_token,
+    );
     let sp = GenericService::new(NAME, true);
     sp.run(run);
     sp
@@ -157,9 +188,209 @@ fn check_display_changed(
     return false;
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CodecFormat {
+    VP9,
+    AV1,
+    H264,
+    H265,
+}
+
+impl CodecFormat {
+    #[cfg(feature = "hwcodec")]
+    fn hw_codec(&self) -> Option<hwcodec::HwCodec> {
+        match self {
+            CodecFormat::H264 => Some(hwcodec::HwCodec::H264),
+            CodecFormat::H265 => Some(hwcodec::HwCodec::H265),
+            _ => None,
+        }
+    }
+}
+
+// One encoder is shared by all the connections, so the codec is one every
+// one of them can decode.
+enum VideoEncoder {
+    VP9(Encoder),
+    #[cfg(feature = "av1")]
+    AV1(av1::Encoder),
+    #[cfg(feature = "hwcodec")]
+    Hw(hwcodec::HwEncoder),
+}
+
+impl VideoEncoder {
+    fn new(cfg: &Config, codec: CodecFormat) -> ResultType<Self> {
+        match codec {
+            #[cfg(feature = "av1")]
+            CodecFormat::AV1 => {
+                return match av1::Encoder::new(cfg, 0) {
+                    Ok(x) => Ok(VideoEncoder::AV1(x)),
+                    Err(err) => bail!("Failed to create av1 encoder: {}", err),
+                };
+            }
+            #[cfg(feature = "hwcodec")]
+            CodecFormat::H264 | CodecFormat::H265 => {
+                let hw_codec = codec.hw_codec().unwrap();
+                if let Some(backend) = get_hw_backend(hw_codec) {
+                    match hwcodec::HwEncoder::new(
+                        backend,
+                        hw_codec,
+                        cfg.width as _,
+                        cfg.height as _,
+                        cfg.bitrate,
+                        MAX_FPS,
+                    ) {
+                        Ok(x) => return Ok(VideoEncoder::Hw(x)),
+                        Err(err) => {
+                            // it passed the probe, but not at this size or any more
+                            disable_hw_backend(hw_codec);
+                            bail!(
+                                "Failed to create {:?} {:?} encoder: {}",
+                                backend,
+                                hw_codec,
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        match Encoder::new(cfg, 0) {
+            Ok(x) => Ok(VideoEncoder::VP9(x)),
+            Err(err) => bail!("Failed to create encoder: {}", err),
+        }
+    }
+
+    fn set_bitrate(&mut self, bitrate: u32) -> ResultType<()> {
+        match self {
+            VideoEncoder::VP9(vpx) => vpx.set_bitrate(bitrate)?,
+            #[cfg(feature = "av1")]
+            VideoEncoder::AV1(aom) => aom.set_bitrate(bitrate)?,
+            #[cfg(feature = "hwcodec")]
+            VideoEncoder::Hw(hw) => hw.set_bitrate(bitrate)?,
+        }
+        Ok(())
+    }
+
+    // false if this encoder can not, a new one is needed then
+    fn request_key_frame(&mut self) -> bool {
+        match self {
+            VideoEncoder::VP9(vpx) => vpx.request_key_frame(),
+            #[cfg(feature = "av1")]
+            VideoEncoder::AV1(aom) => aom.request_key_frame(),
+            #[cfg(feature = "hwcodec")]
+            VideoEncoder::Hw(_) => return false,
+        }
+        true
+    }
+
+    fn encode(&mut self, ms: i64, frame: &[u8]) -> ResultType<Option<Message>> {
+        let mut frames = Vec::new();
+        match self {
+            VideoEncoder::VP9(vpx) => {
+                for ref frame in vpx
+                    .encode(ms, frame, STRIDE_ALIGN)
+                    .with_context(|| "Failed to encode")?
+                {
+                    frames.push(create_frame(frame));
+                }
+                for ref frame in vpx.flush().with_context(|| "Failed to flush")? {
+                    frames.push(create_frame(frame));
+                }
+            }
+            #[cfg(feature = "av1")]
+            VideoEncoder::AV1(aom) => {
+                for ref frame in aom
+                    .encode(ms, frame, STRIDE_ALIGN)
+                    .with_context(|| "Failed to encode")?
+                {
+                    frames.push(create_frame(frame));
+                }
+                for ref frame in aom.flush().with_context(|| "Failed to flush")? {
+                    frames.push(create_frame(frame));
+                }
+            }
+            #[cfg(feature = "hwcodec")]
+            VideoEncoder::Hw(hw) => {
+                for frame in hw
+                    .encode(ms, frame, STRIDE_ALIGN)
+                    .with_context(|| "Failed to encode")?
+                {
+                    frames.push(VP9 {
+                        data: frame.data,
+                        key: frame.key,
+                        pts: frame.pts,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        // to-do: flush periodically, e.g. 1 second
+        if frames.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(match self {
+            VideoEncoder::VP9(_) => create_msg(frames),
+            #[cfg(feature = "av1")]
+            VideoEncoder::AV1(_) => create_av1_msg(frames),
+            #[cfg(feature = "hwcodec")]
+            VideoEncoder::Hw(hw) => match hw.codec() {
+                hwcodec::HwCodec::H264 => create_h264_msg(frames),
+                hwcodec::HwCodec::H265 => create_h265_msg(frames),
+            },
+        }))
+    }
+}
+
+// A display streamed besides the current one, in the same thread with its own
+// capturer and encoder, view only on the client.
+struct ExtraDisplay {
+    index: usize,
+    capturer: Capturer,
+    encoder: VideoEncoder,
+    bitrate: u32,
+    crc: (u32, u32),
+}
+
+fn create_extra_displays(
+    indexes: &Vec<usize>,
+    q: i32,
+    codec: CodecFormat,
+) -> ResultType<Vec<ExtraDisplay>> {
+    let mut extras = Vec::new();
+    for (index, display) in Display::all()?.into_iter().enumerate() {
+        if !indexes.contains(&index) {
+            continue;
+        }
+        let (width, height) = (display.width(), display.height());
+        let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) = get_quality(width, height, q);
+        let cfg = Config {
+            width: width as _,
+            height: height as _,
+            timebase: [1, 1000],
+            bitrate,
+            codec: VideoCodecId::VP9,
+            rc_min_quantizer,
+            rc_max_quantizer,
+            speed,
+        };
+        extras.push(ExtraDisplay {
+            index,
+            capturer: Capturer::new(display, true).with_context(|| "Failed to create capturer")?,
+            encoder: VideoEncoder::new(&cfg, codec)?,
+            bitrate,
+            crc: (0, 0),
+        });
+    }
+    Ok(extras)
+}
+
 fn run(sp: GenericService) -> ResultType<()> {
-    let fps = 30;
-    let spf = time::Duration::from_secs_f32(1. / (fps as f32));
+    let mut spf = time::Duration::from_secs_f32(1. / (get_fps_cap() as f32));
+    #[cfg(any(windows, target_os = "linux"))]
+    if let Err(err) = super::virtual_display::plug_in_if_no_display() {
+        log::error!("Failed to plug in a virtual display: {}", err);
+    }
     let (ndisplay, current, display) = get_current_display()?;
     let (origin, width, height) = (display.origin(), display.width(), display.height());
     log::debug!(
@@ -172,14 +403,26 @@ fn run(sp: GenericService) -> ResultType<()> {
     );
     // Capturer object is expensive, avoiding to create it frequently.
     let mut c = Capturer::new(display, true).with_context(|| "Failed to create capturer")?;
+    let region = get_capture_region();
+    let crop = region
+        .as_ref()
+        .and_then(|r| get_crop(r, origin, width, height));
+    let mut cropped = Vec::new();
+    // what the peers see of the display
+    let (view_x, view_y, view_width, view_height) = match crop {
+        Some((x, y, w, h)) => (origin.0 + x as i32, origin.1 + y as i32, w, h),
+        None => (origin.0, origin.1, width, height),
+    };
+    log::debug!("crop: {:?}", crop);
 
     let q = get_image_quality();
-    let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) = get_quality(width, height, q);
+    let (bitrate, rc_min_quantizer, rc_max_quantizer, speed) =
+        get_quality(view_width, view_height, q);
     log::info!("bitrate={}, rc_min_quantizer={}", bitrate, rc_min_quantizer);
     let mut wait = WAIT_BASE;
     let cfg = Config {
-        width: width as _,
-        height: height as _,
+        width: view_width as _,
+        height: view_height as _,
         timebase: [1, 1000], // Output timestamp precision
         bitrate,
         codec: VideoCodecId::VP9,
@@ -187,21 +430,22 @@ fn run(sp: GenericService) -> ResultType<()> {
         rc_max_quantizer,
         speed,
     };
-    let mut vpx;
-    match Encoder::new(&cfg, 0) {
-        Ok(x) => vpx = x,
-        Err(err) => bail!("Failed to create encoder: {}", err),
-    }
+    let codec = negotiate_codec();
+    log::info!("codec: {:?}", codec);
+    let mut encoder = VideoEncoder::new(&cfg, codec)?;
+    let extra_indexes = get_extra_displays(current, ndisplay);
+    let mut extras = create_extra_displays(&extra_indexes, q, codec)?;
+    log::debug!("extra displays: {:?}", extra_indexes);
 
     if *SWITCH.lock().unwrap() {
         log::debug!("Broadcasting display switch");
         let mut misc = Misc::new();
         misc.set_switch_display(SwitchDisplay {
             display: current as _,
-            x: origin.0 as _,
-            y: origin.1 as _,
-            width: width as _,
-            height: height as _,
+            x: view_x as _,
+            y: view_y as _,
+            width: view_width as _,
+            height: view_height as _,
             ..Default::default()
         });
         let mut msg_out = Message::new();
@@ -215,6 +459,10 @@ fn run(sp: GenericService) -> ResultType<()> {
     let mut crc = (0, 0);
     let start = time::Instant::now();
     let mut last_check_displays = time::Instant::now();
+    let mut last_check_bandwidth = time::Instant::now();
+    let mut cur_bitrate = bitrate;
+    let mut nframes = 0;
+    *ENCODER_STATS.lock().unwrap() = (format!("{:?}", codec), 0, bitrate);
     #[cfg(windows)]
     let mut try_gdi = 1;
     #[cfg(windows)]
@@ -227,12 +475,20 @@ fn run(sp: GenericService) -> ResultType<()> {
             *SWITCH.lock().unwrap() = true;
             bail!("SWITCH");
         }
-        if get_image_quality() != q {
+        if get_image_quality() != q
+            || negotiate_codec() != codec
+            || get_extra_displays(current, ndisplay) != extra_indexes
+        {
             bail!("SWITCH");
         }
         #[cfg(windows)]
         {
             if crate::platform::windows::desktop_changed() {
+                // e.g. to the Winlogon desktop of the lock screen or a UAC prompt, the
+                // capturer is created again on it right away, without the error wait
+                if crate::platform::windows::try_change_desktop() {
+                    return Ok(());
+                }
                 bail!("Desktop changed");
             }
         }
@@ -244,16 +500,94 @@ fn run(sp: GenericService) -> ResultType<()> {
                 *SWITCH.lock().unwrap() = true;
                 bail!("SWITCH");
             }
+            // the window moved or was resized
+            if get_capture_region() != region
+                || region
+                    .as_ref()
+                    .map(|r| r.window > 0 && get_crop(r, origin, width, height) != crop)
+                    == Some(true)
+            {
+                *SWITCH.lock().unwrap() = true;
+                bail!("SWITCH");
+            }
+        }
+        if last_check_bandwidth.elapsed().as_millis() > 1000 {
+            let encoded_fps = nframes * 1000 / last_check_bandwidth.elapsed().as_millis() as usize;
+            nframes = 0;
+            last_check_bandwidth = now;
+            let (b, fps) = adapt_to_bandwidth(bitrate, get_bandwidth_estimate());
+            // avoid reconfiguring the encoder for noise
+            if (b as f32 - cur_bitrate as f32).abs() > cur_bitrate as f32 * 0.1 {
+                log::debug!("bitrate {} -> {}, fps={}", cur_bitrate, b, fps);
+                match encoder.set_bitrate(b) {
+                    Ok(()) => cur_bitrate = b,
+                    Err(err) => log::error!("Failed to set bitrate: {}", err),
+                }
+                for extra in extras.iter_mut() {
+                    let b = (extra.bitrate as u64 * b as u64 / bitrate as u64) as u32;
+                    allow_err!(extra.encoder.set_bitrate(b));
+                }
+            }
+            spf = time::Duration::from_secs_f32(1. / (fps.min(get_fps_cap()) as f32));
+            *ENCODER_STATS.lock().unwrap() =
+                (format!("{:?}", codec), encoded_fps as _, cur_bitrate);
         }
         *LAST_ACTIVE.lock().unwrap() = now;
 
+        if take_key_frame_requests() {
+            let mut ok = encoder.request_key_frame();
+            for extra in extras.iter_mut() {
+                ok &= extra.encoder.request_key_frame();
+            }
+            if !ok {
+                *SWITCH.lock().unwrap() = true;
+                bail!("SWITCH");
+            }
+        }
+
         frame_controller.reset();
 
+        let time = now - start;
+        let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
+        // before the current one, which skips the rest of the loop without a new image
+        for extra in extras.iter_mut() {
+            match extra.capturer.frame(0) {
+                Ok(frame) => {
+                    let send_conn_ids = handle_one_frame(
+                        &sp,
+                        &frame,
+                        ms,
+                        &mut extra.crc,
+                        &mut extra.encoder,
+                        extra.index + 1,
+                    )?;
+                    frame_controller.set_send(now, send_conn_ids);
+                }
+                Err(ref e) if e.kind() == WouldBlock => {}
+                Err(err) => {
+                    if check_display_changed(ndisplay, current, width, height) {
+                        log::info!("Displays changed");
+                        *SWITCH.lock().unwrap() = true;
+                        bail!("SWITCH");
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+
         match c.frame(wait as _) {
             Ok(frame) => {
-                let time = now - start;
-                let ms = (time.as_secs() * 1000 + time.subsec_millis() as u64) as i64;
-                let send_conn_ids = handle_one_frame(&sp, &frame, ms, &mut crc, &mut vpx)?;
+                let frame = match crop {
+                    Some((x, y, w, h)) => {
+                        scrap::i420_crop(width, height, &frame, x, y, w, h, &mut cropped);
+                        &cropped[..]
+                    }
+                    None => &frame[..],
+                };
+                let send_conn_ids = handle_one_frame(&sp, frame, ms, &mut crc, &mut encoder, 0)?;
+                if !send_conn_ids.is_empty() {
+                    nframes += 1;
+                }
                 frame_controller.set_send(now, send_conn_ids);
                 #[cfg(windows)]
                 {
@@ -298,6 +632,9 @@ fn run(sp: GenericService) -> ResultType<()> {
             std::thread::sleep(spf - elapsed);
         }
     }
+    // no connection any more
+    #[cfg(any(windows, target_os = "linux"))]
+    super::virtual_display::plug_out();
     Ok(())
 }
 
@@ -313,6 +650,72 @@ fn create_msg(vp9s: Vec<VP9>) -> Message {
     msg_out
 }
 
+#[cfg(feature = "av1")]
+#[inline]
+fn create_av1_msg(frames: Vec<VP9>) -> Message {
+    let mut msg_out = Message::new();
+    let mut vf = VideoFrame::new();
+    vf.set_av1s(AV1s {
+        frames: frames
+            .into_iter()
+            .map(|f| AV1 {
+                data: f.data,
+                key: f.key,
+                pts: f.pts,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+            .into(),
+        ..Default::default()
+    });
+    msg_out.set_video_frame(vf);
+    msg_out
+}
+
+#[cfg(feature = "hwcodec")]
+#[inline]
+fn create_h264_msg(frames: Vec<VP9>) -> Message {
+    let mut msg_out = Message::new();
+    let mut vf = VideoFrame::new();
+    vf.set_h264s(H264s {
+        frames: frames
+            .into_iter()
+            .map(|f| H264 {
+                data: f.data,
+                key: f.key,
+                pts: f.pts,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+            .into(),
+        ..Default::default()
+    });
+    msg_out.set_video_frame(vf);
+    msg_out
+}
+
+#[cfg(feature = "hwcodec")]
+#[inline]
+fn create_h265_msg(frames: Vec<VP9>) -> Message {
+    let mut msg_out = Message::new();
+    let mut vf = VideoFrame::new();
+    vf.set_h265s(H265s {
+        frames: frames
+            .into_iter()
+            .map(|f| H265 {
+                data: f.data,
+                key: f.key,
+                pts: f.pts,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+            .into(),
+        ..Default::default()
+    });
+    msg_out.set_video_frame(vf);
+    msg_out
+}
+
 #[inline]
 fn create_frame(frame: &EncodeFrame) -> VP9 {
     VP9 {
@@ -329,7 +732,8 @@ fn handle_one_frame(
     frame: &[u8],
     ms: i64,
     crc: &mut (u32, u32),
-    vpx: &mut Encoder,
+    encoder: &mut VideoEncoder,
+    display: usize,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -350,20 +754,11 @@ fn handle_one_frame(
 
     let mut send_conn_ids: HashSet<i32> = Default::default();
     if crc.1 <= 180 && crc.1 % 5 == 0 {
-        let mut frames = Vec::new();
-        for ref frame in vpx
-            .encode(ms, frame, STRIDE_ALIGN)
-            .with_context(|| "Failed to encode")?
-        {
-            frames.push(create_frame(frame));
-        }
-        for ref frame in vpx.flush().with_context(|| "Failed to flush")? {
-            frames.push(create_frame(frame));
-        }
-
-        // to-do: flush periodically, e.g. 1 second
-        if frames.len() > 0 {
-            send_conn_ids = sp.send_video_frame(create_msg(frames));
+        if let Some(mut msg) = encoder.encode(ms, frame)? {
+            if let Some(message::Union::video_frame(vf)) = msg.union.as_mut() {
+                vf.display = display as _;
+            }
+            send_conn_ids = sp.send_video_frame(msg);
         }
     }
     Ok(send_conn_ids)
@@ -418,6 +813,30 @@ pub fn refresh() {
     *SWITCH.lock().unwrap() = true;
 }
 
+/// A keyframe for the decoder of `conn_id` only, e.g. after frames of it were
+/// lost, without restarting the service for every connection as `refresh`
+/// does. The encoder is shared, so the others get that keyframe too.
+pub fn request_key_frame(conn_id: i32) {
+    KEY_FRAME_REQUESTS.lock().unwrap().insert(conn_id);
+}
+
+fn take_key_frame_requests() -> bool {
+    let mut requests = KEY_FRAME_REQUESTS.lock().unwrap();
+    let any = !requests.is_empty();
+    requests.clear();
+    any
+}
+
+// parity / data packets of video frames on the udp media channel, so minor
+// loss is recovered without retransmission, 0 disables FEC
+pub fn get_fec_ratio() -> f32 {
+    hbb_common::config::Config::get_option("fec-ratio")
+        .parse()
+        .unwrap_or(DEFAULT_FEC_RATIO)
+        .max(0.)
+        .min(1.)
+}
+
 fn get_primary() -> usize {
     if let Ok(all) = Display::all() {
         for (i, d) in all.iter().enumerate() {
@@ -503,6 +922,286 @@ pub fn update_image_quality(id: i32, q: Option<i32>) {
     }
 }
 
+pub fn update_bandwidth_estimate(id: i32, kbps: Option<u32>) {
+    match kbps {
+        Some(kbps) => {
+            BANDWIDTH_ESTIMATES.lock().unwrap().insert(id, kbps);
+        }
+        None => {
+            BANDWIDTH_ESTIMATES.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// The frames per second at most of a connection, None or MAX_FPS and above for
+/// no limit.
+pub fn update_fps_cap(id: i32, fps: Option<u32>) {
+    match fps {
+        Some(fps) if fps < MAX_FPS => {
+            FPS_CAPS.lock().unwrap().insert(id, fps.max(MIN_FPS));
+        }
+        _ => {
+            FPS_CAPS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+pub fn update_capture_region(id: i32, region: Option<CaptureRegion>) {
+    match region {
+        Some(region) if region.width > 0 || region.window > 0 => {
+            CAPTURE_REGIONS.lock().unwrap().insert(id, region);
+        }
+        _ => {
+            CAPTURE_REGIONS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+// the region of the connections which ask for one, the whole display if they
+// differ
+fn get_capture_region() -> Option<CaptureRegion> {
+    let regions = CAPTURE_REGIONS.lock().unwrap();
+    let first = regions.values().next()?;
+    if regions.values().all(|r| r == first) {
+        Some(first.clone())
+    } else {
+        None
+    }
+}
+
+// x, y, width, height of `region` in the display at `origin` of `width` x
+// `height`, even for i420, none for the whole display
+fn get_crop(
+    region: &CaptureRegion,
+    origin: (i32, i32),
+    width: usize,
+    height: usize,
+) -> Option<(usize, usize, usize, usize)> {
+    let (x, y, w, h) = if region.window > 0 {
+        let window = crate::platform::get_windows()
+            .into_iter()
+            .find(|w| w.id == region.window)?;
+        (
+            window.x - origin.0,
+            window.y - origin.1,
+            window.width,
+            window.height,
+        )
+    } else {
+        (region.x, region.y, region.width, region.height)
+    };
+    let (width, height) = (width as i32, height as i32);
+    let x0 = x.max(0).min(width) & !1;
+    let y0 = y.max(0).min(height) & !1;
+    let w = ((x + w).max(0).min(width) - x0) & !1;
+    let h = ((y + h).max(0).min(height) - y0) & !1;
+    if w < MIN_CROP_SIZE || h < MIN_CROP_SIZE || (w == width && h == height) {
+        return None;
+    }
+    Some((x0 as _, y0 as _, w as _, h as _))
+}
+
+pub fn update_capture_displays(id: i32, displays: Option<Vec<usize>>) {
+    match displays {
+        Some(displays) => {
+            CAPTURE_DISPLAYS.lock().unwrap().insert(id, displays);
+        }
+        None => {
+            CAPTURE_DISPLAYS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+// the displays any connection wants besides the current one
+fn get_extra_displays(current: usize, n: usize) -> Vec<usize> {
+    let mut displays: Vec<usize> = CAPTURE_DISPLAYS
+        .lock()
+        .unwrap()
+        .values()
+        .flatten()
+        .filter(|d| **d != current && **d < n)
+        .cloned()
+        .collect();
+    displays.sort();
+    displays.dedup();
+    displays
+}
+
+pub fn get_encoder_stats() -> (String, u32, u32) {
+    ENCODER_STATS.lock().unwrap().clone()
+}
+
+// The current display takes the mode closest to the size the latest connection
+// asks for, and gets its own back once no connection asks any more.
+pub fn update_resolution(id: i32, size: Option<(usize, usize)>) {
+    let mut lock = RESOLUTIONS.lock().unwrap();
+    match size {
+        Some(size) => {
+            lock.insert(id, size);
+            drop(lock);
+            if let Err(err) = change_resolution(size) {
+                log::error!("Failed to change resolution: {}", err);
+            }
+        }
+        None => {
+            if lock.remove(&id).is_some() && lock.is_empty() {
+                drop(lock);
+                restore_resolution();
+            }
+        }
+    }
+}
+
+fn change_resolution((width, height): (usize, usize)) -> ResultType<()> {
+    let (_, _, display) = get_current_display()?;
+    let name = display.name();
+    let mut original = ORIGINAL_RESOLUTION.lock().unwrap();
+    if original.as_ref().map(|o| &o.0) != Some(&name) {
+        // switched to another display since
+        if let Some((name, (w, h))) = original.take() {
+            allow_err!(crate::platform::change_resolution(&name, w, h));
+        }
+        *original = Some((name.clone(), crate::platform::current_resolution(&name)?));
+    }
+    let modes = crate::platform::resolutions(&name);
+    let (w, h) = match crate::platform::pick_resolution(&modes, width, height) {
+        Some(mode) => mode,
+        None => bail!("No display mode for {}x{}", width, height),
+    };
+    if w == display.width() && h == display.height() {
+        return Ok(());
+    }
+    log::info!("Change resolution of display {:?} to {}x{}", name, w, h);
+    crate::platform::change_resolution(&name, w, h)?;
+    refresh();
+    Ok(())
+}
+
+fn restore_resolution() {
+    if let Some((name, (w, h))) = ORIGINAL_RESOLUTION.lock().unwrap().take() {
+        log::info!("Restore resolution of display {:?} to {}x{}", name, w, h);
+        allow_err!(crate::platform::change_resolution(&name, w, h));
+        refresh();
+    }
+}
+
+pub fn update_supported_decoding(id: i32, decoding: Option<SupportedDecoding>) {
+    match decoding {
+        Some(decoding) => {
+            SUPPORTED_DECODINGS.lock().unwrap().insert(id, decoding);
+        }
+        None => {
+            SUPPORTED_DECODINGS.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// The sessions coming in are recorded by the connections, for audit.
+#[inline]
+pub fn is_recording_incoming() -> bool {
+    hbb_common::config::Config::get_option("allow-record-incoming") == "Y"
+}
+
+// The h265 or h264 of a hardware encoder saves the cpu, h265 with less
+// bandwidth, AV1 keeps more quality at the low bitrates of relayed sessions for
+// more cpu, VP9 is what every peer decodes, and what the recordings take. A codec
+// is used if every connection can decode it and none prefers another.
+fn negotiate_codec() -> CodecFormat {
+    use supported_decoding::PreferCodec;
+    let decodings = SUPPORTED_DECODINGS.lock().unwrap();
+    if decodings.is_empty() || is_recording_incoming() {
+        return CodecFormat::VP9;
+    }
+    let usable = |ability: fn(&SupportedDecoding) -> bool, codec: PreferCodec| {
+        decodings.values().all(|d| {
+            let prefer = d.prefer.enum_value_or_default();
+            ability(d) && (prefer == PreferCodec::Auto || prefer == codec)
+        })
+    };
+    if usable(|d| d.ability_h265, PreferCodec::H265) && has_hw_backend(CodecFormat::H265) {
+        CodecFormat::H265
+    } else if usable(|d| d.ability_h264, PreferCodec::H264) && has_hw_backend(CodecFormat::H264) {
+        CodecFormat::H264
+    } else if cfg!(feature = "av1") && usable(|d| d.ability_av1, PreferCodec::AV1) {
+        CodecFormat::AV1
+    } else {
+        CodecFormat::VP9
+    }
+}
+
+#[cfg(feature = "hwcodec")]
+fn has_hw_backend(codec: CodecFormat) -> bool {
+    match codec.hw_codec() {
+        Some(hw_codec) => get_hw_backend(hw_codec).is_some(),
+        None => false,
+    }
+}
+
+#[cfg(not(feature = "hwcodec"))]
+fn has_hw_backend(_codec: CodecFormat) -> bool {
+    false
+}
+
+// `hwcodec` is "N" for the software codecs only, or the backend to use if it
+// works, e.g. "vaapi", otherwise the first one that works
+#[cfg(feature = "hwcodec")]
+fn get_hw_backend(codec: hwcodec::HwCodec) -> Option<hwcodec::HwBackend> {
+    let option = hbb_common::config::Config::get_option("hwcodec");
+    let mut lock = HW_BACKENDS.lock().unwrap();
+    match lock.get(&codec) {
+        Some((probed, backend)) if probed == &option => *backend,
+        _ => {
+            let backend = if option == "N" {
+                None
+            } else {
+                hwcodec::probe(&option, codec)
+            };
+            log::info!("hardware {:?} encoder ({}): {:?}", codec, option, backend);
+            lock.insert(codec, (option, backend));
+            backend
+        }
+    }
+}
+
+#[cfg(feature = "hwcodec")]
+fn disable_hw_backend(codec: hwcodec::HwCodec) {
+    if let Some((_, backend)) = HW_BACKENDS.lock().unwrap().get_mut(&codec) {
+        *backend = None;
+    }
+}
+
+// the slowest connection decides
+fn get_bandwidth_estimate() -> Option<u32> {
+    BANDWIDTH_ESTIMATES.lock().unwrap().values().min().cloned()
+}
+
+// the connection wanting the fewest frames decides
+fn get_fps_cap() -> u32 {
+    FPS_CAPS
+        .lock()
+        .unwrap()
+        .values()
+        .min()
+        .cloned()
+        .unwrap_or(MAX_FPS)
+}
+
+// Bitrate and fps for the estimated bandwidth, the bitrate of the image quality
+// is the reference, below it the fps goes down too so that each frame keeps
+// some quality.
+fn adapt_to_bandwidth(bitrate: u32, estimate: Option<u32>) -> (u32, u32) {
+    let estimate = match estimate {
+        Some(estimate) => estimate,
+        None => return (bitrate, MAX_FPS),
+    };
+    let b = (estimate as f32)
+        .min(bitrate as f32 * MAX_BITRATE_GAIN)
+        .max(bitrate as f32 * MIN_BITRATE_GAIN);
+    let ratio = (b / bitrate.max(1) as f32).min(1.);
+    let fps = ((MAX_FPS as f32 * ratio.sqrt()) as u32).max(MIN_FPS);
+    (b as u32, fps)
+}
+
 fn get_image_quality() -> i32 {
     IMAGE_QUALITIES
         .lock()
@@ -0,0 +1,338 @@
+// A virtual monitor for the hosts without any, which have nothing to capture
+// otherwise, plugged in on demand by the video service.
+// windows: a software device of an IddCx driver, e.g.
+// https://github.com/roshkins/IddSampleDriver, the hardware id of the driver
+// is the option `virtual-display-hwid`
+// linux: an output of https://github.com/DisplayLink/evdi, through libevdi
+
+use hbb_common::{bail, config::Config, log, ResultType};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// until the new monitor is enumerated
+const WAIT_DISPLAY: u64 = 5_000;
+
+lazy_static::lazy_static! {
+    static ref DEVICE: Mutex<Option<Device>> = Default::default();
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_option("enable-virtual-display") != "N"
+}
+
+fn has_display() -> bool {
+    #[cfg(windows)]
+    {
+        scrap::Display::all()
+            .map(|d| !d.is_empty())
+            .unwrap_or(false)
+    }
+    // x11 reports the screen as a display even if no output is on, the
+    // compositor of wayland has its own outputs
+    #[cfg(target_os = "linux")]
+    {
+        crate::platform::linux::get_display_server() == "wayland"
+            || crate::platform::current_resolution("").is_ok()
+    }
+}
+
+/// Plug in a virtual monitor if there is no display, and wait for it to show up.
+pub fn plug_in_if_no_display() -> ResultType<()> {
+    if !is_enabled() || has_display() {
+        return Ok(());
+    }
+    {
+        let mut lock = DEVICE.lock().unwrap();
+        if lock.is_none() {
+            log::info!("No display, plug in a virtual one");
+            *lock = Some(Device::plug_in()?);
+        }
+    }
+    let start = Instant::now();
+    while start.elapsed().as_millis() < WAIT_DISPLAY as _ {
+        if has_display() {
+            log::info!("Virtual display plugged in");
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    plug_out();
+    bail!("The virtual display did not show up");
+}
+
+pub fn plug_out() {
+    if DEVICE.lock().unwrap().take().is_some() {
+        log::info!("Virtual display plugged out");
+    }
+}
+
+#[cfg(windows)]
+use windows::Device;
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use std::{ffi::c_void, os::windows::ffi::OsStrExt, ptr};
+    use winapi::{
+        shared::{
+            guiddef::GUID,
+            minwindef::{FALSE, ULONG},
+            ntdef::{HRESULT, LPCWSTR},
+            winerror::{S_OK, WAIT_TIMEOUT},
+        },
+        um::{
+            handleapi::CloseHandle,
+            synchapi::{CreateEventW, SetEvent, WaitForSingleObject},
+            winnt::HANDLE,
+        },
+    };
+
+    const DEFAULT_HWID: &str = "IddSampleDriver";
+    const SW_DEVICE_CAPABILITIES_REMOVABLE: ULONG = 0x1;
+    const SW_DEVICE_CAPABILITIES_SILENT_INSTALL: ULONG = 0x2;
+    const SW_DEVICE_CAPABILITIES_DRIVER_REQUIRED: ULONG = 0x8;
+
+    type HSWDEVICE = *mut c_void;
+
+    #[allow(non_snake_case)]
+    #[repr(C)]
+    struct SW_DEVICE_CREATE_INFO {
+        cbSize: ULONG,
+        pszInstanceId: LPCWSTR,
+        pszzHardwareIds: LPCWSTR,
+        pszzCompatibleIds: LPCWSTR,
+        pContainerId: *const GUID,
+        CapabilityFlags: ULONG,
+        pszDeviceDescription: LPCWSTR,
+        pszDeviceLocation: LPCWSTR,
+        pSecurityDescriptor: *const c_void,
+    }
+
+    #[link(name = "cfgmgr32")]
+    extern "system" {
+        fn SwDeviceCreate(
+            pszEnumeratorName: LPCWSTR,
+            pszParentDeviceInstance: LPCWSTR,
+            pCreateInfo: *const SW_DEVICE_CREATE_INFO,
+            cPropertyCount: ULONG,
+            pProperties: *const c_void,
+            pCallback: Option<unsafe extern "system" fn(HSWDEVICE, HRESULT, *mut c_void, LPCWSTR)>,
+            pContext: *mut c_void,
+            phSwDevice: *mut HSWDEVICE,
+        ) -> HRESULT;
+        fn SwDeviceClose(hSwDevice: HSWDEVICE);
+    }
+
+    struct Context {
+        event: HANDLE,
+        result: HRESULT,
+    }
+
+    unsafe extern "system" fn on_created(
+        _device: HSWDEVICE,
+        result: HRESULT,
+        context: *mut c_void,
+        _instance_id: LPCWSTR,
+    ) {
+        let context = &mut *(context as *mut Context);
+        context.result = result;
+        SetEvent(context.event);
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        std::ffi::OsStr::new(s)
+            .encode_wide()
+            .chain(Some(0).into_iter())
+            .collect()
+    }
+
+    // the device, and so the monitors of the driver, lives as long as the handle
+    pub struct Device(HSWDEVICE);
+
+    unsafe impl Send for Device {}
+
+    impl Device {
+        pub fn plug_in() -> ResultType<Self> {
+            let mut hwid = Config::get_option("virtual-display-hwid");
+            if hwid.is_empty() {
+                hwid = DEFAULT_HWID.to_owned();
+            }
+            // a list ending with an empty string
+            let mut hwids = wide(&hwid);
+            hwids.push(0);
+            let enumerator = wide(&hwid);
+            let instance_id = wide(&format!("{}Monitor", hbb_common::config::APP_NAME));
+            let description = wide(&format!("{} Virtual Display", hbb_common::config::APP_NAME));
+            let parent = wide("HTREE\\ROOT\\0");
+            let info = SW_DEVICE_CREATE_INFO {
+                cbSize: std::mem::size_of::<SW_DEVICE_CREATE_INFO>() as _,
+                pszInstanceId: instance_id.as_ptr(),
+                pszzHardwareIds: hwids.as_ptr(),
+                pszzCompatibleIds: ptr::null(),
+                pContainerId: ptr::null(),
+                CapabilityFlags: SW_DEVICE_CAPABILITIES_REMOVABLE
+                    | SW_DEVICE_CAPABILITIES_SILENT_INSTALL
+                    | SW_DEVICE_CAPABILITIES_DRIVER_REQUIRED,
+                pszDeviceDescription: description.as_ptr(),
+                pszDeviceLocation: ptr::null(),
+                pSecurityDescriptor: ptr::null(),
+            };
+            unsafe {
+                let event = CreateEventW(ptr::null_mut(), FALSE, FALSE, ptr::null());
+                if event.is_null() {
+                    bail!("Failed to create event");
+                }
+                let mut context = Context {
+                    event,
+                    result: S_OK,
+                };
+                let mut device: HSWDEVICE = ptr::null_mut();
+                let hr = SwDeviceCreate(
+                    enumerator.as_ptr(),
+                    parent.as_ptr(),
+                    &info,
+                    0,
+                    ptr::null(),
+                    Some(on_created),
+                    &mut context as *mut _ as _,
+                    &mut device,
+                );
+                let res = if hr != S_OK {
+                    Err(format!("SwDeviceCreate failed: {:#x}", hr))
+                } else if WaitForSingleObject(event, WAIT_DISPLAY as _) == WAIT_TIMEOUT {
+                    Err("Timeout to create the virtual display device".to_owned())
+                } else if context.result != S_OK {
+                    Err(format!(
+                        "Failed to create the virtual display device, is the driver {} installed? {:#x}",
+                        hwid, context.result
+                    ))
+                } else {
+                    Ok(())
+                };
+                CloseHandle(event);
+                if let Err(err) = res {
+                    if !device.is_null() {
+                        SwDeviceClose(device);
+                    }
+                    bail!("{}", err);
+                }
+                Ok(Self(device))
+            }
+        }
+    }
+
+    impl Drop for Device {
+        fn drop(&mut self) {
+            unsafe {
+                SwDeviceClose(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+use evdi::Device;
+
+#[cfg(target_os = "linux")]
+mod evdi {
+    use super::*;
+    use libc::{c_int, c_uint, c_void};
+    use libloading::{Library, Symbol};
+
+    const MAX_DEVICES: c_int = 16;
+    const AVAILABLE: c_int = 0;
+    // 1920x1080, the largest mode of the edid
+    const SKU_AREA_LIMIT: u32 = 1920 * 1080;
+    // a 1080p monitor named RustDesk, with a few lower modes
+    const EDID: [u8; 128] = [
+        0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x48, 0x8b, 0x01, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x01, 0x20, 0x01, 0x03, 0x80, 0x35, 0x1e, 0x78, 0x0a, 0xee, 0x91, 0xa3, 0x54, 0x4c,
+        0x99, 0x26, 0x0f, 0x50, 0x54, 0x21, 0x08, 0x00, 0x81, 0xc0, 0xa9, 0xc0, 0x81, 0x80, 0x95,
+        0x00, 0xb3, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x3a, 0x80, 0x18, 0x71, 0x38,
+        0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0x12, 0x2c, 0x21, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00,
+        0xfd, 0x00, 0x32, 0x4b, 0x1e, 0x50, 0x0f, 0x00, 0x0a, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+        0x00, 0x00, 0x00, 0xfc, 0x00, 0x52, 0x75, 0x73, 0x74, 0x44, 0x65, 0x73, 0x6b, 0x0a, 0x20,
+        0x20, 0x20, 0x20, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa5,
+    ];
+
+    type Handle = *mut c_void;
+
+    pub struct Device {
+        lib: Library,
+        handle: Handle,
+    }
+
+    unsafe impl Send for Device {}
+
+    fn load() -> ResultType<Library> {
+        unsafe {
+            match Library::new("libevdi.so") {
+                Ok(lib) => Ok(lib),
+                Err(_) => Ok(Library::new("libevdi.so.1")?),
+            }
+        }
+    }
+
+    impl Device {
+        pub fn plug_in() -> ResultType<Self> {
+            let lib = load()?;
+            let handle = unsafe {
+                let check: Symbol<unsafe extern "C" fn(c_int) -> c_int> =
+                    lib.get(b"evdi_check_device\0")?;
+                let add: Symbol<unsafe extern "C" fn() -> c_int> = lib.get(b"evdi_add_device\0")?;
+                let open: Symbol<unsafe extern "C" fn(c_int) -> Handle> =
+                    lib.get(b"evdi_open\0")?;
+                let connect: Symbol<unsafe extern "C" fn(Handle, *const u8, c_uint, u32)> =
+                    lib.get(b"evdi_connect\0")?;
+                let find = || (0..MAX_DEVICES).find(|i| check(*i) == AVAILABLE);
+                let mut device = find();
+                if device.is_none() {
+                    // root only, unless the module is loaded with initial_device_count
+                    if add() <= 0 {
+                        bail!("Failed to add an evdi device");
+                    }
+                    device = find();
+                }
+                let device = match device {
+                    Some(device) => device,
+                    None => bail!("No evdi device available"),
+                };
+                let handle = open(device);
+                if handle.is_null() {
+                    bail!("Failed to open evdi device {}", device);
+                }
+                connect(handle, EDID.as_ptr(), EDID.len() as _, SKU_AREA_LIMIT);
+                handle
+            };
+            // the new output is off until the preferred mode is set
+            std::thread::sleep(Duration::from_millis(500));
+            if let Err(err) = std::process::Command::new("xrandr").arg("--auto").output() {
+                log::error!("Failed to turn on the virtual display: {}", err);
+            }
+            Ok(Self { lib, handle })
+        }
+    }
+
+    impl Drop for Device {
+        fn drop(&mut self) {
+            unsafe {
+                if let Ok(disconnect) = self
+                    .lib
+                    .get::<unsafe extern "C" fn(Handle)>(b"evdi_disconnect\0")
+                {
+                    disconnect(self.handle);
+                }
+                if let Ok(close) = self
+                    .lib
+                    .get::<unsafe extern "C" fn(Handle)>(b"evdi_close\0")
+                {
+                    close(self.handle);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// The tunnel profiles saved for the peers, the auto-start ones run by the main
+// window in the background: started once the peer can be logged in with the
+// remembered password, and again after the peer goes offline and back. A
+// profile failing to log in is not retried until it is changed.
+use crate::client::*;
+use hbb_common::{
+    config::{PeerConfig, TunnelProfile},
+    log,
+    message_proto::*,
+    tokio::{self, sync::mpsc, time::Duration},
+    Stream,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+struct Session {
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    // a password or 2FA code is needed, which can not be asked for here
+    failed: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl Interface for Session {
+    fn msgbox(&self, msgtype: &str, title: &str, text: &str) {
+        if msgtype.starts_with("input") || msgtype.starts_with("re-input") {
+            self.failed.store(true, Ordering::SeqCst);
+        }
+        log::info!("tunnel {}: {}: {}", msgtype, title, text);
+    }
+
+    fn handle_login_error(&mut self, err: &str) -> bool {
+        self.failed.store(true, Ordering::SeqCst);
+        self.lc.write().unwrap().handle_login_error(err, self)
+    }
+
+    fn handle_peer_info(&mut self, pi: PeerInfo) {
+        let username = self.lc.read().unwrap().get_username(&pi);
+        self.lc.write().unwrap().handle_peer_info(username, pi);
+    }
+
+    async fn handle_hash(&mut self, hash: Hash, peer: &mut Stream) {
+        handle_hash(self.lc.clone(), hash, self, peer).await;
+    }
+
+    async fn handle_login_from_ui(&mut self, password: String, remember: bool, peer: &mut Stream) {
+        handle_login_from_ui(self.lc.clone(), password, remember, peer).await;
+    }
+
+    async fn handle_test_delay(&mut self, t: TestDelay, peer: &mut Stream) {
+        handle_test_delay(t, peer).await;
+    }
+}
+
+struct Running {
+    profile: TunnelProfile,
+    sender: mpsc::UnboundedSender<Data>,
+    failed: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+}
+
+pub fn start() {
+    std::thread::spawn(run);
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run() {
+    let mut running = HashMap::<(String, String), Running>::new();
+    let mut tested = false;
+    loop {
+        let mut profiles = HashMap::new();
+        for (id, _, c) in PeerConfig::peers() {
+            for p in c.tunnels.into_iter().filter(|x| x.auto_start) {
+                profiles.insert((id.clone(), p.name.clone()), p);
+            }
+        }
+        running.retain(|k, r| {
+            let failed = r.failed.load(Ordering::SeqCst);
+            // a local forwarding would log in again at each connection
+            if failed || profiles.get(k) != Some(&r.profile) {
+                r.sender.send(Data::Close).ok();
+            }
+            profiles.get(k) == Some(&r.profile) && (failed || !r.done.load(Ordering::SeqCst))
+        });
+        for (k, p) in profiles {
+            if running.contains_key(&k) {
+                continue;
+            }
+            if !tested {
+                crate::common::test_rendezvous_server();
+                crate::common::test_nat_type();
+                tested = true;
+            }
+            running.insert(k.clone(), start_one(k.0, p));
+        }
+        tokio::time::sleep(SCAN_INTERVAL).await;
+    }
+}
+
+fn start_one(id: String, p: TunnelProfile) -> Running {
+    log::info!("starting tunnel {} of {}", p.name, id);
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let session = Session {
+        lc: Default::default(),
+        failed: Default::default(),
+    };
+    {
+        let mut lc = session.lc.write().unwrap();
+        lc.initialize(id.clone(), false, true);
+        lc.port_forward = (p.remote_host.clone(), p.remote_port);
+        lc.reverse_forward = p.direction == "reverse";
+        lc.udp_forward = p.direction == "udp";
+    }
+    let running = Running {
+        profile: p.clone(),
+        sender,
+        failed: session.failed.clone(),
+        done: Default::default(),
+    };
+    let done = running.done.clone();
+    tokio::spawn(async move {
+        let target = (p.remote_host.clone(), p.remote_port);
+        let local = ("localhost".to_owned(), p.local_port);
+        let res = match p.direction.as_str() {
+            "dynamic" => {
+                crate::port_forward::listen_dynamic(id.clone(), p.local_port, session, receiver)
+                    .await
+            }
+            "reverse" => {
+                crate::port_forward::connect_reverse(id.clone(), local, session, receiver).await
+            }
+            "udp" => {
+                crate::port_forward::listen_udp(id.clone(), p.local_port, target, session, receiver)
+                    .await
+            }
+            _ => crate::port_forward::listen(id.clone(), p.local_port, session, receiver).await,
+        };
+        if let Err(err) = res {
+            log::error!("tunnel {} of {}: {}", p.name, id, err);
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    running
+}
+
+/// The profiles of a peer as text, one a line:
+/// `name direction local-port [[remote-host:]remote-port] [auto]`.
+pub fn format(profiles: &[TunnelProfile]) -> String {
+    profiles
+        .iter()
+        .map(|p| {
+            let mut fds = vec![p.name.clone(), get_direction(p).to_owned()];
+            fds.push(p.local_port.to_string());
+            if p.direction != "dynamic" {
+                if p.remote_host.is_empty() {
+                    fds.push(p.remote_port.to_string());
+                } else {
+                    fds.push(format!("{}:{}", p.remote_host, p.remote_port));
+                }
+            }
+            if p.auto_start {
+                fds.push("auto".to_owned());
+            }
+            fds.join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn parse(text: &str) -> Result<Vec<TunnelProfile>, String> {
+    let mut profiles: Vec<TunnelProfile> = Vec::new();
+    for line in text.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let p = parse_line(line).ok_or(line.to_owned())?;
+        if profiles.iter().any(|x| x.name == p.name) {
+            return Err(line.to_owned());
+        }
+        profiles.push(p);
+    }
+    Ok(profiles)
+}
+
+fn parse_line(line: &str) -> Option<TunnelProfile> {
+    let mut fds: Vec<&str> = line.split_whitespace().collect();
+    let auto_start = fds.last() == Some(&"auto");
+    if auto_start {
+        fds.pop();
+    }
+    let mut p = TunnelProfile {
+        auto_start,
+        ..Default::default()
+    };
+    match fds[..] {
+        [name, direction, local_port] if direction == "dynamic" => {
+            p.name = name.to_owned();
+            p.direction = direction.to_owned();
+            p.local_port = parse_port(local_port)?;
+        }
+        [name, direction, local_port, remote] => {
+            if !["local", "udp", "reverse"].contains(&direction) {
+                return None;
+            }
+            p.name = name.to_owned();
+            if direction != "local" {
+                p.direction = direction.to_owned();
+            }
+            p.local_port = parse_port(local_port)?;
+            let (host, port) = match remote.rfind(':') {
+                Some(i) => (&remote[..i], &remote[i + 1..]),
+                None if direction == "reverse" => ("", remote),
+                None => ("localhost", remote),
+            };
+            p.remote_host = host.to_owned();
+            p.remote_port = parse_port(port)?;
+        }
+        _ => return None,
+    }
+    Some(p)
+}
+
+fn parse_port(port: &str) -> Option<i32> {
+    match port.parse::<i32>() {
+        Ok(port) if port > 0 && port < 65536 => Some(port),
+        _ => None,
+    }
+}
+
+fn get_direction(p: &TunnelProfile) -> &str {
+    if p.direction.is_empty() {
+        "local"
+    } else {
+        &p.direction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "web local 8080 10.0.0.2:80 auto\nproxy dynamic 1080\nback reverse 3000 8000";
+        let profiles = parse(text).unwrap();
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(profiles[0].direction, "");
+        assert_eq!(profiles[0].remote_host, "10.0.0.2");
+        assert!(profiles[0].auto_start);
+        assert_eq!(profiles[1].local_port, 1080);
+        assert_eq!(profiles[2].remote_host, "");
+        assert_eq!(profiles[2].remote_port, 8000);
+        assert_eq!(format(&profiles), text);
+        assert_eq!(parse("dns udp 53 53").unwrap()[0].remote_host, "localhost");
+        assert!(parse("web local 8080").is_err());
+        assert!(parse("web tcp 8080 80").is_err());
+        assert!(parse("web local 70000 80").is_err());
+        assert!(parse("web local 80 80\nweb udp 80 80").is_err());
+    }
+}
@@ -8,9 +8,10 @@ use crate::common::SOFTWARE_UPDATE_URL;
 use crate::ipc;
 use hbb_common::{
     allow_err,
-    config::{self, Config, Fav, PeerConfig, APP_NAME, ICON},
+    config::{self, AbPeer, AddressBook, Config, Fav, PeerConfig, APP_NAME, ICON},
     log, sleep,
     tokio::{self, time},
+    totp,
 };
 use sciter::Value;
 use std::{
@@ -38,6 +39,39 @@ fn get_msgbox() -> String {
     return "".to_owned();
 }
 
+// for the chat box, with the ids and the times in ms as floats
+fn make_chat_message(m: &crate::chat::Message) -> Value {
+    let mut v = Value::map();
+    v.set_item("id", m.id as f64);
+    v.set_item("name", if m.me { "me" } else { m.name.as_str() });
+    v.set_item("text", m.text.clone());
+    v.set_item("file", m.file.clone());
+    v.set_item("ms", m.time as f64);
+    let state = if !m.me {
+        ""
+    } else if m.read {
+        "read"
+    } else if m.delivered {
+        "delivered"
+    } else {
+        "sent"
+    };
+    v.set_item("state", state);
+    v
+}
+
+fn make_chat_history(peer_id: &str) -> Value {
+    let mut v = Value::array(0);
+    for m in crate::chat::load(peer_id) {
+        v.push(make_chat_message(&m));
+    }
+    v
+}
+
+fn make_chat_ids(ids: &[i64]) -> Value {
+    Value::from_iter(ids.iter().map(|x| *x as f64))
+}
+
 pub fn start(args: &mut [String]) {
     // https://github.com/c-smile/sciter-sdk/blob/master/include/sciter-x-types.h
     // https://github.com/rustdesk/rustdesk/issues/132#issuecomment-886069737
@@ -87,6 +121,7 @@ pub fn start(args: &mut [String]) {
         let cloned = childs.clone();
         std::thread::spawn(move || check_zombie(cloned));
         crate::common::check_software_update();
+        crate::tunnels::start();
         frame.event_handler(UI::new(childs));
         frame.sciter_handler(UIHostHandler {});
         page = "index.html";
@@ -101,6 +136,7 @@ pub fn start(args: &mut [String]) {
         });
         page = "cm.html";
     } else if (args[0] == "--connect"
+        || args[0] == "--view-only"
         || args[0] == "--file-transfer"
         || args[0] == "--port-forward"
         || args[0] == "--rdp")
@@ -207,6 +243,10 @@ impl UI {
         ipc::get_id()
     }
 
+    fn get_fingerprint(&self) -> String {
+        ipc::get_fingerprint()
+    }
+
     fn get_password(&mut self) -> String {
         ipc::get_password()
     }
@@ -215,8 +255,47 @@ impl UI {
         if password.is_empty() {
             allow_err!(ipc::set_password(Config::get_auto_password()));
         } else {
-            allow_err!(ipc::set_password(password));
+            allow_err!(ipc::set_permanent_password(password));
+        }
+    }
+
+    fn is_totp_enabled(&self) -> bool {
+        ipc::is_totp_enabled()
+    }
+
+    fn new_totp_secret(&self) -> String {
+        totp::generate_secret()
+    }
+
+    // svg of the otpauth url, for the authenticator apps to scan
+    fn get_totp_qr(&self, secret: String) -> String {
+        let url = totp::get_url(&secret, &ipc::get_id());
+        match qrcode::QrCode::new(url.as_bytes()) {
+            Ok(code) => {
+                let svg = code
+                    .render::<qrcode::render::svg::Color>()
+                    .min_dimensions(160, 160)
+                    .build();
+                // without the xml declaration, to be inlined
+                match svg.find("<svg") {
+                    Some(i) => svg[i..].to_owned(),
+                    None => svg,
+                }
+            }
+            Err(err) => {
+                log::error!("Failed to create the QR code: {}", err);
+                "".to_owned()
+            }
+        }
+    }
+
+    // the code is checked before a new secret is saved, empty to turn it off
+    fn set_totp(&self, secret: String, code: String) -> bool {
+        if !secret.is_empty() && !totp::verify(&secret, &code) {
+            return false;
         }
+        allow_err!(ipc::set_totp_secret(secret));
+        true
     }
 
     fn get_remote_id(&mut self) -> String {
@@ -241,6 +320,11 @@ impl UI {
             allow_err!(crate::platform::windows::install_me(&_options));
             std::process::exit(0);
         });
+        #[cfg(target_os = "macos")]
+        std::thread::spawn(move || match crate::platform::macos::install_service() {
+            Ok(_) => std::process::exit(0),
+            Err(err) => log::error!("Failed to install the service: {}", err),
+        });
     }
 
     fn update_me(&self, _path: String) {
@@ -281,6 +365,10 @@ impl UI {
         Config::get_option(&key)
     }
 
+    fn is_option_locked(&self, key: String) -> bool {
+        Config::is_option_locked(&key)
+    }
+
     fn peer_has_password(&self, id: String) -> bool {
         !PeerConfig::load(&id).password.is_empty()
     }
@@ -306,6 +394,23 @@ impl UI {
         c.store(&id);
     }
 
+    fn get_tunnels(&self, id: String) -> String {
+        crate::tunnels::format(&PeerConfig::load(&id).tunnels)
+    }
+
+    // the invalid line if any
+    fn set_tunnels(&self, id: String, text: String) -> String {
+        match crate::tunnels::parse(&text) {
+            Ok(tunnels) => {
+                let mut c = PeerConfig::load(&id);
+                c.tunnels = tunnels;
+                c.store(&id);
+                "".to_owned()
+            }
+            Err(line) => line,
+        }
+    }
+
     fn get_options(&self) -> Value {
         let mut m = Value::map();
         for (k, v) in self.2.lock().unwrap().iter() {
@@ -395,6 +500,90 @@ impl UI {
         .ok();
     }
 
+    // [key, minutes left] of the current bans of the failed logins
+    fn get_bans(&self) -> Value {
+        let now = crate::get_time();
+        let mut bans: Vec<_> = ipc::get_bans()
+            .into_iter()
+            .filter(|(_, x)| x.0 > now)
+            .collect();
+        bans.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut v = Value::array(0);
+        for (key, (until, _)) in bans {
+            let mut ban = Value::array(0);
+            ban.push(key);
+            ban.push(((until - now) / 60_000 + 1) as i32);
+            v.push(ban);
+        }
+        v
+    }
+
+    fn unban(&self, key: String) {
+        allow_err!(ipc::unban(key));
+    }
+
+    // the plugins installed, with [id, label] of their permissions
+    fn get_plugins(&self) -> Value {
+        let mut v = Value::array(0);
+        for m in crate::plugins::list() {
+            let mut p = Value::map();
+            p.set_item("id", m.id);
+            p.set_item("name", m.name);
+            p.set_item("version", m.version);
+            p.set_item("description", m.description);
+            let mut permissions = Value::array(0);
+            for x in m.permissions {
+                let mut perm = Value::array(0);
+                perm.push(x.id);
+                perm.push(x.label);
+                permissions.push(perm);
+            }
+            p.set_item("permissions", permissions);
+            v.push(p);
+        }
+        v
+    }
+
+    // the entries of the audit log with `filter` in them, the latest first,
+    // flagged if at or after the first one breaking the chain
+    fn get_audit_log(&self, filter: String) -> Value {
+        let (entries, broken) = ipc::get_audit_log();
+        let broken = broken.unwrap_or(entries.len());
+        let mut v = Value::array(0);
+        for (i, e) in entries.iter().enumerate().rev() {
+            if !crate::audit::matches(e, &filter) {
+                continue;
+            }
+            let mut x = Value::map();
+            x.set_item("direction", e.direction.clone());
+            x.set_item("peer_id", e.peer_id.clone());
+            x.set_item("name", e.name.clone());
+            x.set_item("ip", e.ip.clone());
+            x.set_item("type", e.conn_type.clone());
+            x.set_item("start", (e.start / 1000) as f64);
+            x.set_item("end", (e.end / 1000) as f64);
+            x.set_item("permissions", e.permissions.join(", "));
+            x.set_item("files", e.files.join("; "));
+            x.set_item("reason", e.reason.clone());
+            x.set_item("broken", i >= broken);
+            v.push(x);
+        }
+        v
+    }
+
+    // the error if any
+    fn export_audit_log(&self, filter: String, path: String) -> String {
+        let entries: Vec<_> = ipc::get_audit_log()
+            .0
+            .into_iter()
+            .filter(|e| crate::audit::matches(e, &filter))
+            .collect();
+        match std::fs::write(&path, crate::audit::to_csv(&entries)) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
     fn is_installed(&mut self) -> bool {
         crate::platform::is_installed()
     }
@@ -483,6 +672,201 @@ impl UI {
         PeerConfig::remove(&id);
     }
 
+    fn get_ab_peer_value(p: &AbPeer) -> Value {
+        let mut m = Value::map();
+        m.set_item("id", p.id.clone());
+        m.set_item("alias", p.alias.clone());
+        m.set_item("username", p.username.clone());
+        m.set_item("hostname", p.hostname.clone());
+        m.set_item("platform", p.platform.clone());
+        m.set_item("notes", p.notes.clone());
+        m.set_item("group", p.group.clone());
+        m.set_item("tags", Value::from_iter(p.tags.clone()));
+        m
+    }
+
+    fn get_ab_peers(&self) -> Value {
+        let ab = AddressBook::load();
+        Value::from_iter(ab.get_peers().into_iter().map(Self::get_ab_peer_value))
+    }
+
+    fn get_ab_tags(&self) -> Value {
+        Value::from_iter(AddressBook::load().get_tags())
+    }
+
+    fn get_ab_groups(&self) -> Value {
+        Value::from_iter(AddressBook::load().get_groups())
+    }
+
+    fn is_in_ab(&self, id: String) -> bool {
+        AddressBook::load().get(&id).is_some()
+    }
+
+    fn get_ab_password(&self, id: String) -> String {
+        let ab = AddressBook::load();
+        ab.get(&id).map(|x| x.password.clone()).unwrap_or_default()
+    }
+
+    // alias, group, tags separated by commas, notes and password
+    fn set_ab_peer(&self, id: String, v: Value) {
+        let mut ab = AddressBook::load();
+        let mut p = match ab.get(&id) {
+            Some(p) => p.clone(),
+            None => {
+                let mut c = PeerConfig::load(&id);
+                AbPeer {
+                    alias: c.options.remove("alias").unwrap_or_default(),
+                    username: c.info.username,
+                    hostname: c.info.hostname,
+                    platform: c.info.platform,
+                    id,
+                    ..Default::default()
+                }
+            }
+        };
+        let get = |k: &str| v.get_item(k).as_string().map(|x| x.trim().to_owned());
+        if let Some(alias) = get("alias") {
+            p.alias = alias;
+        }
+        if let Some(tags) = get("tags") {
+            p.tags.clear();
+            for tag in tags.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                if !p.tags.iter().any(|x| x == tag) {
+                    p.tags.push(tag.to_owned());
+                }
+            }
+        }
+        if let Some(notes) = get("notes") {
+            p.notes = notes;
+        }
+        if let Some(group) = get("group") {
+            let parts: Vec<&str> = group.split('/').map(|x| x.trim()).collect();
+            p.group = parts.into_iter().filter(|x| !x.is_empty()).collect::<Vec<_>>().join("/");
+        }
+        if let Some(password) = get("password") {
+            p.password = password;
+        }
+        ab.set(p);
+        ab.store();
+        crate::ab::sync_in_background();
+    }
+
+    fn remove_ab_peer(&self, id: String) {
+        let mut ab = AddressBook::load();
+        ab.remove(&id);
+        ab.store();
+        crate::ab::sync_in_background();
+    }
+
+    // url, token and passphrase
+    fn get_ab_sync(&self) -> Value {
+        let ab = AddressBook::load();
+        Value::from_iter(vec![ab.sync_url, ab.sync_token, ab.sync_passphrase])
+    }
+
+    fn set_ab_sync(&self, url: String, token: String, passphrase: String) {
+        let mut ab = AddressBook::load();
+        ab.sync_url = url;
+        ab.sync_token = token;
+        ab.sync_passphrase = passphrase;
+        ab.store();
+        crate::ab::sync_in_background();
+    }
+
+    fn sync_ab(&self) {
+        crate::ab::sync_in_background();
+    }
+
+    fn query_online_states(&self, ids: Value) {
+        crate::ab::query_online_states(Self::get_ids(ids));
+    }
+
+    fn get_online_states(&self) -> Value {
+        let mut m = Value::map();
+        for (id, online) in crate::ab::get_online_states().0 {
+            m.set_item(id, online);
+        }
+        m
+    }
+
+    fn is_querying_online(&self) -> bool {
+        crate::ab::get_online_states().1
+    }
+
+    fn wake_up_peers(&self, ids: Value) {
+        crate::ab::wake_up(Self::get_ids(ids));
+    }
+
+    // a setting of the sessions with the peers, "Y" or "N" for the switches
+    fn set_peers_config(&self, ids: Value, key: String, value: String) {
+        for id in Self::get_ids(ids) {
+            let mut c = PeerConfig::load(&id);
+            match key.as_str() {
+                "image-quality" => c.image_quality = value.clone(),
+                "view-style" => c.view_style = value.clone(),
+                "disable-audio" => c.disable_audio = value == "Y",
+                "disable-clipboard" => c.disable_clipboard = value == "Y",
+                "show-remote-cursor" => c.show_remote_cursor = value == "Y",
+                _ => return,
+            }
+            c.store(&id);
+        }
+    }
+
+    fn get_ids(ids: Value) -> Vec<String> {
+        ids.values().filter_map(|x| x.as_string()).collect()
+    }
+
+    // "syncing", or the error of the last sync
+    fn get_ab_sync_status(&self) -> String {
+        crate::ab::get_status()
+    }
+
+    // "<id> <base64 key> # <fingerprint>" of the peers with a pinned key
+    fn export_pinned_keys(&self) -> String {
+        PeerConfig::peers()
+            .into_iter()
+            .filter(|p| !p.2.pinned_key.is_empty())
+            .map(|p| {
+                format!(
+                    "{} {} # {}",
+                    p.0,
+                    base64::encode(&p.2.pinned_key),
+                    crate::common::get_fingerprint(&p.2.pinned_key)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // the first invalid line, nothing imported then
+    fn import_pinned_keys(&self, text: String) -> String {
+        let mut keys = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut it = line.split_whitespace();
+            let id = it.next().unwrap_or_default();
+            let valid_id = id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            match it.next().map(base64::decode) {
+                Some(Ok(key)) if valid_id && key.len() == 32 && it.next().is_none() => {
+                    keys.push((id.to_owned(), key));
+                }
+                _ => return line.to_owned(),
+            }
+        }
+        for (id, key) in keys {
+            let mut config = PeerConfig::load(&id);
+            config.pinned_key = key;
+            config.store(&id);
+        }
+        "".to_owned()
+    }
+
     fn new_remote(&mut self, id: String, remote_type: String) {
         let mut lock = self.0.lock().unwrap();
         let args = vec![format!("--{}", remote_type), id.clone()];
@@ -619,6 +1003,16 @@ impl UI {
         config::LanPeers::load().peers
     }
 
+    fn can_wake_up(&self, id: String) -> bool {
+        config::LanPeers::load().macs.contains_key(&id)
+    }
+
+    fn wake_up(&self, id: String) {
+        std::thread::spawn(move || {
+            allow_err!(crate::rendezvous_mediator::wake_up(id));
+        });
+    }
+
     fn open_url(&self, url: String) {
         #[cfg(windows)]
         let p = "explorer";
@@ -644,13 +1038,36 @@ impl sciter::EventHandler for UI {
         fn is_xfce();
         fn get_id();
         fn get_password();
+        fn get_fingerprint();
         fn update_password(String);
+        fn is_totp_enabled();
+        fn new_totp_secret();
+        fn get_totp_qr(String);
+        fn set_totp(String, String);
         fn get_remote_id();
         fn set_remote_id(String);
         fn save_size(i32, i32, i32, i32);
         fn get_size();
         fn new_remote(String, bool);
         fn remove_peer(String);
+        fn get_ab_peers();
+        fn get_ab_tags();
+        fn get_ab_groups();
+        fn query_online_states(Value);
+        fn get_online_states();
+        fn is_querying_online();
+        fn wake_up_peers(Value);
+        fn set_peers_config(Value, String, String);
+        fn is_in_ab(String);
+        fn get_ab_password(String);
+        fn set_ab_peer(String, Value);
+        fn remove_ab_peer(String);
+        fn get_ab_sync();
+        fn set_ab_sync(String, String, String);
+        fn sync_ab();
+        fn get_ab_sync_status();
+        fn export_pinned_keys();
+        fn import_pinned_keys(String);
         fn get_connect_status();
         fn get_recent_sessions();
         fn get_peer(String);
@@ -663,6 +1080,11 @@ impl sciter::EventHandler for UI {
         fn is_installed();
         fn set_socks(String, String, String);
         fn get_socks();
+        fn get_bans();
+        fn unban(String);
+        fn get_plugins();
+        fn get_audit_log(String);
+        fn export_audit_log(String, String);
         fn is_installed_lower_version();
         fn install_path();
         fn goto_install();
@@ -675,11 +1097,14 @@ impl sciter::EventHandler for UI {
         fn modify_default_login();
         fn get_options();
         fn get_option(String);
+        fn is_option_locked(String);
         fn get_local_option(String);
         fn get_peer_option(String, String);
         fn peer_has_password(String);
         fn forget_password(String);
         fn set_peer_option(String, String, String);
+        fn get_tunnels(String);
+        fn set_tunnels(String, String);
         fn test_if_valid_server(String);
         fn get_sound_inputs();
         fn set_options(Value);
@@ -695,6 +1120,8 @@ impl sciter::EventHandler for UI {
         fn create_shortcut(String);
         fn discover();
         fn get_lan_peers();
+        fn can_wake_up(String);
+        fn wake_up(String);
     }
 }
 
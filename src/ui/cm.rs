@@ -4,19 +4,26 @@ use hbb_common::{
     config::{Config, ICON},
     fs, log,
     message_proto::*,
-    protobuf::Message as _,
+    protobuf::{Message as _, ProtobufEnum},
     tokio::{self, sync::mpsc, task::spawn_blocking},
 };
 use sciter::{make_args, Element, Value, HELEMENT};
 use std::{
     collections::HashMap,
     ops::Deref,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 
+lazy_static::lazy_static! {
+    // the written path of the dropped files to paste when done, by connection and job
+    static ref PASTE_JOBS: Mutex<HashMap<(i32, i32), String>> = Default::default();
+}
+
 pub struct ConnectionManagerInner {
     root: Option<Element>,
     senders: HashMap<i32, mpsc::UnboundedSender<Data>>,
+    // the peer id and name by connection, for the chat history
+    peers: HashMap<i32, (String, String)>,
 }
 
 #[derive(Clone)]
@@ -37,6 +44,7 @@ impl ConnectionManager {
         let inner = ConnectionManagerInner {
             root: None,
             senders: HashMap::new(),
+            peers: HashMap::new(),
         };
         let cm = Self(Arc::new(RwLock::new(inner)));
         #[cfg(target_os = "macos")]
@@ -70,26 +78,34 @@ impl ConnectionManager {
         id: i32,
         is_file_transfer: bool,
         port_forward: String,
+        is_terminal: bool,
         peer_id: String,
         name: String,
         authorized: bool,
         keyboard: bool,
         clipboard: bool,
         audio: bool,
+        microphone: bool,
         tx: mpsc::UnboundedSender<Data>,
     ) {
+        self.write()
+            .unwrap()
+            .peers
+            .insert(id, (peer_id.clone(), name.clone()));
         self.call(
             "addConnection",
             &make_args!(
                 id,
                 is_file_transfer,
                 port_forward,
+                is_terminal,
                 peer_id,
                 name,
                 authorized,
                 keyboard,
                 clipboard,
-                audio
+                audio,
+                microphone
             ),
         );
         self.write().unwrap().senders.insert(id, tx);
@@ -97,6 +113,7 @@ impl ConnectionManager {
 
     fn remove_connection(&self, id: i32) {
         self.write().unwrap().senders.remove(&id);
+        self.write().unwrap().peers.remove(&id);
         self.call("removeConnection", &make_args!(id));
     }
 
@@ -108,8 +125,48 @@ impl ConnectionManager {
         conn: &mut Connection,
     ) {
         match data {
-            Data::ChatMessage { text } => {
-                self.call("newMessage", &make_args!(id, text));
+            Data::ChatMessage {
+                text,
+                id: msg_id,
+                file,
+            } => {
+                let (peer_id, name) = self.get_peer(id);
+                let msg = crate::chat::Message {
+                    id: msg_id,
+                    name,
+                    text,
+                    file,
+                    time: crate::common::get_time(),
+                    ..Default::default()
+                };
+                crate::chat::add(&peer_id, msg.clone());
+                if msg_id != 0 {
+                    let receipt = Data::ChatReceipt {
+                        ids: vec![msg_id],
+                        read: false,
+                    };
+                    allow_err!(conn.send(&receipt).await);
+                }
+                self.call(
+                    "newMessage",
+                    &make_args!(id, super::make_chat_message(&msg)),
+                );
+            }
+            Data::ChatReceipt { ids, read } => {
+                crate::chat::set_receipt(&self.get_peer(id).0, &ids, read);
+                self.call(
+                    "chatReceipt",
+                    &make_args!(id, super::make_chat_ids(&ids), read),
+                );
+            }
+            Data::SwitchPermission { name, enabled } => {
+                self.call("switchPermission", &make_args!(id, name, enabled));
+            }
+            Data::VoiceCallIncoming => {
+                self.call("voiceCallIncoming", &make_args!(id));
+            }
+            Data::CloseVoiceCall(reason) => {
+                self.call("closeVoiceCall", &make_args!(id, reason));
             }
             Data::FS(v) => match v {
                 ipc::FS::ReadDir {
@@ -132,34 +189,75 @@ impl ConnectionManager {
                     Self::create_dir(path, id, conn).await;
                 }
                 ipc::FS::NewWrite {
-                    path,
-                    id,
+                    mut path,
+                    id: job_id,
+                    drop,
+                    paste,
                     mut files,
                 } => {
+                    if drop {
+                        match fs::get_drop_path(&path) {
+                            Ok(p) => path = p,
+                            Err(err) => {
+                                Self::send(fs::new_error(job_id, err, 0), conn).await;
+                                return;
+                            }
+                        }
+                        if paste {
+                            PASTE_JOBS
+                                .lock()
+                                .unwrap()
+                                .insert((id, job_id), path.clone());
+                        }
+                    }
                     write_jobs.push(fs::TransferJob::new_write(
-                        id,
+                        job_id,
                         path,
                         files
                             .drain(..)
                             .map(|f| FileEntry {
                                 name: f.0,
                                 modified_time: f.1,
+                                entry_type: FileType::from_i32(f.2)
+                                    .unwrap_or(FileType::File)
+                                    .into(),
+                                mode: f.3,
+                                link_target: f.4,
                                 ..Default::default()
                             })
                             .collect(),
                     ));
                 }
-                ipc::FS::CancelWrite { id } => {
-                    if let Some(job) = fs::get_job(id, write_jobs) {
-                        job.remove_download_file();
-                        fs::remove_job(id, write_jobs);
+                ipc::FS::CancelWrite {
+                    id: job_id,
+                    keep_download,
+                } => {
+                    PASTE_JOBS.lock().unwrap().remove(&(id, job_id));
+                    if let Some(job) = fs::get_job(job_id, write_jobs) {
+                        if !keep_download {
+                            job.remove_download_file();
+                        }
+                        fs::remove_job(job_id, write_jobs);
                     }
                 }
-                ipc::FS::WriteDone { id, file_num } => {
-                    if let Some(job) = fs::get_job(id, write_jobs) {
-                        job.modify_time();
-                        Self::send(fs::new_done(id, file_num), conn).await;
-                        fs::remove_job(id, write_jobs);
+                ipc::FS::WriteDone {
+                    id: job_id,
+                    file_num,
+                } => {
+                    let paste = PASTE_JOBS.lock().unwrap().remove(&(id, job_id));
+                    if let Some(job) = fs::get_job(job_id, write_jobs) {
+                        job.write_done();
+                        if let Some(path) = paste {
+                            // pasted by the ctrl+v the client sends on done
+                            match crate::common::ClipboardContext::new() {
+                                Ok(mut ctx) => allow_err!(ctx.set_text(path)),
+                                Err(err) => {
+                                    log::error!("Failed to create clipboard context: {}", err)
+                                }
+                            }
+                        }
+                        Self::send(fs::new_done(job_id, file_num), conn).await;
+                        fs::remove_job(job_id, write_jobs);
                     }
                 }
                 ipc::FS::WriteBlock {
@@ -167,6 +265,9 @@ impl ConnectionManager {
                     file_num,
                     data,
                     compressed,
+                    offset,
+                    copy_blocks,
+                    sha256,
                 } => {
                     if let Some(job) = fs::get_job(id, write_jobs) {
                         if let Err(err) = job
@@ -175,6 +276,9 @@ impl ConnectionManager {
                                 file_num,
                                 data,
                                 compressed,
+                                offset,
+                                copy_blocks,
+                                sha256,
                                 ..Default::default()
                             })
                             .await
@@ -183,6 +287,25 @@ impl ConnectionManager {
                         }
                     }
                 }
+                ipc::FS::WriteDigest {
+                    id,
+                    file_num,
+                    last_modified,
+                    file_size,
+                } => {
+                    if let Some(job) = fs::get_job(id, write_jobs) {
+                        let msg = job
+                            .handle_digest(FileTransferDigest {
+                                id,
+                                file_num,
+                                last_modified,
+                                file_size,
+                                ..Default::default()
+                            })
+                            .await;
+                        Self::send(msg, conn).await;
+                    }
+                }
             },
             _ => {}
         }
@@ -283,10 +406,63 @@ impl ConnectionManager {
         }
     }
 
-    fn send_msg(&self, id: i32, text: String) {
+    fn get_peer(&self, id: i32) -> (String, String) {
+        self.read()
+            .unwrap()
+            .peers
+            .get(&id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // the message kept in the history, with its id
+    fn send_msg(&self, id: i32, text: String) -> Value {
+        let msg = crate::chat::Message {
+            id: crate::chat::new_id(),
+            me: true,
+            text: text.clone(),
+            time: crate::common::get_time(),
+            ..Default::default()
+        };
+        crate::chat::add(&self.get_peer(id).0, msg.clone());
+        let lock = self.read().unwrap();
+        if let Some(s) = lock.senders.get(&id) {
+            allow_err!(s.send(Data::ChatMessage {
+                text,
+                id: msg.id,
+                file: "".to_owned(),
+            }));
+        }
+        super::make_chat_message(&msg)
+    }
+
+    fn get_chat_history(&self, id: i32) -> Value {
+        super::make_chat_history(&self.get_peer(id).0)
+    }
+
+    // the messages of the peer shown, with a receipt for it
+    fn read_chat(&self, id: i32) {
+        let ids = crate::chat::read_all(&self.get_peer(id).0);
+        if ids.is_empty() {
+            return;
+        }
+        let lock = self.read().unwrap();
+        if let Some(s) = lock.senders.get(&id) {
+            allow_err!(s.send(Data::ChatReceipt { ids, read: true }));
+        }
+    }
+
+    fn voice_call_response(&self, id: i32, accepted: bool) {
+        let lock = self.read().unwrap();
+        if let Some(s) = lock.senders.get(&id) {
+            allow_err!(s.send(Data::VoiceCallResponse(accepted)));
+        }
+    }
+
+    fn close_voice_call(&self, id: i32) {
         let lock = self.read().unwrap();
         if let Some(s) = lock.senders.get(&id) {
-            allow_err!(s.send(Data::ChatMessage { text }));
+            allow_err!(s.send(Data::CloseVoiceCall("".to_owned())));
         }
     }
 
@@ -318,6 +494,10 @@ impl sciter::EventHandler for ConnectionManager {
         fn authorize(i32);
         fn switch_permission(i32, String, bool);
         fn send_msg(i32, String);
+        fn get_chat_history(i32);
+        fn read_chat(i32);
+        fn voice_call_response(i32, bool);
+        fn close_voice_call(i32);
         fn exit();
     }
 }
@@ -345,9 +525,9 @@ async fn start_ipc(cm: ConnectionManager) {
                                             }
                                             Ok(Some(data)) => {
                                                 match data {
-                                                    Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio} => {
+                                                    Data::Login{id, is_file_transfer, port_forward, is_terminal, peer_id, name, authorized, keyboard, clipboard, audio, microphone} => {
                                                         conn_id = id;
-                                                        cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, tx.clone());
+                                                        cm.add_connection(id, is_file_transfer, port_forward, is_terminal, peer_id, name, authorized, keyboard, clipboard, audio, microphone, tx.clone());
                                                     }
                                                     _ => {
                                                         cm.handle_data(conn_id, data, &mut write_jobs, &mut stream).await;
@@ -1,15 +1,18 @@
+use crate::audit;
 use crate::client::*;
 use crate::common::{
-    self, check_clipboard, update_clipboard, ClipboardContext, CLIPBOARD_INTERVAL,
+    self, check_clipboard, get_clipboard_chunk, get_clipboard_progress, update_clipboard,
+    update_clipboard_chunk, ClipboardContent, ClipboardContext, CLIPBOARD_INTERVAL,
 };
 use enigo::{self, Enigo, KeyboardControllable};
 use hbb_common::{
-    allow_err,
+    allow_err, capabilities,
     config::{self, Config, PeerConfig},
     fs, log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
+    rudp::{self, ReliableChannel},
     sleep,
     tokio::{
         self,
@@ -28,8 +31,9 @@ use sciter::{
     Value,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ops::Deref,
+    path::Path,
     sync::{Arc, Mutex, RwLock},
 };
 
@@ -38,6 +42,8 @@ type Video = AssetPtr<video_destination>;
 lazy_static::lazy_static! {
     static ref ENIGO: Arc<Mutex<Enigo>> = Arc::new(Mutex::new(Enigo::new()));
     static ref VIDEO: Arc<Mutex<Option<Video>>> = Default::default();
+    // of the displays streamed besides the current one
+    static ref EXTRA_VIDEOS: Arc<Mutex<HashMap<usize, Video>>> = Default::default();
 }
 
 fn get_key_state(key: enigo::Key) -> bool {
@@ -48,9 +54,184 @@ fn get_key_state(key: enigo::Key) -> bool {
     ENIGO.lock().unwrap().get_key_state(key)
 }
 
+// the media keys, not named by rdev, virtual key codes on Windows and the key
+// codes of evdev on Linux
+fn get_media_key(code: u32) -> Option<ControlKey> {
+    let codes = if cfg!(windows) {
+        [0xAD, 0xAE, 0xAF, 0xB3, 0xB0, 0xB1, 0xB2]
+    } else if cfg!(target_os = "linux") {
+        [121, 122, 123, 172, 171, 173, 174]
+    } else {
+        return None;
+    };
+    let keys = [
+        ControlKey::VolumeMute,
+        ControlKey::VolumeDown,
+        ControlKey::VolumeUp,
+        ControlKey::MediaPlayPause,
+        ControlKey::MediaNextTrack,
+        ControlKey::MediaPrevTrack,
+        ControlKey::MediaStop,
+    ];
+    codes.iter().position(|x| *x == code).map(|i| keys[i])
+}
+
+// the PC set 1 scan codes of the keys typing characters, by their position on
+// a US keyboard, and their virtual key codes on Windows, which rdev maps to
+// the US layout whatever the one in use
+const SCAN_CODES: [(rdev::Key, u32, u32); 48] = [
+    (rdev::Key::Num1, 0x02, 0x31),
+    (rdev::Key::Num2, 0x03, 0x32),
+    (rdev::Key::Num3, 0x04, 0x33),
+    (rdev::Key::Num4, 0x05, 0x34),
+    (rdev::Key::Num5, 0x06, 0x35),
+    (rdev::Key::Num6, 0x07, 0x36),
+    (rdev::Key::Num7, 0x08, 0x37),
+    (rdev::Key::Num8, 0x09, 0x38),
+    (rdev::Key::Num9, 0x0A, 0x39),
+    (rdev::Key::Num0, 0x0B, 0x30),
+    (rdev::Key::Minus, 0x0C, 0xBD),
+    (rdev::Key::Equal, 0x0D, 0xBB),
+    (rdev::Key::KeyQ, 0x10, 0x51),
+    (rdev::Key::KeyW, 0x11, 0x57),
+    (rdev::Key::KeyE, 0x12, 0x45),
+    (rdev::Key::KeyR, 0x13, 0x52),
+    (rdev::Key::KeyT, 0x14, 0x54),
+    (rdev::Key::KeyY, 0x15, 0x59),
+    (rdev::Key::KeyU, 0x16, 0x55),
+    (rdev::Key::KeyI, 0x17, 0x49),
+    (rdev::Key::KeyO, 0x18, 0x4F),
+    (rdev::Key::KeyP, 0x19, 0x50),
+    (rdev::Key::LeftBracket, 0x1A, 0xDB),
+    (rdev::Key::RightBracket, 0x1B, 0xDD),
+    (rdev::Key::KeyA, 0x1E, 0x41),
+    (rdev::Key::KeyS, 0x1F, 0x53),
+    (rdev::Key::KeyD, 0x20, 0x44),
+    (rdev::Key::KeyF, 0x21, 0x46),
+    (rdev::Key::KeyG, 0x22, 0x47),
+    (rdev::Key::KeyH, 0x23, 0x48),
+    (rdev::Key::KeyJ, 0x24, 0x4A),
+    (rdev::Key::KeyK, 0x25, 0x4B),
+    (rdev::Key::KeyL, 0x26, 0x4C),
+    (rdev::Key::SemiColon, 0x27, 0xBA),
+    (rdev::Key::Quote, 0x28, 0xDE),
+    (rdev::Key::BackQuote, 0x29, 0xC0),
+    (rdev::Key::BackSlash, 0x2B, 0xDC),
+    (rdev::Key::KeyZ, 0x2C, 0x5A),
+    (rdev::Key::KeyX, 0x2D, 0x58),
+    (rdev::Key::KeyC, 0x2E, 0x43),
+    (rdev::Key::KeyV, 0x2F, 0x56),
+    (rdev::Key::KeyB, 0x30, 0x42),
+    (rdev::Key::KeyN, 0x31, 0x4E),
+    (rdev::Key::KeyM, 0x32, 0x4D),
+    (rdev::Key::Comma, 0x33, 0xBC),
+    (rdev::Key::Dot, 0x34, 0xBE),
+    (rdev::Key::Slash, 0x35, 0xBF),
+    (rdev::Key::IntlBackslash, 0x56, 0xE2),
+];
+
+fn get_scan_code(key: rdev::Key) -> Option<u32> {
+    let (_, code, _vk) = SCAN_CODES.iter().find(|x| x.0 == key)?;
+    #[cfg(windows)]
+    {
+        // the position of the key in the layout in use
+        let code = unsafe { winapi::um::winuser::MapVirtualKeyW(*_vk, 0) };
+        if code != 0 {
+            return Some(code);
+        }
+    }
+    Some(*code)
+}
+
+fn get_gamepad_state(index: u32, gamepad: &gilrs::Gamepad) -> GamepadState {
+    use gilrs::{Axis, Button};
+    // the bits of XInput
+    const BUTTONS: [(Button, u32); 15] = [
+        (Button::DPadUp, 0x0001),
+        (Button::DPadDown, 0x0002),
+        (Button::DPadLeft, 0x0004),
+        (Button::DPadRight, 0x0008),
+        (Button::Start, 0x0010),
+        (Button::Select, 0x0020),
+        (Button::LeftThumb, 0x0040),
+        (Button::RightThumb, 0x0080),
+        (Button::LeftTrigger, 0x0100),
+        (Button::RightTrigger, 0x0200),
+        (Button::Mode, 0x0400),
+        (Button::South, 0x1000),
+        (Button::East, 0x2000),
+        (Button::West, 0x4000),
+        (Button::North, 0x8000),
+    ];
+    let buttons = BUTTONS
+        .iter()
+        .filter(|x| gamepad.is_pressed(x.0))
+        .fold(0, |a, x| a | x.1);
+    let trigger = |button| {
+        gamepad
+            .button_data(button)
+            .map(|x| (x.value() * 255.) as u32)
+            .unwrap_or(0)
+    };
+    let thumb = |axis| (gamepad.value(axis) * 32767.) as i32;
+    GamepadState {
+        index,
+        buttons,
+        left_trigger: trigger(Button::LeftTrigger2),
+        right_trigger: trigger(Button::RightTrigger2),
+        left_thumb_x: thumb(Axis::LeftStickX),
+        left_thumb_y: thumb(Axis::LeftStickY),
+        right_thumb_x: thumb(Axis::RightStickX),
+        right_thumb_y: thumb(Axis::RightStickY),
+        connected: true,
+        ..Default::default()
+    }
+}
+
 static mut IS_IN: bool = false;
 static mut KEYBOARD_HOOKED: bool = false;
 static mut KEYBOARD_ENABLED: bool = true;
+static mut GAMEPADS_POLLED: bool = false;
+
+#[cfg(windows)]
+thread_local! {
+    // the session given the touch and pen input of its window
+    static POINTER_HANDLER: std::cell::RefCell<Option<Handler>> = Default::default();
+}
+
+// the touch and pen input of the window, true if the session takes it
+#[cfg(windows)]
+extern "C" fn on_pointer(
+    kind: u32,
+    id: u32,
+    x: i32,
+    y: i32,
+    pressure: u32,
+    tilt_x: i32,
+    tilt_y: i32,
+    flags: u32,
+) -> i32 {
+    POINTER_HANDLER.with(|h| {
+        if let Some(h) = h.borrow().as_ref() {
+            if let Some(ref e) = h.read().unwrap().element {
+                let args = make_args!(
+                    kind as i32,
+                    id as i32,
+                    x,
+                    y,
+                    pressure as i32,
+                    tilt_x,
+                    tilt_y,
+                    flags as i32
+                );
+                if let Ok(v) = e.call_method("onPointer", &args) {
+                    return v.to_bool().unwrap_or(false) as _;
+                }
+            }
+        }
+        0
+    })
+}
 
 #[derive(Default)]
 pub struct HandlerInner {
@@ -58,6 +239,9 @@ pub struct HandlerInner {
     sender: Option<mpsc::UnboundedSender<Data>>,
     thread: Option<std::thread::JoinHandle<()>>,
     close_state: HashMap<String, String>,
+    // the peer was asked to restart or elevate, the session reconnects once it is
+    // back, with the title and text of the message box meanwhile
+    restarting: Option<(&'static str, &'static str)>,
 }
 
 #[derive(Clone, Default)]
@@ -114,7 +298,27 @@ impl sciter::EventHandler for Handler {
                     if ptr.is_null() {
                         return true;
                     }
-                    let site = AssetPtr::adopt(ptr as *mut video_destination);
+                    let mut site = AssetPtr::adopt(ptr as *mut video_destination);
+                    // <video .extra-display display=i width=w height=h>
+                    if let Some(display) = source
+                        .get_attribute("display")
+                        .and_then(|d| d.parse::<usize>().ok())
+                    {
+                        let size = |name| {
+                            source
+                                .get_attribute(name)
+                                .and_then(|v| v.parse::<i32>().ok())
+                                .unwrap_or(0)
+                        };
+                        let ok = site.start_streaming(
+                            (size("width"), size("height")),
+                            COLOR_SPACE::Rgb32,
+                            None,
+                        );
+                        log::debug!("[video] start extra display {}: {:?}", display, ok);
+                        EXTRA_VIDEOS.lock().unwrap().insert(display, site);
+                        return true;
+                    }
                     log::debug!("[video] start video");
                     *VIDEO.lock().unwrap() = Some(site);
                     self.reconnect();
@@ -153,17 +357,34 @@ impl sciter::EventHandler for Handler {
         fn save_close_state(String, String);
         fn is_file_transfer();
         fn is_port_forward();
+        fn is_view_only();
         fn is_rdp();
         fn login(String, bool);
+        fn login_totp(String);
         fn new_rdp();
+        fn record_screen(bool);
+        fn toggle_microphone(bool);
+        fn voice_call(bool);
         fn send_mouse(i32, i32, i32, bool, bool, bool, bool);
+        fn send_wheel(i32, i32, i32, i32, bool, bool, bool, bool);
+        fn send_touch(i32, i32, i32, i32, bool);
+        fn send_pen(i32, i32, i32, i32, i32, i32);
+        fn send_annotation(i32, i32, i32, i32, i32, i32);
+        fn hook_pointer();
         fn enter();
         fn leave();
         fn ctrl_alt_del();
         fn transfer_file();
         fn tunnel();
+        fn switch_sides();
+        fn restart_remote_device(bool);
+        fn elevate(String, String);
         fn lock_screen();
+        fn tap_key(String);
+        fn get_clipboard_history();
+        fn send_clipboard_history(i32);
         fn reconnect();
+        fn trust_peer_key();
         fn get_msgbox();
         fn get_chatbox();
         fn get_icon();
@@ -173,12 +394,25 @@ impl sciter::EventHandler for Handler {
         fn create_dir(i32, String, bool);
         fn remove_file(i32, String, i32, bool);
         fn read_remote_dir(String, bool);
+        fn search_remote(i32, String, String, bool);
         fn send_chat(String);
+        fn send_chat_file(i32, String);
+        fn get_chat_history();
+        fn read_chat();
+        fn print_job(String);
+        fn save_print_job(String, String);
+        fn discard_print_job(String);
         fn switch_display(i32);
+        fn capture_displays(String);
         fn remove_dir_all(i32, String, bool);
         fn confirm_delete_files(i32, i32);
         fn set_no_confirm(i32);
         fn cancel_job(i32);
+        fn pause_job(i32);
+        fn sync_dir(i32, String, String, bool, bool, bool);
+        fn confirm_sync(i32);
+        fn drop_file(i32, String);
+        fn resume_job(i32);
         fn send_files(i32, String, String, bool, bool);
         fn get_platform(bool);
         fn get_path_sep(bool);
@@ -193,13 +427,25 @@ impl sciter::EventHandler for Handler {
         fn get_view_style();
         fn get_image_quality();
         fn get_custom_image_quality();
+        fn get_quality_preset();
         fn save_view_style(String);
         fn save_image_quality(String);
+        fn save_quality_preset(String);
+        fn save_audio_mode(String);
+        fn save_clipboard_direction(String);
+        fn save_keyboard_mode(String);
         fn save_custom_image_quality(i32, i32);
         fn refresh_video();
         fn support_refresh();
         fn get_toggle_option(String);
+        fn has_capability(String);
+        fn get_plugin_actions();
+        fn plugin_action(String, String);
         fn toggle_option(String);
+        fn get_windows();
+        fn request_stats(bool);
+        fn set_capture_region(i32, i32, i32, i32, String);
+        fn change_resolution(i32, i32);
         fn get_remember();
     }
 }
@@ -216,11 +462,18 @@ impl Handler {
             .write()
             .unwrap()
             .initialize(id, me.is_file_transfer(), me.is_port_forward());
+        me.lc.write().unwrap().view_only = me.is_view_only();
+        if me.args.iter().any(|x| x == "--switch-sides") {
+            match crate::ipc::take_switch_sides_back(me.id.clone()) {
+                Ok(token) => me.lc.write().unwrap().switch_sides = token,
+                Err(err) => log::error!("Failed to switch sides: {}", err),
+            }
+        }
         me
     }
 
     fn start_keyboard_hook(&self) {
-        if self.is_port_forward() || self.is_file_transfer() {
+        if self.is_port_forward() || self.is_file_transfer() || self.is_view_only() {
             return;
         }
         if unsafe { KEYBOARD_HOOKED } {
@@ -329,16 +582,33 @@ impl Handler {
                     Key::PageUp => Some(ControlKey::PageUp),
                     Key::PageDown => Some(ControlKey::PageDown),
                     Key::Pause => Some(ControlKey::Pause),
+                    Key::Unknown(code) => get_media_key(code),
                     _ => None,
                 };
                 let mut key_event = KeyEvent::new();
                 if let Some(k) = control_key {
                     key_event.set_control_key(k);
                 } else {
+                    let mode = me.lc.read().unwrap().get_option("keyboard-mode");
+                    if mode == "positional" {
+                        if let Some(code) = get_scan_code(key) {
+                            key_event.set_scan_code(code);
+                            me.key_down_or_up(down, key_event, alt, ctrl, shift, command);
+                            return;
+                        }
+                    }
                     let chr = match evt.name {
                         Some(ref s) => s.chars().next().unwrap_or('\0'),
                         _ => '\0',
                     };
+                    if mode == "character" && !(alt || ctrl || command) {
+                        // typed as is by the peer, the dead keys already composed
+                        if down == 1 && chr != '\0' {
+                            key_event.set_unicode(chr as _);
+                            me.key_down_or_up(3, key_event, false, false, false, false);
+                        }
+                        return;
+                    }
                     if chr != '\0' {
                         if chr == 'l' && is_win && command {
                             me.lock_screen();
@@ -358,6 +628,64 @@ impl Handler {
         });
     }
 
+    // the gamepads of the client, sent at each change while "forward-gamepad" is on
+    fn start_gamepad_thread(&self) {
+        if self.is_port_forward() || self.is_file_transfer() || self.is_view_only() {
+            return;
+        }
+        if unsafe { GAMEPADS_POLLED } {
+            return;
+        }
+        unsafe {
+            GAMEPADS_POLLED = true;
+        }
+        let mut me = self.clone();
+        std::thread::spawn(move || {
+            let mut gilrs = match gilrs::Gilrs::new() {
+                Ok(gilrs) => gilrs,
+                Err(err) => {
+                    log::error!("Failed to open the gamepads: {}", err);
+                    return;
+                }
+            };
+            let mut sent: HashMap<usize, GamepadState> = HashMap::new();
+            loop {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                // the states are updated by the events
+                while gilrs.next_event().is_some() {}
+                let mut states = HashMap::new();
+                let enabled = me.lc.read().unwrap().get_toggle_option("forward-gamepad");
+                if enabled && unsafe { KEYBOARD_ENABLED } {
+                    for (id, gamepad) in gilrs.gamepads() {
+                        let id: usize = id.into();
+                        if id < 4 {
+                            states.insert(id, get_gamepad_state(id as _, &gamepad));
+                        }
+                    }
+                }
+                for (id, state) in sent.iter() {
+                    if !states.contains_key(id) {
+                        let mut state = state.clone();
+                        state.connected = false;
+                        me.send_gamepad_state(state);
+                    }
+                }
+                for (id, state) in states.iter() {
+                    if sent.get(id) != Some(state) {
+                        me.send_gamepad_state(state.clone());
+                    }
+                }
+                sent = states;
+            }
+        });
+    }
+
+    fn send_gamepad_state(&mut self, state: GamepadState) {
+        let mut msg_out = Message::new();
+        msg_out.set_gamepad_state(state);
+        self.send(Data::Message(msg_out));
+    }
+
     fn get_view_style(&mut self) -> String {
         return self.lc.read().unwrap().view_style.clone();
     }
@@ -366,6 +694,10 @@ impl Handler {
         return self.lc.read().unwrap().image_quality.clone();
     }
 
+    fn get_quality_preset(&mut self) -> String {
+        self.lc.read().unwrap().quality_preset.clone()
+    }
+
     fn get_custom_image_quality(&mut self) -> Value {
         let mut v = Value::array(0);
         for x in self.lc.read().unwrap().custom_image_quality.iter() {
@@ -399,6 +731,86 @@ impl Handler {
         self.lc.read().unwrap().get_toggle_option(&name)
     }
 
+    // a feature of the protocol of hbb_common::capabilities, which the peer has
+    fn has_capability(&self, name: String) -> bool {
+        self.peer_has(&name)
+    }
+
+    fn peer_has(&self, capability: &str) -> bool {
+        self.lc.read().unwrap().capabilities.has(capability)
+    }
+
+    // [plugin, action, label] of the plugins enabled, for the toolbar
+    fn get_plugin_actions(&self) -> Value {
+        let mut v = Value::array(0);
+        for (plugin, action, label) in crate::plugins::actions() {
+            let mut a = Value::array(0);
+            a.push(plugin);
+            a.push(action);
+            a.push(label);
+            v.push(a);
+        }
+        v
+    }
+
+    fn plugin_action(&mut self, plugin: String, action: String) {
+        if let Some(msg) = crate::plugins::on_action(&plugin, &action, &self.id) {
+            self.send(Data::Message(msg));
+        }
+    }
+
+    // answered with updateStats every few seconds while on
+    fn request_stats(&mut self, on: bool) {
+        let mut misc = Misc::new();
+        misc.set_request_stats(on);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    // answered with showWindows
+    fn get_windows(&mut self) {
+        let mut misc = Misc::new();
+        misc.set_get_windows(true);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    // of the current display, a window if its id is not empty, or the whole
+    // display if width is 0
+    fn set_capture_region(&mut self, x: i32, y: i32, width: i32, height: i32, window: String) {
+        let mut misc = Misc::new();
+        misc.set_option(OptionMessage {
+            capture_region: Some(CaptureRegion {
+                x,
+                y,
+                width,
+                height,
+                window: window.parse().unwrap_or(0),
+                ..Default::default()
+            })
+            .into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    // of the current display, to the size of the view, or back to its own if 0x0
+    fn change_resolution(&mut self, width: i32, height: i32) {
+        let mut misc = Misc::new();
+        misc.set_change_resolution(Resolution {
+            width,
+            height,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn refresh_video(&mut self) {
         self.send(Data::Message(LoginConfigHandler::refresh()));
     }
@@ -423,6 +835,35 @@ impl Handler {
         }
     }
 
+    fn save_quality_preset(&mut self, value: String) {
+        let msg = self.lc.write().unwrap().save_quality_preset(value);
+        if let Some(msg) = msg {
+            self.send(Data::Message(msg));
+        }
+    }
+
+    fn save_audio_mode(&mut self, value: String) {
+        let msg = self.lc.write().unwrap().save_audio_mode(value);
+        if let Some(msg) = msg {
+            self.send(Data::Message(msg));
+        }
+    }
+
+    fn save_clipboard_direction(&mut self, value: String) {
+        let msg = self.lc.write().unwrap().save_clipboard_direction(value);
+        self.send(Data::Message(msg));
+    }
+
+    /// "character" to type the characters of the local layout, "positional" to
+    /// press the keys at the same place in the layout of the peer, the legacy
+    /// mapping otherwise.
+    fn save_keyboard_mode(&mut self, value: String) {
+        self.lc
+            .write()
+            .unwrap()
+            .set_option("keyboard-mode".to_owned(), value);
+    }
+
     fn get_remember(&mut self) -> bool {
         self.lc.read().unwrap().remember
     }
@@ -595,6 +1036,42 @@ impl Handler {
         self.send(Data::CancelJob(id));
     }
 
+    fn sync_dir(
+        &mut self,
+        id: i32,
+        local: String,
+        remote: String,
+        to_remote: bool,
+        by_hash: bool,
+        include_hidden: bool,
+    ) {
+        self.send(Data::SyncDir((
+            id,
+            local,
+            remote,
+            to_remote,
+            by_hash,
+            include_hidden,
+        )));
+    }
+
+    fn confirm_sync(&mut self, id: i32) {
+        self.send(Data::ConfirmSync(id));
+    }
+
+    fn drop_file(&mut self, id: i32, path: String) {
+        let paste = self.get_toggle_option("paste-after-drop".to_owned());
+        self.send(Data::DropFile((id, path, paste)));
+    }
+
+    fn pause_job(&mut self, id: i32) {
+        self.send(Data::PauseJob(id));
+    }
+
+    fn resume_job(&mut self, id: i32) {
+        self.send(Data::ResumeJob(id));
+    }
+
     fn read_remote_dir(&mut self, path: String, include_hidden: bool) {
         let mut msg_out = Message::new();
         let mut file_action = FileAction::new();
@@ -607,10 +1084,75 @@ impl Handler {
         self.send(Data::Message(msg_out));
     }
 
-    fn send_chat(&mut self, text: String) {
+    fn search_remote(&mut self, id: i32, path: String, pattern: String, include_hidden: bool) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_search(FileSearch {
+            id,
+            path,
+            pattern,
+            include_hidden,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        self.send(Data::Message(msg_out));
+    }
+
+    // the message kept in the history, with its id
+    fn send_chat(&mut self, text: String) -> Value {
+        self.send_chat_message(text, "".to_owned())
+    }
+
+    // a small file attached, sent as the dropped files, the error if it can not be
+    fn send_chat_file(&mut self, id: i32, path: String) -> Value {
+        match std::fs::metadata(&path) {
+            Ok(m) if m.is_file() && m.len() <= crate::chat::MAX_FILE_SIZE => {}
+            Ok(m) if m.is_file() => return Value::from("File too large"),
+            Ok(_) => return Value::from("Not a file"),
+            Err(err) => return Value::from(err.to_string()),
+        }
+        let name = fs::get_file_name(&fs::get_path(&path));
+        self.send(Data::DropFile((id, path, false)));
+        self.send_chat_message("".to_owned(), name)
+    }
+
+    fn send_chat_message(&mut self, text: String, file: String) -> Value {
+        let msg = crate::chat::Message {
+            id: crate::chat::new_id(),
+            me: true,
+            text: text.clone(),
+            file: file.clone(),
+            time: crate::get_time(),
+            ..Default::default()
+        };
+        crate::chat::add(&self.id, msg.clone());
         let mut misc = Misc::new();
         misc.set_chat_message(ChatMessage {
             text,
+            id: msg.id,
+            file,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+        super::make_chat_message(&msg)
+    }
+
+    fn get_chat_history(&mut self) -> Value {
+        super::make_chat_history(&self.id)
+    }
+
+    // the messages of the peer shown, with a receipt for it
+    fn read_chat(&mut self) {
+        let ids = crate::chat::read_all(&self.id);
+        if ids.is_empty() || !self.peer_has(capabilities::CHAT_RECEIPT) {
+            return;
+        }
+        let mut misc = Misc::new();
+        misc.set_chat_receipt(ChatReceipt {
+            ids,
+            read: true,
             ..Default::default()
         });
         let mut msg_out = Message::new();
@@ -618,6 +1160,25 @@ impl Handler {
         self.send(Data::Message(msg_out));
     }
 
+    // of the jobs received from the printer of the peer, the error if any
+    fn print_job(&mut self, path: String) -> String {
+        match crate::printer::print(Path::new(&path)) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn save_print_job(&mut self, path: String, to: String) -> String {
+        match crate::printer::save(Path::new(&path), Path::new(&to)) {
+            Ok(_) => "".to_owned(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn discard_print_job(&mut self, path: String) {
+        allow_err!(crate::printer::discard(Path::new(&path)));
+    }
+
     fn switch_display(&mut self, display: i32) {
         let mut misc = Misc::new();
         misc.set_switch_display(SwitchDisplay {
@@ -629,6 +1190,26 @@ impl Handler {
         self.send(Data::Message(msg_out));
     }
 
+    // e.g. "0,2", empty for the current display only
+    fn capture_displays(&mut self, displays: String) {
+        let displays: Vec<i32> = displays
+            .split(',')
+            .filter_map(|d| d.trim().parse().ok())
+            .collect();
+        EXTRA_VIDEOS
+            .lock()
+            .unwrap()
+            .retain(|d, _| displays.contains(&(*d as i32)));
+        let mut misc = Misc::new();
+        misc.set_capture_displays(CaptureDisplays {
+            displays,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn remove_file(&mut self, id: i32, path: String, file_num: i32, is_remote: bool) {
         self.send(Data::RemoveFile((id, path, file_num, is_remote)));
     }
@@ -672,6 +1253,10 @@ impl Handler {
         self.cmd == "--file-transfer"
     }
 
+    fn is_view_only(&self) -> bool {
+        self.cmd == "--view-only"
+    }
+
     fn is_port_forward(&self) -> bool {
         self.cmd == "--port-forward" || self.is_rdp()
     }
@@ -680,6 +1265,10 @@ impl Handler {
         self.cmd == "--rdp"
     }
 
+    fn trust_peer_key(&self) {
+        crate::client::trust_peer_key(&self.id);
+    }
+
     fn reconnect(&mut self) {
         let cloned = self.clone();
         let mut lock = self.write().unwrap();
@@ -772,10 +1361,26 @@ impl Handler {
         self.send(Data::Login((password, remember)));
     }
 
+    fn login_totp(&mut self, code: String) {
+        self.send(Data::LoginTotp(code));
+    }
+
     fn new_rdp(&mut self) {
         self.send(Data::NewRDP);
     }
 
+    fn record_screen(&mut self, start: bool) {
+        self.send(Data::RecordScreen(start));
+    }
+
+    fn toggle_microphone(&mut self, on: bool) {
+        self.send(Data::Microphone(on));
+    }
+
+    fn voice_call(&mut self, on: bool) {
+        self.send(Data::VoiceCall(on));
+    }
+
     fn enter(&mut self) {
         unsafe {
             IS_IN = true;
@@ -798,6 +1403,9 @@ impl Handler {
         shift: bool,
         command: bool,
     ) {
+        if self.is_view_only() {
+            return;
+        }
         let mut msg_out = Message::new();
         let mut mouse_event = MouseEvent {
             mask,
@@ -805,18 +1413,7 @@ impl Handler {
             y,
             ..Default::default()
         };
-        if alt {
-            mouse_event.modifiers.push(ControlKey::Alt.into());
-        }
-        if shift {
-            mouse_event.modifiers.push(ControlKey::Shift.into());
-        }
-        if ctrl {
-            mouse_event.modifiers.push(ControlKey::Control.into());
-        }
-        if command {
-            mouse_event.modifiers.push(ControlKey::Meta.into());
-        }
+        push_modifiers(&mut mouse_event, alt, ctrl, shift, command);
         msg_out.set_mouse_event(mouse_event);
         self.send(Data::Message(msg_out));
         // on macos, ctrl + left = right, up wont emit, so we need to
@@ -831,6 +1428,109 @@ impl Handler {
         }
     }
 
+    // x and y in whole clicks for the older peers, wheel_x and wheel_y in 1/120
+    fn send_wheel(
+        &mut self,
+        x: i32,
+        y: i32,
+        wheel_x: i32,
+        wheel_y: i32,
+        alt: bool,
+        ctrl: bool,
+        shift: bool,
+        command: bool,
+    ) {
+        if self.is_view_only() {
+            return;
+        }
+        let mut msg_out = Message::new();
+        let mut mouse_event = MouseEvent {
+            mask: 3,
+            x,
+            y,
+            wheel_x,
+            wheel_y,
+            ..Default::default()
+        };
+        push_modifiers(&mut mouse_event, alt, ctrl, shift, command);
+        msg_out.set_mouse_event(mouse_event);
+        self.send(Data::Message(msg_out));
+    }
+
+    fn send_touch(&mut self, id: i32, x: i32, y: i32, pressure: i32, down: bool) {
+        if self.is_view_only() {
+            return;
+        }
+        let mut msg_out = Message::new();
+        msg_out.set_touch_event(TouchEvent {
+            id: id as _,
+            x,
+            y,
+            pressure: pressure as _,
+            down,
+            ..Default::default()
+        });
+        self.send(Data::Message(msg_out));
+    }
+
+    // flags: 1 down, 2 in range, 4 barrel button, 8 eraser
+    fn send_pen(&mut self, x: i32, y: i32, pressure: i32, tilt_x: i32, tilt_y: i32, flags: i32) {
+        if self.is_view_only() {
+            return;
+        }
+        let mut msg_out = Message::new();
+        msg_out.set_pen_event(PenEvent {
+            x,
+            y,
+            pressure: pressure as _,
+            tilt_x,
+            tilt_y,
+            down: flags & 1 != 0,
+            in_range: flags & 2 != 0,
+            barrel: flags & 4 != 0,
+            eraser: flags & 8 != 0,
+            ..Default::default()
+        });
+        self.send(Data::Message(msg_out));
+    }
+
+    // shape: 0 laser, 1 arrow, 2 circle, 3 clear, color 0xRRGGBB, in the
+    // coordinates of the remote screen
+    fn send_annotation(&mut self, shape: i32, x0: i32, y0: i32, x1: i32, y1: i32, color: i32) {
+        if self.is_view_only() || !self.peer_has(capabilities::ANNOTATION) {
+            return;
+        }
+        let shape = match shape {
+            0 => annotation::Shape::Laser,
+            1 => annotation::Shape::Arrow,
+            2 => annotation::Shape::Circle,
+            _ => annotation::Shape::Clear,
+        };
+        let mut msg_out = Message::new();
+        msg_out.set_annotation(Annotation {
+            shape: shape.into(),
+            x0,
+            y0,
+            x1,
+            y1,
+            color: color as _,
+            ..Default::default()
+        });
+        self.send(Data::Message(msg_out));
+    }
+
+    // the touch and pen input of the window given to onPointer of the script,
+    // on Windows only
+    fn hook_pointer(&mut self) -> bool {
+        #[cfg(windows)]
+        {
+            POINTER_HANDLER.with(|h| *h.borrow_mut() = Some(self.clone()));
+            return crate::platform::windows::hook_pointer(on_pointer);
+        }
+        #[cfg(not(windows))]
+        false
+    }
+
     fn set_cursor_data(&mut self, cd: CursorData) {
         let colors = hbb_common::compress::decompress(&cd.colors);
         let mut png = Vec::new();
@@ -980,13 +1680,70 @@ impl Handler {
         }
     }
 
-    fn lock_screen(&mut self) {
+    // after the dropped files are written, their path is in the remote clipboard
+    fn paste(&mut self) {
         let mut key_event = KeyEvent::new();
-        key_event.set_control_key(ControlKey::LockScreen);
-        self.key_down_or_up(1, key_event, false, false, false, false);
+        key_event.set_chr('v' as _);
+        let command = self.peer_platform() == "Mac OS";
+        self.key_down_or_up(3, key_event, false, !command, false, command);
     }
 
-    fn transfer_file(&mut self) {
+    // the first line of each text, shortened for the menu
+    fn get_clipboard_history(&mut self) -> Value {
+        let mut v = Value::array(0);
+        for text in common::get_clipboard_history() {
+            let line = text.lines().map(|x| x.trim()).find(|x| !x.is_empty());
+            let line = line.unwrap_or_default();
+            let mut label: String = line.chars().take(40).collect();
+            if label.len() < line.len() {
+                label.push_str("...");
+            }
+            v.push(label);
+        }
+        v
+    }
+
+    // an older text of the clipboard to the clipboard of the peer, and pasted
+    fn send_clipboard_history(&mut self, index: i32) {
+        {
+            let lc = self.lc.read().unwrap();
+            if lc.disable_clipboard
+                || lc.get_clipboard_direction() == option_message::ClipboardDirection::ToClient
+            {
+                return;
+            }
+        }
+        if let Some(msg) = common::create_clipboard_history_msg(index as _) {
+            self.send(Data::Message(msg));
+            self.paste();
+        }
+    }
+
+    fn lock_screen(&mut self) {
+        let mut key_event = KeyEvent::new();
+        key_event.set_control_key(ControlKey::LockScreen);
+        self.key_down_or_up(1, key_event, false, false, false, false);
+    }
+
+    // the special keys of the toolbar, which the local system keeps for itself
+    fn tap_key(&mut self, name: String) {
+        let key = match name.as_str() {
+            "meta" => ControlKey::Meta,
+            "volume-mute" => ControlKey::VolumeMute,
+            "volume-down" => ControlKey::VolumeDown,
+            "volume-up" => ControlKey::VolumeUp,
+            "media-play-pause" => ControlKey::MediaPlayPause,
+            "media-next-track" => ControlKey::MediaNextTrack,
+            "media-prev-track" => ControlKey::MediaPrevTrack,
+            "media-stop" => ControlKey::MediaStop,
+            _ => return,
+        };
+        let mut key_event = KeyEvent::new();
+        key_event.set_control_key(key);
+        self.key_down_or_up(3, key_event, false, false, false, false);
+    }
+
+    fn transfer_file(&mut self) {
         let id = self.get_id();
         let args = vec!["--file-transfer", &id];
         if let Err(err) = crate::run_me(args) {
@@ -1002,6 +1759,63 @@ impl Handler {
         }
     }
 
+    // the peer connects back with a one-time token the local server lets in
+    // without a password, signed with the key pinned here of the peer, false
+    // if it could not be given to the server
+    fn switch_sides(&mut self) -> bool {
+        let pk = PeerConfig::load(&self.get_id()).pinned_key;
+        if pk.is_empty() {
+            self.msgbox("error", "Error", "No key of the peer to switch sides");
+            return false;
+        }
+        let token = uuid::Uuid::new_v4().to_string();
+        if let Err(err) = crate::ipc::switch_sides(self.get_id(), token.clone(), pk) {
+            log::error!("Failed to switch sides: {}", err);
+            self.msgbox("error", "Error", "Service is not running");
+            return false;
+        }
+        let mut misc = Misc::new();
+        misc.set_switch_sides(token);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+        true
+    }
+
+    fn restart_remote_device(&mut self, safe_mode: bool) {
+        self.write().unwrap().restarting =
+            Some(("Restarting Remote Device", "remote_restarting_tip"));
+        let mut misc = Misc::new();
+        misc.set_restart(Restart {
+            safe_mode,
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    // the UAC prompt for the remote user if `username` is empty,
+    // otherwise logged on as the administrator
+    fn elevate(&mut self, username: String, password: String) {
+        self.write().unwrap().restarting = Some(("Request Elevation", "elevation_restarting_tip"));
+        let mut request = ElevationRequest::new();
+        if username.is_empty() {
+            request.set_direct(true);
+        } else {
+            request.set_logon(ElevationRequestWithLogon {
+                username,
+                password,
+                ..Default::default()
+            });
+        }
+        let mut misc = Misc::new();
+        misc.set_elevation_request(request);
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
     fn key_down_or_up(
         &mut self,
         down_or_up: i32,
@@ -1011,6 +1825,9 @@ impl Handler {
         shift: bool,
         command: bool,
     ) {
+        if self.is_view_only() {
+            return;
+        }
         let mut key_event = evt;
 
         if alt
@@ -1081,6 +1898,8 @@ impl Handler {
 }
 
 const MILLI1: Duration = Duration::from_millis(1);
+// of the peer to reboot and register again
+const RESTART_TIMEOUT: Duration = Duration::from_secs(300);
 
 async fn start_one_port_forward(
     handler: Handler,
@@ -1090,11 +1909,28 @@ async fn start_one_port_forward(
     receiver: mpsc::UnboundedReceiver<Data>,
 ) {
     handler.lc.write().unwrap().port_forward = (remote_host, remote_port);
-    if let Err(err) =
-        crate::port_forward::listen(handler.id.clone(), port, handler.clone(), receiver).await
-    {
+    let id = handler.id.clone();
+    let start = crate::get_time();
+    let res = if remote_port == 0 {
+        crate::port_forward::listen_dynamic(id.clone(), port, handler.clone(), receiver).await
+    } else {
+        crate::port_forward::listen(id.clone(), port, handler.clone(), receiver).await
+    };
+    let mut reason = "".to_owned();
+    if let Err(err) = res {
+        reason = err.to_string();
         handler.on_error(&format!("Failed to listen on {}: {}", port, err));
     }
+    crate::ipc::add_audit(audit::Entry {
+        direction: "outgoing".to_owned(),
+        peer_id: id,
+        conn_type: "port-forward".to_owned(),
+        start,
+        end: crate::get_time(),
+        reason,
+        ..Default::default()
+    })
+    .await;
     log::info!("port forward (:{}) exit", port);
 }
 
@@ -1114,7 +1950,7 @@ async fn io_loop(handler: Handler) {
             loop {
                 match receiver.recv().await {
                     Some(Data::AddPortForward((port, remote_host, remote_port))) => {
-                        if port <= 0 || remote_port <= 0 {
+                        if port <= 0 || remote_port < 0 {
                             continue;
                         }
                         let (sender, receiver) = mpsc::unbounded_channel::<Data>();
@@ -1164,6 +2000,7 @@ async fn io_loop(handler: Handler) {
     let mut remote = Remote {
         handler,
         video_handler: VideoHandler::new(),
+        extra_video_handlers: Default::default(),
         audio_handler: Default::default(),
         receiver,
         sender,
@@ -1176,6 +2013,24 @@ async fn io_loop(handler: Handler) {
         clipboard: Arc::new(RwLock::new(true)),
         keyboard: Arc::new(RwLock::new(true)),
         first_frame: false,
+        direct: false,
+        media: None,
+        pending_jobs: Default::default(),
+        queued_jobs: Default::default(),
+        paused_jobs: Default::default(),
+        sync_jobs: Default::default(),
+        drop_jobs: Default::default(),
+        cursor_pos: (0, 0),
+        video_stats: Default::default(),
+        recorder: None,
+        audio_format: None,
+        display_size: (0, 0),
+        audit: Default::default(),
+        #[cfg(not(any(target_os = "android")))]
+        microphone: None,
+        #[cfg(not(any(target_os = "android")))]
+        voice_call: None,
+        print_spooler: Default::default(),
     };
     remote.io_loop().await;
 }
@@ -1202,13 +2057,49 @@ impl RemoveJob {
     }
 }
 
+// A folder made the same as the other, the files of the source missing in
+// the destination or not the same there are copied.
+struct SyncJob {
+    local: String,
+    remote: String,
+    to_remote: bool,
+    by_hash: bool,
+    include_hidden: bool,
+    files: Vec<FileEntry>,
+}
+
+fn push_modifiers(evt: &mut MouseEvent, alt: bool, ctrl: bool, shift: bool, command: bool) {
+    if alt {
+        evt.modifiers.push(ControlKey::Alt.into());
+    }
+    if shift {
+        evt.modifiers.push(ControlKey::Shift.into());
+    }
+    if ctrl {
+        evt.modifiers.push(ControlKey::Control.into());
+    }
+    if command {
+        evt.modifiers.push(ControlKey::Meta.into());
+    }
+}
+
+// of the transfers running at the same time, the others are queued
+#[inline]
+fn get_max_parallel_jobs() -> usize {
+    Config::get_option("max-parallel-transfers")
+        .parse::<usize>()
+        .unwrap_or(3)
+        .max(1)
+}
+
 struct Remote {
     handler: Handler,
     audio_handler: AudioHandler,
     video_handler: VideoHandler,
+    extra_video_handlers: HashMap<usize, VideoHandler>,
     receiver: mpsc::UnboundedReceiver<Data>,
     sender: mpsc::UnboundedSender<Data>,
-    old_clipboard: Arc<Mutex<String>>,
+    old_clipboard: Arc<Mutex<ClipboardContent>>,
     read_jobs: Vec<fs::TransferJob>,
     write_jobs: Vec<fs::TransferJob>,
     remove_jobs: HashMap<i32, RemoveJob>,
@@ -1217,87 +2108,338 @@ struct Remote {
     clipboard: Arc<RwLock<bool>>,
     keyboard: Arc<RwLock<bool>>,
     first_frame: bool,
+    direct: bool,
+    media: Option<ReliableChannel>,
+    // arguments of the unfinished transfers, to restart them on resume
+    pending_jobs: HashMap<i32, (String, String, bool, bool)>,
+    // of the transfers waiting for one of the others to finish
+    queued_jobs: VecDeque<(i32, (String, String, bool, bool))>,
+    // of the transfers cancelled with their download files kept, to resume them
+    paused_jobs: HashMap<i32, (String, String, bool, bool)>,
+    // compared, waiting for the preview to be confirmed
+    sync_jobs: HashMap<i32, SyncJob>,
+    // of the files dropped on the remote view, and if pasted when written
+    drop_jobs: HashMap<i32, bool>,
+    // of the remote cursor, moved by the deltas
+    cursor_pos: (i32, i32),
+    video_stats: VideoStats,
+    recorder: Option<Recorder>,
+    audio_format: Option<AudioFormat>,
+    // of the current display, a new recording is started when it changes
+    display_size: (i32, i32),
+    // of the session once logged in, written to the audit log at its end
+    audit: audit::Entry,
+    #[cfg(not(any(target_os = "android")))]
+    microphone: Option<Microphone>,
+    #[cfg(not(any(target_os = "android")))]
+    voice_call: Option<VoiceCall>,
+    print_spooler: crate::printer::Spooler,
 }
 
 impl Remote {
     async fn io_loop(&mut self) {
         let stop_clipboard = self.start_clipboard();
-        let mut last_recv_time = Instant::now();
         let conn_type = if self.handler.is_file_transfer() {
             ConnType::FILE_TRANSFER
         } else {
             ConnType::default()
         };
-        match Client::start(&self.handler.id, conn_type).await {
-            Ok((mut peer, direct)) => {
-                unsafe {
-                    KEYBOARD_ENABLED = true;
+        let resume_timeout = Duration::from_secs(Config::get_session_resume_timeout());
+        let mut resume_since: Option<Instant> = None;
+        let mut restart_since: Option<Instant> = None;
+        loop {
+            let waiting = resume_since.map(|x| x.elapsed() < resume_timeout) == Some(true)
+                || restart_since.map(|x| x.elapsed() < RESTART_TIMEOUT) == Some(true);
+            match Client::start(&self.handler.id, conn_type).await {
+                Ok((mut peer, direct)) => {
+                    restart_since = None;
+                    peer.set_max_frame_size(crate::common::get_max_frame_size());
+                    unsafe {
+                        KEYBOARD_ENABLED = true;
+                    }
+                    let fingerprint = if peer.is_secured() {
+                        crate::common::get_fingerprint(
+                            &PeerConfig::load(&self.handler.id).pinned_key,
+                        )
+                    } else {
+                        "".to_owned()
+                    };
+                    self.handler.call(
+                        "setConnectionType",
+                        &make_args!(peer.is_secured(), direct, fingerprint),
+                    );
+                    self.direct = direct;
+                    if resume_since.take().is_some() {
+                        self.restart_jobs(&mut peer).await;
+                    }
+                    let err = match self.peer_loop(&mut peer).await {
+                        Some(err) => err,
+                        None => {
+                            self.audit.reason = "".to_owned();
+                            break;
+                        }
+                    };
+                    self.audit.reason = err.clone();
+                    let restarting = self.handler.write().unwrap().restarting.take();
+                    if restarting.is_some() {
+                        log::info!("Peer restarting, try to reconnect: {}", err);
+                        self.handler.lc.write().unwrap().resume_token.clear();
+                        restart_since = Some(Instant::now());
+                    } else if resume_timeout.as_secs() == 0
+                        || self.handler.lc.read().unwrap().resume_token.is_empty()
+                    {
+                        self.handler.msgbox("error", "Connection Error", &err);
+                        break;
+                    } else {
+                        log::info!("Connection interrupted, try to resume: {}", err);
+                        resume_since = Some(Instant::now());
+                    }
+                    self.media = None;
+                    self.first_frame = false;
+                    self.video_handler.reset();
+                    self.extra_video_handlers.clear();
+                    if let Some((title, text)) = restarting {
+                        self.handler.msgbox("connecting", title, text);
+                    } else {
+                        self.handler.msgbox(
+                            "connecting",
+                            "Connecting...",
+                            "Connection in progress. Please wait.",
+                        );
+                    }
                 }
-                self.handler
-                    .call("setConnectionType", &make_args!(peer.is_secured(), direct));
-                loop {
-                    tokio::select! {
-                        res = peer.next() => {
-                            if let Some(res) = res {
-                                match res {
-                                    Err(err) => {
-                                        log::error!("Connection closed: {}", err);
-                                        self.handler.msgbox("error", "Connection Error", &err.to_string());
-                                        break;
-                                    }
-                                    Ok(ref bytes) => {
-                                        last_recv_time = Instant::now();
-                                        if !self.handle_msg_from_peer(bytes, &mut peer).await {
-                                            break
-                                        }
-                                    }
-                                }
-                            } else {
-                                log::info!("Reset by the peer");
-                                self.handler.msgbox("error", "Connection Error", "Reset by the peer");
-                                break;
-                            }
+                Err(err) if waiting => {
+                    log::info!("Failed to reconnect: {}", err);
+                    sleep(if restart_since.is_some() { 3. } else { 1. }).await;
+                }
+                Err(err) => {
+                    match crate::client::get_key_change(&self.handler.id) {
+                        Some((pinned, new)) if err.to_string() == KEY_CHANGED => {
+                            self.handler.call("keyChanged", &make_args!(pinned, new));
                         }
-                        d = self.receiver.recv() => {
-                            if let Some(d) = d {
-                                if !self.handle_msg_from_ui(d, &mut peer).await {
-                                    break;
-                                }
-                            }
+                        _ => {
+                            self.handler
+                                .msgbox("error", "Connection Error", &err.to_string());
                         }
-                        _ = self.timer.tick() => {
-                            if last_recv_time.elapsed() >= SEC30 {
-                                self.handler.msgbox("error", "Connection Error", "Timeout");
-                                break;
+                    }
+                    break;
+                }
+            }
+        }
+        log::debug!("Exit io_loop of id={}", self.handler.id);
+        if self.audit.start > 0 {
+            let conn_type = if self.handler.is_file_transfer() {
+                "file-transfer"
+            } else {
+                "desktop"
+            };
+            crate::ipc::add_audit(audit::Entry {
+                direction: "outgoing".to_owned(),
+                peer_id: self.handler.id.clone(),
+                conn_type: conn_type.to_owned(),
+                end: crate::get_time(),
+                ..std::mem::take(&mut self.audit)
+            })
+            .await;
+        }
+        if let Some(stop) = stop_clipboard {
+            stop.send(()).ok();
+        }
+        unsafe {
+            KEYBOARD_ENABLED = false;
+        }
+    }
+
+    // The error if the connection is lost, None if closed on purpose.
+    async fn peer_loop(&mut self, peer: &mut Stream) -> Option<String> {
+        let keepalive = peer.keepalive().interval();
+        let mut keepalive_timer = time::interval_at(Instant::now() + keepalive, keepalive);
+        loop {
+            tokio::select! {
+                res = peer.next() => {
+                    if let Some(res) = res {
+                        match res {
+                            Err(err) => {
+                                log::error!("Connection closed: {}", err);
+                                return Some(err.to_string());
                             }
-                            if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
-                                    self.handler.msgbox("error", "Connection Error", &err.to_string());
-                                    break;
+                            Ok(ref bytes) => {
+                                if !self.handle_msg_from_peer(bytes, peer).await {
+                                    return None;
                                 }
-                                self.update_jobs_status();
-                            } else {
-                                self.timer = time::interval_at(Instant::now() + SEC30, SEC30);
                             }
                         }
+                    } else {
+                        log::info!("Reset by the peer");
+                        return Some("Reset by the peer".to_owned());
+                    }
+                }
+                res = rudp::next_opt(&mut self.media) => {
+                    match res {
+                        Err(err) => {
+                            log::info!("Udp media channel closed, continue on tcp: {}", err);
+                            self.media = None;
+                        }
+                        Ok(ref bytes) => {
+                            peer.keepalive().on_recv();
+                            if !self.handle_msg_from_peer(bytes, peer).await {
+                                return None;
+                            }
+                        }
+                    }
+                }
+                d = self.receiver.recv() => {
+                    if let Some(d) = d {
+                        if !self.handle_msg_from_ui(d, peer).await {
+                            return None;
+                        }
+                    }
+                }
+                _ = keepalive_timer.tick() => {
+                    if !send_keepalive(peer).await {
+                        return Some("Timeout".to_owned());
+                    }
+                }
+                _ = self.timer.tick() => {
+                    if !self.read_jobs.is_empty() {
+                        if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, peer).await {
+                            return Some(err.to_string());
+                        }
+                        self.update_jobs_status();
+                    } else {
+                        self.timer = time::interval_at(Instant::now() + SEC30, SEC30);
                     }
                 }
-                log::debug!("Exit io_loop of id={}", self.handler.id);
             }
-            Err(err) => {
-                self.handler
-                    .msgbox("error", "Connection Error", &err.to_string());
+        }
+    }
+
+    // Blocks in flight were lost with the old stream, so the unfinished
+    // transfers start over on both sides, from the last checkpoint of each
+    // file where the peer can resume.
+    async fn restart_jobs(&mut self, peer: &mut Stream) {
+        self.read_jobs.clear();
+        self.write_jobs.clear();
+        let jobs: Vec<_> = self.pending_jobs.drain().collect();
+        for (id, (path, to, include_hidden, is_remote)) in jobs {
+            log::info!("Restart job {} after resume", id);
+            let mut msg_out = Message::new();
+            let mut file_action = FileAction::new();
+            file_action.set_cancel(FileTransferCancel {
+                id,
+                keep_download: true,
+                ..Default::default()
+            });
+            msg_out.set_file_action(file_action);
+            allow_err!(peer.send(&msg_out).await);
+            self.handle_msg_from_ui(
+                Data::SendFiles((id, path, to, include_hidden, is_remote)),
+                peer,
+            )
+            .await;
+        }
+    }
+
+    // only `files` of `path` if any, e.g. the ones differing in a sync
+    async fn start_job(
+        &mut self,
+        id: i32,
+        args: (String, String, bool, bool),
+        files: Vec<String>,
+        peer: &mut Stream,
+    ) {
+        self.pending_jobs.insert(id, args.clone());
+        let (path, to, include_hidden, is_remote) = args;
+        // once, started again on resume
+        let file = if is_remote {
+            format!("received {}", path)
+        } else {
+            format!("sent {}", path)
+        };
+        if !self.audit.files.contains(&file) {
+            self.audit.files.push(file);
+        }
+        if is_remote {
+            log::debug!("New job {}, write to {} from remote {}", id, to, path);
+            self.write_jobs
+                .push(fs::TransferJob::new_write(id, to, Vec::new()));
+            allow_err!(
+                peer.send(&fs::new_send(id, path, include_hidden, files))
+                    .await
+            );
+        } else {
+            match fs::TransferJob::new_read(id, path.clone(), include_hidden) {
+                Err(err) => {
+                    self.handle_job_status(id, -1, Some(err.to_string()));
+                }
+                Ok(mut job) => {
+                    let version = self.handler.lc.read().unwrap().version;
+                    job.set_resume(fs::can_resume(version));
+                    if !files.is_empty() {
+                        job.retain_files(&files);
+                    }
+                    log::debug!(
+                        "New job {}, read {} to remote {}, {} files",
+                        id,
+                        path,
+                        to,
+                        job.files().len()
+                    );
+                    let m = make_fd(job.id(), job.files(), true);
+                    self.handler.call("updateFolderFiles", &make_args!(m));
+                    let files = job.files().clone();
+                    self.read_jobs.push(job);
+                    self.timer = time::interval(MILLI1);
+                    allow_err!(peer.send(&fs::new_receive(id, to, files)).await);
+                }
             }
         }
-        if let Some(stop) = stop_clipboard {
-            stop.send(()).ok();
+    }
+
+    // The differing files are shown before any is copied, the extra ones
+    // of the destination are kept.
+    fn sync_preview(&mut self, id: i32, remote: Vec<FileEntry>) {
+        let job = match self.sync_jobs.get_mut(&id) {
+            Some(job) => job,
+            None => return,
+        };
+        let mut local = fs::get_recursive_files(&job.local, job.include_hidden).unwrap_or_default();
+        if job.by_hash {
+            fs::hash_files(&job.local, &mut local);
         }
-        unsafe {
-            KEYBOARD_ENABLED = false;
+        job.files = if job.to_remote {
+            fs::diff_files(&local, &remote)
+        } else {
+            fs::diff_files(&remote, &local)
+        };
+        let m = make_fd(id, &job.files, false);
+        self.handler.call("syncPreview", &make_args!(id, m));
+    }
+
+    fn start_queued_job(&mut self) {
+        if self.pending_jobs.len() < get_max_parallel_jobs() {
+            if let Some((id, (path, to, include_hidden, is_remote))) = self.queued_jobs.pop_front()
+            {
+                self.sender
+                    .send(Data::SendFiles((id, path, to, include_hidden, is_remote)))
+                    .ok();
+            }
         }
     }
 
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
+        if let Some(paste) = self.drop_jobs.remove(&id) {
+            if let Some(err) = err {
+                fs::remove_job(id, &mut self.read_jobs);
+                self.handler.msgbox("custom-error", "Drop files", &err);
+            } else if paste {
+                self.handler.paste();
+            }
+            return;
+        }
+        if self.pending_jobs.remove(&id).is_some() {
+            self.start_queued_job();
+        }
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
                 let file_num = (file_num + 1) as usize;
@@ -1351,6 +2493,8 @@ impl Remote {
                     if !*clipboard.read().unwrap()
                         || !*keyboard.read().unwrap()
                         || lc.read().unwrap().disable_clipboard
+                        || lc.read().unwrap().get_clipboard_direction()
+                            == option_message::ClipboardDirection::ToClient
                     {
                         continue;
                     }
@@ -1366,6 +2510,12 @@ impl Remote {
         Some(tx)
     }
 
+    fn update_clipboard_progress(&self) {
+        let progress = get_clipboard_progress().unwrap_or(-1);
+        self.handler
+            .call("setClipboardProgress", &make_args!(progress));
+    }
+
     async fn handle_msg_from_ui(&mut self, data: Data, peer: &mut Stream) -> bool {
         match data {
             Data::Close => {
@@ -1376,35 +2526,85 @@ impl Remote {
                     .handle_login_from_ui(password, remember, peer)
                     .await;
             }
+            Data::LoginTotp(code) => {
+                handle_login_totp(self.handler.lc.clone(), code, peer).await;
+            }
             Data::Message(msg) => {
-                allow_err!(peer.send(&msg).await);
+                if let Some(msg) = self.filter(true, msg) {
+                    allow_err!(peer.send(&msg).await);
+                }
             }
             Data::SendFiles((id, path, to, include_hidden, is_remote)) => {
-                if is_remote {
-                    log::debug!("New job {}, write to {} from remote {}", id, to, path);
-                    self.write_jobs
-                        .push(fs::TransferJob::new_write(id, to, Vec::new()));
-                    allow_err!(peer.send(&fs::new_send(id, path, include_hidden)).await);
-                } else {
-                    match fs::TransferJob::new_read(id, path.clone(), include_hidden) {
-                        Err(err) => {
-                            self.handle_job_status(id, -1, Some(err.to_string()));
-                        }
-                        Ok(job) => {
-                            log::debug!(
-                                "New job {}, read {} to remote {}, {} files",
-                                id,
-                                path,
-                                to,
-                                job.files().len()
-                            );
-                            let m = make_fd(job.id(), job.files(), true);
-                            self.handler.call("updateFolderFiles", &make_args!(m));
-                            let files = job.files().clone();
-                            self.read_jobs.push(job);
-                            self.timer = time::interval(MILLI1);
-                            allow_err!(peer.send(&fs::new_receive(id, to, files)).await);
-                        }
+                if self.pending_jobs.len() >= get_max_parallel_jobs() {
+                    log::debug!("Job {} queued", id);
+                    self.queued_jobs
+                        .push_back((id, (path, to, include_hidden, is_remote)));
+                    return true;
+                }
+                self.start_job(id, (path, to, include_hidden, is_remote), Vec::new(), peer)
+                    .await;
+            }
+            Data::DropFile((id, path, paste)) => {
+                match fs::TransferJob::new_read(id, path.clone(), false) {
+                    Err(err) => {
+                        self.handler
+                            .msgbox("custom-error", "Drop files", &err.to_string());
+                    }
+                    Ok(job) => {
+                        log::debug!("New job {}, drop {} on remote", id, path);
+                        let mut msg_out = Message::new();
+                        let mut file_action = FileAction::new();
+                        file_action.set_receive(FileTransferReceiveRequest {
+                            id,
+                            path: fs::get_file_name(&fs::get_path(&path)),
+                            files: job.files().clone().into(),
+                            drop: true,
+                            paste,
+                            ..Default::default()
+                        });
+                        msg_out.set_file_action(file_action);
+                        self.drop_jobs.insert(id, paste);
+                        self.read_jobs.push(job);
+                        self.timer = time::interval(MILLI1);
+                        allow_err!(peer.send(&msg_out).await);
+                    }
+                }
+            }
+            Data::SyncDir((id, local, remote, to_remote, by_hash, include_hidden)) => {
+                let mut msg_out = Message::new();
+                let mut file_action = FileAction::new();
+                file_action.set_all_files(ReadAllFiles {
+                    id,
+                    path: remote.clone(),
+                    include_hidden,
+                    hash: by_hash,
+                    ..Default::default()
+                });
+                msg_out.set_file_action(file_action);
+                allow_err!(peer.send(&msg_out).await);
+                self.sync_jobs.insert(
+                    id,
+                    SyncJob {
+                        local,
+                        remote,
+                        to_remote,
+                        by_hash,
+                        include_hidden,
+                        files: Vec::new(),
+                    },
+                );
+            }
+            Data::ConfirmSync(id) => {
+                if let Some(job) = self.sync_jobs.remove(&id) {
+                    let files: Vec<String> = job.files.iter().map(|x| x.name.clone()).collect();
+                    if files.is_empty() {
+                        self.handle_job_status(id, -1, None);
+                    } else if job.to_remote {
+                        let args = (job.local, job.remote, job.include_hidden, false);
+                        self.start_job(id, args, files, peer).await;
+                    } else {
+                        let args = (job.remote, job.local, job.include_hidden, true);
+                        self.start_job(id, args, files, peer).await;
                     }
                 }
             }
@@ -1468,6 +2668,41 @@ impl Remote {
                 }
                 fs::remove_job(id, &mut self.read_jobs);
                 self.remove_jobs.remove(&id);
+                self.queued_jobs.retain(|x| x.0 != id);
+                self.paused_jobs.remove(&id);
+                self.sync_jobs.remove(&id);
+                if self.pending_jobs.remove(&id).is_some() {
+                    self.start_queued_job();
+                }
+            }
+            Data::PauseJob(id) => {
+                if let Some(args) = self.pending_jobs.remove(&id) {
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_cancel(FileTransferCancel {
+                        id,
+                        keep_download: true,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(peer.send(&msg_out).await);
+                    fs::remove_job(id, &mut self.write_jobs);
+                    fs::remove_job(id, &mut self.read_jobs);
+                    self.paused_jobs.insert(id, args);
+                    self.start_queued_job();
+                } else if let Some(i) = self.queued_jobs.iter().position(|x| x.0 == id) {
+                    if let Some((id, args)) = self.queued_jobs.remove(i) {
+                        self.paused_jobs.insert(id, args);
+                    }
+                }
+            }
+            Data::ResumeJob(id) => {
+                // from the checkpoints of the download files
+                if let Some((path, to, include_hidden, is_remote)) = self.paused_jobs.remove(&id) {
+                    self.sender
+                        .send(Data::SendFiles((id, path, to, include_hidden, is_remote)))
+                        .ok();
+                }
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -1526,20 +2761,74 @@ impl Remote {
                     }
                 }
             }
+            #[cfg(not(any(target_os = "android")))]
+            Data::Microphone(on) => {
+                self.microphone = None;
+                if on {
+                    let sender = self.sender.clone();
+                    match Microphone::start(
+                        move |msg| {
+                            sender.send(Data::Message(msg)).ok();
+                        },
+                        None,
+                    ) {
+                        Ok(microphone) => self.microphone = Some(microphone),
+                        Err(err) => {
+                            log::error!("Failed to start the microphone: {}", err);
+                            self.handler.call("setMicrophone", &make_args!(false));
+                            self.handler
+                                .msgbox("custom-error", "Microphone", &err.to_string());
+                        }
+                    }
+                }
+            }
+            #[cfg(not(any(target_os = "android")))]
+            Data::VoiceCall(on) => {
+                // started once accepted by the peer
+                self.voice_call = None;
+                send_voice_call_request(peer, on).await;
+            }
+            Data::RecordScreen(start) => {
+                self.recorder = None;
+                if start {
+                    self.start_recording(peer).await;
+                }
+            }
             _ => {}
         }
         true
     }
 
+    async fn start_recording(&mut self, peer: &mut Stream) {
+        let (w, h) = self.display_size;
+        let dir = Config::get_video_save_directory();
+        let name = format!("{}_{}", self.handler.id, crate::get_time());
+        match Recorder::new(&dir, &name, w as _, h as _, self.audio_format.as_ref()) {
+            Ok(recorder) => {
+                self.recorder = Some(recorder);
+                // the recording starts with a key frame
+                allow_err!(peer.send(&LoginConfigHandler::refresh()).await);
+            }
+            Err(err) => self.stop_recording(&err.to_string()),
+        }
+    }
+
+    fn stop_recording(&mut self, err: &str) {
+        log::error!("Recording stopped: {}", err);
+        self.recorder = None;
+        self.handler.call("setRecording", &make_args!(false));
+        self.handler.msgbox("custom-error", "Recording", err);
+    }
+
     #[inline]
     fn update_job_status(
         job: &fs::TransferJob,
         elapsed: i32,
         last_update_jobs_status: &mut (Instant, HashMap<i32, u64>),
         handler: &mut Handler,
-    ) {
+    ) -> f64 {
         if elapsed <= 0 {
-            return;
+            return 0.;
         }
         let transferred = job.transferred();
         let last_transferred = {
@@ -1556,48 +2845,88 @@ impl Remote {
             "jobProgress",
             &make_args!(job.id(), file_num, speed, job.finished_size() as f64),
         );
+        speed
     }
 
     fn update_jobs_status(&mut self) {
+        // no job table in a desktop session, where only files are dropped
+        if !self.handler.is_file_transfer() {
+            return;
+        }
         let elapsed = self.last_update_jobs_status.0.elapsed().as_millis() as i32;
         if elapsed >= 1000 {
-            for job in self.read_jobs.iter() {
-                Self::update_job_status(
-                    job,
-                    elapsed,
-                    &mut self.last_update_jobs_status,
-                    &mut self.handler,
-                );
-            }
-            for job in self.write_jobs.iter() {
-                Self::update_job_status(
+            let mut speed = 0.;
+            let mut finished_size = 0;
+            let mut total_size = 0;
+            for job in self.read_jobs.iter().chain(self.write_jobs.iter()) {
+                speed += Self::update_job_status(
                     job,
                     elapsed,
                     &mut self.last_update_jobs_status,
                     &mut self.handler,
                 );
+                finished_size += job.finished_size();
+                total_size += job.total_size();
             }
+            self.handler.call(
+                "jobsProgress",
+                &make_args!(
+                    self.pending_jobs.len() as i32,
+                    self.queued_jobs.len() as i32,
+                    speed,
+                    finished_size as f64,
+                    total_size as f64
+                ),
+            );
             self.last_update_jobs_status.0 = Instant::now();
         }
     }
 
+    // seen by the plugins, None if dropped
+    fn filter(&self, outgoing: bool, msg: Message) -> Option<Message> {
+        crate::plugins::filter_owned(crate::plugins::SIDE_CLIENT, &self.handler.id, outgoing, msg)
+    }
+
     async fn handle_msg_from_peer(&mut self, data: &[u8], peer: &mut Stream) -> bool {
         if let Ok(msg_in) = Message::parse_from_bytes(&data) {
+            let msg_in = match self.filter(false, msg_in) {
+                Some(msg_in) => msg_in,
+                None => return true,
+            };
             match msg_in.union {
                 Some(message::Union::video_frame(vf)) => {
+                    self.video_stats.on_frame(&vf, data.len());
+                    if vf.display == 0 {
+                        if let Some(Err(err)) = self.recorder.as_mut().map(|r| r.write_video(&vf)) {
+                            self.stop_recording(&err.to_string());
+                        }
+                    }
                     if !self.first_frame {
                         self.first_frame = true;
                         self.handler.call("closeSuccess", &make_args!());
                         self.handler.call("adaptSize", &make_args!());
                     }
-                    if let Some(video_frame::Union::vp9s(vp9s)) = &vf.union {
-                        if let Ok(true) = self.video_handler.handle_vp9s(vp9s) {
-                            VIDEO
+                    if vf.display > 0 {
+                        let display = vf.display as usize - 1;
+                        let handler = self
+                            .extra_video_handlers
+                            .entry(display)
+                            .or_insert_with(VideoHandler::new);
+                        if let Ok(true) = handler.handle_frame(&vf) {
+                            EXTRA_VIDEOS
                                 .lock()
                                 .unwrap()
-                                .as_mut()
-                                .map(|v| v.render_frame(&self.video_handler.rgb).ok());
+                                .get_mut(&display)
+                                .map(|v| v.render_frame(&handler.rgb).ok());
                         }
+                        return true;
+                    }
+                    if let Ok(true) = self.video_handler.handle_frame(&vf) {
+                        VIDEO
+                            .lock()
+                            .unwrap()
+                            .as_mut()
+                            .map(|v| v.render_frame(&self.video_handler.rgb).ok());
                     }
                 }
                 Some(message::Union::hash(hash)) => {
@@ -1610,6 +2939,17 @@ impl Remote {
                         }
                     }
                     Some(login_response::Union::peer_info(pi)) => {
+                        if self.audit.start == 0 {
+                            self.audit.start = crate::get_time();
+                        }
+                        self.audit.name = pi.hostname.clone();
+                        if let Some(d) = pi
+                            .displays
+                            .get(pi.current_display as usize)
+                            .or(pi.displays.first())
+                        {
+                            self.display_size = (d.width, d.height);
+                        }
                         self.handler.handle_peer_info(pi);
                         if !(self.handler.is_file_transfer()
                             || self.handler.is_port_forward()
@@ -1617,9 +2957,8 @@ impl Remote {
                             || !*self.keyboard.read().unwrap()
                             || self.handler.lc.read().unwrap().disable_clipboard)
                         {
-                            let txt = self.old_clipboard.lock().unwrap().clone();
-                            if !txt.is_empty() {
-                                let msg_out = crate::create_clipboard_msg(txt);
+                            let content = self.old_clipboard.lock().unwrap().clone();
+                            if let Some(msg_out) = content.to_msg() {
                                 let sender = self.sender.clone();
                                 tokio::spawn(async move {
                                     // due to clipboard service interval time
@@ -1638,14 +2977,63 @@ impl Remote {
                     self.handler.set_cursor_id(id.to_string());
                 }
                 Some(message::Union::cursor_position(cp)) => {
+                    self.cursor_pos = (cp.x, cp.y);
                     self.handler.set_cursor_position(cp);
                 }
+                Some(message::Union::cursor_delta(cd)) => {
+                    self.cursor_pos.0 += cd.dx;
+                    self.cursor_pos.1 += cd.dy;
+                    self.handler.set_cursor_position(CursorPosition {
+                        x: self.cursor_pos.0,
+                        y: self.cursor_pos.1,
+                        ..Default::default()
+                    });
+                }
+                Some(message::Union::print_job(job)) => {
+                    if self
+                        .handler
+                        .lc
+                        .read()
+                        .unwrap()
+                        .get_toggle_option("enable-printer")
+                    {
+                        let name = job.name.clone();
+                        if let Some(path) = self.print_spooler.add(job) {
+                            let path = path.to_string_lossy().to_string();
+                            self.handler.call("printJob", &make_args!(path, name));
+                        }
+                    }
+                }
                 Some(message::Union::clipboard(cb)) => {
                     if !self.handler.lc.read().unwrap().disable_clipboard {
-                        update_clipboard(cb, Some(&self.old_clipboard));
+                        if let Some(msg) = update_clipboard(cb, Some(&self.old_clipboard)) {
+                            allow_err!(peer.send(&msg).await);
+                        }
+                        self.update_clipboard_progress();
+                    }
+                }
+                Some(message::Union::clipboard_chunk(chunk)) => {
+                    if !self.handler.lc.read().unwrap().disable_clipboard {
+                        if let Some(msg) = update_clipboard_chunk(chunk, Some(&self.old_clipboard))
+                        {
+                            allow_err!(peer.send(&msg).await);
+                        }
+                        self.update_clipboard_progress();
+                    }
+                }
+                Some(message::Union::clipboard_request(req)) => {
+                    if *self.clipboard.read().unwrap()
+                        && !self.handler.lc.read().unwrap().disable_clipboard
+                    {
+                        if let Some(msg) = get_clipboard_chunk(&req) {
+                            allow_err!(peer.send(&msg).await);
+                        }
                     }
                 }
                 Some(message::Union::file_response(fr)) => match fr.union {
+                    Some(file_response::Union::dir(fd)) if self.sync_jobs.contains_key(&fd.id) => {
+                        self.sync_preview(fd.id, fd.entries.to_vec());
+                    }
                     Some(file_response::Union::dir(fd)) => {
                         let entries = fd.entries.to_vec();
                         let mut m = make_fd(fd.id, &entries, fd.id > 0);
@@ -1661,33 +3049,99 @@ impl Remote {
                     }
                     Some(file_response::Union::block(block)) => {
                         if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
-                            if let Err(_err) = job.write(block).await {
+                            let (id, file_num) = (block.id, block.file_num);
+                            if let Err(err) = job.write(block).await {
                                 // to-do: add "skip" for writing job
+                                self.handle_job_status(id, file_num, Some(err.to_string()));
                             }
                             self.update_jobs_status();
                         }
                     }
                     Some(file_response::Union::done(d)) => {
                         if let Some(job) = fs::get_job(d.id, &mut self.write_jobs) {
-                            job.modify_time();
+                            job.write_done();
                             fs::remove_job(d.id, &mut self.write_jobs);
                         }
                         self.handle_job_status(d.id, d.file_num, None);
                     }
                     Some(file_response::Union::error(e)) => {
-                        self.handle_job_status(e.id, e.file_num, Some(e.error));
+                        // no folder to sync to on the remote side yet
+                        if self.sync_jobs.get(&e.id).map(|x| x.to_remote) == Some(true) {
+                            self.sync_preview(e.id, Vec::new());
+                        } else {
+                            self.sync_jobs.remove(&e.id);
+                            self.handle_job_status(e.id, e.file_num, Some(e.error));
+                        }
+                    }
+                    Some(file_response::Union::search_result(r)) => {
+                        let mut m = make_fd(r.id, &r.entries.to_vec(), false);
+                        m.set_item("done", r.done);
+                        self.handler.call("searchResults", &make_args!(m));
+                    }
+                    Some(file_response::Union::digest(d)) => {
+                        if let Some(job) = fs::get_job(d.id, &mut self.write_jobs) {
+                            let msg = job.handle_digest(d).await;
+                            allow_err!(peer.send(&msg).await);
+                        }
+                    }
+                    _ => {}
+                },
+                Some(message::Union::file_action(fa)) => match fa.union {
+                    Some(file_action::Union::send_confirm(r)) => {
+                        if let Some(job) = fs::get_job(r.id, &mut self.read_jobs) {
+                            if let Err(err) = job.confirm(&r).await {
+                                self.handle_job_status(r.id, r.file_num, Some(err.to_string()));
+                            }
+                        }
                     }
                     _ => {}
                 },
                 Some(message::Union::misc(misc)) => match misc.union {
+                    #[cfg(not(any(target_os = "android")))]
+                    Some(misc::Union::voice_format(f)) => {
+                        if let Some(call) = self.voice_call.as_mut() {
+                            call.handle_format(f);
+                        }
+                    }
                     Some(misc::Union::audio_format(f)) => {
+                        self.audio_format = Some(f.clone());
                         self.audio_handler.handle_format(f);
                     }
                     Some(misc::Union::chat_message(c)) => {
-                        self.handler.call("newMessage", &make_args!(c.text));
+                        let msg = crate::chat::Message {
+                            id: c.id,
+                            name: self.handler.lc.read().unwrap().info.username.clone(),
+                            text: c.text,
+                            file: c.file,
+                            time: crate::get_time(),
+                            ..Default::default()
+                        };
+                        crate::chat::add(&self.handler.id, msg.clone());
+                        if c.id != 0 {
+                            let mut misc = Misc::new();
+                            misc.set_chat_receipt(ChatReceipt {
+                                ids: vec![c.id],
+                                ..Default::default()
+                            });
+                            let mut msg_out = Message::new();
+                            msg_out.set_misc(misc);
+                            allow_err!(peer.send(&msg_out).await);
+                        }
+                        let msg = super::make_chat_message(&msg);
+                        self.handler.call("newMessage", &make_args!(msg));
+                    }
+                    Some(misc::Union::chat_receipt(r)) => {
+                        crate::chat::set_receipt(&self.handler.id, &r.ids, r.read);
+                        let ids = super::make_chat_ids(&r.ids);
+                        self.handler.call("chatReceipt", &make_args!(ids, r.read));
                     }
                     Some(misc::Union::permission_info(p)) => {
                         log::info!("Change permission {:?} -> {}", p.permission, p.enabled);
+                        let permission = p.permission.enum_value_or_default();
+                        let name = format!("{:?}", permission).to_lowercase();
+                        if p.enabled && !self.audit.permissions.contains(&name) {
+                            self.audit.permissions.push(name);
+                        }
                         match p.permission.enum_value_or_default() {
                             Permission::Keyboard => {
                                 unsafe {
@@ -1705,11 +3159,47 @@ impl Remote {
                                 self.handler
                                     .call("setPermission", &make_args!("audio", p.enabled));
                             }
+                            Permission::Microphone => {
+                                #[cfg(not(any(target_os = "android")))]
+                                if !p.enabled {
+                                    self.microphone = None;
+                                }
+                                self.handler
+                                    .call("setPermission", &make_args!("microphone", p.enabled));
+                            }
                         }
                     }
+                    Some(misc::Union::stats(s)) => {
+                        let (fps, kbps) = self.video_stats.take();
+                        // of our own pings if any, else of the peer's
+                        let rtt = match peer.keepalive().rtt() {
+                            Some(rtt) => rtt.as_millis() as i32,
+                            None => s.rtt,
+                        };
+                        let media = self.media.as_ref().map(|m| m.is_established()) == Some(true);
+                        self.handler.call(
+                            "updateStats",
+                            &make_args!(
+                                rtt,
+                                if s.codec.is_empty() {
+                                    self.video_stats.codec.to_owned()
+                                } else {
+                                    s.codec
+                                },
+                                s.fps as i32,
+                                fps as i32,
+                                s.target_bitrate as i32,
+                                kbps as i32,
+                                s.loss as f64 / 10.,
+                                self.direct,
+                                media
+                            ),
+                        );
+                    }
                     Some(misc::Union::switch_display(s)) => {
                         self.handler.call("switchDisplay", &make_args!(s.display));
                         self.video_handler.reset();
+                        self.extra_video_handlers.clear();
                         if s.width > 0 && s.height > 0 {
                             VIDEO.lock().unwrap().as_mut().map(|v| {
                                 v.stop_streaming().ok();
@@ -1721,18 +3211,121 @@ impl Remote {
                                 log::info!("[video] reinitialized: {:?}", ok);
                             });
                             self.handler.set_display(s.x, s.y, s.width, s.height);
+                            if (s.width, s.height) != self.display_size {
+                                self.display_size = (s.width, s.height);
+                                if self.recorder.is_some() {
+                                    self.recorder = None;
+                                    self.start_recording(peer).await;
+                                }
+                            }
+                        }
+                    }
+                    Some(misc::Union::windows(list)) => {
+                        let mut windows = Value::array(0);
+                        for w in list.windows.iter() {
+                            let mut window = Value::map();
+                            // u64 is not a value of sciter
+                            window.set_item("id", w.id.to_string());
+                            window.set_item("title", w.title.clone());
+                            window.set_item("width", w.width);
+                            window.set_item("height", w.height);
+                            windows.push(window);
+                        }
+                        self.handler.call("showWindows", &make_args!(windows));
+                    }
+                    Some(misc::Union::privacy_mode(on)) => {
+                        let requested = self
+                            .handler
+                            .lc
+                            .read()
+                            .unwrap()
+                            .get_toggle_option("privacy-mode");
+                        if requested && !on {
+                            self.handler.lc.write().unwrap().reset_privacy_mode();
+                            self.handler.msgbox(
+                                "custom-error",
+                                "Privacy mode",
+                                "privacy_mode_failed_tip",
+                            );
+                        }
+                    }
+                    Some(misc::Union::block_input(on)) => {
+                        self.handler.call("setInputBlocked", &make_args!(on));
+                    }
+                    Some(misc::Union::session_timeout(t)) => {
+                        self.handler
+                            .call("sessionTimeout", &make_args!(t.seconds, t.idle));
+                    }
+                    Some(misc::Union::elevation_response(err)) => {
+                        // the peer restarts elevated if no error
+                        if !err.is_empty() {
+                            self.handler.write().unwrap().restarting = None;
+                            self.handler.msgbox("custom-error", "Elevation Error", &err);
                         }
                     }
                     Some(misc::Union::close_reason(c)) => {
                         self.handler.msgbox("error", "Connection Error", &c);
                         return false;
                     }
+                    Some(misc::Union::media_channel(mc)) => {
+                        if self.direct {
+                            match Client::connect_media_channel(&mc, peer).await {
+                                Ok(media) => self.media = Some(media),
+                                Err(err) => log::info!("Udp media channel not used: {}", err),
+                            }
+                        }
+                    }
                     _ => {}
                 },
                 Some(message::Union::test_delay(t)) => {
                     self.handler.handle_test_delay(t, peer).await;
                 }
+                #[cfg(not(any(target_os = "android")))]
+                Some(message::Union::voice_call_response(r)) => {
+                    if r.accepted {
+                        let sender = self.sender.clone();
+                        match VoiceCall::start(move |msg| {
+                            sender.send(Data::Message(msg)).ok();
+                        }) {
+                            Ok(call) => {
+                                self.voice_call = Some(call);
+                                self.handler.call("onVoiceCall", &make_args!("connected"));
+                            }
+                            Err(err) => {
+                                log::error!("Failed to start the voice call: {}", err);
+                                send_voice_call_request(peer, false).await;
+                                self.handler.call("onVoiceCall", &make_args!(""));
+                                self.handler
+                                    .msgbox("custom-error", "Voice call", &err.to_string());
+                            }
+                        }
+                    } else {
+                        self.handler.call("onVoiceCall", &make_args!(""));
+                        self.handler.msgbox(
+                            "custom-nocancel",
+                            "Voice call",
+                            "The voice call was declined",
+                        );
+                    }
+                }
+                #[cfg(not(any(target_os = "android")))]
+                Some(message::Union::voice_call_request(r)) => {
+                    // only the controlled side is called, this is to hang up
+                    if !r.is_connect {
+                        self.voice_call = None;
+                        self.handler.call("onVoiceCall", &make_args!(""));
+                    }
+                }
+                #[cfg(not(any(target_os = "android")))]
+                Some(message::Union::voice_frame(frame)) => {
+                    if let Some(call) = self.voice_call.as_ref() {
+                        call.handle_frame(frame);
+                    }
+                }
                 Some(message::Union::audio_frame(frame)) => {
+                    if let Some(recorder) = self.recorder.as_mut() {
+                        recorder.write_audio(&frame);
+                    }
                     self.audio_handler
                         .handle_frame(frame, !self.handler.lc.read().unwrap().disable_audio);
                 }
@@ -1743,6 +3336,17 @@ impl Remote {
     }
 }
 
+#[cfg(not(any(target_os = "android")))]
+async fn send_voice_call_request(peer: &mut Stream, is_connect: bool) {
+    let mut msg_out = Message::new();
+    msg_out.set_voice_call_request(VoiceCallRequest {
+        req_timestamp: crate::get_time(),
+        is_connect,
+        ..Default::default()
+    });
+    allow_err!(peer.send(&msg_out).await);
+}
+
 fn make_fd(id: i32, entries: &Vec<FileEntry>, only_count: bool) -> Value {
     let mut m = Value::map();
     m.set_item("id", id);
@@ -1787,6 +3391,7 @@ impl Interface for Handler {
         pi_sciter.set_item("hostname", pi.hostname.clone());
         pi_sciter.set_item("platform", pi.platform.clone());
         pi_sciter.set_item("sas_enabled", pi.sas_enabled);
+        pi_sciter.set_item("can_elevate", pi.can_elevate);
         if self.is_file_transfer() {
             if pi.username.is_empty() {
                 self.on_error("No active console user logged on, please connect and logon first.");
@@ -1846,6 +3451,7 @@ impl Interface for Handler {
             }
         }
         self.start_keyboard_hook();
+        self.start_gamepad_thread();
     }
 
     async fn handle_hash(&mut self, hash: Hash, peer: &mut Stream) {
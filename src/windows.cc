@@ -3,6 +3,7 @@
 #include <tlhelp32.h>
 #include <cstdio>
 #include <cstdint>
+#include <cmath>
 #include <intrin.h>
 #include <string>
 #include <memory>
@@ -368,6 +369,453 @@ extern "C"
         }
     }
 
+    // BlockInput blocks the input injected by the other threads too, so the
+    // local input is blocked by low level hooks, letting the injected input of
+    // the remote session through.
+    static HANDLE block_input_thread = NULL;
+    static DWORD block_input_thread_id = 0;
+
+    static LRESULT CALLBACK block_keyboard_hook(int code, WPARAM wparam, LPARAM lparam)
+    {
+        if (code == HC_ACTION && !(((KBDLLHOOKSTRUCT *)lparam)->flags & LLKHF_INJECTED))
+            return 1;
+        return CallNextHookEx(NULL, code, wparam, lparam);
+    }
+
+    static LRESULT CALLBACK block_mouse_hook(int code, WPARAM wparam, LPARAM lparam)
+    {
+        if (code == HC_ACTION && !(((MSLLHOOKSTRUCT *)lparam)->flags & LLMHF_INJECTED))
+            return 1;
+        return CallNextHookEx(NULL, code, wparam, lparam);
+    }
+
+    static DWORD WINAPI block_input_thread_proc(LPVOID)
+    {
+        auto instance = GetModuleHandleW(NULL);
+        auto keyboard = SetWindowsHookExW(WH_KEYBOARD_LL, block_keyboard_hook, instance, 0);
+        auto mouse = SetWindowsHookExW(WH_MOUSE_LL, block_mouse_hook, instance, 0);
+        MSG msg;
+        while (GetMessageW(&msg, NULL, 0, 0) > 0)
+            DispatchMessageW(&msg);
+        if (keyboard)
+            UnhookWindowsHookEx(keyboard);
+        if (mouse)
+            UnhookWindowsHookEx(mouse);
+        return 0;
+    }
+
+    static void stop_thread(HANDLE *thread, DWORD id)
+    {
+        if (!*thread)
+            return;
+        // fails until the thread has its message queue
+        while (!PostThreadMessageW(id, WM_QUIT, 0, 0) &&
+               WaitForSingleObject(*thread, 10) == WAIT_TIMEOUT)
+            ;
+        WaitForSingleObject(*thread, 3000);
+        CloseHandle(*thread);
+        *thread = NULL;
+    }
+
+    BOOL block_local_input(BOOL on)
+    {
+        if (on)
+        {
+            if (!block_input_thread)
+                block_input_thread = CreateThread(NULL, 0, block_input_thread_proc, NULL, 0, &block_input_thread_id);
+            return block_input_thread != NULL;
+        }
+        stop_thread(&block_input_thread, block_input_thread_id);
+        return FALSE;
+    }
+
+    // Privacy mode: a black topmost window over all the monitors, excluded from
+    // the capture, or the monitors turned off again and again before Windows 10
+    // 2004.
+    static HANDLE privacy_thread = NULL;
+    static DWORD privacy_thread_id = 0;
+
+    static DWORD WINAPI privacy_thread_proc(LPVOID)
+    {
+        auto instance = GetModuleHandleW(NULL);
+        WNDCLASSW wc = {0};
+        wc.lpfnWndProc = DefWindowProcW;
+        wc.hInstance = instance;
+        wc.hbrBackground = (HBRUSH)GetStockObject(BLACK_BRUSH);
+        wc.lpszClassName = L"PrivacyModeWindow";
+        RegisterClassW(&wc);
+        auto hwnd = CreateWindowExW(
+            WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_TRANSPARENT | WS_EX_LAYERED,
+            wc.lpszClassName, L"", WS_POPUP,
+            GetSystemMetrics(SM_XVIRTUALSCREEN), GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN), GetSystemMetrics(SM_CYVIRTUALSCREEN),
+            NULL, NULL, instance, NULL);
+        // WDA_EXCLUDEFROMCAPTURE
+        if (hwnd && SetWindowDisplayAffinity(hwnd, 0x11))
+        {
+            SetLayeredWindowAttributes(hwnd, 0, 255, LWA_ALPHA);
+            ShowWindow(hwnd, SW_SHOWNOACTIVATE);
+        }
+        else if (hwnd)
+        {
+            DestroyWindow(hwnd);
+            hwnd = NULL;
+        }
+        if (!hwnd)
+        {
+            SetTimer(NULL, 0, 1000, NULL);
+            PostMessage(HWND_BROADCAST, WM_SYSCOMMAND, SC_MONITORPOWER, (LPARAM)2);
+        }
+        MSG msg;
+        while (GetMessageW(&msg, NULL, 0, 0) > 0)
+        {
+            if (msg.message == WM_TIMER && !hwnd)
+            {
+                // the remote input wakes the monitors up
+                PostMessage(HWND_BROADCAST, WM_SYSCOMMAND, SC_MONITORPOWER, (LPARAM)2);
+            }
+            DispatchMessageW(&msg);
+        }
+        if (hwnd)
+            DestroyWindow(hwnd);
+        else
+            PostMessage(HWND_BROADCAST, WM_SYSCOMMAND, SC_MONITORPOWER, (LPARAM)-1);
+        return 0;
+    }
+
+    BOOL privacy_mode(BOOL on)
+    {
+        if (on)
+        {
+            if (!privacy_thread)
+                privacy_thread = CreateThread(NULL, 0, privacy_thread_proc, NULL, 0, &privacy_thread_id);
+            return privacy_thread != NULL;
+        }
+        stop_thread(&privacy_thread, privacy_thread_id);
+        return FALSE;
+    }
+
+    // Annotations: the shapes drawn by the controllers, in a topmost window over
+    // all the monitors passing the input through, excluded from the capture
+    // since Windows 10 2004 as the controllers draw them in their view.
+    typedef struct
+    {
+        // 0 laser, 1 arrow, 2 circle
+        UINT32 kind;
+        INT32 x0;
+        INT32 y0;
+        INT32 x1;
+        INT32 y1;
+        // 0xRRGGBB
+        UINT32 color;
+    } overlay_shape;
+
+    static const UINT32 max_overlay_shapes = 256;
+    // the transparent color of the window
+    static const COLORREF overlay_key = RGB(255, 0, 255);
+    static HANDLE overlay_thread = NULL;
+    static DWORD overlay_thread_id = 0;
+    static SRWLOCK overlay_lock = SRWLOCK_INIT;
+    static HWND overlay_hwnd = NULL;
+    static overlay_shape overlay_shapes[max_overlay_shapes];
+    static UINT32 overlay_n = 0;
+
+    static void draw_overlay_shape(HDC dc, const overlay_shape &s, int ox, int oy)
+    {
+        auto color = RGB((s.color >> 16) & 0xFF, (s.color >> 8) & 0xFF, s.color & 0xFF);
+        if (color == overlay_key)
+            color = RGB(255, 0, 254);
+        auto pen = CreatePen(PS_SOLID, 4, color);
+        auto brush = s.kind == 0 ? CreateSolidBrush(color) : (HBRUSH)GetStockObject(NULL_BRUSH);
+        auto old_pen = SelectObject(dc, pen);
+        auto old_brush = SelectObject(dc, brush);
+        int x0 = s.x0 - ox, y0 = s.y0 - oy, x1 = s.x1 - ox, y1 = s.y1 - oy;
+        if (s.kind == 0)
+        {
+            Ellipse(dc, x1 - 8, y1 - 8, x1 + 8, y1 + 8);
+        }
+        else if (s.kind == 1)
+        {
+            MoveToEx(dc, x0, y0, NULL);
+            LineTo(dc, x1, y1);
+            // the head, two strokes at 30 degrees of the shaft
+            auto a = atan2((double)(y0 - y1), (double)(x0 - x1));
+            for (auto d : {-0.5236, 0.5236})
+            {
+                MoveToEx(dc, x1, y1, NULL);
+                LineTo(dc, x1 + (int)(20 * cos(a + d)), y1 + (int)(20 * sin(a + d)));
+            }
+        }
+        else if (s.kind == 2)
+        {
+            Ellipse(dc, x0 < x1 ? x0 : x1, y0 < y1 ? y0 : y1, x0 < x1 ? x1 : x0, y0 < y1 ? y1 : y0);
+        }
+        SelectObject(dc, old_pen);
+        SelectObject(dc, old_brush);
+        DeleteObject(pen);
+        if (s.kind == 0)
+            DeleteObject(brush);
+    }
+
+    static LRESULT CALLBACK overlay_proc(HWND hwnd, UINT msg, WPARAM wparam, LPARAM lparam)
+    {
+        if (msg == WM_ERASEBKGND)
+            return 1;
+        if (msg != WM_PAINT)
+            return DefWindowProcW(hwnd, msg, wparam, lparam);
+        PAINTSTRUCT ps;
+        auto dc = BeginPaint(hwnd, &ps);
+        RECT rc;
+        GetClientRect(hwnd, &rc);
+        // drawn off screen first, not to flicker as the laser moves
+        auto mem = CreateCompatibleDC(dc);
+        auto bitmap = CreateCompatibleBitmap(dc, rc.right, rc.bottom);
+        auto old_bitmap = SelectObject(mem, bitmap);
+        auto bg = CreateSolidBrush(overlay_key);
+        FillRect(mem, &rc, bg);
+        DeleteObject(bg);
+        auto ox = GetSystemMetrics(SM_XVIRTUALSCREEN);
+        auto oy = GetSystemMetrics(SM_YVIRTUALSCREEN);
+        AcquireSRWLockShared(&overlay_lock);
+        for (UINT32 i = 0; i < overlay_n; i++)
+            draw_overlay_shape(mem, overlay_shapes[i], ox, oy);
+        ReleaseSRWLockShared(&overlay_lock);
+        BitBlt(dc, 0, 0, rc.right, rc.bottom, mem, 0, 0, SRCCOPY);
+        SelectObject(mem, old_bitmap);
+        DeleteObject(bitmap);
+        DeleteDC(mem);
+        EndPaint(hwnd, &ps);
+        return 0;
+    }
+
+    static DWORD WINAPI overlay_thread_proc(LPVOID)
+    {
+        auto instance = GetModuleHandleW(NULL);
+        WNDCLASSW wc = {0};
+        wc.lpfnWndProc = overlay_proc;
+        wc.hInstance = instance;
+        wc.lpszClassName = L"AnnotationOverlayWindow";
+        RegisterClassW(&wc);
+        auto hwnd = CreateWindowExW(
+            WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_TRANSPARENT | WS_EX_LAYERED | WS_EX_NOACTIVATE,
+            wc.lpszClassName, L"", WS_POPUP,
+            GetSystemMetrics(SM_XVIRTUALSCREEN), GetSystemMetrics(SM_YVIRTUALSCREEN),
+            GetSystemMetrics(SM_CXVIRTUALSCREEN), GetSystemMetrics(SM_CYVIRTUALSCREEN),
+            NULL, NULL, instance, NULL);
+        if (!hwnd)
+            return 0;
+        SetLayeredWindowAttributes(hwnd, overlay_key, 0, LWA_COLORKEY);
+        // WDA_EXCLUDEFROMCAPTURE
+        SetWindowDisplayAffinity(hwnd, 0x11);
+        ShowWindow(hwnd, SW_SHOWNOACTIVATE);
+        AcquireSRWLockExclusive(&overlay_lock);
+        overlay_hwnd = hwnd;
+        ReleaseSRWLockExclusive(&overlay_lock);
+        MSG msg;
+        while (GetMessageW(&msg, NULL, 0, 0) > 0)
+            DispatchMessageW(&msg);
+        AcquireSRWLockExclusive(&overlay_lock);
+        overlay_hwnd = NULL;
+        ReleaseSRWLockExclusive(&overlay_lock);
+        DestroyWindow(hwnd);
+        return 0;
+    }
+
+    BOOL annotation_overlay(const overlay_shape *shapes, UINT32 n)
+    {
+        if (n > max_overlay_shapes)
+            n = max_overlay_shapes;
+        AcquireSRWLockExclusive(&overlay_lock);
+        memcpy(overlay_shapes, shapes, n * sizeof(overlay_shape));
+        overlay_n = n;
+        auto hwnd = overlay_hwnd;
+        ReleaseSRWLockExclusive(&overlay_lock);
+        if (n == 0)
+        {
+            stop_thread(&overlay_thread, overlay_thread_id);
+            return FALSE;
+        }
+        if (!overlay_thread)
+        {
+            overlay_thread = CreateThread(NULL, 0, overlay_thread_proc, NULL, 0, &overlay_thread_id);
+            return overlay_thread != NULL;
+        }
+        if (hwnd)
+            InvalidateRect(hwnd, NULL, FALSE);
+        return TRUE;
+    }
+
+    // Touch injection, since Windows 8, all the contacts down given at once.
+    static const UINT32 max_touch_contacts = 10;
+
+    typedef struct
+    {
+        UINT32 id;
+        INT32 x;
+        INT32 y;
+        UINT32 pressure;
+        // 1 down, 2 up, 0 moved
+        UINT32 state;
+    } touch_contact;
+
+    BOOL inject_touch_input(const touch_contact *contacts, UINT32 n)
+    {
+        static BOOL initialized = FALSE;
+        if (!initialized)
+            initialized = InitializeTouchInjection(max_touch_contacts, TOUCH_FEEDBACK_DEFAULT);
+        if (!initialized || n > max_touch_contacts)
+            return FALSE;
+        POINTER_TOUCH_INFO infos[max_touch_contacts];
+        memset(infos, 0, sizeof(infos));
+        for (UINT32 i = 0; i < n; i++)
+        {
+            auto &c = contacts[i];
+            auto &info = infos[i];
+            info.pointerInfo.pointerType = PT_TOUCH;
+            info.pointerInfo.pointerId = c.id;
+            info.pointerInfo.ptPixelLocation.x = c.x;
+            info.pointerInfo.ptPixelLocation.y = c.y;
+            if (c.state == 1)
+                info.pointerInfo.pointerFlags = POINTER_FLAG_DOWN | POINTER_FLAG_INRANGE | POINTER_FLAG_INCONTACT;
+            else if (c.state == 2)
+                info.pointerInfo.pointerFlags = POINTER_FLAG_UP;
+            else
+                info.pointerInfo.pointerFlags = POINTER_FLAG_UPDATE | POINTER_FLAG_INRANGE | POINTER_FLAG_INCONTACT;
+            info.touchFlags = TOUCH_FLAG_NONE;
+            info.touchMask = TOUCH_MASK_CONTACTAREA | TOUCH_MASK_PRESSURE;
+            info.pressure = c.pressure;
+            info.rcContact.left = c.x - 2;
+            info.rcContact.top = c.y - 2;
+            info.rcContact.right = c.x + 2;
+            info.rcContact.bottom = c.y + 2;
+        }
+        return InjectTouchInput(n, infos);
+    }
+
+    // Pen injection by a synthetic pointer device, since Windows 10 1809, whose
+    // functions are loaded at run time.
+    // the flags of the pen: 1 down, 2 in range, 4 barrel button, 8 eraser
+    typedef HANDLE(WINAPI *create_synthetic_pointer_device_fn)(POINTER_INPUT_TYPE, ULONG, POINTER_FEEDBACK_MODE);
+
+    // POINTER_TYPE_INFO of the Windows 10 SDK
+    typedef struct
+    {
+        POINTER_INPUT_TYPE type;
+        union
+        {
+            POINTER_TOUCH_INFO touchInfo;
+            POINTER_PEN_INFO penInfo;
+        };
+    } pointer_type_info;
+
+    typedef BOOL(WINAPI *inject_synthetic_pointer_input_fn)(HANDLE, const pointer_type_info *, UINT32);
+
+    BOOL inject_pen_input(INT32 x, INT32 y, UINT32 pressure, INT32 tilt_x, INT32 tilt_y, UINT32 flags)
+    {
+        static HANDLE device = NULL;
+        static inject_synthetic_pointer_input_fn inject = NULL;
+        static UINT32 last_flags = 0;
+        if (!device)
+        {
+            auto user32 = GetModuleHandleW(L"user32.dll");
+            auto create = (create_synthetic_pointer_device_fn)GetProcAddress(user32, "CreateSyntheticPointerDevice");
+            inject = (inject_synthetic_pointer_input_fn)GetProcAddress(user32, "InjectSyntheticPointerInput");
+            if (!create || !inject)
+                return FALSE;
+            device = create(PT_PEN, 1, POINTER_FEEDBACK_DEFAULT);
+            if (!device)
+                return FALSE;
+        }
+        pointer_type_info info;
+        memset(&info, 0, sizeof(info));
+        info.type = PT_PEN;
+        auto &pen = info.penInfo;
+        pen.pointerInfo.pointerType = PT_PEN;
+        pen.pointerInfo.ptPixelLocation.x = x;
+        pen.pointerInfo.ptPixelLocation.y = y;
+        auto down = flags & 1;
+        auto was_down = last_flags & 1;
+        POINTER_FLAGS pointer_flags = 0;
+        if (flags & 2)
+            pointer_flags |= POINTER_FLAG_INRANGE;
+        if (down)
+            pointer_flags |= POINTER_FLAG_INCONTACT;
+        if (down && !was_down)
+            pointer_flags |= POINTER_FLAG_DOWN;
+        else if (!down && was_down)
+            pointer_flags |= POINTER_FLAG_UP;
+        else
+            pointer_flags |= POINTER_FLAG_UPDATE;
+        pen.pointerInfo.pointerFlags = pointer_flags;
+        pen.penFlags = (flags & 4 ? PEN_FLAG_BARREL : 0) | (flags & 8 ? PEN_FLAG_ERASER : 0);
+        pen.penMask = PEN_MASK_PRESSURE | PEN_MASK_TILT_X | PEN_MASK_TILT_Y;
+        pen.pressure = pressure;
+        pen.tiltX = tilt_x;
+        pen.tiltY = tilt_y;
+        last_flags = flags;
+        return inject(device, &info, 1);
+    }
+
+    // The touch and pen input of the windows of the calling thread, given to the
+    // callback rather than turned into the mouse input if it takes it.
+    // kind: 0 touch, 1 pen, and the flags of the pen above
+    typedef BOOL (*pointer_callback)(UINT32 kind, UINT32 id, INT32 x, INT32 y, UINT32 pressure,
+                                     INT32 tilt_x, INT32 tilt_y, UINT32 flags);
+    static pointer_callback pointer_cb = NULL;
+
+    static UINT32 get_pointer_flags(POINTER_FLAGS flags)
+    {
+        return (flags & POINTER_FLAG_INCONTACT ? 1 : 0) | (flags & POINTER_FLAG_INRANGE ? 2 : 0);
+    }
+
+    static LRESULT CALLBACK pointer_hook(int code, WPARAM wparam, LPARAM lparam)
+    {
+        auto msg = (MSG *)lparam;
+        if (code == HC_ACTION && wparam == PM_REMOVE && pointer_cb &&
+            (msg->message == WM_POINTERUPDATE || msg->message == WM_POINTERDOWN ||
+             msg->message == WM_POINTERUP || msg->message == WM_POINTERLEAVE))
+        {
+            auto id = GET_POINTERID_WPARAM(msg->wParam);
+            POINTER_INPUT_TYPE type = PT_POINTER;
+            POINTER_TOUCH_INFO touch;
+            POINTER_PEN_INFO pen;
+            BOOL taken = FALSE;
+            GetPointerType(id, &type);
+            if (type == PT_TOUCH && GetPointerTouchInfo(id, &touch))
+            {
+                auto &p = touch.pointerInfo;
+                taken = pointer_cb(0, id, p.ptPixelLocation.x, p.ptPixelLocation.y,
+                                   touch.touchMask & TOUCH_MASK_PRESSURE ? touch.pressure : 512,
+                                   0, 0, get_pointer_flags(p.pointerFlags));
+            }
+            else if (type == PT_PEN && GetPointerPenInfo(id, &pen))
+            {
+                auto &p = pen.pointerInfo;
+                auto flags = get_pointer_flags(p.pointerFlags);
+                if (pen.penFlags & PEN_FLAG_BARREL)
+                    flags |= 4;
+                if (pen.penFlags & (PEN_FLAG_ERASER | PEN_FLAG_INVERTED))
+                    flags |= 8;
+                taken = pointer_cb(1, id, p.ptPixelLocation.x, p.ptPixelLocation.y,
+                                   pen.penMask & PEN_MASK_PRESSURE ? pen.pressure : 0,
+                                   pen.penMask & PEN_MASK_TILT_X ? pen.tiltX : 0,
+                                   pen.penMask & PEN_MASK_TILT_Y ? pen.tiltY : 0, flags);
+            }
+            if (taken)
+                msg->message = WM_NULL;
+        }
+        return CallNextHookEx(NULL, code, wparam, lparam);
+    }
+
+    BOOL hook_pointer_input(pointer_callback cb)
+    {
+        static HHOOK hook = NULL;
+        pointer_cb = cb;
+        if (!hook)
+            hook = SetWindowsHookExW(WH_GETMESSAGE, pointer_hook, NULL, GetCurrentThreadId());
+        return hook != NULL;
+    }
+
     void AddRecentDocument(PCWSTR path)
     {
         SHAddToRecentDocs(SHARD_PATHW, path);